@@ -0,0 +1,108 @@
+// Server-side access rules for `/api/access-rules`
+// Which controller id may connect to which target, checked on every punch hole
+// request (hbbs::AccessPolicy) whatever password the client uses. Patterns are
+// an exact id, `tag:name` (peers whose `tags` attribute lists name, comma
+// separated) or `*`. Without any rule everything is allowed; once there is one,
+// a pair no rule matches is denied. The rules are cached by the rendezvous side
+// and dropped from the cache on every change made here.
+
+use hbbs::AccessRule;
+use sqlx::{
+    sqlite::{SqlitePool, SqliteRow},
+    Row,
+};
+
+pub const MAX_PATTERN_BYTES: usize = 128;
+
+/// `*`, `tag:name` or an id; no whitespace, control characters or commas
+pub fn check_pattern(pattern: &str) -> Result<(), String> {
+    let name = pattern.strip_prefix("tag:").unwrap_or(pattern);
+    if name.is_empty() || pattern.len() > MAX_PATTERN_BYTES {
+        return Err(format!(
+            "Pattern {:?} must be *, tag:name or an id of 1-{} bytes",
+            pattern, MAX_PATTERN_BYTES
+        ));
+    }
+    if name
+        .chars()
+        .any(|c| c.is_whitespace() || c.is_control() || c == ',')
+    {
+        return Err(format!(
+            "Pattern {:?} cannot contain whitespace, control characters or ','",
+            pattern
+        ));
+    }
+    if name.contains('*') && pattern != "*" {
+        return Err(format!(
+            "Pattern {:?}: * only matches everything on its own",
+            pattern
+        ));
+    }
+    Ok(())
+}
+
+pub fn check_action(action: &str) -> Result<(), String> {
+    match action {
+        "allow" | "deny" => Ok(()),
+        _ => Err(format!("Action {:?} is not one of allow, deny", action)),
+    }
+}
+
+/// All rules in evaluation order
+pub async fn list(pool: &SqlitePool) -> Result<Vec<AccessRule>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT id, controller, target, action, priority, created_at FROM access_rules
+         ORDER BY priority DESC, action = 'deny' DESC, id",
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows.iter().map(from_row).collect())
+}
+
+/// Add a rule checked with check_pattern and check_action
+pub async fn add(
+    pool: &SqlitePool,
+    controller: &str,
+    target: &str,
+    action: &str,
+    priority: i64,
+) -> Result<AccessRule, sqlx::Error> {
+    let row = sqlx::query(
+        "INSERT INTO access_rules (controller, target, action, priority, created_at)
+         VALUES (?, ?, ?, ?, ?)
+         RETURNING id, controller, target, action, priority, created_at",
+    )
+    .bind(controller)
+    .bind(target)
+    .bind(action)
+    .bind(priority)
+    .bind(chrono::Utc::now().timestamp())
+    .fetch_one(pool)
+    .await?;
+    hbbs::invalidate_access_rules();
+    Ok(from_row(&row))
+}
+
+/// Remove a rule; Ok(None) when there is none with this id
+pub async fn remove(pool: &SqlitePool, id: i64) -> Result<Option<AccessRule>, sqlx::Error> {
+    let row = sqlx::query(
+        "DELETE FROM access_rules WHERE id = ?
+         RETURNING id, controller, target, action, priority, created_at",
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+    hbbs::invalidate_access_rules();
+    Ok(row.as_ref().map(from_row))
+}
+
+fn from_row(row: &SqliteRow) -> AccessRule {
+    AccessRule {
+        id: row.get("id"),
+        controller: row.get("controller"),
+        target: row.get("target"),
+        action: row.get("action"),
+        priority: row.get("priority"),
+        created_at: row.get("created_at"),
+    }
+}
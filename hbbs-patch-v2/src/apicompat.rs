@@ -0,0 +1,118 @@
+// Legacy v1 response shapes under `/api/v1/...`, next to `/api/...`
+// Dashboards written against the v1 API keep working while servers are
+// upgraded first. The routes run the v2 handlers and only re-serialize their
+// answers: the envelope without timestamp (or source), peers with just id, note
+// and online, the health payload as v1's fixed string and v1's 401 body. Using
+// them logs a deprecation warning at most once a day.
+
+use crate::http_api::{health_check, list_peers, ApiState, Bearer};
+use axum::{
+    extract::{Extension, Query},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use serde::Serialize;
+use serde_json::Value;
+use std::sync::{
+    atomic::{AtomicI64, Ordering},
+    Arc,
+};
+
+const V1_HEALTH: &str = "RustDesk API is running";
+const V1_UNAUTHORIZED: &str = "Unauthorized: Invalid or missing API key";
+
+/// Day (since the epoch) of the last deprecation warning
+static WARNED_DAY: AtomicI64 = AtomicI64::new(-1);
+
+#[derive(Serialize)]
+struct V1Response<T> {
+    success: bool,
+    data: Option<T>,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct V1Peer {
+    id: String,
+    note: Option<String>,
+    online: bool,
+}
+
+fn deprecated(route: &str) {
+    let day = chrono::Utc::now().timestamp() / 86_400;
+    if WARNED_DAY.swap(day, Ordering::Relaxed) != day {
+        hbb_common::log::warn!(
+            "API: {} is the deprecated v1 API, move dashboards to /api/... (logged once a day)",
+            route
+        );
+    }
+}
+
+/// A v2 handler's refusal as v1 sent it
+fn refused(status: StatusCode) -> Response {
+    if status != StatusCode::UNAUTHORIZED {
+        return status.into_response();
+    }
+    let body = serde_json::json!({ "error": V1_UNAUTHORIZED });
+    (status, Json(body)).into_response()
+}
+
+/// The v1 envelope around a v2 response serialized to JSON
+fn envelope<T>(v2: &Value, data: Option<T>) -> V1Response<T> {
+    V1Response {
+        success: v2["success"] == true,
+        data,
+        error: v2["error"].as_str().map(str::to_owned),
+    }
+}
+
+/// GET /api/v1/health
+pub(crate) async fn health(
+    headers: HeaderMap,
+    bearer: Bearer,
+    state: Extension<Arc<ApiState>>,
+) -> Response {
+    deprecated("/api/v1/health");
+    let (status, Json(res)) = match health_check(headers, bearer, state).await {
+        Ok(res) => res,
+        Err(status) => return refused(status),
+    };
+    let v2 = serde_json::to_value(&res).unwrap_or_default();
+    let mut v1 = envelope(&v2, None);
+    match v2["data"]["status"].as_str() {
+        Some("running") => v1.data = Some(V1_HEALTH),
+        other => {
+            // v1 had no starting/down state; keep v2's 503 with the reason as error
+            v1.success = false;
+            v1.error = v2["data"]["reason"].as_str().or(other).map(str::to_owned);
+        }
+    }
+    (status, Json(v1)).into_response()
+}
+
+/// GET /api/v1/peers
+pub(crate) async fn peers(
+    headers: HeaderMap,
+    bearer: Bearer,
+    params: Query<Vec<(String, String)>>,
+    state: Extension<Arc<ApiState>>,
+) -> Response {
+    deprecated("/api/v1/peers");
+    // v1 listed every peer; paging only when asked for
+    let res = match list_peers(headers, bearer, params, state, None).await {
+        Ok(Json(res)) => res,
+        Err(status) => return refused(status),
+    };
+    let v2 = serde_json::to_value(&res).unwrap_or_default();
+    let peers = v2["data"].as_array().map(|peers| {
+        peers
+            .iter()
+            .map(|peer| V1Peer {
+                id: peer["id"].as_str().unwrap_or_default().to_owned(),
+                note: peer["note"].as_str().map(str::to_owned),
+                online: peer["online"] == true,
+            })
+            .collect::<Vec<_>>()
+    });
+    Json(envelope(&v2, peers)).into_response()
+}
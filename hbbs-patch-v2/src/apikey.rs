@@ -0,0 +1,228 @@
+// The API keys behind X-API-Key, the primary one rotated through
+// `POST /api/keys/rotate`
+// A rotation writes a new key to the key file (a temporary file with 0600
+// renamed over it, so a reader never sees half a key) and only then swaps it in.
+// The key it replaced stays accepted for API_KEY_GRACE_SECS, so automation that
+// read the file just before keeps working until it reads it again; a second
+// rotation within that window ends it for the oldest key at once.
+// More keys come from the keys file (API_KEYS_FILE, `.api_keys` next to the key
+// file), one `scope:key` per line with scope read or admin, read at startup. A
+// read key has what a read-only OIDC token has: the GET routes. The primary key
+// is always admin.
+
+use crate::oidc::Role;
+use crate::sync::env_u64;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+pub const KEY_LEN: usize = 64;
+/// Shorter keys in the keys file are refused
+pub const MIN_SCOPED_KEY_LEN: usize = 16;
+/// How long the replaced key is still accepted after a rotation (API_KEY_GRACE_SECS)
+const GRACE_SECS: u64 = 60;
+
+pub fn grace() -> Duration {
+    Duration::from_secs(env_u64("API_KEY_GRACE_SECS", GRACE_SECS))
+}
+
+/// KEY_LEN random letters and digits
+pub fn generate() -> String {
+    use hbb_common::rand::Rng;
+    const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+    let mut rng = hbb_common::rand::thread_rng();
+    (0..KEY_LEN)
+        .map(|_| {
+            let idx = rng.gen_range(0..CHARSET.len());
+            CHARSET[idx] as char
+        })
+        .collect()
+}
+
+/// Replace the key file with `key`, readable by the owner only
+pub fn write_key_file(path: &str, key: &str) -> std::io::Result<()> {
+    let path = std::path::Path::new(path);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = std::path::PathBuf::from(tmp);
+    let res = write_tmp(&tmp, key).and_then(|_| std::fs::rename(&tmp, path));
+    if res.is_err() {
+        std::fs::remove_file(&tmp).ok();
+    }
+    res
+}
+
+fn write_tmp(tmp: &std::path::Path, key: &str) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(tmp)?;
+    // the mode above only applies to a file it creates
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    std::io::Write::write_all(&mut file, key.as_bytes())?;
+    file.sync_all()
+}
+
+/// The `scope:key` lines of a keys file; blank lines and `#` comments are
+/// skipped, a line that is neither is a problem and left out
+pub fn parse_scoped(text: &str) -> (Vec<(Role, String)>, Vec<String>) {
+    let mut keys = Vec::new();
+    let mut problems = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (scope, key) = line.split_once(':').unwrap_or((line, ""));
+        let scope = match scope.trim() {
+            "read" => Role::ReadOnly,
+            "admin" => Role::Admin,
+            other => {
+                problems.push(format!(
+                    "line {}: scope {:?} is neither read nor admin",
+                    n + 1,
+                    other
+                ));
+                continue;
+            }
+        };
+        let key = key.trim();
+        if key.len() < MIN_SCOPED_KEY_LEN || key.chars().any(|c| c.is_whitespace()) {
+            problems.push(format!(
+                "line {}: a key has {} or more characters, without whitespace",
+                n + 1,
+                MIN_SCOPED_KEY_LEN
+            ));
+            continue;
+        }
+        keys.push((scope, key.to_owned()));
+    }
+    (keys, problems)
+}
+
+/// The keys of the keys file at `path`; none when there is no file
+pub fn load_scoped(path: &str) -> Vec<(Role, String)> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Vec::new(),
+        Err(e) => {
+            hbb_common::log::error!("API: Cannot read the keys file {}: {}", path, e);
+            return Vec::new();
+        }
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let Ok(metadata) = std::fs::metadata(path) {
+            if metadata.permissions().mode() & 0o077 != 0 {
+                hbb_common::log::warn!("API: {} is readable by others, chmod 600 it", path);
+            }
+        }
+    }
+    let (keys, problems) = parse_scoped(&text);
+    for problem in &problems {
+        hbb_common::log::error!("API: Keys file {}, {}: left out", path, problem);
+    }
+    let admin = keys
+        .iter()
+        .filter(|(scope, _)| *scope == Role::Admin)
+        .count();
+    hbb_common::log::info!(
+        "API: {} read and {} admin keys from {}",
+        keys.len() - admin,
+        admin,
+        path
+    );
+    keys
+}
+
+struct Keys {
+    current: String,
+    /// The replaced key and until when it is accepted
+    previous: Option<(String, Instant)>,
+    /// From the keys file
+    scoped: Vec<(Role, String)>,
+}
+
+/// One rotation at a time, so the key file ends up with the key swapped in last
+static ROTATING: Mutex<()> = Mutex::new(());
+
+/// The API keys of an ApiState, shared by its clones
+#[derive(Clone)]
+pub struct ApiKeys(Arc<RwLock<Keys>>);
+
+impl ApiKeys {
+    pub fn new(key: impl Into<String>) -> Self {
+        Self(Arc::new(RwLock::new(Keys {
+            current: key.into(),
+            previous: None,
+            scoped: Vec::new(),
+        })))
+    }
+
+    /// With the keys of a keys file next to the primary key
+    pub fn with_scoped(self, scoped: Vec<(Role, String)>) -> Self {
+        match self.0.write() {
+            Ok(mut keys) => keys.scoped = scoped,
+            Err(e) => e.into_inner().scoped = scoped,
+        }
+        self
+    }
+
+    pub fn current(&self) -> String {
+        match self.0.read() {
+            Ok(keys) => keys.current.clone(),
+            Err(e) => e.into_inner().current.clone(),
+        }
+    }
+
+    /// The scope of `key`: admin for the current primary key and the replaced one
+    /// within its grace period, that of its line for a key of the keys file;
+    /// None for any other. Compared in constant time like peer credentials.
+    pub fn scope_of(&self, key: &str) -> Option<Role> {
+        let keys = match self.0.read() {
+            Ok(keys) => keys,
+            Err(e) => e.into_inner(),
+        };
+        let matches = |other: &str| hbbs::ct_eq(key.as_bytes(), other.as_bytes());
+        if matches(&keys.current) {
+            return Some(Role::Admin);
+        }
+        if let Some((previous, until)) = &keys.previous {
+            if matches(previous) && Instant::now() < *until {
+                return Some(Role::Admin);
+            }
+        }
+        keys.scoped
+            .iter()
+            .find(|(_, scoped)| matches(scoped))
+            .map(|(scope, _)| *scope)
+    }
+
+    /// Persist a new key to `path`, then make it the current one; the replaced
+    /// key is accepted for `grace` more. Nothing changes when the file cannot be
+    /// written. Requests are checked against the old key while the file is
+    /// written, the write lock is only taken for the swap.
+    pub fn rotate(&self, path: &str, grace: Duration) -> std::io::Result<String> {
+        let _rotating = ROTATING.lock().unwrap_or_else(|e| e.into_inner());
+        let key = generate();
+        write_key_file(path, &key)?;
+        let mut keys = match self.0.write() {
+            Ok(keys) => keys,
+            Err(e) => e.into_inner(),
+        };
+        let previous = std::mem::replace(&mut keys.current, key.clone());
+        keys.previous = Some((previous, Instant::now() + grace));
+        Ok(key)
+    }
+}
@@ -0,0 +1,256 @@
+// Per-route latency and errors of the HTTP API, for `/metrics` and
+// `GET /api/stats/api`
+// A route layer times every request that matched a route, by the route pattern
+// (`/api/peers/:id`, never the concrete path), so the label set is fixed by
+// the router. Requests slower than API_SLOW_MS are logged with their route,
+// duration and caller. Handlers run their database work through query() here,
+// and the API pools (pool_options()) note when a connection was handed out, so
+// that line splits the database time into waiting for a pool connection and
+// running queries.
+
+use axum::{
+    extract::{ConnectInfo, MatchedPath},
+    http::Request,
+    middleware::Next,
+    response::Response,
+};
+use hbb_common::{log, tokio};
+use hbbs::{Histogram, MetricsText};
+use serde::Serialize;
+use sqlx::sqlite::SqlitePoolOptions;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+const SLOW_MS: u64 = 1_000; // API_SLOW_MS, 0 disables the log
+
+#[derive(Default)]
+struct RouteStats {
+    latency: Histogram,
+    client_errors: AtomicUsize,
+    server_errors: AtomicUsize,
+}
+
+lazy_static::lazy_static! {
+    // keyed by route pattern; patterns are leaked once, there are only as many
+    // as the router has routes
+    static ref ROUTES: RwLock<BTreeMap<&'static str, Arc<RouteStats>>> = Default::default();
+}
+
+tokio::task_local! {
+    static DB_TIMING: Arc<DbTiming>;
+}
+
+/// Database time of the request being handled
+#[derive(Debug, Default)]
+pub struct DbTiming {
+    wait_us: AtomicU64,
+    query_us: AtomicU64,
+    // when the pool last handed a connection to this request
+    acquired: Mutex<Option<Instant>>,
+}
+
+impl DbTiming {
+    pub fn wait(&self) -> Duration {
+        Duration::from_micros(self.wait_us.load(Ordering::Relaxed))
+    }
+
+    pub fn query(&self) -> Duration {
+        Duration::from_micros(self.query_us.load(Ordering::Relaxed))
+    }
+}
+
+/// Pool options noting for the request being handled when it got a connection,
+/// reused from the idle ones or newly opened
+pub fn pool_options() -> SqlitePoolOptions {
+    SqlitePoolOptions::new()
+        .after_connect(|_, _| {
+            connection_acquired();
+            Box::pin(async { Ok(()) })
+        })
+        .before_acquire(|_, _| {
+            connection_acquired();
+            Box::pin(async { Ok(true) })
+        })
+}
+
+fn connection_acquired() {
+    DB_TIMING
+        .try_with(|x| {
+            if let Ok(mut acquired) = x.acquired.lock() {
+                *acquired = Some(Instant::now());
+            }
+        })
+        .ok();
+}
+
+/// Run database work of the current request: the time until the pool handed
+/// out a connection counts as waiting, the rest as queries
+pub async fn query<F: Future>(work: F) -> F::Output {
+    let started = Instant::now();
+    let output = work.await;
+    let finished = Instant::now();
+    DB_TIMING
+        .try_with(|x| {
+            let acquired = x
+                .acquired
+                .lock()
+                .ok()
+                .and_then(|mut x| x.take())
+                .filter(|at| *at >= started)
+                .unwrap_or(started);
+            let (wait, query) = (acquired - started, finished - acquired);
+            x.wait_us
+                .fetch_add(wait.as_micros() as u64, Ordering::Relaxed);
+            x.query_us
+                .fetch_add(query.as_micros() as u64, Ordering::Relaxed);
+        })
+        .ok();
+    output
+}
+
+/// Run `work` as a request with its own database timing
+pub async fn timed<F: Future>(timing: Arc<DbTiming>, work: F) -> F::Output {
+    DB_TIMING.scope(timing, work).await
+}
+
+/// Route layer timing each request by its matched route
+pub async fn track<B>(
+    matched: MatchedPath,
+    ConnectInfo(caller): ConnectInfo<SocketAddr>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let started = Instant::now();
+    let timing = Arc::new(DbTiming::default());
+    let res = timed(timing.clone(), next.run(req)).await;
+    record(
+        matched.as_str(),
+        res.status().as_u16(),
+        started.elapsed(),
+        caller,
+        &timing,
+    );
+    res
+}
+
+/// Count one finished request, and log it when slower than API_SLOW_MS
+pub fn record(route: &str, status: u16, elapsed: Duration, caller: SocketAddr, db: &DbTiming) {
+    let stats = route_stats(route);
+    stats.latency.observe(elapsed);
+    match status {
+        400..=499 => stats.client_errors.fetch_add(1, Ordering::Relaxed),
+        500..=599 => stats.server_errors.fetch_add(1, Ordering::Relaxed),
+        _ => 0,
+    };
+    let slow_ms = crate::sync::env_u64("API_SLOW_MS", SLOW_MS);
+    if slow_ms == 0 || elapsed < Duration::from_millis(slow_ms) {
+        return;
+    }
+    let (wait, query) = (db.wait(), db.query());
+    if wait.is_zero() && query.is_zero() {
+        log::warn!(
+            "Slow API request: {} took {}ms (status {}, from {})",
+            route,
+            elapsed.as_millis(),
+            status,
+            caller.ip()
+        );
+    } else {
+        log::warn!(
+            "Slow API request: {} took {}ms (status {}, from {}), database: {}ms waiting for \
+             a connection, {}ms in queries",
+            route,
+            elapsed.as_millis(),
+            status,
+            caller.ip(),
+            wait.as_millis(),
+            query.as_millis()
+        );
+    }
+}
+
+fn route_stats(route: &str) -> Arc<RouteStats> {
+    if let Some(x) = ROUTES.read().ok().and_then(|x| x.get(route).cloned()) {
+        return x;
+    }
+    let mut lock = match ROUTES.write() {
+        Ok(x) => x,
+        Err(_) => return Default::default(),
+    };
+    if let Some(x) = lock.get(route) {
+        return x.clone();
+    }
+    let stats: Arc<RouteStats> = Default::default();
+    lock.insert(Box::leak(route.to_owned().into_boxed_str()), stats.clone());
+    stats
+}
+
+#[derive(Debug, Serialize)]
+pub struct RouteUsage {
+    pub route: &'static str,
+    pub requests: usize,
+    pub client_errors: usize,
+    pub server_errors: usize,
+    pub avg_ms: f64,
+    /// (upper bound in ms, requests at or below it)
+    pub buckets: Vec<(u64, usize)>,
+}
+
+/// Routes requested since start, by route pattern
+pub fn usage() -> Vec<RouteUsage> {
+    let routes = match ROUTES.read() {
+        Ok(x) => x,
+        Err(_) => return Vec::new(),
+    };
+    routes
+        .iter()
+        .map(|(route, x)| {
+            let requests = x.latency.count();
+            RouteUsage {
+                route: *route,
+                requests,
+                client_errors: x.client_errors.load(Ordering::Relaxed),
+                server_errors: x.server_errors.load(Ordering::Relaxed),
+                avg_ms: x.latency.sum().as_secs_f64() * 1000. / requests.max(1) as f64,
+                buckets: x.latency.buckets(),
+            }
+        })
+        .collect()
+}
+
+/// Prometheus lines for the API routes, appended to /metrics
+pub fn render_metrics() -> String {
+    let routes: Vec<(&'static str, Arc<RouteStats>)> = match ROUTES.read() {
+        Ok(x) => x.iter().map(|(route, x)| (*route, x.clone())).collect(),
+        Err(_) => return String::new(),
+    };
+    let mut m = MetricsText::default();
+    let hs: Vec<(&'static str, &Histogram)> = routes
+        .iter()
+        .map(|(route, x)| (*route, &x.latency))
+        .collect();
+    m.histograms(
+        "hbbs_api_request_seconds",
+        "HTTP API latency by route pattern",
+        "route",
+        &hs,
+    );
+    let mut out = m.finish();
+    out.push_str("# HELP hbbs_api_errors_total HTTP API error responses by route pattern\n");
+    out.push_str("# TYPE hbbs_api_errors_total counter\n");
+    for (route, x) in &routes {
+        for (class, n) in [("4xx", &x.client_errors), ("5xx", &x.server_errors)] {
+            out.push_str(&format!(
+                "hbbs_api_errors_total{{route=\"{}\",class=\"{}\"}} {}\n",
+                route,
+                class,
+                n.load(Ordering::Relaxed)
+            ));
+        }
+    }
+    out
+}
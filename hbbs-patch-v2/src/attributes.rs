@@ -0,0 +1,219 @@
+// Custom per-peer attributes for `/api/peers/:id/attributes`
+// Free-form key/value pairs (asset tag, owner, cost center) kept in
+// peer_attributes by the peer's guid, so they follow id changes and are still
+// there when a soft-deleted peer is restored. A PUT merges into the stored map
+// (null removes a key) in one transaction that is rolled back when the result
+// breaks a cap. Listings filter on `?attr=key:value` through the (key, value)
+// index. Changes bump the peer's version (see peerversion.rs).
+
+use crate::peerversion::Bump;
+use sqlx::{sqlite::SqlitePool, Row};
+use std::collections::BTreeMap;
+
+pub const MAX_KEYS: usize = 20;
+pub const MAX_KEY_BYTES: usize = 128;
+pub const MAX_VALUE_BYTES: usize = 1024;
+
+pub type Attributes = BTreeMap<String, String>;
+
+#[derive(Debug)]
+pub enum AttributeError {
+    NoSuchPeer(String),
+    Invalid(String),
+    /// The merge would leave the peer with this many keys
+    TooMany(usize),
+    /// The peer's version is no longer the If-Match one, but this
+    Conflict(i64),
+    Database(sqlx::Error),
+}
+
+impl std::fmt::Display for AttributeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AttributeError::NoSuchPeer(id) => write!(f, "Peer {} not found", id),
+            AttributeError::Invalid(problem) => f.write_str(problem),
+            AttributeError::TooMany(n) => write!(
+                f,
+                "A peer can have at most {} attributes, this would leave {}",
+                MAX_KEYS, n
+            ),
+            AttributeError::Conflict(version) => write!(
+                f,
+                "Peer was changed meanwhile, its version is now {}",
+                version
+            ),
+            AttributeError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for AttributeError {
+    fn from(e: sqlx::Error) -> Self {
+        AttributeError::Database(e)
+    }
+}
+
+/// Keys are 1-128 bytes without ':' (the filter separator) or control characters
+pub fn check_key(key: &str) -> Result<(), String> {
+    if key.is_empty() || key.len() > MAX_KEY_BYTES {
+        return Err(format!(
+            "Attribute key {:?} must be 1-{} bytes",
+            key, MAX_KEY_BYTES
+        ));
+    }
+    if key.contains(':') || key.chars().any(char::is_control) {
+        return Err(format!(
+            "Attribute key {:?} cannot contain ':' or control characters",
+            key
+        ));
+    }
+    Ok(())
+}
+
+pub fn check_value(key: &str, value: &str) -> Result<(), String> {
+    if value.len() > MAX_VALUE_BYTES {
+        return Err(format!(
+            "Value of attribute {:?} is {} bytes, at most {} allowed",
+            key,
+            value.len(),
+            MAX_VALUE_BYTES
+        ));
+    }
+    Ok(())
+}
+
+/// The (key, value) of an `attr=key:value` filter; the value may contain ':'
+pub fn parse_filter(filter: &str) -> Result<(String, String), String> {
+    let (key, value) = filter
+        .split_once(':')
+        .ok_or_else(|| format!("Invalid attr filter {:?}, expected key:value", filter))?;
+    check_key(key)?;
+    Ok((key.to_owned(), value.to_owned()))
+}
+
+/// SQL condition on `peer` for filters from parse_filter, one pair of binds each
+pub fn filter_clause(filters: usize) -> String {
+    " AND guid IN (SELECT guid FROM peer_attributes WHERE key = ? AND value = ?)".repeat(filters)
+}
+
+async fn guid_of(conn: &mut sqlx::SqliteConnection, id: &str) -> Result<Vec<u8>, AttributeError> {
+    sqlx::query("SELECT guid FROM peer WHERE id = ? AND is_deleted = 0")
+        .bind(id)
+        .fetch_optional(conn)
+        .await?
+        .map(|row| row.get("guid"))
+        .ok_or_else(|| AttributeError::NoSuchPeer(id.to_owned()))
+}
+
+async fn bump(
+    conn: &mut sqlx::SqliteConnection,
+    id: &str,
+    expected: Option<i64>,
+) -> Result<i64, AttributeError> {
+    match crate::peerversion::bump(conn, id, expected).await? {
+        Bump::Done(version) => Ok(version),
+        Bump::Conflict(version) => Err(AttributeError::Conflict(version)),
+        Bump::NoSuchPeer => Err(AttributeError::NoSuchPeer(id.to_owned())),
+    }
+}
+
+async fn load(conn: &mut sqlx::SqliteConnection, guid: &[u8]) -> Result<Attributes, sqlx::Error> {
+    let rows = sqlx::query("SELECT key, value FROM peer_attributes WHERE guid = ?")
+        .bind(guid)
+        .fetch_all(conn)
+        .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.get("key"), row.get("value")))
+        .collect())
+}
+
+/// Attributes of a peer that is not deleted
+pub async fn get(pool: &SqlitePool, id: &str) -> Result<Attributes, AttributeError> {
+    let mut conn = pool.acquire().await?;
+    let guid = guid_of(&mut conn, id).await?;
+    Ok(load(&mut conn, &guid).await?)
+}
+
+/// Set the given keys, remove those mapped to None, while the peer's version
+/// is `expected` (any without); returns the resulting map and version
+pub async fn merge(
+    pool: &SqlitePool,
+    id: &str,
+    changes: &BTreeMap<String, Option<String>>,
+    expected: Option<i64>,
+) -> Result<(Attributes, i64), AttributeError> {
+    for (key, value) in changes {
+        check_key(key).map_err(AttributeError::Invalid)?;
+        if let Some(value) = value {
+            check_value(key, value).map_err(AttributeError::Invalid)?;
+        }
+    }
+    let now = chrono::Utc::now().timestamp();
+    let mut tx = pool.begin().await?;
+    let version = bump(&mut tx, id, expected).await?;
+    let guid = guid_of(&mut tx, id).await?;
+    for (key, value) in changes {
+        match value {
+            Some(value) => {
+                sqlx::query(
+                    "INSERT INTO peer_attributes (guid, key, value, updated_at) VALUES (?, ?, ?, ?)
+                    ON CONFLICT (guid, key) DO UPDATE SET value = excluded.value, updated_at = excluded.updated_at",
+                )
+                .bind(&guid)
+                .bind(key)
+                .bind(value)
+                .bind(now)
+                .execute(&mut *tx)
+                .await?;
+            }
+            None => {
+                sqlx::query("DELETE FROM peer_attributes WHERE guid = ? AND key = ?")
+                    .bind(&guid)
+                    .bind(key)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+    }
+    let attributes = load(&mut tx, &guid).await?;
+    if attributes.len() > MAX_KEYS {
+        // dropping the transaction rolls it back
+        return Err(AttributeError::TooMany(attributes.len()));
+    }
+    tx.commit().await?;
+    if changes.contains_key(hbbs::TAGS_ATTRIBUTE) {
+        hbbs::invalidate_access_rules();
+        hbbs::invalidate_broadcasts();
+    }
+    Ok((attributes, version))
+}
+
+/// Remove one key while the peer's version is `expected` (any without);
+/// Ok(None) when the peer does not have it
+pub async fn remove(
+    pool: &SqlitePool,
+    id: &str,
+    key: &str,
+    expected: Option<i64>,
+) -> Result<Option<(Attributes, i64)>, AttributeError> {
+    let mut tx = pool.begin().await?;
+    let version = bump(&mut tx, id, expected).await?;
+    let guid = guid_of(&mut tx, id).await?;
+    let res = sqlx::query("DELETE FROM peer_attributes WHERE guid = ? AND key = ?")
+        .bind(&guid)
+        .bind(key)
+        .execute(&mut *tx)
+        .await?;
+    if res.rows_affected() == 0 {
+        // nothing changed, dropping the transaction undoes the bump
+        return Ok(None);
+    }
+    let attributes = load(&mut tx, &guid).await?;
+    tx.commit().await?;
+    if key == hbbs::TAGS_ATTRIBUTE {
+        hbbs::invalidate_access_rules();
+        hbbs::invalidate_broadcasts();
+    }
+    Ok(Some((attributes, version)))
+}
@@ -0,0 +1,196 @@
+// Admin text notices for `/api/admin/broadcast`
+// A broadcast goes to all peers, those with a tag (the `tags` attribute) or a
+// list of ids. The rendezvous side attaches it to the next registration
+// response of each matching peer (a PeerNotice of kind hbbs::BROADCAST_NOTICE
+// in field hbbs::NOTICE_FIELD) until it expires, once per peer, and records the
+// delivery; the progress is counted here against the peers it targets.
+
+use hbbs::Broadcast;
+use serde::Serialize;
+use sqlx::{
+    sqlite::{SqlitePool, SqliteRow},
+    Row,
+};
+
+pub const MAX_MESSAGE_CHARS: usize = 500;
+pub const MAX_IDS: usize = 1_000;
+/// Furthest expiry accepted, a week
+pub const MAX_LIFETIME_SECS: i64 = 7 * 24 * 3600;
+
+#[derive(Debug, Serialize)]
+pub struct Delivery {
+    pub peer_id: String,
+    pub delivered_at: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Progress {
+    #[serde(flatten)]
+    pub broadcast: Broadcast,
+    pub expired: bool,
+    /// Peers known to the server the broadcast is meant for
+    pub targeted: usize,
+    pub delivered: usize,
+    pub deliveries: Vec<Delivery>,
+}
+
+/// Plain text of 1-MAX_MESSAGE_CHARS characters: no control characters other
+/// than newlines, and no markup
+pub fn check_message(message: &str) -> Result<(), String> {
+    let chars = message.chars().count();
+    if message.trim().is_empty() || chars > MAX_MESSAGE_CHARS {
+        return Err(format!(
+            "Message must be 1-{} characters, got {}",
+            MAX_MESSAGE_CHARS, chars
+        ));
+    }
+    if message.chars().any(|c| c.is_control() && c != '\n') {
+        return Err("Message cannot contain control characters".to_owned());
+    }
+    if message.contains('<') || message.contains('>') {
+        return Err("Message must be plain text, without < or >".to_owned());
+    }
+    Ok(())
+}
+
+/// The target as stored: ("all", ""), ("tag", tag) or ("ids", comma separated)
+pub fn check_target(
+    target: &str,
+    tag: Option<&str>,
+    ids: Option<&[String]>,
+) -> Result<(&'static str, String), String> {
+    match (target, tag, ids) {
+        ("all", None, None) => Ok(("all", String::new())),
+        ("tag", Some(tag), None) => {
+            crate::access::check_pattern(tag)?;
+            if tag == "*" || tag.starts_with("tag:") {
+                return Err(format!("Tag {:?} must be a plain tag name", tag));
+            }
+            Ok(("tag", tag.to_owned()))
+        }
+        ("ids", None, Some(ids)) => {
+            if ids.is_empty() || ids.len() > MAX_IDS {
+                return Err(format!("ids must list 1-{} peers", MAX_IDS));
+            }
+            for id in ids {
+                crate::access::check_pattern(id)?;
+                if id == "*" || id.starts_with("tag:") {
+                    return Err(format!("{:?} is not a peer id", id));
+                }
+            }
+            Ok(("ids", ids.join(",")))
+        }
+        ("all" | "tag" | "ids", _, _) => Err(format!(
+            "Target {} takes {}",
+            target,
+            match target {
+                "all" => "neither tag nor ids",
+                "tag" => "a tag and no ids",
+                _ => "ids and no tag",
+            }
+        )),
+        _ => Err(format!("Target {:?} is not one of all, tag, ids", target)),
+    }
+}
+
+/// In the future and at most MAX_LIFETIME_SECS ahead of `now`
+pub fn check_expiry(expires_at: i64, now: i64) -> Result<(), String> {
+    if expires_at <= now || expires_at > now + MAX_LIFETIME_SECS {
+        return Err(format!(
+            "expires_at must be within the next {} seconds",
+            MAX_LIFETIME_SECS
+        ));
+    }
+    Ok(())
+}
+
+/// Store a broadcast checked with check_message, check_target and check_expiry
+pub async fn create(
+    pool: &SqlitePool,
+    message: &str,
+    target: &str,
+    target_value: &str,
+    expires_at: i64,
+) -> Result<Broadcast, sqlx::Error> {
+    let row = sqlx::query(
+        "INSERT INTO broadcasts (message, target, target_value, created_at, expires_at)
+         VALUES (?, ?, ?, ?, ?)
+         RETURNING id, message, target, target_value, created_at, expires_at",
+    )
+    .bind(message)
+    .bind(target)
+    .bind(target_value)
+    .bind(chrono::Utc::now().timestamp())
+    .bind(expires_at)
+    .fetch_one(pool)
+    .await?;
+    hbbs::invalidate_broadcasts();
+    Ok(from_row(&row))
+}
+
+/// Delivery progress of a broadcast; Ok(None) when there is none with this id
+pub async fn progress(pool: &SqlitePool, id: i64) -> Result<Option<Progress>, sqlx::Error> {
+    let row = sqlx::query(
+        "SELECT id, message, target, target_value, created_at, expires_at FROM broadcasts
+         WHERE id = ?",
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+    let broadcast = match row {
+        Some(row) => from_row(&row),
+        None => return Ok(None),
+    };
+    let deliveries: Vec<Delivery> = sqlx::query(
+        "SELECT peer_id, delivered_at FROM broadcast_deliveries WHERE broadcast_id = ?
+         ORDER BY delivered_at, peer_id",
+    )
+    .bind(id)
+    .fetch_all(pool)
+    .await?
+    .iter()
+    .map(|row| Delivery {
+        peer_id: row.get("peer_id"),
+        delivered_at: row.get("delivered_at"),
+    })
+    .collect();
+    let targeted = match broadcast.target.as_str() {
+        "all" => sqlx::query("SELECT count(*) FROM peer WHERE is_deleted = 0")
+            .fetch_one(pool)
+            .await?
+            .get::<i64, _>(0) as usize,
+        "tag" => sqlx::query(
+            "SELECT a.value FROM peer_attributes a JOIN peer p ON p.guid = a.guid
+             WHERE a.key = ? AND p.is_deleted = 0",
+        )
+        .bind(hbbs::TAGS_ATTRIBUTE)
+        .fetch_all(pool)
+        .await?
+        .iter()
+        .filter(|row| {
+            row.get::<String, _>("value")
+                .split(',')
+                .any(|x| x.trim() == broadcast.target_value)
+        })
+        .count(),
+        _ => broadcast.target_value.split(',').count(),
+    };
+    Ok(Some(Progress {
+        expired: broadcast.expires_at <= chrono::Utc::now().timestamp(),
+        targeted,
+        delivered: deliveries.len(),
+        deliveries,
+        broadcast,
+    }))
+}
+
+fn from_row(row: &SqliteRow) -> Broadcast {
+    Broadcast {
+        id: row.get("id"),
+        message: row.get("message"),
+        target: row.get("target"),
+        target_value: row.get("target_value"),
+        created_at: row.get("created_at"),
+        expires_at: row.get("expires_at"),
+    }
+}
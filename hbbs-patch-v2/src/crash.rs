@@ -0,0 +1,266 @@
+// Exit codes and crash reports
+// Each fatal path ends the process with a code of its own (ExitCode), so a
+// supervisor can tell a taken port from a broken database. A panic in one of
+// the supervised background tasks (hbbs::supervise) is only logged, the task is
+// restarted. A panic in any other tokio task (a connection, an API handler)
+// ends that task only, as tokio catches it; it is logged and reported, and the
+// server keeps running. A panic outside a task, on the main thread or a thread
+// of its own, synchronously writes crash-<time>.txt next to the database before
+// exiting: the panic message and location, a backtrace, the last buffered log
+// lines and the effective settings (the key only as a fingerprint). A
+// last-crash file in the same directory points the next start at the report
+// for `/api/health`.
+
+use flexi_logger::LoggerHandle;
+use hbb_common::{anyhow, log};
+use serde::Serialize;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+
+const REPORT_LOG_LINES: usize = 100;
+const LAST_CRASH_FILE: &str = "last-crash";
+
+/// Process exit codes; documented in INSTALLATION.md, keep them stable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    Failure = 1,
+    InvalidConfig = 2,
+    PortBind = 3,
+    Database = 4,
+    SelfTest = 5,
+    Panic = 6,
+}
+
+impl ExitCode {
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+}
+
+impl std::fmt::Display for ExitCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            ExitCode::Failure => "failed",
+            ExitCode::InvalidConfig => "invalid configuration",
+            ExitCode::PortBind => "cannot listen on a port",
+            ExitCode::Database => "database error",
+            ExitCode::SelfTest => "self-test failed",
+            ExitCode::Panic => "panic",
+        })
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref LOGGER: Mutex<Option<LoggerHandle>> = Default::default();
+    static ref PREVIOUS: RwLock<Option<PreviousCrash>> = Default::default();
+}
+
+/// The crash report the previous run left behind
+#[derive(Debug, Clone, Serialize)]
+pub struct PreviousCrash {
+    /// File name in the database's directory
+    pub report: String,
+    pub crashed_at: String,
+}
+
+/// Keeps the logger for flushing on exit and installs the panic hook
+pub fn install(logger: LoggerHandle) {
+    if let Ok(mut lock) = LOGGER.lock() {
+        *lock = Some(logger);
+    }
+    std::panic::set_hook(Box::new(|info| {
+        let message = match info.payload().downcast_ref::<&str>() {
+            Some(s) => s.to_string(),
+            None => match info.payload().downcast_ref::<String>() {
+                Some(s) => s.clone(),
+                None => "Box<dyn Any>".to_owned(),
+            },
+        };
+        let location = info
+            .location()
+            .map(|x| format!("{}:{}:{}", x.file(), x.line(), x.column()))
+            .unwrap_or_default();
+        if let Some(task) = hbbs::supervised_task() {
+            log::error!(
+                "panic in task {} at {}: {}; restarting it",
+                task,
+                location,
+                message
+            );
+            return;
+        }
+        let thread = std::thread::current()
+            .name()
+            .unwrap_or("unnamed")
+            .to_owned();
+        // tokio catches the unwind of a task and only that task ends
+        let task = hbb_common::tokio::task::try_id();
+        let place = match task {
+            Some(id) => format!("task {} on thread {}", id, thread),
+            None => format!("thread {}", thread),
+        };
+        match write_report(&data_dir(), &message, &location, &place, task.is_none()) {
+            Ok(path) => log::error!(
+                "panic in {} at {}: {}; crash report: {}",
+                place,
+                location,
+                message,
+                path.display()
+            ),
+            Err(e) => log::error!(
+                "panic in {} at {}: {}; no crash report: {}",
+                place,
+                location,
+                message,
+                e
+            ),
+        }
+        if task.is_none() {
+            exit(ExitCode::Panic);
+        }
+    }));
+}
+
+/// Flushes the log and ends the process: 0 for Ok, the error's ExitCode otherwise
+pub fn finish(res: hbb_common::ResultType<()>) -> ! {
+    match res {
+        Ok(()) => exit_with(0),
+        Err(e) => {
+            let code = exit_code(&e);
+            log::error!("{:#} (exit code {})", e, code.code());
+            exit(code)
+        }
+    }
+}
+
+pub fn exit(code: ExitCode) -> ! {
+    exit_with(code.code())
+}
+
+fn exit_with(code: i32) -> ! {
+    // try_lock: a panic while flushing must not wait on itself
+    if let Ok(mut lock) = LOGGER.try_lock() {
+        if let Some(logger) = lock.take() {
+            logger.flush();
+            logger.shutdown();
+        }
+    }
+    std::process::exit(code)
+}
+
+/// ExitCode attached as context, else told by the underlying error: database
+/// and storage errors, then addresses that cannot be bound
+pub fn exit_code(e: &anyhow::Error) -> ExitCode {
+    if let Some(code) = e.downcast_ref::<ExitCode>() {
+        return *code;
+    }
+    for cause in e.chain() {
+        if cause.downcast_ref::<sqlx::Error>().is_some()
+            || cause.downcast_ref::<hbbs::StorageIssue>().is_some()
+        {
+            return ExitCode::Database;
+        }
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            use std::io::ErrorKind::*;
+            if matches!(e.kind(), AddrInUse | AddrNotAvailable | PermissionDenied) {
+                return ExitCode::PortBind;
+            }
+        }
+    }
+    ExitCode::Failure
+}
+
+/// Where crash reports go: the database's directory
+pub fn data_dir() -> PathBuf {
+    let db = std::env::var("DB_URL").unwrap_or_default();
+    match Path::new(db.trim_start_matches("sqlite://")).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_owned(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// `fatal` for a panic ending the process, which the next start announces
+fn write_report(
+    dir: &Path,
+    message: &str,
+    location: &str,
+    place: &str,
+    fatal: bool,
+) -> std::io::Result<PathBuf> {
+    let now = chrono::Utc::now();
+    let name = format!("crash-{}.txt", now.format("%Y%m%d-%H%M%S"));
+    let mut report = format!(
+        "hbbs {} crash report\ntime: {}\nin: {}\nfatal: {}\nlocation: {}\npanic: {}\n\nbacktrace:\n{}\n",
+        env!("CARGO_PKG_VERSION"),
+        now.to_rfc3339(),
+        place,
+        if fatal { "yes" } else { "no, the task ended" },
+        location,
+        message,
+        std::backtrace::Backtrace::force_capture()
+    );
+    report.push_str("\nsettings:\n");
+    match hbbs::server_config() {
+        Some(fields) => {
+            for x in fields {
+                report.push_str(&format!("  {} = {}\n", x.field, x.value));
+            }
+        }
+        None => report.push_str("  (rendezvous server not running)\n"),
+    }
+    report.push_str(&format!("\nlast {} log lines:\n", REPORT_LOG_LINES));
+    for x in crate::logs::recent(REPORT_LOG_LINES, log::LevelFilter::Trace, "") {
+        report.push_str(&format!(
+            "{} {} {}: {}\n",
+            x.timestamp, x.level, x.target, x.message
+        ));
+    }
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(&name);
+    let mut file = std::fs::File::create(&path)?;
+    file.write_all(report.as_bytes())?;
+    file.sync_all()?;
+    if !fatal {
+        return Ok(path);
+    }
+    std::fs::write(
+        dir.join(LAST_CRASH_FILE),
+        format!("{}\n{}\n", name, now.to_rfc3339()),
+    )?;
+    Ok(path)
+}
+
+/// Reads and removes the last-crash pointer in `dir`, so a report is announced
+/// by the start right after the crash only
+pub fn take_previous_in(dir: &Path) -> Option<PreviousCrash> {
+    let path = dir.join(LAST_CRASH_FILE);
+    let text = std::fs::read_to_string(&path).ok()?;
+    if let Err(e) = std::fs::remove_file(&path) {
+        log::warn!("cannot remove {}: {}", path.display(), e);
+    }
+    let mut lines = text.lines();
+    Some(PreviousCrash {
+        report: lines.next()?.to_owned(),
+        crashed_at: lines.next().unwrap_or_default().to_owned(),
+    })
+}
+
+/// At startup: note a crash of the previous run for the log and `/api/health`
+pub fn load_previous() {
+    let previous = take_previous_in(&data_dir());
+    if let Some(x) = &previous {
+        log::warn!(
+            "The previous run crashed at {}, see {}",
+            x.crashed_at,
+            data_dir().join(&x.report).display()
+        );
+    }
+    if let Ok(mut lock) = PREVIOUS.write() {
+        *lock = previous;
+    }
+}
+
+pub fn previous() -> Option<PreviousCrash> {
+    PREVIOUS.read().ok().and_then(|x| x.clone())
+}
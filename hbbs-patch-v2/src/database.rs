@@ -1,118 +1,2222 @@
+use crate::peer::{canonical_id, env_u64, RecoveryStatus, SharedClock, SystemClock, DAY_SECONDS};
+use crate::rendezvous_server::{
+    count_legacy_timestamps, count_status_writes, emit_event, EventKind, Histogram,
+};
 use async_trait::async_trait;
+use serde_derive::{Deserialize, Serialize};
 use hbb_common::{log, ResultType, tokio};
 use sqlx::{
-    sqlite::SqliteConnectOptions, ConnectOptions, Connection, Error as SqlxError, Row, SqliteConnection,
+    sqlite::{SqliteConnectOptions, SqliteJournalMode},
+    ConnectOptions, Connection, Error as SqlxError, Row, SqliteConnection,
 };
-use std::{ops::DerefMut, str::FromStr, sync::Arc};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant};
 
-type Pool = deadpool::managed::Pool<DbPool>;
+type Pool = deadpool::managed::Pool<DbPool>;
+
+/// Prepared statements each connection keeps (DB_STATEMENT_CACHE); the writer
+/// runs most of the distinct queries, the hot ones must not drop out
+pub const STATEMENT_CACHE: u64 = 256;
+
+// The hot queries, as fixed strings so each connection prepares them once
+const SET_ONLINE: &str = "UPDATE peer SET status = 1, last_online = datetime('now') WHERE id = ?";
+const SET_OFFLINE: &str = "UPDATE peer SET status = 0 WHERE id = ?";
+const IS_BANNED: &str =
+    "SELECT is_banned = 1 AND (banned_until IS NULL OR banned_until > ?) AS banned
+     FROM peer WHERE id = ?";
+
+// Operations timed for the hbbs_db_operation_seconds histogram (fixed label set)
+const DB_OPERATIONS: [&str; 8] = [
+    "get_peer",
+    "insert_peer",
+    "update_pk",
+    "set_online",
+    "status_flush",
+    "batch_set_offline",
+    "is_device_banned",
+    "change_peer_id",
+];
+
+lazy_static::lazy_static! {
+    static ref DB_TIMINGS: Vec<(&'static str, Histogram)> = DB_OPERATIONS
+        .iter()
+        .map(|op| (*op, Histogram::default()))
+        .collect();
+}
+
+#[inline]
+fn observe(op: &str, started: Instant) {
+    if let Some((_, h)) = DB_TIMINGS.iter().find(|(x, _)| *x == op) {
+        h.observe(started.elapsed());
+    }
+}
+
+/// Connection pool sizes for the hbbs_db_pool_* gauges, keyed by pool name
+pub type PoolStatsFn = Box<dyn Fn() -> PoolStats + Send + Sync>;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PoolStats {
+    pub size: usize,
+    pub idle: usize,
+    pub max_size: usize,
+}
+
+lazy_static::lazy_static! {
+    static ref POOL_STATS: RwLock<Vec<(&'static str, PoolStatsFn)>> = Default::default();
+}
+
+/// Register a pool under `name` (replacing an earlier one of the same name)
+pub fn register_pool_stats(name: &'static str, stats: PoolStatsFn) {
+    if let Ok(mut pools) = POOL_STATS.write() {
+        pools.retain(|(x, _)| *x != name);
+        pools.push((name, stats));
+    }
+}
+
+pub(crate) fn pool_stats() -> Vec<(&'static str, PoolStats)> {
+    POOL_STATS
+        .read()
+        .map(|pools| pools.iter().map(|(name, f)| (*name, f())).collect())
+        .unwrap_or_default()
+}
+
+fn deadpool_stats(pool: &Pool) -> PoolStats {
+    let status = pool.status();
+    PoolStats {
+        size: status.size as _,
+        idle: status.available as _,
+        max_size: status.max_size as _,
+    }
+}
+
+pub(crate) fn db_histograms() -> Vec<(&'static str, &'static Histogram)> {
+    let timings: &'static Vec<(&'static str, Histogram)> = &DB_TIMINGS;
+    timings.iter().map(|(op, h)| (*op, h)).collect()
+}
+
+pub struct DbPool {
+    url: String,
+    read_only: bool,
+}
+
+#[async_trait]
+impl deadpool::managed::Manager for DbPool {
+    type Type = SqliteConnection;
+    type Error = SqlxError;
+    async fn create(&self) -> Result<SqliteConnection, SqlxError> {
+        let mut opt = SqliteConnectOptions::from_str(&self.url).unwrap();
+        // WAL so the readers never block the writer and vice versa
+        opt = if self.read_only {
+            opt.read_only(true)
+        } else {
+            opt.journal_mode(SqliteJournalMode::Wal)
+        };
+        let cache = env_u64("DB_STATEMENT_CACHE", STATEMENT_CACHE) as usize;
+        opt = opt.statement_cache_capacity(cache);
+        opt.log_statements(log::LevelFilter::Debug);
+        SqliteConnection::connect_with(&opt).await
+    }
+    async fn recycle(
+        &self,
+        obj: &mut SqliteConnection,
+    ) -> deadpool::managed::RecycleResult<SqlxError> {
+        Ok(obj.ping().await?)
+    }
+}
+
+/// All INSERT/UPDATE traffic goes through `writer`, a pool of one connection, so
+/// writes are serialized in-process instead of contending for SQLite's lock;
+/// lookups, listings and reports use the read-only `reader` pool.
+#[derive(Clone)]
+pub struct Database {
+    writer: Pool,
+    reader: Pool,
+    url: String,
+    // stamps status events, server runs and audit entries
+    clock: SharedClock,
+    status_queue: std::sync::Arc<std::sync::Mutex<StatusQueue>>,
+}
+
+/// Status columns of a peer row, as compared by the consistency check
+pub struct PeerStatusRow {
+    pub id: String,
+    pub status: Option<i64>,
+    pub last_online: Option<String>,
+}
+
+/// Last known health of a relay server, persisted so a restart does not assume
+/// every relay is up until the first check
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RelayHealth {
+    pub host: String,
+    pub healthy: bool,
+    pub latency_ms: Option<u64>,
+    pub failures: u32,
+    pub checked_at: i64,
+}
+
+/// A peer-affecting admin action (rename, key_reset, ban) waiting for the client:
+/// handed out in its first registration response, removed on the next
+/// registration from `peer_id` or `ack_id` (the new id of a rename)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PeerNotice {
+    pub seq: i64,
+    pub peer_id: String,
+    pub ack_id: String,
+    pub kind: String,
+    pub detail: serde_json::Value,
+    pub created_at: i64,
+    pub delivered_at: Option<i64>,
+}
+
+impl PeerNotice {
+    fn from_row(row: &sqlx::sqlite::SqliteRow) -> Self {
+        PeerNotice {
+            seq: row.get("seq"),
+            peer_id: row.get("peer_id"),
+            ack_id: row.get("ack_id"),
+            kind: row.get("kind"),
+            detail: serde_json::from_str(&row.get::<String, _>("detail")).unwrap_or_default(),
+            created_at: row.get("created_at"),
+            delivered_at: row.get("delivered_at"),
+        }
+    }
+}
+
+/// A server-side rule on which controller may connect to which target
+/// (`/api/access-rules`). Patterns are an exact id, `tag:<name>` or `*`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AccessRule {
+    pub id: i64,
+    pub controller: String,
+    pub target: String,
+    /// "allow" or "deny"
+    pub action: String,
+    /// Higher first; at equal priority a deny wins
+    pub priority: i64,
+    pub created_at: i64,
+}
+
+/// An admin text notice for online peers (`/api/admin/broadcast`), attached to
+/// their registration responses until `expires_at`, once per peer
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Broadcast {
+    pub id: i64,
+    pub message: String,
+    /// "all", "tag" or "ids"
+    pub target: String,
+    /// The tag, or the ids comma separated; empty for "all"
+    pub target_value: String,
+    pub created_at: i64,
+    pub expires_at: i64,
+}
+
+impl Broadcast {
+    fn from_row(row: &sqlx::sqlite::SqliteRow) -> Self {
+        Broadcast {
+            id: row.get("id"),
+            message: row.get("message"),
+            target: row.get("target"),
+            target_value: row.get("target_value"),
+            created_at: row.get("created_at"),
+            expires_at: row.get("expires_at"),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Peer {
+    pub guid: Vec<u8>,
+    pub id: String,
+    pub uuid: Vec<u8>,
+    pub pk: Vec<u8>,
+    pub user: Option<Vec<u8>>,
+    pub info: String,
+    pub status: Option<i64>,
+}
+
+/// Who asked for an id change
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdChangeVia {
+    Api,
+    Client,
+}
+
+/// One hop of a peer's `previous_ids` history. Older rows store bare id strings;
+/// those read back with only `id` set and are rewritten on the next change.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IdHistoryEntry {
+    pub id: String,
+    /// RFC3339
+    #[serde(default)]
+    pub changed_at: Option<String>,
+    #[serde(default)]
+    pub via: Option<IdChangeVia>,
+    /// `key:<api key fingerprint> ip:<source>` for the API, `ip:<source>` for clients
+    #[serde(default)]
+    pub actor: Option<String>,
+}
+
+impl IdHistoryEntry {
+    pub fn new(id: &str, via: IdChangeVia, actor: String) -> Self {
+        Self {
+            id: id.to_owned(),
+            changed_at: Some(chrono::Utc::now().to_rfc3339()),
+            via: Some(via),
+            actor: Some(actor),
+        }
+    }
+}
+
+/// A `last_online` value as UTC. This version writes SQLite's `datetime('now')`
+/// form; RFC3339 and other ISO 8601 forms from other versions during a rolling
+/// upgrade are read too and counted in hbbs_legacy_timestamps_total, which
+/// stays flat once no process writes them any more.
+pub fn parse_last_online(ts: &str) -> Option<chrono::NaiveDateTime> {
+    use chrono::NaiveDateTime;
+    if let Ok(at) = NaiveDateTime::parse_from_str(ts, "%Y-%m-%d %H:%M:%S") {
+        return Some(at);
+    }
+    let at = chrono::DateTime::parse_from_rfc3339(ts)
+        .map(|at| at.naive_utc())
+        .or_else(|_| NaiveDateTime::parse_from_str(ts, "%Y-%m-%dT%H:%M:%S%.f"))
+        .or_else(|_| NaiveDateTime::parse_from_str(ts, "%Y-%m-%d %H:%M:%S%.f"))
+        .ok()?;
+    count_legacy_timestamps("read", 1);
+    Some(at)
+}
+
+/// Parse a `previous_ids` column, oldest first, accepting both the legacy array of
+/// id strings and the array of entry objects (or a mix of the two)
+pub fn parse_id_history(raw: &str) -> Vec<IdHistoryEntry> {
+    if raw.trim().is_empty() {
+        return Vec::new();
+    }
+    let values: Vec<serde_json::Value> = serde_json::from_str(raw).unwrap_or_default();
+    values
+        .into_iter()
+        .filter_map(|v| match v {
+            serde_json::Value::String(id) => Some(IdHistoryEntry {
+                id,
+                changed_at: None,
+                via: None,
+                actor: None,
+            }),
+            v => serde_json::from_value(v).ok(),
+        })
+        .collect()
+}
+
+/// `previous_ids` with `entry` appended, legacy entries normalized to objects
+pub fn append_id_history(raw: &str, entry: IdHistoryEntry) -> String {
+    let mut history = parse_id_history(raw);
+    history.push(entry);
+    serde_json::to_string(&history).unwrap_or_default()
+}
+
+/// Peer rows that shared an id, merged into the one with the freshest activity
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PeerMerge {
+    pub id: String,
+    /// guid of the row kept, hex
+    pub kept: String,
+    /// guids of the rows removed, hex
+    pub removed: Vec<String>,
+    /// The kept row stays soft-deleted only if every row was
+    pub deleted: bool,
+    /// Attributes (tags included) moved over from the removed rows; keys the
+    /// kept row already had keep its value
+    pub attributes_moved: u64,
+    #[serde(skip)]
+    pub guid: Vec<u8>,
+}
+
+fn guid_hex(guid: &[u8]) -> String {
+    guid.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Whether new audit_log rows are hash-chained (AUDIT_CHAIN=Y)
+pub fn audit_chain_enabled() -> bool {
+    std::env::var("AUDIT_CHAIN")
+        .unwrap_or_default()
+        .to_uppercase()
+        == "Y"
+}
+
+/// One audit_log row as the hash chain covers it
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub seq: i64,
+    pub at: i64,
+    pub actor: String,
+    pub action: String,
+    pub peer_id: String,
+    pub detail: String,
+}
+
+impl AuditEntry {
+    /// SHA-256 (hex) over the previous row's hash (empty for seq 1) and this
+    /// row's canonical form, the JSON array [seq, at, actor, action, peer_id, detail]
+    pub fn hash(&self, prev: &str) -> String {
+        let canonical = serde_json::json!([
+            self.seq,
+            self.at,
+            self.actor,
+            self.action,
+            self.peer_id,
+            self.detail
+        ]);
+        let mut input = prev.as_bytes().to_vec();
+        input.extend_from_slice(canonical.to_string().as_bytes());
+        guid_hex(&sodiumoxide::crypto::hash::sha256::hash(&input).0)
+    }
+}
+
+/// Append a row to audit_log. With AUDIT_CHAIN=Y it gets the next seq and its
+/// hash in the same transaction; the insert comes first so the transaction
+/// holds the write lock while it reads the chain head, and no other writer can
+/// append in between. Nests as a savepoint inside the caller's transaction.
+pub async fn insert_audit(
+    conn: &mut SqliteConnection,
+    at: i64,
+    actor: &str,
+    action: &str,
+    peer_id: &str,
+    detail: &str,
+) -> Result<(), SqlxError> {
+    let insert = sqlx::query(
+        "INSERT INTO audit_log (at, actor, action, peer_id, detail) VALUES (?, ?, ?, ?, ?)
+         RETURNING rowid",
+    )
+    .bind(at)
+    .bind(actor)
+    .bind(action)
+    .bind(peer_id)
+    .bind(detail);
+    if !audit_chain_enabled() {
+        insert.execute(conn).await?;
+        return Ok(());
+    }
+    let mut tx = conn.begin().await?;
+    let rowid: i64 = insert.fetch_one(&mut *tx).await?.get("rowid");
+    let head = sqlx::query(
+        "SELECT seq, hash FROM audit_log WHERE seq IS NOT NULL ORDER BY seq DESC LIMIT 1",
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    let (seq, prev) = match head {
+        Some(row) => (row.get::<i64, _>("seq") + 1, row.get::<String, _>("hash")),
+        None => (1, String::new()),
+    };
+    let entry = AuditEntry {
+        seq,
+        at,
+        actor: actor.to_owned(),
+        action: action.to_owned(),
+        peer_id: peer_id.to_owned(),
+        detail: detail.to_owned(),
+    };
+    sqlx::query("UPDATE audit_log SET seq = ?, hash = ? WHERE rowid = ?")
+        .bind(seq)
+        .bind(entry.hash(&prev))
+        .bind(rowid)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// The last chained row's (seq, hash), as an anchor records it
+pub async fn audit_head(conn: &mut SqliteConnection) -> Result<Option<AuditAnchor>, SqlxError> {
+    let row = sqlx::query(
+        "SELECT seq, hash FROM audit_log WHERE seq IS NOT NULL ORDER BY seq DESC LIMIT 1",
+    )
+    .fetch_optional(conn)
+    .await?;
+    Ok(row.map(|row| AuditAnchor {
+        at: chrono::Utc::now().timestamp(),
+        seq: row.get("seq"),
+        hash: row.get("hash"),
+    }))
+}
+
+/// The chain head at some time, appended to the anchor file (one JSON object per
+/// line) so that rewriting or cutting off the chain up to it shows
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditAnchor {
+    pub at: i64,
+    pub seq: i64,
+    pub hash: String,
+}
+
+/// Where anchors go: AUDIT_ANCHOR_FILE, else audit-anchors.jsonl next to the database
+pub fn audit_anchor_file(db_file: &str) -> PathBuf {
+    if let Ok(path) = std::env::var("AUDIT_ANCHOR_FILE") {
+        if !path.is_empty() {
+            return PathBuf::from(path);
+        }
+    }
+    let db_file = db_file.trim_start_matches("sqlite://");
+    Path::new(db_file)
+        .parent()
+        .unwrap_or_else(|| Path::new("."))
+        .join("audit-anchors.jsonl")
+}
+
+/// Anchors in the file, oldest first; none without the file, unreadable lines skipped
+pub fn read_audit_anchors(path: &Path) -> Vec<AuditAnchor> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(_) => return Vec::new(),
+    };
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(anchor) => Some(anchor),
+            Err(e) => {
+                log::warn!(
+                    "Skipping audit anchor {:?} in {}: {}",
+                    line,
+                    path.display(),
+                    e
+                );
+                None
+            }
+        })
+        .collect()
+}
+
+pub fn append_audit_anchor(path: &Path, anchor: &AuditAnchor) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    writeln!(
+        file,
+        "{}",
+        serde_json::to_string(anchor).unwrap_or_default()
+    )?;
+    file.sync_data()
+}
+
+/// The first place the chain does not hold
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AuditBreak {
+    pub seq: i64,
+    /// hash_mismatch: the row is not what was hashed, or its predecessor changed;
+    /// missing: no row with this seq; anchor_mismatch: the row's hash is not the
+    /// one anchored for it; truncated: an anchor is past the last row
+    pub reason: &'static str,
+    pub expected_hash: Option<String>,
+    pub stored_hash: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct AuditVerification {
+    /// chained rows walked, up to the break if any
+    pub checked: u64,
+    /// rows without a seq: written before AUDIT_CHAIN was on, or while it was off
+    pub unchained: u64,
+    /// the last row walked
+    pub head_seq: Option<i64>,
+    pub head_hash: Option<String>,
+    /// anchors read from the anchor file
+    pub anchors: usize,
+    pub broken: Option<AuditBreak>,
+}
+
+/// Walk the chain in seq order, streaming, and report the first broken link
+pub async fn verify_audit_chain(
+    conn: &mut SqliteConnection,
+    anchors: &[AuditAnchor],
+) -> Result<AuditVerification, SqlxError> {
+    use hbb_common::futures_util::TryStreamExt;
+    let anchored: HashMap<i64, &str> = anchors.iter().map(|x| (x.seq, x.hash.as_str())).collect();
+    let mut report = AuditVerification {
+        unchained: sqlx::query("SELECT count(*) FROM audit_log WHERE seq IS NULL")
+            .fetch_one(&mut *conn)
+            .await?
+            .get::<i64, _>(0) as u64,
+        anchors: anchors.len(),
+        ..Default::default()
+    };
+    let mut prev = String::new();
+    let mut expected_seq = 1;
+    let mut rows = sqlx::query(
+        "SELECT seq, at, actor, action, peer_id, detail, hash FROM audit_log
+         WHERE seq IS NOT NULL ORDER BY seq",
+    )
+    .fetch(&mut *conn);
+    while let Some(row) = rows.try_next().await? {
+        let entry = AuditEntry {
+            seq: row.get("seq"),
+            at: row.get("at"),
+            actor: row.get("actor"),
+            action: row.get("action"),
+            peer_id: row.get("peer_id"),
+            detail: row.get("detail"),
+        };
+        let stored: String = row.get("hash");
+        let expected = entry.hash(&prev);
+        let broken = |reason, expected_hash: Option<&str>| AuditBreak {
+            seq: entry.seq,
+            reason,
+            expected_hash: expected_hash.map(str::to_owned),
+            stored_hash: Some(stored.clone()),
+        };
+        if entry.seq != expected_seq {
+            report.broken = Some(AuditBreak {
+                seq: expected_seq,
+                reason: "missing",
+                expected_hash: None,
+                stored_hash: None,
+            });
+        } else if stored != expected {
+            report.broken = Some(broken("hash_mismatch", Some(expected.as_str())));
+        } else if let Some(anchor) = anchored.get(&entry.seq).filter(|x| **x != stored) {
+            report.broken = Some(broken("anchor_mismatch", Some(*anchor)));
+        }
+        if report.broken.is_some() {
+            return Ok(report);
+        }
+        report.checked += 1;
+        report.head_seq = Some(entry.seq);
+        report.head_hash = Some(stored.clone());
+        prev = stored;
+        expected_seq += 1;
+    }
+    let last = expected_seq - 1;
+    if anchors.iter().any(|x| x.seq > last) {
+        report.broken = Some(AuditBreak {
+            seq: last + 1,
+            reason: "truncated",
+            expected_hash: None,
+            stored_hash: None,
+        });
+    }
+    Ok(report)
+}
+
+/// Merge peer rows sharing an id, left by patch lineages whose unique index
+/// did not cover soft-deleted rows, so lookups by id find one row again. The
+/// row with the freshest last_online is kept (the live one on a tie); it gets
+/// the others' attributes, id history and note if it had none, and each merge
+/// is audited as `peer_merge` by `actor`. Everything else refers to peers by
+/// id and needs no change.
+pub async fn merge_duplicate_peers(
+    conn: &mut SqliteConnection,
+    actor: &str,
+) -> ResultType<Vec<PeerMerge>> {
+    let mut tx = conn.begin().await?;
+    let ids: Vec<String> =
+        sqlx::query("SELECT id FROM peer GROUP BY id HAVING count(*) > 1 ORDER BY id")
+            .fetch_all(&mut *tx)
+            .await?
+            .iter()
+            .map(|row| row.get("id"))
+            .collect();
+    let mut merges = Vec::new();
+    for id in ids {
+        merges.push(merge_peer_rows(&mut tx, &id, &id, actor).await?);
+    }
+    tx.commit().await?;
+    emit_merges(&merges, actor);
+    Ok(merges)
+}
+
+/// Merge the rows stored as `id` or as `other` into the one with the freshest
+/// activity, stored as `id`, as merge_duplicate_peers does for each id
+async fn merge_peer_rows(
+    tx: &mut SqliteConnection,
+    id: &str,
+    other: &str,
+    actor: &str,
+) -> ResultType<PeerMerge> {
+    let rows = sqlx::query(
+        "SELECT guid, is_deleted, note, previous_ids, version FROM peer WHERE id IN (?, ?)
+         ORDER BY coalesce(julianday(last_online), 0) DESC, coalesce(is_deleted, 0),
+                  created_at DESC, guid",
+    )
+    .bind(id)
+    .bind(other)
+    .fetch_all(&mut *tx)
+    .await?;
+    let text = |row: &sqlx::sqlite::SqliteRow, column: &str| {
+        row.try_get::<Option<String>, _>(column)
+            .ok()
+            .flatten()
+            .filter(|x| !x.is_empty())
+    };
+    let guid: Vec<u8> = rows[0].get("guid");
+    let mut note = text(&rows[0], "note");
+    let mut history = parse_id_history(&text(&rows[0], "previous_ids").unwrap_or_default());
+    let mut deleted = true;
+    let mut version = 0;
+    let mut removed = Vec::new();
+    let mut attributes_moved = 0;
+    for (i, row) in rows.iter().enumerate() {
+        deleted &= row.try_get::<Option<i64>, _>("is_deleted").ok().flatten() == Some(1);
+        version = version.max(row.try_get::<i64, _>("version").unwrap_or_default());
+        if i == 0 {
+            continue;
+        }
+        let other: Vec<u8> = row.get("guid");
+        if note.is_none() {
+            note = text(row, "note");
+        }
+        for entry in parse_id_history(&text(row, "previous_ids").unwrap_or_default()) {
+            if !history.contains(&entry) {
+                history.push(entry);
+            }
+        }
+        attributes_moved += sqlx::query(
+            "INSERT OR IGNORE INTO peer_attributes (guid, key, value, updated_at)
+             SELECT ?, key, value, updated_at FROM peer_attributes WHERE guid = ?",
+        )
+        .bind(&guid)
+        .bind(&other)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        sqlx::query("DELETE FROM peer_attributes WHERE guid = ?")
+            .bind(&other)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM peer WHERE guid = ?")
+            .bind(&other)
+            .execute(&mut *tx)
+            .await?;
+        removed.push(guid_hex(&other));
+    }
+    // oldest first; entries without a time predate the ones with one
+    history.sort_by(|a, b| a.changed_at.cmp(&b.changed_at));
+    sqlx::query(
+        "UPDATE peer SET id = ?, is_deleted = ?, note = ?, previous_ids = ?, version = ?
+         WHERE guid = ?",
+    )
+    .bind(id)
+    .bind(deleted as i64)
+    .bind(&note)
+    .bind(serde_json::to_string(&history).unwrap_or_default())
+    .bind(version + 1)
+    .bind(&guid)
+    .execute(&mut *tx)
+    .await?;
+    let merge = PeerMerge {
+        id: id.to_owned(),
+        kept: guid_hex(&guid),
+        removed,
+        deleted,
+        attributes_moved,
+        guid,
+    };
+    insert_audit(
+        tx,
+        chrono::Utc::now().timestamp(),
+        actor,
+        "peer_merge",
+        &merge.id,
+        &serde_json::to_string(&merge).unwrap_or_default(),
+    )
+    .await?;
+    Ok(merge)
+}
+
+fn emit_merges(merges: &[PeerMerge], actor: &str) {
+    for merge in merges {
+        emit_event(EventKind::Audit {
+            actor: actor.to_owned(),
+            action: "peer_merge",
+            peer_id: merge.id.clone(),
+            detail: serde_json::to_string(merge).unwrap_or_default(),
+        });
+    }
+}
+
+/// Status events older than this many days move from `peer_event` into monthly
+/// archive files (EVENT_HOT_DAYS, 0 keeps everything in the main database)
+pub const EVENT_HOT_DAYS: u64 = 90;
+/// Archive files one report may attach; wider ranges are refused
+pub const MAX_ATTACHED_ARCHIVES: usize = 2;
+
+/// Directory of the monthly archives (`archive/2025-03.sqlite3`), next to the
+/// database file. The archives are not part of the database backup.
+pub fn archive_dir(db_file: &str) -> PathBuf {
+    let db_file = db_file.trim_start_matches("sqlite://");
+    Path::new(db_file)
+        .parent()
+        .unwrap_or_else(|| Path::new("."))
+        .join("archive")
+}
+
+/// [start, end) of the UTC month `at` falls in, and its archive name (YYYY-MM)
+pub fn month_bounds(at: i64) -> (i64, i64, String) {
+    use chrono::Datelike;
+    let t = chrono::NaiveDateTime::from_timestamp_opt(at, 0).unwrap_or_default();
+    let (year, month) = (t.year(), t.month());
+    let (next_year, next_month) = if month == 12 {
+        (year + 1, 1)
+    } else {
+        (year, month + 1)
+    };
+    let start = |y, m| {
+        chrono::NaiveDate::from_ymd_opt(y, m, 1)
+            .and_then(|d| d.and_hms_opt(0, 0, 0))
+            .map_or(0, |t| t.timestamp())
+    };
+    (
+        start(year, month),
+        start(next_year, next_month),
+        format!("{:04}-{:02}", year, month),
+    )
+}
+
+/// Existing archive files of the months overlapping [from, to], oldest first
+pub fn archives_for(dir: &Path, from: i64, to: i64) -> Vec<(String, PathBuf)> {
+    let mut out = Vec::new();
+    let mut at = from;
+    while at <= to {
+        let (_, end, month) = month_bounds(at);
+        let path = dir.join(format!("{}.sqlite3", month));
+        if path.exists() {
+            out.push((month, path));
+        }
+        at = end;
+    }
+    out
+}
+
+/// Free space below which the storage checks alert (STORAGE_LOW_SPACE_MB)
+pub const STORAGE_LOW_SPACE_MB: u64 = 512;
+/// Free space below which the server does not start: SQLite cannot even grow its WAL
+const STORAGE_MIN_FREE_BYTES: u64 = 16 * 1024 * 1024;
+/// How often the free space of the database's filesystem is checked
+const STORAGE_CHECK_SECS: u64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageProblem {
+    DirMissing,
+    DirNotWritable,
+    NotAFile,
+    FileNotWritable,
+    /// Not fatal: writable now, but by someone else's grace
+    ForeignOwner,
+    NoSpace,
+}
+
+/// One finding of the storage checks, naming the path it is about and what to do
+#[derive(Debug, Clone, Serialize)]
+pub struct StorageIssue {
+    pub problem: StorageProblem,
+    pub path: String,
+    pub detail: String,
+}
+
+impl std::fmt::Display for StorageIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "storage: {}: {}", self.path, self.detail)
+    }
+}
+
+impl std::error::Error for StorageIssue {}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StorageReport {
+    pub path: String,
+    /// The server cannot (keep) writing the database, first one fatal at startup
+    pub problems: Vec<StorageIssue>,
+    pub warnings: Vec<StorageIssue>,
+    /// Free bytes for the service user on the database's filesystem, None where unknown
+    pub free_bytes: Option<u64>,
+    pub low_space_threshold_bytes: u64,
+    pub low_space: bool,
+}
+
+lazy_static::lazy_static! {
+    // the database the server runs on, checked again for /api/health
+    static ref STORAGE_PATH: std::sync::RwLock<Option<String>> = Default::default();
+    static ref LAST_STORAGE: std::sync::RwLock<Option<StorageReport>> = Default::default();
+}
+
+/// Check that the database file `url` and its directory can be written by this
+/// process and that its filesystem has room; nothing is changed but a probe
+/// file created and deleted in the directory
+pub fn check_storage(url: &str) -> StorageReport {
+    let path = Path::new(url.trim_start_matches("sqlite://"));
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let threshold = std::env::var("STORAGE_LOW_SPACE_MB")
+        .ok()
+        .and_then(|x| x.parse::<u64>().ok())
+        .unwrap_or(STORAGE_LOW_SPACE_MB)
+        .saturating_mul(1024 * 1024);
+    let mut report = StorageReport {
+        path: path.display().to_string(),
+        problems: Vec::new(),
+        warnings: Vec::new(),
+        free_bytes: None,
+        low_space_threshold_bytes: threshold,
+        low_space: false,
+    };
+    let issue = |problem, path: &Path, detail: String| StorageIssue {
+        problem,
+        path: path.display().to_string(),
+        detail,
+    };
+    if !dir.is_dir() {
+        let detail = if dir.exists() {
+            "is not a directory".to_owned()
+        } else {
+            "directory does not exist; create it and make it writable for the service user"
+                .to_owned()
+        };
+        report
+            .problems
+            .push(issue(StorageProblem::DirMissing, dir, detail));
+        return report;
+    }
+    // SQLite creates its -wal and -shm files next to the database
+    let probe = dir.join(format!(".hbbs-probe-{}", std::process::id()));
+    match std::fs::File::create(&probe) {
+        Ok(_) => {
+            std::fs::remove_file(&probe).ok();
+        }
+        Err(e) => report.problems.push(issue(
+            StorageProblem::DirNotWritable,
+            dir,
+            format!(
+                "cannot create files in the directory ({}){}; SQLite needs it for its -wal and \
+                 -shm files, give the service user write access",
+                e,
+                owner_hint(dir)
+            ),
+        )),
+    }
+    for file in ["", "-wal", "-shm"] {
+        let file = PathBuf::from(format!("{}{}", path.display(), file));
+        let meta = match std::fs::metadata(&file) {
+            Ok(meta) => meta,
+            Err(_) => continue,
+        };
+        if !meta.is_file() {
+            report.problems.push(issue(
+                StorageProblem::NotAFile,
+                &file,
+                "is not a regular file".to_owned(),
+            ));
+            continue;
+        }
+        match std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&file)
+        {
+            Err(e) => report.problems.push(issue(
+                StorageProblem::FileNotWritable,
+                &file,
+                format!(
+                    "cannot be opened for writing ({}){}; chown it to the service user",
+                    e,
+                    owner_hint(&file)
+                ),
+            )),
+            Ok(_) if foreign_owner(&meta) => report.warnings.push(issue(
+                StorageProblem::ForeignOwner,
+                &file,
+                format!(
+                    "writable, but{}; it may stop being writable after a permission change",
+                    owner_hint(&file)
+                ),
+            )),
+            Ok(_) => {}
+        }
+    }
+    report.free_bytes = free_space(dir);
+    if let Some(free) = report.free_bytes {
+        report.low_space = free < threshold;
+        if free < STORAGE_MIN_FREE_BYTES {
+            report.problems.push(issue(
+                StorageProblem::NoSpace,
+                dir,
+                format!(
+                    "only {} KiB free on the filesystem, at least {} MiB are needed",
+                    free / 1024,
+                    STORAGE_MIN_FREE_BYTES / 1024 / 1024
+                ),
+            ));
+        }
+    }
+    report
+}
+
+/// Owner and mode of `path` against the user the server runs as, for messages
+#[cfg(unix)]
+fn owner_hint(path: &Path) -> String {
+    use std::os::unix::fs::MetadataExt;
+    match std::fs::metadata(path) {
+        Ok(meta) => format!(
+            ", owned by uid {} with mode {:o}, the server runs as uid {}",
+            meta.uid(),
+            meta.mode() & 0o7777,
+            unsafe { libc::geteuid() }
+        ),
+        Err(_) => String::new(),
+    }
+}
+
+#[cfg(not(unix))]
+fn owner_hint(_: &Path) -> String {
+    String::new()
+}
+
+#[cfg(unix)]
+fn foreign_owner(meta: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    meta.uid() != unsafe { libc::geteuid() }
+}
+
+#[cfg(not(unix))]
+fn foreign_owner(_: &std::fs::Metadata) -> bool {
+    false
+}
+
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)]
+fn free_space(dir: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let dir = std::ffi::CString::new(dir.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(dir.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn free_space(_: &Path) -> Option<u64> {
+    None
+}
+
+/// The storage checks of the server's database run now, None before it opened one
+pub fn storage_report() -> Option<StorageReport> {
+    let path = STORAGE_PATH.read().ok()?.clone()?;
+    Some(check_storage(&path))
+}
+
+/// The storage checks from the last periodic pass, for /metrics
+pub(crate) fn last_storage_report() -> Option<StorageReport> {
+    LAST_STORAGE.read().ok()?.clone()
+}
+
+/// Check the server's database `url` every STORAGE_CHECK_SECS, logging when free
+/// space drops below or recovers above the alert threshold and new problems
+pub(crate) async fn monitor_storage(url: String) {
+    if let Ok(mut lock) = STORAGE_PATH.write() {
+        *lock = Some(url.clone());
+    }
+    let mut interval = tokio::time::interval(Duration::from_secs(STORAGE_CHECK_SECS));
+    let mut was_low = false;
+    let mut known = Vec::new();
+    loop {
+        interval.tick().await;
+        let report = check_storage(&url);
+        if report.low_space != was_low {
+            match (report.low_space, report.free_bytes) {
+                (true, Some(free)) => log::warn!(
+                    "storage: {} MiB free for {}, below the {} MiB alert threshold",
+                    free / 1024 / 1024,
+                    report.path,
+                    report.low_space_threshold_bytes / 1024 / 1024
+                ),
+                _ => log::info!(
+                    "storage: free space for {} back above the threshold",
+                    report.path
+                ),
+            }
+            was_low = report.low_space;
+        }
+        let problems: Vec<StorageProblem> = report.problems.iter().map(|x| x.problem).collect();
+        for issue in &report.problems {
+            if !known.contains(&issue.problem) {
+                log::error!("{}", issue);
+            }
+        }
+        known = problems;
+        if let Ok(mut lock) = LAST_STORAGE.write() {
+            *lock = Some(report);
+        }
+    }
+}
+
+/// Writes of the status writer per flush (WRITE_BATCH)
+pub const WRITE_BATCH: u64 = 512;
+/// Writes one source contributes to a single flush (WRITE_PER_SOURCE)
+pub const WRITE_PER_SOURCE: u64 = 64;
+/// Milliseconds between flushes of the status writer (WRITE_FLUSH_MS)
+pub const WRITE_FLUSH_MS: u64 = 100;
+/// Window over which the top writers are counted
+const TOP_WRITERS_SECS: u64 = 60;
+/// Peer ids counted per window; writes of further ids go uncounted
+const TOP_WRITERS_MAX_IDS: usize = 10_000;
+/// Peer ids and sources listed in the writer stats
+const TOP_WRITERS: usize = 10;
+/// Days of per-day active peer rows kept (ACTIVE_PEERS_KEEP_DAYS)
+const ACTIVE_PEERS_KEEP_DAYS: u64 = 400;
+
+/// set_online/set_offline writes waiting for the next flush. Writes queue under
+/// their source (the peer's ip, else its id) and a flush takes at most
+/// WRITE_PER_SOURCE from each source in round-robin order, so one address
+/// registering many ids cannot hold back everybody else's status. A peer has at
+/// most one pending write: a newer one replaces its state in place.
+#[derive(Default)]
+struct StatusQueue {
+    pending: HashMap<String, bool>,
+    sources: HashMap<String, VecDeque<String>>,
+    // sources with pending writes, the next to be served first
+    turn: VecDeque<String>,
+    writers: HashMap<String, u64>,
+    window_start: Option<Instant>,
+}
+
+impl StatusQueue {
+    /// Queue a write, false when it replaced a pending one of the same peer
+    fn push(&mut self, id: &str, online: bool, source: &str) -> bool {
+        let now = Instant::now();
+        match self.window_start {
+            Some(start) if now.duration_since(start).as_secs() < TOP_WRITERS_SECS => {}
+            _ => {
+                self.writers.clear();
+                self.window_start = Some(now);
+            }
+        }
+        if self.writers.len() < TOP_WRITERS_MAX_IDS || self.writers.contains_key(id) {
+            *self.writers.entry(id.to_owned()).or_default() += 1;
+        }
+        if let Some(state) = self.pending.get_mut(id) {
+            *state = online;
+            return false;
+        }
+        self.pending.insert(id.to_owned(), online);
+        if !self.sources.contains_key(source) {
+            self.turn.push_back(source.to_owned());
+        }
+        self.sources
+            .entry(source.to_owned())
+            .or_default()
+            .push_back(id.to_owned());
+        true
+    }
+
+    /// The next flush: each source with pending writes gets one turn of up to
+    /// `per_source`, until `batch` is full; the rest waits for the next flush
+    fn take(&mut self, batch: usize, per_source: usize) -> Vec<(String, bool)> {
+        let mut out = Vec::new();
+        for _ in 0..self.turn.len() {
+            if out.len() >= batch {
+                break;
+            }
+            let source = match self.turn.pop_front() {
+                Some(source) => source,
+                None => break,
+            };
+            let ids = match self.sources.get_mut(&source) {
+                Some(ids) => ids,
+                None => continue,
+            };
+            let n = ids.len().min(per_source).min(batch - out.len());
+            for id in ids.drain(..n) {
+                if let Some(online) = self.pending.remove(&id) {
+                    out.push((id, online));
+                }
+            }
+            if ids.is_empty() {
+                self.sources.remove(&source);
+            } else {
+                self.turn.push_back(source);
+            }
+        }
+        out
+    }
+
+    fn stats(&self) -> StatusWriterStats {
+        let mut backlog: Vec<SourceBacklog> = self
+            .sources
+            .iter()
+            .map(|(source, ids)| SourceBacklog {
+                source: source.clone(),
+                pending: ids.len(),
+            })
+            .collect();
+        backlog.sort_by(|a, b| {
+            b.pending
+                .cmp(&a.pending)
+                .then_with(|| a.source.cmp(&b.source))
+        });
+        backlog.truncate(TOP_WRITERS);
+        let mut top_writers: Vec<TopWriter> = self
+            .writers
+            .iter()
+            .map(|(id, writes)| TopWriter {
+                id: id.clone(),
+                writes: *writes,
+            })
+            .collect();
+        top_writers.sort_by(|a, b| b.writes.cmp(&a.writes).then_with(|| a.id.cmp(&b.id)));
+        top_writers.truncate(TOP_WRITERS);
+        StatusWriterStats {
+            pending: self.pending.len(),
+            hot_sources: backlog,
+            top_writers,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SourceBacklog {
+    pub source: String,
+    pub pending: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TopWriter {
+    pub id: String,
+    /// Status writes queued in the current window, replaced ones included
+    pub writes: u64,
+}
+
+/// The status writer after its last flush
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StatusWriterStats {
+    /// Writes left for the next flush
+    pub pending: usize,
+    /// Sources with the most writes left over, most first
+    pub hot_sources: Vec<SourceBacklog>,
+    /// Peers queueing the most writes in the last minute or so, most first
+    pub top_writers: Vec<TopWriter>,
+}
+
+lazy_static::lazy_static! {
+    static ref STATUS_WRITER: std::sync::RwLock<StatusWriterStats> = Default::default();
+}
+
+/// The server's status writer as of its last flush, for /api/stats and /metrics
+pub fn status_writer_stats() -> StatusWriterStats {
+    STATUS_WRITER.read().map(|x| x.clone()).unwrap_or_default()
+}
+
+/// Flush the status writes of `db` every WRITE_FLUSH_MS
+pub(crate) async fn status_writer_loop(db: Database) {
+    let every = Duration::from_millis(env_u64("WRITE_FLUSH_MS", WRITE_FLUSH_MS).max(1));
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        db.flush_status_writes().await;
+        if let Ok(mut lock) = STATUS_WRITER.write() {
+            *lock = db.status_writer();
+        }
+    }
+}
+
+/// A pk change parked until an admin approves it (`--pk-change-policy=approve`)
+#[derive(Debug, Clone)]
+pub struct PendingKeyChange {
+    pub new_pk: Vec<u8>,
+    pub approved: bool,
+}
+
+impl Database {
+    pub async fn new(url: &str) -> ResultType<Database> {
+        // fail here with the path and the cause rather than with a pool error later
+        let storage = check_storage(url);
+        for issue in &storage.warnings {
+            log::warn!("{}", issue);
+        }
+        if let Some((first, rest)) = storage.problems.split_first() {
+            for issue in rest {
+                log::error!("{}", issue);
+            }
+            return Err(first.clone().into());
+        }
+        if storage.low_space {
+            log::warn!(
+                "storage: {} MiB free for {}, below the {} MiB alert threshold",
+                storage.free_bytes.unwrap_or_default() / 1024 / 1024,
+                storage.path,
+                storage.low_space_threshold_bytes / 1024 / 1024
+            );
+        }
+        if !std::path::Path::new(url).exists() {
+            std::fs::File::create(url)?;
+        }
+        let n: usize = std::env::var("MAX_DATABASE_CONNECTIONS")
+            .unwrap_or_else(|_| "5".to_owned())  // Increased from 1 to 5
+            .parse()
+            .unwrap_or(5);
+        log::info!("MAX_DATABASE_CONNECTIONS={} (read-only pool)", n);
+        let writer = Pool::new(
+            DbPool {
+                url: url.to_owned(),
+                read_only: false,
+            },
+            1,
+        );
+        let _ = writer.get().await?; // test, and switches the file to WAL
+        let reader = Pool::new(
+            DbPool {
+                url: url.to_owned(),
+                read_only: true,
+            },
+            n.max(1),
+        );
+        let db = Database {
+            writer,
+            reader,
+            url: url.to_owned(),
+            clock: std::sync::Arc::new(SystemClock),
+            status_queue: Default::default(),
+        };
+        db.create_tables().await?;
+        db.ensure_columns().await?;
+        db.create_peer_indexes().await?;
+        db.create_event_tables().await?;
+        db.create_key_change_tables().await?;
+        db.create_relay_health_table().await?;
+        db.create_attribute_table().await?;
+        db.create_notice_table().await?;
+        db.create_access_rule_table().await?;
+        db.create_user_table().await?;
+        db.create_broadcast_tables().await?;
+        db.create_protocol_version_table().await?;
+        db.create_active_peer_tables().await?;
+        db.normalize_timestamps().await?;
+        db.merge_duplicates().await?;
+        db.canonicalize_ids().await?;
+        let _ = db.reader.get().await?; // test, once the tables exist
+        let writer = db.writer.clone();
+        register_pool_stats("write", Box::new(move || deadpool_stats(&writer)));
+        let reader = db.reader.clone();
+        register_pool_stats("read", Box::new(move || deadpool_stats(&reader)));
+        Ok(db)
+    }
+
+    /// Stamp rows with `clock` instead of the system clock
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    async fn create_tables(&self) -> ResultType<()> {
+        sqlx::query!(
+            "
+            create table if not exists peer (
+                guid blob primary key not null,
+                id varchar(100) not null,
+                uuid blob not null,
+                pk blob not null,
+                created_at datetime not null default(current_timestamp),
+                user blob,
+                status tinyint,
+                note varchar(300),
+                info text not null
+            ) without rowid;
+            create unique index if not exists index_peer_id on peer (id);
+            create index if not exists index_peer_user on peer (user);
+            create index if not exists index_peer_created_at on peer (created_at);
+            create index if not exists index_peer_status on peer (status);
+        "
+        )
+        .execute(self.writer.get().await?.deref_mut())
+        .await?;
+        Ok(())
+    }
+
+    /// Rewrite last_online values another version wrote as RFC3339 into the
+    /// `datetime('now')` form, once per start; reads accept both meanwhile
+    async fn normalize_timestamps(&self) -> ResultType<()> {
+        let n = sqlx::query(
+            "UPDATE peer SET last_online = datetime(last_online)
+             WHERE datetime(last_online) IS NOT NULL AND last_online != datetime(last_online)",
+        )
+        .execute(self.writer.get().await?.deref_mut())
+        .await?
+        .rows_affected();
+        if n > 0 {
+            log::warn!(
+                "Normalized {} last_online values written in a legacy format",
+                n
+            );
+            count_legacy_timestamps("normalized", n as usize);
+        }
+        Ok(())
+    }
+
+    /// Rewrite ids stored before every ingress made them canonical (a lower
+    /// case or spaced id a client registered with), once per start. The id as
+    /// stored goes into the row's id history for display. A row whose canonical
+    /// id another row already has is merged with that row as merge_duplicates
+    /// does, audited and logged. Soft-deleted rows, renamed to
+    /// `<id>~deleted~<guid>`, keep their ids.
+    async fn canonicalize_ids(&self) -> ResultType<()> {
+        let mut conn = self.writer.get().await?;
+        let rows = sqlx::query(
+            "SELECT guid, id, previous_ids FROM peer
+             WHERE (id != upper(id) OR id GLOB '*[^0-9A-Z_-]*') AND id NOT LIKE '%~%'",
+        )
+        .fetch_all(conn.deref_mut())
+        .await?;
+        let mut merges = Vec::new();
+        let mut tx = conn.deref_mut().begin().await?;
+        for row in rows {
+            let guid: Vec<u8> = row.get("guid");
+            let id: String = row.get("id");
+            let canonical = canonical_id(&id).into_owned();
+            if canonical == id {
+                continue;
+            }
+            let entry = IdHistoryEntry {
+                id: id.clone(),
+                changed_at: Some(chrono::Utc::now().to_rfc3339()),
+                via: None,
+                actor: None,
+            };
+            let history = append_id_history(
+                &row.try_get::<Option<String>, _>("previous_ids")
+                    .ok()
+                    .flatten()
+                    .unwrap_or_default(),
+                entry,
+            );
+            sqlx::query("UPDATE peer SET previous_ids = ? WHERE guid = ?")
+                .bind(&history)
+                .bind(&guid)
+                .execute(&mut *tx)
+                .await?;
+            let taken = sqlx::query("SELECT 1 FROM peer WHERE id = ?")
+                .bind(&canonical)
+                .fetch_optional(&mut *tx)
+                .await?
+                .is_some();
+            if !taken {
+                sqlx::query("UPDATE peer SET id = ? WHERE guid = ?")
+                    .bind(&canonical)
+                    .bind(&guid)
+                    .execute(&mut *tx)
+                    .await?;
+                log::info!("Peer id {:?} stored as {}", id, canonical);
+                continue;
+            }
+            let merge = merge_peer_rows(&mut tx, &canonical, &id, "startup").await?;
+            log::warn!(
+                "Peer id {:?} merged into {}, which another row had: kept {}, removed {}",
+                id,
+                canonical,
+                merge.kept,
+                merge.removed.join(", ")
+            );
+            merges.push(merge);
+        }
+        tx.commit().await?;
+        emit_merges(&merges, "startup");
+        Ok(())
+    }
+
+    /// Merge peer rows sharing an id, then keep it from recurring with a unique
+    /// index over the live rows (older databases may lack index_peer_id's
+    /// uniqueness)
+    async fn merge_duplicates(&self) -> ResultType<()> {
+        let merges = merge_duplicate_peers(self.writer.get().await?.deref_mut(), "startup").await?;
+        for merge in &merges {
+            log::warn!(
+                "Merged {} rows of peer {} into {}{}",
+                merge.removed.len() + 1,
+                merge.id,
+                merge.kept,
+                if merge.deleted { " (deleted)" } else { "" }
+            );
+        }
+        sqlx::query(
+            "CREATE UNIQUE INDEX IF NOT EXISTS index_peer_id_live ON peer (id)
+             WHERE is_deleted = 0",
+        )
+        .execute(self.writer.get().await?.deref_mut())
+        .await?;
+        Ok(())
+    }
+
+    /// Ensure additional columns exist (safe migration for older databases)
+    async fn ensure_columns(&self) -> ResultType<()> {
+        let migrations = [
+            "ALTER TABLE peer ADD COLUMN previous_ids TEXT DEFAULT ''",
+            "ALTER TABLE peer ADD COLUMN id_changed_at TEXT DEFAULT ''",
+            "ALTER TABLE peer ADD COLUMN is_deleted INTEGER DEFAULT 0",
+            "ALTER TABLE peer ADD COLUMN is_banned INTEGER DEFAULT 0",
+            // unix seconds a temporary ban ends at, NULL for a permanent one
+            "ALTER TABLE peer ADD COLUMN banned_until INTEGER",
+            "ALTER TABLE peer ADD COLUMN is_quarantined INTEGER DEFAULT 0",
+            "ALTER TABLE peer ADD COLUMN last_online TEXT",
+            // bumped by every change to the peer, for the API's If-Match checks
+            "ALTER TABLE peer ADD COLUMN version INTEGER NOT NULL DEFAULT 0",
+            // the pk/uuid a registration replaced (one generation back), when (unix
+            // seconds) and from which ip, and the pk a rollback dropped again
+            "ALTER TABLE peer ADD COLUMN previous_pk BLOB",
+            "ALTER TABLE peer ADD COLUMN previous_uuid BLOB",
+            "ALTER TABLE peer ADD COLUMN pk_replaced_at INTEGER",
+            "ALTER TABLE peer ADD COLUMN pk_replaced_from TEXT",
+            "ALTER TABLE peer ADD COLUMN revoked_pk BLOB",
+        ];
+        for sql in &migrations {
+            // Ignore errors — column may already exist
+            let _ = sqlx::query(sql)
+                .execute(self.writer.get().await?.deref_mut())
+                .await;
+        }
+        log::debug!("Column migration check completed");
+        Ok(())
+    }
+
+    /// Indexes over the migrated peer columns, for the API's device summary to
+    /// count without reading the rows
+    async fn create_peer_indexes(&self) -> ResultType<()> {
+        let statements = [
+            "CREATE INDEX IF NOT EXISTS index_peer_deleted ON peer (is_deleted)",
+            "CREATE INDEX IF NOT EXISTS index_peer_banned ON peer (is_deleted, banned_until)
+             WHERE is_banned = 1",
+            "CREATE INDEX IF NOT EXISTS index_peer_last_online ON peer (last_online)",
+        ];
+        for sql in &statements {
+            sqlx::query(sql)
+                .execute(self.writer.get().await?.deref_mut())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Status-event log: peer online/offline transitions, and one row per server run
+    /// whose `alive_until` is bumped while it runs (gaps between runs are unknown time)
+    async fn create_event_tables(&self) -> ResultType<()> {
+        let statements = [
+            "CREATE TABLE IF NOT EXISTS peer_event (
+                peer_id VARCHAR(100) NOT NULL,
+                online TINYINT NOT NULL,
+                reason VARCHAR(32) NOT NULL DEFAULT '',
+                at INTEGER NOT NULL
+            )",
+            "CREATE INDEX IF NOT EXISTS index_peer_event ON peer_event (peer_id, at)",
+            "CREATE INDEX IF NOT EXISTS index_peer_event_at ON peer_event (at)",
+            "CREATE TABLE IF NOT EXISTS server_run (
+                started_at INTEGER NOT NULL,
+                alive_until INTEGER NOT NULL
+            )",
+            // seconds from a start until 50/90/99% of the peers known at it were
+            // back, NULL for a milestone not reached within the recovery window
+            "CREATE TABLE IF NOT EXISTS restart_history (
+                started_at INTEGER NOT NULL,
+                known_peers INTEGER NOT NULL,
+                recovered_peers INTEGER NOT NULL,
+                p50_secs INTEGER,
+                p90_secs INTEGER,
+                p99_secs INTEGER,
+                recorded_at INTEGER NOT NULL
+            )",
+        ];
+        for sql in &statements {
+            sqlx::query(sql)
+                .execute(self.writer.get().await?.deref_mut())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Pending pk changes awaiting approval, and the audit trail of key decisions
+    async fn create_key_change_tables(&self) -> ResultType<()> {
+        let statements = [
+            "CREATE TABLE IF NOT EXISTS pending_key_changes (
+                peer_id VARCHAR(100) PRIMARY KEY NOT NULL,
+                old_pk BLOB NOT NULL,
+                new_pk BLOB NOT NULL,
+                old_fingerprint VARCHAR(64) NOT NULL,
+                new_fingerprint VARCHAR(64) NOT NULL,
+                first_seen INTEGER NOT NULL,
+                last_seen INTEGER NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 1,
+                approved_at INTEGER
+            )",
+            "CREATE TABLE IF NOT EXISTS audit_log (
+                at INTEGER NOT NULL,
+                actor VARCHAR(32) NOT NULL,
+                action VARCHAR(64) NOT NULL,
+                peer_id VARCHAR(100) NOT NULL,
+                detail TEXT NOT NULL DEFAULT ''
+            )",
+            "CREATE INDEX IF NOT EXISTS index_audit_log_at ON audit_log (at)",
+            // the latest ban of each peer for GET /api/bans
+            "CREATE INDEX IF NOT EXISTS index_audit_log_peer ON audit_log (peer_id, action, at)",
+        ];
+        for sql in &statements {
+            sqlx::query(sql)
+                .execute(self.writer.get().await?.deref_mut())
+                .await?;
+        }
+        // the hash chain of AUDIT_CHAIN, see insert_audit; errors mean the
+        // columns exist already
+        for sql in [
+            "ALTER TABLE audit_log ADD COLUMN seq INTEGER",
+            "ALTER TABLE audit_log ADD COLUMN hash TEXT",
+        ] {
+            let _ = sqlx::query(sql)
+                .execute(self.writer.get().await?.deref_mut())
+                .await;
+        }
+        sqlx::query("CREATE UNIQUE INDEX IF NOT EXISTS index_audit_log_seq ON audit_log (seq)")
+            .execute(self.writer.get().await?.deref_mut())
+            .await?;
+        Ok(())
+    }
+
+    /// Relay health snapshot, one row per relay host
+    async fn create_relay_health_table(&self) -> ResultType<()> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS relay_health (
+                host VARCHAR(255) PRIMARY KEY NOT NULL,
+                healthy TINYINT NOT NULL,
+                latency_ms INTEGER,
+                failures INTEGER NOT NULL DEFAULT 0,
+                checked_at INTEGER NOT NULL
+            )",
+        )
+        .execute(self.writer.get().await?.deref_mut())
+        .await?;
+        Ok(())
+    }
+
+    /// Custom key/value attributes per peer, by guid so they follow id changes and
+    /// outlive a soft delete; (key, value) indexed for the API's attribute filters
+    async fn create_attribute_table(&self) -> ResultType<()> {
+        let statements = [
+            "CREATE TABLE IF NOT EXISTS peer_attributes (
+                guid BLOB NOT NULL,
+                key VARCHAR(128) NOT NULL,
+                value TEXT NOT NULL,
+                updated_at INTEGER NOT NULL,
+                PRIMARY KEY (guid, key)
+            ) WITHOUT ROWID",
+            "CREATE INDEX IF NOT EXISTS index_peer_attributes_kv ON peer_attributes (key, value)",
+        ];
+        for sql in &statements {
+            sqlx::query(sql)
+                .execute(self.writer.get().await?.deref_mut())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Notices of admin actions for clients, see PeerNotice
+    async fn create_notice_table(&self) -> ResultType<()> {
+        let statements = [
+            "CREATE TABLE IF NOT EXISTS peer_notices (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                peer_id VARCHAR(100) NOT NULL,
+                ack_id VARCHAR(100) NOT NULL,
+                kind VARCHAR(16) NOT NULL,
+                detail TEXT NOT NULL DEFAULT '{}',
+                created_at INTEGER NOT NULL,
+                delivered_at INTEGER
+            )",
+            "CREATE INDEX IF NOT EXISTS index_peer_notices_peer ON peer_notices (peer_id)",
+            "CREATE INDEX IF NOT EXISTS index_peer_notices_ack ON peer_notices (ack_id)",
+        ];
+        for sql in &statements {
+            sqlx::query(sql)
+                .execute(self.writer.get().await?.deref_mut())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Access rules, see AccessRule
+    async fn create_access_rule_table(&self) -> ResultType<()> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS access_rules (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                controller VARCHAR(128) NOT NULL,
+                target VARCHAR(128) NOT NULL,
+                action VARCHAR(8) NOT NULL,
+                priority INTEGER NOT NULL DEFAULT 0,
+                created_at INTEGER NOT NULL
+            )",
+        )
+        .execute(self.writer.get().await?.deref_mut())
+        .await?;
+        Ok(())
+    }
+
+    /// Users peers are assigned to through peer.user (the name as bytes), with the
+    /// switch that disables all of a user's peers at once
+    async fn create_user_table(&self) -> ResultType<()> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS users (
+                name VARCHAR(100) PRIMARY KEY NOT NULL,
+                disabled TINYINT NOT NULL DEFAULT 0,
+                changed_at INTEGER NOT NULL
+            )",
+        )
+        .execute(self.writer.get().await?.deref_mut())
+        .await?;
+        Ok(())
+    }
+
+    /// Broadcasts and their per-peer deliveries, see Broadcast
+    async fn create_broadcast_tables(&self) -> ResultType<()> {
+        let statements = [
+            "CREATE TABLE IF NOT EXISTS broadcasts (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                message TEXT NOT NULL,
+                target VARCHAR(8) NOT NULL,
+                target_value TEXT NOT NULL DEFAULT '',
+                created_at INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL
+            )",
+            "CREATE TABLE IF NOT EXISTS broadcast_deliveries (
+                broadcast_id INTEGER NOT NULL,
+                peer_id VARCHAR(100) NOT NULL,
+                delivered_at INTEGER NOT NULL,
+                PRIMARY KEY (broadcast_id, peer_id)
+            )",
+        ];
+        for sql in &statements {
+            sqlx::query(sql)
+                .execute(self.writer.get().await?.deref_mut())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Messages per client version, transport and UTC day, see ProtocolKind
+    async fn create_protocol_version_table(&self) -> ResultType<()> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS protocol_versions (
+                day VARCHAR(10) NOT NULL,
+                kind VARCHAR(16) NOT NULL,
+                transport VARCHAR(3) NOT NULL,
+                version VARCHAR(32) NOT NULL,
+                count INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (day, kind, transport, version)
+            )",
+        )
+        .execute(self.writer.get().await?.deref_mut())
+        .await?;
+        Ok(())
+    }
+
+    /// Ids active per UTC day and per month; the daily rows are kept for
+    /// ACTIVE_PEERS_KEEP_DAYS, the monthly ones for good
+    async fn create_active_peer_tables(&self) -> ResultType<()> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS active_peers (
+                day VARCHAR(10) NOT NULL,
+                id VARCHAR(100) NOT NULL,
+                PRIMARY KEY (day, id)
+            ) WITHOUT ROWID;
+            CREATE TABLE IF NOT EXISTS active_peers_month (
+                month VARCHAR(7) NOT NULL,
+                id VARCHAR(100) NOT NULL,
+                PRIMARY KEY (month, id)
+            ) WITHOUT ROWID",
+        )
+        .execute(self.writer.get().await?.deref_mut())
+        .await?;
+        Ok(())
+    }
+
+    /// Store (UTC day number, id) marks of active peers in one transaction,
+    /// then drop daily rows older than ACTIVE_PEERS_KEEP_DAYS (0 keeps all)
+    pub async fn add_active_peers(&self, marks: &[(i64, String)]) -> ResultType<()> {
+        let day = |n: i64| {
+            chrono::NaiveDateTime::from_timestamp_opt(n * DAY_SECONDS as i64, 0)
+                .unwrap_or_default()
+                .format("%Y-%m-%d")
+                .to_string()
+        };
+        let mut conn = self.writer.get().await?;
+        let mut tx = conn.deref_mut().begin().await?;
+        for (n, id) in marks {
+            let day = day(*n);
+            sqlx::query("INSERT OR IGNORE INTO active_peers (day, id) VALUES (?, ?)")
+                .bind(&day)
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query("INSERT OR IGNORE INTO active_peers_month (month, id) VALUES (?, ?)")
+                .bind(&day[..7])
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        let keep = env_u64("ACTIVE_PEERS_KEEP_DAYS", ACTIVE_PEERS_KEEP_DAYS) as i64;
+        if let (true, Some(latest)) = (keep > 0, marks.iter().map(|(n, _)| *n).max()) {
+            sqlx::query("DELETE FROM active_peers WHERE day < ?")
+                .bind(day(latest - keep))
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Add `counts` to the rows of `day` (YYYY-MM-DD), in one transaction
+    pub async fn add_protocol_versions(
+        &self,
+        day: &str,
+        counts: &[crate::peer::ProtocolVersionCount],
+    ) -> ResultType<()> {
+        let mut conn = self.writer.get().await?;
+        let mut tx = conn.deref_mut().begin().await?;
+        for x in counts {
+            sqlx::query(
+                "INSERT INTO protocol_versions (day, kind, transport, version, count)
+                 VALUES (?, ?, ?, ?, ?)
+                 ON CONFLICT (day, kind, transport, version)
+                 DO UPDATE SET count = count + excluded.count",
+            )
+            .bind(day)
+            .bind(x.kind)
+            .bind(x.transport)
+            .bind(&x.version)
+            .bind(x.count as i64)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Broadcasts not expired at `now`, each with the ids it was delivered to
+    pub async fn active_broadcasts(&self, now: i64) -> ResultType<Vec<(Broadcast, Vec<String>)>> {
+        let mut conn = self.reader.get().await?;
+        let rows = sqlx::query(
+            "SELECT id, message, target, target_value, created_at, expires_at FROM broadcasts
+             WHERE expires_at > ? ORDER BY id",
+        )
+        .bind(now)
+        .fetch_all(conn.deref_mut())
+        .await?;
+        let mut active = Vec::with_capacity(rows.len());
+        for row in &rows {
+            let broadcast = Broadcast::from_row(row);
+            let delivered =
+                sqlx::query("SELECT peer_id FROM broadcast_deliveries WHERE broadcast_id = ?")
+                    .bind(broadcast.id)
+                    .fetch_all(conn.deref_mut())
+                    .await?
+                    .iter()
+                    .map(|row| row.get("peer_id"))
+                    .collect();
+            active.push((broadcast, delivered));
+        }
+        Ok(active)
+    }
 
-pub struct DbPool {
-    url: String,
-}
+    pub async fn record_broadcast_delivery(&self, id: i64, peer_id: &str) -> ResultType<()> {
+        sqlx::query(
+            "INSERT OR IGNORE INTO broadcast_deliveries (broadcast_id, peer_id, delivered_at)
+             VALUES (?, ?, ?)",
+        )
+        .bind(id)
+        .bind(peer_id)
+        .bind(chrono::Utc::now().timestamp())
+        .execute(self.writer.get().await?.deref_mut())
+        .await?;
+        Ok(())
+    }
 
-#[async_trait]
-impl deadpool::managed::Manager for DbPool {
-    type Type = SqliteConnection;
-    type Error = SqlxError;
-    async fn create(&self) -> Result<SqliteConnection, SqlxError> {
-        let mut opt = SqliteConnectOptions::from_str(&self.url).unwrap();
-        opt.log_statements(log::LevelFilter::Debug);
-        SqliteConnection::connect_with(&opt).await
+    /// All access rules in evaluation order
+    pub async fn access_rules(&self) -> ResultType<Vec<AccessRule>> {
+        let rows = sqlx::query(
+            "SELECT id, controller, target, action, priority, created_at FROM access_rules
+             ORDER BY priority DESC, action = 'deny' DESC, id",
+        )
+        .fetch_all(self.reader.get().await?.deref_mut())
+        .await?;
+        Ok(rows
+            .iter()
+            .map(|row| AccessRule {
+                id: row.get("id"),
+                controller: row.get("controller"),
+                target: row.get("target"),
+                action: row.get("action"),
+                priority: row.get("priority"),
+                created_at: row.get("created_at"),
+            })
+            .collect())
     }
-    async fn recycle(
-        &self,
-        obj: &mut SqliteConnection,
-    ) -> deadpool::managed::RecycleResult<SqlxError> {
-        Ok(obj.ping().await?)
+
+    /// (id, value of the `tags` attribute) of every peer that has one
+    pub async fn tagged_peers(&self) -> ResultType<Vec<(String, String)>> {
+        let rows = sqlx::query(
+            "SELECT p.id, a.value FROM peer_attributes a JOIN peer p ON p.guid = a.guid
+             WHERE a.key = 'tags' AND p.is_deleted = 0",
+        )
+        .fetch_all(self.reader.get().await?.deref_mut())
+        .await?;
+        Ok(rows
+            .iter()
+            .map(|row| (row.get("id"), row.get("value")))
+            .collect())
     }
-}
 
-#[derive(Clone)]
-pub struct Database {
-    pool: Pool,
-    url: String,
-}
+    /// Ids whose registrations have notices to deliver or acknowledge
+    pub async fn notice_ids(&self) -> ResultType<Vec<String>> {
+        let rows = sqlx::query(
+            "SELECT peer_id AS id FROM peer_notices UNION SELECT ack_id FROM peer_notices",
+        )
+        .fetch_all(self.reader.get().await?.deref_mut())
+        .await?;
+        Ok(rows.into_iter().map(|row| row.get("id")).collect())
+    }
 
-#[derive(Default)]
-pub struct Peer {
-    pub guid: Vec<u8>,
-    pub id: String,
-    pub uuid: Vec<u8>,
-    pub pk: Vec<u8>,
-    pub user: Option<Vec<u8>>,
-    pub info: String,
-    pub status: Option<i64>,
-}
+    /// For a registration of `id`: acknowledge (remove) the notices handed out
+    /// before, then mark the undelivered ones of `id` delivered. Returns those,
+    /// and whether `id` still has notices waiting for acknowledgment.
+    pub async fn take_notices(&self, id: &str) -> ResultType<(Vec<PeerNotice>, bool)> {
+        let now = chrono::Utc::now().timestamp();
+        let mut conn = self.writer.get().await?;
+        let mut tx = conn.deref_mut().begin().await?;
+        sqlx::query(
+            "DELETE FROM peer_notices WHERE delivered_at IS NOT NULL AND (peer_id = ? OR ack_id = ?)",
+        )
+        .bind(id)
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+        let rows = sqlx::query(
+            "UPDATE peer_notices SET delivered_at = ? WHERE peer_id = ? AND delivered_at IS NULL
+             RETURNING seq, peer_id, ack_id, kind, detail, created_at, delivered_at",
+        )
+        .bind(now)
+        .bind(id)
+        .fetch_all(&mut *tx)
+        .await?;
+        let waiting =
+            sqlx::query("SELECT 1 FROM peer_notices WHERE peer_id = ? OR ack_id = ? LIMIT 1")
+                .bind(id)
+                .bind(id)
+                .fetch_optional(&mut *tx)
+                .await?
+                .is_some();
+        tx.commit().await?;
+        let mut notices: Vec<PeerNotice> = rows.iter().map(PeerNotice::from_row).collect();
+        notices.sort_by_key(|x| x.seq);
+        Ok((notices, waiting))
+    }
 
-impl Database {
-    pub async fn new(url: &str) -> ResultType<Database> {
-        if !std::path::Path::new(url).exists() {
-            std::fs::File::create(url).ok();
+    /// The persisted relay health snapshot
+    pub async fn relay_health(&self) -> ResultType<Vec<RelayHealth>> {
+        let rows =
+            sqlx::query("SELECT host, healthy, latency_ms, failures, checked_at FROM relay_health")
+                .fetch_all(self.reader.get().await?.deref_mut())
+                .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| RelayHealth {
+                host: row.get("host"),
+                healthy: row.get::<i64, _>("healthy") != 0,
+                latency_ms: row.get::<Option<i64>, _>("latency_ms").map(|x| x as u64),
+                failures: row.get::<i64, _>("failures") as u32,
+                checked_at: row.get("checked_at"),
+            })
+            .collect())
+    }
+
+    /// Store the latest health of each relay (rows of relays no longer configured
+    /// are kept; they are ignored when the snapshot is loaded)
+    pub async fn save_relay_health(&self, relays: &[RelayHealth]) -> ResultType<()> {
+        let mut conn = self.writer.get().await?;
+        for x in relays {
+            sqlx::query(
+                "INSERT OR REPLACE INTO relay_health (host, healthy, latency_ms, failures, checked_at)
+                VALUES (?, ?, ?, ?, ?)",
+            )
+            .bind(&x.host)
+            .bind(x.healthy as i64)
+            .bind(x.latency_ms.map(|x| x as i64))
+            .bind(x.failures as i64)
+            .bind(x.checked_at)
+            .execute(conn.deref_mut())
+            .await?;
         }
-        let n: usize = std::env::var("MAX_DATABASE_CONNECTIONS")
-            .unwrap_or_else(|_| "5".to_owned())  // Increased from 1 to 5
-            .parse()
-            .unwrap_or(5);
-        log::info!("MAX_DATABASE_CONNECTIONS={}", n);
-        let pool = Pool::new(
-            DbPool {
-                url: url.to_owned(),
-            },
-            n,
-        );
-        let _ = pool.get().await?; // test
-        let db = Database { 
-            pool, 
-            url: url.to_owned(),
-        };
-        db.create_tables().await?;
-        db.ensure_columns().await?;
-        Ok(db)
+        Ok(())
     }
 
-    async fn create_tables(&self) -> ResultType<()> {
-        sqlx::query!(
-            "
-            create table if not exists peer (
-                guid blob primary key not null,
-                id varchar(100) not null,
-                uuid blob not null,
-                pk blob not null,
-                created_at datetime not null default(current_timestamp),
-                user blob,
-                status tinyint,
-                note varchar(300),
-                info text not null
-            ) without rowid;
-            create unique index if not exists index_peer_id on peer (id);
-            create index if not exists index_peer_user on peer (user);
-            create index if not exists index_peer_created_at on peer (created_at);
-            create index if not exists index_peer_status on peer (status);
-        "
+    /// Move status events before `before` into their monthly archive files, one
+    /// month per transaction; returns the number of events moved per month
+    pub async fn archive_events(&self, before: i64) -> ResultType<Vec<(String, u64)>> {
+        let dir = archive_dir(&self.url);
+        let mut moved = Vec::new();
+        let mut conn = self.writer.get().await?;
+        loop {
+            let first: Option<i64> = sqlx::query("SELECT min(at) FROM peer_event WHERE at < ?")
+                .bind(before)
+                .fetch_one(conn.deref_mut())
+                .await?
+                .get(0);
+            let first = match first {
+                Some(first) => first,
+                None => break,
+            };
+            let (start, end, month) = month_bounds(first);
+            std::fs::create_dir_all(&dir)?;
+            let path = dir.join(format!("{}.sqlite3", month));
+            sqlx::query("ATTACH DATABASE ? AS archive")
+                .bind(path.to_string_lossy().to_string())
+                .execute(conn.deref_mut())
+                .await?;
+            let res = move_events(conn.deref_mut(), start, end.min(before)).await;
+            if res.is_err() {
+                let _ = sqlx::query("ROLLBACK").execute(conn.deref_mut()).await;
+            }
+            sqlx::query("DETACH DATABASE archive")
+                .execute(conn.deref_mut())
+                .await?;
+            moved.push((month, res?));
+        }
+        Ok(moved)
+    }
+
+    /// Delete archive files of months before the last `keep_months` (counting the
+    /// current one); returns the months deleted
+    pub fn prune_archives(&self, keep_months: u32, now: i64) -> Vec<String> {
+        let dir = archive_dir(&self.url);
+        let mut oldest = month_bounds(now);
+        for _ in 1..keep_months.max(1) {
+            oldest = month_bounds(oldest.0 - 1);
+        }
+        let mut deleted = Vec::new();
+        for entry in std::fs::read_dir(&dir).into_iter().flatten().flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let month = match name.strip_suffix(".sqlite3") {
+                Some(month) if month.len() == 7 => month.to_owned(),
+                _ => continue,
+            };
+            if month < oldest.2 && std::fs::remove_file(entry.path()).is_ok() {
+                deleted.push(month);
+            }
+        }
+        deleted.sort();
+        deleted
+    }
+
+    /// Append an entry to the audit log (fire and forget)
+    pub async fn audit(
+        &self,
+        actor: &'static str,
+        action: &'static str,
+        peer_id: &str,
+        detail: String,
+    ) {
+        emit_event(EventKind::Audit {
+            actor: actor.to_owned(),
+            action,
+            peer_id: peer_id.to_owned(),
+            detail: detail.clone(),
+        });
+        let db = self.clone();
+        let peer_id = peer_id.to_owned();
+        tokio::spawn(async move {
+            let res: ResultType<()> = async {
+                insert_audit(
+                    db.writer.get().await?.deref_mut(),
+                    db.clock.now_utc().timestamp(),
+                    actor,
+                    action,
+                    &peer_id,
+                    &detail,
+                )
+                .await?;
+                Ok(())
+            }
+            .await;
+            if let Err(e) = res {
+                log::warn!(
+                    "Failed to write audit entry {} for {}: {}",
+                    action,
+                    peer_id,
+                    e
+                );
+            }
+        });
+    }
+
+    pub async fn audit_chain_head(&self) -> ResultType<Option<AuditAnchor>> {
+        Ok(audit_head(self.reader.get().await?.deref_mut()).await?)
+    }
+
+    pub async fn pending_key_change(&self, id: &str) -> ResultType<Option<PendingKeyChange>> {
+        let row =
+            sqlx::query("SELECT new_pk, approved_at FROM pending_key_changes WHERE peer_id = ?")
+                .bind(id)
+                .fetch_optional(self.reader.get().await?.deref_mut())
+                .await?;
+        Ok(row.map(|row| PendingKeyChange {
+            new_pk: row.get("new_pk"),
+            approved: row.get::<Option<i64>, _>("approved_at").is_some(),
+        }))
+    }
+
+    /// Park a pk change for approval. A retry with the same new pk only bumps the
+    /// attempt counter; a different new pk replaces the entry (and any approval).
+    /// Returns true when a new entry was parked.
+    pub async fn park_key_change(
+        &self,
+        id: &str,
+        old_pk: &[u8],
+        new_pk: &[u8],
+        old_fingerprint: &str,
+        new_fingerprint: &str,
+    ) -> ResultType<bool> {
+        let now = chrono::Utc::now().timestamp();
+        let mut conn = self.writer.get().await?;
+        let res = sqlx::query(
+            "UPDATE pending_key_changes SET attempts = attempts + 1, last_seen = ?
+             WHERE peer_id = ? AND new_pk = ?",
+        )
+        .bind(now)
+        .bind(id)
+        .bind(new_pk)
+        .execute(conn.deref_mut())
+        .await?;
+        if res.rows_affected() > 0 {
+            return Ok(false);
+        }
+        sqlx::query(
+            "INSERT OR REPLACE INTO pending_key_changes
+             (peer_id, old_pk, new_pk, old_fingerprint, new_fingerprint, first_seen, last_seen, attempts, approved_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, 1, NULL)",
         )
-        .execute(self.pool.get().await?.deref_mut())
+        .bind(id)
+        .bind(old_pk)
+        .bind(new_pk)
+        .bind(old_fingerprint)
+        .bind(new_fingerprint)
+        .bind(now)
+        .bind(now)
+        .execute(conn.deref_mut())
         .await?;
+        Ok(true)
+    }
+
+    /// The pk a key rollback dropped for `id`, refused from then on
+    pub async fn revoked_pk(&self, id: &str) -> ResultType<Option<Vec<u8>>> {
+        let row = sqlx::query("SELECT revoked_pk FROM peer WHERE id = ? AND is_deleted = 0")
+            .bind(id)
+            .fetch_optional(self.reader.get().await?.deref_mut())
+            .await?;
+        Ok(row.and_then(|row| row.get("revoked_pk")))
+    }
+
+    pub async fn remove_pending_key_change(&self, id: &str) -> ResultType<()> {
+        sqlx::query("DELETE FROM pending_key_changes WHERE peer_id = ?")
+            .bind(id)
+            .execute(self.writer.get().await?.deref_mut())
+            .await?;
         Ok(())
     }
 
-    /// Ensure additional columns exist (safe migration for older databases)
-    async fn ensure_columns(&self) -> ResultType<()> {
-        let migrations = [
-            "ALTER TABLE peer ADD COLUMN previous_ids TEXT DEFAULT ''",
-            "ALTER TABLE peer ADD COLUMN id_changed_at TEXT DEFAULT ''",
-            "ALTER TABLE peer ADD COLUMN is_deleted INTEGER DEFAULT 0",
-            "ALTER TABLE peer ADD COLUMN is_banned INTEGER DEFAULT 0",
-            "ALTER TABLE peer ADD COLUMN last_online TEXT",
-        ];
-        for sql in &migrations {
-            // Ignore errors — column may already exist
-            let _ = sqlx::query(sql)
-                .execute(self.pool.get().await?.deref_mut())
-                .await;
+    /// Append online/offline transitions to the status-event log (fire and forget)
+    pub async fn record_status_events(&self, ids: Vec<String>, online: bool, reason: &'static str) {
+        if ids.is_empty() {
+            return;
         }
-        log::debug!("Column migration check completed");
+        for id in &ids {
+            let id = id.clone();
+            emit_event(if online {
+                EventKind::Online { id, reason }
+            } else {
+                EventKind::Offline { id, reason }
+            });
+        }
+        let db = self.clone();
+        tokio::spawn(async move {
+            let at = db.clock.now_utc().timestamp();
+            let res: ResultType<()> = async {
+                let mut conn = db.writer.get().await?;
+                for id in &ids {
+                    sqlx::query(
+                        "INSERT INTO peer_event (peer_id, online, reason, at) VALUES (?, ?, ?, ?)",
+                    )
+                    .bind(id)
+                    .bind(online as i64)
+                    .bind(reason)
+                    .bind(at)
+                    .execute(conn.deref_mut())
+                    .await?;
+                }
+                Ok(())
+            }
+            .await;
+            if let Err(e) = res {
+                log::warn!("Failed to record {} status events: {}", ids.len(), e);
+            }
+        });
+    }
+
+    /// Open a new server run in the status-event log, returns its rowid
+    pub async fn start_server_run(&self) -> ResultType<i64> {
+        let now = self.clock.now_utc().timestamp();
+        let res = sqlx::query("INSERT INTO server_run (started_at, alive_until) VALUES (?, ?)")
+            .bind(now)
+            .bind(now)
+            .execute(self.writer.get().await?.deref_mut())
+            .await?;
+        Ok(res.last_insert_rowid())
+    }
+
+    /// Extend the current server run up to now
+    pub async fn touch_server_run(&self, run: i64) -> ResultType<()> {
+        sqlx::query("UPDATE server_run SET alive_until = ? WHERE rowid = ?")
+            .bind(self.clock.now_utc().timestamp())
+            .bind(run)
+            .execute(self.writer.get().await?.deref_mut())
+            .await?;
+        Ok(())
+    }
+
+    /// Ids of the peers that are not deleted, whose return a restart's recovery waits for
+    pub async fn known_peer_ids(&self) -> ResultType<Vec<String>> {
+        let rows = sqlx::query("SELECT id FROM peer WHERE is_deleted = 0")
+            .fetch_all(self.reader.get().await?.deref_mut())
+            .await?;
+        Ok(rows.iter().map(|row| row.get("id")).collect())
+    }
+
+    /// Store the recovery milestones of a start in restart_history
+    pub async fn record_restart(&self, recovery: &RecoveryStatus) -> ResultType<()> {
+        let after = |percent| {
+            recovery
+                .milestones
+                .iter()
+                .find(|x| x.percent == percent)
+                .and_then(|x| x.after_secs)
+        };
+        sqlx::query(
+            "INSERT INTO restart_history (started_at, known_peers, recovered_peers,
+                 p50_secs, p90_secs, p99_secs, recorded_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(recovery.started_at)
+        .bind(recovery.known_peers as i64)
+        .bind(recovery.recovered_peers as i64)
+        .bind(after(50))
+        .bind(after(90))
+        .bind(after(99))
+        .bind(self.clock.now_utc().timestamp())
+        .execute(self.writer.get().await?.deref_mut())
+        .await?;
         Ok(())
     }
 
@@ -120,15 +2224,22 @@ impl Database {
     pub async fn is_id_available(&self, id: &str) -> ResultType<bool> {
         let row = sqlx::query("SELECT 1 FROM peer WHERE id = ?")
             .bind(id)
-            .fetch_optional(self.pool.get().await?.deref_mut())
+            .fetch_optional(self.reader.get().await?.deref_mut())
             .await?;
         Ok(row.is_none())
     }
 
     /// Change peer ID in the database with history tracking
-    /// Updates id, previous_ids (appends old_id), and id_changed_at
-    pub async fn change_peer_id(&self, old_id: &str, new_id: &str) -> ResultType<()> {
-        let mut conn = self.pool.get().await?;
+    /// Updates id, previous_ids (appends old_id with who changed it), and id_changed_at
+    pub async fn change_peer_id(
+        &self,
+        old_id: &str,
+        new_id: &str,
+        via: IdChangeVia,
+        actor: String,
+    ) -> ResultType<()> {
+        let started = Instant::now();
+        let mut conn = self.writer.get().await?;
 
         // Get current previous_ids for history tracking
         let prev_row = sqlx::query("SELECT previous_ids FROM peer WHERE id = ?")
@@ -141,17 +2252,13 @@ impl Database {
             .unwrap_or_default();
 
         // Build updated history: parse existing JSON array and append old_id
-        let mut history: Vec<String> = if prev_str.is_empty() {
-            Vec::new()
-        } else {
-            serde_json::from_str(&prev_str).unwrap_or_default()
-        };
-        history.push(old_id.to_string());
-        let updated_history = serde_json::to_string(&history).unwrap_or_default();
+        let updated_history =
+            append_id_history(&prev_str, IdHistoryEntry::new(old_id, via, actor.clone()));
 
         // Perform the ID change
         sqlx::query(
-            "UPDATE peer SET id = ?, previous_ids = ?, id_changed_at = datetime('now') WHERE id = ?"
+            "UPDATE peer SET id = ?, previous_ids = ?, id_changed_at = datetime('now'),
+             version = version + 1 WHERE id = ?",
         )
             .bind(new_id)
             .bind(&updated_history)
@@ -159,18 +2266,28 @@ impl Database {
             .execute(conn.deref_mut())
             .await?;
 
+        observe("change_peer_id", started);
         log::info!("Database: ID changed {} -> {} (history: {})", old_id, new_id, updated_history);
+        emit_event(EventKind::Rename {
+            old_id: old_id.to_owned(),
+            new_id: new_id.to_owned(),
+            via,
+            actor,
+        });
         Ok(())
     }
 
     pub async fn get_peer(&self, id: &str) -> ResultType<Option<Peer>> {
-        Ok(sqlx::query_as!(
+        let started = Instant::now();
+        let peer = sqlx::query_as!(
             Peer,
             "select guid, id, uuid, pk, user, status, info from peer where id = ?",
             id
         )
-        .fetch_optional(self.pool.get().await?.deref_mut())
-        .await?)
+        .fetch_optional(self.reader.get().await?.deref_mut())
+        .await?;
+        observe("get_peer", started);
+        Ok(peer)
     }
 
     pub async fn insert_peer(
@@ -180,6 +2297,7 @@ impl Database {
         pk: &[u8],
         info: &str,
     ) -> ResultType<Vec<u8>> {
+        let started = Instant::now();
         let guid = uuid::Uuid::new_v4().as_bytes().to_vec();
         sqlx::query!(
             "insert into peer(guid, id, uuid, pk, info, status, last_online) values(?, ?, ?, ?, ?, 1, datetime('now'))",
@@ -189,89 +2307,150 @@ impl Database {
             pk,
             info
         )
-        .execute(self.pool.get().await?.deref_mut())
+        .execute(self.writer.get().await?.deref_mut())
         .await?;
+        observe("insert_peer", started);
         log::info!("New peer {} inserted with status=1 (online)", id);
         Ok(guid)
     }
 
+    /// A different pk than the stored one moves the stored pk/uuid to the
+    /// previous_* columns with `ip`, replacing the generation before
     pub async fn update_pk(
         &self,
         guid: &Vec<u8>,
         id: &str,
         pk: &[u8],
         info: &str,
+        ip: &str,
     ) -> ResultType<()> {
-        sqlx::query!(
-            "update peer set id=?, pk=?, info=?, status=1, last_online=datetime('now') where guid=?",
-            id,
-            pk,
-            info,
-            guid
+        let started = Instant::now();
+        // bumps the version without the API's If-Match check: registrations
+        // never wait on an admin's edit
+        sqlx::query(
+            "update peer set
+             previous_pk = case when length(pk) > 0 and pk != ? then pk else previous_pk end,
+             previous_uuid = case when length(pk) > 0 and pk != ? then uuid else previous_uuid end,
+             pk_replaced_at = case when length(pk) > 0 and pk != ? then ? else pk_replaced_at end,
+             pk_replaced_from = case when length(pk) > 0 and pk != ? then ? else pk_replaced_from end,
+             id=?, pk=?, info=?, status=1, last_online=datetime('now'),
+             version=version+1 where guid=?",
         )
-        .execute(self.pool.get().await?.deref_mut())
+        .bind(pk)
+        .bind(pk)
+        .bind(pk)
+        .bind(chrono::Utc::now().timestamp())
+        .bind(pk)
+        .bind(ip)
+        .bind(id)
+        .bind(pk)
+        .bind(info)
+        .bind(guid)
+        .execute(self.writer.get().await?.deref_mut())
         .await?;
+        observe("update_pk", started);
         log::debug!("Peer {} updated pk, set status=1, last_online=now", id);
         Ok(())
     }
 
     /// Set device status to online and update last_online timestamp
-    /// Called when device registers or sends heartbeat
-    pub async fn set_online(&self, id: &str) {
-        let id_owned = id.to_string();
-        let url = self.url.clone();
-        
-        // Fire and forget - don't block the main flow
-        tokio::spawn(async move {
-            if let Err(e) = Self::set_online_internal(&url, &id_owned).await {
-                log::warn!("Failed to set {} online: {}", id_owned, e);
+    /// Called when device registers or sends heartbeat; queued for the status
+    /// writer under `source`, the peer's ip where known
+    pub async fn set_online(&self, id: &str, source: &str) {
+        self.queue_status(id, true, source);
+    }
+
+    /// Backlog and top writers of the status writer right now
+    pub fn status_writer(&self) -> StatusWriterStats {
+        self.status_queue
+            .lock()
+            .map(|queue| queue.stats())
+            .unwrap_or_default()
+    }
+
+    fn queue_status(&self, id: &str, online: bool, source: &str) {
+        if let Ok(mut queue) = self.status_queue.lock() {
+            if !queue.push(id, online, source) {
+                count_status_writes("coalesced", 1);
             }
-        });
+        }
+    }
+
+    /// Write the next batch of queued status writes in one transaction
+    pub async fn flush_status_writes(&self) {
+        let batch = env_u64("WRITE_BATCH", WRITE_BATCH).max(1) as usize;
+        let per_source = env_u64("WRITE_PER_SOURCE", WRITE_PER_SOURCE).max(1) as usize;
+        let writes = match self.status_queue.lock() {
+            Ok(mut queue) => queue.take(batch, per_source),
+            Err(_) => return,
+        };
+        if writes.is_empty() {
+            return;
+        }
+        let started = Instant::now();
+        let res: ResultType<()> = async {
+            let mut conn = self.writer.get().await?;
+            // one transaction, and the two statements come from the connection's cache
+            let mut tx = conn.deref_mut().begin().await?;
+            for (id, online) in &writes {
+                sqlx::query(if *online { SET_ONLINE } else { SET_OFFLINE })
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await?;
+            Ok(())
+        }
+        .await;
+        observe("status_flush", started);
+        match res {
+            Ok(()) => count_status_writes("written", writes.len()),
+            Err(e) => {
+                count_status_writes("failed", writes.len());
+                log::warn!("Failed to write {} status changes: {}", writes.len(), e);
+            }
+        }
     }
     
-    async fn set_online_internal(url: &str, id: &str) -> ResultType<()> {
-        let mut opt = SqliteConnectOptions::from_str(url).unwrap();
-        opt.log_statements(log::LevelFilter::Debug);
-        let mut conn = SqliteConnection::connect_with(&opt).await?;
-        
+    /// Set a peer online right away instead of through the status writer
+    pub async fn heartbeat(&self, id: &str) -> ResultType<()> {
+        let started = Instant::now();
         sqlx::query!(
             "UPDATE peer SET status = 1, last_online = datetime('now') WHERE id = ?",
             id
         )
-        .execute(&mut conn)
+        .execute(self.writer.get().await?.deref_mut())
         .await?;
+        observe("set_online", started);
         
         log::trace!("Set {} online, last_online updated", id);
         Ok(())
     }
     
     /// Set device status to offline
-    /// Called when device times out or disconnects
-    pub async fn set_offline(&self, id: &str) {
-        let id_owned = id.to_string();
-        let url = self.url.clone();
-        
+    /// Called when device times out or disconnects; queued like `set_online`
+    pub async fn set_offline(&self, id: &str, source: &str) {
+        self.queue_status(id, false, source);
+    }
+    
+    /// Store a peer's info (ip, transport) outside of a registration
+    pub async fn set_info(&self, guid: Vec<u8>, info: String) {
+        let db = self.clone();
+
         // Fire and forget
         tokio::spawn(async move {
-            if let Err(e) = Self::set_offline_internal(&url, &id_owned).await {
-                log::warn!("Failed to set {} offline: {}", id_owned, e);
+            if let Err(e) = db.set_info_internal(&guid, &info).await {
+                log::warn!("Failed to store peer info {}: {}", info, e);
             }
         });
     }
-    
-    async fn set_offline_internal(url: &str, id: &str) -> ResultType<()> {
-        let mut opt = SqliteConnectOptions::from_str(url).unwrap();
-        opt.log_statements(log::LevelFilter::Debug);
-        let mut conn = SqliteConnection::connect_with(&opt).await?;
-        
-        sqlx::query!(
-            "UPDATE peer SET status = 0 WHERE id = ?",
-            id
-        )
-        .execute(&mut conn)
-        .await?;
-        
-        log::debug!("Set {} offline", id);
+
+    async fn set_info_internal(&self, guid: &[u8], info: &str) -> ResultType<()> {
+        sqlx::query("UPDATE peer SET info = ? WHERE guid = ?")
+            .bind(info)
+            .bind(guid)
+            .execute(self.writer.get().await?.deref_mut())
+            .await?;
         Ok(())
     }
     
@@ -280,7 +2459,7 @@ impl Database {
         sqlx::query!(
             "UPDATE peer SET status = 0 WHERE status = 1"
         )
-        .execute(self.pool.get().await?.deref_mut())
+        .execute(self.writer.get().await?.deref_mut())
         .await?;
         
         log::info!("Reset all devices to offline status on startup");
@@ -293,38 +2472,196 @@ impl Database {
             return Ok(());
         }
         
-        let mut conn = self.pool.get().await?;
-        
+        // a write still queued for one of them is older than this decision
+        if let Ok(mut queue) = self.status_queue.lock() {
+            for id in ids {
+                if let Some(online) = queue.pending.get_mut(id) {
+                    *online = false;
+                }
+            }
+        }
+        let started = Instant::now();
+        let mut conn = self.writer.get().await?;
+        let mut tx = conn.deref_mut().begin().await?;
         for id in ids {
-            sqlx::query!(
-                "UPDATE peer SET status = 0 WHERE id = ?",
-                id
-            )
-            .execute(conn.deref_mut())
-            .await?;
+            sqlx::query(SET_OFFLINE).bind(id).execute(&mut *tx).await?;
         }
+        tx.commit().await?;
+        observe("batch_set_offline", started);
         
         log::debug!("Batch set {} devices offline", ids.len());
         Ok(())
     }
 
+    /// Status of a single peer row
+    pub async fn peer_status_row(&self, id: &str) -> ResultType<Option<PeerStatusRow>> {
+        let row = sqlx::query("SELECT id, status, last_online FROM peer WHERE id = ?")
+            .bind(id)
+            .fetch_optional(self.reader.get().await?.deref_mut())
+            .await?;
+        Ok(row.map(|row| PeerStatusRow {
+            id: row.get("id"),
+            status: row.get("status"),
+            last_online: row.get("last_online"),
+        }))
+    }
+
+    /// Random sample of peer status rows, or the whole table when `limit` is None
+    pub async fn peer_status_rows(&self, limit: Option<usize>) -> ResultType<Vec<PeerStatusRow>> {
+        let mut conn = self.reader.get().await?;
+        let rows = match limit {
+            Some(n) => {
+                sqlx::query("SELECT id, status, last_online FROM peer ORDER BY random() LIMIT ?")
+                    .bind(n as i64)
+                    .fetch_all(conn.deref_mut())
+                    .await?
+            }
+            None => {
+                sqlx::query("SELECT id, status, last_online FROM peer")
+                    .fetch_all(conn.deref_mut())
+                    .await?
+            }
+        };
+        Ok(rows
+            .into_iter()
+            .map(|row| PeerStatusRow {
+                id: row.get("id"),
+                status: row.get("status"),
+                last_online: row.get("last_online"),
+            })
+            .collect())
+    }
+
+    /// Up to `limit` peer status rows with ids after `after`, in id order; pages of a
+    /// pass over the whole table
+    pub async fn peer_status_page(
+        &self,
+        after: &str,
+        limit: usize,
+    ) -> ResultType<Vec<PeerStatusRow>> {
+        let rows = sqlx::query(
+            "SELECT id, status, last_online FROM peer WHERE id > ? ORDER BY id LIMIT ?",
+        )
+        .bind(after)
+        .bind(limit as i64)
+        .fetch_all(self.reader.get().await?.deref_mut())
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| PeerStatusRow {
+                id: row.get("id"),
+                status: row.get("status"),
+                last_online: row.get("last_online"),
+            })
+            .collect())
+    }
+
+    /// Whether a peer is quarantined: it registers and shows online as usual, but
+    /// no session is brokered to or from it
+    pub async fn is_quarantined(&self, id: &str) -> ResultType<bool> {
+        let row = sqlx::query("SELECT is_quarantined FROM peer WHERE id = ? AND is_deleted = 0")
+            .bind(id)
+            .fetch_optional(self.reader.get().await?.deref_mut())
+            .await?;
+        Ok(row.and_then(|row| row.get::<Option<i64>, _>("is_quarantined")) == Some(1))
+    }
+
+    /// Whether the user a peer is assigned to is disabled; a user without a row
+    /// in users is enabled
+    pub async fn is_user_disabled(&self, user: &[u8]) -> ResultType<bool> {
+        let row = sqlx::query("SELECT disabled FROM users WHERE name = ?")
+            .bind(String::from_utf8_lossy(user))
+            .fetch_optional(self.reader.get().await?.deref_mut())
+            .await?;
+        Ok(row.map(|row| row.get::<i64, _>("disabled")) == Some(1))
+    }
+
     /// Check if a device is banned in the database
-    /// Returns true if device has is_banned=1, false otherwise
-    /// Uses synchronous rusqlite to avoid nested Tokio runtime panic
+    /// Returns true if device has is_banned=1 and its banned_until, if any, is
+    /// still ahead; an expired ban counts as lifted before the sweep clears it
     pub async fn is_device_banned(&self, id: &str) -> ResultType<bool> {
-        let db_path = self.url.clone();
-        let id = id.to_string();
-        
-        // Use spawn_blocking to run synchronous rusqlite code
-        let result = tokio::task::spawn_blocking(move || -> ResultType<bool> {
-            let conn = rusqlite::Connection::open(&db_path)?;
-            let mut stmt = conn.prepare("SELECT is_banned FROM peer WHERE id = ?")?;
-            let is_banned: Option<i32> = stmt
-                .query_row([&id], |row| row.get(0))
-                .unwrap_or(None);
-            Ok(is_banned == Some(1))
-        }).await?;
-        
-        result
+        let started = Instant::now();
+        // on a pooled reader with the statement cached, rather than opening
+        // the file and preparing the query for every registration
+        let row = sqlx::query(IS_BANNED)
+            .bind(self.clock.now_utc().timestamp())
+            .bind(id)
+            .fetch_optional(self.reader.get().await?.deref_mut())
+            .await?;
+        observe("is_device_banned", started);
+        Ok(row.and_then(|row| row.get::<Option<i64>, _>("banned")) == Some(1))
+    }
+
+    /// Lift the temporary bans whose banned_until has passed, withdrawing their
+    /// undelivered ban notices; returns the ids
+    pub async fn clear_expired_bans(&self) -> ResultType<Vec<String>> {
+        let mut conn = self.writer.get().await?;
+        let mut tx = conn.deref_mut().begin().await?;
+        let ids: Vec<String> = sqlx::query(
+            "UPDATE peer SET is_banned = 0, banned_until = NULL, version = version + 1
+             WHERE is_banned = 1 AND banned_until <= ? RETURNING id",
+        )
+        .bind(self.clock.now_utc().timestamp())
+        .fetch_all(&mut *tx)
+        .await?
+        .iter()
+        .map(|row| row.get("id"))
+        .collect();
+        for id in &ids {
+            sqlx::query(
+                "DELETE FROM peer_notices WHERE peer_id = ? AND kind = 'ban'
+                 AND delivered_at IS NULL",
+            )
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(ids)
+    }
+}
+
+/// Move the events in [from, to) into the attached `archive` database
+async fn move_events(conn: &mut SqliteConnection, from: i64, to: i64) -> ResultType<u64> {
+    let statements = [
+        "CREATE TABLE IF NOT EXISTS archive.peer_event (
+            peer_id VARCHAR(100) NOT NULL,
+            online TINYINT NOT NULL,
+            reason VARCHAR(32) NOT NULL DEFAULT '',
+            at INTEGER NOT NULL
+        )",
+        "CREATE INDEX IF NOT EXISTS archive.index_peer_event ON peer_event (peer_id, at)",
+        "CREATE INDEX IF NOT EXISTS archive.index_peer_event_at ON peer_event (at)",
+    ];
+    for sql in &statements {
+        sqlx::query(sql).execute(&mut *conn).await?;
     }
+    sqlx::query("BEGIN IMMEDIATE").execute(&mut *conn).await?;
+    // With WAL the commit is atomic per file only: after a crash between the two,
+    // rows still in the main table may already be archived, so those are replaced
+    sqlx::query(
+        "DELETE FROM archive.peer_event WHERE at < ?
+         AND at >= (SELECT min(at) FROM main.peer_event WHERE at >= ? AND at < ?)",
+    )
+    .bind(to)
+    .bind(from)
+    .bind(to)
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query(
+        "INSERT INTO archive.peer_event (peer_id, online, reason, at)
+         SELECT peer_id, online, reason, at FROM main.peer_event WHERE at >= ? AND at < ?",
+    )
+    .bind(from)
+    .bind(to)
+    .execute(&mut *conn)
+    .await?;
+    let moved = sqlx::query("DELETE FROM main.peer_event WHERE at >= ? AND at < ?")
+        .bind(from)
+        .bind(to)
+        .execute(&mut *conn)
+        .await?
+        .rows_affected();
+    sqlx::query("COMMIT").execute(&mut *conn).await?;
+    Ok(moved)
 }
@@ -0,0 +1,126 @@
+// Writer contention benchmark: `hbbs dbbench [PEERS]`
+// Seeds a throwaway database, then times heartbeat writes (the awaitable form
+// of set_online) first on an idle database and again while full-table reads
+// stream through the read-only pool in a loop. With the read/write split and
+// WAL the two latency distributions should be about the same.
+
+use hbb_common::{bail, log, tokio, ResultType};
+use sqlx::{sqlite::SqliteConnectOptions, Connection};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const DEFAULT_PEERS: usize = 50_000;
+const HEARTBEATS: usize = 500;
+const EXPORTERS: usize = 2;
+
+pub fn run() -> ResultType<()> {
+    let peers = match std::env::args().nth(2) {
+        Some(n) => n.parse::<usize>()?,
+        None => DEFAULT_PEERS,
+    };
+    if peers == 0 {
+        bail!("need at least one peer");
+    }
+    let dir = std::env::temp_dir().join(format!("hbbs-dbbench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let db = dir.join("db_v2.sqlite3").to_string_lossy().to_string();
+    let res = tokio::runtime::Runtime::new()?.block_on(bench(&db, peers));
+    std::fs::remove_dir_all(&dir).ok();
+    res
+}
+
+async fn bench(url: &str, peers: usize) -> ResultType<()> {
+    let db = hbbs::Database::new(url).await?;
+    seed(url, peers).await?;
+    log::info!("dbbench: seeded {} peers", peers);
+
+    let idle = heartbeats(&db, peers).await?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let exports = Arc::new(AtomicU64::new(0));
+    let mut exporters = Vec::new();
+    for _ in 0..EXPORTERS {
+        let (db, stop, exports) = (db.clone(), stop.clone(), exports.clone());
+        exporters.push(tokio::spawn(async move {
+            while !stop.load(Ordering::Relaxed) {
+                match db.peer_status_rows(None).await {
+                    Ok(_) => {
+                        exports.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(e) => {
+                        log::error!("dbbench: export failed: {}", e);
+                        break;
+                    }
+                }
+            }
+        }));
+    }
+    // let the exports get going before timing
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let loaded = heartbeats(&db, peers).await?;
+    stop.store(true, Ordering::Relaxed);
+    for x in exporters {
+        x.await.ok();
+    }
+
+    report("idle", &idle);
+    report("during export", &loaded);
+    log::info!(
+        "dbbench: {} full-table exports of {} rows completed while timing",
+        exports.load(Ordering::Relaxed),
+        peers
+    );
+    if exports.load(Ordering::Relaxed) == 0 {
+        bail!("no export completed, the read pool was not exercised");
+    }
+    Ok(())
+}
+
+pub(crate) async fn seed(url: &str, peers: usize) -> ResultType<()> {
+    let opt = SqliteConnectOptions::from_str(url)?;
+    let mut conn = sqlx::SqliteConnection::connect_with(&opt).await?;
+    let mut tx = conn.begin().await?;
+    for i in 0..peers {
+        sqlx::query(
+            "INSERT INTO peer (guid, id, uuid, pk, info, status, last_online)
+             VALUES (?, ?, ?, ?, '', 0, datetime('now'))",
+        )
+        .bind(uuid::Uuid::new_v4().as_bytes().to_vec())
+        .bind(bench_id(i))
+        .bind(vec![0u8; 16])
+        .bind(vec![0u8; 32])
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+async fn heartbeats(db: &hbbs::Database, peers: usize) -> ResultType<Vec<Duration>> {
+    let mut out = Vec::with_capacity(HEARTBEATS);
+    for i in 0..HEARTBEATS {
+        let id = bench_id(i * 7919 % peers);
+        let started = Instant::now();
+        db.heartbeat(&id).await?;
+        out.push(started.elapsed());
+    }
+    out.sort();
+    Ok(out)
+}
+
+fn report(phase: &str, sorted: &[Duration]) {
+    let at = |q: f64| sorted[((sorted.len() - 1) as f64 * q) as usize];
+    log::info!(
+        "dbbench: heartbeat write {}: p50 {:?}, p99 {:?}, max {:?}",
+        phase,
+        at(0.5),
+        at(0.99),
+        sorted[sorted.len() - 1]
+    );
+}
+
+pub(crate) fn bench_id(i: usize) -> String {
+    format!("BENCH{:08}", i)
+}
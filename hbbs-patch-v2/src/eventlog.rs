@@ -0,0 +1,403 @@
+// Server events as JSON lines for SIEM ingestion: `--event-log FILE`
+// A dedicated thread subscribes to the server's event stream (the same Event
+// payloads other subscribers get) and appends one JSON object per line. The
+// server never waits on the file: a writer more than EVENT_QUEUE events behind
+// loses the oldest ones, counted in hbbs_event_log_events_total{outcome="dropped"}
+// and visible as a gap in seq. Once the file would grow past EVENT_LOG_MAX_MB it
+// is rotated to FILE.1 (FILE.1 to FILE.2, ...), keeping EVENT_LOG_KEEP old files.
+// During a surge (a restart, a site-wide outage) online and offline transitions
+// past EVENT_SURGE_THRESHOLD within EVENT_SURGE_WINDOW_SECS are collapsed into one
+// online_bulk/offline_bulk line per window; the event stream itself is unchanged.
+
+use crate::sync::env_u64;
+use hbb_common::{
+    log,
+    tokio::sync::broadcast::{
+        self,
+        error::{RecvError, TryRecvError},
+    },
+    ResultType,
+};
+use hbbs::{BulkEvents, Event, EventKind};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+const MAX_MB: u64 = 100;
+const KEEP: u64 = 5;
+const SURGE_THRESHOLD: u64 = 100;
+const SURGE_WINDOW_SECS: u64 = 10;
+const SURGE_SAMPLE: u64 = 20;
+// how long the writer sleeps between polls while a surge window is open
+const SURGE_POLL: Duration = Duration::from_millis(50);
+
+static WRITTEN: AtomicU64 = AtomicU64::new(0);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+static FAILED: AtomicU64 = AtomicU64::new(0);
+static ROTATIONS: AtomicU64 = AtomicU64::new(0);
+static COLLAPSED: AtomicU64 = AtomicU64::new(0);
+static SUMMARIES: AtomicU64 = AtomicU64::new(0);
+
+/// Collapses online/offline transitions past `threshold` per kind within a window
+/// into one summary event per window. The first `threshold` events of a window
+/// pass as they are, so a quiet server never waits for a window to end.
+pub struct Surge {
+    threshold: u64,
+    window: Duration,
+    sample: usize,
+    windows: HashMap<&'static str, SurgeWindow>,
+}
+
+struct SurgeWindow {
+    started: Instant,
+    seen: u64,
+    bulk: BulkEvents,
+    last_seq: u64,
+}
+
+impl Surge {
+    /// A threshold of 0 turns collapsing off
+    pub fn new(threshold: u64, window: Duration, sample: usize) -> Self {
+        Surge {
+            threshold,
+            window,
+            sample,
+            windows: HashMap::new(),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        Self::new(
+            env_u64("EVENT_SURGE_THRESHOLD", SURGE_THRESHOLD),
+            Duration::from_secs(env_u64("EVENT_SURGE_WINDOW_SECS", SURGE_WINDOW_SECS).max(1)),
+            env_u64("EVENT_SURGE_SAMPLE", SURGE_SAMPLE) as usize,
+        )
+    }
+
+    /// The events to write for `event` at `now`: summaries of windows that
+    /// ended, then the event itself unless it is collapsed
+    pub fn push(&mut self, event: Event, now: Instant, out: &mut Vec<Event>) {
+        self.expire(now, out);
+        let (kind, id, reason) = match &event.kind {
+            EventKind::Online { id, reason } => ("online", id, *reason),
+            EventKind::Offline { id, reason } => ("offline", id, *reason),
+            _ => return out.push(event),
+        };
+        if self.threshold == 0 {
+            return out.push(event);
+        }
+        let window = self.windows.entry(kind).or_insert_with(|| SurgeWindow {
+            started: now,
+            seen: 0,
+            bulk: BulkEvents {
+                window_secs: self.window.as_secs(),
+                ..Default::default()
+            },
+            last_seq: 0,
+        });
+        window.seen += 1;
+        if window.seen <= self.threshold {
+            return out.push(event);
+        }
+        let bulk = &mut window.bulk;
+        if bulk.count == 0 {
+            bulk.first_seq = event.seq;
+        }
+        bulk.count += 1;
+        *bulk.reasons.entry(reason).or_default() += 1;
+        if bulk.sample_ids.len() < self.sample {
+            bulk.sample_ids.push(id.clone());
+        }
+        window.last_seq = event.seq;
+        COLLAPSED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Summaries of the windows that ended by `now`
+    pub fn expire(&mut self, now: Instant, out: &mut Vec<Event>) {
+        let window = self.window;
+        let ended: Vec<_> = self
+            .windows
+            .iter()
+            .filter(|(_, x)| now.duration_since(x.started) >= window)
+            .map(|(kind, _)| *kind)
+            .collect();
+        for kind in ended {
+            let window = match self.windows.remove(kind) {
+                Some(window) => window,
+                None => continue,
+            };
+            if window.bulk.count == 0 {
+                continue;
+            }
+            log::warn!(
+                "Event log: {} {} events collapsed into one line",
+                window.bulk.count,
+                kind
+            );
+            SUMMARIES.fetch_add(1, Ordering::Relaxed);
+            out.push(Event {
+                v: hbbs::EVENT_SCHEMA,
+                seq: window.last_seq,
+                at: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+                kind: if kind == "online" {
+                    EventKind::OnlineBulk(window.bulk)
+                } else {
+                    EventKind::OfflineBulk(window.bulk)
+                },
+            });
+        }
+    }
+
+    /// Whether a window holds collapsed events not summarized yet
+    pub fn pending(&self) -> bool {
+        self.windows.values().any(|x| x.bulk.count > 0)
+    }
+}
+
+pub struct EventLog {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: u64,
+    file: Option<BufWriter<File>>,
+    size: u64,
+    failing: bool,
+    surge: Surge,
+}
+
+impl EventLog {
+    /// Open `path` for appending; the file is rotated before it would exceed `max_bytes`
+    pub fn open(path: impl Into<PathBuf>, max_bytes: u64, keep: u64) -> std::io::Result<Self> {
+        let mut log = EventLog {
+            path: path.into(),
+            max_bytes: max_bytes.max(1),
+            keep,
+            file: None,
+            size: 0,
+            failing: false,
+            surge: Surge::new(0, Duration::from_secs(1), 0),
+        };
+        log.reopen()?;
+        Ok(log)
+    }
+
+    fn reopen(&mut self) -> std::io::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = file.metadata()?.len();
+        self.file = Some(BufWriter::new(file));
+        Ok(())
+    }
+
+    /// Collapse surges of online/offline events with `surge`
+    pub fn with_surge(mut self, surge: Surge) -> Self {
+        self.surge = surge;
+        self
+    }
+
+    /// FILE.n for the n-th most recent rotated file
+    pub fn rotated(&self, n: u64) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if let Some(mut file) = self.file.take() {
+            file.flush()?;
+        }
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = self.rotated(n);
+                if from.exists() {
+                    std::fs::rename(&from, self.rotated(n + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, self.rotated(1))?;
+        }
+        ROTATIONS.fetch_add(1, Ordering::Relaxed);
+        self.reopen()
+    }
+
+    /// Append one event as a line; failures are counted and logged once until the
+    /// next success
+    pub fn write(&mut self, event: &Event) {
+        let mut line = match serde_json::to_vec(event) {
+            Ok(line) => line,
+            Err(e) => return self.failed(e.into()),
+        };
+        line.push(b'\n');
+        let res = (|| {
+            if self.file.is_none() {
+                self.reopen()?;
+            }
+            if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+                self.rotate()?;
+            }
+            if let Some(file) = self.file.as_mut() {
+                file.write_all(&line)?;
+            }
+            Ok::<_, std::io::Error>(())
+        })();
+        match res {
+            Ok(()) => {
+                self.size += line.len() as u64;
+                WRITTEN.fetch_add(1, Ordering::Relaxed);
+                if std::mem::replace(&mut self.failing, false) {
+                    log::info!("Event log: writing to {} again", self.path.display());
+                }
+            }
+            Err(e) => {
+                // reopened (and re-measured) on the next event
+                self.file = None;
+                self.failed(e.into());
+            }
+        }
+    }
+
+    fn failed(&mut self, e: hbb_common::anyhow::Error) {
+        FAILED.fetch_add(1, Ordering::Relaxed);
+        if !std::mem::replace(&mut self.failing, true) {
+            log::warn!("Event log: cannot write to {}: {}", self.path.display(), e);
+        }
+    }
+
+    pub fn flush(&mut self) {
+        if let Some(Err(e)) = self.file.as_mut().map(|file| file.flush()) {
+            self.file = None;
+            self.failed(e.into());
+        }
+    }
+
+    /// Write `event` at `now`, unless a surge collapses it, along with the
+    /// summaries of surge windows that ended
+    pub fn admit(&mut self, event: Event, now: Instant) {
+        let mut out = Vec::new();
+        self.surge.push(event, now, &mut out);
+        for event in &out {
+            self.write(event);
+        }
+    }
+
+    /// Write the summaries of the surge windows that ended by `now`
+    pub fn expire(&mut self, now: Instant) {
+        let mut out = Vec::new();
+        self.surge.expire(now, &mut out);
+        for event in &out {
+            self.write(event);
+        }
+    }
+
+    /// Write everything already queued, then flush; false once the stream is closed
+    pub fn drain(&mut self, rx: &mut broadcast::Receiver<Event>) -> bool {
+        loop {
+            match rx.try_recv() {
+                Ok(event) => self.admit(event, Instant::now()),
+                Err(TryRecvError::Lagged(n)) => lagged(n),
+                Err(TryRecvError::Empty) => {
+                    self.expire(Instant::now());
+                    self.flush();
+                    return true;
+                }
+                Err(TryRecvError::Closed) => {
+                    // whatever was collapsed is summarized before the end
+                    self.expire(Instant::now() + self.surge.window);
+                    self.flush();
+                    return false;
+                }
+            }
+        }
+    }
+}
+
+fn lagged(n: u64) {
+    DROPPED.fetch_add(n, Ordering::Relaxed);
+    log::warn!("Event log: fell behind, {} events dropped", n);
+}
+
+/// Open the event log from the command line and start its writer thread
+pub fn spawn_writer_thread(path: &str) -> ResultType<()> {
+    let max_bytes = env_u64("EVENT_LOG_MAX_MB", MAX_MB).saturating_mul(1024 * 1024);
+    let keep = env_u64("EVENT_LOG_KEEP", KEEP);
+    let mut log = EventLog::open(path, max_bytes, keep)
+        .map_err(|e| hbb_common::anyhow::anyhow!("Cannot open the event log {}: {}", path, e))?
+        .with_surge(Surge::from_env());
+    // subscribed before the server starts, so no event is missed
+    let mut rx = hbbs::subscribe_events();
+    log::info!(
+        "Event log: {} (rotated at {} MB, {} kept)",
+        path,
+        max_bytes / 1024 / 1024,
+        keep
+    );
+    std::thread::spawn(move || {
+        while log.drain(&mut rx) {
+            // an open surge window is summarized when it ends, not on the next event
+            if log.surge.pending() {
+                std::thread::sleep(SURGE_POLL);
+                continue;
+            }
+            match rx.blocking_recv() {
+                Ok(event) => log.admit(event, Instant::now()),
+                Err(RecvError::Lagged(n)) => lagged(n),
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+    Ok(())
+}
+
+/// (written, dropped, failed, rotations) since the start
+pub fn counts() -> (u64, u64, u64, u64) {
+    (
+        WRITTEN.load(Ordering::Relaxed),
+        DROPPED.load(Ordering::Relaxed),
+        FAILED.load(Ordering::Relaxed),
+        ROTATIONS.load(Ordering::Relaxed),
+    )
+}
+
+/// (events collapsed, summary lines) since the start
+pub fn surge_counts() -> (u64, u64) {
+    (
+        COLLAPSED.load(Ordering::Relaxed),
+        SUMMARIES.load(Ordering::Relaxed),
+    )
+}
+
+pub fn render_metrics() -> String {
+    let (written, dropped, failed, rotations) = counts();
+    let (collapsed, summaries) = surge_counts();
+    let mut out = String::new();
+    out.push_str("# HELP hbbs_event_log_events_total Events for the --event-log file by outcome\n");
+    out.push_str("# TYPE hbbs_event_log_events_total counter\n");
+    for (outcome, n) in [
+        ("written", written),
+        ("dropped", dropped),
+        ("failed", failed),
+        ("collapsed", collapsed),
+    ] {
+        out.push_str(&format!(
+            "hbbs_event_log_events_total{{outcome=\"{}\"}} {}\n",
+            outcome, n
+        ));
+    }
+    out.push_str("# HELP hbbs_event_log_rotations_total Event log files rotated\n");
+    out.push_str("# TYPE hbbs_event_log_rotations_total counter\n");
+    out.push_str(&format!("hbbs_event_log_rotations_total {}\n", rotations));
+    out.push_str(
+        "# HELP hbbs_event_log_surge_summaries_total Bulk lines written for collapsed events\n",
+    );
+    out.push_str("# TYPE hbbs_event_log_surge_summaries_total counter\n");
+    out.push_str(&format!(
+        "hbbs_event_log_surge_summaries_total {}\n",
+        summaries
+    ));
+    out
+}
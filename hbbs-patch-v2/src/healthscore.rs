@@ -0,0 +1,348 @@
+// Fleet health score for NOC wallboards: one number from 0 to 100
+// Every HEALTH_SCORE_INTERVAL_SECS the loop samples four components, each from
+// 0 (bad) to 1 (good), and weighs them with the WEIGHT_* constants:
+//   online   - peers online against the expected-online baseline, full marks
+//              from ONLINE_FULL_RATIO of it
+//   relays   - share of the checked relays that are healthy
+//   database - 1 up to DB_GOOD_MS for this tick's own queries, 0 from DB_BAD_MS,
+//              linear in between; 0 when they fail or storage is unwritable
+//   errors   - 1 minus the share of registrations answered with an error since
+//              the last tick, 0 from ERROR_RATE_ZERO
+// A component that cannot be told (no baseline yet, no relay checked, no
+// registrations) is left out and the weights of the others count in full.
+// The baseline is the average online count of the same UTC weekday and hour
+// over the trailing HEALTH_BASELINE_WEEKS weeks, from the per-hour averages in
+// online_baseline, so nighttime dips read as expected. The last 24 hours of
+// scores stay in memory for GET /api/stats/health-score; the latest is the
+// hbbs_fleet_health_score gauge.
+
+use crate::sync::env_u64;
+use hbb_common::{log, tokio, ResultType};
+use serde::Serialize;
+use sqlx::{sqlite::SqlitePool, Row};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const INTERVAL_SECS: u64 = 60; // HEALTH_SCORE_INTERVAL_SECS
+const BASELINE_WEEKS: u64 = 4; // HEALTH_BASELINE_WEEKS
+const TREND_SECS: u64 = 24 * 3600;
+
+pub const WEIGHT_ONLINE: f64 = 0.4;
+pub const WEIGHT_RELAYS: f64 = 0.2;
+pub const WEIGHT_DATABASE: f64 = 0.2;
+pub const WEIGHT_ERRORS: f64 = 0.2;
+/// Online against the baseline from which the online component is full
+pub const ONLINE_FULL_RATIO: f64 = 0.9;
+/// Below this many peers expected online the comparison says nothing
+pub const ONLINE_MIN_EXPECTED: f64 = 1.0;
+pub const DB_GOOD_MS: u64 = 50;
+pub const DB_BAD_MS: u64 = 1000;
+/// Share of registrations answered with an error at which the component is 0
+pub const ERROR_RATE_ZERO: f64 = 0.25;
+
+const HOUR_SECS: i64 = 3600;
+const WEEK_HOURS: i64 = 7 * 24;
+
+/// What one tick measured
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Inputs {
+    /// None without the PeerMap
+    pub online_peers: Option<u64>,
+    /// Average online at this weekday and hour in the trailing weeks, None
+    /// until a week of samples exists
+    pub expected_online: Option<f64>,
+    pub relays_healthy: u64,
+    pub relays_down: u64,
+    /// How long the tick's own queries took, None when they failed
+    pub db_latency_ms: Option<u64>,
+    /// The database file can be written
+    pub storage_ok: bool,
+    /// RegisterPk responses since the last tick, and how many were errors
+    pub registrations: u64,
+    pub registration_errors: u64,
+}
+
+/// Each from 0 to 1, None when it cannot be told
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Components {
+    pub online: Option<f64>,
+    pub relays: Option<f64>,
+    pub database: Option<f64>,
+    pub errors: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Sample {
+    pub at: i64,
+    /// 0 to 100
+    pub score: f64,
+    pub components: Components,
+    pub inputs: Inputs,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TrendPoint {
+    pub at: i64,
+    pub score: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Weights {
+    pub online: f64,
+    pub relays: f64,
+    pub database: f64,
+    pub errors: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    /// None until the first tick
+    pub latest: Option<Sample>,
+    pub weights: Weights,
+    pub interval_secs: u64,
+    /// Oldest first, the last 24 hours
+    pub trend: Vec<TrendPoint>,
+}
+
+lazy_static::lazy_static! {
+    static ref TREND: Mutex<VecDeque<Sample>> = Default::default();
+    // RegisterPk totals (all, ok) at the last tick
+    static ref LAST_REGISTRATIONS: Mutex<Option<(usize, usize)>> = Default::default();
+}
+
+/// The components of a tick's measurements
+pub fn components(inputs: &Inputs) -> Components {
+    let online = match (inputs.online_peers, inputs.expected_online) {
+        (Some(online), Some(expected)) if expected >= ONLINE_MIN_EXPECTED => {
+            Some((online as f64 / expected / ONLINE_FULL_RATIO).min(1.0))
+        }
+        _ => None,
+    };
+    let checked = inputs.relays_healthy + inputs.relays_down;
+    let relays = match checked {
+        0 => None,
+        _ => Some(inputs.relays_healthy as f64 / checked as f64),
+    };
+    let database = match (inputs.storage_ok, inputs.db_latency_ms) {
+        (true, Some(ms)) if ms <= DB_GOOD_MS => Some(1.0),
+        (true, Some(ms)) if ms < DB_BAD_MS => {
+            Some((DB_BAD_MS - ms) as f64 / (DB_BAD_MS - DB_GOOD_MS) as f64)
+        }
+        _ => Some(0.0),
+    };
+    let errors = match inputs.registrations {
+        0 => None,
+        n => {
+            let rate = inputs.registration_errors as f64 / n as f64;
+            Some((1.0 - rate / ERROR_RATE_ZERO).max(0.0))
+        }
+    };
+    Components {
+        online,
+        relays,
+        database,
+        errors,
+    }
+}
+
+/// The weighted score from 0 to 100 over the components that can be told;
+/// 100 when none can
+pub fn score(components: &Components) -> f64 {
+    let weighted = [
+        (components.online, WEIGHT_ONLINE),
+        (components.relays, WEIGHT_RELAYS),
+        (components.database, WEIGHT_DATABASE),
+        (components.errors, WEIGHT_ERRORS),
+    ];
+    let (sum, weights) = weighted
+        .iter()
+        .filter_map(|(value, weight)| value.map(|x| (x.clamp(0.0, 1.0) * weight, weight)))
+        .fold((0.0, 0.0), |(sum, weights), (x, w)| (sum + x, weights + w));
+    if weights <= 0.0 {
+        return 100.0;
+    }
+    (sum / weights * 1000.0).round() / 10.0
+}
+
+/// Create the baseline table; `start` runs the loop
+pub async fn init(pool: &SqlitePool) -> ResultType<()> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS online_baseline (
+            hour INTEGER PRIMARY KEY,
+            online_sum INTEGER NOT NULL,
+            samples INTEGER NOT NULL
+        )",
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub fn start(pool: SqlitePool) {
+    hbbs::supervise("health_score", move || run(pool.clone()));
+}
+
+async fn run(pool: SqlitePool) {
+    let interval = env_u64("HEALTH_SCORE_INTERVAL_SECS", INTERVAL_SECS).max(1);
+    loop {
+        tick(&pool, chrono::Utc::now().timestamp()).await;
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+    }
+}
+
+/// Measure, score and keep one sample as of `now` (unix seconds)
+pub async fn tick(pool: &SqlitePool, now: i64) -> Sample {
+    let pm = hbbs::peer_map_watch().borrow().clone();
+    let online_peers = match pm {
+        Some(pm) => Some(pm.online_ids().await.len() as u64),
+        None => None,
+    };
+    let started = Instant::now();
+    let expected_online = match baseline(pool, now, online_peers).await {
+        Ok(expected) => Ok(expected),
+        Err(e) => {
+            log::warn!("Health score: baseline unavailable: {}", e);
+            Err(e)
+        }
+    };
+    let db_latency_ms = expected_online
+        .as_ref()
+        .ok()
+        .map(|_| started.elapsed().as_millis() as u64);
+    let (_, relays) = hbbs::relay_servers_status();
+    let (registrations, registration_errors) = registrations_since_last_tick();
+    let inputs = Inputs {
+        online_peers,
+        expected_online: expected_online.ok().flatten(),
+        relays_healthy: relays.iter().filter(|x| x.state == "healthy").count() as u64,
+        relays_down: relays.iter().filter(|x| x.state == "down").count() as u64,
+        db_latency_ms,
+        storage_ok: hbbs::storage_report().map_or(true, |x| x.problems.is_empty()),
+        registrations,
+        registration_errors,
+    };
+    let components = components(&inputs);
+    let sample = Sample {
+        at: now,
+        score: score(&components),
+        components,
+        inputs,
+    };
+    if let Ok(mut trend) = TREND.lock() {
+        let interval = env_u64("HEALTH_SCORE_INTERVAL_SECS", INTERVAL_SECS).max(1);
+        let keep = (TREND_SECS / interval).max(1) as usize;
+        while trend.len() >= keep {
+            trend.pop_front();
+        }
+        trend.push_back(sample.clone());
+    }
+    sample
+}
+
+/// Add this tick's online count to its hour, drop hours older than the
+/// baseline window, and return the average online at this weekday and hour
+/// in the trailing weeks
+pub async fn baseline(
+    pool: &SqlitePool,
+    now: i64,
+    online: Option<u64>,
+) -> Result<Option<f64>, sqlx::Error> {
+    let weeks = env_u64("HEALTH_BASELINE_WEEKS", BASELINE_WEEKS).max(1) as i64;
+    let hour = now.div_euclid(HOUR_SECS);
+    if let Some(online) = online {
+        sqlx::query(
+            "INSERT INTO online_baseline (hour, online_sum, samples) VALUES (?, ?, 1)
+             ON CONFLICT(hour) DO UPDATE SET online_sum = online_sum + excluded.online_sum,
+                 samples = samples + 1",
+        )
+        .bind(hour)
+        .bind(online as i64)
+        .execute(pool)
+        .await?;
+        sqlx::query("DELETE FROM online_baseline WHERE hour < ?")
+            .bind(hour - weeks * WEEK_HOURS)
+            .execute(pool)
+            .await?;
+    }
+    let expected: Option<f64> = sqlx::query(
+        "SELECT avg(online_sum * 1.0 / samples) FROM online_baseline
+         WHERE hour < ? AND hour >= ? AND (? - hour) % ? = 0",
+    )
+    .bind(hour)
+    .bind(hour - weeks * WEEK_HOURS)
+    .bind(hour)
+    .bind(WEEK_HOURS)
+    .fetch_one(pool)
+    .await?
+    .get(0);
+    Ok(expected)
+}
+
+/// RegisterPk responses since the last call, and how many of them were errors
+fn registrations_since_last_tick() -> (u64, u64) {
+    let (total, ok) = hbbs::registration_totals();
+    let last = LAST_REGISTRATIONS
+        .lock()
+        .ok()
+        .and_then(|mut x| x.replace((total, ok)));
+    let (last_total, last_ok) = last.unwrap_or((total, ok));
+    let registrations = total.saturating_sub(last_total);
+    let ok = ok.saturating_sub(last_ok);
+    (
+        registrations as u64,
+        registrations.saturating_sub(ok) as u64,
+    )
+}
+
+pub fn report() -> Report {
+    let trend = TREND.lock().map(|x| x.clone()).unwrap_or_default();
+    Report {
+        latest: trend.back().cloned(),
+        weights: Weights {
+            online: WEIGHT_ONLINE,
+            relays: WEIGHT_RELAYS,
+            database: WEIGHT_DATABASE,
+            errors: WEIGHT_ERRORS,
+        },
+        interval_secs: env_u64("HEALTH_SCORE_INTERVAL_SECS", INTERVAL_SECS).max(1),
+        trend: trend
+            .iter()
+            .map(|x| TrendPoint {
+                at: x.at,
+                score: x.score,
+            })
+            .collect(),
+    }
+}
+
+pub fn render_metrics() -> String {
+    let latest = TREND.lock().ok().and_then(|x| x.back().cloned());
+    let latest = match latest {
+        Some(latest) => latest,
+        None => return String::new(),
+    };
+    let mut out = String::new();
+    out.push_str("# HELP hbbs_fleet_health_score Fleet health score from 0 to 100\n");
+    out.push_str("# TYPE hbbs_fleet_health_score gauge\n");
+    out.push_str(&format!("hbbs_fleet_health_score {}\n", latest.score));
+    out.push_str(
+        "# HELP hbbs_fleet_health_component Components of the fleet health score from 0 to 1\n",
+    );
+    out.push_str("# TYPE hbbs_fleet_health_component gauge\n");
+    let c = latest.components;
+    for (component, value) in [
+        ("online", c.online),
+        ("relays", c.relays),
+        ("database", c.database),
+        ("errors", c.errors),
+    ] {
+        if let Some(value) = value {
+            out.push_str(&format!(
+                "hbbs_fleet_health_component{{component=\"{}\"}} {}\n",
+                component, value
+            ));
+        }
+    }
+    out
+}
@@ -4,23 +4,29 @@
 
 extern crate serde_json;
 
+use crate::oidc::Role;
 use axum::{
-    extract::{Extension, Path},
-    http::{StatusCode, HeaderMap},
-    response::Json,
-    routing::{get, post},
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, Extension, Path, Query,
+    },
+    http::{HeaderValue, StatusCode, HeaderMap},
+    response::{IntoResponse, Json, Response},
+    routing::{delete, get, post, put},
     Router,
 };
-use serde::{Serialize, Deserialize};
+use hbb_common::tokio::sync::watch;
+use serde::{Deserialize, Serialize};
 use sqlx::{sqlite::SqlitePool, Row};
-use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::fs;
 use std::time::Instant;
 
 /// Get the API key file path.
 /// Priority: 1) API_KEY_FILE env var  2) CWD-relative on Windows  3) /opt/rustdesk/.api_key on Linux
-fn get_api_key_path() -> String {
+pub(crate) fn get_api_key_path() -> String {
     if let Ok(p) = std::env::var("API_KEY_FILE") {
         return p;
     }
@@ -32,355 +38,5165 @@ fn get_api_key_path() -> String {
     }
 }
 
+/// The keys file with read and admin keys: API_KEYS_FILE, or `.api_keys` next to
+/// the API key file
+pub(crate) fn get_api_keys_path() -> String {
+    if let Ok(p) = std::env::var("API_KEYS_FILE") {
+        return p;
+    }
+    std::path::Path::new(&get_api_key_path())
+        .with_file_name(".api_keys")
+        .to_string_lossy()
+        .to_string()
+}
+
 #[derive(Clone)]
 pub struct ApiState {
     pub db_pool: SqlitePool,
-    pub api_key: String,
+    /// Read-only connections for listings and reports, so long reads never hold up writes
+    pub read_pool: SqlitePool,
+    /// X-API-Key; rotated in place by POST /api/keys/rotate
+    pub api_key: crate::apikey::ApiKeys,
     pub start_time: Instant,
+    pub public_peer_list: Option<PublicPeerList>,
+    /// Live PeerMap from the rendezvous side, sampled per request
+    pub peer_map: watch::Receiver<Option<hbbs::PeerMapHandle>>,
+    /// Since when requests have been answered without the PeerMap
+    pub peer_map_fallback_since: Arc<std::sync::Mutex<Option<Instant>>>,
+    /// The udp self-test result; health is `starting` until it passed
+    pub readiness: watch::Receiver<hbbs::Readiness>,
+    /// Peer changes without If-Match are refused with 428 (`--api-require-if-match`)
+    pub require_if_match: bool,
+}
+
+/// What the unauthenticated `/api/public/peers` listing shows next to the online flag
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PublicPeerList {
+    /// Peer ids only (`--public-peer-list=minimal`)
+    Ids,
+    /// Notes only, for fleets whose ids should not be shown (`--public-peer-list=notes`)
+    Notes,
+}
+
+impl PublicPeerList {
+    /// None when the listing is disabled (the default)
+    pub fn parse(s: &str) -> Result<Option<Self>, String> {
+        match s.to_lowercase().as_str() {
+            "" | "off" => Ok(None),
+            "minimal" | "ids" => Ok(Some(Self::Ids)),
+            "notes" => Ok(Some(Self::Notes)),
+            _ => Err(format!(
+                "invalid public-peer-list {}, expected off, minimal or notes",
+                s
+            )),
+        }
+    }
+}
+
+/// A peer as seen by the public listing: never IPs, timestamps or other fields
+#[derive(Serialize, Debug, PartialEq)]
+pub(crate) struct PublicPeer {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    pub online: bool,
 }
 
+/// Requests per source IP and minute to `/api/public/peers` (PUBLIC_PEER_LIST_RATE)
+const PUBLIC_PEER_LIST_RATE: u32 = 6;
+const PUBLIC_RATE_MAX_IPS: usize = 10_000;
+
+lazy_static::lazy_static! {
+    static ref PUBLIC_RATE: std::sync::Mutex<HashMap<IpAddr, (Instant, u32)>> = Default::default();
+    // event seqs start over with the process; an SSE id from before a restart
+    // names another epoch
+    static ref EVENT_EPOCH: i64 = chrono::Utc::now().timestamp();
+}
+
+/// Reporting the database's view instead of the PeerMap's for over this long
+/// raises `peer_map_fallback` in the health check
+const PEER_MAP_FALLBACK_HEALTH_SECS: u64 = 60;
+
 #[derive(Serialize)]
-struct PeerStatus {
+pub(crate) struct PeerStatus {
     id: String,
     note: Option<String>,
     online: bool,
     last_online: Option<String>,
+    /// Bumped by every change to the peer, the ETag for If-Match
+    version: i64,
+    /// udp, tcp or ws: the path of its last registration, from the PeerMap
+    /// when it registered since start, else as last stored
+    transport: Option<String>,
+    /// Registered and online as usual, but no sessions to or from it
+    quarantined: bool,
+    /// User the peer is assigned to, see PUT /api/peers/:id/user
+    user: Option<String>,
+    /// Custom attributes, in the peer detail only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attributes: Option<crate::attributes::Attributes>,
+    /// Notices the client has not acknowledged yet, in the peer detail only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pending_notices: Option<Vec<hbbs::PeerNotice>>,
+    /// The key a registration replaced, what POST .../rollback-key restores;
+    /// in the peer detail only, with when (RFC3339) and from which ip
+    #[serde(skip_serializing_if = "Option::is_none")]
+    previous_key_fingerprint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key_replaced_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key_replaced_from: Option<String>,
 }
 
 #[derive(Serialize)]
-struct ApiResponse<T> {
+pub(crate) struct ApiResponse<T> {
     success: bool,
     data: Option<T>,
     error: Option<String>,
     timestamp: String,
 }
 
+/// A response annotated with where the online flags came from: "peer_map" (live), or
+/// "database" with `may_be_stale` while the PeerMap is unavailable
+#[derive(Serialize)]
+pub(crate) struct Sourced<T> {
+    #[serde(flatten)]
+    response: ApiResponse<T>,
+    source: &'static str,
+    may_be_stale: bool,
+    #[serde(flatten)]
+    page: Option<Page>,
+}
+
+/// Where a page of a listing starts, and how many entries the whole listing has
+#[derive(Serialize)]
+pub(crate) struct Page {
+    total: i64,
+    limit: u32,
+    offset: u32,
+}
+
+fn sourced<T>(live: &Option<hbbs::PeerMapHandle>, response: ApiResponse<T>) -> Sourced<T> {
+    Sourced {
+        response,
+        page: None,
+        source: if live.is_some() {
+            "peer_map"
+        } else {
+            "database"
+        },
+        may_be_stale: live.is_none(),
+    }
+}
+
+/// A response about one peer, with the peer's version as ETag and in `version`;
+/// a 412 carries the current version to reload from
+#[derive(Serialize)]
+pub(crate) struct Versioned<T> {
+    #[serde(skip)]
+    status: StatusCode,
+    #[serde(flatten)]
+    response: T,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<i64>,
+}
+
+impl<T: Serialize> IntoResponse for Versioned<T> {
+    fn into_response(self) -> Response {
+        let etag = self
+            .version
+            .and_then(|x| HeaderValue::from_str(&crate::peerversion::etag(x)).ok());
+        let mut res = (self.status, Json(self)).into_response();
+        if let Some(etag) = etag {
+            res.headers_mut().insert(axum::http::header::ETAG, etag);
+        }
+        res
+    }
+}
+
+fn versioned<T>(version: Option<i64>, response: T) -> Versioned<T> {
+    Versioned {
+        status: StatusCode::OK,
+        response,
+        version,
+    }
+}
+
+/// 412: the peer was changed since the client read `If-Match`'s version
+fn version_conflict<T>(peer_id: &str, current: i64) -> Versioned<ApiResponse<T>> {
+    Versioned {
+        status: StatusCode::PRECONDITION_FAILED,
+        response: ApiResponse {
+            success: false,
+            data: None,
+            error: Some(format!(
+                "Peer {} was changed meanwhile, its version is now {}",
+                peer_id, current
+            )),
+            timestamp: get_current_timestamp(),
+        },
+        version: Some(current),
+    }
+}
+
 #[derive(Serialize)]
-struct HealthStatus {
+pub(crate) struct HealthStatus {
+    /// starting, running, maintenance or down
     status: String,
+    /// Why the server is down or in maintenance
+    reason: Option<String>,
     uptime_seconds: u64,
     version: String,
+    /// Last memory/database consistency pass, None until the first one ran
+    drift: Option<hbbs::DriftReport>,
+    /// Peer listings have come from the database alone for over a minute
+    peer_map_fallback: bool,
+    /// Last NAT self-check, None until the first one finished or with it off
+    nat: Option<crate::nat::NatReport>,
+    /// Crash report left by the previous run, if it ended in a panic
+    previous_crash: Option<crate::crash::PreviousCrash>,
+    /// Drain in effect, None without one
+    drain: Option<hbbs::Drain>,
+    /// Writability and free space of the database, None before it was opened
+    storage: Option<hbbs::StorageReport>,
+    /// Supervised background loops with their restarts and last panic
+    tasks: Vec<hbbs::TaskStatus>,
+    /// The operator's banner-* settings, None when unset
+    banner: Option<hbbs::Banner>,
 }
 
 #[derive(Deserialize)]
-struct ChangeIdRequest {
-    new_id: String,
+pub(crate) struct LogParams {
+    lines: Option<usize>,
+    level: Option<String>,
+    target: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct RecentLogs {
+    entries: Vec<crate::logs::LogLine>,
+    /// lines lost to the buffer since start because the collector fell behind
+    dropped: u64,
+    ips_redacted: bool,
+}
+
+#[derive(Deserialize)]
+struct VerifyParams {
+    full: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct UptimeParams {
+    from: Option<String>,
+    to: Option<String>,
+    tag: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ProtocolVersionParams {
+    /// Days of persisted counts, today included (default 7, at most 366)
+    pub days: Option<u32>,
+}
+
+/// Persisted messages per client version on one UTC day
+#[derive(Serialize)]
+pub(crate) struct ProtocolVersionDay {
+    day: String,
+    kind: String,
+    transport: String,
+    version: String,
+    count: i64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ProtocolVersionReport {
+    /// The deprecated-versions setting
+    deprecated: Vec<String>,
+    /// Counts since the server started
+    since_start: Vec<hbbs::ProtocolVersionCount>,
+    /// Persisted counts per day, newest first; the last few minutes are only
+    /// in since_start until the next flush
+    days: Vec<ProtocolVersionDay>,
+    /// Peers whose last reported version is deprecated
+    deprecated_peers: Vec<hbbs::DeprecatedVersion>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ActivePeersParams {
+    /// "day" (default) or "month"
+    pub granularity: Option<String>,
+    /// First period, YYYY-MM-DD or YYYY-MM (default: 30 days or 12 months back)
+    pub from: Option<String>,
+    /// Last period, included (default: the current one)
+    pub to: Option<String>,
 }
 
+/// Distinct peers that sent at least one heartbeat in a UTC day or month
 #[derive(Serialize)]
-struct ChangeIdResponse {
+pub(crate) struct ActivePeriod {
+    pub period: String,
+    pub peers: i64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ActivePeersReport {
+    pub granularity: String,
+    pub from: String,
+    pub to: String,
+    /// Every period of the range, oldest first, empty ones included
+    pub periods: Vec<ActivePeriod>,
+    /// Distinct peers over the whole range
+    pub total: i64,
+}
+
+#[derive(Deserialize)]
+struct ChunkParams {
+    n: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct ApproveKeyParams {
+    /// Expected new-key fingerprint, guards against approving a key that was
+    /// replaced after the queue was listed
+    fingerprint: Option<String>,
+}
+
+#[derive(Serialize)]
+struct KeyChange {
+    id: String,
+    old_fingerprint: String,
+    new_fingerprint: String,
+    first_seen: String,
+    last_seen: String,
+    attempts: i64,
+    approved_at: Option<String>,
+}
+
+pub(crate) enum ApproveOutcome {
+    Approved(String),
+    NotFound,
+    AlreadyApproved,
+    FingerprintMismatch(String),
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ChangeIdRequest {
+    pub new_id: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ChangeIdResponse {
     old_id: String,
     new_id: String,
     changed_at: String,
     previous_ids: Vec<String>,
 }
 
-fn verify_api_key(headers: &HeaderMap, state: &ApiState) -> Result<(), StatusCode> {
-    match headers.get("X-API-Key") {
-        Some(key) => {
-            if key.to_str().unwrap_or("") == state.api_key {
-                Ok(())
-            } else {
-                hbb_common::log::warn!("API: Invalid API key");
-                Err(StatusCode::UNAUTHORIZED)
-            }
-        }
-        None => {
-            hbb_common::log::warn!("API: Missing X-API-Key header");
-            Err(StatusCode::UNAUTHORIZED)
-        }
-    }
-}
+/// The peer health counters the io loop logs as "Peer Statistics"
+#[derive(Serialize)]
+struct PeerHealthCounts {
+    /// Peers in memory, timed out ones included
+    total: usize,
+    healthy: usize,
+    /// Online with HEARTBEAT_WARNING_THRESHOLD or more heartbeats missed
+    degraded: usize,
+    /// Online with HEARTBEAT_CRITICAL_THRESHOLD or more heartbeats missed
+    critical: usize,
+}
+
+#[derive(Serialize)]
+struct ServerStats {
+    peers: PeerHealthCounts,
+    /// The heartbeat interval and peer timeout the counters were taken with
+    heartbeat_interval_secs: u64,
+    peer_timeout_secs: u64,
+    relay_reasons: HashMap<String, usize>,
+    malformed_credentials: usize,
+    /// Online peers by the transport of their last registration (udp, tcp, ws)
+    online_by_transport: HashMap<String, usize>,
+    /// Configuration sync per -R peer, in order of preference
+    peer_sync: Vec<crate::peersync::PeerStatus>,
+    /// Drain in effect, None without one
+    drain: Option<hbbs::Drain>,
+    /// Backlog and top writers of the batched status writer
+    status_writer: hbbs::StatusWriterStats,
+    /// The operator's banner-* settings, None when unset
+    banner: Option<hbbs::Banner>,
+    /// Known peers back online since the start, None after the recovery window
+    recovery: Option<hbbs::RecoveryStatus>,
+}
+
+#[derive(Deserialize)]
+struct NetworkParams {
+    top: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct NetworkUsage {
+    #[serde(flatten)]
+    stats: hbbs::NetworkStats,
+    /// Read and written bytes per second, averaged since start
+    avg_bytes_per_sec: u64,
+}
+
+#[derive(Serialize)]
+struct ConnStats {
+    id: String,
+    last_relay_reason: Option<String>,
+    last_relay_from: Option<String>,
+    last_relay_server: Option<String>,
+    last_relay_at: Option<String>,
+    relay_reasons: HashMap<String, usize>,
+}
+
+#[derive(Serialize)]
+struct UuidChurnAnomaly {
+    ip: String,
+    distinct_uuids: usize,
+    ids: Vec<String>,
+    throttled: usize,
+    window_age_secs: u64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct RecentErrorEntry {
+    at: String,
+    id: String,
+    ip: String,
+    reason: String,
+    detail: String,
+}
+
+/// The bearer token crate::oidc::authenticate let through, None for requests
+/// without one (or that did not pass the route layer)
+pub(crate) type Bearer = Option<Extension<crate::oidc::Verified>>;
+
+/// 401 without a valid API key or bearer token, 403 when it lacks `scope`: read
+/// keys and read-only tokens are refused on Role::Admin routes (every one that
+/// changes something)
+fn verify_scope(
+    headers: &HeaderMap,
+    bearer: &Bearer,
+    state: &ApiState,
+    scope: Role,
+) -> Result<(), StatusCode> {
+    // checked (and its role against the method) by crate::oidc::authenticate; a
+    // bearer token never falls back to the API key
+    let role = if crate::oidc::bearer(headers).is_some() {
+        match bearer {
+            Some(Extension(verified)) => verified.role,
+            None => return Err(StatusCode::UNAUTHORIZED),
+        }
+    } else {
+        match headers.get("X-API-Key") {
+            Some(key) => match state.api_key.scope_of(key.to_str().unwrap_or("")) {
+                Some(role) => role,
+                None => {
+                    hbb_common::log::warn!("API: Invalid API key");
+                    return Err(StatusCode::UNAUTHORIZED);
+                }
+            },
+            None => {
+                hbb_common::log::warn!("API: Missing X-API-Key header");
+                return Err(StatusCode::UNAUTHORIZED);
+            }
+        }
+    };
+    if scope == Role::Admin && role != Role::Admin {
+        hbb_common::log::warn!("API: Read-only key or token refused, admin scope required");
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(())
+}
+
+/// The PeerMap if the rendezvous side still shares it; tracks how long the
+/// API has been falling back to the database otherwise
+fn live_peer_map(state: &ApiState) -> Option<hbbs::PeerMapHandle> {
+    // an error means the sender is gone
+    let live = match state.peer_map.has_changed() {
+        Ok(_) => state.peer_map.borrow().clone(),
+        Err(_) => None,
+    };
+    if let Ok(mut since) = state.peer_map_fallback_since.lock() {
+        match (&live, *since) {
+            (Some(_), Some(_)) => {
+                hbb_common::log::info!("API: PeerMap available again");
+                *since = None;
+            }
+            (None, None) => {
+                hbb_common::log::warn!("API: PeerMap unavailable, answering from the database");
+                *since = Some(Instant::now());
+            }
+            _ => {}
+        }
+    }
+    live
+}
+
+fn peer_map_fallback(state: &ApiState) -> bool {
+    live_peer_map(state);
+    state
+        .peer_map_fallback_since
+        .lock()
+        .map(|since| {
+            since.map_or(false, |x| {
+                x.elapsed().as_secs() > PEER_MAP_FALLBACK_HEALTH_SECS
+            })
+        })
+        .unwrap_or(false)
+}
+
+fn get_current_timestamp() -> String {
+    chrono::Utc::now().to_rfc3339()
+}
+
+/// Check if a timestamp string is within the last N seconds (default 60s)
+/// Supports formats: "YYYY-MM-DD HH:MM:SS" (SQLite) and RFC3339, see
+/// `hbbs::parse_last_online`
+fn is_online_recently(timestamp: &Option<String>, timeout_secs: i64) -> bool {
+    match timestamp.as_deref().and_then(hbbs::parse_last_online) {
+        Some(dt) => {
+            let now = chrono::Utc::now().naive_utc();
+            now.signed_duration_since(dt).num_seconds() < timeout_secs
+        }
+        // If we can't parse, assume offline
+        None => false,
+    }
+}
+
+/// Default timeout for online status (60 seconds)
+const ONLINE_TIMEOUT_SECS: i64 = 60;
+
+/// The transport stored with a peer's info
+const TRANSPORT_COLUMN: &str =
+    "CASE WHEN json_valid(info) THEN json_extract(info, '$.transport') END AS transport";
+
+fn is_quarantined(row: &sqlx::sqlite::SqliteRow) -> bool {
+    row.try_get::<Option<i64>, _>("is_quarantined")
+        .unwrap_or_default()
+        == Some(1)
+}
+
+/// Peers per page of `GET /api/peers` without `limit`
+const PEERS_PAGE_DEFAULT: u32 = 100;
+/// Largest `limit` honoured; larger ones are cut down to it
+const PEERS_PAGE_MAX: u32 = 1000;
+
+/// GET /api/peers?attr=key:value (repeatable, all must match)&transport=udp|tcp|ws&limit=&offset=
+///     &online=true|false (anything else is a 400)&sort=id|last_online|created_at&order=asc|desc
+pub(crate) async fn get_online_peers(
+    headers: HeaderMap,
+    bearer: Bearer,
+    params: Query<Vec<(String, String)>>,
+    state: Extension<Arc<ApiState>>,
+) -> Result<Json<Sourced<Vec<PeerStatus>>>, StatusCode> {
+    list_peers(headers, bearer, params, state, Some(PEERS_PAGE_DEFAULT)).await
+}
+
+/// ORDER BY of the peer listing for `sort` and `order`, from this list only so
+/// neither reaches the SQL as given; ties go by id for pages that do not shift
+fn peer_order(sort: &str, order: &str) -> Result<String, String> {
+    let direction = match order {
+        "asc" => "ASC",
+        "desc" => "DESC",
+        other => return Err(format!("Invalid order {}, expected asc or desc", other)),
+    };
+    match sort {
+        "id" => Ok(format!("id {}", direction)),
+        // never online last either way; julianday reads both stored formats
+        "last_online" => Ok(format!(
+            "julianday(last_online) IS NULL, julianday(last_online) {}, id",
+            direction
+        )),
+        "created_at" => Ok(format!("created_at {}, id", direction)),
+        other => Err(format!(
+            "Invalid sort {}, expected id, last_online or created_at",
+            other
+        )),
+    }
+}
+
+/// The peer listing, by id unless `sort` says otherwise; without `limit` and
+/// `default_limit` unpaged
+pub(crate) async fn list_peers(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Query(params): Query<Vec<(String, String)>>,
+    Extension(state): Extension<Arc<ApiState>>,
+    default_limit: Option<u32>,
+) -> Result<Json<Sourced<Vec<PeerStatus>>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::ReadOnly)?;
+    
+    hbb_common::log::debug!("API: Fetching all peers");
+    let live = live_peer_map(&state);
+    let filters: Result<Vec<(String, String)>, String> = params
+        .iter()
+        .filter(|(name, _)| name == "attr")
+        .map(|(_, filter)| crate::attributes::parse_filter(filter))
+        .collect();
+    let param = |wanted: &str| {
+        params
+            .iter()
+            .rev()
+            .find(|(name, _)| name == wanted)
+            .map(|(_, value)| value.as_str())
+    };
+    let transport = param("transport").map(str::to_ascii_lowercase);
+    let online = match param("online") {
+        None => None,
+        Some("true") => Some(true),
+        Some("false") => Some(false),
+        Some(other) => {
+            hbb_common::log::warn!("API: Invalid online {}, expected true or false", other);
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    };
+    let number = |name: &str| match param(name) {
+        None => Ok(None),
+        Some(value) => value
+            .parse::<u32>()
+            .map(Some)
+            .map_err(|_| format!("Invalid {} {}, expected a whole number", name, value)),
+    };
+    let page = match (number("limit"), number("offset")) {
+        (Ok(Some(0)), _) => Err("Invalid limit 0, expected at least 1".to_string()),
+        (Ok(limit), Ok(offset)) => Ok((
+            limit
+                .or(default_limit)
+                .map(|limit| limit.min(PEERS_PAGE_MAX)),
+            offset.unwrap_or(0),
+        )),
+        (Err(e), _) | (_, Err(e)) => Err(e),
+    };
+    let sort = peer_order(
+        param("sort").unwrap_or("id"),
+        param("order").unwrap_or("asc"),
+    );
+    let filters = match (filters, transport.as_deref(), page, sort) {
+        (Err(e), _, _, _) | (_, _, Err(e), _) | (_, _, _, Err(e)) => Err(e),
+        (_, Some(other), _, _) if !["udp", "tcp", "ws"].contains(&other) => Err(format!(
+            "Invalid transport {}, expected udp, tcp or ws",
+            other
+        )),
+        (Ok(filters), _, Ok(page), Ok(sort)) => Ok((filters, page, sort)),
+    };
+    let (filters, (limit, offset), sort) = match filters {
+        Ok(filters) => filters,
+        Err(e) => {
+            return Ok(Json(sourced(
+                &live,
+                ApiResponse {
+                    success: false,
+                    data: None,
+                    error: Some(e),
+                    timestamp: get_current_timestamp(),
+                },
+            )))
+        }
+    };
+    let (online_ids, transports) = match &live {
+        Some(pm) => (Some(pm.online_ids().await), pm.transports().await),
+        None => (None, HashMap::new()),
+    };
+    
+    // the live transport overrides the stored one, so the filter takes the
+    // live peers on it and the stored transport of the others, in SQL for the
+    // page to hold only matching peers
+    let mut clause = crate::attributes::filter_clause(filters.len());
+    let mut binds: Vec<String> = filters
+        .iter()
+        .flat_map(|(key, value)| [key.clone(), value.clone()])
+        .collect();
+    if let Some(transport) = &transport {
+        let on: Vec<&String> = transports
+            .iter()
+            .filter(|(_, live)| **live == transport.as_str())
+            .map(|(id, _)| id)
+            .collect();
+        let known: Vec<&String> = transports.keys().collect();
+        clause.push_str(
+            " AND (id IN (SELECT value FROM json_each(?))
+               OR (id NOT IN (SELECT value FROM json_each(?))
+                   AND CASE WHEN json_valid(info) THEN json_extract(info, '$.transport') END = ?))",
+        );
+        binds.push(serde_json::to_string(&on).unwrap_or_default());
+        binds.push(serde_json::to_string(&known).unwrap_or_default());
+        binds.push(transport.clone());
+    }
+    // online as the listing reports it: in the PeerMap, or else seen within
+    // ONLINE_TIMEOUT_SECS
+    match (online, &online_ids) {
+        (None, _) => {}
+        (Some(online), Some(ids)) => {
+            clause.push_str(if online {
+                " AND id IN (SELECT value FROM json_each(?))"
+            } else {
+                " AND id NOT IN (SELECT value FROM json_each(?))"
+            });
+            binds.push(serde_json::to_string(ids).unwrap_or_default());
+        }
+        (Some(online), None) => {
+            let recent = "coalesce(julianday(last_online) > julianday('now', ?), 0)";
+            clause.push_str(&format!(" AND {} = {}", recent, online as i32));
+            binds.push(format!("-{} seconds", ONLINE_TIMEOUT_SECS));
+        }
+    }
+    let sql = format!(
+        "SELECT id, note, last_online, version, is_quarantined, CAST(user AS TEXT) AS user, {}
+         FROM peer WHERE is_deleted = 0{} ORDER BY {} LIMIT ? OFFSET ?",
+        TRANSPORT_COLUMN, clause, sort
+    );
+    let count_sql = format!("SELECT COUNT(*) FROM peer WHERE is_deleted = 0{}", clause);
+    let mut query = sqlx::query(&sql);
+    let mut count = sqlx::query_scalar::<_, i64>(&count_sql);
+    for bind in &binds {
+        query = query.bind(bind);
+        count = count.bind(bind);
+    }
+    // SQLite reads a negative LIMIT as none
+    let query = query.bind(limit.map_or(-1, i64::from)).bind(offset);
+    let listed = async {
+        let rows = query.fetch_all(&state.read_pool).await?;
+        let total = match limit {
+            Some(_) => Some(count.fetch_one(&state.read_pool).await?),
+            None => None,
+        };
+        Ok::<_, sqlx::Error>((rows, total))
+    };
+    match crate::apistats::query(listed).await {
+        Ok((rows, total)) => {
+            let mut peers: Vec<PeerStatus> = Vec::new();
+            
+            for row in rows.iter() {
+                let id: String = row.get("id");
+                let note: Option<String> = row.get("note");
+                let last_online: Option<String> = row.get("last_online");
+                let online = match &online_ids {
+                    Some(ids) => ids.contains(&id),
+                    None => is_online_recently(&last_online, ONLINE_TIMEOUT_SECS),
+                };
+                let peer_transport = match transports.get(&id) {
+                    Some(live) => Some(live.to_string()),
+                    None => row.get("transport"),
+                };
+                
+                peers.push(PeerStatus {
+                    id,
+                    note,
+                    online,
+                    last_online,
+                    version: row.get("version"),
+                    transport: peer_transport,
+                    quarantined: is_quarantined(row),
+                    user: row.get("user"),
+                    attributes: None,
+                    pending_notices: None,
+                    previous_key_fingerprint: None,
+                    key_replaced_at: None,
+                    key_replaced_from: None,
+                });
+            }
+            
+            hbb_common::log::info!("API: Returned {} peers", peers.len());
+
+            let mut res = sourced(
+                &live,
+                ApiResponse {
+                    success: true,
+                    data: Some(peers),
+                    error: None,
+                    timestamp: get_current_timestamp(),
+                },
+            );
+            res.page = limit.zip(total).map(|(limit, total)| Page {
+                total,
+                limit,
+                offset,
+            });
+            Ok(Json(res))
+        }
+        Err(e) => {
+            hbb_common::log::error!("API: Database query failed: {}", e);
+            Ok(Json(sourced(
+                &live,
+                ApiResponse {
+                    success: false,
+                    data: None,
+                    error: Some(format!("Database error: {}", e)),
+                    timestamp: get_current_timestamp(),
+                },
+            )))
+        }
+    }
+}
+
+/// Bytes of NDJSON collected before they go out as one chunk of the export
+const EXPORT_CHUNK_BYTES: usize = 64 * 1024;
+
+#[derive(Deserialize)]
+pub(crate) struct ExportParams {
+    /// true: only peers under a ban in force, false: only the others
+    pub banned: Option<bool>,
+}
+
+/// One line of GET /api/peers/export
+#[derive(Serialize)]
+struct ExportedPeer {
+    id: String,
+    note: Option<String>,
+    online: bool,
+    last_online: Option<String>,
+    created_at: Option<String>,
+    banned: bool,
+}
+
+/// Every peer as one JSON object per line, in id order, streamed from a
+/// database cursor in chunks of EXPORT_CHUNK_BYTES: memory stays flat however
+/// many peers there are. Online flags as in GET /api/peers.
+/// GET /api/peers/export?banned=true|false
+pub(crate) async fn export_peers(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Query(params): Query<ExportParams>,
+    Extension(state): Extension<Arc<ApiState>>,
+) -> Result<Response, StatusCode> {
+    use axum::body::Bytes;
+    use hbb_common::futures_util::TryStreamExt;
+    verify_scope(&headers, &bearer, &state, Role::ReadOnly)?;
+
+    let live = live_peer_map(&state);
+    let online_ids = match &live {
+        Some(pm) => Some(pm.online_ids().await),
+        None => None,
+    };
+    let filter = match params.banned {
+        Some(true) => format!(" AND {}", BAN_ACTIVE),
+        Some(false) => format!(" AND NOT ({})", BAN_ACTIVE),
+        None => String::new(),
+    };
+    let sql = format!(
+        "SELECT id, note, last_online, datetime(created_at) AS created_at,
+                {} AS banned FROM peer
+         WHERE is_deleted = 0{} ORDER BY id",
+        BAN_ACTIVE, filter
+    );
+    let now = chrono::Utc::now().timestamp();
+    let pool = state.read_pool.clone();
+    // the cursor borrows the pool, so it runs in its own task and hands the
+    // chunks over; the small channel holds it back while the client reads slowly
+    let (tx, rx) = hbb_common::tokio::sync::mpsc::channel::<std::io::Result<Bytes>>(4);
+    hbb_common::tokio::spawn(async move {
+        let mut query = sqlx::query(&sql).bind(now);
+        if params.banned.is_some() {
+            query = query.bind(now);
+        }
+        let mut rows = query.fetch(&pool);
+        let (mut buf, mut exported) = (Vec::with_capacity(EXPORT_CHUNK_BYTES), 0);
+        loop {
+            let row = match rows.try_next().await {
+                Ok(Some(row)) => row,
+                Ok(None) => break,
+                Err(e) => {
+                    hbb_common::log::error!("API: Export failed after {} peers: {}", exported, e);
+                    let e = std::io::Error::new(std::io::ErrorKind::Other, e);
+                    let _ = tx.send(Err(e)).await;
+                    return;
+                }
+            };
+            let id: String = row.get("id");
+            let last_online: Option<String> = row.get("last_online");
+            let peer = ExportedPeer {
+                online: match &online_ids {
+                    Some(ids) => ids.contains(&id),
+                    None => is_online_recently(&last_online, ONLINE_TIMEOUT_SECS),
+                },
+                id,
+                note: row.get("note"),
+                last_online,
+                created_at: row.get("created_at"),
+                banned: row.try_get::<Option<i32>, _>("banned").unwrap_or_default() == Some(1),
+            };
+            if serde_json::to_writer(&mut buf, &peer).is_err() {
+                continue;
+            }
+            buf.push(b'\n');
+            exported += 1;
+            if buf.len() >= EXPORT_CHUNK_BYTES {
+                let chunk = std::mem::replace(&mut buf, Vec::with_capacity(EXPORT_CHUNK_BYTES));
+                if tx.send(Ok(chunk.into())).await.is_err() {
+                    // the client went away
+                    return;
+                }
+            }
+        }
+        if !buf.is_empty() {
+            let _ = tx.send(Ok(buf.into())).await;
+        }
+        hbb_common::log::info!("API: Exported {} peers", exported);
+    });
+    let chunks = hbb_common::futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+    Ok((
+        [(axum::http::header::CONTENT_TYPE, "application/x-ndjson")],
+        axum::body::StreamBody::new(chunks),
+    )
+        .into_response())
+}
+
+/// Liveness for container orchestration: no API key, no database, 503 once a
+/// stop signal arrived
+pub(crate) async fn healthz() -> (StatusCode, &'static str) {
+    if hbbs::stopping() {
+        (StatusCode::SERVICE_UNAVAILABLE, "stopping\n")
+    } else {
+        (StatusCode::OK, "ok\n")
+    }
+}
+
+/// 503 while the udp self-test has not passed yet, or failed
+pub(crate) async fn health_check(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+) -> Result<(StatusCode, Json<ApiResponse<HealthStatus>>), StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::ReadOnly)?;
+    
+    let uptime = state.start_time.elapsed().as_secs();
+    let readiness = state.readiness.borrow().clone();
+    let drain = hbbs::current_drain();
+    let storage = hbbs::storage_report();
+    let storage_problem = storage
+        .as_ref()
+        .and_then(|x| x.problems.first())
+        .map(|x| x.to_string());
+    let (code, status, reason) = match readiness {
+        hbbs::Readiness::Starting => (StatusCode::SERVICE_UNAVAILABLE, "starting", None),
+        // the database became unwritable while running
+        hbbs::Readiness::Ready if storage_problem.is_some() => {
+            (StatusCode::SERVICE_UNAVAILABLE, "down", storage_problem)
+        }
+        // a full drain takes the server out of load balancer rotation
+        hbbs::Readiness::Ready => match &drain {
+            Some(drain) if drain.mode == hbbs::DrainMode::All => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "maintenance",
+                Some(format!("Draining until {}", drain.until)),
+            ),
+            _ => (StatusCode::OK, "running", None),
+        },
+        hbbs::Readiness::Down(reason) => {
+            (StatusCode::SERVICE_UNAVAILABLE, "down", Some(reason))
+        }
+    };
+
+    Ok((
+        code,
+        Json(ApiResponse {
+            success: true,
+            data: Some(HealthStatus {
+                status: status.to_string(),
+                reason,
+                uptime_seconds: uptime,
+                version: "2.0.0".to_string(),
+                drift: hbbs::last_drift_report(),
+                peer_map_fallback: peer_map_fallback(&state),
+                nat: crate::nat::last_report(),
+                previous_crash: crate::crash::previous(),
+                drain,
+                storage,
+                tasks: hbbs::task_statuses(),
+                banner: hbbs::server_banner(),
+            }),
+            error: None,
+            timestamp: get_current_timestamp(),
+        }),
+    ))
+}
+
+pub(crate) async fn get_peer_details(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+    axum::extract::Path(peer_id): axum::extract::Path<String>,
+) -> Result<Versioned<Sourced<PeerStatus>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::ReadOnly)?;
+    let peer_id = hbbs::canonical_id(&peer_id).into_owned();
+    
+    hbb_common::log::debug!("API: Fetching details for peer {}", peer_id);
+    let live = live_peer_map(&state);
+    
+    match peer_details(&state, &live, &peer_id).await {
+        Ok(Some(peer)) => Ok(versioned(
+            Some(peer.version),
+            sourced(
+                &live,
+                ApiResponse {
+                    success: true,
+                    data: Some(peer),
+                    error: None,
+                    timestamp: get_current_timestamp(),
+                },
+            ),
+        )),
+        Ok(None) => Ok(versioned(
+            None,
+            sourced(
+                &live,
+                ApiResponse {
+                    success: false,
+                    data: None,
+                    error: Some(format!("Peer {} not found", peer_id)),
+                    timestamp: get_current_timestamp(),
+                },
+            ),
+        )),
+        Err(e) => {
+            hbb_common::log::error!("API: Database query failed: {}", e);
+            Ok(versioned(
+                None,
+                sourced(
+                    &live,
+                    ApiResponse {
+                        success: false,
+                        data: None,
+                        error: Some(format!("Database error: {}", e)),
+                        timestamp: get_current_timestamp(),
+                    },
+                ),
+            ))
+        }
+    }
+}
+
+/// The peer as GET /api/peers/:id shows it, None when there is no such peer
+async fn peer_details(
+    state: &ApiState,
+    live: &Option<hbbs::PeerMapHandle>,
+    peer_id: &str,
+) -> Result<Option<PeerStatus>, sqlx::Error> {
+    let row = match crate::apistats::query(
+        sqlx::query(&format!(
+            "SELECT id, note, last_online, version, is_quarantined, CAST(user AS TEXT) AS user,
+                    previous_pk, pk_replaced_at, pk_replaced_from, {} FROM peer
+             WHERE id = ? AND is_deleted = 0",
+            TRANSPORT_COLUMN
+        ))
+        .bind(peer_id)
+        .fetch_optional(&state.read_pool),
+    )
+    .await?
+    {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+    let id: String = row.get("id");
+    let note: Option<String> = row.get("note");
+    let last_online: Option<String> = row.get("last_online");
+    let version: i64 = row.get("version");
+    let online = match live {
+        Some(pm) => pm.is_online(&id).await,
+        None => is_online_recently(&last_online, ONLINE_TIMEOUT_SECS),
+    };
+    let transport = match live {
+        Some(pm) => pm.transport(&id).await.map(str::to_owned),
+        None => None,
+    };
+    let transport = transport.or_else(|| row.get("transport"));
+    let quarantined = is_quarantined(&row);
+    let attributes =
+        match crate::apistats::query(crate::attributes::get(&state.read_pool, &id)).await {
+            Ok(attributes) => Some(attributes),
+            Err(e) => {
+                hbb_common::log::warn!("API: Attributes of {} unavailable: {}", id, e);
+                None
+            }
+        };
+    let pending_notices =
+        match crate::apistats::query(crate::notices::pending(&state.read_pool, &id)).await {
+            Ok(notices) => Some(notices),
+            Err(e) => {
+                hbb_common::log::warn!("API: Notices of {} unavailable: {}", id, e);
+                None
+            }
+        };
+    let previous_pk: Option<Vec<u8>> = row.get("previous_pk");
+    let key_replaced_at = row
+        .get::<Option<i64>, _>("pk_replaced_at")
+        .map(unix_to_rfc3339);
+    Ok(Some(PeerStatus {
+        id,
+        note,
+        online,
+        last_online,
+        version,
+        transport,
+        quarantined,
+        user: row.get("user"),
+        attributes,
+        pending_notices,
+        previous_key_fingerprint: previous_pk.map(|pk| hbbs::pk_fingerprint(&pk)),
+        key_replaced_at,
+        key_replaced_from: row.get("pk_replaced_from"),
+    }))
+}
+
+/// Soft-delete a peer: its row is kept with is_deleted = 1 and renamed out of the
+/// way, so a later registration as the id starts over with a new guid; returns
+/// the peer as it was. 404 for an unknown or already deleted id.
+/// DELETE /api/peers/:id
+pub(crate) async fn delete_peer(
+    headers: HeaderMap,
+    bearer: Bearer,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(state): Extension<Arc<ApiState>>,
+    Path(peer_id): Path<String>,
+) -> Result<Versioned<ApiResponse<PeerStatus>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+    let peer_id = hbbs::canonical_id(&peer_id).into_owned();
+    let expected = crate::peerversion::if_match(&headers, state.require_if_match)?;
+    let fail = |status: StatusCode, error: String| Versioned {
+        status,
+        response: ApiResponse {
+            success: false,
+            data: None,
+            error: Some(error),
+            timestamp: get_current_timestamp(),
+        },
+        version: None,
+    };
+
+    let live = live_peer_map(&state);
+    let peer = match peer_details(&state, &live, &peer_id).await {
+        Ok(Some(peer)) => peer,
+        Ok(None) => {
+            let error = format!("Peer '{}' not found", peer_id);
+            return Ok(fail(StatusCode::NOT_FOUND, error));
+        }
+        Err(e) => {
+            hbb_common::log::error!("API: Failed to delete {}: {}", peer_id, e);
+            return Ok(fail(StatusCode::OK, format!("Database error: {}", e)));
+        }
+    };
+    let result = crate::apistats::query(soft_delete(&state.db_pool, &peer_id, expected)).await;
+    match result {
+        Ok(crate::peerversion::Bump::Done(version)) => {
+            let actor = api_actor(&state, &headers, addr);
+            hbb_common::log::info!("API: Deleted {} ({})", peer_id, actor);
+            let now = chrono::Utc::now().timestamp();
+            let audit = append_audit(&state.db_pool, now, &actor, "delete", &peer_id, "").await;
+            if let Err(e) = audit {
+                hbb_common::log::warn!("API: Cannot audit deletion of {}: {}", peer_id, e);
+            }
+            hbbs::emit_event(hbbs::EventKind::Audit {
+                actor,
+                action: "delete",
+                peer_id: peer_id.clone(),
+                detail: String::new(),
+            });
+            hbbs::disconnect_peer(&peer_id, hbbs::DisconnectReason::Deleted, "");
+            // the next registration finds neither the entry nor its guid and is
+            // taken as a new peer
+            if let Some(pm) = &live {
+                pm.evict(&peer_id).await;
+            }
+            hbbs::reset_ban_rejections(&peer_id);
+            // tag rules must not keep matching the id through the deleted row's tags
+            hbbs::invalidate_access_rules();
+            Ok(versioned(
+                Some(version),
+                ApiResponse {
+                    success: true,
+                    data: Some(PeerStatus { version, ..peer }),
+                    error: None,
+                    timestamp: get_current_timestamp(),
+                },
+            ))
+        }
+        Ok(crate::peerversion::Bump::Conflict(current)) => Ok(version_conflict(&peer_id, current)),
+        Ok(crate::peerversion::Bump::NoSuchPeer) => {
+            let error = format!("Peer '{}' not found", peer_id);
+            Ok(fail(StatusCode::NOT_FOUND, error))
+        }
+        Err(e) => {
+            hbb_common::log::error!("API: Failed to delete {}: {}", peer_id, e);
+            Ok(fail(StatusCode::OK, format!("Database error: {}", e)))
+        }
+    }
+}
+
+/// Mark `id` deleted with its version bump, in one transaction. The row is renamed
+/// to `<id>~deleted~<guid>` ('~' is never in a client id) so the unique id index
+/// leaves the id free; attributes stay with the old guid. Notices and a pending
+/// key change of the id go with it, a new registration must not inherit them.
+async fn soft_delete(
+    pool: &SqlitePool,
+    id: &str,
+    expected: Option<i64>,
+) -> Result<crate::peerversion::Bump, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let bumped = crate::peerversion::bump(&mut tx, id, expected).await?;
+    if let crate::peerversion::Bump::Done(_) = bumped {
+        sqlx::query(
+            "UPDATE peer SET is_deleted = 1, status = 0, id = id || '~deleted~' || hex(guid)
+             WHERE id = ? AND is_deleted = 0",
+        )
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("DELETE FROM peer_notices WHERE peer_id = ? OR ack_id = ?")
+            .bind(id)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM pending_key_changes WHERE peer_id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+    }
+    Ok(bumped)
+}
+
+#[derive(Serialize)]
+pub(crate) struct RestoreResponse {
+    id: String,
+    restored_by: String,
+    /// RFC3339
+    restored_at: String,
+    /// hex; the row's own, kept through the delete
+    guid: String,
+    /// base64, as the client registered them, so it reconnects without re-pairing
+    uuid: String,
+    pk: String,
+    /// the peer as GET /api/peers/:id shows it now
+    peer: Option<PeerStatus>,
+}
+
+/// Undo a soft delete: the deleted row gets its id back with its guid, uuid and
+/// key. 404 without a deleted row of the id, 409 (naming the device) when an
+/// active peer has registered as the id since.
+/// POST /api/peers/:id/restore
+pub(crate) async fn restore_peer(
+    headers: HeaderMap,
+    bearer: Bearer,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(state): Extension<Arc<ApiState>>,
+    Path(peer_id): Path<String>,
+) -> Result<Versioned<ApiResponse<RestoreResponse>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+    let peer_id = hbbs::canonical_id(&peer_id).into_owned();
+    let expected = crate::peerversion::if_match(&headers, state.require_if_match)?;
+    let fail = |status: StatusCode, error: String| Versioned {
+        status,
+        response: ApiResponse {
+            success: false,
+            data: None,
+            error: Some(error),
+            timestamp: get_current_timestamp(),
+        },
+        version: None,
+    };
+
+    let result = crate::apistats::query(restore(&state.db_pool, &peer_id, expected)).await;
+    let restored = match result {
+        Ok(Restore::Done(restored)) => restored,
+        Ok(Restore::Taken(guid)) => {
+            let error = format!(
+                "ID '{}' is in use by another active peer (guid {})",
+                peer_id, guid
+            );
+            return Ok(fail(StatusCode::CONFLICT, error));
+        }
+        Ok(Restore::Conflict(current)) => return Ok(version_conflict(&peer_id, current)),
+        Ok(Restore::NoSuchPeer) => {
+            let error = format!("No deleted peer '{}'", peer_id);
+            return Ok(fail(StatusCode::NOT_FOUND, error));
+        }
+        Err(e) => {
+            hbb_common::log::error!("API: Failed to restore {}: {}", peer_id, e);
+            return Ok(fail(StatusCode::OK, format!("Database error: {}", e)));
+        }
+    };
+    let actor = api_actor(&state, &headers, addr);
+    let restored_at = chrono::Utc::now();
+    hbb_common::log::info!("API: Restored {} ({})", peer_id, actor);
+    let guid: String = restored.guid.iter().map(|b| format!("{:02x}", b)).collect();
+    let at = restored_at.timestamp();
+    let audit = append_audit(&state.db_pool, at, &actor, "restore", &peer_id, &guid).await;
+    if let Err(e) = audit {
+        hbb_common::log::warn!("API: Cannot audit restore of {}: {}", peer_id, e);
+    }
+    hbbs::emit_event(hbbs::EventKind::Audit {
+        actor: actor.clone(),
+        action: "restore",
+        peer_id: peer_id.clone(),
+        detail: guid.clone(),
+    });
+    // a registration refused while the id was gone may be in memory; the next
+    // one loads the restored row, whose uuid and key the client still has
+    let live = live_peer_map(&state);
+    if let Some(pm) = &live {
+        pm.evict(&peer_id).await;
+    }
+    hbbs::invalidate_access_rules();
+    let peer = match peer_details(&state, &live, &peer_id).await {
+        Ok(peer) => peer,
+        Err(e) => {
+            hbb_common::log::warn!("API: Cannot read back {}: {}", peer_id, e);
+            None
+        }
+    };
+    Ok(versioned(
+        Some(restored.version),
+        ApiResponse {
+            success: true,
+            data: Some(RestoreResponse {
+                id: peer_id,
+                restored_by: actor,
+                restored_at: restored_at.to_rfc3339(),
+                guid,
+                uuid: base64::encode(&restored.uuid),
+                pk: base64::encode(&restored.pk),
+                peer,
+            }),
+            error: None,
+            timestamp: get_current_timestamp(),
+        },
+    ))
+}
+
+struct Restored {
+    guid: Vec<u8>,
+    uuid: Vec<u8>,
+    pk: Vec<u8>,
+    version: i64,
+}
+
+enum Restore {
+    Done(Restored),
+    /// guid (hex) of the active peer holding the id
+    Taken(String),
+    /// the deleted row's version is not the expected one, but this
+    Conflict(i64),
+    NoSuchPeer,
+}
+
+/// Give the latest deleted row of `id` its id back, in one transaction with the
+/// check that no active peer holds it. Rows deleted through the API carry the
+/// `<id>~deleted~` prefix of soft_delete; rows flagged directly in the database
+/// still have the id itself.
+async fn restore(
+    pool: &SqlitePool,
+    id: &str,
+    expected: Option<i64>,
+) -> Result<Restore, sqlx::Error> {
+    let tombstone = format!("{}~deleted~", id);
+    let mut tx = pool.begin().await?;
+    let deleted = sqlx::query(
+        "SELECT guid, uuid, pk, version FROM peer
+         WHERE is_deleted = 1 AND (id = ? OR substr(id, 1, ?) = ?)
+         ORDER BY coalesce(julianday(last_online), 0) DESC, created_at DESC
+         LIMIT 1",
+    )
+    .bind(id)
+    .bind(tombstone.chars().count() as i64)
+    .bind(&tombstone)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let deleted = match deleted {
+        Some(row) => row,
+        None => return Ok(Restore::NoSuchPeer),
+    };
+    let version: i64 = deleted.get("version");
+    if expected.map_or(false, |x| x != version) {
+        return Ok(Restore::Conflict(version));
+    }
+    let taken = sqlx::query("SELECT hex(guid) AS guid FROM peer WHERE id = ? AND is_deleted = 0")
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await?;
+    if let Some(row) = taken {
+        return Ok(Restore::Taken(row.get::<String, _>("guid").to_lowercase()));
+    }
+    let guid: Vec<u8> = deleted.get("guid");
+    sqlx::query("UPDATE peer SET id = ?, is_deleted = 0, version = version + 1 WHERE guid = ?")
+        .bind(id)
+        .bind(&guid)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(Restore::Done(Restored {
+        guid,
+        uuid: deleted.get("uuid"),
+        pk: deleted.get("pk"),
+        version: version + 1,
+    }))
+}
+
+#[derive(Serialize)]
+pub(crate) struct KeyRollbackResponse {
+    id: String,
+    rolled_back_by: String,
+    /// fingerprint of the key registered again, and of the one dropped, which
+    /// registrations of the id are refused with from now on
+    restored_fingerprint: String,
+    revoked_fingerprint: String,
+    /// the peer as GET /api/peers/:id shows it now
+    peer: Option<PeerStatus>,
+}
+
+/// Undo a key overwrite: the pk/uuid a registration replaced become the peer's
+/// again, the replacing pk is refused from then on and the peer leaves memory,
+/// so its next registration is checked against the restored pair. 409 when no
+/// previous key is kept (one generation only, gone after a rollback).
+/// POST /api/peers/:id/rollback-key
+pub(crate) async fn rollback_peer_key(
+    headers: HeaderMap,
+    bearer: Bearer,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(state): Extension<Arc<ApiState>>,
+    Path(peer_id): Path<String>,
+) -> Result<Versioned<ApiResponse<KeyRollbackResponse>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+    let peer_id = hbbs::canonical_id(&peer_id).into_owned();
+    let expected = crate::peerversion::if_match(&headers, state.require_if_match)?;
+    let fail = |status: StatusCode, error: String| Versioned {
+        status,
+        response: ApiResponse {
+            success: false,
+            data: None,
+            error: Some(error),
+            timestamp: get_current_timestamp(),
+        },
+        version: None,
+    };
+
+    let result = crate::apistats::query(rollback_key(&state.db_pool, &peer_id, expected)).await;
+    let (version, restored, revoked) = match result {
+        Ok(KeyRollback::Done {
+            version,
+            restored,
+            revoked,
+        }) => (version, restored, revoked),
+        Ok(KeyRollback::NoPreviousKey) => {
+            let error = format!("No previous key kept for '{}'", peer_id);
+            return Ok(fail(StatusCode::CONFLICT, error));
+        }
+        Ok(KeyRollback::Conflict(current)) => return Ok(version_conflict(&peer_id, current)),
+        Ok(KeyRollback::NoSuchPeer) => {
+            let error = format!("Peer '{}' not found", peer_id);
+            return Ok(fail(StatusCode::NOT_FOUND, error));
+        }
+        Err(e) => {
+            hbb_common::log::error!("API: Failed to roll back the key of {}: {}", peer_id, e);
+            return Ok(fail(StatusCode::OK, format!("Database error: {}", e)));
+        }
+    };
+    let actor = api_actor(&state, &headers, addr);
+    let detail = format!("{} -> {}", revoked, restored);
+    hbb_common::log::warn!(
+        "API: Rolled back the key of {} {} ({})",
+        peer_id,
+        detail,
+        actor
+    );
+    let at = chrono::Utc::now().timestamp();
+    let audit = append_audit(
+        &state.db_pool,
+        at,
+        &actor,
+        "key_rollback",
+        &peer_id,
+        &detail,
+    )
+    .await;
+    if let Err(e) = audit {
+        hbb_common::log::warn!("API: Cannot audit key rollback of {}: {}", peer_id, e);
+    }
+    hbbs::emit_event(hbbs::EventKind::Audit {
+        actor: actor.clone(),
+        action: "key_rollback",
+        peer_id: peer_id.clone(),
+        detail,
+    });
+    // the PeerMap still holds the dropped key; without the entry the next
+    // registration loads the restored pair from the database
+    let live = live_peer_map(&state);
+    if let Some(pm) = &live {
+        pm.evict(&peer_id).await;
+    }
+    let peer = match peer_details(&state, &live, &peer_id).await {
+        Ok(peer) => peer,
+        Err(e) => {
+            hbb_common::log::warn!("API: Cannot read back {}: {}", peer_id, e);
+            None
+        }
+    };
+    Ok(versioned(
+        Some(version),
+        ApiResponse {
+            success: true,
+            data: Some(KeyRollbackResponse {
+                id: peer_id,
+                rolled_back_by: actor,
+                restored_fingerprint: restored,
+                revoked_fingerprint: revoked,
+                peer,
+            }),
+            error: None,
+            timestamp: get_current_timestamp(),
+        },
+    ))
+}
+
+enum KeyRollback {
+    /// fingerprints of the restored and the revoked key
+    Done {
+        version: i64,
+        restored: String,
+        revoked: String,
+    },
+    NoPreviousKey,
+    /// the version is no longer the expected one, but this
+    Conflict(i64),
+    NoSuchPeer,
+}
+
+/// Swap the previous pk/uuid of `id` back in, in one transaction with the
+/// version bump, keeping the current pk as revoked_pk
+async fn rollback_key(
+    pool: &SqlitePool,
+    id: &str,
+    expected: Option<i64>,
+) -> Result<KeyRollback, sqlx::Error> {
+    use crate::peerversion::Bump;
+    let mut tx = pool.begin().await?;
+    let version = match crate::peerversion::bump(&mut tx, id, expected).await? {
+        Bump::Done(version) => version,
+        Bump::Conflict(current) => return Ok(KeyRollback::Conflict(current)),
+        Bump::NoSuchPeer => return Ok(KeyRollback::NoSuchPeer),
+    };
+    let row = sqlx::query(
+        "UPDATE peer SET revoked_pk = pk, pk = previous_pk, uuid = previous_uuid,
+             previous_pk = NULL, previous_uuid = NULL, pk_replaced_at = NULL,
+             pk_replaced_from = NULL
+         WHERE id = ? AND is_deleted = 0 AND previous_pk IS NOT NULL
+         RETURNING pk, revoked_pk",
+    )
+    .bind(id)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let row = match row {
+        Some(row) => row,
+        // dropping tx rolls the bump back
+        None => return Ok(KeyRollback::NoPreviousKey),
+    };
+    tx.commit().await?;
+    Ok(KeyRollback::Done {
+        version,
+        restored: hbbs::pk_fingerprint(&row.get::<Vec<u8>, _>("pk")),
+        revoked: hbbs::pk_fingerprint(&row.get::<Vec<u8>, _>("revoked_pk")),
+    })
+}
+
+/// Merge custom attributes into a peer's; a null value removes the key
+/// PUT /api/peers/:id/attributes
+/// Body: { "owner": "alice@example.com", "asset_tag": null }
+pub(crate) async fn put_peer_attributes(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+    Path(peer_id): Path<String>,
+    Json(changes): Json<std::collections::BTreeMap<String, Option<String>>>,
+) -> Result<Versioned<ApiResponse<crate::attributes::Attributes>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+    let peer_id = hbbs::canonical_id(&peer_id).into_owned();
+    let expected = crate::peerversion::if_match(&headers, state.require_if_match)?;
+
+    attributes_response(
+        &peer_id,
+        crate::apistats::query(crate::attributes::merge(
+            &state.db_pool,
+            &peer_id,
+            &changes,
+            expected,
+        ))
+        .await,
+    )
+}
+
+/// DELETE /api/peers/:id/attributes/:key
+pub(crate) async fn delete_peer_attribute(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+    Path((peer_id, key)): Path<(String, String)>,
+) -> Result<Versioned<ApiResponse<crate::attributes::Attributes>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+    let peer_id = hbbs::canonical_id(&peer_id).into_owned();
+    let expected = crate::peerversion::if_match(&headers, state.require_if_match)?;
+
+    let removed = crate::attributes::remove(&state.db_pool, &peer_id, &key, expected);
+    match crate::apistats::query(removed).await {
+        Ok(Some(res)) => attributes_response(&peer_id, Ok(res)),
+        Ok(None) => attributes_response(
+            &peer_id,
+            Err(crate::attributes::AttributeError::Invalid(format!(
+                "Peer {} has no attribute {:?}",
+                peer_id, key
+            ))),
+        ),
+        Err(e) => attributes_response(&peer_id, Err(e)),
+    }
+}
+
+fn attributes_response(
+    peer_id: &str,
+    res: Result<(crate::attributes::Attributes, i64), crate::attributes::AttributeError>,
+) -> Result<Versioned<ApiResponse<crate::attributes::Attributes>>, StatusCode> {
+    let (data, version, error) = match res {
+        Ok((attributes, version)) => (Some(attributes), Some(version), None),
+        Err(crate::attributes::AttributeError::Conflict(current)) => {
+            return Ok(version_conflict(peer_id, current))
+        }
+        Err(e) => {
+            if let crate::attributes::AttributeError::Database(e) = &e {
+                hbb_common::log::error!("API: Attribute update failed: {}", e);
+            }
+            (None, None, Some(e.to_string()))
+        }
+    };
+    Ok(versioned(
+        version,
+        ApiResponse {
+            success: error.is_none(),
+            data,
+            error,
+            timestamp: get_current_timestamp(),
+        },
+    ))
+}
+
+/// Change peer ID (admin endpoint)
+/// POST /api/peers/:id/change-id
+/// Body: { "new_id": "NEW123456" }
+pub(crate) async fn change_peer_id(
+    headers: HeaderMap,
+    bearer: Bearer,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(state): Extension<Arc<ApiState>>,
+    Path(old_id): Path<String>,
+    Json(payload): Json<ChangeIdRequest>,
+) -> Result<Versioned<ApiResponse<ChangeIdResponse>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+    let expected = crate::peerversion::if_match(&headers, state.require_if_match)?;
+    
+    let new_id = hbbs::canonical_id(&payload.new_id).into_owned();
+    let old_id = hbbs::canonical_id(&old_id).into_owned();
+    
+    hbb_common::log::info!("API: Change ID request: {} -> {}", old_id, new_id);
+    
+    // Validate new ID format (6-16 chars, alphanumeric/dash/underscore)
+    if new_id.len() < 6 || new_id.len() > 16 {
+        return Ok(versioned(
+            None,
+            ApiResponse {
+                success: false,
+                data: None,
+                error: Some("New ID must be 6-16 characters".to_string()),
+                timestamp: get_current_timestamp(),
+            },
+        ));
+    }
+
+    if !new_id
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+    {
+        return Ok(versioned(
+            None,
+            ApiResponse {
+                success: false,
+                data: None,
+                error: Some(
+                    "New ID can only contain letters, numbers, dash and underscore".to_string(),
+                ),
+                timestamp: get_current_timestamp(),
+            },
+        ));
+    }
+    
+    // Check if old_id exists
+    let old_peer = crate::apistats::query(
+        sqlx::query("SELECT previous_ids FROM peer WHERE id = ? AND is_deleted = 0")
+            .bind(&old_id)
+            .fetch_optional(&state.db_pool),
+    )
+    .await;
+    
+    let old_row = match old_peer {
+        Ok(Some(row)) => row,
+        Ok(None) => {
+            return Ok(versioned(
+                None,
+                ApiResponse {
+                    success: false,
+                    data: None,
+                    error: Some(format!("Peer '{}' not found", old_id)),
+                    timestamp: get_current_timestamp(),
+                },
+            ));
+        }
+        Err(e) => {
+            return Ok(versioned(
+                None,
+                ApiResponse {
+                    success: false,
+                    data: None,
+                    error: Some(format!("Database error: {}", e)),
+                    timestamp: get_current_timestamp(),
+                },
+            ));
+        }
+    };
+    
+    // Check if new_id already exists
+    let new_exists = crate::apistats::query(
+        sqlx::query("SELECT 1 FROM peer WHERE id = ? AND is_deleted = 0")
+            .bind(&new_id)
+            .fetch_optional(&state.db_pool),
+    )
+    .await;
+    
+    if let Ok(Some(_)) = new_exists {
+        return Ok(versioned(
+            None,
+            ApiResponse {
+                success: false,
+                data: None,
+                error: Some(format!("ID '{}' is already in use", new_id)),
+                timestamp: get_current_timestamp(),
+            },
+        ));
+    }
+    
+    // Get and update previous_ids
+    let previous_ids_str: String = old_row.try_get("previous_ids").unwrap_or_default();
+    let actor = api_actor(&state, &headers, addr);
+    let updated_history = hbbs::append_id_history(
+        &previous_ids_str,
+        hbbs::IdHistoryEntry::new(&old_id, hbbs::IdChangeVia::Api, actor),
+    );
+    let previous_ids: Vec<String> = hbbs::parse_id_history(&updated_history)
+        .into_iter()
+        .map(|x| x.id)
+        .collect();
+    
+    let now = get_current_timestamp();
+    
+    // Perform the update, with the version bump
+    let result = crate::apistats::query(crate::peerversion::change(
+        &state.db_pool,
+        &old_id,
+        expected,
+        sqlx::query("UPDATE peer SET id = ?, previous_ids = ?, id_changed_at = ? WHERE id = ?")
+            .bind(&new_id)
+            .bind(&updated_history)
+            .bind(&now)
+            .bind(&old_id),
+    ))
+    .await;
+    
+    match result {
+        Ok(crate::peerversion::Bump::Done(version)) => {
+            hbb_common::log::info!("API: ID changed successfully: {} -> {}", old_id, new_id);
+            hbbs::emit_event(hbbs::EventKind::Rename {
+                old_id: old_id.clone(),
+                new_id: new_id.clone(),
+                via: hbbs::IdChangeVia::Api,
+                actor: api_actor(&state, &headers, addr),
+            });
+            // Websocket-connected peers learn about it right away instead of failing their next registration
+            hbbs::disconnect_peer(&old_id, hbbs::DisconnectReason::IdChanged, &new_id);
+            // rules naming either id by tag follow the peer
+            hbbs::invalidate_access_rules();
+            // The client still registers as the old id until it learns the new one
+            let detail = serde_json::json!({ "old_id": old_id, "new_id": new_id });
+            if let Err(e) = crate::notices::queue(
+                &state.db_pool,
+                &old_id,
+                &new_id,
+                crate::notices::RENAME,
+                detail,
+            )
+            .await
+            {
+                hbb_common::log::warn!("API: Cannot queue rename notice for {}: {}", old_id, e);
+            }
+            Ok(versioned(
+                Some(version),
+                ApiResponse {
+                    success: true,
+                    data: Some(ChangeIdResponse {
+                        old_id,
+                        new_id,
+                        changed_at: now,
+                        previous_ids,
+                    }),
+                    error: None,
+                    timestamp: get_current_timestamp(),
+                },
+            ))
+        }
+        Ok(crate::peerversion::Bump::Conflict(current)) => Ok(version_conflict(&old_id, current)),
+        Ok(crate::peerversion::Bump::NoSuchPeer) => Ok(versioned(
+            None,
+            ApiResponse {
+                success: false,
+                data: None,
+                error: Some(format!("Peer '{}' not found", old_id)),
+                timestamp: get_current_timestamp(),
+            },
+        )),
+        Err(e) => {
+            hbb_common::log::error!("API: Failed to change ID: {}", e);
+            Ok(versioned(
+                None,
+                ApiResponse {
+                    success: false,
+                    data: None,
+                    error: Some(format!("Failed to change ID: {}", e)),
+                    timestamp: get_current_timestamp(),
+                },
+            ))
+        }
+    }
+}
+
+/// Append to audit_log, hash-chained with AUDIT_CHAIN=Y
+async fn append_audit(
+    pool: &SqlitePool,
+    at: i64,
+    actor: &str,
+    action: &str,
+    peer_id: &str,
+    detail: &str,
+) -> Result<(), sqlx::Error> {
+    let mut conn = pool.acquire().await?;
+    hbbs::insert_audit(&mut conn, at, actor, action, peer_id, detail).await
+}
+
+/// Who made an API change: the API key it came with (as a fingerprint, never the
+/// key) and source IP; the primary key's fingerprint for bearer tokens
+fn api_actor(state: &ApiState, headers: &HeaderMap, addr: SocketAddr) -> String {
+    let key = match headers.get("X-API-Key").and_then(|x| x.to_str().ok()) {
+        Some(key) if crate::oidc::bearer(headers).is_none() => key.to_owned(),
+        _ => state.api_key.current(),
+    };
+    format!(
+        "key:{} ip:{}",
+        &hbbs::pk_fingerprint(key.as_bytes())[..12],
+        addr.ip()
+    )
+}
+
+/// Id changes of a peer, oldest first; entries from before the history recorded
+/// who made the change only carry the id
+/// GET /api/peers/:id/history
+async fn get_peer_history(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+    Path(peer_id): Path<String>,
+) -> Result<Json<ApiResponse<Vec<hbbs::IdHistoryEntry>>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::ReadOnly)?;
+    let peer_id = hbbs::canonical_id(&peer_id).into_owned();
+
+    let row = crate::apistats::query(
+        sqlx::query("SELECT previous_ids FROM peer WHERE id = ? AND is_deleted = 0")
+            .bind(&peer_id)
+            .fetch_optional(&state.read_pool),
+    )
+    .await;
+    match row {
+        Ok(Some(row)) => {
+            let raw: Option<String> = row.try_get("previous_ids").unwrap_or_default();
+            Ok(Json(ApiResponse {
+                success: true,
+                data: Some(hbbs::parse_id_history(&raw.unwrap_or_default())),
+                error: None,
+                timestamp: get_current_timestamp(),
+            }))
+        }
+        Ok(None) => Ok(Json(ApiResponse {
+            success: false,
+            data: None,
+            error: Some(format!("Peer '{}' not found", peer_id)),
+            timestamp: get_current_timestamp(),
+        })),
+        Err(e) => {
+            hbb_common::log::error!("API: Database query failed: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct PeerRuntime {
+    id: String,
+    /// false when the peer has no entry in the live PeerMap (offline or never seen this run)
+    in_memory: bool,
+    /// null when answering from the database, the timers only exist in memory
+    timers: Option<hbbs::PeerTimers>,
+    banned: bool,
+    /// time left of a temporary ban, null for a permanent one or none
+    ban_remaining_secs: Option<u64>,
+}
+
+/// Deadlines support gets asked about, recomputed on every request
+/// GET /api/peers/:id/runtime
+pub(crate) async fn get_peer_runtime(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+    Path(peer_id): Path<String>,
+) -> Result<Json<Sourced<PeerRuntime>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::ReadOnly)?;
+    let peer_id = hbbs::canonical_id(&peer_id).into_owned();
+
+    let live = live_peer_map(&state);
+    let now = chrono::Utc::now().timestamp();
+    let row = crate::apistats::query(
+        sqlx::query(&format!(
+            "SELECT id, {} AS banned, banned_until FROM peer WHERE id = ? AND is_deleted = 0",
+            BAN_ACTIVE
+        ))
+        .bind(now)
+        .bind(&peer_id)
+        .fetch_optional(&state.read_pool),
+    )
+    .await;
+    let response = match row {
+        Ok(Some(row)) => {
+            let id: String = row.get("id");
+            let banned = row.try_get::<Option<i32>, _>("banned").unwrap_or_default() == Some(1);
+            let ban_remaining_secs = row
+                .try_get::<Option<i64>, _>("banned_until")
+                .unwrap_or_default()
+                .filter(|_| banned)
+                .map(|until| (until - now).max(0) as u64);
+            let (in_memory, timers) = match &live {
+                Some(pm) => (pm.is_known(&id).await, Some(pm.timers(&id).await)),
+                None => (false, None),
+            };
+            ApiResponse {
+                success: true,
+                data: Some(PeerRuntime {
+                    id,
+                    in_memory,
+                    timers,
+                    banned,
+                    ban_remaining_secs,
+                }),
+                error: None,
+                timestamp: get_current_timestamp(),
+            }
+        }
+        Ok(None) => ApiResponse {
+            success: false,
+            data: None,
+            error: Some(format!("Peer '{}' not found", peer_id)),
+            timestamp: get_current_timestamp(),
+        },
+        Err(e) => {
+            hbb_common::log::error!("API: Database query failed: {}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    Ok(Json(sourced(&live, response)))
+}
+
+/// Prometheus exposition (authenticated like the rest of the API)
+/// GET /metrics
+pub(crate) async fn get_metrics(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+) -> Result<([(axum::http::header::HeaderName, &'static str); 1], String), StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::ReadOnly)?;
+    Ok((
+        [(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        hbbs::render_metrics()
+            + &crate::sync::render_metrics()
+            + &crate::apistats::render_metrics()
+            + &crate::eventlog::render_metrics()
+            + &crate::oidc::render_metrics()
+            + &crate::ratelimit::render_metrics()
+            + &crate::healthscore::render_metrics()
+            + &crate::webhook::render_metrics(),
+    ))
+}
+
+fn sync_status(e: crate::sync::SyncError) -> StatusCode {
+    use crate::sync::SyncError::*;
+    match e {
+        Busy => StatusCode::TOO_MANY_REQUESTS,
+        UnknownToken | NoSuchChunk => StatusCode::NOT_FOUND,
+        Expired => StatusCode::GONE,
+        Database(e) => {
+            hbb_common::log::error!("API: Sync query failed: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// Snapshot the peer table for chunked export; 429 when too many tokens are held
+/// POST /api/sync/start
+async fn sync_start(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+) -> Result<Json<ApiResponse<crate::sync::SyncStart>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+
+    let started = crate::apistats::query(crate::sync::start(&state.db_pool))
+        .await
+        .map_err(sync_status)?;
+    Ok(Json(ApiResponse {
+        success: true,
+        data: Some(started),
+        error: None,
+        timestamp: get_current_timestamp(),
+    }))
+}
+
+/// NDJSON chunk of a snapshot, identical for every request with the same token; 410 once expired
+/// GET /api/sync/:token/chunk?n=0
+async fn sync_chunk(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+    Path(token): Path<String>,
+    Query(params): Query<ChunkParams>,
+) -> Result<([(axum::http::header::HeaderName, &'static str); 1], String), StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::ReadOnly)?;
+
+    let body = crate::apistats::query(crate::sync::chunk(
+        &state.db_pool,
+        &state.read_pool,
+        &token,
+        params.n.unwrap_or(0),
+    ))
+    .await
+    .map_err(sync_status)?;
+    Ok((
+        [(axum::http::header::CONTENT_TYPE, "application/x-ndjson")],
+        body,
+    ))
+}
+
+/// DELETE /api/sync/:token
+async fn sync_release(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+    Path(token): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+
+    crate::apistats::query(crate::sync::release(&state.db_pool, &token))
+        .await
+        .map_err(sync_status)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Settings in effect (key as fingerprint), including reloaded ones such as relay-mode
+/// GET /api/server/config
+async fn get_server_config(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+) -> Result<Json<ApiResponse<Vec<hbbs::ConfigField>>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::ReadOnly)?;
+
+    let data = hbbs::server_config();
+    Ok(Json(ApiResponse {
+        success: data.is_some(),
+        error: match data {
+            Some(_) => None,
+            None => Some("Rendezvous server is not running".to_string()),
+        },
+        data,
+        timestamp: get_current_timestamp(),
+    }))
+}
+
+/// Configured relays with their last check result
+#[derive(Serialize)]
+pub struct RelayServerList {
+    mode: &'static str,
+    /// "live" once this run has checked the relays, "seeded" while the state is the
+    /// snapshot persisted by the previous run
+    source: &'static str,
+    relays: Vec<hbbs::RelayStatus>,
+    /// relay-bindings with what a session to a peer with each tag gets now
+    bindings: Vec<hbbs::RelayBindingStatus>,
+    /// What a bound tag gets while all its relays are down: shared or fail
+    binding_fallback: &'static str,
+}
+
+/// GET /api/relay-servers
+async fn get_relay_servers(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+) -> Result<Json<ApiResponse<RelayServerList>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::ReadOnly)?;
+
+    let (live, relays) = hbbs::relay_servers_status();
+    Ok(Json(ApiResponse {
+        success: true,
+        data: Some(RelayServerList {
+            mode: hbbs::relay_mode().as_str(),
+            source: if live { "live" } else { "seeded" },
+            relays,
+            bindings: hbbs::relay_bindings_status(),
+            binding_fallback: hbbs::relay_bindings().fallback.as_str(),
+        }),
+        error: None,
+        timestamp: get_current_timestamp(),
+    }))
+}
+
+/// Re-read the config file; returns what changed and whether it applied live or needs a restart
+/// POST /api/server/reload
+async fn server_reload(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+) -> Result<Json<ApiResponse<hbbs::ReloadReport>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+
+    hbb_common::log::info!("API: Config reload requested");
+    let (data, error) = match hbbs::request_reload("api").await {
+        Some(Ok(report)) => (Some(report), None),
+        Some(Err(e)) => (None, Some(format!("Reload failed: {}", e))),
+        None => (None, Some("Rendezvous server is not running".to_string())),
+    };
+    Ok(Json(ApiResponse {
+        success: data.is_some(),
+        data,
+        error,
+        timestamp: get_current_timestamp(),
+    }))
+}
+
+/// The configuration serial pushed to clients and how far it has spread among
+/// the peers that registered since start
+/// GET /api/server/serial
+pub(crate) async fn get_server_serial(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+) -> Result<Json<ApiResponse<hbbs::SerialAdoption>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::ReadOnly)?;
+
+    let (data, error) = match live_peer_map(&state) {
+        Some(pm) => (
+            Some(hbbs::serial_adoption(
+                hbbs::current_serial(),
+                pm.serials().await,
+            )),
+            None,
+        ),
+        None => (None, Some("Rendezvous server is not running".to_string())),
+    };
+    Ok(Json(ApiResponse {
+        success: data.is_some(),
+        data,
+        error,
+        timestamp: get_current_timestamp(),
+    }))
+}
+
+#[derive(Deserialize, Default)]
+pub struct SerialRequest {
+    /// Must be above the current serial; the next one without it
+    pub serial: Option<i32>,
+}
+
+/// Raise the serial so that clients registering with an older one are sent a
+/// ConfigureUpdate (rendezvous servers); not persisted, pass -s to keep it
+/// POST /api/server/serial
+/// Body: { "serial": 5 } (optional)
+pub(crate) async fn bump_server_serial(
+    headers: HeaderMap,
+    bearer: Bearer,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(state): Extension<Arc<ApiState>>,
+    payload: Option<Json<SerialRequest>>,
+) -> Result<Json<ApiResponse<hbbs::SerialBump>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+
+    let serial = payload.map(|Json(p)| p).unwrap_or_default().serial;
+    let actor = api_actor(&state, &headers, addr);
+    let (data, error) = match hbbs::request_serial(serial, actor).await {
+        Some(Ok(bump)) => (Some(bump), None),
+        Some(Err(e)) => (None, Some(e)),
+        None => (None, Some("Rendezvous server is not running".to_string())),
+    };
+    Ok(Json(ApiResponse {
+        success: data.is_some(),
+        data,
+        error,
+        timestamp: get_current_timestamp(),
+    }))
+}
+
+/// Longest drain; one left behind reverts within a day
+const MAX_DRAIN_SECS: i64 = 86_400;
+
+#[derive(Deserialize)]
+pub(crate) struct DrainRequest {
+    /// "registrations", "punch", "all", or "none" to end the drain in effect
+    pub mode: String,
+    /// Deadline in seconds from now...
+    pub seconds: Option<i64>,
+    /// ...or as unix seconds
+    pub until: Option<i64>,
+}
+
+/// Turn away new registrations and/or session brokering until a deadline, for
+/// debugging and blue/green cutover; in memory only, a restart ends it. Answers
+/// the drain in effect afterwards.
+/// POST /api/admin/drain
+/// Body: { "mode": "registrations", "seconds": 600 }
+pub(crate) async fn admin_drain(
+    headers: HeaderMap,
+    bearer: Bearer,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(state): Extension<Arc<ApiState>>,
+    Json(req): Json<DrainRequest>,
+) -> Result<Json<ApiResponse<Option<hbbs::Drain>>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+
+    let by = api_actor(&state, &headers, addr);
+    let now = chrono::Utc::now().timestamp();
+    let until = match (req.seconds, req.until) {
+        (Some(seconds), None) => Some(now.saturating_add(seconds)),
+        (None, until) => until,
+        (Some(_), Some(_)) => None,
+    };
+    let checked = match (hbbs::DrainMode::parse(&req.mode), until) {
+        _ if req.mode == "none" => Ok(None),
+        (None, _) => Err(format!("Unknown drain mode: {}", req.mode)),
+        (Some(_), None) => Err("Give the deadline as either seconds or until".to_string()),
+        (Some(_), Some(until)) if until <= now => Err("The deadline is in the past".to_string()),
+        (Some(_), Some(until)) if until - now > MAX_DRAIN_SECS => {
+            Err(format!("A drain lasts at most {} seconds", MAX_DRAIN_SECS))
+        }
+        (Some(mode), Some(until)) => Ok(Some((mode, until))),
+    };
+    let (data, error) = match checked {
+        Ok(Some((mode, until))) => {
+            hbbs::emit_event(hbbs::EventKind::Audit {
+                actor: by.clone(),
+                action: "drain",
+                peer_id: String::new(),
+                detail: format!("{} until {}", mode.as_str(), until),
+            });
+            (Some(Some(hbbs::start_drain(mode, until, by))), None)
+        }
+        Ok(None) => {
+            if hbbs::end_drain(&by).is_some() {
+                hbbs::emit_event(hbbs::EventKind::Audit {
+                    actor: by,
+                    action: "drain",
+                    peer_id: String::new(),
+                    detail: "none".to_owned(),
+                });
+            }
+            (Some(None), None)
+        }
+        Err(problem) => (None, Some(problem)),
+    };
+    Ok(Json(ApiResponse {
+        success: data.is_some(),
+        data,
+        error,
+        timestamp: get_current_timestamp(),
+    }))
+}
+
+/// POST /api/keys/rotate
+#[derive(Serialize, Debug)]
+pub(crate) struct RotatedKey {
+    /// Answered this once; afterwards only the key file has it
+    pub key: String,
+    pub key_file: String,
+    /// Until when the replaced key is still accepted (API_KEY_GRACE_SECS)
+    pub previous_valid_until: String,
+}
+
+/// Issue a new API key: written to the key file (0600, replaced atomically)
+/// before it takes over, with the replaced one accepted for API_KEY_GRACE_SECS
+/// more; logged and audited as key_rotate with the replaced key's fingerprint
+/// POST /api/keys/rotate
+pub(crate) async fn rotate_api_key(
+    headers: HeaderMap,
+    bearer: Bearer,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(state): Extension<Arc<ApiState>>,
+) -> Result<Response, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+
+    // the fingerprint of the key being replaced
+    let actor = api_actor(&state, &headers, addr);
+    let key_file = get_api_key_path();
+    let grace = crate::apikey::grace();
+    let (data, error) = match state.api_key.rotate(&key_file, grace) {
+        Ok(key) => {
+            let until = chrono::Utc::now()
+                + chrono::Duration::from_std(grace).unwrap_or_else(|_| chrono::Duration::zero());
+            hbb_common::log::warn!(
+                "API: Key rotated ({}), written to {}, the previous key is accepted for {}s",
+                actor,
+                key_file,
+                grace.as_secs()
+            );
+            let detail = format!("grace {}s", grace.as_secs());
+            let at = chrono::Utc::now().timestamp();
+            let audit = append_audit(&state.db_pool, at, &actor, "key_rotate", "", &detail).await;
+            if let Err(e) = audit {
+                hbb_common::log::warn!("API: Cannot audit the key rotation: {}", e);
+            }
+            hbbs::emit_event(hbbs::EventKind::Audit {
+                actor,
+                action: "key_rotate",
+                peer_id: String::new(),
+                detail,
+            });
+            let data = RotatedKey {
+                key,
+                key_file,
+                previous_valid_until: until.to_rfc3339(),
+            };
+            (Some(data), None)
+        }
+        Err(e) => {
+            hbb_common::log::error!("API: Key not rotated, cannot write {}: {}", key_file, e);
+            let error = format!("Key not rotated, cannot write {}: {}", key_file, e);
+            (None, Some(error))
+        }
+    };
+    let response = Json(ApiResponse {
+        success: data.is_some(),
+        data,
+        error,
+        timestamp: get_current_timestamp(),
+    });
+    Ok(([(axum::http::header::CACHE_CONTROL, "no-store")], response).into_response())
+}
+
+/// Run a memory/database consistency pass now (sampled, or the whole table with full=true)
+/// POST /api/admin/verify?full=true
+async fn admin_verify(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+    Query(params): Query<VerifyParams>,
+) -> Result<Json<ApiResponse<hbbs::DriftReport>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+
+    let full = params.full.unwrap_or(false);
+    hbb_common::log::info!("API: Consistency check requested (full={})", full);
+
+    match hbbs::request_verify(full).await {
+        Some(report) => Ok(Json(ApiResponse {
+            success: true,
+            data: Some(report),
+            error: None,
+            timestamp: get_current_timestamp(),
+        })),
+        None => Ok(Json(ApiResponse {
+            success: false,
+            data: None,
+            error: Some("Rendezvous server is not running".to_string()),
+            timestamp: get_current_timestamp(),
+        })),
+    }
+}
+
+/// Merge peer rows sharing an id (see hbbs::merge_duplicate_peers); the same
+/// pass runs at startup
+/// POST /api/admin/dedupe
+pub(crate) async fn admin_dedupe(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+) -> Result<Json<ApiResponse<Vec<hbbs::PeerMerge>>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+
+    let merged = crate::apistats::query(async {
+        let mut conn = state.db_pool.acquire().await?;
+        hbbs::merge_duplicate_peers(&mut conn, "api").await
+    })
+    .await;
+    let (data, error) = match merged {
+        Ok(merges) => {
+            hbb_common::log::info!("API: Dedupe merged {} peers", merges.len());
+            if let Some(pm) = live_peer_map(&state) {
+                for merge in &merges {
+                    pm.set_guid(&merge.id, merge.guid.clone()).await;
+                }
+            }
+            (Some(merges), None)
+        }
+        Err(e) => {
+            hbb_common::log::error!("API: Dedupe failed: {}", e);
+            (None, Some(format!("Database error: {}", e)))
+        }
+    };
+    Ok(Json(ApiResponse {
+        success: data.is_some(),
+        data,
+        error,
+        timestamp: get_current_timestamp(),
+    }))
+}
+
+#[derive(Serialize)]
+pub(crate) struct AuditVerifyResponse {
+    /// AUDIT_CHAIN=Y; without it new rows are written unchained
+    chain_enabled: bool,
+    anchor_file: String,
+    /// no broken link and no anchor past the end of the chain
+    intact: bool,
+    #[serde(flatten)]
+    verification: hbbs::AuditVerification,
+}
+
+/// Walk the hash chain of audit_log and report its first broken link, checking
+/// the rows anchored in the anchor file on the way
+/// GET /api/audit/verify
+pub(crate) async fn audit_verify(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+) -> Result<Json<ApiResponse<AuditVerifyResponse>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::ReadOnly)?;
+
+    let verified = crate::apistats::query(async {
+        let mut conn = state.read_pool.acquire().await?;
+        // the anchor file defaults to the directory of the database the pool has open
+        let db_file: String =
+            sqlx::query("SELECT file FROM pragma_database_list WHERE name = 'main'")
+                .fetch_one(&mut *conn)
+                .await?
+                .get("file");
+        let anchor_file = hbbs::audit_anchor_file(&db_file);
+        let anchors = hbbs::read_audit_anchors(&anchor_file);
+        let verification = hbbs::verify_audit_chain(&mut conn, &anchors).await?;
+        Ok::<_, sqlx::Error>((anchor_file, verification))
+    })
+    .await;
+    let (data, error) = match verified {
+        Ok((anchor_file, verification)) => {
+            if let Some(broken) = &verification.broken {
+                hbb_common::log::warn!(
+                    "API: Audit chain broken at seq {} ({})",
+                    broken.seq,
+                    broken.reason
+                );
+            }
+            let data = AuditVerifyResponse {
+                chain_enabled: hbbs::audit_chain_enabled(),
+                anchor_file: anchor_file.display().to_string(),
+                intact: verification.broken.is_none(),
+                verification,
+            };
+            (Some(data), None)
+        }
+        Err(e) => {
+            hbb_common::log::error!("API: Audit chain verification failed: {}", e);
+            (None, Some(format!("Database error: {}", e)))
+        }
+    };
+    Ok(Json(ApiResponse {
+        success: data.is_some(),
+        data,
+        error,
+        timestamp: get_current_timestamp(),
+    }))
+}
+
+/// Last error responses sent to clients (pk/uuid are never included); admin
+/// keys only, as they carry ids and addresses of the peers refused
+/// GET /api/debug/recent-errors
+pub(crate) async fn get_recent_errors(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+) -> Result<Json<ApiResponse<Vec<RecentErrorEntry>>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+
+    let errors = hbbs::recent_errors()
+        .into_iter()
+        .map(|e| RecentErrorEntry {
+            at: e.at.to_rfc3339(),
+            id: e.id,
+            ip: e.ip,
+            reason: e.reason.as_str().to_string(),
+            detail: e.detail,
+        })
+        .collect();
+
+    Ok(Json(ApiResponse {
+        success: true,
+        data: Some(errors),
+        error: None,
+        timestamp: get_current_timestamp(),
+    }))
+}
+
+/// Open GET /api/events and /api/events/sse connections at most
+/// (EVENT_STREAMS_MAX); each holds up to EVENT_QUEUE events for its client
+const EVENT_STREAMS_MAX: u64 = 32;
+/// Ping (or SSE heartbeat) on an event stream this often, so a client gone
+/// silently is noticed and proxies keep the connection
+const EVENT_STREAM_PING_SECS: u64 = 30;
+
+fn event_stream_ping() -> std::time::Duration {
+    let secs = crate::sync::env_u64("EVENT_STREAM_PING_SECS", EVENT_STREAM_PING_SECS);
+    std::time::Duration::from_secs(secs.max(1))
+}
+
+static EVENT_STREAMS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+/// An open event stream, released when the connection ends or never upgrades
+struct EventStreamSlot;
+
+impl EventStreamSlot {
+    fn take() -> Result<Self, StatusCode> {
+        let max = crate::sync::env_u64("EVENT_STREAMS_MAX", EVENT_STREAMS_MAX) as usize;
+        if EVENT_STREAMS.fetch_add(1, std::sync::atomic::Ordering::Relaxed) >= max {
+            EVENT_STREAMS.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+            hbb_common::log::warn!("API: Event stream refused, {} already open", max);
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        }
+        Ok(Self)
+    }
+}
+
+impl Drop for EventStreamSlot {
+    fn drop(&mut self) {
+        EVENT_STREAMS.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct EventsParams {
+    /// The API key, for clients that cannot set headers (browsers: WebSocket,
+    /// EventSource)
+    pub api_key: Option<String>,
+}
+
+/// verify_scope for reading. ?api_key= is for browsers, which cannot set headers
+/// on a WebSocket or EventSource: it stands in for X-API-Key when the headers
+/// carry neither key nor token, and is checked the same way.
+fn verify_events_key(
+    headers: &HeaderMap,
+    bearer: &Bearer,
+    params: &EventsParams,
+    state: &ApiState,
+) -> Result<(), StatusCode> {
+    let in_headers = headers.get("X-API-Key").is_some() || crate::oidc::bearer(headers).is_some();
+    match &params.api_key {
+        Some(key) if !in_headers => {
+            let mut headers = headers.clone();
+            let key = HeaderValue::from_str(key).map_err(|_| {
+                hbb_common::log::warn!("API: Invalid API key");
+                StatusCode::UNAUTHORIZED
+            })?;
+            headers.insert("X-API-Key", key);
+            verify_scope(&headers, bearer, state, Role::ReadOnly)
+        }
+        _ => verify_scope(headers, bearer, state, Role::ReadOnly),
+    }
+}
+
+/// One text message of GET /api/events:
+/// `{"type":"online","id":"123456789","ts":"2026-02-06T14:00:27.113Z","reason":"register","seq":7}`
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum StreamEvent {
+    Online {
+        id: String,
+        ts: String,
+        /// As in the status-event log (register, heartbeat, timeout, ...)
+        reason: &'static str,
+        /// Of the server's event stream; other kinds leave gaps
+        seq: u64,
+    },
+    Offline {
+        id: String,
+        ts: String,
+        reason: &'static str,
+        seq: u64,
+    },
+    /// The client fell behind and `missed` events were dropped, transitions
+    /// among them; GET /api/peers has the current state
+    Lagged { missed: u64 },
+}
+
+impl StreamEvent {
+    /// The online/offline transitions of the server's events
+    pub(crate) fn from_event(event: hbbs::Event) -> Option<Self> {
+        let (ts, seq) = (event.at, event.seq);
+        match event.kind {
+            hbbs::EventKind::Online { id, reason } => Some(Self::Online {
+                id,
+                ts,
+                reason,
+                seq,
+            }),
+            hbbs::EventKind::Offline { id, reason } => Some(Self::Offline {
+                id,
+                ts,
+                reason,
+                seq,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Online/offline transitions as the rendezvous side sees them (register,
+/// heartbeat, timeout sweep, ...), one JSON text message each, instead of
+/// polling GET /api/peers; the key in X-API-Key, a bearer token or ?api_key=
+/// GET /api/events (WebSocket)
+pub(crate) async fn get_events(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Query(params): Query<EventsParams>,
+    Extension(state): Extension<Arc<ApiState>>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, StatusCode> {
+    verify_events_key(&headers, &bearer, &params, &state)?;
+    let slot = EventStreamSlot::take()?;
+    // subscribed before answering the upgrade, so no transition in between is missed
+    let rx = hbbs::subscribe_events();
+    Ok(ws.on_upgrade(move |socket| async move {
+        stream_events(socket, rx).await;
+        drop(slot);
+    }))
+}
+
+async fn stream_events(
+    mut socket: WebSocket,
+    mut rx: hbb_common::tokio::sync::broadcast::Receiver<hbbs::Event>,
+) {
+    use hbb_common::tokio::{self, sync::broadcast::error::RecvError};
+    let mut ping = tokio::time::interval(event_stream_ping());
+    ping.tick().await;
+    loop {
+        let event = tokio::select! {
+            event = rx.recv() => match event {
+                Ok(event) => match StreamEvent::from_event(event) {
+                    Some(event) => event,
+                    None => continue,
+                },
+                Err(RecvError::Lagged(missed)) => StreamEvent::Lagged { missed },
+                Err(RecvError::Closed) => break,
+            },
+            // nothing is expected from the client but its close
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+            _ = ping.tick() => {
+                if socket.send(Message::Ping(Vec::new())).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+        };
+        let text = match serde_json::to_string(&event) {
+            Ok(text) => text,
+            Err(_) => continue,
+        };
+        if socket.send(Message::Text(text)).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// What a client reconnecting with Last-Event-ID missed, as the first message
+/// of its stream
+#[derive(Serialize, Debug)]
+pub(crate) struct SseResume {
+    pub last_event_id: String,
+    /// Events of any kind since that id, an upper bound of the status changes
+    /// missed; None when the id is from before a restart or unknown
+    pub missed: Option<u64>,
+}
+
+/// The SSE id of a status event: `{epoch}-{seq}`
+fn sse_event_id(seq: u64) -> String {
+    format!("{}-{}", *EVENT_EPOCH, seq)
+}
+
+fn sse_resume(last_event_id: &str, seq: u64) -> SseResume {
+    let missed = last_event_id
+        .split_once('-')
+        .filter(|(epoch, _)| epoch.parse() == Ok(*EVENT_EPOCH))
+        .and_then(|(_, last)| last.parse::<u64>().ok())
+        .filter(|last| *last <= seq)
+        .map(|last| seq - last);
+    SseResume {
+        last_event_id: last_event_id.to_owned(),
+        missed,
+    }
+}
+
+/// The events of GET /api/events as Server-Sent Events for browser dashboards:
+/// `event: status` with the same JSON (online/offline) and an `{epoch}-{seq}`
+/// id, `event: lagged` when the client fell behind and events were dropped,
+/// `event: heartbeat` every EVENT_STREAM_PING_SECS for proxies, and with a
+/// Last-Event-ID first `event: resume` with how many events were missed
+/// GET /api/events/sse
+pub(crate) async fn get_events_sse(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Query(params): Query<EventsParams>,
+    Extension(state): Extension<Arc<ApiState>>,
+) -> Result<Response, StatusCode> {
+    use axum::response::sse::{Event, Sse};
+    use hbb_common::futures_util::{stream, StreamExt};
+    use hbb_common::tokio::{self, sync::broadcast::error::RecvError};
+    verify_events_key(&headers, &bearer, &params, &state)?;
+    let slot = EventStreamSlot::take()?;
+    let (seq, rx) = hbbs::subscribe_events_since();
+
+    let resume = headers
+        .get("Last-Event-ID")
+        .and_then(|x| x.to_str().ok())
+        .map(|last| sse_resume(last.trim(), seq))
+        .and_then(|resume| Event::default().event("resume").json_data(resume).ok());
+    let mut ping = tokio::time::interval(event_stream_ping());
+    ping.tick().await;
+    // a slow client only makes its receiver lag; the rendezvous side never waits
+    let events = stream::unfold((rx, ping, slot), |(mut rx, mut ping, slot)| async move {
+        let event = loop {
+            tokio::select! {
+                event = rx.recv() => match event {
+                    Ok(event) => {
+                        let id = sse_event_id(event.seq);
+                        if let Some(event) = StreamEvent::from_event(event) {
+                            break Event::default().event("status").id(id).json_data(event);
+                        }
+                    }
+                    Err(RecvError::Lagged(missed)) => {
+                        let lagged = StreamEvent::Lagged { missed };
+                        break Event::default().event("lagged").json_data(lagged);
+                    }
+                    Err(RecvError::Closed) => return None,
+                },
+                _ = ping.tick() => {
+                    let ts = serde_json::json!({ "ts": get_current_timestamp() });
+                    break Event::default().event("heartbeat").json_data(ts);
+                }
+            }
+        };
+        Some((event, (rx, ping, slot)))
+    });
+    let resume = stream::iter(resume.map(Ok));
+    Ok(Sse::new(resume.chain(events)).into_response())
+}
+
+#[derive(Deserialize)]
+pub(crate) struct SimulatePunchRequest {
+    pub from_id: String,
+    pub to_id: String,
+    /// Where the initiator asks from, e.g. "203.0.113.5:21116"; where it last
+    /// registered without it
+    pub from_addr: Option<String>,
+    /// "udp" (default), "tcp" or "ws"
+    pub transport: Option<String>,
+}
+
+/// Answer a punch hole request from from_id to to_id would get right now, with
+/// the checks it went through; nothing is sent, counted or logged
+/// POST /api/debug/simulate-punch
+/// Body: { "from_id": "123456789", "to_id": "987654321" }
+pub(crate) async fn simulate_punch(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+    Json(payload): Json<SimulatePunchRequest>,
+) -> Result<Json<ApiResponse<hbbs::PunchTrace>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+
+    let fail = |error: String| {
+        Ok(Json(ApiResponse {
+            success: false,
+            data: None,
+            error: Some(error),
+            timestamp: get_current_timestamp(),
+        }))
+    };
+    if payload.from_id.trim().is_empty() || payload.to_id.trim().is_empty() {
+        return fail("from_id and to_id are required".to_string());
+    }
+    let from_addr = match payload.from_addr.as_deref().map(str::parse::<SocketAddr>) {
+        None => None,
+        Some(Ok(addr)) => Some(addr),
+        Some(Err(_)) => return fail("from_addr must be an ip:port".to_string()),
+    };
+    let transport = match payload.transport.as_deref().unwrap_or("udp") {
+        "udp" => hbbs::Transport::Udp,
+        "tcp" => hbbs::Transport::Tcp,
+        "ws" => hbbs::Transport::Ws,
+        _ => return fail("transport must be udp, tcp or ws".to_string()),
+    };
+    let sim = hbbs::PunchSimulation {
+        from_id: payload.from_id,
+        to_id: payload.to_id,
+        from_addr,
+        transport,
+    };
+    let (data, error) = match hbbs::simulate_punch(sim).await {
+        Some(Ok(trace)) => (Some(trace), None),
+        Some(Err(e)) => (None, Some(e)),
+        None => (None, Some("Rendezvous server is not running".to_string())),
+    };
+    Ok(Json(ApiResponse {
+        success: data.is_some(),
+        data,
+        error,
+        timestamp: get_current_timestamp(),
+    }))
+}
+
+/// Most recent log lines, newest last; admin keys only, as logs carry addresses
+/// and ids of every peer
+/// GET /api/admin/logs?lines=200&level=warn&target=hbbs
+pub(crate) async fn get_recent_logs(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+    Query(params): Query<LogParams>,
+) -> Result<Json<ApiResponse<RecentLogs>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+
+    let level = match params.level.as_deref().unwrap_or("trace").parse::<hbb_common::log::LevelFilter>() {
+        Ok(level) => level,
+        Err(_) => {
+            return Ok(Json(ApiResponse {
+                success: false,
+                data: None,
+                error: Some("level must be one of error, warn, info, debug, trace".to_string()),
+                timestamp: get_current_timestamp(),
+            }))
+        }
+    };
+    let lines = params
+        .lines
+        .unwrap_or(200)
+        .min(crate::logs::RING_LINES);
+    let entries = crate::logs::recent(lines, level, params.target.as_deref().unwrap_or(""));
+
+    Ok(Json(ApiResponse {
+        success: true,
+        data: Some(RecentLogs {
+            entries,
+            dropped: crate::logs::dropped(),
+            ips_redacted: crate::logs::redacting_ips(),
+        }),
+        error: None,
+        timestamp: get_current_timestamp(),
+    }))
+}
+
+/// Source IPs registering an unusual number of distinct uuids
+/// GET /api/anomalies/uuid-churn
+async fn get_uuid_churn(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+) -> Result<Json<ApiResponse<Vec<UuidChurnAnomaly>>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::ReadOnly)?;
+
+    let anomalies = hbbs::uuid_churn_anomalies()
+        .await
+        .into_iter()
+        .map(|a| UuidChurnAnomaly {
+            ip: a.ip,
+            distinct_uuids: a.distinct_uuids,
+            ids: a.ids,
+            throttled: a.throttled,
+            window_age_secs: a.window_age_secs,
+        })
+        .collect();
+
+    Ok(Json(ApiResponse {
+        success: true,
+        data: Some(anomalies),
+        error: None,
+        timestamp: get_current_timestamp(),
+    }))
+}
+
+fn unix_to_rfc3339(secs: i64) -> String {
+    chrono::TimeZone::timestamp_opt(&chrono::Utc, secs, 0)
+        .single()
+        .map(|t| t.to_rfc3339())
+        .unwrap_or_default()
+}
+
+/// Pk changes parked by `--pk-change-policy=approve`
+/// GET /api/key-changes
+async fn list_key_changes(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+) -> Result<Json<ApiResponse<Vec<KeyChange>>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::ReadOnly)?;
+
+    let rows = crate::apistats::query(
+        sqlx::query(
+            "SELECT peer_id, old_fingerprint, new_fingerprint, first_seen, last_seen, attempts, approved_at
+             FROM pending_key_changes ORDER BY first_seen",
+        )
+        .fetch_all(&state.read_pool),
+    )
+    .await;
+    match rows {
+        Ok(rows) => Ok(Json(ApiResponse {
+            success: true,
+            data: Some(
+                rows.iter()
+                    .map(|row| KeyChange {
+                        id: row.get("peer_id"),
+                        old_fingerprint: row.get("old_fingerprint"),
+                        new_fingerprint: row.get("new_fingerprint"),
+                        first_seen: unix_to_rfc3339(row.get("first_seen")),
+                        last_seen: unix_to_rfc3339(row.get("last_seen")),
+                        attempts: row.get("attempts"),
+                        approved_at: row
+                            .get::<Option<i64>, _>("approved_at")
+                            .map(unix_to_rfc3339),
+                    })
+                    .collect(),
+            ),
+            error: None,
+            timestamp: get_current_timestamp(),
+        })),
+        Err(e) => {
+            hbb_common::log::error!("API: Database query failed: {}", e);
+            Ok(Json(ApiResponse {
+                success: false,
+                data: None,
+                error: Some(format!("Database error: {}", e)),
+                timestamp: get_current_timestamp(),
+            }))
+        }
+    }
+}
+
+/// Mark a parked pk change approved; the server applies it on the device's next registration
+pub(crate) async fn approve_key_change(
+    pool: &SqlitePool,
+    id: &str,
+    fingerprint: Option<&str>,
+) -> Result<ApproveOutcome, sqlx::Error> {
+    let row = sqlx::query(
+        "SELECT new_fingerprint, approved_at FROM pending_key_changes WHERE peer_id = ?",
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+    let row = match row {
+        Some(row) => row,
+        None => return Ok(ApproveOutcome::NotFound),
+    };
+    let new_fingerprint: String = row.get("new_fingerprint");
+    if row.get::<Option<i64>, _>("approved_at").is_some() {
+        return Ok(ApproveOutcome::AlreadyApproved);
+    }
+    if fingerprint.map_or(false, |f| !f.eq_ignore_ascii_case(&new_fingerprint)) {
+        return Ok(ApproveOutcome::FingerprintMismatch(new_fingerprint));
+    }
+    let now = chrono::Utc::now().timestamp();
+    let res = sqlx::query(
+        "UPDATE pending_key_changes SET approved_at = ?
+         WHERE peer_id = ? AND new_fingerprint = ? AND approved_at IS NULL",
+    )
+    .bind(now)
+    .bind(id)
+    .bind(&new_fingerprint)
+    .execute(pool)
+    .await?;
+    if res.rows_affected() == 0 {
+        // replaced or approved concurrently
+        return Ok(ApproveOutcome::NotFound);
+    }
+    append_audit(
+        pool,
+        now,
+        "api",
+        "key_change_approved",
+        id,
+        &new_fingerprint,
+    )
+    .await?;
+    hbbs::emit_event(hbbs::EventKind::Audit {
+        actor: "api".to_owned(),
+        action: "key_change_approved",
+        peer_id: id.to_owned(),
+        detail: new_fingerprint.clone(),
+    });
+    Ok(ApproveOutcome::Approved(new_fingerprint))
+}
+
+/// POST /api/peers/:id/approve-key-change?fingerprint=
+async fn approve_peer_key_change(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+    Path(peer_id): Path<String>,
+    Query(params): Query<ApproveKeyParams>,
+) -> Result<Json<ApiResponse<String>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+    let peer_id = hbbs::canonical_id(&peer_id).into_owned();
+
+    let (data, error) =
+        match crate::apistats::query(approve_key_change(
+            &state.db_pool,
+            &peer_id,
+            params.fingerprint.as_deref(),
+        ))
+        .await
+        {
+            Ok(ApproveOutcome::Approved(fingerprint)) => {
+                hbb_common::log::info!(
+                    "API: Approved key change of {} to {}",
+                    peer_id,
+                    fingerprint
+                );
+                let detail = serde_json::json!({ "fingerprint": fingerprint });
+                if let Err(e) = crate::notices::queue(
+                    &state.db_pool,
+                    &peer_id,
+                    &peer_id,
+                    crate::notices::KEY_RESET,
+                    detail,
+                )
+                .await
+                {
+                    hbb_common::log::warn!("API: Cannot queue key notice for {}: {}", peer_id, e);
+                }
+                (Some(fingerprint), None)
+            }
+            Ok(ApproveOutcome::NotFound) => {
+                (None, Some(format!("No pending key change for {}", peer_id)))
+            }
+            Ok(ApproveOutcome::AlreadyApproved) => (
+                None,
+                Some(format!("Key change of {} is already approved", peer_id)),
+            ),
+            Ok(ApproveOutcome::FingerprintMismatch(current)) => (
+                None,
+                Some(format!(
+                    "Pending key of {} has fingerprint {}",
+                    peer_id, current
+                )),
+            ),
+            Err(e) => {
+                hbb_common::log::error!("API: Database query failed: {}", e);
+                (None, Some(format!("Database error: {}", e)))
+            }
+        };
+    Ok(Json(ApiResponse {
+        success: data.is_some(),
+        data,
+        error,
+        timestamp: get_current_timestamp(),
+    }))
+}
+
+#[derive(Deserialize, Default)]
+pub(crate) struct BanRequest {
+    /// Shown to the user by clients that read notices
+    pub message: Option<String>,
+    /// Why the device was banned; kept in the audit log, not shown to the user
+    pub reason: Option<String>,
+    /// Lift the ban by itself after this many hours; permanent without
+    pub duration_hours: Option<u64>,
+}
+
+/// Longest temporary ban, ten years; longer ones are meant to be permanent
+const MAX_BAN_HOURS: u64 = 10 * 366 * 24;
+
+/// Whether a ban is in force, as of `?`: an expired temporary ban is lifted
+/// even while the sweep has yet to clear its flag
+const BAN_ACTIVE: &str = "is_banned = 1 AND (banned_until IS NULL OR banned_until > ?)";
+
+/// "permanent", or how long a temporary ban ending at `until` has left
+fn ban_remaining(until: Option<i64>, now: i64) -> String {
+    let secs = match until {
+        Some(until) => (until - now).max(0),
+        None => return "permanent".to_owned(),
+    };
+    let (days, hours, mins) = (secs / 86400, secs % 86400 / 3600, secs % 3600 / 60);
+    if days > 0 {
+        format!("{}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, mins)
+    } else if mins > 0 {
+        format!("{}m", mins)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct BanResponse {
+    id: String,
+    banned_by: String,
+    /// RFC3339
+    banned_at: String,
+    /// RFC3339, when a temporary ban lifts; None for a permanent one
+    banned_until: Option<String>,
+    reason: Option<String>,
+    /// whether the peer was in memory and was dropped from it
+    evicted: bool,
+    /// the ban notice queued for the client, None when it could not be queued
+    notice: Option<hbbs::PeerNotice>,
+}
+
+/// Ban a peer, closing its live connection, dropping it from memory and telling
+/// the client why. 404 for an unknown id, 409 when it is banned already.
+/// POST /api/peers/:id/ban
+/// Body: { "message": "...", "reason": "...", "duration_hours": 24 }
+pub(crate) async fn ban_peer(
+    headers: HeaderMap,
+    bearer: Bearer,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(state): Extension<Arc<ApiState>>,
+    Path(peer_id): Path<String>,
+    payload: Option<Json<BanRequest>>,
+) -> Result<Versioned<ApiResponse<BanResponse>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+    let peer_id = hbbs::canonical_id(&peer_id).into_owned();
+    let expected = crate::peerversion::if_match(&headers, state.require_if_match)?;
+    let fail = |status: StatusCode, error: String| Versioned {
+        status,
+        response: ApiResponse {
+            success: false,
+            data: None,
+            error: Some(error),
+            timestamp: get_current_timestamp(),
+        },
+        version: None,
+    };
+
+    let BanRequest {
+        message,
+        reason,
+        duration_hours,
+    } = payload.map(|Json(p)| p).unwrap_or_default();
+    let message = message.unwrap_or_default();
+    if let Some(hours) = duration_hours.filter(|x| !(1..=MAX_BAN_HOURS).contains(x)) {
+        let error = format!("duration_hours {} is not 1 to {}", hours, MAX_BAN_HOURS);
+        return Ok(fail(StatusCode::BAD_REQUEST, error));
+    }
+    let now = chrono::Utc::now().timestamp();
+    let banned_until = duration_hours.map(|hours| now + hours as i64 * 3600);
+    let banned = crate::apistats::query(
+        sqlx::query(&format!(
+            "SELECT {} AS banned FROM peer WHERE id = ? AND is_deleted = 0",
+            BAN_ACTIVE
+        ))
+        .bind(now)
+        .bind(&peer_id)
+        .fetch_optional(&state.db_pool),
+    )
+    .await;
+    match banned.map(|row| row.map(|row| row.get::<Option<i64>, _>("banned"))) {
+        Ok(None) => {
+            let error = format!("Peer '{}' not found", peer_id);
+            return Ok(fail(StatusCode::NOT_FOUND, error));
+        }
+        Ok(Some(Some(1))) => {
+            let error = format!("Peer '{}' is already banned", peer_id);
+            return Ok(fail(StatusCode::CONFLICT, error));
+        }
+        Ok(Some(_)) => {}
+        Err(e) => {
+            hbb_common::log::error!("API: Failed to ban {}: {}", peer_id, e);
+            return Ok(fail(StatusCode::OK, format!("Database error: {}", e)));
+        }
+    }
+    let result = crate::apistats::query(crate::peerversion::change(
+        &state.db_pool,
+        &peer_id,
+        expected,
+        sqlx::query("UPDATE peer SET is_banned = 1, banned_until = ? WHERE id = ?")
+            .bind(banned_until)
+            .bind(&peer_id),
+    ))
+    .await;
+    let (data, version, error) = match result {
+        Ok(crate::peerversion::Bump::Done(version)) => {
+            let actor = api_actor(&state, &headers, addr);
+            let banned_at = chrono::Utc::now();
+            hbb_common::log::info!("API: Banned {} ({})", peer_id, actor);
+            hbbs::reset_ban_rejections(&peer_id);
+            hbbs::emit_event(hbbs::EventKind::Ban {
+                id: peer_id.clone(),
+                actor: actor.clone(),
+            });
+            let audit = append_audit(
+                &state.db_pool,
+                banned_at.timestamp(),
+                &actor,
+                "ban",
+                &peer_id,
+                reason.as_deref().unwrap_or_default(),
+            )
+            .await;
+            if let Err(e) = audit {
+                hbb_common::log::warn!("API: Cannot audit ban of {}: {}", peer_id, e);
+            }
+            let detail = serde_json::json!({ "message": message });
+            let notice = crate::notices::queue(
+                &state.db_pool,
+                &peer_id,
+                &peer_id,
+                crate::notices::BAN,
+                detail,
+            )
+            .await;
+            hbbs::disconnect_peer(&peer_id, hbbs::DisconnectReason::Banned, &message);
+            // a udp peer has no connection to close; without this it stays reachable
+            // until its entry times out
+            let evicted = match live_peer_map(&state) {
+                Some(pm) => pm.evict(&peer_id).await,
+                None => false,
+            };
+            let (notice, error) = match notice {
+                Ok(notice) => (Some(notice), None),
+                Err(e) => {
+                    hbb_common::log::warn!("API: Cannot queue ban notice for {}: {}", peer_id, e);
+                    (None, Some(format!("Banned, but no notice queued: {}", e)))
+                }
+            };
+            let data = BanResponse {
+                id: peer_id,
+                banned_by: actor,
+                banned_at: banned_at.to_rfc3339(),
+                banned_until: banned_until.map(unix_to_rfc3339),
+                reason,
+                evicted,
+                notice,
+            };
+            (Some(data), Some(version), error)
+        }
+        Ok(crate::peerversion::Bump::Conflict(current)) => {
+            return Ok(version_conflict(&peer_id, current))
+        }
+        Ok(crate::peerversion::Bump::NoSuchPeer) => {
+            let error = format!("Peer '{}' not found", peer_id);
+            return Ok(fail(StatusCode::NOT_FOUND, error));
+        }
+        Err(e) => {
+            hbb_common::log::error!("API: Failed to ban {}: {}", peer_id, e);
+            (None, None, Some(format!("Database error: {}", e)))
+        }
+    };
+    Ok(versioned(
+        version,
+        ApiResponse {
+            success: data.is_some(),
+            data,
+            error,
+            timestamp: get_current_timestamp(),
+        },
+    ))
+}
+
+#[derive(Deserialize, Default)]
+pub(crate) struct BansParams {
+    /// Only bans from this time on, RFC3339 or unix seconds; bans set outside
+    /// the API have no known time and are left out
+    pub since: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct BannedPeer {
+    pub id: String,
+    pub note: Option<String>,
+    /// RFC3339, from the latest ban in the audit log; None for a peer banned
+    /// by setting is_banned directly
+    pub banned_at: Option<String>,
+    pub banned_by: Option<String>,
+    pub reason: Option<String>,
+    /// RFC3339, when a temporary ban lifts; None for a permanent one
+    pub banned_until: Option<String>,
+    /// "permanent", or the time a temporary ban has left, e.g. "23h 59m"
+    pub remaining: String,
+    /// RegisterPk attempts refused for the ban since it was set or the server
+    /// started, whichever was later
+    pub rejected_registrations: u64,
+}
+
+/// Every peer banned now, latest bans first; expired temporary bans are left out
+/// GET /api/bans?since=2026-01-01T00:00:00Z
+pub(crate) async fn get_bans(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Query(params): Query<BansParams>,
+    Extension(state): Extension<Arc<ApiState>>,
+) -> Result<Json<ApiResponse<Vec<BannedPeer>>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::ReadOnly)?;
+    let fail = |error: String| {
+        Ok(Json(ApiResponse {
+            success: false,
+            data: None,
+            error: Some(error),
+            timestamp: get_current_timestamp(),
+        }))
+    };
+    let since = match params.since.as_deref() {
+        Some(s) => match crate::uptime::parse_time(s) {
+            Some(since) => Some(since),
+            None => return fail(format!("Invalid time: {}", s)),
+        },
+        None => None,
+    };
+    let now = chrono::Utc::now().timestamp();
+    let rows = crate::apistats::query(
+        sqlx::query(
+            "SELECT p.id, p.note, p.banned_until, a.at, a.actor, a.detail FROM peer p
+             LEFT JOIN audit_log a ON a.rowid = (
+                 SELECT rowid FROM audit_log WHERE peer_id = p.id AND action = 'ban'
+                 ORDER BY at DESC, rowid DESC LIMIT 1
+             )
+             WHERE p.is_banned = 1 AND (p.banned_until IS NULL OR p.banned_until > ?)
+                 AND p.is_deleted = 0 AND (? IS NULL OR a.at >= ?)
+             ORDER BY a.at IS NULL, a.at DESC, p.id",
+        )
+        .bind(now)
+        .bind(since)
+        .bind(since)
+        .fetch_all(&state.read_pool),
+    )
+    .await;
+    let rows = match rows {
+        Ok(rows) => rows,
+        Err(e) => {
+            hbb_common::log::error!("API: Failed to list bans: {}", e);
+            return fail(format!("Database error: {}", e));
+        }
+    };
+    let bans = rows
+        .iter()
+        .map(|row| {
+            let id: String = row.get("id");
+            let banned_until: Option<i64> = row.get("banned_until");
+            BannedPeer {
+                rejected_registrations: hbbs::ban_rejections(&id),
+                id,
+                note: row.get("note"),
+                banned_at: row.get::<Option<i64>, _>("at").map(unix_to_rfc3339),
+                banned_by: row.get("actor"),
+                reason: row
+                    .get::<Option<String>, _>("detail")
+                    .filter(|x| !x.is_empty()),
+                banned_until: banned_until.map(unix_to_rfc3339),
+                remaining: ban_remaining(banned_until, now),
+            }
+        })
+        .collect();
+    Ok(Json(ApiResponse {
+        success: true,
+        data: Some(bans),
+        error: None,
+        timestamp: get_current_timestamp(),
+    }))
+}
+
+#[derive(Serialize)]
+pub(crate) struct UnbanResponse {
+    id: String,
+    unbanned_by: String,
+    /// RFC3339
+    unbanned_at: String,
+    /// undelivered ban notices dropped, so the client is not told of a lifted ban
+    withdrawn_notices: u64,
+    /// the peer as GET /api/peers/:id shows it now
+    peer: Option<PeerStatus>,
+}
+
+/// Lift a ban; the device's next RegisterPk is accepted. 404 for an unknown id,
+/// 409 when it is not banned or its temporary ban has expired.
+/// POST /api/peers/:id/unban
+pub(crate) async fn unban_peer(
+    headers: HeaderMap,
+    bearer: Bearer,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(state): Extension<Arc<ApiState>>,
+    Path(peer_id): Path<String>,
+) -> Result<Versioned<ApiResponse<UnbanResponse>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+    let peer_id = hbbs::canonical_id(&peer_id).into_owned();
+    let expected = crate::peerversion::if_match(&headers, state.require_if_match)?;
+    let fail = |status: StatusCode, error: String| Versioned {
+        status,
+        response: ApiResponse {
+            success: false,
+            data: None,
+            error: Some(error),
+            timestamp: get_current_timestamp(),
+        },
+        version: None,
+    };
+
+    let banned = crate::apistats::query(
+        sqlx::query(&format!(
+            "SELECT {} AS banned FROM peer WHERE id = ? AND is_deleted = 0",
+            BAN_ACTIVE
+        ))
+        .bind(chrono::Utc::now().timestamp())
+        .bind(&peer_id)
+        .fetch_optional(&state.db_pool),
+    )
+    .await;
+    match banned.map(|row| row.map(|row| row.get::<Option<i64>, _>("banned"))) {
+        Ok(None) => {
+            let error = format!("Peer '{}' not found", peer_id);
+            return Ok(fail(StatusCode::NOT_FOUND, error));
+        }
+        Ok(Some(Some(1))) => {}
+        Ok(Some(_)) => {
+            let error = format!("Peer '{}' is not banned", peer_id);
+            return Ok(fail(StatusCode::CONFLICT, error));
+        }
+        Err(e) => {
+            hbb_common::log::error!("API: Failed to unban {}: {}", peer_id, e);
+            return Ok(fail(StatusCode::OK, format!("Database error: {}", e)));
+        }
+    }
+    let result = crate::apistats::query(crate::peerversion::change(
+        &state.db_pool,
+        &peer_id,
+        expected,
+        sqlx::query("UPDATE peer SET is_banned = 0, banned_until = NULL WHERE id = ?")
+            .bind(&peer_id),
+    ))
+    .await;
+    let (data, version, error) = match result {
+        Ok(crate::peerversion::Bump::Done(version)) => {
+            let actor = api_actor(&state, &headers, addr);
+            let unbanned_at = chrono::Utc::now();
+            hbb_common::log::info!("API: Unbanned {} ({})", peer_id, actor);
+            hbbs::reset_ban_rejections(&peer_id);
+            let at = unbanned_at.timestamp();
+            let audit = append_audit(&state.db_pool, at, &actor, "unban", &peer_id, "").await;
+            if let Err(e) = audit {
+                hbb_common::log::warn!("API: Cannot audit unban of {}: {}", peer_id, e);
+            }
+            hbbs::emit_event(hbbs::EventKind::Audit {
+                actor: actor.clone(),
+                action: "unban",
+                peer_id: peer_id.clone(),
+                detail: String::new(),
+            });
+            let withdrawn =
+                crate::notices::withdraw(&state.db_pool, &peer_id, crate::notices::BAN).await;
+            let withdrawn_notices = match withdrawn {
+                Ok(n) => n,
+                Err(e) => {
+                    hbb_common::log::warn!(
+                        "API: Cannot withdraw ban notices of {}: {}",
+                        peer_id,
+                        e
+                    );
+                    0
+                }
+            };
+            // whatever is in memory from the banned registrations (a refused
+            // entry, its RegisterPk rate limit) goes, so the next one starts clean
+            let live = live_peer_map(&state);
+            if let Some(pm) = &live {
+                pm.evict(&peer_id).await;
+            }
+            let peer = match peer_details(&state, &live, &peer_id).await {
+                Ok(peer) => peer,
+                Err(e) => {
+                    hbb_common::log::warn!("API: Cannot read back {}: {}", peer_id, e);
+                    None
+                }
+            };
+            let data = UnbanResponse {
+                id: peer_id,
+                unbanned_by: actor,
+                unbanned_at: unbanned_at.to_rfc3339(),
+                withdrawn_notices,
+                peer,
+            };
+            (Some(data), Some(version), None)
+        }
+        Ok(crate::peerversion::Bump::Conflict(current)) => {
+            return Ok(version_conflict(&peer_id, current))
+        }
+        Ok(crate::peerversion::Bump::NoSuchPeer) => {
+            let error = format!("Peer '{}' not found", peer_id);
+            return Ok(fail(StatusCode::NOT_FOUND, error));
+        }
+        Err(e) => {
+            hbb_common::log::error!("API: Failed to unban {}: {}", peer_id, e);
+            (None, None, Some(format!("Database error: {}", e)))
+        }
+    };
+    Ok(versioned(
+        version,
+        ApiResponse {
+            success: data.is_some(),
+            data,
+            error,
+            timestamp: get_current_timestamp(),
+        },
+    ))
+}
+
+#[derive(Serialize)]
+pub(crate) struct QuarantineResponse {
+    id: String,
+    quarantined: bool,
+}
+
+/// Quarantine a peer: it keeps registering and showing online, but punch holes
+/// to or from it are refused until it is unquarantined. Unlike a ban nothing is
+/// closed and the client is not told.
+/// POST /api/peers/:id/quarantine
+pub(crate) async fn quarantine_peer(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+    Path(peer_id): Path<String>,
+) -> Result<Versioned<ApiResponse<QuarantineResponse>>, StatusCode> {
+    set_quarantine(headers, bearer, state, peer_id, true).await
+}
+
+/// POST /api/peers/:id/unquarantine
+pub(crate) async fn unquarantine_peer(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+    Path(peer_id): Path<String>,
+) -> Result<Versioned<ApiResponse<QuarantineResponse>>, StatusCode> {
+    set_quarantine(headers, bearer, state, peer_id, false).await
+}
+
+async fn set_quarantine(
+    headers: HeaderMap,
+    bearer: Bearer,
+    state: Arc<ApiState>,
+    peer_id: String,
+    quarantined: bool,
+) -> Result<Versioned<ApiResponse<QuarantineResponse>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+    let peer_id = hbbs::canonical_id(&peer_id).into_owned();
+    let expected = crate::peerversion::if_match(&headers, state.require_if_match)?;
+    let action = if quarantined {
+        "quarantine"
+    } else {
+        "unquarantine"
+    };
+
+    let result = crate::apistats::query(crate::peerversion::change(
+        &state.db_pool,
+        &peer_id,
+        expected,
+        sqlx::query("UPDATE peer SET is_quarantined = ? WHERE id = ?")
+            .bind(quarantined as i64)
+            .bind(&peer_id),
+    ))
+    .await;
+    let (data, version, error) = match result {
+        Ok(crate::peerversion::Bump::Done(version)) => {
+            hbb_common::log::info!("API: {} {}", action, peer_id);
+            let now = chrono::Utc::now().timestamp();
+            let audit = append_audit(&state.db_pool, now, "api", action, &peer_id, "").await;
+            if let Err(e) = audit {
+                hbb_common::log::warn!("API: Cannot audit {} of {}: {}", action, peer_id, e);
+            }
+            hbbs::emit_event(hbbs::EventKind::Audit {
+                actor: "api".to_owned(),
+                action,
+                peer_id: peer_id.clone(),
+                detail: String::new(),
+            });
+            let data = QuarantineResponse {
+                id: peer_id,
+                quarantined,
+            };
+            (Some(data), Some(version), None)
+        }
+        Ok(crate::peerversion::Bump::Conflict(current)) => {
+            return Ok(version_conflict(&peer_id, current))
+        }
+        Ok(crate::peerversion::Bump::NoSuchPeer) => {
+            (None, None, Some(format!("Peer '{}' not found", peer_id)))
+        }
+        Err(e) => {
+            hbb_common::log::error!("API: Failed to {} {}: {}", action, peer_id, e);
+            (None, None, Some(format!("Database error: {}", e)))
+        }
+    };
+    Ok(versioned(
+        version,
+        ApiResponse {
+            success: data.is_some(),
+            data,
+            error,
+            timestamp: get_current_timestamp(),
+        },
+    ))
+}
+
+/// Notes longer than this are refused, the column is varchar(300)
+const MAX_NOTE_CHARS: usize = 300;
+
+#[derive(Deserialize)]
+pub(crate) struct NoteRequest {
+    /// null or empty clears the note
+    pub note: Option<String>,
+}
+
+/// Set or clear a peer's note, trimmed; 400 for a note over MAX_NOTE_CHARS
+/// PUT /api/peers/:id/note
+/// Body: { "note": "..." }
+pub(crate) async fn put_peer_note(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+    Path(peer_id): Path<String>,
+    Json(payload): Json<NoteRequest>,
+) -> Result<Versioned<ApiResponse<PeerStatus>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+    let peer_id = hbbs::canonical_id(&peer_id).into_owned();
+    let expected = crate::peerversion::if_match(&headers, state.require_if_match)?;
+    let fail = |status: StatusCode, error: String| Versioned {
+        status,
+        response: ApiResponse {
+            success: false,
+            data: None,
+            error: Some(error),
+            timestamp: get_current_timestamp(),
+        },
+        version: None,
+    };
+
+    let note = payload
+        .note
+        .map(|x| x.trim().to_owned())
+        .filter(|x| !x.is_empty());
+    if let Some(note) = &note {
+        if note.chars().count() > MAX_NOTE_CHARS || note.chars().any(char::is_control) {
+            let error = format!(
+                "Note must be at most {} characters, without control characters",
+                MAX_NOTE_CHARS
+            );
+            return Ok(fail(StatusCode::BAD_REQUEST, error));
+        }
+    }
+    let result = crate::apistats::query(crate::peerversion::change(
+        &state.db_pool,
+        &peer_id,
+        expected,
+        sqlx::query("UPDATE peer SET note = ? WHERE id = ? AND is_deleted = 0")
+            .bind(&note)
+            .bind(&peer_id),
+    ))
+    .await;
+    match result {
+        Ok(crate::peerversion::Bump::Done(version)) => {
+            hbb_common::log::info!("API: Note of {} changed", peer_id);
+            let live = live_peer_map(&state);
+            match peer_details(&state, &live, &peer_id).await {
+                Ok(Some(peer)) => Ok(versioned(
+                    Some(version),
+                    ApiResponse {
+                        success: true,
+                        data: Some(peer),
+                        error: None,
+                        timestamp: get_current_timestamp(),
+                    },
+                )),
+                Ok(None) => {
+                    let error = format!("Peer '{}' not found", peer_id);
+                    Ok(fail(StatusCode::NOT_FOUND, error))
+                }
+                Err(e) => {
+                    hbb_common::log::error!("API: Failed to read {} back: {}", peer_id, e);
+                    Ok(fail(StatusCode::OK, format!("Database error: {}", e)))
+                }
+            }
+        }
+        Ok(crate::peerversion::Bump::Conflict(current)) => Ok(version_conflict(&peer_id, current)),
+        Ok(crate::peerversion::Bump::NoSuchPeer) => {
+            let error = format!("Peer '{}' not found", peer_id);
+            Ok(fail(StatusCode::NOT_FOUND, error))
+        }
+        Err(e) => {
+            hbb_common::log::error!("API: Failed to change the note of {}: {}", peer_id, e);
+            Ok(fail(StatusCode::OK, format!("Database error: {}", e)))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct UserRequest {
+    /// null or empty takes the peer from its user
+    pub user: Option<String>,
+}
+
+/// Assign a peer to a user, whose disable switch then applies to it, or to none;
+/// 400 for a name refused by users::check_name
+/// PUT /api/peers/:id/user
+/// Body: { "user": "alice" }
+pub(crate) async fn put_peer_user(
+    headers: HeaderMap,
+    bearer: Bearer,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(state): Extension<Arc<ApiState>>,
+    Path(peer_id): Path<String>,
+    Json(payload): Json<UserRequest>,
+) -> Result<Versioned<ApiResponse<PeerStatus>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+    let peer_id = hbbs::canonical_id(&peer_id).into_owned();
+    let expected = crate::peerversion::if_match(&headers, state.require_if_match)?;
+    let fail = |status: StatusCode, error: String| Versioned {
+        status,
+        response: ApiResponse {
+            success: false,
+            data: None,
+            error: Some(error),
+            timestamp: get_current_timestamp(),
+        },
+        version: None,
+    };
+
+    let user = payload.user.filter(|x| !x.is_empty());
+    if let Some(Err(problem)) = user.as_deref().map(crate::users::check_name) {
+        return Ok(fail(StatusCode::BAD_REQUEST, problem));
+    }
+    let bytes = user.as_ref().map(|x| x.as_bytes().to_vec());
+    let result = crate::apistats::query(crate::peerversion::change(
+        &state.db_pool,
+        &peer_id,
+        expected,
+        sqlx::query("UPDATE peer SET user = ? WHERE id = ? AND is_deleted = 0")
+            .bind(&bytes)
+            .bind(&peer_id),
+    ))
+    .await;
+    match result {
+        Ok(crate::peerversion::Bump::Done(version)) => {
+            let actor = api_actor(&state, &headers, addr);
+            let detail = user.clone().unwrap_or_default();
+            hbb_common::log::info!("API: {} assigned to user {:?} ({})", peer_id, user, actor);
+            let live = live_peer_map(&state);
+            if let Some(pm) = &live {
+                pm.set_user(&peer_id, bytes).await;
+            }
+            let now = chrono::Utc::now().timestamp();
+            let audit = append_audit(&state.db_pool, now, &actor, "user", &peer_id, &detail).await;
+            if let Err(e) = audit {
+                hbb_common::log::warn!("API: Cannot audit the user of {}: {}", peer_id, e);
+            }
+            hbbs::emit_event(hbbs::EventKind::Audit {
+                actor,
+                action: "user",
+                peer_id: peer_id.clone(),
+                detail,
+            });
+            match peer_details(&state, &live, &peer_id).await {
+                Ok(Some(peer)) => Ok(versioned(
+                    Some(version),
+                    ApiResponse {
+                        success: true,
+                        data: Some(peer),
+                        error: None,
+                        timestamp: get_current_timestamp(),
+                    },
+                )),
+                Ok(None) => {
+                    let error = format!("Peer '{}' not found", peer_id);
+                    Ok(fail(StatusCode::NOT_FOUND, error))
+                }
+                Err(e) => {
+                    hbb_common::log::error!("API: Failed to read {} back: {}", peer_id, e);
+                    Ok(fail(StatusCode::OK, format!("Database error: {}", e)))
+                }
+            }
+        }
+        Ok(crate::peerversion::Bump::Conflict(current)) => Ok(version_conflict(&peer_id, current)),
+        Ok(crate::peerversion::Bump::NoSuchPeer) => {
+            let error = format!("Peer '{}' not found", peer_id);
+            Ok(fail(StatusCode::NOT_FOUND, error))
+        }
+        Err(e) => {
+            hbb_common::log::error!("API: Failed to change the user of {}: {}", peer_id, e);
+            Ok(fail(StatusCode::OK, format!("Database error: {}", e)))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct AccessRuleRequest {
+    pub controller: String,
+    pub target: String,
+    pub action: String,
+    #[serde(default)]
+    pub priority: i64,
+}
+
+/// An id pattern with the id canonical; `*` and `tag:` names are kept as given
+fn canonical_pattern(pattern: &str) -> String {
+    if pattern == "*" || pattern.starts_with("tag:") {
+        pattern.to_owned()
+    } else {
+        hbbs::canonical_id(pattern).into_owned()
+    }
+}
+
+/// Access rules in evaluation order
+/// GET /api/access-rules
+pub(crate) async fn get_access_rules(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+) -> Result<Json<ApiResponse<Vec<hbbs::AccessRule>>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::ReadOnly)?;
+
+    match crate::apistats::query(crate::access::list(&state.read_pool)).await {
+        Ok(rules) => Ok(Json(ApiResponse {
+            success: true,
+            data: Some(rules),
+            error: None,
+            timestamp: get_current_timestamp(),
+        })),
+        Err(e) => {
+            hbb_common::log::error!("API: Database query failed: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Add an access rule
+/// POST /api/access-rules
+/// Body: { "controller": "tag:helpdesk", "target": "*", "action": "allow", "priority": 10 }
+pub(crate) async fn post_access_rule(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+    Json(mut rule): Json<AccessRuleRequest>,
+) -> Result<Json<ApiResponse<hbbs::AccessRule>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+    rule.controller = canonical_pattern(&rule.controller);
+    rule.target = canonical_pattern(&rule.target);
+
+    let checked = crate::access::check_pattern(&rule.controller)
+        .and_then(|_| crate::access::check_pattern(&rule.target))
+        .and_then(|_| crate::access::check_action(&rule.action));
+    let (data, error) = match checked {
+        Err(problem) => (None, Some(problem)),
+        Ok(()) => match crate::apistats::query(crate::access::add(
+            &state.db_pool,
+            &rule.controller,
+            &rule.target,
+            &rule.action,
+            rule.priority,
+        ))
+        .await
+        {
+            Ok(rule) => {
+                hbb_common::log::info!(
+                    "API: Access rule {} added: {} {} -> {} (priority {})",
+                    rule.id,
+                    rule.action,
+                    rule.controller,
+                    rule.target,
+                    rule.priority
+                );
+                (Some(rule), None)
+            }
+            Err(e) => {
+                hbb_common::log::error!("API: Failed to add access rule: {}", e);
+                (None, Some(format!("Database error: {}", e)))
+            }
+        },
+    };
+    Ok(Json(ApiResponse {
+        success: data.is_some(),
+        data,
+        error,
+        timestamp: get_current_timestamp(),
+    }))
+}
+
+/// DELETE /api/access-rules/:id
+pub(crate) async fn delete_access_rule(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+    Path(rule_id): Path<i64>,
+) -> Result<Json<ApiResponse<hbbs::AccessRule>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+
+    let (data, error) =
+        match crate::apistats::query(crate::access::remove(&state.db_pool, rule_id)).await {
+            Ok(Some(rule)) => {
+                hbb_common::log::info!("API: Access rule {} removed", rule.id);
+                (Some(rule), None)
+            }
+            Ok(None) => (None, Some(format!("No access rule {}", rule_id))),
+            Err(e) => {
+                hbb_common::log::error!("API: Failed to remove access rule: {}", e);
+                (None, Some(format!("Database error: {}", e)))
+            }
+        };
+    Ok(Json(ApiResponse {
+        success: data.is_some(),
+        data,
+        error,
+        timestamp: get_current_timestamp(),
+    }))
+}
+
+/// Users with their switch and the number of peers assigned to them
+/// GET /api/users
+pub(crate) async fn get_users(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+) -> Result<Json<ApiResponse<Vec<crate::users::User>>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::ReadOnly)?;
+
+    match crate::apistats::query(crate::users::list(&state.read_pool)).await {
+        Ok(users) => Ok(Json(ApiResponse {
+            success: true,
+            data: Some(users),
+            error: None,
+            timestamp: get_current_timestamp(),
+        })),
+        Err(e) => {
+            hbb_common::log::error!("API: Database query failed: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct UserSwitchResponse {
+    #[serde(flatten)]
+    user: crate::users::User,
+    /// Peers of the user closed and dropped from memory by the disable
+    evicted: Vec<String>,
+}
+
+/// Disable a user: registrations and punch holes of all its peers are refused,
+/// and those online are disconnected, until it is enabled again
+/// POST /api/users/:name/disable
+pub(crate) async fn disable_user(
+    headers: HeaderMap,
+    bearer: Bearer,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(state): Extension<Arc<ApiState>>,
+    Path(name): Path<String>,
+) -> Result<Json<ApiResponse<UserSwitchResponse>>, StatusCode> {
+    set_user_disabled(headers, bearer, addr, state, name, true).await
+}
+
+/// POST /api/users/:name/enable
+pub(crate) async fn enable_user(
+    headers: HeaderMap,
+    bearer: Bearer,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(state): Extension<Arc<ApiState>>,
+    Path(name): Path<String>,
+) -> Result<Json<ApiResponse<UserSwitchResponse>>, StatusCode> {
+    set_user_disabled(headers, bearer, addr, state, name, false).await
+}
+
+async fn set_user_disabled(
+    headers: HeaderMap,
+    bearer: Bearer,
+    addr: SocketAddr,
+    state: Arc<ApiState>,
+    name: String,
+    disabled: bool,
+) -> Result<Json<ApiResponse<UserSwitchResponse>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+    let action = if disabled {
+        "disable_user"
+    } else {
+        "enable_user"
+    };
+
+    if let Err(problem) = crate::users::check_name(&name) {
+        return Ok(Json(ApiResponse {
+            success: false,
+            data: None,
+            error: Some(problem),
+            timestamp: get_current_timestamp(),
+        }));
+    }
+    let result =
+        crate::apistats::query(crate::users::set_disabled(&state.db_pool, &name, disabled)).await;
+    let (data, error) = match result {
+        Ok((user, ids)) => {
+            let actor = api_actor(&state, &headers, addr);
+            hbb_common::log::info!("API: {} {} ({})", action, name, actor);
+            let now = chrono::Utc::now().timestamp();
+            let audit = append_audit(&state.db_pool, now, &actor, action, "", &name).await;
+            if let Err(e) = audit {
+                hbb_common::log::warn!("API: Cannot audit {} of {}: {}", action, name, e);
+            }
+            hbbs::emit_event(hbbs::EventKind::Audit {
+                actor,
+                action,
+                peer_id: String::new(),
+                detail: name.clone(),
+            });
+            let mut evicted = Vec::new();
+            if disabled {
+                let live = live_peer_map(&state);
+                for id in ids {
+                    hbbs::disconnect_peer(&id, hbbs::DisconnectReason::UserDisabled, "");
+                    // udp peers have no connection to close, see ban_peer
+                    if let Some(pm) = &live {
+                        if pm.evict(&id).await {
+                            evicted.push(id);
+                        }
+                    }
+                }
+            }
+            (Some(UserSwitchResponse { user, evicted }), None)
+        }
+        Err(e) => {
+            hbb_common::log::error!("API: Failed to {} {}: {}", action, name, e);
+            (None, Some(format!("Database error: {}", e)))
+        }
+    };
+    Ok(Json(ApiResponse {
+        success: data.is_some(),
+        data,
+        error,
+        timestamp: get_current_timestamp(),
+    }))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct BroadcastRequest {
+    pub message: String,
+    /// Unix seconds
+    pub expires_at: i64,
+    /// "all", "tag" or "ids"
+    pub target: String,
+    pub tag: Option<String>,
+    pub ids: Option<Vec<String>>,
+}
+
+/// Queue a text notice for the matching peers' next registration responses
+/// POST /api/admin/broadcast
+/// Body: { "message": "Server restarting at 22:00", "expires_at": 1760000000, "target": "all" }
+pub(crate) async fn post_broadcast(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+    Json(mut req): Json<BroadcastRequest>,
+) -> Result<Json<ApiResponse<hbbs::Broadcast>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+    if let Some(ids) = req.ids.as_mut() {
+        for id in ids.iter_mut() {
+            *id = canonical_pattern(id);
+        }
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let checked = crate::broadcast::check_message(&req.message)
+        .and_then(|_| crate::broadcast::check_expiry(req.expires_at, now))
+        .and_then(|_| {
+            crate::broadcast::check_target(&req.target, req.tag.as_deref(), req.ids.as_deref())
+        });
+    let (data, error) = match checked {
+        Err(problem) => (None, Some(problem)),
+        Ok((target, value)) => match crate::apistats::query(crate::broadcast::create(
+            &state.db_pool,
+            &req.message,
+            target,
+            &value,
+            req.expires_at,
+        ))
+        .await
+        {
+            Ok(broadcast) => {
+                hbb_common::log::info!(
+                    "API: Broadcast {} to {} {} until {}",
+                    broadcast.id,
+                    broadcast.target,
+                    broadcast.target_value,
+                    broadcast.expires_at
+                );
+                (Some(broadcast), None)
+            }
+            Err(e) => {
+                hbb_common::log::error!("API: Failed to store broadcast: {}", e);
+                (None, Some(format!("Database error: {}", e)))
+            }
+        },
+    };
+    Ok(Json(ApiResponse {
+        success: data.is_some(),
+        data,
+        error,
+        timestamp: get_current_timestamp(),
+    }))
+}
+
+/// Delivery progress of a broadcast
+/// GET /api/admin/broadcast/:id
+pub(crate) async fn get_broadcast(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+    Path(broadcast_id): Path<i64>,
+) -> Result<Json<ApiResponse<crate::broadcast::Progress>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::ReadOnly)?;
+
+    match crate::apistats::query(crate::broadcast::progress(&state.read_pool, broadcast_id)).await {
+        Ok(Some(progress)) => Ok(Json(ApiResponse {
+            success: true,
+            data: Some(progress),
+            error: None,
+            timestamp: get_current_timestamp(),
+        })),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            hbb_common::log::error!("API: Database query failed: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct WebhookRequest {
+    pub url: String,
+    /// Peer tags routed to the webhook; empty: every peer
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Event types routed to the webhook; empty: every type
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// {{id}}, {{note}} and {{event}} substituted; empty: the event's JSON
+    #[serde(default)]
+    pub template: String,
+    /// Send {"text": ...} for Slack-compatible incoming webhooks
+    #[serde(default)]
+    pub slack: bool,
+}
+
+/// Webhooks with their filters and delivery stats; admin only, as the URLs of
+/// incoming webhooks are secrets
+/// GET /api/webhooks
+pub(crate) async fn get_webhooks(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+) -> Result<Json<ApiResponse<Vec<crate::webhook::Webhook>>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+
+    match crate::apistats::query(crate::webhook::list(&state.read_pool)).await {
+        Ok(hooks) => Ok(Json(ApiResponse {
+            success: true,
+            data: Some(hooks),
+            error: None,
+            timestamp: get_current_timestamp(),
+        })),
+        Err(e) => {
+            hbb_common::log::error!("API: Database query failed: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Add a webhook
+/// POST /api/webhooks
+/// Body: { "url": "https://hooks.slack.com/services/...", "tags": ["office"],
+///         "events": ["offline"], "template": "{{id}} ({{note}}) went {{event}}", "slack": true }
+pub(crate) async fn post_webhook(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+    Json(req): Json<WebhookRequest>,
+) -> Result<Json<ApiResponse<crate::webhook::Webhook>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+
+    let checked = crate::webhook::check_url(&req.url)
+        .and_then(|_| crate::webhook::check_filters(&req.tags, &req.events))
+        .and_then(|_| crate::webhook::check_template(&req.template));
+    let (data, error) = match checked {
+        Err(problem) => (None, Some(problem)),
+        Ok(()) => match crate::apistats::query(crate::webhook::add(
+            &state.db_pool,
+            &req.url,
+            &req.tags,
+            &req.events,
+            &req.template,
+            req.slack,
+        ))
+        .await
+        {
+            Ok(hook) => {
+                hbb_common::log::info!(
+                    "API: Webhook {} added for tags {:?}, events {:?}",
+                    hook.id,
+                    hook.tags,
+                    hook.events
+                );
+                (Some(hook), None)
+            }
+            Err(e) => {
+                hbb_common::log::error!("API: Failed to add webhook: {}", e);
+                (None, Some(format!("Database error: {}", e)))
+            }
+        },
+    };
+    Ok(Json(ApiResponse {
+        success: data.is_some(),
+        data,
+        error,
+        timestamp: get_current_timestamp(),
+    }))
+}
+
+/// DELETE /api/webhooks/:id
+pub(crate) async fn delete_webhook(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+    Path(webhook_id): Path<i64>,
+) -> Result<Json<ApiResponse<crate::webhook::Webhook>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+
+    let (data, error) =
+        match crate::apistats::query(crate::webhook::remove(&state.db_pool, webhook_id)).await {
+            Ok(Some(hook)) => {
+                hbb_common::log::info!("API: Webhook {} removed", hook.id);
+                (Some(hook), None)
+            }
+            Ok(None) => (None, Some(format!("No webhook {}", webhook_id))),
+            Err(e) => {
+                hbb_common::log::error!("API: Failed to remove webhook: {}", e);
+                (None, Some(format!("Database error: {}", e)))
+            }
+        };
+    Ok(Json(ApiResponse {
+        success: data.is_some(),
+        data,
+        error,
+        timestamp: get_current_timestamp(),
+    }))
+}
+
+/// RFC1918, loopback and link-local addresses (and their IPv6 counterparts) count as LAN
+fn is_lan_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => v4.is_private() || v4.is_loopback() || v4.is_link_local(),
+        IpAddr::V6(v6) => {
+            v6.is_loopback()
+                || (v6.segments()[0] & 0xfe00) == 0xfc00 // unique local
+                || (v6.segments()[0] & 0xffc0) == 0xfe80 // link local
+        }
+    }
+}
+
+/// The public listing is only served when the API is reachable from the LAN alone:
+/// a wildcard bind counts as every interface address
+pub(crate) fn public_list_bind_check(
+    bind: IpAddr,
+    interfaces: &[IpAddr],
+    allow_wan: bool,
+) -> Result<(), String> {
+    if allow_wan {
+        return Ok(());
+    }
+    let exposed: Vec<IpAddr> = if bind.is_unspecified() {
+        interfaces.to_vec()
+    } else {
+        vec![bind]
+    };
+    match exposed.into_iter().find(|ip| !is_lan_ip(*ip)) {
+        Some(ip) => Err(format!("API is bound to non-private address {}", ip)),
+        None => Ok(()),
+    }
+}
+
+/// Strip a peer down to what the public listing may show
+pub(crate) fn public_peer(
+    mode: PublicPeerList,
+    id: String,
+    note: Option<String>,
+    online: bool,
+) -> PublicPeer {
+    match mode {
+        PublicPeerList::Ids => PublicPeer {
+            id: Some(id),
+            note: None,
+            online,
+        },
+        PublicPeerList::Notes => PublicPeer {
+            id: None,
+            note: Some(note.unwrap_or_default()),
+            online,
+        },
+    }
+}
+
+fn public_rate_allowed(ip: IpAddr) -> bool {
+    let limit = std::env::var("PUBLIC_PEER_LIST_RATE")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(PUBLIC_PEER_LIST_RATE);
+    let mut lock = match PUBLIC_RATE.lock() {
+        Ok(lock) => lock,
+        Err(_) => return false,
+    };
+    if lock.len() >= PUBLIC_RATE_MAX_IPS {
+        lock.retain(|_, (since, _)| since.elapsed().as_secs() < 60);
+        if lock.len() >= PUBLIC_RATE_MAX_IPS {
+            return false;
+        }
+    }
+    let entry = lock.entry(ip).or_insert((Instant::now(), 0));
+    if entry.0.elapsed().as_secs() >= 60 {
+        *entry = (Instant::now(), 0);
+    }
+    entry.1 += 1;
+    entry.1 <= limit
+}
+
+/// Which machines are online, for kiosk displays on the LAN; no API key
+/// GET /api/public/peers (only with --public-peer-list)
+async fn get_public_peers(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(state): Extension<Arc<ApiState>>,
+) -> Result<Json<ApiResponse<Vec<PublicPeer>>>, StatusCode> {
+    let mode = state.public_peer_list.ok_or(StatusCode::NOT_FOUND)?;
+    if !public_rate_allowed(addr.ip()) {
+        hbb_common::log::warn!("API: Public peer list rate limit hit by {}", addr.ip());
+        return Err(StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    match crate::apistats::query(
+        sqlx::query("SELECT id, note, last_online FROM peer WHERE is_deleted = 0 ORDER BY id")
+            .fetch_all(&state.read_pool),
+    )
+    .await
+    {
+        Ok(rows) => Ok(Json(ApiResponse {
+            success: true,
+            data: Some(
+                rows.iter()
+                    .map(|row| {
+                        let last_online: Option<String> = row.get("last_online");
+                        public_peer(
+                            mode,
+                            row.get("id"),
+                            row.get("note"),
+                            is_online_recently(&last_online, ONLINE_TIMEOUT_SECS),
+                        )
+                    })
+                    .collect(),
+            ),
+            error: None,
+            timestamp: get_current_timestamp(),
+        })),
+        Err(e) => {
+            hbb_common::log::error!("API: Database query failed: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Per-peer uptime over a window, from the status-event log, with the aggregate
+/// over all peers or those carrying `tag`
+/// GET /api/reports/uptime?from=&to= (RFC3339 or unix seconds, default: last 30 days)&tag=
+async fn get_uptime_report(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Query(params): Query<UptimeParams>,
+    Extension(state): Extension<Arc<ApiState>>,
+) -> Result<Json<ApiResponse<crate::uptime::UptimeReport>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::ReadOnly)?;
+
+    let fail = |error: String| {
+        Ok(Json(ApiResponse {
+            success: false,
+            data: None,
+            error: Some(error),
+            timestamp: get_current_timestamp(),
+        }))
+    };
+    let tag = params.tag.as_deref().map(str::trim);
+    if tag == Some("") {
+        return fail("Empty tag".to_string());
+    }
+    let (from, to) = match crate::uptime::window(params.from.as_deref(), params.to.as_deref()) {
+        Ok(window) => window,
+        Err(e) => return fail(e),
+    };
+
+    let report = crate::uptime::uptime_report(&state.read_pool, from, to, tag);
+    match crate::apistats::query(report).await {
+        Ok(report) => Ok(Json(ApiResponse {
+            success: true,
+            data: Some(report),
+            error: None,
+            timestamp: get_current_timestamp(),
+        })),
+        Err(e @ crate::uptime::ReportError::TooManyArchives(_)) => fail(e.to_string()),
+        Err(e) => {
+            hbb_common::log::error!("API: Uptime report failed: {}", e);
+            fail(e.to_string())
+        }
+    }
+}
+
+/// Client versions seen in registrations and punch holes, per transport
+/// GET /api/reports/protocol-versions?days=7
+pub(crate) async fn get_protocol_versions(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Query(params): Query<ProtocolVersionParams>,
+    Extension(state): Extension<Arc<ApiState>>,
+) -> Result<Json<ApiResponse<ProtocolVersionReport>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::ReadOnly)?;
+
+    let days = params.days.unwrap_or(7).clamp(1, 366);
+    let since = (chrono::Utc::now() - chrono::Duration::days(days as i64 - 1))
+        .format("%Y-%m-%d")
+        .to_string();
+    let rows = crate::apistats::query(
+        sqlx::query(
+            "SELECT day, kind, transport, version, count FROM protocol_versions
+             WHERE day >= ? ORDER BY day DESC, kind, transport, version",
+        )
+        .bind(&since)
+        .fetch_all(&state.read_pool),
+    )
+    .await
+    .map_err(|e| {
+        hbb_common::log::error!("API: Protocol version report failed: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let days = rows
+        .iter()
+        .map(|row| ProtocolVersionDay {
+            day: row.get("day"),
+            kind: row.get("kind"),
+            transport: row.get("transport"),
+            version: row.get("version"),
+            count: row.get("count"),
+        })
+        .collect();
+
+    Ok(Json(ApiResponse {
+        success: true,
+        data: Some(ProtocolVersionReport {
+            deprecated: hbbs::deprecated_versions(),
+            since_start: hbbs::protocol_versions(),
+            days,
+            deprecated_peers: hbbs::deprecated_version_peers(),
+        }),
+        error: None,
+        timestamp: get_current_timestamp(),
+    }))
+}
+
+/// Periods from..=to of an active peers report, as stored: YYYY-MM-DD days or
+/// YYYY-MM months
+fn active_periods(
+    monthly: bool,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Result<Vec<String>, String> {
+    use chrono::Datelike;
+    let parse = |value: &str| {
+        let date = if monthly {
+            format!("{}-01", value)
+        } else {
+            value.to_string()
+        };
+        chrono::NaiveDate::parse_from_str(&date, "%Y-%m-%d")
+            .map_err(|_| format!("Invalid period '{}'", value))
+    };
+    let month_index = |date: chrono::NaiveDate| date.year() * 12 + date.month0() as i32;
+    let today = chrono::Utc::now().date_naive();
+    let to = match to {
+        Some(to) => parse(to)?,
+        None => today,
+    };
+    let from = match from {
+        Some(from) => parse(from)?,
+        None if monthly => {
+            let index = month_index(to) - 11;
+            chrono::NaiveDate::from_ymd_opt(
+                index.div_euclid(12),
+                index.rem_euclid(12) as u32 + 1,
+                1,
+            )
+            .unwrap_or(to)
+        }
+        None => to - chrono::Duration::days(29),
+    };
+    if from > to {
+        return Err("from is after to".to_string());
+    }
+    if monthly {
+        let (first, last) = (month_index(from), month_index(to));
+        if last - first >= 120 {
+            return Err("At most 120 months per report".to_string());
+        }
+        Ok((first..=last)
+            .map(|index| {
+                format!(
+                    "{:04}-{:02}",
+                    index.div_euclid(12),
+                    index.rem_euclid(12) + 1
+                )
+            })
+            .collect())
+    } else {
+        if (to - from).num_days() >= 366 {
+            return Err("At most 366 days per report".to_string());
+        }
+        Ok(from
+            .iter_days()
+            .take_while(|day| *day <= to)
+            .map(|day| day.format("%Y-%m-%d").to_string())
+            .collect())
+    }
+}
+
+/// Unique peers seen per UTC day or month, counted on their first heartbeat
+/// of the day. Marks reach the database every ACTIVE_FLUSH_SECS.
+/// GET /api/reports/active-peers?granularity=day|month&from=&to=
+pub(crate) async fn get_active_peers(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Query(params): Query<ActivePeersParams>,
+    Extension(state): Extension<Arc<ApiState>>,
+) -> Result<Json<ApiResponse<ActivePeersReport>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::ReadOnly)?;
+
+    let fail = |error: String| {
+        Ok(Json(ApiResponse {
+            success: false,
+            data: None,
+            error: Some(error),
+            timestamp: get_current_timestamp(),
+        }))
+    };
+    let granularity = params.granularity.unwrap_or_else(|| "day".to_string());
+    let (table, column) = match granularity.as_str() {
+        "day" => ("active_peers", "day"),
+        "month" => ("active_peers_month", "month"),
+        _ => return fail("granularity must be day or month".to_string()),
+    };
+    let periods = match active_periods(
+        column == "month",
+        params.from.as_deref(),
+        params.to.as_deref(),
+    ) {
+        Ok(periods) => periods,
+        Err(e) => return fail(e),
+    };
+    let (from, to) = (periods[0].clone(), periods[periods.len() - 1].clone());
+
+    let counts = async {
+        let rows = sqlx::query(&format!(
+            "SELECT {column} AS period, COUNT(*) AS peers FROM {table}
+             WHERE {column} BETWEEN ? AND ? GROUP BY {column}"
+        ))
+        .bind(&from)
+        .bind(&to)
+        .fetch_all(&state.read_pool)
+        .await?;
+        let total: i64 = sqlx::query_scalar(&format!(
+            "SELECT COUNT(DISTINCT id) FROM {table} WHERE {column} BETWEEN ? AND ?"
+        ))
+        .bind(&from)
+        .bind(&to)
+        .fetch_one(&state.read_pool)
+        .await?;
+        Ok::<_, sqlx::Error>((rows, total))
+    };
+    let (rows, total) = match crate::apistats::query(counts).await {
+        Ok(counts) => counts,
+        Err(e) => {
+            hbb_common::log::error!("API: Active peers report failed: {}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    let counts: HashMap<String, i64> = rows
+        .iter()
+        .map(|row| (row.get("period"), row.get("peers")))
+        .collect();
+
+    Ok(Json(ApiResponse {
+        success: true,
+        data: Some(ActivePeersReport {
+            granularity,
+            from,
+            to,
+            periods: periods
+                .into_iter()
+                .map(|period| ActivePeriod {
+                    peers: counts.get(&period).copied().unwrap_or(0),
+                    period,
+                })
+                .collect(),
+            total,
+        }),
+        error: None,
+        timestamp: get_current_timestamp(),
+    }))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct JobRequest {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub params: serde_json::Value,
+}
+
+/// Queue a report too large for one request; poll GET /api/jobs/:id for it
+/// POST /api/jobs
+/// Body: { "type": "uptime_report", "params": { "from": "...", "to": "..." } }
+///    or { "type": "export" } (the peers as /api/sync records, NDJSON)
+pub(crate) async fn post_job(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+    Json(request): Json<JobRequest>,
+) -> Result<Json<ApiResponse<crate::jobs::Job>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+
+    let submitted = crate::apistats::query(crate::jobs::submit(
+        &state.db_pool,
+        &request.kind,
+        request.params,
+    ))
+    .await;
+    let (data, error) = match submitted {
+        Ok(job) => {
+            hbb_common::log::info!("API: Job {} ({}) queued", job.id, job.kind);
+            (Some(job), None)
+        }
+        Err(e) => (None, Some(e)),
+    };
+    Ok(Json(ApiResponse {
+        success: data.is_some(),
+        data,
+        error,
+        timestamp: get_current_timestamp(),
+    }))
+}
+
+/// Status and progress of a job
+/// GET /api/jobs/:id
+pub(crate) async fn get_job(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+    Path(id): Path<i64>,
+) -> Result<Json<ApiResponse<crate::jobs::Job>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::ReadOnly)?;
+
+    match crate::apistats::query(crate::jobs::get(&state.read_pool, id)).await {
+        Ok(Some(job)) => Ok(Json(ApiResponse {
+            success: true,
+            data: Some(job),
+            error: None,
+            timestamp: get_current_timestamp(),
+        })),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => Ok(Json(ApiResponse {
+            success: false,
+            data: None,
+            error: Some(format!("Database error: {}", e)),
+            timestamp: get_current_timestamp(),
+        })),
+    }
+}
+
+/// The result of a finished job, streamed from its file; 409 until it is done,
+/// 410 once pruned
+/// GET /api/jobs/:id/result
+pub(crate) async fn get_job_result(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+    Path(id): Path<i64>,
+) -> Result<Response, StatusCode> {
+    use axum::http::header;
+    use hbb_common::tokio::io::AsyncReadExt;
+    verify_scope(&headers, &bearer, &state, Role::ReadOnly)?;
+
+    let job = crate::apistats::query(crate::jobs::get(&state.read_pool, id))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if job.status != "done" {
+        return Err(StatusCode::CONFLICT);
+    }
+    let dir = crate::jobs::dir().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let path = crate::jobs::artifact(&dir, id, &job.kind);
+    let file = hbb_common::tokio::fs::File::open(&path)
+        .await
+        .map_err(|_| StatusCode::GONE)?;
+    let chunks = hbb_common::futures_util::stream::unfold(file, |mut file| async move {
+        let mut buf = vec![0; 64 * 1024];
+        match file.read(&mut buf).await {
+            Ok(0) => None,
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(axum::body::Bytes::from(buf)), file))
+            }
+            Err(e) => Some((Err(e), file)),
+        }
+    });
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    Ok((
+        [
+            (header::CONTENT_TYPE, job.content_type().to_owned()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", name),
+            ),
+        ],
+        axum::body::StreamBody::new(chunks),
+    )
+        .into_response())
+}
+
+/// Cancel a queued or running job (a running one stops cooperatively), or
+/// remove a finished one with its result
+/// DELETE /api/jobs/:id
+pub(crate) async fn delete_job(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+    Path(id): Path<i64>,
+) -> Result<Json<ApiResponse<crate::jobs::Job>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+
+    let dir = crate::jobs::dir().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    match crate::apistats::query(crate::jobs::cancel(&state.db_pool, &dir, id)).await {
+        Ok(Some(job)) => {
+            hbb_common::log::info!("API: Job {} cancelled or removed ({})", id, job.status);
+            Ok(Json(ApiResponse {
+                success: true,
+                data: Some(job),
+                error: None,
+                timestamp: get_current_timestamp(),
+            }))
+        }
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => Ok(Json(ApiResponse {
+            success: false,
+            data: None,
+            error: Some(format!("Database error: {}", e)),
+            timestamp: get_current_timestamp(),
+        })),
+    }
+}
+
+/// Server-wide counters kept by the rendezvous server
+/// GET /api/stats
+pub(crate) async fn get_stats(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+) -> Result<Json<ApiResponse<ServerStats>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::ReadOnly)?;
+
+    let relay_reasons = hbbs::relay_reason_counts()
+        .into_iter()
+        .map(|(reason, n)| (reason.as_str().to_string(), n))
+        .collect();
+    // live when the PeerMap is shared, else the last periodic snapshot
+    let peer_stats = match live_peer_map(&state) {
+        Some(pm) => pm.stats().await,
+        None => hbbs::peer_stats(),
+    };
+    let peers = PeerHealthCounts {
+        total: peer_stats.total,
+        healthy: peer_stats.healthy,
+        degraded: peer_stats.degraded,
+        critical: peer_stats.critical,
+    };
+    let mut online_by_transport: HashMap<String, usize> = ["udp", "tcp", "ws"]
+        .iter()
+        .map(|x| (x.to_string(), 0))
+        .collect();
+    for (transport, n) in peer_stats.transports {
+        online_by_transport.insert(transport.to_string(), n);
+    }
+
+    Ok(Json(ApiResponse {
+        success: true,
+        data: Some(ServerStats {
+            peers,
+            heartbeat_interval_secs: hbbs::heartbeat_interval_secs(),
+            peer_timeout_secs: hbbs::peer_timeout_secs(),
+            relay_reasons,
+            malformed_credentials: hbbs::malformed_credential_count(),
+            online_by_transport,
+            peer_sync: crate::peersync::status(),
+            drain: hbbs::current_drain(),
+            status_writer: hbbs::status_writer_stats(),
+            banner: hbbs::server_banner(),
+            recovery: hbbs::recovery_status(),
+        }),
+        error: None,
+        timestamp: get_current_timestamp(),
+    }))
+}
+
+/// Device counts for a dashboard header, soft-deleted devices only in `deleted`
+#[derive(Serialize)]
+pub(crate) struct DeviceSummary {
+    total: i64,
+    online: i64,
+    offline: i64,
+    /// Under a ban in effect, temporary ones until they run out
+    banned: i64,
+    deleted: i64,
+    /// Registered since `registered_since`, 24 hours ago
+    registered_24h: i64,
+    registered_since: String,
+}
+
+/// Device counts from a few indexed COUNT queries, online as GET /api/peers reports it
+/// GET /api/summary
+pub(crate) async fn get_summary(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+) -> Result<Json<Sourced<DeviceSummary>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::ReadOnly)?;
+
+    let live = live_peer_map(&state);
+    let now = chrono::Utc::now();
+    let since = now - chrono::Duration::days(1);
+    // a date prefix narrows the julianday check to a range of the last_online
+    // index; a day early, for RFC3339 values written with a negative offset
+    let (online, online_bind) = match &live {
+        Some(pm) => (
+            "id IN (SELECT value FROM json_each(?))".to_owned(),
+            serde_json::to_string(&pm.online_ids().await).unwrap_or_default(),
+        ),
+        None => (
+            format!(
+                "last_online >= ? AND julianday(last_online) > julianday('now', '-{} seconds')",
+                ONLINE_TIMEOUT_SECS
+            ),
+            (now - chrono::Duration::days(1))
+                .format("%Y-%m-%d")
+                .to_string(),
+        ),
+    };
+    let sql = format!(
+        "SELECT (SELECT count(*) FROM peer WHERE is_deleted = 0) AS total,
+                (SELECT count(*) FROM peer WHERE is_deleted = 0 AND {}) AS online,
+                (SELECT count(*) FROM peer WHERE is_deleted = 0 AND {}) AS banned,
+                (SELECT count(*) FROM peer WHERE is_deleted != 0) AS deleted,
+                (SELECT count(*) FROM peer
+                 WHERE created_at >= datetime(?, 'unixepoch') AND is_deleted = 0) AS registered",
+        online, BAN_ACTIVE
+    );
+    let counts = sqlx::query(&sql)
+        .bind(online_bind)
+        .bind(now.timestamp())
+        .bind(since.timestamp())
+        .fetch_one(&state.read_pool);
+    let (data, error) = match crate::apistats::query(counts).await {
+        Ok(row) => {
+            let total: i64 = row.get("total");
+            let online: i64 = row.get("online");
+            let summary = DeviceSummary {
+                total,
+                online,
+                offline: total - online,
+                banned: row.get("banned"),
+                deleted: row.get("deleted"),
+                registered_24h: row.get("registered"),
+                registered_since: since.to_rfc3339(),
+            };
+            (Some(summary), None)
+        }
+        Err(e) => (None, Some(format!("Database error: {}", e))),
+    };
+    Ok(Json(sourced(
+        &live,
+        ApiResponse {
+            success: data.is_some(),
+            data,
+            error,
+            timestamp: get_current_timestamp(),
+        },
+    )))
+}
+
+/// Bytes through the TCP/websocket rendezvous connections: totals since start and
+/// the open connections carrying the most, by peer id where known
+/// GET /api/stats/network?top=10
+async fn get_network_stats(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+    Query(params): Query<NetworkParams>,
+) -> Result<Json<ApiResponse<NetworkUsage>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::ReadOnly)?;
+
+    let stats = hbbs::network_stats(params.top.unwrap_or(10).min(100));
+    let uptime = state.start_time.elapsed().as_secs().max(1);
+
+    Ok(Json(ApiResponse {
+        success: true,
+        data: Some(NetworkUsage {
+            avg_bytes_per_sec: (stats.read_bytes + stats.written_bytes) / uptime,
+            stats,
+        }),
+        error: None,
+        timestamp: get_current_timestamp(),
+    }))
+}
+
+/// Entry counts and approximate sizes of the server's in-memory structures,
+/// with the process RSS and any soft caps from MEMORY_SOFT_CAPS
+/// GET /api/stats/memory
+async fn get_memory_stats(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+) -> Result<Json<ApiResponse<hbbs::MemoryStats>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::ReadOnly)?;
 
-fn get_current_timestamp() -> String {
-    chrono::Utc::now().to_rfc3339()
+    Ok(Json(ApiResponse {
+        success: true,
+        data: Some(hbbs::memory_stats()),
+        error: None,
+        timestamp: get_current_timestamp(),
+    }))
 }
 
-/// Check if a timestamp string is within the last N seconds (default 60s)
-/// Supports formats: "YYYY-MM-DD HH:MM:SS" (SQLite) and RFC3339
-fn is_online_recently(timestamp: &Option<String>, timeout_secs: i64) -> bool {
-    match timestamp {
-        Some(ts) => {
-            // Try SQLite format first: "2026-02-06 14:00:27"
-            if let Ok(dt) = chrono::NaiveDateTime::parse_from_str(ts, "%Y-%m-%d %H:%M:%S") {
-                let now = chrono::Utc::now().naive_utc();
-                let diff = now.signed_duration_since(dt);
-                return diff.num_seconds() < timeout_secs;
-            }
-            // Try RFC3339 format
-            if let Ok(dt) = chrono::DateTime::parse_from_rfc3339(ts) {
-                let now = chrono::Utc::now();
-                let diff = now.signed_duration_since(dt);
-                return diff.num_seconds() < timeout_secs;
-            }
-            // If we can't parse, assume offline
-            false
-        }
-        None => false,
-    }
-}
+/// Latency and error counts per API route since start
+/// GET /api/stats/api
+async fn get_api_stats(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+) -> Result<Json<ApiResponse<Vec<crate::apistats::RouteUsage>>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::ReadOnly)?;
 
-/// Default timeout for online status (60 seconds)
-const ONLINE_TIMEOUT_SECS: i64 = 60;
+    Ok(Json(ApiResponse {
+        success: true,
+        data: Some(crate::apistats::usage()),
+        error: None,
+        timestamp: get_current_timestamp(),
+    }))
+}
 
-async fn get_online_peers(
+/// Fleet health score, its components and the last 24 hours of scores
+/// GET /api/stats/health-score
+pub(crate) async fn get_health_score(
     headers: HeaderMap,
+    bearer: Bearer,
     Extension(state): Extension<Arc<ApiState>>,
-) -> Result<Json<ApiResponse<Vec<PeerStatus>>>, StatusCode> {
-    verify_api_key(&headers, &state)?;
-    
-    hbb_common::log::debug!("API: Fetching all peers");
-    
-    match sqlx::query(
-        "SELECT id, note, last_online FROM peer WHERE is_deleted = 0"
-    )
-    .fetch_all(&state.db_pool)
-    .await
-    {
-        Ok(rows) => {
-            let mut peers: Vec<PeerStatus> = Vec::new();
-            
-            for row in rows.iter() {
-                let id: String = row.get("id");
-                let note: Option<String> = row.get("note");
-                let last_online: Option<String> = row.get("last_online");
-                let online = is_online_recently(&last_online, ONLINE_TIMEOUT_SECS);
-                
-                peers.push(PeerStatus {
-                    id,
-                    note,
-                    online,
-                    last_online,
-                });
-            }
-            
-            hbb_common::log::info!("API: Returned {} peers", peers.len());
+) -> Result<Json<ApiResponse<crate::healthscore::Report>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::ReadOnly)?;
 
-            Ok(Json(ApiResponse {
-                success: true,
-                data: Some(peers),
-                error: None,
-                timestamp: get_current_timestamp(),
-            }))
-        }
-        Err(e) => {
-            hbb_common::log::error!("API: Database query failed: {}", e);
-            Ok(Json(ApiResponse {
-                success: false,
-                data: None,
-                error: Some(format!("Database error: {}", e)),
-                timestamp: get_current_timestamp(),
-            }))
-        }
-    }
+    Ok(Json(ApiResponse {
+        success: true,
+        data: Some(crate::healthscore::report()),
+        error: None,
+        timestamp: get_current_timestamp(),
+    }))
 }
 
-async fn health_check(
+/// Relay decisions recorded for a single peer (as the connection target)
+/// GET /api/peers/:id/conn-stats
+async fn get_peer_conn_stats(
     headers: HeaderMap,
+    bearer: Bearer,
     Extension(state): Extension<Arc<ApiState>>,
-) -> Result<Json<ApiResponse<HealthStatus>>, StatusCode> {
-    verify_api_key(&headers, &state)?;
-    
-    let uptime = state.start_time.elapsed().as_secs();
-    
+    Path(peer_id): Path<String>,
+) -> Result<Json<ApiResponse<ConnStats>>, StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::ReadOnly)?;
+    let peer_id = hbbs::canonical_id(&peer_id).into_owned();
+
+    let stats = hbbs::peer_relay_stats(&peer_id).await.unwrap_or_default();
+
     Ok(Json(ApiResponse {
         success: true,
-        data: Some(HealthStatus {
-            status: "running".to_string(),
-            uptime_seconds: uptime,
-            version: "2.0.0".to_string(),
+        data: Some(ConnStats {
+            id: peer_id,
+            last_relay_reason: stats.last_reason.map(|r| r.as_str().to_string()),
+            last_relay_from: stats.last_reason.map(|_| stats.last_from.clone()),
+            last_relay_server: stats.last_reason.map(|_| stats.last_relay.clone()),
+            last_relay_at: stats.last_at.map(|t| t.to_rfc3339()),
+            relay_reasons: stats
+                .counts
+                .iter()
+                .map(|(reason, n)| (reason.as_str().to_string(), *n))
+                .collect(),
         }),
         error: None,
         timestamp: get_current_timestamp(),
     }))
 }
 
-async fn get_peer_details(
-    headers: HeaderMap,
-    Extension(state): Extension<Arc<ApiState>>,
-    axum::extract::Path(peer_id): axum::extract::Path<String>,
-) -> Result<Json<ApiResponse<PeerStatus>>, StatusCode> {
-    verify_api_key(&headers, &state)?;
-    
-    hbb_common::log::debug!("API: Fetching details for peer {}", peer_id);
-    
-    match sqlx::query(
-        "SELECT id, note, last_online FROM peer WHERE id = ? AND is_deleted = 0"
-    )
-    .bind(&peer_id)
-    .fetch_optional(&state.db_pool)
-    .await
-    {
-        Ok(Some(row)) => {
-            let id: String = row.get("id");
-            let note: Option<String> = row.get("note");
-            let last_online: Option<String> = row.get("last_online");
-            let online = is_online_recently(&last_online, ONLINE_TIMEOUT_SECS);
-            
-            Ok(Json(ApiResponse {
-                success: true,
-                data: Some(PeerStatus {
-                    id,
-                    note,
-                    online,
-                    last_online,
-                }),
-                error: None,
-                timestamp: get_current_timestamp(),
-            }))
-        }
-        Ok(None) => {
-            Ok(Json(ApiResponse {
-                success: false,
-                data: None,
-                error: Some(format!("Peer {} not found", peer_id)),
-                timestamp: get_current_timestamp(),
-            }))
-        }
-        Err(e) => {
-            hbb_common::log::error!("API: Database query failed: {}", e);
-            Ok(Json(ApiResponse {
-                success: false,
-                data: None,
-                error: Some(format!("Database error: {}", e)),
-                timestamp: get_current_timestamp(),
-            }))
-        }
-    }
+/// Trace length without `seconds`
+const DEFAULT_TRACE_SECS: i64 = 600;
+
+#[derive(Deserialize, Default)]
+pub(crate) struct TraceRequest {
+    /// 1 to hbbs::MAX_TRACE_SECS
+    pub seconds: Option<i64>,
+    /// Keep the traced messages for GET /api/peers/:id/trace
+    pub capture: Option<bool>,
 }
 
-/// Change peer ID (admin endpoint)
-/// POST /api/peers/:id/change-id
-/// Body: { "new_id": "NEW123456" }
-async fn change_peer_id(
+/// Log every message to or from one peer at info, by its id and address, for
+/// a while; with capture also keep the last ones in memory. Replaces a trace
+/// of the peer in effect. 404 for an unknown id.
+/// POST /api/peers/:id/trace
+/// Body: { "seconds": 600, "capture": true }
+pub(crate) async fn trace_peer(
     headers: HeaderMap,
+    bearer: Bearer,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     Extension(state): Extension<Arc<ApiState>>,
-    Path(old_id): Path<String>,
-    Json(payload): Json<ChangeIdRequest>,
-) -> Result<Json<ApiResponse<ChangeIdResponse>>, StatusCode> {
-    verify_api_key(&headers, &state)?;
-    
-    let new_id = payload.new_id.trim().to_uppercase();
-    let old_id = old_id.trim().to_uppercase();
-    
-    hbb_common::log::info!("API: Change ID request: {} -> {}", old_id, new_id);
-    
-    // Validate new ID format (6-16 chars, alphanumeric/dash/underscore)
-    if new_id.len() < 6 || new_id.len() > 16 {
-        return Ok(Json(ApiResponse {
-            success: false,
-            data: None,
-            error: Some("New ID must be 6-16 characters".to_string()),
-            timestamp: get_current_timestamp(),
-        }));
-    }
-    
-    if !new_id.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
-        return Ok(Json(ApiResponse {
+    Path(peer_id): Path<String>,
+    payload: Option<Json<TraceRequest>>,
+) -> Result<(StatusCode, Json<ApiResponse<hbbs::PeerTrace>>), StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+    let peer_id = hbbs::canonical_id(&peer_id).into_owned();
+    let fail = |status: StatusCode, error: String| {
+        let response = ApiResponse {
             success: false,
             data: None,
-            error: Some("New ID can only contain letters, numbers, dash and underscore".to_string()),
+            error: Some(error),
             timestamp: get_current_timestamp(),
-        }));
+        };
+        (status, Json(response))
+    };
+
+    let TraceRequest { seconds, capture } = payload.map(|Json(p)| p).unwrap_or_default();
+    let seconds = seconds.unwrap_or(DEFAULT_TRACE_SECS);
+    if !(1..=hbbs::MAX_TRACE_SECS).contains(&seconds) {
+        let error = format!("seconds {} is not 1 to {}", seconds, hbbs::MAX_TRACE_SECS);
+        return Ok(fail(StatusCode::BAD_REQUEST, error));
     }
-    
-    // Check if old_id exists
-    let old_peer = sqlx::query("SELECT previous_ids FROM peer WHERE id = ? AND is_deleted = 0")
-        .bind(&old_id)
-        .fetch_optional(&state.db_pool)
-        .await;
-    
-    let old_row = match old_peer {
-        Ok(Some(row)) => row,
+    let known = crate::apistats::query(
+        sqlx::query("SELECT 1 FROM peer WHERE id = ? AND is_deleted = 0")
+            .bind(&peer_id)
+            .fetch_optional(&state.read_pool),
+    )
+    .await;
+    match known {
+        Ok(Some(_)) => {}
         Ok(None) => {
-            return Ok(Json(ApiResponse {
-                success: false,
-                data: None,
-                error: Some(format!("Peer '{}' not found", old_id)),
-                timestamp: get_current_timestamp(),
-            }));
+            let error = format!("Peer '{}' not found", peer_id);
+            return Ok(fail(StatusCode::NOT_FOUND, error));
         }
         Err(e) => {
-            return Ok(Json(ApiResponse {
-                success: false,
-                data: None,
-                error: Some(format!("Database error: {}", e)),
-                timestamp: get_current_timestamp(),
-            }));
+            hbb_common::log::error!("API: Failed to trace {}: {}", peer_id, e);
+            return Ok(fail(StatusCode::OK, format!("Database error: {}", e)));
         }
-    };
-    
-    // Check if new_id already exists
-    let new_exists = sqlx::query("SELECT 1 FROM peer WHERE id = ? AND is_deleted = 0")
-        .bind(&new_id)
-        .fetch_optional(&state.db_pool)
-        .await;
-    
-    if let Ok(Some(_)) = new_exists {
-        return Ok(Json(ApiResponse {
-            success: false,
-            data: None,
-            error: Some(format!("ID '{}' is already in use", new_id)),
-            timestamp: get_current_timestamp(),
-        }));
     }
-    
-    // Get and update previous_ids
-    let previous_ids_str: String = old_row.try_get("previous_ids").unwrap_or_default();
-    let mut previous_ids: Vec<String> = if previous_ids_str.is_empty() {
-        Vec::new()
-    } else {
-        serde_json::from_str(&previous_ids_str).unwrap_or_default()
+    let peer_addr = match live_peer_map(&state) {
+        Some(pm) => pm.socket_addr(&peer_id).await,
+        None => None,
     };
-    previous_ids.push(old_id.clone());
-    let updated_history = serde_json::to_string(&previous_ids).unwrap_or_default();
-    
-    let now = get_current_timestamp();
-    
-    // Perform the update
-    let result = sqlx::query(
-        "UPDATE peer SET id = ?, previous_ids = ?, id_changed_at = ? WHERE id = ? AND is_deleted = 0"
-    )
-        .bind(&new_id)
-        .bind(&updated_history)
-        .bind(&now)
-        .bind(&old_id)
-        .execute(&state.db_pool)
-        .await;
-    
-    match result {
-        Ok(res) if res.rows_affected() > 0 => {
-            hbb_common::log::info!("API: ID changed successfully: {} -> {}", old_id, new_id);
-            Ok(Json(ApiResponse {
-                success: true,
-                data: Some(ChangeIdResponse {
-                    old_id,
-                    new_id,
-                    changed_at: now,
-                    previous_ids,
-                }),
-                error: None,
-                timestamp: get_current_timestamp(),
-            }))
-        }
-        Ok(_) => {
-            Ok(Json(ApiResponse {
-                success: false,
-                data: None,
-                error: Some("No rows affected".to_string()),
-                timestamp: get_current_timestamp(),
-            }))
-        }
-        Err(e) => {
-            hbb_common::log::error!("API: Failed to change ID: {}", e);
-            Ok(Json(ApiResponse {
-                success: false,
-                data: None,
-                error: Some(format!("Failed to change ID: {}", e)),
-                timestamp: get_current_timestamp(),
-            }))
-        }
+    let actor = api_actor(&state, &headers, addr);
+    let capture = capture.unwrap_or(false);
+    let until = chrono::Utc::now().timestamp() + seconds;
+    let trace = hbbs::start_peer_trace(&peer_id, peer_addr, until, capture, actor.clone());
+    // traced messages show the peer's traffic in the logs; audited as exposure
+    let detail = format!("{}s{}", seconds, if capture { " with capture" } else { "" });
+    let at = chrono::Utc::now().timestamp();
+    let audit = append_audit(&state.db_pool, at, &actor, "trace", &peer_id, &detail).await;
+    if let Err(e) = audit {
+        hbb_common::log::warn!("API: Cannot audit trace of {}: {}", peer_id, e);
     }
+    hbbs::emit_event(hbbs::EventKind::Audit {
+        actor,
+        action: "trace",
+        peer_id,
+        detail,
+    });
+    Ok((
+        StatusCode::OK,
+        Json(ApiResponse {
+            success: true,
+            data: Some(trace),
+            error: None,
+            timestamp: get_current_timestamp(),
+        }),
+    ))
+}
+
+/// The trace of a peer in effect, with the captured messages oldest first;
+/// 404 once it ended; admin keys only, like starting it
+/// GET /api/peers/:id/trace
+pub(crate) async fn get_peer_trace(
+    headers: HeaderMap,
+    bearer: Bearer,
+    Extension(state): Extension<Arc<ApiState>>,
+    Path(peer_id): Path<String>,
+) -> Result<(StatusCode, Json<ApiResponse<hbbs::PeerTrace>>), StatusCode> {
+    verify_scope(&headers, &bearer, &state, Role::Admin)?;
+    let peer_id = hbbs::canonical_id(&peer_id).into_owned();
+
+    let trace = hbbs::peer_trace(&peer_id);
+    let status = match trace {
+        Some(_) => StatusCode::OK,
+        None => StatusCode::NOT_FOUND,
+    };
+    Ok((
+        status,
+        Json(ApiResponse {
+            success: trace.is_some(),
+            error: match trace {
+                Some(_) => None,
+                None => Some(format!("No trace of '{}' in effect", peer_id)),
+            },
+            data: trace,
+            timestamp: get_current_timestamp(),
+        }),
+    ))
 }
 
 fn load_or_generate_api_key() -> String {
@@ -394,39 +5210,90 @@ fn load_or_generate_api_key() -> String {
         }
     }
     
-    use hbb_common::rand::Rng;
-    const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
-    let mut rng = hbb_common::rand::thread_rng();
-    let key: String = (0..64)
-        .map(|_| {
-            let idx = rng.gen_range(0..CHARSET.len());
-            CHARSET[idx] as char
-        })
-        .collect();
-    
-    if let Some(parent) = std::path::Path::new(&api_key_file).parent() {
-        let _ = fs::create_dir_all(parent);
-    }
-    
-    if let Err(e) = fs::write(&api_key_file, &key) {
+    let key = crate::apikey::generate();
+    if let Err(e) = crate::apikey::write_key_file(&api_key_file, &key) {
         hbb_common::log::warn!("API: Could not save API key: {}", e);
     } else {
         hbb_common::log::info!("API: Generated new API key saved to {}", api_key_file);
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
-            if let Ok(metadata) = fs::metadata(&api_key_file) {
-                let mut perms = metadata.permissions();
-                perms.set_mode(0o600);
-                let _ = fs::set_permissions(&api_key_file, perms);
-            }
-        }
     }
     
     key
 }
 
-pub async fn start_api_server(db_path: String, port: u16) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+/// HTTP API settings from the command line
+pub struct ApiConfig {
+    pub port: u16,
+    pub public_peer_list: Option<(PublicPeerList, bool)>,
+    /// `--api-require-if-match`
+    pub require_if_match: bool,
+    /// Bearer tokens from OIDC_INTROSPECTION_URL and friends
+    pub oidc: Option<crate::oidc::OidcConfig>,
+    /// `--api-rate-limit` and `--api-rate-burst`, None without a limit
+    pub rate_limit: Option<crate::ratelimit::Limit>,
+}
+
+impl ApiConfig {
+    /// None when the API is disabled with `--no-api`
+    pub fn from_args(
+        no_api: bool,
+        port: &str,
+        public_peer_list: &str,
+        allow_wan: bool,
+        require_if_match: bool,
+        rate_limit: (&str, &str),
+    ) -> Result<Option<Self>, String> {
+        let public_peer_list = PublicPeerList::parse(public_peer_list)?;
+        let rate_limit = crate::ratelimit::Limit::from_args(rate_limit.0, rate_limit.1)?;
+        if no_api {
+            if public_peer_list.is_some() {
+                return Err("--public-peer-list needs the API, drop --no-api".to_owned());
+            }
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            port: port.parse::<u16>().unwrap_or(crate::API_PORT),
+            public_peer_list: public_peer_list.map(|mode| (mode, allow_wan)),
+            require_if_match,
+            oidc: crate::oidc::OidcConfig::from_env()?,
+            rate_limit,
+        }))
+    }
+}
+
+/// Runs the API on its own thread and runtime. With the API disabled nothing is
+/// started: no listener, no database pools and no API key file
+pub fn spawn_api_thread(config: Option<ApiConfig>) -> Option<std::thread::JoinHandle<()>> {
+    let config = config?;
+    crate::oidc::configure(config.oidc);
+    crate::ratelimit::configure(config.rate_limit);
+    let db_path = std::env::current_dir()
+        .unwrap_or_default()
+        .join("db_v2.sqlite3")
+        .to_string_lossy()
+        .to_string();
+    Some(std::thread::spawn(move || {
+        let rt = hbb_common::tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            if let Err(e) = start_api_server(
+                db_path,
+                config.port,
+                config.public_peer_list,
+                config.require_if_match,
+            )
+            .await
+            {
+                hbb_common::log::error!("HTTP API failed: {}", e);
+            }
+        });
+    }))
+}
+
+pub async fn start_api_server(
+    db_path: String,
+    port: u16,
+    public_peer_list: Option<(PublicPeerList, bool)>,
+    require_if_match: bool,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     use sqlx::sqlite::SqliteConnectOptions;
     use std::str::FromStr;
     
@@ -441,7 +5308,10 @@ pub async fn start_api_server(db_path: String, port: u16) -> Result<(), Box<dyn
         }
     };
     
-    let pool = match SqlitePool::connect_with(connect_options).await {
+    let pool = match crate::apistats::pool_options()
+        .connect_with(connect_options)
+        .await
+    {
         Ok(p) => p,
         Err(e) => {
             hbb_common::log::warn!("API: Could not connect to database: {}. API will retry later.", e);
@@ -450,25 +5320,198 @@ pub async fn start_api_server(db_path: String, port: u16) -> Result<(), Box<dyn
             let opts = SqliteConnectOptions::from_str(&format!("sqlite://{}", db_path))?
                 .read_only(false)
                 .create_if_missing(false);
-            SqlitePool::connect_with(opts).await?
+            crate::apistats::pool_options().connect_with(opts).await?
+        }
+    };
+
+    let read_connections: u32 = std::env::var("MAX_DATABASE_CONNECTIONS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(5);
+    let read_pool = match crate::apistats::pool_options()
+        .max_connections(read_connections.max(1))
+        .connect_with(
+            SqliteConnectOptions::from_str(&format!("sqlite://{}", db_path))?
+                .read_only(true)
+                .create_if_missing(false),
+        )
+        .await
+    {
+        Ok(p) => p,
+        Err(e) => {
+            hbb_common::log::warn!(
+                "API: Could not open read-only pool: {}, sharing the write pool",
+                e
+            );
+            pool.clone()
         }
     };
+    {
+        let (write, read) = (pool.clone(), read_pool.clone());
+        let stats = |p: &SqlitePool| hbbs::PoolStats {
+            size: p.size() as _,
+            idle: p.num_idle(),
+            max_size: p.options().get_max_connections() as _,
+        };
+        hbbs::register_pool_stats("api_write", Box::new(move || stats(&write)));
+        hbbs::register_pool_stats("api_read", Box::new(move || stats(&read)));
+    }
+    hbbs::register_memory_source("log_ring", Box::new(crate::logs::memory));
+    hbbs::register_memory_source("sync_tokens", Box::new(crate::sync::memory));
+    hbbs::register_memory_source(
+        "public_rate",
+        Box::new(|| {
+            let ips = PUBLIC_RATE.try_lock().ok()?.len();
+            Some((ips, hbbs::map_entry_bytes::<IpAddr, (Instant, u32)>(0)))
+        }),
+    );
+    hbbs::register_memory_source("api_rate", Box::new(crate::ratelimit::memory));
+    crate::ratelimit::start();
     
     hbb_common::log::info!("API: Database connection pool created");
 
     let api_key = load_or_generate_api_key();
 
+    let public_peer_list = match public_peer_list {
+        Some((mode, allow_wan)) => {
+            let interfaces: Vec<IpAddr> = local_ip_address::list_afinet_netifas()
+                .map(|x| x.into_iter().map(|(_, ip)| ip).collect())
+                .unwrap_or_default();
+            match public_list_bind_check(IpAddr::from([0, 0, 0, 0]), &interfaces, allow_wan) {
+                Ok(()) => Some(mode),
+                Err(e) => {
+                    hbb_common::log::error!(
+                        "API: Refusing to serve /api/public/peers: {} (set --public-peer-list-allow-wan to override)",
+                        e
+                    );
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+
+    if let Err(e) = crate::sync::init(pool.clone()).await {
+        hbb_common::log::error!("API: Could not prepare sync snapshots: {}", e);
+    }
+    let jobs_dir = std::path::Path::new(&db_path)
+        .parent()
+        .unwrap_or_else(|| std::path::Path::new("."))
+        .join("jobs");
+    match crate::jobs::init(&pool, jobs_dir).await {
+        Ok(()) => crate::jobs::start(pool.clone(), read_pool.clone()),
+        Err(e) => hbb_common::log::error!("API: Could not prepare background jobs: {}", e),
+    }
+    match crate::healthscore::init(&pool).await {
+        Ok(()) => crate::healthscore::start(pool.clone()),
+        Err(e) => hbb_common::log::error!("API: Could not prepare the health score: {}", e),
+    }
+    match crate::webhook::init(&pool).await {
+        Ok(()) => crate::webhook::start(pool.clone(), read_pool.clone()),
+        Err(e) => hbb_common::log::error!("API: Could not prepare webhooks: {}", e),
+    }
+
     let state = Arc::new(ApiState { 
         db_pool: pool,
-        api_key,
+        read_pool,
+        api_key: crate::apikey::ApiKeys::new(api_key)
+            .with_scoped(crate::apikey::load_scoped(&get_api_keys_path())),
         start_time: Instant::now(),
+        public_peer_list,
+        peer_map: hbbs::peer_map_watch(),
+        peer_map_fallback_since: Default::default(),
+        readiness: hbbs::readiness_watch(),
+        require_if_match,
     });
 
-    let app = Router::new()
+    let mut app = Router::new()
+        .route("/metrics", get(get_metrics))
         .route("/api/health", get(health_check))
+        .route("/api/stats", get(get_stats))
+        .route("/api/summary", get(get_summary))
+        .route("/api/stats/network", get(get_network_stats))
+        .route("/api/stats/memory", get(get_memory_stats))
+        .route("/api/stats/api", get(get_api_stats))
+        .route("/api/stats/health-score", get(get_health_score))
         .route("/api/peers", get(get_online_peers))
-        .route("/api/peers/:id", get(get_peer_details))
+        .route("/api/peers/export", get(export_peers))
+        .route("/api/peers/:id", get(get_peer_details).delete(delete_peer))
         .route("/api/peers/:id/change-id", post(change_peer_id))
+        .route("/api/peers/:id/ban", post(ban_peer))
+        .route("/api/peers/:id/unban", post(unban_peer))
+        .route("/api/peers/:id/restore", post(restore_peer))
+        .route("/api/peers/:id/rollback-key", post(rollback_peer_key))
+        .route("/api/peers/:id/quarantine", post(quarantine_peer))
+        .route("/api/peers/:id/unquarantine", post(unquarantine_peer))
+        .route("/api/bans", get(get_bans))
+        .route("/api/peers/:id/note", put(put_peer_note))
+        .route("/api/peers/:id/user", put(put_peer_user))
+        .route("/api/peers/:id/attributes", put(put_peer_attributes))
+        .route(
+            "/api/peers/:id/attributes/:key",
+            delete(delete_peer_attribute),
+        )
+        .route("/api/peers/:id/history", get(get_peer_history))
+        .route("/api/peers/:id/runtime", get(get_peer_runtime))
+        .route("/api/peers/:id/conn-stats", get(get_peer_conn_stats))
+        .route("/api/peers/:id/trace", post(trace_peer).get(get_peer_trace))
+        .route(
+            "/api/peers/:id/approve-key-change",
+            post(approve_peer_key_change),
+        )
+        .route("/api/key-changes", get(list_key_changes))
+        .route(
+            "/api/access-rules",
+            get(get_access_rules).post(post_access_rule),
+        )
+        .route("/api/access-rules/:id", delete(delete_access_rule))
+        .route("/api/users", get(get_users))
+        .route("/api/users/:name/disable", post(disable_user))
+        .route("/api/users/:name/enable", post(enable_user))
+        .route("/api/sync/start", post(sync_start))
+        .route("/api/sync/:token/chunk", get(sync_chunk))
+        .route("/api/sync/:token", delete(sync_release))
+        .route("/api/admin/verify", post(admin_verify))
+        .route("/api/audit/verify", get(audit_verify))
+        .route("/api/admin/dedupe", post(admin_dedupe))
+        .route("/api/admin/drain", post(admin_drain))
+        .route("/api/keys/rotate", post(rotate_api_key))
+        .route("/api/server/config", get(get_server_config))
+        .route("/api/server/reload", post(server_reload))
+        .route(
+            "/api/server/serial",
+            get(get_server_serial).post(bump_server_serial),
+        )
+        .route("/api/relay-servers", get(get_relay_servers))
+        .route("/api/events", get(get_events))
+        .route("/api/events/sse", get(get_events_sse))
+        .route("/api/debug/recent-errors", get(get_recent_errors))
+        .route("/api/debug/simulate-punch", post(simulate_punch))
+        .route("/api/admin/logs", get(get_recent_logs))
+        .route("/api/admin/broadcast", post(post_broadcast))
+        .route("/api/admin/broadcast/:id", get(get_broadcast))
+        .route("/api/webhooks", get(get_webhooks).post(post_webhook))
+        .route("/api/webhooks/:id", delete(delete_webhook))
+        .route("/api/anomalies/uuid-churn", get(get_uuid_churn))
+        .route("/api/reports/uptime", get(get_uptime_report))
+        .route("/api/reports/protocol-versions", get(get_protocol_versions))
+        .route("/api/reports/active-peers", get(get_active_peers))
+        .route("/api/jobs", post(post_job))
+        .route("/api/jobs/:id", get(get_job).delete(delete_job))
+        .route("/api/jobs/:id/result", get(get_job_result))
+        .route("/api/v1/health", get(crate::apicompat::health))
+        .route("/api/v1/peers", get(crate::apicompat::peers));
+    if public_peer_list.is_some() {
+        app = app.route("/api/public/peers", get(get_public_peers));
+    }
+    // route_layer: only requests that matched a route are timed, by its pattern,
+    // refused bearer tokens and requests over the rate limit included
+    let app = app
+        .route_layer(axum::middleware::from_fn(crate::oidc::authenticate))
+        .route_layer(axum::middleware::from_fn(crate::ratelimit::limit))
+        .route_layer(axum::middleware::from_fn(crate::apistats::track))
+        // after the route layers: a stray Authorization header cannot fail it
+        .route("/healthz", get(healthz))
         .layer(Extension(state));
 
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
@@ -476,16 +5519,103 @@ pub async fn start_api_server(db_path: String, port: u16) -> Result<(), Box<dyn
     hbb_common::log::info!("========================================");
     hbb_common::log::info!("HTTP API Server on port {}", port);
     hbb_common::log::info!("========================================");
+    match crate::oidc::configured() {
+        Some(oidc) => hbb_common::log::info!(
+            "Auth: X-API-Key, or Bearer tokens for {} checked at {}",
+            oidc.audience,
+            oidc.introspection_url
+        ),
+        None => hbb_common::log::info!("Auth: X-API-Key"),
+    }
+    match crate::ratelimit::configured() {
+        Some(limit) => hbb_common::log::info!(
+            "Rate limit: {}/s, burst {}, per API key (per IP without one)",
+            limit.rate,
+            limit.burst
+        ),
+        None => hbb_common::log::info!("Rate limit: off (--api-rate-limit=0)"),
+    }
     hbb_common::log::info!("Endpoints:");
+    hbb_common::log::info!("  GET  /healthz (no auth)");
+    hbb_common::log::info!("  GET  /metrics");
     hbb_common::log::info!("  GET  /api/health");
-    hbb_common::log::info!("  GET  /api/peers");
+    hbb_common::log::info!("  GET  /api/stats");
+    hbb_common::log::info!("  GET  /api/summary");
+    hbb_common::log::info!("  GET  /api/stats/network?top=10");
+    hbb_common::log::info!("  GET  /api/stats/memory");
+    hbb_common::log::info!("  GET  /api/stats/api");
+    hbb_common::log::info!("  GET  /api/stats/health-score");
+    hbb_common::log::info!("  GET  /api/peers?attr=key:value");
     hbb_common::log::info!("  GET  /api/peers/:id");
+    hbb_common::log::info!("  DELETE /api/peers/:id");
     hbb_common::log::info!("  POST /api/peers/:id/change-id");
+    hbb_common::log::info!("  POST /api/peers/:id/ban");
+    hbb_common::log::info!("  POST /api/peers/:id/unban");
+    hbb_common::log::info!("  POST /api/peers/:id/restore");
+    hbb_common::log::info!("  POST /api/peers/:id/quarantine");
+    hbb_common::log::info!("  POST /api/peers/:id/unquarantine");
+    hbb_common::log::info!("  GET  /api/bans?since=");
+    hbb_common::log::info!("  PUT  /api/peers/:id/note");
+    hbb_common::log::info!("  PUT  /api/peers/:id/user");
+    hbb_common::log::info!("  PUT  /api/peers/:id/attributes");
+    hbb_common::log::info!("  DELETE /api/peers/:id/attributes/:key");
+    hbb_common::log::info!("  GET  /api/peers/:id/history");
+    hbb_common::log::info!("  GET  /api/peers/:id/runtime");
+    hbb_common::log::info!("  GET  /api/peers/:id/conn-stats");
+    hbb_common::log::info!("  POST /api/peers/:id/trace");
+    hbb_common::log::info!("  GET  /api/peers/:id/trace");
+    hbb_common::log::info!("  POST /api/peers/:id/approve-key-change");
+    hbb_common::log::info!("  GET  /api/key-changes");
+    hbb_common::log::info!("  GET  /api/access-rules");
+    hbb_common::log::info!("  POST /api/access-rules");
+    hbb_common::log::info!("  DELETE /api/access-rules/:id");
+    hbb_common::log::info!("  GET  /api/users");
+    hbb_common::log::info!("  POST /api/users/:name/disable");
+    hbb_common::log::info!("  POST /api/users/:name/enable");
+    hbb_common::log::info!("  POST /api/sync/start");
+    hbb_common::log::info!("  GET  /api/sync/:token/chunk?n=");
+    hbb_common::log::info!("  DELETE /api/sync/:token");
+    hbb_common::log::info!("  POST /api/admin/verify");
+    hbb_common::log::info!("  GET  /api/audit/verify");
+    hbb_common::log::info!("  POST /api/admin/dedupe");
+    hbb_common::log::info!("  POST /api/admin/drain");
+    hbb_common::log::info!("  POST /api/keys/rotate");
+    hbb_common::log::info!("  GET  /api/server/config");
+    hbb_common::log::info!("  POST /api/server/reload");
+    hbb_common::log::info!("  GET  /api/server/serial");
+    hbb_common::log::info!("  POST /api/server/serial");
+    hbb_common::log::info!("  GET  /api/relay-servers");
+    hbb_common::log::info!("  GET  /api/events (WebSocket)");
+    hbb_common::log::info!("  GET  /api/events/sse");
+    hbb_common::log::info!("  GET  /api/debug/recent-errors");
+    hbb_common::log::info!("  POST /api/debug/simulate-punch");
+    hbb_common::log::info!("  GET  /api/admin/logs");
+    hbb_common::log::info!("  POST /api/admin/broadcast");
+    hbb_common::log::info!("  GET  /api/admin/broadcast/:id");
+    hbb_common::log::info!("  GET  /api/webhooks");
+    hbb_common::log::info!("  POST /api/webhooks");
+    hbb_common::log::info!("  DELETE /api/webhooks/:id");
+    hbb_common::log::info!("  GET  /api/anomalies/uuid-churn");
+    hbb_common::log::info!("  GET  /api/reports/uptime");
+    hbb_common::log::info!("  GET  /api/reports/protocol-versions");
+    hbb_common::log::info!("  GET  /api/reports/active-peers?granularity=day|month&from=&to=");
+    hbb_common::log::info!("  POST /api/jobs");
+    hbb_common::log::info!("  GET  /api/jobs/:id");
+    hbb_common::log::info!("  GET  /api/jobs/:id/result");
+    hbb_common::log::info!("  DELETE /api/jobs/:id");
+    hbb_common::log::info!("  GET  /api/v1/health (deprecated v1 shape)");
+    hbb_common::log::info!("  GET  /api/v1/peers (deprecated v1 shape)");
+    if let Some(mode) = public_peer_list {
+        hbb_common::log::info!("  GET  /api/public/peers (no auth, {:?})", mode);
+    }
+    if require_if_match {
+        hbb_common::log::info!("Peer changes require If-Match (--api-require-if-match)");
+    }
     hbb_common::log::info!("========================================");
 
     // axum 0.5 uses Server::bind
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await?;
 
     Ok(())
@@ -0,0 +1,401 @@
+// Background jobs for reports too large for one API request (`/api/jobs`)
+// A job row is persisted when submitted and claimed by a worker, at most
+// JOB_CONCURRENCY at a time; jobs running when the server stopped are queued
+// again on start. The result goes to a file in the jobs directory next to the
+// database and is streamed by `/api/jobs/:id/result`. Finished jobs and their
+// files are pruned after JOB_RETENTION_SECS. Cancellation is cooperative: the
+// export stops within a batch of rows, the uptime report only between steps.
+
+use crate::sync::env_u64;
+use hbb_common::{futures_util::TryStreamExt, log, tokio, ResultType};
+use serde::Serialize;
+use sqlx::{sqlite::SqlitePool, Row};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+pub const UPTIME_REPORT: &str = "uptime_report";
+pub const EXPORT: &str = "export";
+
+const CONCURRENCY: u64 = 2; // JOB_CONCURRENCY
+const RETENTION_SECS: u64 = 24 * 3600; // JOB_RETENTION_SECS
+const POLL_SECS: u64 = 5;
+const PRUNE_INTERVAL_SECS: u64 = 300;
+/// Rows exported between progress and cancellation checks
+const EXPORT_BATCH: u64 = 500;
+
+/// Progress and the cancel flag of a running job
+#[derive(Default)]
+struct Running {
+    cancel: AtomicBool,
+    done: AtomicU64,
+    total: AtomicU64,
+}
+
+lazy_static::lazy_static! {
+    static ref RUNNING: Mutex<HashMap<i64, Arc<Running>>> = Default::default();
+    static ref WAKE: tokio::sync::Notify = tokio::sync::Notify::new();
+    static ref DIR: Mutex<Option<PathBuf>> = Default::default();
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Job {
+    pub id: i64,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub params: serde_json::Value,
+    /// queued, running, cancelling, done, failed or cancelled
+    pub status: String,
+    /// 0 to 1 while running, 1 once done; None while it cannot be told
+    pub progress: Option<f64>,
+    pub created_at: i64,
+    pub started_at: Option<i64>,
+    pub finished_at: Option<i64>,
+    pub error: Option<String>,
+    /// Size of the result in bytes, once done
+    pub size: Option<i64>,
+}
+
+impl Job {
+    fn from_row(row: &sqlx::sqlite::SqliteRow) -> Self {
+        let id = row.get("id");
+        let mut status: String = row.get("status");
+        let mut progress = None;
+        if status == "done" {
+            progress = Some(1.0);
+        } else if status == "running" {
+            if let Some(running) = running(id) {
+                let total = running.total.load(Ordering::Relaxed);
+                if total > 0 {
+                    progress = Some(running.done.load(Ordering::Relaxed) as f64 / total as f64);
+                }
+                if running.cancel.load(Ordering::Relaxed) {
+                    status = "cancelling".to_owned();
+                }
+            }
+        }
+        Job {
+            id,
+            kind: row.get("kind"),
+            params: serde_json::from_str(&row.get::<String, _>("params")).unwrap_or_default(),
+            status,
+            progress,
+            created_at: row.get("created_at"),
+            started_at: row.get("started_at"),
+            finished_at: row.get("finished_at"),
+            error: row.get("error"),
+            size: row.get("size"),
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        if self.kind == EXPORT {
+            "application/x-ndjson"
+        } else {
+            "application/json"
+        }
+    }
+}
+
+/// The jobs directory, once init ran
+pub fn dir() -> Option<PathBuf> {
+    DIR.lock().ok()?.clone()
+}
+
+fn running(id: i64) -> Option<Arc<Running>> {
+    RUNNING.lock().ok()?.get(&id).cloned()
+}
+
+/// File holding the result of job `id`
+pub fn artifact(dir: &Path, id: i64, kind: &str) -> PathBuf {
+    let ext = if kind == EXPORT { "ndjson" } else { "json" };
+    dir.join(format!("job-{}.{}", id, ext))
+}
+
+/// Create the jobs table and directory and queue jobs a previous run left
+/// running; `start` runs them
+pub async fn init(pool: &SqlitePool, dir: PathBuf) -> ResultType<()> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS jobs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            kind VARCHAR(32) NOT NULL,
+            params TEXT NOT NULL,
+            status VARCHAR(16) NOT NULL,
+            created_at INTEGER NOT NULL,
+            started_at INTEGER,
+            finished_at INTEGER,
+            error TEXT,
+            size INTEGER
+        )",
+    )
+    .execute(pool)
+    .await?;
+    let requeued = sqlx::query(
+        "UPDATE jobs SET status = 'queued', started_at = NULL WHERE status = 'running'",
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+    if requeued > 0 {
+        log::info!(
+            "API: {} jobs interrupted by the last stop queued again",
+            requeued
+        );
+    }
+    std::fs::create_dir_all(&dir)?;
+    if let Ok(mut lock) = DIR.lock() {
+        *lock = Some(dir);
+    }
+    Ok(())
+}
+
+/// Start the worker, once after init; reports read through `read_pool`
+pub fn start(pool: SqlitePool, read_pool: SqlitePool) {
+    if let Some(dir) = dir() {
+        hbbs::supervise("jobs", move || {
+            worker(pool.clone(), read_pool.clone(), dir.clone())
+        });
+    }
+}
+
+/// Check a job's type and parameters and queue it
+pub async fn submit(
+    pool: &SqlitePool,
+    kind: &str,
+    params: serde_json::Value,
+) -> Result<Job, String> {
+    match kind {
+        UPTIME_REPORT => {
+            let bound = |name: &str| params.get(name).and_then(|x| x.as_str());
+            crate::uptime::window(bound("from"), bound("to"))?;
+        }
+        EXPORT => {}
+        other => {
+            return Err(format!(
+                "Unknown job type {}, expected {} or {}",
+                other, UPTIME_REPORT, EXPORT
+            ))
+        }
+    }
+    let id: i64 = sqlx::query(
+        "INSERT INTO jobs (kind, params, status, created_at) VALUES (?, ?, 'queued', ?)
+         RETURNING id",
+    )
+    .bind(kind)
+    .bind(params.to_string())
+    .bind(chrono::Utc::now().timestamp())
+    .fetch_one(pool)
+    .await
+    .map_err(|e| format!("Database error: {}", e))?
+    .get(0);
+    // read back before the worker is woken, so the job is answered as queued
+    let job = get(pool, id)
+        .await
+        .map_err(|e| format!("Database error: {}", e))?
+        .ok_or_else(|| format!("Job {} vanished", id));
+    WAKE.notify_one();
+    job
+}
+
+pub async fn get(pool: &SqlitePool, id: i64) -> Result<Option<Job>, sqlx::Error> {
+    Ok(sqlx::query("SELECT * FROM jobs WHERE id = ?")
+        .bind(id)
+        .fetch_optional(pool)
+        .await?
+        .map(|row| Job::from_row(&row)))
+}
+
+/// Cancel a queued job at once or ask a running one to stop; a finished job is
+/// removed with its result. None for an unknown job.
+pub async fn cancel(pool: &SqlitePool, dir: &Path, id: i64) -> Result<Option<Job>, sqlx::Error> {
+    let job = match get(pool, id).await? {
+        Some(job) => job,
+        None => return Ok(None),
+    };
+    match job.status.as_str() {
+        "queued" => {
+            sqlx::query(
+                "UPDATE jobs SET status = 'cancelled', finished_at = ?
+                 WHERE id = ? AND status = 'queued'",
+            )
+            .bind(chrono::Utc::now().timestamp())
+            .bind(id)
+            .execute(pool)
+            .await?;
+        }
+        "running" | "cancelling" => {
+            if let Some(running) = running(id) {
+                running.cancel.store(true, Ordering::Relaxed);
+            }
+        }
+        _ => {
+            sqlx::query("DELETE FROM jobs WHERE id = ?")
+                .bind(id)
+                .execute(pool)
+                .await?;
+            std::fs::remove_file(artifact(dir, id, &job.kind)).ok();
+            return Ok(Some(job));
+        }
+    }
+    get(pool, id).await
+}
+
+/// Remove jobs finished more than `max_age_secs` ago, with their results
+pub async fn prune(pool: &SqlitePool, dir: &Path, max_age_secs: u64) -> Result<u64, sqlx::Error> {
+    let cutoff = chrono::Utc::now().timestamp() - max_age_secs as i64;
+    let old = sqlx::query("DELETE FROM jobs WHERE finished_at <= ? RETURNING id, kind")
+        .bind(cutoff)
+        .fetch_all(pool)
+        .await?;
+    for row in &old {
+        std::fs::remove_file(artifact(dir, row.get("id"), row.get("kind"))).ok();
+    }
+    if !old.is_empty() {
+        log::info!("API: Pruned {} finished jobs", old.len());
+    }
+    Ok(old.len() as u64)
+}
+
+async fn worker(pool: SqlitePool, read_pool: SqlitePool, dir: PathBuf) {
+    let concurrency = env_u64("JOB_CONCURRENCY", CONCURRENCY).max(1) as usize;
+    let retention = env_u64("JOB_RETENTION_SECS", RETENTION_SECS);
+    let mut pruned_at: Option<std::time::Instant> = None;
+    loop {
+        if pruned_at.map_or(true, |x| x.elapsed().as_secs() >= PRUNE_INTERVAL_SECS) {
+            if let Err(e) = prune(&pool, &dir, retention).await {
+                log::warn!("API: Failed to prune jobs: {}", e);
+            }
+            pruned_at = Some(std::time::Instant::now());
+        }
+        while RUNNING.lock().map(|x| x.len()).unwrap_or(usize::MAX) < concurrency {
+            let claimed = sqlx::query(
+                "UPDATE jobs SET status = 'running', started_at = ?
+                 WHERE id = (SELECT id FROM jobs WHERE status = 'queued' ORDER BY id LIMIT 1)
+                 RETURNING id, kind, params",
+            )
+            .bind(chrono::Utc::now().timestamp())
+            .fetch_optional(&pool)
+            .await;
+            let row = match claimed {
+                Ok(Some(row)) => row,
+                Ok(None) => break,
+                Err(e) => {
+                    log::warn!("API: Failed to claim a job: {}", e);
+                    break;
+                }
+            };
+            let id: i64 = row.get("id");
+            let progress = Arc::new(Running::default());
+            if let Ok(mut running) = RUNNING.lock() {
+                running.insert(id, progress.clone());
+            }
+            let params = serde_json::from_str(&row.get::<String, _>("params")).unwrap_or_default();
+            tokio::spawn(run(
+                pool.clone(),
+                read_pool.clone(),
+                artifact(&dir, id, row.get("kind")),
+                id,
+                row.get("kind"),
+                params,
+                progress,
+            ));
+        }
+        tokio::select! {
+            _ = WAKE.notified() => {}
+            _ = tokio::time::sleep(Duration::from_secs(POLL_SECS)) => {}
+        }
+    }
+}
+
+async fn run(
+    pool: SqlitePool,
+    read_pool: SqlitePool,
+    path: PathBuf,
+    id: i64,
+    kind: String,
+    params: serde_json::Value,
+    progress: Arc<Running>,
+) {
+    log::info!("API: Job {} ({}) started", id, kind);
+    let res = match kind.as_str() {
+        UPTIME_REPORT => uptime_report(&read_pool, &params, &path, &progress).await,
+        _ => export(&read_pool, &path, &progress).await,
+    };
+    let cancelled = progress.cancel.load(Ordering::Relaxed);
+    let (status, error, size) = match res {
+        Ok(_) if cancelled => ("cancelled", None, None),
+        Ok(size) => ("done", None, Some(size as i64)),
+        Err(e) => ("failed", Some(e.to_string()), None),
+    };
+    if status != "done" {
+        std::fs::remove_file(&path).ok();
+    }
+    log::info!("API: Job {} ({}) {}", id, kind, status);
+    if let Err(e) =
+        sqlx::query("UPDATE jobs SET status = ?, finished_at = ?, error = ?, size = ? WHERE id = ?")
+            .bind(status)
+            .bind(chrono::Utc::now().timestamp())
+            .bind(error)
+            .bind(size)
+            .bind(id)
+            .execute(&pool)
+            .await
+    {
+        log::warn!("API: Failed to record the end of job {}: {}", id, e);
+    }
+    if let Ok(mut running) = RUNNING.lock() {
+        running.remove(&id);
+    }
+    WAKE.notify_one();
+}
+
+/// Bytes written; stops early (returning what was written) once cancelled
+async fn uptime_report(
+    pool: &SqlitePool,
+    params: &serde_json::Value,
+    path: &Path,
+    progress: &Running,
+) -> ResultType<u64> {
+    let bound = |name: &str| params.get(name).and_then(|x| x.as_str());
+    let (from, to) = crate::uptime::window(bound("from"), bound("to"))
+        .map_err(hbb_common::anyhow::Error::msg)?;
+    let report = crate::uptime::uptime_report(pool, from, to, bound("tag")).await?;
+    if progress.cancel.load(Ordering::Relaxed) {
+        return Ok(0);
+    }
+    let body = serde_json::to_vec(&report)?;
+    tokio::fs::write(path, &body).await?;
+    Ok(body.len() as u64)
+}
+
+async fn export(pool: &SqlitePool, path: &Path, progress: &Running) -> ResultType<u64> {
+    let total: i64 = sqlx::query("SELECT count(*) FROM peer WHERE is_deleted = 0")
+        .fetch_one(pool)
+        .await?
+        .get(0);
+    progress.total.store(total.max(1) as u64, Ordering::Relaxed);
+    let mut file = tokio::io::BufWriter::new(tokio::fs::File::create(path).await?);
+    let mut written = 0;
+    let mut rows = sqlx::query(crate::sync::EXPORT_SQL).fetch(pool);
+    while let Some(row) = rows.try_next().await? {
+        let line = row.get::<&str, _>(0);
+        file.write_all(line.as_bytes()).await?;
+        file.write_all(b"\n").await?;
+        written += line.len() as u64 + 1;
+        let done = progress.done.fetch_add(1, Ordering::Relaxed) + 1;
+        if done % EXPORT_BATCH == 0 && progress.cancel.load(Ordering::Relaxed) {
+            return Ok(written);
+        }
+    }
+    file.flush().await?;
+    progress.total.store(
+        progress.done.load(Ordering::Relaxed).max(1),
+        Ordering::Relaxed,
+    );
+    Ok(written)
+}
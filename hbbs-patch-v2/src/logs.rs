@@ -0,0 +1,161 @@
+// Recent log lines for `GET /api/admin/logs`
+// The logger's writer only stamps the record and try_sends it into a bounded
+// channel; a dedicated collector thread formats it into a ring buffer of the
+// last RING_LINES entries, redacting IP addresses first when asked to. A full
+// channel drops the line from the buffer (never from stdout) and counts it.
+
+use flexi_logger::{writers::LogWriter, DeferredNow};
+use hbb_common::{log, tokio::sync::mpsc};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+pub const RING_LINES: usize = 2_000;
+const CHANNEL_LINES: usize = 4_096;
+
+lazy_static::lazy_static! {
+    static ref RING: Mutex<VecDeque<LogLine>> = Mutex::new(VecDeque::with_capacity(RING_LINES));
+}
+static REDACT_IPS: AtomicBool = AtomicBool::new(false);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Serialize, Clone, Debug)]
+pub struct LogLine {
+    pub timestamp: String,
+    pub level: String,
+    pub target: String,
+    pub message: String,
+}
+
+type Entry = (chrono::DateTime<chrono::Utc>, log::Level, String, String);
+
+/// Lines held and approximate bytes per line, for `/api/stats/memory`
+pub fn memory() -> Option<(usize, usize)> {
+    let lines = RING.try_lock().ok()?.len();
+    // timestamp, level and target are short; messages average a couple hundred bytes
+    Some((lines, std::mem::size_of::<LogLine>() + 32 + 8 + 32 + 200))
+}
+
+pub struct RingWriter(mpsc::Sender<Entry>);
+
+impl LogWriter for RingWriter {
+    fn write(&self, _now: &mut DeferredNow, record: &log::Record) -> std::io::Result<()> {
+        let entry = (
+            chrono::Utc::now(),
+            record.level(),
+            record.target().to_owned(),
+            record.args().to_string(),
+        );
+        if self.0.try_send(entry).is_err() {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The writer to hand to the logger; starts the collector thread
+pub fn writer() -> Box<RingWriter> {
+    let (tx, mut rx) = mpsc::channel::<Entry>(CHANNEL_LINES);
+    std::thread::spawn(move || {
+        while let Some((at, level, target, message)) = rx.blocking_recv() {
+            let message = if REDACT_IPS.load(Ordering::Relaxed) {
+                redact_ips(&message)
+            } else {
+                message
+            };
+            if let Ok(mut ring) = RING.lock() {
+                if ring.len() >= RING_LINES {
+                    ring.pop_front();
+                }
+                ring.push_back(LogLine {
+                    timestamp: at.to_rfc3339(),
+                    level: level.to_string().to_lowercase(),
+                    target,
+                    message,
+                });
+            }
+        }
+    });
+    Box::new(RingWriter(tx))
+}
+
+/// Replace IP addresses in lines collected from now on (`--log-redact-ips`)
+pub fn set_redact_ips(on: bool) {
+    REDACT_IPS.store(on, Ordering::SeqCst);
+}
+
+pub fn redacting_ips() -> bool {
+    REDACT_IPS.load(Ordering::Relaxed)
+}
+
+/// Lines that did not make it into the buffer because the collector fell behind
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// The last `lines` entries at `level` or more severe whose target starts with
+/// `target`, oldest first
+pub fn recent(lines: usize, level: log::LevelFilter, target: &str) -> Vec<LogLine> {
+    let ring = match RING.lock() {
+        Ok(ring) => ring,
+        Err(_) => return Vec::new(),
+    };
+    let mut out: Vec<LogLine> = ring
+        .iter()
+        .rev()
+        .filter(|x| {
+            x.level.parse::<log::Level>().map_or(false, |l| l <= level)
+                && x.target.starts_with(target)
+        })
+        .take(lines)
+        .cloned()
+        .collect();
+    out.reverse();
+    out
+}
+
+/// IPv4 and IPv6 addresses (with or without a port) replaced by `<ip>`
+pub fn redact_ips(message: &str) -> String {
+    let is_addr_char = |c: char| c.is_ascii_hexdigit() || c == '.' || c == ':';
+    let is_ip = |s: &str| {
+        s.parse::<IpAddr>().is_ok()
+            || s.parse::<SocketAddr>().is_ok()
+            || s.rsplit_once(':').map_or(false, |(ip, port)| {
+                ip.parse::<IpAddr>().is_ok() && port.parse::<u16>().is_ok()
+            })
+    };
+    let mut out = String::with_capacity(message.len());
+    let mut run = String::new();
+    let flush = |run: &mut String, out: &mut String| {
+        // a trailing dot or colon ends the sentence, not the address (unless it
+        // is an IPv6 address ending in ::)
+        let trimmed = if is_ip(run) {
+            run.as_str()
+        } else {
+            run.trim_end_matches(|c| c == '.' || c == ':')
+        };
+        if trimmed.contains(['.', ':']) && is_ip(trimmed) {
+            out.push_str("<ip>");
+            out.push_str(&run[trimmed.len()..]);
+        } else {
+            out.push_str(run);
+        }
+        run.clear();
+    };
+    for c in message.chars() {
+        if is_addr_char(c) {
+            run.push(c);
+        } else {
+            flush(&mut run, &mut out);
+            out.push(c);
+        }
+    }
+    flush(&mut run, &mut out);
+    out
+}
@@ -8,23 +8,109 @@
 // - Database with soft-delete support
 
 use flexi_logger::*;
-use hbb_common::{bail, config::RENDEZVOUS_PORT, ResultType, tokio};
+use hbb_common::{
+    anyhow::{anyhow, Context},
+    bail,
+    config::RENDEZVOUS_PORT,
+    ResultType,
+};
 use hbbs::{common::*, *};
 
+mod access;
+mod apicompat;
+mod apikey;
+mod apistats;
+mod attributes;
+mod broadcast;
+mod crash;
+#[cfg(feature = "selftest")]
+mod dbbench;
+mod eventlog;
+mod healthscore;
 mod http_api;
+mod jobs;
+mod logs;
+mod nat;
+mod notices;
+mod oidc;
+mod peersync;
+mod peerversion;
+#[cfg(feature = "selftest")]
+mod querybench;
+mod ratelimit;
+mod readiness;
+#[cfg(feature = "selftest")]
+mod signbench;
+#[cfg(feature = "selftest")]
+mod smoketest;
+mod startup;
+mod sync;
+mod tls;
+mod uptime;
+mod users;
+mod webhook;
 
 const RMEM: usize = 0;
-const API_PORT: u16 = 21114;
+pub(crate) const API_PORT: u16 = 21114;
 
-fn main() -> ResultType<()> {
-    let _logger = Logger::try_with_env_or_str("info")?
-        .log_to_stdout()
-        .format(opt_format)
-        .write_mode(WriteMode::Async)
-        .start()?;
+fn main() {
+    // stdout as before, plus the ring buffer behind GET /api/admin/logs; stderr
+    // with --startup-summary=json, which keeps stdout for the summary
+    let json_summary = startup::json_requested();
+    let logger = Logger::try_with_env_or_str("info").and_then(|logger| {
+        let logger = logger.log_to_writer(logs::writer());
+        let logger = if json_summary {
+            logger.duplicate_to_stderr(Duplicate::All)
+        } else {
+            logger.duplicate_to_stdout(Duplicate::All)
+        };
+        logger
+            .format(opt_format)
+            .write_mode(WriteMode::Async)
+            .start()
+    });
+    match logger {
+        Ok(logger) => crash::install(logger),
+        Err(e) => {
+            eprintln!("Cannot start the logger: {}", e);
+            std::process::exit(crash::ExitCode::Failure.code());
+        }
+    }
+    crash::finish(run())
+}
+
+/// The self tests and benchmarks, compiled in with the selftest feature only
+#[cfg(feature = "selftest")]
+fn selftest(command: &str) -> ResultType<()> {
+    match command {
+        // `hbbs smoketest` - end-to-end check against a throwaway server
+        "smoketest" => smoketest::run().context(crash::ExitCode::SelfTest),
+        // `hbbs dbbench [PEERS]` - heartbeat write latency while full-table reads stream
+        "dbbench" => dbbench::run(),
+        // `hbbs querybench [OPS]` - hot queries as they were vs cached statements and batching
+        "querybench" => querybench::run(),
+        // `hbbs signbench [REQUESTS]` - IdPk signing per request vs the signed response cache
+        _ => signbench::run(),
+    }
+}
+
+#[cfg(not(feature = "selftest"))]
+fn selftest(command: &str) -> ResultType<()> {
+    bail!("hbbs {} needs a build with --features selftest", command)
+}
+
+fn run() -> ResultType<()> {
+    let command = std::env::args().nth(1);
+    if let Some(command @ ("smoketest" | "dbbench" | "querybench" | "signbench")) =
+        command.as_deref()
+    {
+        return selftest(command);
+    }
     
     let args = format!(
         "-c --config=[FILE] +takes_value 'Sets a custom config file'
+        , --check-config 'Validate the configuration, print the effective settings and exit'
+        , --strict-config 'Treat unknown settings in the config file as errors'
         -p, --port=[NUMBER(default={RENDEZVOUS_PORT})] 'Sets the listening port'
         -s, --serial=[NUMBER(default=0)] 'Sets configure update serial number'
         -R, --rendezvous-servers=[HOSTS] 'Sets rendezvous servers, separated by comma'
@@ -32,44 +118,131 @@ fn main() -> ResultType<()> {
         -r, --relay-servers=[HOST] 'Sets the default relay servers, separated by comma'
         -M, --rmem=[NUMBER(default={RMEM})] 'Sets UDP recv buffer size'
         , --mask=[MASK] 'Determine if the connection comes from LAN'
+        , --single-port 'Serve websocket and TCP clients on the main port'
+        , --tls-cert=[FILE] 'PEM certificate for wss:// clients on the single port'
+        , --tls-key=[FILE] 'PEM (PKCS#8) key of --tls-cert'
+        , --relay-mode=[MODE] 'rotation, sticky (same relay per peer pair) or latency (default: rotation)'
+        , --relay-bindings=[BINDINGS] 'Relays reserved for peers with a tag: tag=host,host;tag2=host'
+        , --relay-binding-fallback=[MODE] 'shared or fail when every relay bound to a tag is down (default: shared)'
+        , --pk-change-policy=[POLICY] 'auto, approve or reject a new key for a known device (default: auto)'
+        , --rebind-policy=[POLICY] 'strict, rebind or rebind-any: registrations from a changed port or ip (default: rebind)'
+        , --deprecated-versions=[VERSIONS] 'Client versions to warn about, separated by comma (1.1 for all 1.1.x, none for unversioned)'
         -k, --key=[KEY] 'Only allow the client with the same key'
-        -a, --api-port=[NUMBER(default={API_PORT})] 'Sets the HTTP API port'",
+        -a, --api-port=[NUMBER(default={API_PORT})] 'Sets the HTTP API port'
+        , --no-api 'Do not start the HTTP API (no listener, no API key file)'
+        , --api-require-if-match 'Refuse API changes to a peer without an If-Match of its current version'
+        , --api-rate-limit=[NUMBER(default={rate})] 'API requests per second per key (per IP without a valid key), 0 for no limit'
+        , --api-rate-burst=[NUMBER(default={burst})] 'API requests a key may make at once above --api-rate-limit'
+        , --public-peer-list=[MODE] 'Unauthenticated online list: off, minimal (ids) or notes (default: off)'
+        , --external-check-url=[URL] 'http:// URL answering with the caller IP, for the NAT check'
+        , --log-redact-ips 'Replace IP addresses in the log lines served by the API'
+        , --event-log=[FILE] 'Append server events as JSON lines to FILE (for SIEM ingestion)'
+        , --startup-summary=[FORMAT] 'Print ports, key and paths as one json line on stdout once ready, logs go to stderr'
+        , --public-peer-list-allow-wan 'Serve the public peer list even if the API is reachable from a public address'",
+        rate = ratelimit::RATE,
+        burst = ratelimit::BURST,
     );
     init_args(&args, "hbbs", "BetterDesk Enhanced Server v2.1.1");
+    logs::set_redact_ips(
+        get_flag("log-redact-ips")
+            || std::env::var("LOG_REDACT_IPS").unwrap_or_default().to_uppercase() == "Y",
+    );
+    
+    let key = get_arg_or("key", "-".to_owned());
+    let banner = match check_config(
+        &get_arg_or("port", RENDEZVOUS_PORT.to_string()),
+        &key,
+        get_flag("strict-config"),
+    ) {
+        Ok(fields) => {
+            if get_flag("check-config") {
+                for x in fields {
+                    println!("{} = {}", x.field, x.value);
+                }
+                return Ok(());
+            }
+            Banner::from_fields(&fields)
+        }
+        Err(errors) => {
+            hbb_common::log::error!("Invalid configuration, {} problem(s):", errors.len());
+            for e in &errors {
+                hbb_common::log::error!("  {}", e);
+            }
+            bail!(crash::ExitCode::InvalidConfig);
+        }
+    };
+    crash::load_previous();
     
-    let port = get_arg_or("port", RENDEZVOUS_PORT.to_string()).parse::<i32>()?;
+    let port = get_arg_or("port", RENDEZVOUS_PORT.to_string())
+        .parse::<i32>()
+        .context(crash::ExitCode::InvalidConfig)?;
     if port < 3 {
-        bail!("Invalid port");
+        return Err(anyhow!("Invalid port")).context(crash::ExitCode::InvalidConfig);
     }
     let rmem = get_arg("rmem").parse::<usize>().unwrap_or(RMEM);
     let serial: i32 = get_arg("serial").parse().unwrap_or(0);
-    let api_port = get_arg("api-port").parse::<u16>().unwrap_or(API_PORT);
+    let api = match http_api::ApiConfig::from_args(
+        get_flag("no-api"),
+        &get_arg("api-port"),
+        &get_arg("public-peer-list"),
+        get_flag("public-peer-list-allow-wan"),
+        get_flag("api-require-if-match"),
+        (&get_arg("api-rate-limit"), &get_arg("api-rate-burst")),
+    ) {
+        Ok(api) => api,
+        Err(e) => return Err(anyhow!("{}", e)).context(crash::ExitCode::InvalidConfig),
+    };
+    let startup_summary = get_arg("startup-summary");
+    if let Err(e) = startup::check_format(&startup_summary) {
+        return Err(anyhow!("{}", e)).context(crash::ExitCode::InvalidConfig);
+    }
+    match tls::configure(&get_arg("tls-cert"), &get_arg("tls-key"), get_flag("single-port")) {
+        Ok(true) => hbb_common::log::info!("TLS: wss:// on the single port"),
+        Ok(false) => {}
+        Err(e) => return Err(anyhow!("{}", e)).context(crash::ExitCode::InvalidConfig),
+    }
+    let external_check_url = get_arg("external-check-url");
+    let event_log = get_arg("event-log");
+    let rendezvous_servers = get_servers(&get_arg("rendezvous-servers"), "rendezvous-servers");
     
     hbb_common::log::info!("========================================");
+    if !banner.is_empty() {
+        hbb_common::log::info!("  {}", banner.line());
+    }
     hbb_common::log::info!("  BetterDesk Enhanced Server v2.1.1");
     hbb_common::log::info!("  Based on RustDesk Server 1.1.14");
     hbb_common::log::info!("========================================");
     hbb_common::log::info!("  Signal Port: {}", port);
-    hbb_common::log::info!("  API Port: {}", api_port);
+    match &api {
+        Some(api) => hbb_common::log::info!("  API Port: {}", api.port),
+        None => hbb_common::log::info!("  API: disabled (--no-api)"),
+    }
+    hbb_common::log::info!(
+        "  NAT check: {}",
+        nat::describe(&external_check_url, &rendezvous_servers)
+    );
     hbb_common::log::info!("========================================");
     
+    if !event_log.is_empty() {
+        eventlog::spawn_writer_thread(&event_log).context(crash::ExitCode::InvalidConfig)?;
+    }
+    // READY=1 once the udp self-test passed, exit code 5 when it failed
+    readiness::spawn_watch_thread();
+    if !startup_summary.is_empty() {
+        startup::spawn_thread(
+            port as u16,
+            get_flag("single-port"),
+            api.as_ref().map(|api| api.port),
+        );
+    }
     // Start HTTP API server in background thread
-    let db_path = std::env::current_dir()
-        .unwrap_or_default()
-        .join("db_v2.sqlite3")
-        .to_string_lossy()
-        .to_string();
-    
-    std::thread::spawn(move || {
-        let rt = tokio::runtime::Runtime::new().unwrap();
-        rt.block_on(async {
-            if let Err(e) = http_api::start_api_server(db_path, api_port).await {
-                hbb_common::log::error!("HTTP API failed: {}", e);
-            }
-        });
-    });
+    http_api::spawn_api_thread(api);
+    // Result lands in the log and /api/health once the first check is done
+    nat::spawn_check_thread(port, external_check_url, rendezvous_servers.clone());
+    // Newer serial and rendezvous-servers from the first peer that answers
+    peersync::spawn_sync_thread(port, rendezvous_servers);
     
     crate::common::check_software_update();
-    RendezvousServer::start(port, serial, &get_arg_or("key", "-".to_owned()), rmem)?;
+    RendezvousServer::start(port, serial, &key, rmem)?;
     Ok(())
 }
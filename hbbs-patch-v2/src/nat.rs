@@ -0,0 +1,270 @@
+// NAT self-check for `/api/health` and the startup log
+// Finds the address this server is seen from outside, either from
+// --external-check-url (an http:// URL answering with the caller's IP as plain
+// text) or from the port a configured rendezvous server observes in a NAT test,
+// and compares it with the local interface addresses. Behind NAT, the public
+// address is then tried with a NAT test of its own as evidence of port
+// forwarding. Runs on its own thread, so it never holds up startup.
+
+use hbb_common::{
+    config::RENDEZVOUS_PORT,
+    log,
+    protobuf::Message as _,
+    rendezvous_proto::*,
+    tcp::FramedStream,
+    tokio::{
+        self,
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    },
+};
+use serde::Serialize;
+use std::net::IpAddr;
+use std::sync::RwLock;
+use std::time::Duration;
+
+const CHECK_INTERVAL_SECS: u64 = 3600;
+const CHECK_TIMEOUT_MS: u64 = 3_000;
+const MAX_RESPONSE_BYTES: u64 = 4096;
+
+lazy_static::lazy_static! {
+    static ref LAST_REPORT: RwLock<Option<NatReport>> = Default::default();
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NatReport {
+    /// direct, behind_nat or unknown
+    pub status: &'static str,
+    /// "direct", "behind NAT via 203.0.113.7" or "unknown"
+    pub summary: String,
+    pub public_addr: Option<String>,
+    pub local_addrs: Vec<String>,
+    /// Where the public address came from
+    pub source: Option<String>,
+    /// Behind NAT: whether the public address answered a NAT test
+    pub forwarded: Option<bool>,
+    pub detail: String,
+    pub checked_at: String,
+}
+
+/// The last finished check, None before the first one or with the check off
+pub fn last_report() -> Option<NatReport> {
+    LAST_REPORT.read().ok().and_then(|x| x.clone())
+}
+
+/// What the startup banner shows about the check
+pub fn describe(url: &str, rendezvous_servers: &[String]) -> String {
+    if !url.is_empty() {
+        format!("via {}", url)
+    } else if let Some(server) = rendezvous_servers.first() {
+        format!("via rendezvous server {}", server)
+    } else {
+        "off (no --external-check-url or rendezvous-servers)".to_owned()
+    }
+}
+
+/// Checks at startup and every hour on a thread of its own; nothing is started
+/// when there is nothing to check against
+pub fn spawn_check_thread(
+    port: i32,
+    url: String,
+    rendezvous_servers: Vec<String>,
+) -> Option<std::thread::JoinHandle<()>> {
+    if url.is_empty() && rendezvous_servers.is_empty() {
+        return None;
+    }
+    Some(std::thread::spawn(move || {
+        let rt = match tokio::runtime::Runtime::new() {
+            Ok(rt) => rt,
+            Err(e) => {
+                log::warn!("NAT check: not started: {}", e);
+                return;
+            }
+        };
+        rt.block_on(async {
+            let mut interval = tokio::time::interval(Duration::from_secs(CHECK_INTERVAL_SECS));
+            loop {
+                interval.tick().await;
+                let report = check(port, &url, &rendezvous_servers, &local_addrs()).await;
+                log_report(&report);
+                if let Ok(mut lock) = LAST_REPORT.write() {
+                    *lock = Some(report);
+                }
+            }
+        });
+    }))
+}
+
+fn log_report(report: &NatReport) {
+    match report.forwarded {
+        Some(false) => log::warn!(
+            "NAT check: {}, and the public address did not answer ({}); clients may be \
+             given an address they cannot reach, forward ports 21115-21119 to this host",
+            report.summary,
+            report.detail
+        ),
+        _ => log::info!("NAT check: {} ({})", report.summary, report.detail),
+    }
+}
+
+fn local_addrs() -> Vec<IpAddr> {
+    local_ip_address::list_afinet_netifas()
+        .map(|x| x.into_iter().map(|(_, ip)| ip).collect())
+        .unwrap_or_default()
+}
+
+/// One check against `local`, the addresses of this host's interfaces
+pub async fn check(
+    port: i32,
+    url: &str,
+    rendezvous_servers: &[String],
+    local: &[IpAddr],
+) -> NatReport {
+    let mut report = NatReport {
+        status: "unknown",
+        summary: "unknown".to_owned(),
+        public_addr: None,
+        local_addrs: local.iter().map(|x| x.to_string()).collect(),
+        source: None,
+        forwarded: None,
+        detail: String::new(),
+        checked_at: chrono::Utc::now().to_rfc3339(),
+    };
+    if !url.is_empty() {
+        report.source = Some(url.to_owned());
+        match fetch_public_ip(url).await {
+            Ok(public) => {
+                report.public_addr = Some(public.to_string());
+                if local.contains(&public) {
+                    report.status = "direct";
+                    report.summary = "direct".to_owned();
+                    report.detail = format!("{} is a local address", public);
+                } else {
+                    report.status = "behind_nat";
+                    report.summary = format!("behind NAT via {}", public);
+                    let target = format!("{}:{}", public, port - 1);
+                    let forwarded = nat_test(&target).await.is_ok();
+                    report.forwarded = Some(forwarded);
+                    report.detail = format!(
+                        "{} {} a NAT test",
+                        target,
+                        if forwarded {
+                            "answered"
+                        } else {
+                            "did not answer"
+                        }
+                    );
+                }
+            }
+            Err(e) => report.detail = e,
+        }
+        return report;
+    }
+    for server in rendezvous_servers {
+        let target = nat_test_addr(server);
+        report.source = Some(server.clone());
+        match nat_test(&target).await {
+            // a NAT test reports the port only, and a NAT may keep ports unchanged
+            Ok((local_port, seen)) if local_port != seen => {
+                report.status = "behind_nat";
+                report.summary = format!("behind NAT via port {}", seen);
+                report.detail = format!(
+                    "{} saw local port {} as {}; set --external-check-url for the public address",
+                    server, local_port, seen
+                );
+            }
+            Ok((local_port, _)) => {
+                report.detail = format!(
+                    "{} saw local port {} unchanged, which a NAT may do as well",
+                    server, local_port
+                );
+            }
+            Err(e) => {
+                report.detail = format!("{}: {}", server, e);
+                continue;
+            }
+        }
+        break;
+    }
+    report
+}
+
+/// The NAT test port of a rendezvous server, one below its main port
+fn nat_test_addr(server: &str) -> String {
+    match server.rsplit_once(':') {
+        Some((host, port)) if !host.ends_with(':') => match port.parse::<u16>() {
+            Ok(port) => format!("{}:{}", host, port.saturating_sub(1)),
+            Err(_) => format!("{}:{}", server, RENDEZVOUS_PORT - 1),
+        },
+        _ => format!("{}:{}", server, RENDEZVOUS_PORT - 1),
+    }
+}
+
+/// (our local port, the port the other side saw) from a NAT test at `target`
+async fn nat_test(target: &str) -> Result<(u16, u16), String> {
+    let mut stream = FramedStream::new(target, None, CHECK_TIMEOUT_MS)
+        .await
+        .map_err(|e| format!("no connection: {}", e))?;
+    let local_port = stream.local_addr().port();
+    let mut msg_out = RendezvousMessage::new();
+    msg_out.set_test_nat_request(TestNatRequest::default());
+    stream
+        .send(&msg_out)
+        .await
+        .map_err(|e| format!("send failed: {}", e))?;
+    match stream.next_timeout(CHECK_TIMEOUT_MS).await {
+        Some(Ok(bytes)) => match RendezvousMessage::parse_from_bytes(&bytes) {
+            Ok(RendezvousMessage {
+                union: Some(rendezvous_message::Union::TestNatResponse(res)),
+                ..
+            }) => Ok((local_port, res.port as u16)),
+            _ => Err("not a NAT test response".to_owned()),
+        },
+        _ => Err("no NAT test response".to_owned()),
+    }
+}
+
+/// GET an http:// URL whose body is the caller's IP address
+async fn fetch_public_ip(url: &str) -> Result<IpAddr, String> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("{} is not an http:// URL", url))?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let addr = match host.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => host.to_owned(),
+        _ => format!("{}:80", host),
+    };
+    let timeout = Duration::from_millis(CHECK_TIMEOUT_MS);
+    let exchange = async {
+        let mut stream = TcpStream::connect(&addr).await?;
+        let request = format!(
+            "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: hbbs\r\nConnection: close\r\n\r\n",
+            path, host
+        );
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream
+            .take(MAX_RESPONSE_BYTES)
+            .read_to_end(&mut response)
+            .await?;
+        Ok::<_, std::io::Error>(response)
+    };
+    let response = match tokio::time::timeout(timeout, exchange).await {
+        Ok(Ok(response)) => String::from_utf8_lossy(&response).to_string(),
+        Ok(Err(e)) => return Err(format!("{}: {}", url, e)),
+        Err(_) => return Err(format!("{}: timed out", url)),
+    };
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| format!("{}: malformed response", url))?;
+    let status = head.split_whitespace().nth(1).unwrap_or_default();
+    if status != "200" {
+        return Err(format!("{}: status {}", url, status));
+    }
+    body.trim()
+        .parse::<IpAddr>()
+        .map_err(|_| format!("{}: {:?} is not an IP address", url, body.trim()))
+}
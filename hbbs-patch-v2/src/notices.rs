@@ -0,0 +1,78 @@
+// Notices of peer-affecting admin actions for `/api/peers/:id`
+// A rename, key reset or ban done through the API queues a notice for the
+// client, which gets it in the first registration response afterwards (field
+// hbbs::NOTICE_FIELD, JSON) and acknowledges it by registering again. Until
+// then the notice is listed with the peer, delivered_at telling whether it went
+// out yet.
+
+use hbbs::PeerNotice;
+use sqlx::{
+    sqlite::{SqlitePool, SqliteRow},
+    Row,
+};
+
+pub const RENAME: &str = "rename";
+pub const KEY_RESET: &str = "key_reset";
+pub const BAN: &str = "ban";
+
+/// Queue a notice for the client registering as `peer_id`; a registration as
+/// `ack_id` acknowledges it too
+pub async fn queue(
+    pool: &SqlitePool,
+    peer_id: &str,
+    ack_id: &str,
+    kind: &str,
+    detail: serde_json::Value,
+) -> Result<PeerNotice, sqlx::Error> {
+    let row = sqlx::query(
+        "INSERT INTO peer_notices (peer_id, ack_id, kind, detail, created_at) VALUES (?, ?, ?, ?, ?)
+         RETURNING seq, peer_id, ack_id, kind, detail, created_at, delivered_at",
+    )
+    .bind(peer_id)
+    .bind(ack_id)
+    .bind(kind)
+    .bind(detail.to_string())
+    .bind(chrono::Utc::now().timestamp())
+    .fetch_one(pool)
+    .await?;
+    hbbs::notice_queued(&[peer_id, ack_id]);
+    Ok(from_row(&row))
+}
+
+/// Drop the notices of `kind` for `peer_id` that have not gone out yet, e.g. a
+/// ban notice once the ban is lifted; returns how many
+pub async fn withdraw(pool: &SqlitePool, peer_id: &str, kind: &str) -> Result<u64, sqlx::Error> {
+    let res = sqlx::query(
+        "DELETE FROM peer_notices WHERE peer_id = ? AND kind = ? AND delivered_at IS NULL",
+    )
+    .bind(peer_id)
+    .bind(kind)
+    .execute(pool)
+    .await?;
+    Ok(res.rows_affected())
+}
+
+/// Notices a registration as `id` would deliver or acknowledge, oldest first
+pub async fn pending(pool: &SqlitePool, id: &str) -> Result<Vec<PeerNotice>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT seq, peer_id, ack_id, kind, detail, created_at, delivered_at FROM peer_notices
+         WHERE peer_id = ? OR ack_id = ? ORDER BY seq",
+    )
+    .bind(id)
+    .bind(id)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows.iter().map(from_row).collect())
+}
+
+fn from_row(row: &SqliteRow) -> PeerNotice {
+    PeerNotice {
+        seq: row.get("seq"),
+        peer_id: row.get("peer_id"),
+        ack_id: row.get("ack_id"),
+        kind: row.get("kind"),
+        detail: serde_json::from_str(&row.get::<String, _>("detail")).unwrap_or_default(),
+        created_at: row.get("created_at"),
+        delivered_at: row.get("delivered_at"),
+    }
+}
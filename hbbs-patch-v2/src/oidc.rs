@@ -0,0 +1,565 @@
+// OIDC bearer tokens for the HTTP API: OIDC_INTROSPECTION_URL
+// Besides X-API-Key, requests may carry `Authorization: Bearer <token>`. The
+// token is checked with the identity provider's introspection endpoint
+// (RFC 7662), which must call it active, issued by OIDC_ISSUER (if set) for
+// OIDC_AUDIENCE and not expired. OIDC_ROLE_CLAIM then decides the role:
+// admin (everything the API key may do) or read-only (GET requests only).
+// Results are kept for OIDC_CACHE_SECS, at most until the token expires; an
+// unreachable endpoint is not cached, and a full cache drops expired and refused
+// results but never valid ones. A request with a bearer token stands or
+// falls with the token: a failed one is refused, even next to a valid API key.
+// Local JWT verification against a JWKS is not supported.
+
+use axum::{
+    http::{header::AUTHORIZATION, HeaderMap, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hbb_common::{
+    log,
+    tokio::{
+        self,
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+        net::TcpStream,
+    },
+};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+const CACHE_SECS: u64 = 60;
+const MAX_CACHED: usize = 10_000;
+const TIMEOUT_MS: u64 = 5_000;
+const MAX_RESPONSE_BYTES: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// GET requests only
+    ReadOnly,
+    Admin,
+}
+
+/// What a token the provider called valid grants; authenticate puts it in the
+/// request's extensions for the handlers' scope check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verified {
+    pub role: Role,
+    /// `sub` of the token (`client_id` for one without)
+    pub subject: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct OidcConfig {
+    /// http:// or https://
+    pub introspection_url: String,
+    /// HTTP Basic credentials for the introspection endpoint, if it wants them
+    pub client_id: String,
+    pub client_secret: String,
+    /// `iss` the token must carry; empty: not checked
+    pub issuer: String,
+    /// Must be the token's `aud` or one of them
+    pub audience: String,
+    /// Claim with the roles, dotted for nested ones (`realm_access.roles`)
+    pub role_claim: String,
+    pub admin_roles: Vec<String>,
+    pub read_roles: Vec<String>,
+    pub cache: Duration,
+}
+
+fn list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|x| x.trim().to_owned())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+impl OidcConfig {
+    /// None without OIDC_INTROSPECTION_URL: bearer tokens are then refused
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let var = |name: &str, default: &str| {
+            std::env::var(name)
+                .map(|x| x.trim().to_owned())
+                .unwrap_or_else(|_| default.to_owned())
+        };
+        let introspection_url = var("OIDC_INTROSPECTION_URL", "");
+        if introspection_url.is_empty() {
+            return Ok(None);
+        }
+        if !introspection_url.starts_with("http://") && !introspection_url.starts_with("https://") {
+            return Err(format!(
+                "OIDC_INTROSPECTION_URL {:?} is not an http:// or https:// URL",
+                introspection_url
+            ));
+        }
+        let config = Self {
+            introspection_url,
+            client_id: var("OIDC_CLIENT_ID", ""),
+            client_secret: var("OIDC_CLIENT_SECRET", ""),
+            issuer: var("OIDC_ISSUER", ""),
+            audience: var("OIDC_AUDIENCE", ""),
+            role_claim: var("OIDC_ROLE_CLAIM", "roles"),
+            admin_roles: list(&var("OIDC_ADMIN_ROLES", "hbbs-admin")),
+            read_roles: list(&var("OIDC_READ_ROLES", "hbbs-read")),
+            cache: Duration::from_secs(crate::sync::env_u64("OIDC_CACHE_SECS", CACHE_SECS)),
+        };
+        // without it, any token of the same provider would do
+        if config.audience.is_empty() {
+            return Err("OIDC_AUDIENCE is required with OIDC_INTROSPECTION_URL".to_owned());
+        }
+        if config.role_claim.is_empty() || config.admin_roles.is_empty() {
+            return Err("OIDC_ROLE_CLAIM and OIDC_ADMIN_ROLES cannot be empty".to_owned());
+        }
+        Ok(Some(config))
+    }
+}
+
+struct Cached {
+    until: Instant,
+    result: Result<Verified, String>,
+}
+
+lazy_static::lazy_static! {
+    static ref CONFIG: RwLock<Option<Arc<OidcConfig>>> = Default::default();
+    // keyed by the token's sha256, so no token is held in memory
+    static ref CACHE: Mutex<HashMap<[u8; 32], Cached>> = Default::default();
+}
+
+static VALID: AtomicU64 = AtomicU64::new(0);
+static REJECTED: AtomicU64 = AtomicU64::new(0);
+static FAILED: AtomicU64 = AtomicU64::new(0);
+static CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+
+/// Accept bearer tokens checked against `config` from now on (None refuses
+/// them); forgets every cached result
+pub fn configure(config: Option<OidcConfig>) {
+    if let Ok(mut cache) = CACHE.lock() {
+        cache.clear();
+    }
+    if let Ok(mut current) = CONFIG.write() {
+        *current = config.map(Arc::new);
+    }
+}
+
+pub fn configured() -> Option<Arc<OidcConfig>> {
+    CONFIG.read().ok().and_then(|x| x.clone())
+}
+
+/// The token of `Authorization: Bearer <token>`
+pub fn bearer(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    if scheme.eq_ignore_ascii_case("bearer") {
+        Some(token.trim())
+    } else {
+        None
+    }
+}
+
+fn cache_key(token: &str) -> [u8; 32] {
+    sodiumoxide::crypto::hash::sha256::hash(token.as_bytes()).0
+}
+
+fn cached(token: &str) -> Option<Result<Verified, String>> {
+    let cache = CACHE.lock().ok()?;
+    match cache.get(&cache_key(token)) {
+        Some(x) if x.until > Instant::now() => Some(x.result.clone()),
+        _ => None,
+    }
+}
+
+/// Who a token the provider called valid was issued to, while it is cached;
+/// the rate limit runs before authenticate and goes by this
+pub fn subject(token: &str) -> Option<String> {
+    cached(token)?.ok()?.subject
+}
+
+/// Room for one more entry in a full cache: expired results go first, then
+/// rejected tokens. Valid ones are never dropped early; false when only they
+/// are left, and the new result is then not cached.
+fn make_room(cache: &mut HashMap<[u8; 32], Cached>, now: Instant) -> bool {
+    if cache.len() < MAX_CACHED {
+        return true;
+    }
+    cache.retain(|_, x| x.until > now);
+    if cache.len() >= MAX_CACHED {
+        cache.retain(|_, x| x.result.is_ok());
+    }
+    cache.len() < MAX_CACHED
+}
+
+/// Check `token` with the introspection endpoint, or take the cached result
+pub async fn validate(config: &OidcConfig, token: &str) -> Result<Verified, String> {
+    if let Some(result) = cached(token) {
+        CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+        return result;
+    }
+    let claims = match introspect(config, token).await {
+        Ok(claims) => claims,
+        Err(e) => {
+            // the provider's trouble, not the token's: asked again next time
+            FAILED.fetch_add(1, Ordering::Relaxed);
+            log::warn!("API: OIDC introspection failed: {}", e);
+            return Err(e);
+        }
+    };
+    let now = chrono::Utc::now().timestamp();
+    let result = check_claims(config, &claims, now).map(|role| Verified {
+        role,
+        subject: claims["sub"]
+            .as_str()
+            .or_else(|| claims["client_id"].as_str())
+            .map(str::to_owned),
+    });
+    let mut until = Instant::now() + config.cache;
+    if let (Ok(_), Some(exp)) = (&result, claims["exp"].as_i64()) {
+        until = until.min(Instant::now() + Duration::from_secs((exp - now).max(0) as u64));
+    }
+    match &result {
+        Ok(_) => VALID.fetch_add(1, Ordering::Relaxed),
+        Err(_) => REJECTED.fetch_add(1, Ordering::Relaxed),
+    };
+    if let Ok(mut cache) = CACHE.lock() {
+        if make_room(&mut cache, Instant::now()) {
+            cache.insert(
+                cache_key(token),
+                Cached {
+                    until,
+                    result: result.clone(),
+                },
+            );
+        }
+    }
+    result
+}
+
+/// The role an introspection response grants at `now` (unix seconds)
+pub fn check_claims(
+    config: &OidcConfig,
+    claims: &serde_json::Value,
+    now: i64,
+) -> Result<Role, String> {
+    if claims["active"] != true {
+        return Err("token is not active".to_owned());
+    }
+    if matches!(claims["exp"].as_i64(), Some(exp) if exp <= now) {
+        return Err("token expired".to_owned());
+    }
+    if !config.issuer.is_empty() && claims["iss"] != config.issuer.as_str() {
+        return Err(format!("token issued by {}", claims["iss"]));
+    }
+    let audience = match &claims["aud"] {
+        serde_json::Value::String(aud) => aud == &config.audience,
+        serde_json::Value::Array(auds) => auds.iter().any(|x| x == &config.audience),
+        _ => false,
+    };
+    if !audience {
+        return Err(format!("token for audience {}", claims["aud"]));
+    }
+    let mut claim = claims;
+    for part in config.role_claim.split('.') {
+        claim = &claim[part];
+    }
+    // a list, or space separated like `scope`
+    let roles: Vec<&str> = match claim {
+        serde_json::Value::String(roles) => roles.split_whitespace().collect(),
+        serde_json::Value::Array(roles) => roles.iter().filter_map(|x| x.as_str()).collect(),
+        _ => Vec::new(),
+    };
+    let has = |wanted: &[String]| roles.iter().any(|x| wanted.iter().any(|w| w == x));
+    if has(&config.admin_roles) {
+        Ok(Role::Admin)
+    } else if has(&config.read_roles) {
+        Ok(Role::ReadOnly)
+    } else {
+        Err(format!("no API role in {}", config.role_claim))
+    }
+}
+
+fn form_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    request: &[u8],
+) -> std::io::Result<Vec<u8>> {
+    stream.write_all(request).await?;
+    let mut response = Vec::new();
+    stream
+        .take(MAX_RESPONSE_BYTES)
+        .read_to_end(&mut response)
+        .await?;
+    Ok(response)
+}
+
+/// POST `body` to an http:// or https:// URL with the extra header lines
+/// `headers` (each ending in \r\n); the status code and the body of the answer
+pub(crate) async fn post(url: &str, headers: &str, body: &str) -> Result<(String, String), String> {
+    let (tls, rest) = match url.strip_prefix("https://") {
+        Some(rest) => (true, rest),
+        None => (false, url.strip_prefix("http://").unwrap_or(url)),
+    };
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (name, addr) = match host.rsplit_once(':') {
+        Some((name, port)) if port.parse::<u16>().is_ok() => (name, host.to_owned()),
+        _ => (host, format!("{}:{}", host, if tls { 443 } else { 80 })),
+    };
+    let request = format!(
+        "POST {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: hbbs\r\n{}Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        path,
+        host,
+        headers,
+        body.len(),
+        body
+    );
+    let timeout = Duration::from_millis(TIMEOUT_MS);
+    let call = async {
+        let stream = TcpStream::connect(&addr).await?;
+        if tls {
+            let connector = tokio_native_tls::native_tls::TlsConnector::new()
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            let stream = tokio_native_tls::TlsConnector::from(connector)
+                .connect(name, stream)
+                .await
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            exchange(stream, request.as_bytes()).await
+        } else {
+            exchange(stream, request.as_bytes()).await
+        }
+    };
+    let response = match tokio::time::timeout(timeout, call).await {
+        Ok(Ok(response)) => String::from_utf8_lossy(&response).to_string(),
+        Ok(Err(e)) => return Err(format!("{}: {}", url, e)),
+        Err(_) => return Err(format!("{}: timed out", url)),
+    };
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| format!("{}: malformed response", url))?;
+    let status = head.split_whitespace().nth(1).unwrap_or_default();
+    Ok((status.to_owned(), body.to_owned()))
+}
+
+/// POST the token to the introspection endpoint; the parsed JSON answer
+async fn introspect(config: &OidcConfig, token: &str) -> Result<serde_json::Value, String> {
+    let url = &config.introspection_url;
+    let body = format!("token={}&token_type_hint=access_token", form_encode(token));
+    let mut headers = "Accept: application/json\r\n\
+                       Content-Type: application/x-www-form-urlencoded\r\n"
+        .to_owned();
+    if !config.client_id.is_empty() {
+        let credentials = format!(
+            "{}:{}",
+            form_encode(&config.client_id),
+            form_encode(&config.client_secret)
+        );
+        headers += &format!("Authorization: Basic {}\r\n", base64::encode(credentials));
+    }
+    let (status, body) = post(url, &headers, &body).await?;
+    if status != "200" {
+        return Err(format!("{}: status {}", url, status));
+    }
+    serde_json::from_str(&body).map_err(|e| format!("{}: {}", url, e))
+}
+
+/// Route layer: requests with a bearer token pass only with a valid one, and
+/// read-only tokens only for GET requests, with the Verified result in their
+/// extensions. Requests without one go on to the handlers' API key check.
+pub async fn authenticate<B>(mut req: Request<B>, next: Next<B>) -> Response {
+    let token = match bearer(req.headers()) {
+        Some(token) => token.to_owned(),
+        None => return next.run(req).await,
+    };
+    let config = match configured() {
+        Some(config) => config,
+        None => {
+            log::warn!("API: Bearer token refused, OIDC_INTROSPECTION_URL is not set");
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+    };
+    match validate(&config, &token).await {
+        Ok(verified)
+            if verified.role == Role::Admin
+                || *req.method() == Method::GET
+                || *req.method() == Method::HEAD =>
+        {
+            req.extensions_mut().insert(verified);
+            next.run(req).await
+        }
+        Ok(_) => {
+            log::warn!(
+                "API: Read-only token refused for {} {}",
+                req.method(),
+                req.uri().path()
+            );
+            StatusCode::FORBIDDEN.into_response()
+        }
+        Err(e) => {
+            log::warn!("API: Bearer token refused: {}", e);
+            StatusCode::UNAUTHORIZED.into_response()
+        }
+    }
+}
+
+pub fn render_metrics() -> String {
+    let mut out = String::new();
+    out.push_str("# HELP hbbs_api_oidc_tokens_total Bearer tokens checked by outcome\n");
+    out.push_str("# TYPE hbbs_api_oidc_tokens_total counter\n");
+    for (outcome, n) in [
+        ("valid", &VALID),
+        ("rejected", &REJECTED),
+        ("failed", &FAILED),
+        ("cached", &CACHE_HITS),
+    ] {
+        out.push_str(&format!(
+            "hbbs_api_oidc_tokens_total{{outcome=\"{}\"}} {}\n",
+            outcome,
+            n.load(Ordering::Relaxed)
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(until: Instant, valid: bool) -> Cached {
+        Cached {
+            until,
+            result: if valid {
+                Ok(Verified {
+                    role: Role::ReadOnly,
+                    subject: None,
+                })
+            } else {
+                Err("token is not active".to_owned())
+            },
+        }
+    }
+
+    fn full(
+        valid: impl Fn(usize) -> bool,
+        until: impl Fn(usize) -> Instant,
+    ) -> HashMap<[u8; 32], Cached> {
+        (0..MAX_CACHED)
+            .map(|i| (cache_key(&i.to_string()), entry(until(i), valid(i))))
+            .collect()
+    }
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn config() -> OidcConfig {
+        OidcConfig {
+            introspection_url: String::new(),
+            client_id: String::new(),
+            client_secret: String::new(),
+            issuer: "https://sso.example.com".to_owned(),
+            audience: "hbbs".to_owned(),
+            role_claim: "realm_access.roles".to_owned(),
+            admin_roles: vec!["hbbs-admin".to_owned()],
+            read_roles: vec!["hbbs-read".to_owned()],
+            cache: Duration::from_secs(60),
+        }
+    }
+
+    fn claims(aud: serde_json::Value, roles: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({
+            "active": true,
+            "iss": "https://sso.example.com",
+            "aud": aud,
+            "exp": NOW + 3600,
+            "realm_access": { "roles": roles },
+        })
+    }
+
+    #[test]
+    fn claims_grant_roles() {
+        let config = config();
+        let admin = claims(
+            serde_json::json!(["grafana", "hbbs"]),
+            serde_json::json!(["hbbs-read", "hbbs-admin"]),
+        );
+        assert_eq!(check_claims(&config, &admin, NOW), Ok(Role::Admin));
+        let read = claims("hbbs".into(), serde_json::json!(["offline", "hbbs-read"]));
+        assert_eq!(check_claims(&config, &read, NOW), Ok(Role::ReadOnly));
+
+        // space separated like `scope`, and no issuer check when none is set
+        let config = OidcConfig {
+            issuer: String::new(),
+            role_claim: "scope".to_owned(),
+            ..config
+        };
+        let scope = serde_json::json!({
+            "active": true,
+            "iss": "https://other.example.com",
+            "aud": "hbbs",
+            "scope": "openid hbbs-read",
+        });
+        assert_eq!(check_claims(&config, &scope, NOW), Ok(Role::ReadOnly));
+    }
+
+    #[test]
+    fn claims_refused() {
+        let config = config();
+        let admin = || claims("hbbs".into(), serde_json::json!(["hbbs-admin"]));
+        let with = |field: &str, value: serde_json::Value| {
+            let mut claims = admin();
+            claims[field] = value;
+            check_claims(&config, &claims, NOW)
+        };
+        assert_eq!(
+            with("active", false.into()),
+            Err("token is not active".to_owned())
+        );
+        assert_eq!(with("exp", NOW.into()), Err("token expired".to_owned()));
+        assert_eq!(
+            with("iss", "https://evil.example.com".into()),
+            Err("token issued by \"https://evil.example.com\"".to_owned())
+        );
+        assert_eq!(
+            with("aud", "grafana".into()),
+            Err("token for audience \"grafana\"".to_owned())
+        );
+        assert_eq!(
+            with("realm_access", serde_json::json!({ "roles": ["offline"] })),
+            Err("no API role in realm_access.roles".to_owned())
+        );
+        assert!(check_claims(&config, &serde_json::json!({ "active": false }), NOW).is_err());
+    }
+
+    #[test]
+    fn full_cache_drops_expired_then_rejected() {
+        let now = Instant::now();
+        let later = now + Duration::from_secs(60);
+        let mut cache = full(|_| true, |i| if i == 0 { now } else { later });
+        assert!(make_room(&mut cache, now));
+        assert_eq!(cache.len(), MAX_CACHED - 1);
+
+        let mut cache = full(|i| i % 2 == 0, |_| later);
+        assert!(make_room(&mut cache, now));
+        assert_eq!(cache.len(), MAX_CACHED / 2);
+        assert!(cache.values().all(|x| x.result.is_ok()));
+    }
+
+    #[test]
+    fn full_cache_keeps_valid_tokens() {
+        let now = Instant::now();
+        let mut cache = full(|_| true, |_| now + Duration::from_secs(60));
+        assert!(!make_room(&mut cache, now));
+        assert_eq!(cache.len(), MAX_CACHED);
+    }
+}
@@ -1,41 +1,377 @@
 use crate::common::*;
 use crate::database;
+use crate::rendezvous_server::{
+    count_ban_rejection, count_key_change, count_sweep, io_loop_lag, record_error,
+    reset_ban_rejections, supervise, ErrorReason, Histogram, Transport,
+};
 use hbb_common::{
     bytes::Bytes,
     log,
     rendezvous_proto::*,
-    tokio::sync::{Mutex, RwLock},
+    tokio::sync::{watch, Mutex, RwLock},
     tokio,
     ResultType,
 };
 use serde_derive::{Deserialize, Serialize};
-use std::{collections::HashMap, collections::HashSet, net::SocketAddr, sync::Arc, time::Instant};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+    sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering},
+    sync::Arc,
+    time::Instant,
+};
 
 type IpBlockMap = HashMap<String, ((u32, Instant), (HashSet<String>, Instant))>;
-type UserStatusMap = HashMap<Vec<u8>, Arc<(Option<Vec<u8>>, bool)>>;
+// user -> (disabled, when it was read from the users table)
+type UserStatusMap = HashMap<Vec<u8>, (bool, Instant)>;
 type IpChangesMap = HashMap<String, (Instant, HashMap<String, i32>)>;
 
 lazy_static::lazy_static! {
     pub(crate) static ref IP_BLOCKER: Mutex<IpBlockMap> = Default::default();
-    pub(crate) static ref USER_STATUS: RwLock<UserStatusMap> = Default::default();
+    pub(crate) static ref USER_STATUS: std::sync::RwLock<UserStatusMap> = Default::default();
     pub(crate) static ref IP_CHANGES: Mutex<IpChangesMap> = Default::default();
     pub(crate) static ref ID_CHANGE_COOLDOWN: Mutex<HashMap<String, Instant>> = Default::default();
+    static ref UUID_CHURN: Mutex<HashMap<String, UuidChurn>> = Default::default();
+    static ref PROTOCOL_VERSIONS: std::sync::Mutex<ProtocolVersions> = Default::default();
+    // (UTC day number, id) of peers active for the first time that day, not yet stored
+    static ref ACTIVE_PENDING: std::sync::Mutex<Vec<(i64, String)>> = Default::default();
+    // last udp datagram per source address, corroborates heartbeat timeouts
+    static ref LAST_PACKET: std::sync::Mutex<HashMap<SocketAddr, Instant>> = Default::default();
+    static ref PEER_MAP_SHARE: watch::Sender<Option<PeerMapHandle>> = watch::channel(None).0;
+    // known peers coming back after this start, None once the recovery window is over
+    static ref RECOVERY: std::sync::Mutex<Option<(SharedClock, RecoveryTracker)>> =
+        Default::default();
 }
 
 pub const IP_CHANGE_DUR: u64 = 180;
 pub const IP_CHANGE_DUR_X2: u64 = IP_CHANGE_DUR * 2;
 pub const DAY_SECONDS: u64 = 3600 * 24;
 pub const IP_BLOCK_DUR: u64 = 60;
+pub const IP_BLOCK_MAX_REGS: u32 = 30; // registrations per IP_BLOCK_DUR before an IP is throttled
 
 // Status tracking constants
 const HEARTBEAT_TIMEOUT_SECS: u64 = 15;  // Mark offline after 15s without heartbeat (was 30s)
-const CLEANUP_INTERVAL_SECS: u64 = 60;   // Check for stale peers every 60s
+const HEARTBEAT_INTERVAL_SECS: u64 = 3;  // Expected heartbeat interval, for the peer statistics
+const SWEEP_INTERVAL_SECS: u64 = 5;      // Offline sweep tick (PEER_SWEEP_INTERVAL_SECS, 0 disables)
+const SWEEP_CHUNK: u64 = 5_000;          // Peers checked per sweep tick (PEER_SWEEP_CHUNK)
 const ID_CHANGE_COOLDOWN_SECS: u64 = 300; // 5 minutes between ID changes per device
+const STALE_LAST_ONLINE_SECS: i64 = 300; // last_online lagging an alive peer by more than this is drift
+const SERVER_RUN_TOUCH_SECS: u64 = 60; // How often the current server run is extended in the event log
+const ARCHIVE_INTERVAL_SECS: u64 = 6 * 3600; // How often old status events are moved to the monthly archives
+const BAN_EXPIRY_SECS: u64 = 60; // Clearing of expired temporary bans (BAN_EXPIRY_SECS, 0 disables)
+const AUDIT_ANCHOR_SECS: u64 = 3600; // Audit chain anchors (AUDIT_ANCHOR_SECS, 0 disables)
+const RECOVERY_WINDOW_SECS: u64 = 3600; // Recovery after a start (RECOVERY_WINDOW_SECS, 0 disables)
+const RECOVERY_TICK_SECS: u64 = 10;
+const RECOVERY_MILESTONES: [u32; 3] = [50, 90, 99];
+const VERIFY_PAGE_SIZE: u64 = 5_000; // Rows per read of a full consistency pass (VERIFY_PAGE_SIZE)
+const LAST_PACKET_MAX_ADDRS: usize = 500_000;
+const SUSPECT_MAX_TIMEOUTS: u32 = 10; // evicted peers still sending are marked offline after this many timeouts
+const USER_STATUS_TTL_SECS: u64 = 60; // A user's disable flag is re-read after this (USER_STATUS_TTL_SECS)
+const USER_STATUS_MAX: usize = 10_000;
+
+// uuid churn: distinct uuids registering from one IP within the window
+const UUID_CHURN_THRESHOLD: u64 = 50; // UUID_CHURN_THRESHOLD, well above a busy office NAT
+const UUID_CHURN_WINDOW_SECS: u64 = DAY_SECONDS; // UUID_CHURN_WINDOW_SECS
+const UUID_CHURN_MAX_IPS: usize = 50_000;
+const UUID_CHURN_MAX_IDS: usize = 100; // ids remembered per IP for the anomaly report
+
+// protocol versions: client versions seen in registrations and punch holes
+const PROTOCOL_FLUSH_SECS: u64 = 300; // PROTOCOL_FLUSH_SECS, counts added to the day's rows
+const PROTOCOL_WARN_SECS: u64 = 3600; // PROTOCOL_WARN_SECS, between deprecated-version warnings
+const PROTOCOL_MAX_VERSIONS: usize = 200; // per kind and transport, the rest count as "other"
+const PROTOCOL_MAX_PEERS: usize = 1_000; // ids remembered per deprecated version
+const PROTOCOL_WARN_IDS: usize = 20; // ids listed per version in the warning
+const PROTOCOL_MAX_VERSION_LEN: usize = 32;
+const ACTIVE_FLUSH_SECS: u64 = 10; // ACTIVE_FLUSH_SECS, first heartbeats of the day stored
+const ACTIVE_MAX_PENDING: usize = 500_000; // marks kept while the database fails
+static ACTIVE_MARKS: AtomicU64 = AtomicU64::new(0);
+
+// Credential sanity limits (ed25519 pk is 32 bytes, uuids are machine ids of a few dozen bytes)
+const MAX_UUID_LEN: usize = 128;
+const MAX_PK_LEN: usize = 64;
+static MALFORMED_CREDENTIALS: AtomicUsize = AtomicUsize::new(0);
+static PK_CHANGE_POLICY: AtomicU8 = AtomicU8::new(PkChangePolicy::Auto as u8);
+
+lazy_static::lazy_static! {
+    pub(crate) static ref SWEEP_TICK: Histogram = Default::default();
+    pub(crate) static ref SWEEP_PASS: Histogram = Default::default();
+}
+
+/// Numeric setting from the environment, `default` when unset or invalid
+pub(crate) fn env_u64(name: &str, default: u64) -> u64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .unwrap_or(default)
+}
+
+/// Time as the peer timing logic sees it: heartbeat timeouts, cooldowns, ip
+/// throttles and the offline sweep. Durations that are only measured (latency
+/// histograms) stay on Instant, and so do timestamps compared with the ones
+/// SQLite writes itself.
+pub trait Clock: Send + Sync {
+    fn now_instant(&self) -> Instant;
+    fn now_utc(&self) -> chrono::DateTime<chrono::Utc>;
+
+    /// Time since `earlier` on this clock, zero for an instant in its future
+    fn elapsed(&self, earlier: Instant) -> std::time::Duration {
+        self.now_instant().duration_since(earlier)
+    }
+}
+
+pub type SharedClock = Arc<dyn Clock>;
+
+/// The clock the server runs on
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_instant(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn now_utc(&self) -> chrono::DateTime<chrono::Utc> {
+        chrono::Utc::now()
+    }
+}
+
+/// The system clock plus whatever it was advanced by, so that a test can let a
+/// timeout or cooldown pass at once instead of sleeping through it. It never
+/// goes backwards.
+#[derive(Default)]
+pub struct TestClock {
+    offset: std::sync::Mutex<std::time::Duration>,
+}
+
+impl TestClock {
+    pub fn advance(&self, by: std::time::Duration) {
+        if let Ok(mut offset) = self.offset.lock() {
+            *offset += by;
+        }
+    }
+
+    pub fn offset(&self) -> std::time::Duration {
+        self.offset.lock().map(|x| *x).unwrap_or_default()
+    }
+}
+
+impl Clock for TestClock {
+    fn now_instant(&self) -> Instant {
+        Instant::now() + self.offset()
+    }
+
+    fn now_utc(&self) -> chrono::DateTime<chrono::Utc> {
+        chrono::Utc::now()
+            + chrono::Duration::from_std(self.offset()).unwrap_or_else(|_| chrono::Duration::zero())
+    }
+}
+
+/// Seconds without a heartbeat before a peer counts as offline (PEER_TIMEOUT_SECS)
+pub fn peer_timeout_secs() -> u64 {
+    env_u64("PEER_TIMEOUT_SECS", HEARTBEAT_TIMEOUT_SECS)
+}
+
+/// Seconds between the heartbeats of a healthy peer (HEARTBEAT_INTERVAL_SECS); the
+/// peer statistics count the missed ones
+pub fn heartbeat_interval_secs() -> u64 {
+    env_u64("HEARTBEAT_INTERVAL_SECS", HEARTBEAT_INTERVAL_SECS).max(1)
+}
+
+/// (name, entries or None when its lock is busy, approximate bytes per entry)
+/// of the maps kept here, for the memory accounting
+pub(crate) fn memory_sources() -> Vec<(&'static str, Option<usize>, usize)> {
+    use crate::rendezvous_server::map_entry_bytes;
+    let pm = PEER_MAP_SHARE
+        .subscribe()
+        .borrow()
+        .as_ref()
+        .map(|x| x.0.clone());
+    vec![
+        (
+            "peer_map",
+            pm.as_ref()
+                .and_then(|pm| pm.map.try_read().ok().map(|x| x.len())),
+            // id, Arc counts, and guid/uuid/pk/ip owned by the peer
+            map_entry_bytes::<String, LockPeer>(
+                16 + 16 + std::mem::size_of::<RwLock<Peer>>() + 128,
+            ),
+        ),
+        (
+            "peer_addrs",
+            pm.as_ref()
+                .and_then(|pm| pm.addrs.try_read().ok().map(|x| x.len())),
+            map_entry_bytes::<SocketAddr, String>(16),
+        ),
+        (
+            "ip_blocker",
+            IP_BLOCKER.try_lock().ok().map(|x| x.len()),
+            // the ip and a small set of ids
+            map_entry_bytes::<String, ((u32, Instant), (HashSet<String>, Instant))>(16 + 96),
+        ),
+        (
+            "ip_changes",
+            IP_CHANGES.try_lock().ok().map(|x| x.len()),
+            map_entry_bytes::<String, (Instant, HashMap<String, i32>)>(16 + 64),
+        ),
+        (
+            "id_change_cooldown",
+            ID_CHANGE_COOLDOWN.try_lock().ok().map(|x| x.len()),
+            map_entry_bytes::<String, Instant>(16),
+        ),
+        (
+            "uuid_churn",
+            UUID_CHURN.try_lock().ok().map(|x| x.len()),
+            map_entry_bytes::<String, UuidChurn>(16 + 96),
+        ),
+        (
+            "user_status",
+            USER_STATUS.try_read().ok().map(|x| x.len()),
+            map_entry_bytes::<Vec<u8>, (bool, Instant)>(16),
+        ),
+        (
+            "last_packet",
+            LAST_PACKET.try_lock().ok().map(|x| x.len()),
+            map_entry_bytes::<SocketAddr, Instant>(0),
+        ),
+    ]
+}
+
+fn user_status_ttl() -> u64 {
+    env_u64("USER_STATUS_TTL_SECS", USER_STATUS_TTL_SECS)
+}
+
+/// After a user was disabled or enabled: read its flag again on the next check
+pub fn forget_user_status(user: &str) {
+    if let Ok(mut lock) = USER_STATUS.write() {
+        lock.remove(user.as_bytes());
+    }
+}
+
+/// Remember that a udp datagram arrived from `addr` (called for every packet)
+pub(crate) fn note_udp_packet(addr: SocketAddr, clock: &dyn Clock) {
+    if let Ok(mut lock) = LAST_PACKET.lock() {
+        if lock.len() < LAST_PACKET_MAX_ADDRS || lock.contains_key(&addr) {
+            lock.insert(addr, clock.now_instant());
+        }
+    }
+}
+
+fn packet_seen_within(addr: SocketAddr, window: std::time::Duration, clock: &dyn Clock) -> bool {
+    LAST_PACKET
+        .lock()
+        .ok()
+        .and_then(|lock| lock.get(&addr).map(|t| clock.elapsed(*t) <= window))
+        .unwrap_or(false)
+}
+
+/// Whether a peer evicted at `evicted` while its address still sent is offline
+/// now: its address went quiet for a timeout, or it stayed a suspect too long
+fn suspect_confirmed(
+    addr: SocketAddr,
+    evicted: Instant,
+    timeout: std::time::Duration,
+    clock: &dyn Clock,
+) -> bool {
+    !packet_seen_within(addr, timeout, clock)
+        || clock.elapsed(evicted) >= timeout * SUSPECT_MAX_TIMEOUTS
+}
+
+/// Whether the offline sweep may run: a loop (the io loop or the sweep itself)
+/// more than two sweep intervals behind means missed heartbeats are our fault
+pub fn offline_pass_allowed(
+    io_loop_lag: std::time::Duration,
+    tick_lag: std::time::Duration,
+    every: std::time::Duration,
+) -> bool {
+    io_loop_lag.max(tick_lag) <= every * 2
+}
+
+/// Number of registrations rejected because of an empty or oversized uuid/pk
+pub fn malformed_credential_count() -> usize {
+    MALFORMED_CREDENTIALS.load(Ordering::SeqCst)
+}
+
+/// Characters that come along when an id is copied from a chat or a web page
+const ZERO_WIDTH: [char; 5] = ['\u{200B}', '\u{200C}', '\u{200D}', '\u{2060}', '\u{FEFF}'];
+
+/// The one form ids are stored and looked up in, for every message, API path
+/// and body: whitespace (clients show ids grouped, "123 456 789") and zero-width
+/// characters removed, letters upper case like the API always stored them.
+/// Borrowed when `raw` already is canonical, as it is for nearly every message.
+/// An id with a '~' is empty, which no peer has and registrations refuse as too
+/// short: soft-deleted rows are renamed to `<id>~deleted~<guid>` and must stay
+/// out of reach of clients and the API.
+pub fn canonical_id(raw: &str) -> Cow<'_, str> {
+    if raw
+        .bytes()
+        .all(|b| b.is_ascii_digit() || b.is_ascii_uppercase() || b == b'-' || b == b'_')
+    {
+        return Cow::Borrowed(raw);
+    }
+    if raw.contains('~') {
+        return Cow::Borrowed("");
+    }
+    Cow::Owned(
+        raw.chars()
+            .filter(|c| !c.is_whitespace() && !ZERO_WIDTH.contains(c))
+            .flat_map(char::to_uppercase)
+            .collect(),
+    )
+}
+
+/// canonical_id in place, for ids of messages the server consumes itself
+pub(crate) fn canonicalize(id: &mut String) {
+    if let Cow::Owned(canonical) = canonical_id(id) {
+        *id = canonical;
+    }
+}
+
+/// Compare credentials without leaking the position of the first differing byte
+#[inline]
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Reject credentials a well-behaved client never sends; an empty uuid would
+/// otherwise match the empty default of a peer created by `get_or`
+pub(crate) fn check_credentials(id: &str, uuid: &[u8], pk: &[u8]) -> bool {
+    let problem = if uuid.is_empty() {
+        "empty uuid"
+    } else if uuid.len() > MAX_UUID_LEN {
+        "oversized uuid"
+    } else if pk.is_empty() {
+        "empty pk"
+    } else if pk.len() > MAX_PK_LEN {
+        "oversized pk"
+    } else {
+        return true;
+    };
+    let n = MALFORMED_CREDENTIALS.fetch_add(1, Ordering::SeqCst) + 1;
+    log::warn!(
+        "Rejected malformed credentials for {}: {} (uuid {} bytes, pk {} bytes, total rejected {})",
+        id,
+        problem,
+        uuid.len(),
+        pk.len(),
+        n
+    );
+    false
+}
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub(crate) struct PeerInfo {
     #[serde(default)]
     pub(crate) ip: String,
+    /// udp, tcp or ws: how the peer last registered, kept across restarts
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub(crate) transport: String,
+    /// Client version from the peer's last punch hole exchange, see version_label
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub(crate) version: String,
 }
 
 pub(crate) struct Peer {
@@ -48,6 +384,18 @@ pub(crate) struct Peer {
     pub(crate) reg_pk: (u32, Instant),
     // Track last heartbeat for online status
     pub(crate) last_heartbeat: Instant,
+    // Whether an online transition was written to the status-event log
+    pub(crate) online: bool,
+    // Ips a RegisterPk with matching uuid and pk came from lately, oldest first
+    pub(crate) verified_ips: Vec<(IpAddr, Instant)>,
+    // Configuration serial of the last RegisterPeer, None before one this run
+    pub(crate) serial: Option<i32>,
+    // Path of the last registration or heartbeat, None before one this run
+    pub(crate) transport: Option<Transport>,
+    // UTC day (days since the epoch) the peer was last counted as active on
+    pub(crate) active_day: Option<i64>,
+    // User the peer is assigned to (peer.user), whose disable switch applies to it
+    pub(crate) user: Option<Vec<u8>>,
 }
 
 impl Default for Peer {
@@ -61,6 +409,12 @@ impl Default for Peer {
             info: Default::default(),
             reg_pk: (0, get_expired_time()),
             last_heartbeat: Instant::now(),
+            online: false,
+            verified_ips: Vec::new(),
+            serial: None,
+            transport: None,
+            active_day: None,
+            user: None,
         }
     }
 }
@@ -68,133 +422,1441 @@ impl Default for Peer {
 pub(crate) type LockPeer = Arc<RwLock<Peer>>;
 
 /// Statistics about online peers
+#[derive(Clone, Default)]
 pub struct PeerStats {
     pub total: usize,
     pub healthy: usize,
     pub degraded: usize,
     pub critical: usize,
+    /// Online peers by the transport they last registered over
+    pub transports: HashMap<&'static str, usize>,
+}
+
+/// Distinct uuids (and the ids they registered) seen from one source IP in the current window
+struct UuidChurn {
+    since: Instant,
+    uuids: HashSet<Bytes>,
+    ids: Vec<String>,
+    throttled: usize,
+}
+
+/// A source IP over the uuid churn threshold, as reported by the API
+#[derive(Clone, Debug)]
+pub struct UuidChurnEntry {
+    pub ip: String,
+    pub distinct_uuids: usize,
+    pub ids: Vec<String>,
+    pub throttled: usize,
+    pub window_age_secs: u64,
+}
+
+pub fn uuid_churn_threshold() -> usize {
+    env_u64("UUID_CHURN_THRESHOLD", UUID_CHURN_THRESHOLD) as _
+}
+
+pub fn uuid_churn_window() -> u64 {
+    env_u64("UUID_CHURN_WINDOW_SECS", UUID_CHURN_WINDOW_SECS)
+}
+
+/// Record a registration's uuid for its source IP. Returns false when the IP is
+/// already over the churn threshold and this uuid is a new one, i.e. the
+/// registration should be throttled.
+pub async fn check_uuid_churn(ip: &str, id: &str, uuid: &Bytes, clock: &dyn Clock) -> bool {
+    let threshold = uuid_churn_threshold();
+    if threshold == 0 {
+        return true;
+    }
+    let window = uuid_churn_window();
+    let now = clock.now_instant();
+    let mut lock = UUID_CHURN.lock().await;
+    if lock.len() >= UUID_CHURN_MAX_IPS && !lock.contains_key(ip) {
+        lock.retain(|_, v| now.duration_since(v.since).as_secs() < window);
+        if lock.len() >= UUID_CHURN_MAX_IPS {
+            return true;
+        }
+    }
+    let churn = lock.entry(ip.to_owned()).or_insert_with(|| UuidChurn {
+        since: now,
+        uuids: Default::default(),
+        ids: Default::default(),
+        throttled: 0,
+    });
+    if now.duration_since(churn.since).as_secs() >= window {
+        churn.since = now;
+        churn.uuids.clear();
+        churn.ids.clear();
+        churn.throttled = 0;
+    }
+    if churn.uuids.contains(uuid) {
+        return true;
+    }
+    if churn.uuids.len() >= threshold {
+        churn.throttled += 1;
+        if churn.throttled == 1 {
+            log::warn!(
+                "uuid churn from {}: {} distinct uuids, throttling new registrations",
+                ip,
+                churn.uuids.len()
+            );
+        }
+        return false;
+    }
+    churn.uuids.insert(uuid.clone());
+    if churn.ids.len() < UUID_CHURN_MAX_IDS && !churn.ids.iter().any(|x| x == id) {
+        churn.ids.push(id.to_owned());
+    }
+    true
+}
+
+/// Source IPs currently over the uuid churn threshold
+pub async fn uuid_churn_anomalies() -> Vec<UuidChurnEntry> {
+    let threshold = uuid_churn_threshold();
+    let window = uuid_churn_window();
+    if threshold == 0 {
+        return Vec::new();
+    }
+    UUID_CHURN
+        .lock()
+        .await
+        .iter()
+        .filter(|(_, v)| v.uuids.len() >= threshold && v.since.elapsed().as_secs() < window)
+        .map(|(ip, v)| UuidChurnEntry {
+            ip: ip.clone(),
+            distinct_uuids: v.uuids.len(),
+            ids: v.ids.clone(),
+            throttled: v.throttled,
+            window_age_secs: v.since.elapsed().as_secs(),
+        })
+        .collect()
+}
+
+/// Which message a protocol version was read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProtocolKind {
+    /// RegisterPeer or RegisterPk; neither carries a version, so the peer's
+    /// last reported one counts ("unknown" before it reported any)
+    Registration,
+    /// PunchHoleRequest, the initiator's version
+    PunchRequest,
+    /// PunchHoleSent or LocalAddr, the version of the peer being reached
+    PunchReply,
+}
+
+impl ProtocolKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProtocolKind::Registration => "registration",
+            ProtocolKind::PunchRequest => "punch_request",
+            ProtocolKind::PunchReply => "punch_reply",
+        }
+    }
+}
+
+type VersionCounts = HashMap<(&'static str, &'static str), HashMap<String, u64>>;
+
+/// Versions seen per (kind, transport), since the start and not yet persisted
+#[derive(Default)]
+struct ProtocolVersions {
+    total: VersionCounts,
+    pending: VersionCounts,
+    /// deprecated-versions setting: versions and version prefixes ("1.1" for 1.1.x)
+    deprecated: Vec<String>,
+    /// Deprecated version -> ids whose last reported version it is
+    deprecated_peers: HashMap<String, HashSet<String>>,
+    last_warning: Option<Instant>,
+}
+
+impl ProtocolVersions {
+    fn is_deprecated(&self, version: &str) -> bool {
+        self.deprecated.iter().any(|x| {
+            version == x || (version.starts_with(x.as_str()) && version[x.len()..].starts_with('.'))
+        })
+    }
+}
+
+fn count_version(
+    counts: &mut VersionCounts,
+    key: (&'static str, &'static str),
+    version: &str,
+    n: u64,
+) {
+    let versions = counts.entry(key).or_default();
+    if let Some(count) = versions.get_mut(version) {
+        *count += n;
+    } else if versions.len() < PROTOCOL_MAX_VERSIONS {
+        versions.insert(version.to_owned(), n);
+    } else {
+        *versions.entry("other".to_owned()).or_default() += n;
+    }
+}
+
+/// A client-supplied version as counted: "none" when empty (clients from before
+/// the version field), "invalid" when it is not a plain version string
+pub fn version_label(version: &str) -> &str {
+    if version.is_empty() {
+        "none"
+    } else if version.len() > PROTOCOL_MAX_VERSION_LEN
+        || !version
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+    {
+        "invalid"
+    } else {
+        version
+    }
+}
+
+/// Count one message's version; `id` is the peer that sent it, when known
+pub(crate) fn note_protocol_version(
+    kind: ProtocolKind,
+    transport: Transport,
+    version: &str,
+    id: Option<&str>,
+) {
+    let mut lock = match PROTOCOL_VERSIONS.lock() {
+        Ok(lock) => lock,
+        Err(_) => return,
+    };
+    let key = (kind.as_str(), transport.as_str());
+    count_version(&mut lock.total, key, version, 1);
+    count_version(&mut lock.pending, key, version, 1);
+    if let Some(id) = id {
+        if lock.is_deprecated(version) {
+            let peers = lock.deprecated_peers.entry(version.to_owned()).or_default();
+            if peers.len() < PROTOCOL_MAX_PEERS {
+                peers.insert(id.to_owned());
+            }
+        }
+    }
+}
+
+/// Forget `id` on a deprecated version it no longer reports
+fn version_changed(id: &str, before: &str) {
+    if let Ok(mut lock) = PROTOCOL_VERSIONS.lock() {
+        if let Some(peers) = lock.deprecated_peers.get_mut(before) {
+            peers.remove(id);
+        }
+    }
+}
+
+/// Versions (and prefixes, "1.1" for every 1.1.x) to warn about, comma separated
+pub fn set_deprecated_versions(list: &str) {
+    if let Ok(mut lock) = PROTOCOL_VERSIONS.lock() {
+        lock.deprecated = list
+            .split(',')
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .map(|x| x.to_owned())
+            .collect();
+        let deprecated = std::mem::take(&mut lock.deprecated_peers);
+        lock.deprecated_peers = deprecated
+            .into_iter()
+            .filter(|(version, _)| lock.is_deprecated(version))
+            .collect();
+    }
+}
+
+pub fn deprecated_versions() -> Vec<String> {
+    PROTOCOL_VERSIONS
+        .lock()
+        .map(|x| x.deprecated.clone())
+        .unwrap_or_default()
+}
+
+/// Messages seen with one version over one transport
+#[derive(Debug, Clone, Serialize)]
+pub struct ProtocolVersionCount {
+    pub kind: &'static str,
+    pub transport: &'static str,
+    pub version: String,
+    pub count: u64,
+}
+
+fn version_rows(counts: &VersionCounts) -> Vec<ProtocolVersionCount> {
+    let mut rows: Vec<ProtocolVersionCount> = counts
+        .iter()
+        .flat_map(|((kind, transport), versions)| {
+            versions
+                .iter()
+                .map(move |(version, count)| ProtocolVersionCount {
+                    kind,
+                    transport,
+                    version: version.clone(),
+                    count: *count,
+                })
+        })
+        .collect();
+    rows.sort_by(|a, b| (a.kind, a.transport, &a.version).cmp(&(b.kind, b.transport, &b.version)));
+    rows
+}
+
+/// Versions seen since the start
+pub fn protocol_versions() -> Vec<ProtocolVersionCount> {
+    PROTOCOL_VERSIONS
+        .lock()
+        .map(|x| version_rows(&x.total))
+        .unwrap_or_default()
+}
+
+/// Peers last seen on a deprecated version
+#[derive(Debug, Clone, Serialize)]
+pub struct DeprecatedVersion {
+    pub version: String,
+    pub peers: Vec<String>,
+}
+
+pub fn deprecated_version_peers() -> Vec<DeprecatedVersion> {
+    let mut list: Vec<DeprecatedVersion> = match PROTOCOL_VERSIONS.lock() {
+        Ok(lock) => lock
+            .deprecated_peers
+            .iter()
+            .filter(|(_, peers)| !peers.is_empty())
+            .map(|(version, peers)| {
+                let mut peers: Vec<String> = peers.iter().cloned().collect();
+                peers.sort();
+                DeprecatedVersion {
+                    version: version.clone(),
+                    peers,
+                }
+            })
+            .collect(),
+        Err(_) => return Vec::new(),
+    };
+    list.sort_by(|a, b| a.version.cmp(&b.version));
+    list
+}
+
+/// Log the peers still on deprecated versions, at most once per
+/// PROTOCOL_WARN_SECS; true when it logged
+pub fn warn_deprecated_versions() -> bool {
+    let every = env_u64("PROTOCOL_WARN_SECS", PROTOCOL_WARN_SECS);
+    let list = deprecated_version_peers();
+    if list.is_empty() {
+        return false;
+    }
+    match PROTOCOL_VERSIONS.lock() {
+        Ok(mut lock) => {
+            if lock.last_warning.map_or(false, |x| x.elapsed().as_secs() < every) {
+                return false;
+            }
+            lock.last_warning = Some(Instant::now());
+        }
+        Err(_) => return false,
+    }
+    let list: Vec<String> = list
+        .iter()
+        .map(|x| {
+            let more = x.peers.len().saturating_sub(PROTOCOL_WARN_IDS);
+            let mut ids = x.peers[..x.peers.len() - more].join(", ");
+            if more > 0 {
+                ids.push_str(&format!(" and {} more", more));
+            }
+            format!("{}: {}", x.version, ids)
+        })
+        .collect();
+    log::warn!("Peers on deprecated protocol versions: {}", list.join("; "));
+    true
+}
+
+/// Add the counts not yet persisted to today's rows (UTC); they are kept for
+/// the next flush when the write fails
+pub async fn flush_protocol_versions(db: &database::Database) -> ResultType<()> {
+    let pending = match PROTOCOL_VERSIONS.lock() {
+        Ok(mut lock) => std::mem::take(&mut lock.pending),
+        Err(_) => return Ok(()),
+    };
+    if pending.is_empty() {
+        return Ok(());
+    }
+    let day = chrono::Utc::now().format("%Y-%m-%d").to_string();
+    let rows = version_rows(&pending);
+    if let Err(e) = db.add_protocol_versions(&day, &rows).await {
+        if let Ok(mut lock) = PROTOCOL_VERSIONS.lock() {
+            for x in rows {
+                count_version(
+                    &mut lock.pending,
+                    (x.kind, x.transport),
+                    &x.version,
+                    x.count,
+                );
+            }
+        }
+        return Err(e);
+    }
+    Ok(())
+}
+
+async fn protocol_version_loop(db: database::Database) {
+    let every = env_u64("PROTOCOL_FLUSH_SECS", PROTOCOL_FLUSH_SECS);
+    if every == 0 {
+        log::info!("Protocol version counts are not persisted (PROTOCOL_FLUSH_SECS=0)");
+        return;
+    }
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(every));
+    loop {
+        interval.tick().await;
+        if let Err(e) = flush_protocol_versions(&db).await {
+            log::warn!("Failed to store protocol version counts: {}", e);
+        }
+        warn_deprecated_versions();
+    }
+}
+
+/// Queue `id` as active on `day` (days since the epoch, UTC) for the next flush
+fn mark_active(day: i64, id: &str) {
+    ACTIVE_MARKS.fetch_add(1, Ordering::Relaxed);
+    if let Ok(mut pending) = ACTIVE_PENDING.lock() {
+        if pending.len() < ACTIVE_MAX_PENDING {
+            pending.push((day, id.to_owned()));
+        }
+    }
+}
+
+/// First heartbeats of a peer on a UTC day since the start
+pub fn active_peer_marks() -> u64 {
+    ACTIVE_MARKS.load(Ordering::Relaxed)
+}
+
+/// Store the peers marked active since the last flush; they are kept for the
+/// next one when the write fails
+pub async fn flush_active_peers(db: &database::Database) -> ResultType<()> {
+    let pending = match ACTIVE_PENDING.lock() {
+        Ok(mut lock) => std::mem::take(&mut *lock),
+        Err(_) => return Ok(()),
+    };
+    if pending.is_empty() {
+        return Ok(());
+    }
+    if let Err(e) = db.add_active_peers(&pending).await {
+        if let Ok(mut lock) = ACTIVE_PENDING.lock() {
+            let room = ACTIVE_MAX_PENDING.saturating_sub(lock.len());
+            lock.extend(pending.into_iter().take(room));
+        }
+        return Err(e);
+    }
+    Ok(())
+}
+
+async fn active_peer_loop(db: database::Database) {
+    let every = env_u64("ACTIVE_FLUSH_SECS", ACTIVE_FLUSH_SECS).max(1);
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(every));
+    loop {
+        interval.tick().await;
+        if let Err(e) = flush_active_peers(&db).await {
+            log::warn!("Failed to store active peers: {}", e);
+        }
+    }
 }
 
+/// Clears the ban flag of temporary bans past their banned_until. The ban stops
+/// counting when it expires; this only keeps the flag from lingering.
+async fn ban_expiry_loop(db: database::Database) {
+    let every = env_u64("BAN_EXPIRY_SECS", BAN_EXPIRY_SECS);
+    if every == 0 {
+        log::info!("Ban expiry sweep disabled (BAN_EXPIRY_SECS=0)");
+        return;
+    }
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(every));
+    loop {
+        interval.tick().await;
+        match db.clear_expired_bans().await {
+            Ok(ids) => {
+                for id in ids {
+                    log::info!("Ban of {} expired", id);
+                    reset_ban_rejections(&id);
+                    db.audit("server", "ban_expired", &id, String::new()).await;
+                }
+            }
+            Err(e) => log::warn!("Failed to clear expired bans: {}", e),
+        }
+    }
+}
+
+/// With AUDIT_CHAIN=Y, appends the audit chain head to the anchor file and logs
+/// it whenever the chain grew since the last anchor
+async fn audit_anchor_loop(db: database::Database, file: std::path::PathBuf) {
+    if !database::audit_chain_enabled() {
+        return;
+    }
+    let every = env_u64("AUDIT_ANCHOR_SECS", AUDIT_ANCHOR_SECS);
+    if every == 0 {
+        log::info!("Audit anchors disabled (AUDIT_ANCHOR_SECS=0)");
+        return;
+    }
+    let mut last = database::read_audit_anchors(&file).pop().map(|x| x.seq);
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(every));
+    loop {
+        interval.tick().await;
+        match db.audit_chain_head().await {
+            Ok(Some(anchor)) if Some(anchor.seq) != last => {
+                if let Err(e) = database::append_audit_anchor(&file, &anchor) {
+                    log::warn!("Failed to write audit anchor to {}: {}", file.display(), e);
+                    continue;
+                }
+                log::info!(
+                    "Audit anchor: seq {} hash {} ({})",
+                    anchor.seq,
+                    anchor.hash,
+                    file.display()
+                );
+                last = Some(anchor.seq);
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!("Failed to read the audit chain head: {}", e),
+        }
+    }
+}
+
+/// Time for one milestone of the recovery after a start
+#[derive(Debug, Clone, Serialize)]
+pub struct RecoveryMilestone {
+    pub percent: u32,
+    /// Known peers that make up the percentage, rounded up
+    pub peers: usize,
+    /// Seconds from the start until they were back, None while not reached
+    pub after_secs: Option<i64>,
+}
+
+/// How far the peers known at a start are back online
+#[derive(Debug, Clone, Serialize)]
+pub struct RecoveryStatus {
+    /// Unix time of the start
+    pub started_at: i64,
+    pub elapsed_secs: i64,
+    /// Peers not deleted at the start
+    pub known_peers: usize,
+    /// Distinct known peers online since the start
+    pub recovered_peers: usize,
+    pub milestones: Vec<RecoveryMilestone>,
+}
+
+/// Distinct known peers coming back online after a start and when each
+/// milestone of RECOVERY_MILESTONES was reached
+pub struct RecoveryTracker {
+    started_at: i64,
+    known: usize,
+    waiting: HashSet<String>,
+    reached: [Option<i64>; 3],
+}
+
+impl RecoveryTracker {
+    /// Tracker for a start at `started_at` (unix time) waiting for `ids`
+    pub fn new(started_at: i64, ids: impl IntoIterator<Item = String>) -> Self {
+        let waiting: HashSet<String> = ids.into_iter().collect();
+        Self {
+            started_at,
+            known: waiting.len(),
+            waiting,
+            reached: Default::default(),
+        }
+    }
+
+    /// Known peers that make up `percent`, rounded up
+    fn target(&self, percent: u32) -> usize {
+        (self.known * percent as usize + 99) / 100
+    }
+
+    /// Count `id` back online at `at`; returns the milestones this reached.
+    /// Unknown ids and peers already back are ignored.
+    pub fn back(&mut self, id: &str, at: i64) -> Vec<u32> {
+        if !self.waiting.remove(id) {
+            return Vec::new();
+        }
+        let back = self.known - self.waiting.len();
+        let mut reached = Vec::new();
+        for (i, &percent) in RECOVERY_MILESTONES.iter().enumerate() {
+            if self.reached[i].is_none() && back >= self.target(percent) {
+                self.reached[i] = Some((at - self.started_at).max(0));
+                reached.push(percent);
+            }
+        }
+        reached
+    }
+
+    /// Whether every milestone was reached
+    pub fn complete(&self) -> bool {
+        self.reached.iter().all(|x| x.is_some())
+    }
+
+    pub fn status(&self, now: i64) -> RecoveryStatus {
+        RecoveryStatus {
+            started_at: self.started_at,
+            elapsed_secs: (now - self.started_at).max(0),
+            known_peers: self.known,
+            recovered_peers: self.known - self.waiting.len(),
+            milestones: RECOVERY_MILESTONES
+                .iter()
+                .zip(self.reached.iter())
+                .map(|(&percent, &after_secs)| RecoveryMilestone {
+                    percent,
+                    peers: self.target(percent),
+                    after_secs,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Start tracking the recovery of `ids`, the peers known at this start
+fn start_recovery(clock: SharedClock, ids: Vec<String>) {
+    if ids.is_empty() {
+        return;
+    }
+    let tracker = RecoveryTracker::new(clock.now_utc().timestamp(), ids);
+    if let Ok(mut lock) = RECOVERY.lock() {
+        *lock = Some((clock, tracker));
+    }
+}
+
+/// Count `id` back online for the recovery after the start
+fn peer_back(id: &str) {
+    let status = match RECOVERY.lock() {
+        Ok(mut lock) => match lock.as_mut() {
+            Some((clock, tracker)) => {
+                let now = clock.now_utc().timestamp();
+                if !tracker.back(id, now).contains(&99) {
+                    return;
+                }
+                tracker.status(now)
+            }
+            None => return,
+        },
+        Err(_) => return,
+    };
+    let after = |i: usize| status.milestones[i].after_secs.unwrap_or_default();
+    log::info!(
+        "Recovery: 99% of {} known peers back {}s after the start (50% after {}s, 90% after {}s)",
+        status.known_peers,
+        after(2),
+        after(0),
+        after(1)
+    );
+}
+
+/// Recovery after the start, None without known peers or once the window is over
+pub fn recovery_status() -> Option<RecoveryStatus> {
+    let lock = RECOVERY.lock().ok()?;
+    let (clock, tracker) = lock.as_ref()?;
+    Some(tracker.status(clock.now_utc().timestamp()))
+}
+
+/// Stores the recovery milestones in restart_history once all are reached or
+/// the window ends, and stops tracking at the end of the window
+async fn recovery_loop(db: database::Database, window: u64) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(RECOVERY_TICK_SECS));
+    let mut recorded = false;
+    loop {
+        interval.tick().await;
+        let (status, complete) = match RECOVERY.lock() {
+            Ok(lock) => match lock.as_ref() {
+                Some((clock, tracker)) => (
+                    tracker.status(clock.now_utc().timestamp()),
+                    tracker.complete(),
+                ),
+                None => return,
+            },
+            Err(_) => return,
+        };
+        let over = status.elapsed_secs >= window as i64;
+        if !recorded && (complete || over) {
+            match db.record_restart(&status).await {
+                Ok(()) => recorded = true,
+                Err(e) => log::warn!("Failed to store the restart recovery: {}", e),
+            }
+        }
+        if over {
+            if let Ok(mut lock) = RECOVERY.lock() {
+                *lock = None;
+            }
+            return;
+        }
+    }
+}
+
+/// Drift found (and repaired towards the in-memory state) by one consistency pass
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DriftReport {
+    pub full: bool,
+    pub checked: usize,
+    pub memory_online_db_offline: usize,
+    pub memory_online_db_missing: usize,
+    pub db_online_memory_missing: usize,
+    pub stale_last_online: usize,
+    pub repaired: usize,
+    pub duration_ms: u64,
+    pub finished_at: String,
+}
+
+impl DriftReport {
+    pub fn total(&self) -> usize {
+        self.memory_online_db_offline
+            + self.memory_online_db_missing
+            + self.db_online_memory_missing
+            + self.stale_last_online
+    }
+}
+
+/// Why a peer left the online set
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OfflineReason {
+    /// No heartbeat within the timeout
+    Timeout,
+    /// The client closed its connection cleanly (websocket close frame / FIN)
+    CleanShutdown,
+    /// The connection was reset or errored out
+    ConnectionLost,
+    /// We closed the connection ourselves (id change, ban, maintenance)
+    ServerDisconnect,
+}
+
+impl OfflineReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Timeout => "timeout",
+            Self::CleanShutdown => "clean_shutdown",
+            Self::ConnectionLost => "connection_lost",
+            Self::ServerDisconnect => "server_disconnect",
+        }
+    }
+}
+
+/// What to do when a known device (matching uuid) registers with a different pk,
+/// e.g. after being restored from a backup (`--pk-change-policy`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PkChangePolicy {
+    /// Overwrite the stored key (the historical behavior)
+    Auto = 0,
+    /// Park the change until an admin approves it via the API
+    Approve = 1,
+    /// Always refuse the new key
+    Reject = 2,
+}
+
+impl PkChangePolicy {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "auto" => Some(Self::Auto),
+            "approve" => Some(Self::Approve),
+            "reject" => Some(Self::Reject),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Auto => "auto",
+            Self::Approve => "approve",
+            Self::Reject => "reject",
+        }
+    }
+}
+
+pub fn pk_change_policy() -> PkChangePolicy {
+    match PK_CHANGE_POLICY.load(Ordering::SeqCst) {
+        1 => PkChangePolicy::Approve,
+        2 => PkChangePolicy::Reject,
+        _ => PkChangePolicy::Auto,
+    }
+}
+
+pub fn set_pk_change_policy(policy: PkChangePolicy) {
+    PK_CHANGE_POLICY.store(policy as u8, Ordering::SeqCst);
+}
+
+/// Short printable fingerprint of a public key (first 16 bytes of its sha256, hex)
+pub fn pk_fingerprint(pk: &[u8]) -> String {
+    sodiumoxide::crypto::hash::sha256::hash(pk).0[..16]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Time since the events the per-peer timers count from, read from live state
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TimerInputs {
+    pub since_heartbeat: Option<std::time::Duration>,
+    pub since_id_change: Option<std::time::Duration>,
+    /// registrations counted for the peer's IP and time since the last accepted one
+    pub ip_window: Option<(u32, std::time::Duration)>,
+}
+
+/// Seconds left on each per-peer timer, 0 when the timer is not running
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct PeerTimers {
+    /// until the sweep may mark the peer offline if no heartbeat arrives
+    pub offline_in_secs: u64,
+    pub id_change_cooldown_secs: u64,
+    pub registration_throttle_secs: u64,
+}
+
+/// All timer arithmetic, shared by the API, the rejection logs and client hints
+pub fn peer_timers(inputs: &TimerInputs, peer_timeout_secs: u64) -> PeerTimers {
+    let left = |total: u64, since: std::time::Duration| total.saturating_sub(since.as_secs());
+    PeerTimers {
+        // online while elapsed <= timeout
+        offline_in_secs: inputs
+            .since_heartbeat
+            .map_or(0, |x| left(peer_timeout_secs, x)),
+        // blocked while elapsed < cooldown
+        id_change_cooldown_secs: inputs
+            .since_id_change
+            .map_or(0, |x| left(ID_CHANGE_COOLDOWN_SECS, x)),
+        // check_ip_blocker keeps the count until elapsed > IP_BLOCK_DUR
+        registration_throttle_secs: match inputs.ip_window {
+            Some((n, x)) if n > IP_BLOCK_MAX_REGS => left(IP_BLOCK_DUR + 1, x),
+            _ => 0,
+        },
+    }
+}
+
+/// View of the live PeerMap for the API thread; read-only apart from evicting
+/// a banned peer
 #[derive(Clone)]
-pub(crate) struct PeerMap {
-    map: Arc<RwLock<HashMap<String, LockPeer>>>,
-    pub(crate) db: database::Database,
+pub struct PeerMapHandle(PeerMap);
+
+impl PeerMapHandle {
+    /// Drop a peer from memory and mark it offline, so that the next lookup reads
+    /// the database (a ban, or that it was lifted) at once. False when it was not
+    /// in memory.
+    pub async fn evict(&self, id: &str) -> bool {
+        if !self.0.is_in_memory(id).await {
+            return false;
+        }
+        self.0.mark_offline(id, OfflineReason::ServerDisconnect).await;
+        true
+    }
+
+    /// Put `n` peers that never registered into memory as `<prefix>00000` on,
+    /// every other one past the peer timeout, to load the periodic jobs in tests
+    pub async fn insert_synthetic(&self, prefix: &str, n: usize) {
+        let now = self.0.clock.now_instant();
+        let timeout = std::time::Duration::from_secs(peer_timeout_secs() * 2);
+        let stale = now.checked_sub(timeout).unwrap_or(now);
+        let mut map = self.0.map.write().await;
+        for i in 0..n {
+            let peer = Peer {
+                last_heartbeat: if i % 2 == 0 { stale } else { now },
+                ..Default::default()
+            };
+            map.insert(format!("{}{:05}", prefix, i), Arc::new(RwLock::new(peer)));
+        }
+    }
+
+    /// Ids in memory with a heartbeat within the peer timeout
+    pub async fn online_ids(&self) -> HashSet<String> {
+        let timeout = std::time::Duration::from_secs(peer_timeout_secs());
+        let peers: Vec<(String, LockPeer)> = self
+            .0
+            .map
+            .read()
+            .await
+            .iter()
+            .map(|(id, peer)| (id.clone(), peer.clone()))
+            .collect();
+        let mut online = HashSet::new();
+        for (id, peer) in peers {
+            if self.0.clock.elapsed(peer.read().await.last_heartbeat) <= timeout {
+                online.insert(id);
+            }
+        }
+        online
+    }
+
+    /// Tags of a peer (its `tags` attribute) from the cache the access rules and
+    /// relay bindings go by
+    pub async fn tags(&self, id: &str) -> ResultType<Vec<String>> {
+        crate::rendezvous_server::peer_tags(&self.0.db, id).await
+    }
+
+    /// Timers of one peer, recomputed from the current state on every call
+    pub async fn timers(&self, id: &str) -> PeerTimers {
+        let mut inputs = TimerInputs::default();
+        let mut ip = None;
+        if let Some(peer) = self.0.get_in_memory(id).await {
+            let peer = peer.read().await;
+            inputs.since_heartbeat = Some(self.0.clock.elapsed(peer.last_heartbeat));
+            ip = Some(peer.info.ip.clone());
+        }
+        inputs.since_id_change = ID_CHANGE_COOLDOWN
+            .lock()
+            .await
+            .get(id)
+            .map(|t| self.0.clock.elapsed(*t));
+        if let Some(ip) = ip {
+            inputs.ip_window = IP_BLOCKER
+                .lock()
+                .await
+                .get(&ip)
+                .map(|x| (x.0 .0, self.0.clock.elapsed(x.0 .1)));
+        }
+        peer_timers(&inputs, peer_timeout_secs())
+    }
+
+    /// (id, serial of its last registration, online) of the peers in memory that
+    /// registered since start
+    pub async fn serials(&self) -> Vec<(String, i32, bool)> {
+        let timeout = std::time::Duration::from_secs(peer_timeout_secs());
+        let peers: Vec<(String, LockPeer)> = self
+            .0
+            .map
+            .read()
+            .await
+            .iter()
+            .map(|(id, peer)| (id.clone(), peer.clone()))
+            .collect();
+        let mut out = Vec::new();
+        for (id, peer) in peers {
+            let peer = peer.read().await;
+            if let Some(serial) = peer.serial {
+                out.push((
+                    id,
+                    serial,
+                    self.0.clock.elapsed(peer.last_heartbeat) <= timeout,
+                ));
+            }
+        }
+        out
+    }
+
+    /// Transport of a peer in memory that registered since start
+    pub async fn transport(&self, id: &str) -> Option<&'static str> {
+        let peer = self.0.get_in_memory(id).await?;
+        let transport = peer.read().await.transport;
+        transport.map(|x| x.as_str())
+    }
+
+    /// Transports of the peers in memory that registered since start, by id
+    pub async fn transports(&self) -> HashMap<String, &'static str> {
+        let peers: Vec<(String, LockPeer)> = self
+            .0
+            .map
+            .read()
+            .await
+            .iter()
+            .map(|(id, peer)| (id.clone(), peer.clone()))
+            .collect();
+        let mut out = HashMap::new();
+        for (id, peer) in peers {
+            if let Some(transport) = peer.read().await.transport {
+                out.insert(id, transport.as_str());
+            }
+        }
+        out
+    }
+
+    /// Peer statistics from the current state rather than the periodic snapshot
+    pub async fn stats(&self) -> PeerStats {
+        self.0.get_stats().await
+    }
+
+    pub async fn is_known(&self, id: &str) -> bool {
+        self.0.get_in_memory(id).await.is_some()
+    }
+
+    pub async fn is_online(&self, id: &str) -> bool {
+        let timeout = std::time::Duration::from_secs(peer_timeout_secs());
+        match self.0.get_in_memory(id).await {
+            Some(peer) => self.0.clock.elapsed(peer.read().await.last_heartbeat) <= timeout,
+            None => false,
+        }
+    }
+
+    /// Address a peer in memory last registered from
+    pub async fn socket_addr(&self, id: &str) -> Option<SocketAddr> {
+        let peer = self.0.get_in_memory(id).await?;
+        let addr = peer.read().await.socket_addr;
+        Some(addr)
+    }
+
+    /// Assign a peer in memory to another user, or to none
+    pub async fn set_user(&self, id: &str, user: Option<Vec<u8>>) {
+        if let Some(peer) = self.0.get_in_memory(id).await {
+            peer.write().await.user = user;
+        }
+    }
+
+    /// Point a peer in memory at the row its duplicates were merged into
+    pub async fn set_guid(&self, id: &str, guid: Vec<u8>) {
+        if let Some(peer) = self.0.get_in_memory(id).await {
+            let mut w = peer.write().await;
+            if !w.guid.is_empty() {
+                w.guid = guid;
+            }
+        }
+    }
 }
 
-impl PeerMap {
-    pub(crate) async fn new() -> ResultType<Self> {
-        let db = std::env::var("DB_URL").unwrap_or({
-            let mut db = "db_v2.sqlite3".to_owned();
-            #[cfg(all(windows, not(debug_assertions)))]
-            {
-                if let Some(path) = hbb_common::config::Config::icon_path().parent() {
-                    db = format!("{}\\{}", path.to_str().unwrap_or("."), db);
+/// Keeps the PeerMap published to the API; dropping it (the rendezvous side ended,
+/// panicked or is restarting) withdraws the map so the API answers from the database
+/// Move status events older than EVENT_HOT_DAYS into the monthly archive files and
+/// delete archives beyond EVENT_ARCHIVE_KEEP_MONTHS (0 keeps them all)
+async fn archive_loop(db: database::Database) {
+    let hot_days = env_u64("EVENT_HOT_DAYS", database::EVENT_HOT_DAYS);
+    if hot_days == 0 {
+        return;
+    }
+    let keep_months = env_u64("EVENT_ARCHIVE_KEEP_MONTHS", 0) as u32;
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(ARCHIVE_INTERVAL_SECS));
+    loop {
+        interval.tick().await;
+        let now = chrono::Utc::now().timestamp();
+        match db
+            .archive_events(now - (hot_days * DAY_SECONDS) as i64)
+            .await
+        {
+            Ok(moved) => {
+                for (month, n) in moved {
+                    log::info!("Archived {} status events to archive/{}.sqlite3", n, month);
                 }
             }
-            #[cfg(not(windows))]
-            {
-                db = format!("./{db}");
+            Err(e) => log::warn!("Failed to archive status events: {}", e),
+        }
+        if keep_months > 0 {
+            for month in db.prune_archives(keep_months, now) {
+                log::info!("Deleted status event archive {}", month);
             }
-            db
-        });
+        }
+    }
+}
+
+pub(crate) struct PeerMapShare;
+
+impl Drop for PeerMapShare {
+    fn drop(&mut self) {
+        PEER_MAP_SHARE.send_replace(None);
+    }
+}
+
+pub(crate) fn share_peer_map(pm: &PeerMap) -> PeerMapShare {
+    PEER_MAP_SHARE.send_replace(Some(PeerMapHandle(pm.clone())));
+    PeerMapShare
+}
+
+/// The API's end of the PeerMap share, None while no rendezvous server is running
+pub fn peer_map_watch() -> watch::Receiver<Option<PeerMapHandle>> {
+    PEER_MAP_SHARE.subscribe()
+}
+
+/// The database the peer map opens: DB_URL, else db_v2.sqlite3 in the working
+/// directory (next to the executable's icon on Windows)
+pub fn db_url() -> String {
+    std::env::var("DB_URL").unwrap_or({
+        let mut db = "db_v2.sqlite3".to_owned();
+        #[cfg(all(windows, not(debug_assertions)))]
+        {
+            if let Some(path) = hbb_common::config::Config::icon_path().parent() {
+                db = format!("{}\\{}", path.to_str().unwrap_or("."), db);
+            }
+        }
+        #[cfg(not(windows))]
+        {
+            db = format!("./{db}");
+        }
+        db
+    })
+}
+
+#[derive(Clone)]
+pub(crate) struct PeerMap {
+    map: Arc<RwLock<HashMap<String, LockPeer>>>,
+    // last registered socket address -> id, to tell who sent a request
+    addrs: Arc<RwLock<HashMap<SocketAddr, String>>>,
+    pub(crate) db: database::Database,
+    pub(crate) clock: SharedClock,
+}
+
+impl PeerMap {
+    pub(crate) async fn new(clock: SharedClock) -> ResultType<Self> {
+        let db = db_url();
         log::info!("DB_URL={}", db);
-        
-        let database = database::Database::new(&db).await?;
-        
+
+        let database = database::Database::new(&db)
+            .await?
+            .with_clock(clock.clone());
+
         // Reset all devices to offline on startup (clean slate)
         if let Err(e) = database.set_all_offline().await {
             log::warn!("Failed to reset devices to offline: {}", e);
         }
+        // Time how long the known devices take to come back
+        let window = env_u64("RECOVERY_WINDOW_SECS", RECOVERY_WINDOW_SECS);
+        if window > 0 {
+            match database.known_peer_ids().await {
+                Ok(ids) => {
+                    start_recovery(clock.clone(), ids);
+                    let recovery_db = database.clone();
+                    supervise("recovery", move || {
+                        recovery_loop(recovery_db.clone(), window)
+                    });
+                }
+                Err(e) => log::warn!("Failed to read the known peers for recovery: {}", e),
+            }
+        }
         
+        // Open a server run in the status-event log; the time between the previous run's
+        // last touch and this start is unknown to uptime reports
+        match database.start_server_run().await {
+            Ok(run) => {
+                let db = database.clone();
+                supervise("server_run", move || {
+                    let db = db.clone();
+                    async move {
+                        let mut interval = tokio::time::interval(std::time::Duration::from_secs(
+                            SERVER_RUN_TOUCH_SECS,
+                        ));
+                        loop {
+                            interval.tick().await;
+                            if let Err(e) = db.touch_server_run(run).await {
+                                log::warn!("Failed to extend server run: {}", e);
+                            }
+                        }
+                    }
+                });
+            }
+            Err(e) => log::warn!("Failed to record server start: {}", e),
+        }
+        let archive_db = database.clone();
+        supervise("archive", move || archive_loop(archive_db.clone()));
+        let writer_db = database.clone();
+        supervise("status_writer", move || {
+            database::status_writer_loop(writer_db.clone())
+        });
+        let version_db = database.clone();
+        supervise("protocol_versions", move || {
+            protocol_version_loop(version_db.clone())
+        });
+        let active_db = database.clone();
+        supervise("active_peers", move || active_peer_loop(active_db.clone()));
+        let anchor_db = database.clone();
+        let anchor_file = database::audit_anchor_file(&db);
+        supervise("audit_anchor", move || {
+            audit_anchor_loop(anchor_db.clone(), anchor_file.clone())
+        });
+        supervise("storage_monitor", move || {
+            database::monitor_storage(db.clone())
+        });
+
         let pm = Self {
             map: Default::default(),
+            addrs: Default::default(),
             db: database,
+            clock,
         };
         
         // Start background task to check for stale peers and set them offline
         let pm_clone = pm.clone();
-        tokio::spawn(async move {
-            pm_clone.status_cleanup_loop().await;
+        supervise("offline_sweep", move || {
+            let pm = pm_clone.clone();
+            async move { pm.status_cleanup_loop().await }
         });
+        let ban_db = pm.db.clone();
+        supervise("ban_expiry", move || ban_expiry_loop(ban_db.clone()));
         
         Ok(pm)
     }
     
-    /// Background loop to detect stale peers and mark them offline
+    /// Background sweep marking stale peers offline. Each tick checks at most
+    /// PEER_SWEEP_CHUNK peers of a snapshot, so a pass over a large map is spread
+    /// across ticks and never holds the map lock while peers are inspected.
     async fn status_cleanup_loop(&self) {
-        let mut interval = tokio::time::interval(std::time::Duration::from_secs(CLEANUP_INTERVAL_SECS));
-        
+        let every = env_u64("PEER_SWEEP_INTERVAL_SECS", SWEEP_INTERVAL_SECS);
+        if every == 0 {
+            log::info!("Offline sweep disabled (PEER_SWEEP_INTERVAL_SECS=0)");
+            return;
+        }
+        let chunk = env_u64("PEER_SWEEP_CHUNK", SWEEP_CHUNK).max(1) as usize;
+        log::info!("Offline sweep every {}s, {} peers per tick", every, chunk);
+        let every = std::time::Duration::from_secs(every);
+        let mut interval = tokio::time::interval(every);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut pending: Vec<(String, LockPeer)> = Vec::new();
+        // evicted from memory while their address was still sending: (addr, evicted at, was online)
+        let mut suspects: HashMap<String, (SocketAddr, Instant, bool)> = HashMap::new();
+        let mut pass_peers = 0;
+        let mut pass_busy = std::time::Duration::ZERO;
+
         loop {
-            interval.tick().await;
-            
-            let now = Instant::now();
-            let timeout = std::time::Duration::from_secs(HEARTBEAT_TIMEOUT_SECS);
+            let scheduled = interval.tick().await;
+            let started = Instant::now();
+
+            let (io_lag, tick_lag) = (io_loop_lag(), scheduled.elapsed());
+            if !offline_pass_allowed(io_lag, tick_lag, every) {
+                log::warn!(
+                    "Skipping offline sweep: server is behind (io loop {:?}, sweep {:?}), missed heartbeats are not the peers' fault",
+                    io_lag,
+                    tick_lag
+                );
+                count_sweep("skipped", 1);
+                continue;
+            }
+
+            if pending.is_empty() {
+                pending = self
+                    .map
+                    .read()
+                    .await
+                    .iter()
+                    .map(|(id, peer)| (id.clone(), peer.clone()))
+                    .collect();
+                pass_peers = pending.len();
+            }
+            let batch = pending.split_off(pending.len().saturating_sub(chunk));
+
+            let now = self.clock.now_instant();
+            let timeout = std::time::Duration::from_secs(peer_timeout_secs());
             let mut stale_peers = Vec::new();
-            
-            // Find stale peers
-            {
-                let map = self.map.read().await;
-                for (id, peer) in map.iter() {
-                    let peer_data = peer.read().await;
-                    if now.duration_since(peer_data.last_heartbeat) > timeout {
-                        stale_peers.push(id.clone());
-                    }
+            for (id, peer) in batch {
+                if now.duration_since(peer.read().await.last_heartbeat) > timeout {
+                    stale_peers.push((id, peer));
                 }
             }
-            
-            // Set stale peers offline and remove from memory
+
+            // Evict stale peers from memory; only those whose address went quiet
+            // too are marked offline, the others wait as suspects
+            let mut offline: Vec<(String, bool)> = Vec::new();
+            let mut evicted_only = 0;
             if !stale_peers.is_empty() {
-                log::info!("Marking {} stale peers as offline", stale_peers.len());
-                
-                // Batch update database
-                if let Err(e) = self.db.batch_set_offline(&stale_peers).await {
-                    log::error!("Failed to batch set offline: {}", e);
-                }
-                
-                // Remove from memory map
-                {
-                    let mut map = self.map.write().await;
-                    for id in &stale_peers {
+                let mut map = self.map.write().await;
+                for (id, peer) in &stale_peers {
+                    // the peer may have been dropped and registered again since the snapshot
+                    if map.get(id).map_or(false, |x| Arc::ptr_eq(x, peer)) {
                         map.remove(id);
                         log::debug!("Removed stale peer {} from memory", id);
+                        let mut w = peer.write().await;
+                        self.unindex_addr(w.socket_addr, id).await;
+                        let was_online = std::mem::replace(&mut w.online, false);
+                        if packet_seen_within(w.socket_addr, timeout, &*self.clock) {
+                            suspects.insert(id.clone(), (w.socket_addr, now, was_online));
+                            evicted_only += 1;
+                        } else {
+                            offline.push((id.clone(), was_online));
+                        }
                     }
                 }
             }
-            
-            // Cleanup IP blocker and IP changes maps
-            self.cleanup_ip_maps().await;
+            count_sweep("offline", offline.len());
+            count_sweep("evicted_only", evicted_only);
+
+            // Suspects are confirmed once their address goes quiet, or after too long
+            if !suspects.is_empty() {
+                let map = self.map.read().await;
+                let mut confirmed = 0;
+                suspects.retain(|id, (addr, evicted, was_online)| {
+                    if map.contains_key(id) {
+                        return false;
+                    }
+                    if !suspect_confirmed(*addr, *evicted, timeout, &*self.clock) {
+                        return true;
+                    }
+                    offline.push((id.clone(), *was_online));
+                    confirmed += 1;
+                    false
+                });
+                count_sweep("confirmed", confirmed);
+            }
+
+            if !offline.is_empty() {
+                log::info!(
+                    "Marking {} stale peers as offline (reason={})",
+                    offline.len(),
+                    OfflineReason::Timeout.as_str()
+                );
+                let ids: Vec<String> = offline.iter().map(|(id, _)| id.clone()).collect();
+                if let Err(e) = self.db.batch_set_offline(&ids).await {
+                    log::error!("Failed to batch set offline: {}", e);
+                }
+                let went_offline = offline
+                    .into_iter()
+                    .filter(|(_, was_online)| *was_online)
+                    .map(|(id, _)| id)
+                    .collect();
+                self.db
+                    .record_status_events(went_offline, false, OfflineReason::Timeout.as_str())
+                    .await;
+            }
+
+            let elapsed = started.elapsed();
+            SWEEP_TICK.observe(elapsed);
+            pass_busy += elapsed;
+            if elapsed >= every {
+                log::warn!(
+                    "Offline sweep tick took {:?}, longer than its {:?} interval; lower PEER_SWEEP_CHUNK",
+                    elapsed,
+                    every
+                );
+            }
+            if pending.is_empty() {
+                // Cleanup IP blocker and IP changes maps once per pass
+                self.cleanup_ip_maps().await;
+                SWEEP_PASS.observe(pass_busy);
+                log::debug!(
+                    "Offline sweep pass over {} peers took {:?}",
+                    pass_peers,
+                    pass_busy
+                );
+                pass_busy = std::time::Duration::ZERO;
+            }
         }
     }
-    
+
     /// Cleanup stale entries from IP maps
     async fn cleanup_ip_maps(&self) {
-        let now = Instant::now();
+        let now = self.clock.now_instant();
         
         // Cleanup IP_BLOCKER
         {
             let mut blocker = IP_BLOCKER.lock().await;
             blocker.retain(|_, ((_, t1), (_, t2))| {
-                now.duration_since(*t1).as_secs() < IP_BLOCK_DUR &&
-                now.duration_since(*t2).as_secs() < DAY_SECONDS
+                now.duration_since(*t1).as_secs() < IP_BLOCK_DUR
+                    && now.duration_since(*t2).as_secs() < DAY_SECONDS
             });
         }
         
         // Cleanup IP_CHANGES
         {
             let mut changes = IP_CHANGES.lock().await;
-            changes.retain(|_, (t, _)| {
-                now.duration_since(*t).as_secs() < IP_CHANGE_DUR_X2
-            });
+            changes.retain(|_, (t, _)| now.duration_since(*t).as_secs() < IP_CHANGE_DUR_X2);
+        }
+
+        // Cleanup UUID_CHURN
+        {
+            let window = uuid_churn_window();
+            let mut churn = UUID_CHURN.lock().await;
+            churn.retain(|_, v| now.duration_since(v.since).as_secs() < window);
+        }
+
+        // Cleanup USER_STATUS, stale entries are re-read anyway
+        {
+            let ttl = std::time::Duration::from_secs(user_status_ttl());
+            if let Ok(mut status) = USER_STATUS.write() {
+                status.retain(|_, (_, t)| now.duration_since(*t) < ttl);
+            }
+        }
+
+        // Cleanup LAST_PACKET, only the last timeout window is ever consulted
+        {
+            let keep = std::time::Duration::from_secs(peer_timeout_secs() * 2);
+            if let Ok(mut packets) = LAST_PACKET.lock() {
+                packets.retain(|_, t| now.duration_since(*t) < keep);
+            }
         }
     }
 
     /// Update heartbeat and set device online
     pub(crate) async fn touch_peer(&self, id: &str) {
-        if let Some(peer) = self.map.read().await.get(id) {
-            peer.write().await.last_heartbeat = Instant::now();
+        let mut source = id.to_owned();
+        if let Some(peer) = self.get_in_memory(id).await {
+            let mut w = peer.write().await;
+            w.last_heartbeat = self.clock.now_instant();
+            // counted once per UTC day, later heartbeats stop at the comparison
+            let today = self.clock.now_utc().timestamp().div_euclid(DAY_SECONDS as i64);
+            if w.active_day != Some(today) {
+                w.active_day = Some(today);
+                mark_active(today, id);
+            }
+            if !std::mem::replace(&mut w.online, true) {
+                self.db
+                    .record_status_events(vec![id.to_owned()], true, "heartbeat")
+                    .await;
+                peer_back(id);
+            }
+            if !w.info.ip.is_empty() {
+                source = w.info.ip.clone();
+            }
         }
         // Update database status
-        self.db.set_online(id).await;
+        self.db.set_online(id, &source).await;
+    }
+
+    /// Remember the configuration serial a peer registered with
+    pub(crate) async fn note_serial(&self, id: &str, serial: i32) {
+        if let Some(peer) = self.get_in_memory(id).await {
+            peer.write().await.serial = Some(serial);
+        }
+    }
+
+    /// Count the version of a punch hole message and remember it as the version
+    /// of `id`, the peer that sent it; a change is stored with the peer's info
+    pub(crate) async fn note_version(
+        &self,
+        kind: ProtocolKind,
+        transport: Transport,
+        id: Option<&str>,
+        version: &str,
+    ) {
+        let version = version_label(version);
+        note_protocol_version(kind, transport, version, id);
+        let id = match id {
+            Some(id) => id,
+            None => return,
+        };
+        let peer = match self.get_in_memory(id).await {
+            Some(peer) => peer,
+            None => return,
+        };
+        let (before, guid, info) = {
+            let mut w = peer.write().await;
+            if w.info.version == version {
+                return;
+            }
+            let before = std::mem::replace(&mut w.info.version, version.to_owned());
+            (
+                before,
+                w.guid.clone(),
+                serde_json::to_string(&w.info).unwrap_or_default(),
+            )
+        };
+        if !guid.is_empty() {
+            self.db.set_info(guid, info).await;
+        }
+        if !before.is_empty() {
+            version_changed(id, &before);
+        }
+    }
+
+    /// Remember how a peer registered; a change from the transport it used
+    /// before (in this run or the last) is stored and logged as a status event.
+    /// The registration counts towards the peer's last reported version.
+    pub(crate) async fn note_transport(&self, id: &str, transport: Transport) {
+        let peer = match self.get_in_memory(id).await {
+            Some(peer) => peer,
+            None => return,
+        };
+        let (before, guid, info) = {
+            let mut w = peer.write().await;
+            let version = match w.info.version.as_str() {
+                "" => "unknown",
+                version => version,
+            };
+            note_protocol_version(ProtocolKind::Registration, transport, version, Some(id));
+            let before = match w.transport.replace(transport) {
+                Some(before) => before.as_str().to_owned(),
+                None => w.info.transport.clone(),
+            };
+            if before == transport.as_str() && w.info.transport == before {
+                return;
+            }
+            w.info.transport = transport.as_str().to_owned();
+            (
+                before,
+                w.guid.clone(),
+                serde_json::to_string(&w.info).unwrap_or_default(),
+            )
+        };
+        if !guid.is_empty() {
+            self.db.set_info(guid, info).await;
+        }
+        if !before.is_empty() && before != transport.as_str() {
+            log::info!("{} moved from {} to {}", id, before, transport.as_str());
+            let reason = match transport {
+                Transport::Udp => "transport_udp",
+                Transport::Tcp => "transport_tcp",
+                Transport::Ws => "transport_ws",
+            };
+            self.db
+                .record_status_events(vec![id.to_owned()], true, reason)
+                .await;
+        }
     }
 
     #[inline]
@@ -209,10 +1871,22 @@ impl PeerMap {
     ) -> register_pk_response::Result {
         log::info!("update_pk {} {:?} {:?} {:?}", id, addr, uuid, pk);
 
+        if !check_credentials(&id, &uuid, &pk) {
+            record_error(
+                ErrorReason::MalformedCredentials,
+                &id,
+                addr,
+                "empty or oversized uuid/pk",
+            );
+            return register_pk_response::Result::UUID_MISMATCH;
+        }
+
         // BAN CHECK: Verify device is not banned before registration
         match self.db.is_device_banned(&id).await {
             Ok(true) => {
                 log::warn!("Registration REJECTED for device {}: DEVICE IS BANNED", id);
+                record_error(ErrorReason::Banned, &id, addr, "device is banned");
+                count_ban_rejection(&id);
                 self.map.write().await.remove(&id);
                 return register_pk_response::Result::UUID_MISMATCH;
             }
@@ -223,15 +1897,44 @@ impl PeerMap {
                 log::error!("Failed to check ban status for device {}: {}. Allowing (fail-open)", id, e);
             }
         }
+
+        // USER CHECK: a peer of a disabled user is refused like a banned one
+        let user = peer.read().await.user.clone();
+        if let Some(user) = user {
+            if self.user_disabled(&user).await {
+                let user = String::from_utf8_lossy(&user);
+                log::warn!(
+                    "Registration REJECTED for device {}: USER {} IS DISABLED",
+                    id,
+                    user
+                );
+                record_error(
+                    ErrorReason::UserDisabled,
+                    &id,
+                    addr,
+                    format!("user {} is disabled", user),
+                );
+                self.map.write().await.remove(&id);
+                return register_pk_response::Result::UUID_MISMATCH;
+            }
+        }
+
+        let old_pk = peer.read().await.pk.clone();
+        if !old_pk.is_empty() && !ct_eq(&old_pk, &pk) {
+            if let Some(res) = self.check_pk_change(&id, &old_pk, &pk, addr).await {
+                return res;
+            }
+        }
         
         let (info_str, guid) = {
             let mut w = peer.write().await;
+            self.index_addr(&id, w.socket_addr, addr).await;
             w.socket_addr = addr;
             w.uuid = uuid.clone();
             w.pk = pk.clone();
-            w.last_reg_time = Instant::now();
-            w.last_heartbeat = Instant::now();  // Update heartbeat on registration
-            w.info.ip = ip;
+            w.last_reg_time = self.clock.now_instant();
+            w.last_heartbeat = w.last_reg_time;  // Update heartbeat on registration
+            w.info.ip = ip.clone();
             (
                 serde_json::to_string(&w.info).unwrap_or_default(),
                 w.guid.clone(),
@@ -242,6 +1945,12 @@ impl PeerMap {
             match self.db.insert_peer(&id, &uuid, &pk, &info_str).await {
                 Err(err) => {
                     log::error!("db.insert_peer failed: {}", err);
+                    record_error(
+                        ErrorReason::Database,
+                        &id,
+                        addr,
+                        format!("insert_peer: {}", err),
+                    );
                     return register_pk_response::Result::SERVER_ERROR;
                 }
                 Ok(guid) => {
@@ -249,19 +1958,172 @@ impl PeerMap {
                 }
             }
         } else {
-            if let Err(err) = self.db.update_pk(&guid, &id, &pk, &info_str).await {
+            if let Err(err) = self.db.update_pk(&guid, &id, &pk, &info_str, &ip).await {
                 log::error!("db.update_pk failed: {}", err);
+                record_error(
+                    ErrorReason::Database,
+                    &id,
+                    addr,
+                    format!("update_pk: {}", err),
+                );
                 return register_pk_response::Result::SERVER_ERROR;
             }
             log::info!("pk updated instead of insert");
         }
         
         // Device just registered, mark as online
-        self.db.set_online(&id).await;
+        self.db.set_online(&id, &ip).await;
+        if !std::mem::replace(&mut peer.write().await.online, true) {
+            self.db
+                .record_status_events(vec![id.clone()], true, "register")
+                .await;
+            peer_back(&id);
+        }
         
         register_pk_response::Result::OK
     }
 
+    /// Whether `user` is disabled, from USER_STATUS while the entry is fresh.
+    /// A failed read allows the user (fail-open, like the ban check).
+    pub(crate) async fn user_disabled(&self, user: &[u8]) -> bool {
+        let now = self.clock.now_instant();
+        let ttl = std::time::Duration::from_secs(user_status_ttl());
+        if let Some((disabled, _)) = USER_STATUS
+            .read()
+            .ok()
+            .and_then(|x| x.get(user).copied())
+            .filter(|(_, t)| now.duration_since(*t) < ttl)
+        {
+            return disabled;
+        }
+        match self.db.is_user_disabled(user).await {
+            Ok(disabled) => {
+                if let Ok(mut lock) = USER_STATUS.write() {
+                    if lock.len() < USER_STATUS_MAX || lock.contains_key(user) {
+                        lock.insert(user.to_vec(), (disabled, now));
+                    }
+                }
+                disabled
+            }
+            Err(e) => {
+                log::error!(
+                    "Failed to check user {}: {}. Allowing (fail-open)",
+                    String::from_utf8_lossy(user),
+                    e
+                );
+                false
+            }
+        }
+    }
+
+    /// The disabled user `id` is assigned to, None when it has no user or the
+    /// user is enabled
+    pub(crate) async fn disabled_user(&self, id: &str) -> Option<String> {
+        let user = self.peek(id).await?.read().await.user.clone()?;
+        if self.user_disabled(&user).await {
+            Some(String::from_utf8_lossy(&user).into_owned())
+        } else {
+            None
+        }
+    }
+
+    /// Apply the pk change policy to a known device registering with a new pk.
+    /// Returns the response to send when the change is refused.
+    async fn check_pk_change(
+        &self,
+        id: &str,
+        old_pk: &[u8],
+        new_pk: &[u8],
+        addr: SocketAddr,
+    ) -> Option<register_pk_response::Result> {
+        let (old_fp, new_fp) = (pk_fingerprint(old_pk), pk_fingerprint(new_pk));
+        let detail = format!("{} -> {}", old_fp, new_fp);
+        // a key an admin rolled back stays refused whatever the policy
+        match self.db.revoked_pk(id).await {
+            Ok(Some(revoked)) if ct_eq(&revoked, new_pk) => {
+                log::warn!("Peer {} pk change {} REJECTED: rolled back", id, detail);
+                count_key_change("revoked");
+                record_error(ErrorReason::PkChangeRejected, id, addr, detail.clone());
+                self.db
+                    .audit("server", "key_change_revoked", id, detail)
+                    .await;
+                return Some(register_pk_response::Result::UUID_MISMATCH);
+            }
+            Ok(_) => {}
+            Err(e) => {
+                log::error!("Failed to look up the revoked key of {}: {}", id, e);
+            }
+        }
+        match pk_change_policy() {
+            PkChangePolicy::Auto => {
+                log::info!("Peer {} changed pk {}", id, detail);
+                count_key_change("auto");
+                self.db.audit("server", "key_change_auto", id, detail).await;
+                None
+            }
+            PkChangePolicy::Reject => {
+                log::warn!("Peer {} pk change {} REJECTED by policy", id, detail);
+                count_key_change("rejected");
+                record_error(ErrorReason::PkChangeRejected, id, addr, detail.clone());
+                self.db
+                    .audit("server", "key_change_rejected", id, detail)
+                    .await;
+                Some(register_pk_response::Result::UUID_MISMATCH)
+            }
+            PkChangePolicy::Approve => match self.db.pending_key_change(id).await {
+                Ok(Some(pending)) if pending.approved && ct_eq(&pending.new_pk, new_pk) => {
+                    log::info!("Peer {} pk change {} applied after approval", id, detail);
+                    if let Err(e) = self.db.remove_pending_key_change(id).await {
+                        log::error!("Failed to clear pending key change of {}: {}", id, e);
+                    }
+                    count_key_change("applied");
+                    self.db
+                        .audit("server", "key_change_applied", id, detail)
+                        .await;
+                    None
+                }
+                Ok(_) => {
+                    match self
+                        .db
+                        .park_key_change(id, old_pk, new_pk, &old_fp, &new_fp)
+                        .await
+                    {
+                        Ok(true) => {
+                            log::warn!("Peer {} pk change {} parked for approval", id, detail);
+                            self.db
+                                .audit("server", "key_change_pending", id, detail.clone())
+                                .await;
+                        }
+                        Ok(false) => {}
+                        Err(e) => {
+                            log::error!("Failed to park key change of {}: {}", id, e);
+                            record_error(
+                                ErrorReason::Database,
+                                id,
+                                addr,
+                                format!("park_key_change: {}", e),
+                            );
+                            return Some(register_pk_response::Result::SERVER_ERROR);
+                        }
+                    }
+                    count_key_change("pending");
+                    record_error(ErrorReason::PkChangePending, id, addr, detail);
+                    Some(register_pk_response::Result::UUID_MISMATCH)
+                }
+                Err(e) => {
+                    log::error!("Failed to look up pending key change of {}: {}", id, e);
+                    record_error(
+                        ErrorReason::Database,
+                        id,
+                        addr,
+                        format!("pending_key_change: {}", e),
+                    );
+                    Some(register_pk_response::Result::SERVER_ERROR)
+                }
+            },
+        }
+    }
+
     /// Handle ID change request from RegisterPk with old_id
     /// Validates format, rate limit, UUID match, new ID availability
     /// Updates database and in-memory peer map
@@ -276,12 +2138,33 @@ impl PeerMap {
     ) -> register_pk_response::Result {
         log::info!("change_id: {} -> {} from {}", old_id, new_id, ip);
 
+        if !check_credentials(&old_id, &uuid, &pk) {
+            record_error(
+                ErrorReason::MalformedCredentials,
+                &old_id,
+                addr,
+                "empty or oversized uuid/pk",
+            );
+            return register_pk_response::Result::UUID_MISMATCH;
+        }
+
         // Rate limit check (per device, 5 min cooldown)
         {
             let mut cooldown = ID_CHANGE_COOLDOWN.lock().await;
             if let Some(last) = cooldown.get(&old_id) {
-                if last.elapsed().as_secs() < ID_CHANGE_COOLDOWN_SECS {
-                    log::warn!("ID change rate limited for {}", old_id);
+                let inputs = TimerInputs {
+                    since_id_change: Some(self.clock.elapsed(*last)),
+                    ..Default::default()
+                };
+                let left = peer_timers(&inputs, 0).id_change_cooldown_secs;
+                if left > 0 {
+                    log::warn!("ID change rate limited for {}, {}s left", old_id, left);
+                    record_error(
+                        ErrorReason::TooFrequent,
+                        &old_id,
+                        addr,
+                        "id change cooldown",
+                    );
                     return register_pk_response::Result::TOO_FREQUENT;
                 }
             }
@@ -291,6 +2174,7 @@ impl PeerMap {
         match self.db.is_device_banned(&old_id).await {
             Ok(true) => {
                 log::warn!("ID change rejected for banned device {}", old_id);
+                record_error(ErrorReason::Banned, &old_id, addr, "device is banned");
                 return register_pk_response::Result::UUID_MISMATCH;
             }
             Ok(false) => {}
@@ -303,13 +2187,25 @@ impl PeerMap {
         match self.get(&old_id).await {
             Some(peer) => {
                 let peer_data = peer.read().await;
-                if peer_data.uuid != uuid {
+                if peer_data.uuid.is_empty() || !ct_eq(&peer_data.uuid, &uuid) {
                     log::warn!("UUID mismatch for ID change {} -> {}", old_id, new_id);
+                    record_error(
+                        ErrorReason::UuidMismatch,
+                        &old_id,
+                        addr,
+                        "id change with a different uuid",
+                    );
                     return register_pk_response::Result::UUID_MISMATCH;
                 }
             }
             None => {
                 log::warn!("Peer {} not found for ID change", old_id);
+                record_error(
+                    ErrorReason::IdNotFound,
+                    &old_id,
+                    addr,
+                    "id change of an unknown peer",
+                );
                 return register_pk_response::Result::UUID_MISMATCH;
             }
         }
@@ -320,10 +2216,22 @@ impl PeerMap {
             Ok(false) => {
                 // TODO: Use register_pk_response::Result::ID_EXISTS when proto supports it
                 log::info!("ID {} already exists, cannot change from {}", new_id, old_id);
+                record_error(
+                    ErrorReason::IdTaken,
+                    &old_id,
+                    addr,
+                    format!("{} is taken", new_id),
+                );
                 return register_pk_response::Result::UUID_MISMATCH;
             }
             Err(e) => {
                 log::error!("Failed to check ID availability: {}", e);
+                record_error(
+                    ErrorReason::Database,
+                    &old_id,
+                    addr,
+                    format!("is_id_available: {}", e),
+                );
                 return register_pk_response::Result::SERVER_ERROR;
             }
         }
@@ -332,12 +2240,33 @@ impl PeerMap {
         if self.is_in_memory(&new_id).await {
             // TODO: Use register_pk_response::Result::ID_EXISTS when proto supports it
             log::info!("ID {} exists in memory, cannot change from {}", new_id, old_id);
+            record_error(
+                ErrorReason::IdTaken,
+                &old_id,
+                addr,
+                format!("{} is online", new_id),
+            );
             return register_pk_response::Result::UUID_MISMATCH;
         }
 
         // Perform database change
-        if let Err(e) = self.db.change_peer_id(&old_id, &new_id).await {
+        if let Err(e) = self
+            .db
+            .change_peer_id(
+                &old_id,
+                &new_id,
+                database::IdChangeVia::Client,
+                format!("ip:{}", ip),
+            )
+            .await
+        {
             log::error!("Database ID change failed {} -> {}: {}", old_id, new_id, e);
+            record_error(
+                ErrorReason::Database,
+                &old_id,
+                addr,
+                format!("change_peer_id: {}", e),
+            );
             return register_pk_response::Result::SERVER_ERROR;
         }
 
@@ -347,11 +2276,14 @@ impl PeerMap {
             if let Some(peer) = map.remove(&old_id) {
                 {
                     let mut w = peer.write().await;
+                    self.unindex_addr(w.socket_addr, &old_id).await;
+                    self.index_addr(&new_id, w.socket_addr, addr).await;
                     w.socket_addr = addr;
                     w.pk = pk;
-                    w.last_reg_time = Instant::now();
-                    w.last_heartbeat = Instant::now();
-                    w.info.ip = ip;
+                    w.last_reg_time = self.clock.now_instant();
+                    w.last_heartbeat = w.last_reg_time;
+                    w.info.ip = ip.clone();
+                    w.online = true;
                 }
                 map.insert(new_id.clone(), peer);
             }
@@ -360,11 +2292,17 @@ impl PeerMap {
         // Update rate limit cooldown
         {
             let mut cooldown = ID_CHANGE_COOLDOWN.lock().await;
-            cooldown.insert(new_id.clone(), Instant::now());
+            cooldown.insert(new_id.clone(), self.clock.now_instant());
         }
 
         // Mark new ID as online
-        self.db.set_online(&new_id).await;
+        self.db.set_online(&new_id, &ip).await;
+        self.db
+            .record_status_events(vec![old_id.clone()], false, "id_change")
+            .await;
+        self.db
+            .record_status_events(vec![new_id.clone()], true, "id_change")
+            .await;
 
         log::info!("ID change successful: {} -> {}", old_id, new_id);
         register_pk_response::Result::OK
@@ -372,6 +2310,16 @@ impl PeerMap {
 
     #[inline]
     pub(crate) async fn get(&self, id: &str) -> Option<LockPeer> {
+        self.lookup(id, true).await
+    }
+
+    /// `get` without keeping a peer loaded from the database in memory
+    #[inline]
+    pub(crate) async fn peek(&self, id: &str) -> Option<LockPeer> {
+        self.lookup(id, false).await
+    }
+
+    async fn lookup(&self, id: &str, cache: bool) -> Option<LockPeer> {
         let p = self.map.read().await.get(id).cloned();
         if p.is_some() {
             return p;
@@ -386,11 +2334,14 @@ impl PeerMap {
                 uuid: v.uuid.into(),
                 pk: v.pk.into(),
                 info: serde_json::from_str::<PeerInfo>(&v.info).unwrap_or_default(),
-                last_heartbeat: Instant::now(),
+                last_heartbeat: self.clock.now_instant(),
+                user: v.user,
                 ..Default::default()
             };
             let peer = Arc::new(RwLock::new(peer));
-            self.map.write().await.insert(id.to_owned(), peer.clone());
+            if cache {
+                self.map.write().await.insert(id.to_owned(), peer.clone());
+            }
             return Some(peer);
         }
         None
@@ -405,7 +2356,10 @@ impl PeerMap {
         if let Some(p) = w.get(id) {
             return p.clone();
         }
-        let tmp = LockPeer::default();
+        let tmp = Arc::new(RwLock::new(Peer {
+            last_heartbeat: self.clock.now_instant(),
+            ..Default::default()
+        }));
         w.insert(id.to_owned(), tmp.clone());
         tmp
     }
@@ -420,28 +2374,191 @@ impl PeerMap {
         self.map.read().await.contains_key(id)
     }
 
-    /// Find device ID by socket address (for ban enforcement)
+    /// Immediately drop a peer from the online set instead of waiting for the heartbeat timeout
+    pub(crate) async fn mark_offline(&self, id: &str, reason: OfflineReason) {
+        let peer = match self.map.write().await.remove(id) {
+            Some(peer) => peer,
+            None => return,
+        };
+        let (addr, ip) = {
+            let r = peer.read().await;
+            (r.socket_addr, r.info.ip.clone())
+        };
+        self.db.set_offline(id, &ip).await;
+        self.unindex_addr(addr, id).await;
+        if std::mem::replace(&mut peer.write().await.online, false) {
+            self.db
+                .record_status_events(vec![id.to_owned()], false, reason.as_str())
+                .await;
+        }
+        log::info!("Peer {} offline (reason={})", id, reason.as_str());
+    }
+
+    /// Compare in-memory presence against the peer table and repair the table where they disagree.
+    /// Samples `sample` peers from each side, or checks everything when it is None.
+    pub(crate) async fn verify_consistency(&self, sample: Option<usize>) -> DriftReport {
+        use hbb_common::rand::seq::IteratorRandom;
+        let started = Instant::now();
+        let timeout = std::time::Duration::from_secs(peer_timeout_secs());
+        let mut report = DriftReport {
+            full: sample.is_none(),
+            ..Default::default()
+        };
+        // checked in memory, so their rows are not counted again
+        let mut seen = HashSet::new();
+
+        let peers: Vec<(String, LockPeer)> = {
+            let map = self.map.read().await;
+            let iter = map.iter().map(|(id, p)| (id.clone(), p.clone()));
+            match sample {
+                Some(n) => iter.choose_multiple(&mut hbb_common::rand::thread_rng(), n),
+                None => iter.collect(),
+            }
+        };
+        for (id, peer) in peers {
+            if self.clock.elapsed(peer.read().await.last_heartbeat) > timeout {
+                // Not alive any more, the offline sweep owns this one
+                continue;
+            }
+            report.checked += 1;
+            seen.insert(id.clone());
+            let row = match self.db.peer_status_row(&id).await {
+                Ok(row) => row,
+                Err(e) => {
+                    log::warn!("Consistency check: failed to read {}: {}", id, e);
+                    continue;
+                }
+            };
+            match row {
+                None => {
+                    report.memory_online_db_missing += 1;
+                    log::warn!("Drift memory-online-db-missing: {}", id);
+                }
+                Some(row) if row.status != Some(1) => {
+                    report.memory_online_db_offline += 1;
+                    log::warn!("Drift memory-online-db-offline: {}", id);
+                    self.db.set_online(&id, &id).await;
+                    report.repaired += 1;
+                }
+                Some(row) if is_stale(&row.last_online) => {
+                    report.stale_last_online += 1;
+                    log::warn!("Drift stale-last-online: {} ({:?})", id, row.last_online);
+                    self.db.set_online(&id, &id).await;
+                    report.repaired += 1;
+                }
+                Some(_) => {}
+            }
+        }
+
+        if sample.is_some() {
+            match self.db.peer_status_rows(sample).await {
+                Ok(rows) => self.check_status_rows(rows, &seen, &mut report).await,
+                Err(e) => log::warn!("Consistency check: failed to read peer table: {}", e),
+            }
+        } else {
+            // a full pass reads the table in pages of VERIFY_PAGE_SIZE rows, so a
+            // large table is never held at once
+            let page = env_u64("VERIFY_PAGE_SIZE", VERIFY_PAGE_SIZE).max(1) as usize;
+            let mut after = String::new();
+            loop {
+                let rows = match self.db.peer_status_page(&after, page).await {
+                    Ok(rows) => rows,
+                    Err(e) => {
+                        log::warn!("Consistency check: failed to read peer table: {}", e);
+                        break;
+                    }
+                };
+                let last = match rows.last() {
+                    Some(row) => row.id.clone(),
+                    None => break,
+                };
+                let full = rows.len() == page;
+                self.check_status_rows(rows, &seen, &mut report).await;
+                if !full {
+                    break;
+                }
+                after = last;
+            }
+        }
+
+        report.duration_ms = started.elapsed().as_millis() as _;
+        report.finished_at = self.clock.now_utc().to_rfc3339();
+        if report.total() > 0 {
+            log::warn!(
+                "Consistency check ({}): {} drift in {} checked, {} repaired",
+                if report.full { "full" } else { "sampled" },
+                report.total(),
+                report.checked,
+                report.repaired
+            );
+        } else {
+            log::info!(
+                "Consistency check ({}): no drift in {} checked ({}ms)",
+                if report.full { "full" } else { "sampled" },
+                report.checked,
+                report.duration_ms
+            );
+        }
+        report
+    }
+
+    /// Set rows online in the table but not in memory offline; rows of `seen`
+    /// ids were counted as checked already
+    async fn check_status_rows(
+        &self,
+        rows: Vec<database::PeerStatusRow>,
+        seen: &HashSet<String>,
+        report: &mut DriftReport,
+    ) {
+        for row in rows {
+            if !seen.contains(&row.id) {
+                report.checked += 1;
+            }
+            if row.status == Some(1) && !self.is_in_memory(&row.id).await {
+                report.db_online_memory_missing += 1;
+                log::warn!("Drift db-online-memory-missing: {}", row.id);
+                self.db.set_offline(&row.id, &row.id).await;
+                report.repaired += 1;
+            }
+        }
+    }
+
+    /// Record that `id` now registers from `addr` instead of `old`
+    pub(crate) async fn index_addr(&self, id: &str, old: SocketAddr, addr: SocketAddr) {
+        let mut addrs = self.addrs.write().await;
+        if old != addr && addrs.get(&old).map_or(false, |x| x == id) {
+            addrs.remove(&old);
+        }
+        addrs.insert(addr, id.to_owned());
+    }
+
+    async fn unindex_addr(&self, addr: SocketAddr, id: &str) {
+        let mut addrs = self.addrs.write().await;
+        if addrs.get(&addr).map_or(false, |x| x == id) {
+            addrs.remove(&addr);
+        }
+    }
+
+    /// Find device ID by socket address (for ban enforcement). Only a peer in memory
+    /// whose last registration came from `addr` counts.
     pub(crate) async fn get_id_by_addr(&self, addr: SocketAddr) -> Option<String> {
-        let map = self.map.read().await;
-        for (id, peer) in map.iter() {
-            let peer_addr = peer.read().await.socket_addr;
-            if peer_addr == addr {
-                return Some(id.clone());
+        let id = self.addrs.read().await.get(&addr).cloned()?;
+        match self.get_in_memory(&id).await {
+            Some(peer) if peer.read().await.socket_addr == addr => Some(id),
+            _ => {
+                self.unindex_addr(addr, &id).await;
+                None
             }
         }
-        None
     }
-    
+
     /// Get statistics about online peers  
     pub(crate) async fn get_stats(&self) -> PeerStats {
         let map = self.map.read().await;
         let total = map.len();
-        let now = Instant::now();
+        let now = self.clock.now_instant();
         
-        let timeout_secs = std::env::var("PEER_TIMEOUT_SECS")
-            .ok()
-            .and_then(|s| s.parse::<u64>().ok())
-            .unwrap_or(15);
+        let timeout_secs = peer_timeout_secs();
         let warning_threshold = std::env::var("HEARTBEAT_WARNING_THRESHOLD")
             .ok()
             .and_then(|s| s.parse::<u64>().ok())
@@ -450,19 +2567,20 @@ impl PeerMap {
             .ok()
             .and_then(|s| s.parse::<u64>().ok())
             .unwrap_or(4);
-        let heartbeat_interval = std::env::var("HEARTBEAT_INTERVAL_SECS")
-            .ok()
-            .and_then(|s| s.parse::<u64>().ok())
-            .unwrap_or(3);
+        let heartbeat_interval = heartbeat_interval_secs();
         
         let mut healthy = 0;
         let mut degraded = 0;
         let mut critical = 0;
+        let mut transports = HashMap::new();
         
         for (_id, peer) in map.iter() {
             if let Ok(p) = peer.try_read() {
                 let elapsed = now.duration_since(p.last_heartbeat).as_secs();
                 if elapsed <= timeout_secs {
+                    if let Some(transport) = p.transport {
+                        *transports.entry(transport.as_str()).or_default() += 1;
+                    }
                     let missed = elapsed / heartbeat_interval;
                     if missed >= critical_threshold {
                         critical += 1;
@@ -475,55 +2593,217 @@ impl PeerMap {
             }
         }
         
-        PeerStats { total, healthy, degraded, critical }
+        PeerStats {
+            total,
+            healthy,
+            degraded,
+            critical,
+            transports,
+        }
     }
-    
-    /// Check online peers and mark offline ones
-    pub(crate) async fn check_online_peers(&self) {
-        let timeout_secs = std::env::var("PEER_TIMEOUT_SECS")
-            .ok()
-            .and_then(|s| s.parse::<u64>().ok())
-            .unwrap_or(15);
-        
-        let now = Instant::now();
-        let mut offline_peers = Vec::new();
-        let mut online_peers = Vec::new();
-        
-        {
-            let map = self.map.read().await;
-            for (id, peer) in map.iter() {
-                let p = peer.read().await;
-                let elapsed = now.duration_since(p.last_heartbeat).as_secs();
-                
-                if elapsed > timeout_secs {
-                    offline_peers.push(id.clone());
-                } else {
-                    online_peers.push(id.clone());
+}
+
+/// Whether a SQLite `last_online` timestamp lags behind an alive peer
+fn is_stale(last_online: &Option<String>) -> bool {
+    match last_online.as_deref().and_then(database::parse_last_online) {
+        Some(ts) => {
+            chrono::Utc::now()
+                .naive_utc()
+                .signed_duration_since(ts)
+                .num_seconds()
+                > STALE_LAST_ONLINE_SECS
+        }
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn secs(n: u64) -> Duration {
+        Duration::from_secs(n)
+    }
+
+    fn block_on<F: std::future::Future>(f: F) -> F::Output {
+        tokio::runtime::Runtime::new().unwrap().block_on(f)
+    }
+
+    #[test]
+    fn churning_ip_is_throttled_where_an_office_nat_is_not() {
+        const CHURNER: &str = "203.0.113.41";
+        const OFFICE: &str = "203.0.113.42";
+        let clock = TestClock::default();
+        let threshold = uuid_churn_threshold();
+        assert!(threshold > 30);
+        let uuid = |n: usize| Bytes::from(format!("churn-uuid-{}", n));
+        block_on(async {
+            for n in 0..threshold + 5 {
+                let id = format!("CHURN{}", n);
+                let allowed = check_uuid_churn(CHURNER, &id, &uuid(n), &clock).await;
+                assert_eq!(allowed, n < threshold, "uuid {}", n + 1);
+            }
+            // a device it already registered is let through
+            assert!(check_uuid_churn(CHURNER, "CHURN0", &uuid(0), &clock).await);
+            // 30 devices register again and again with the uuid each has
+            for _ in 0..10 {
+                for n in 0..30 {
+                    let id = format!("OFFICE{}", n);
+                    let device = Bytes::from(format!("office-uuid-{}", n));
+                    assert!(check_uuid_churn(OFFICE, &id, &device, &clock).await);
                 }
             }
+            // the next window starts over
+            clock.advance(std::time::Duration::from_secs(uuid_churn_window() + 1));
+            let next = uuid(threshold + 5);
+            assert!(check_uuid_churn(CHURNER, "CHURNX", &next, &clock).await);
+        });
+    }
+
+    #[test]
+    fn test_clock_advances_instants_and_utc() {
+        let clock = TestClock::default();
+        let start = clock.now_instant();
+        let utc = clock.now_utc();
+        clock.advance(secs(3600));
+        assert!(clock.elapsed(start) >= secs(3600));
+        assert!(clock.now_utc() - utc >= chrono::Duration::hours(1));
+        // an instant in the clock's future is no time ago
+        assert_eq!(
+            clock.elapsed(clock.now_instant() + secs(60)),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn suspect_is_confirmed_once_its_address_goes_quiet() {
+        let clock = TestClock::default();
+        let timeout = secs(30);
+        let addr: SocketAddr = "203.0.113.50:21116".parse().unwrap();
+        note_udp_packet(addr, &clock);
+        let evicted = clock.now_instant();
+        assert!(!suspect_confirmed(addr, evicted, timeout, &clock));
+        clock.advance(timeout - secs(1));
+        assert!(!suspect_confirmed(addr, evicted, timeout, &clock));
+        clock.advance(secs(2));
+        assert!(suspect_confirmed(addr, evicted, timeout, &clock));
+    }
+
+    #[test]
+    fn suspect_still_sending_is_confirmed_after_too_long() {
+        let clock = TestClock::default();
+        let timeout = secs(30);
+        let addr: SocketAddr = "203.0.113.51:21116".parse().unwrap();
+        let evicted = clock.now_instant();
+        for _ in 1..SUSPECT_MAX_TIMEOUTS {
+            clock.advance(timeout);
+            note_udp_packet(addr, &clock);
+            assert!(!suspect_confirmed(addr, evicted, timeout, &clock));
         }
-        
-        // Update online devices in database
-        for id in &online_peers {
-            self.db.set_online(id).await;
+        clock.advance(timeout);
+        note_udp_packet(addr, &clock);
+        assert!(suspect_confirmed(addr, evicted, timeout, &clock));
+    }
+
+    #[test]
+    fn id_change_cooldown_runs_out_on_the_test_clock() {
+        let clock = TestClock::default();
+        let changed = clock.now_instant();
+        let left = |clock: &TestClock| {
+            let inputs = TimerInputs {
+                since_id_change: Some(clock.elapsed(changed)),
+                ..Default::default()
+            };
+            peer_timers(&inputs, 0).id_change_cooldown_secs
+        };
+        assert_eq!(left(&clock), ID_CHANGE_COOLDOWN_SECS);
+        clock.advance(secs(ID_CHANGE_COOLDOWN_SECS - 1));
+        assert_eq!(left(&clock), 1);
+        clock.advance(secs(1));
+        assert_eq!(left(&clock), 0);
+    }
+
+    #[test]
+    fn pasted_ids_canonicalize_alike() {
+        for raw in [
+            "123 456 789",
+            "123\t456  789",
+            "\u{FEFF}123\u{200B}456\u{2060}789\u{200D}",
+            " 123456789 ",
+        ] {
+            assert_eq!(canonical_id(raw), "123456789", "{:?}", raw);
         }
-        
-        // Mark offline devices
-        if !offline_peers.is_empty() {
-            log::info!("Setting {} peers as offline (timeout {}s)", offline_peers.len(), timeout_secs);
-            
-            if let Err(e) = self.db.batch_set_offline(&offline_peers).await {
-                log::error!("Batch offline update failed: {}", e);
-                for id in &offline_peers {
-                    self.db.set_offline(id).await;
-                }
-            }
-            
-            // Remove from memory
-            let mut map = self.map.write().await;
-            for id in offline_peers {
-                map.remove(&id);
-            }
+        assert_eq!(canonical_id("ab c-d_e"), "ABC-D_E");
+        assert_eq!(canonical_id("o f\u{200C}fice"), "OFFICE");
+    }
+
+    #[test]
+    fn canonical_ids_are_borrowed() {
+        for id in ["123456789", "ABC-D_E", ""] {
+            assert!(matches!(canonical_id(id), Cow::Borrowed(x) if x == id));
         }
     }
+
+    #[test]
+    fn deleted_ids_canonicalize_to_empty() {
+        assert_eq!(canonical_id("123456789~deleted~00ff"), "");
+        assert_eq!(canonical_id("abc~deleted~00ff"), "");
+    }
+
+    #[test]
+    fn timers_not_running_are_zero() {
+        assert_eq!(
+            peer_timers(&TimerInputs::default(), 15),
+            PeerTimers::default()
+        );
+    }
+
+    #[test]
+    fn timers_count_down() {
+        let inputs = TimerInputs {
+            since_heartbeat: Some(secs(4)),
+            since_id_change: Some(secs(299)),
+            ip_window: Some((31, secs(10))),
+        };
+        let expected = PeerTimers {
+            offline_in_secs: 11,
+            id_change_cooldown_secs: 1,
+            registration_throttle_secs: 51,
+        };
+        assert_eq!(peer_timers(&inputs, 15), expected);
+    }
+
+    #[test]
+    fn timers_at_their_boundaries() {
+        // online at exactly the timeout, renaming allowed at exactly the
+        // cooldown, the ip counter kept through second 60
+        let inputs = TimerInputs {
+            since_heartbeat: Some(secs(15)),
+            since_id_change: Some(secs(300)),
+            ip_window: Some((31, secs(60))),
+        };
+        let expected = PeerTimers {
+            offline_in_secs: 0,
+            id_change_cooldown_secs: 0,
+            registration_throttle_secs: 1,
+        };
+        assert_eq!(peer_timers(&inputs, 15), expected);
+
+        let inputs = TimerInputs {
+            since_heartbeat: Some(secs(3600)),
+            since_id_change: Some(secs(3600)),
+            ip_window: Some((31, secs(61))),
+        };
+        assert_eq!(peer_timers(&inputs, 15), PeerTimers::default());
+    }
+
+    #[test]
+    fn ip_at_the_limit_is_not_throttled() {
+        let inputs = TimerInputs {
+            ip_window: Some((30, secs(1))),
+            ..Default::default()
+        };
+        assert_eq!(peer_timers(&inputs, 15), PeerTimers::default());
+    }
 }
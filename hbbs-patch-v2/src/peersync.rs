@@ -0,0 +1,232 @@
+// Configuration sync from the peer servers of -R/--rendezvous-servers
+// Every PEER_SYNC_INTERVAL_SECS one peer is asked, with a NAT test carrying our
+// serial, whether it has a newer configuration; a newer serial and its
+// rendezvous-servers list go to the rendezvous side as the loopback
+// ConfigureUpdate it accepts. The first peer of the list is preferred: one that
+// fails is skipped for an exponential backoff while the next one is asked, and
+// is asked first again once its backoff ran out. Successes and failures per
+// peer are listed in `/api/stats`.
+
+use hbb_common::{
+    config::RENDEZVOUS_PORT, log, protobuf::Message as _, rendezvous_proto::*, tcp::FramedStream,
+    tokio, udp::FramedSocket, ResultType,
+};
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+const SYNC_INTERVAL_SECS: u64 = 60; // PEER_SYNC_INTERVAL_SECS, 0 disables
+const BACKOFF_MAX_SECS: u64 = 3600;
+const SYNC_TIMEOUT_MS: u64 = 3_000;
+
+lazy_static::lazy_static! {
+    static ref STATUS: RwLock<Vec<PeerStatus>> = Default::default();
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerStatus {
+    pub server: String,
+    pub healthy: bool,
+    pub successes: u64,
+    pub failures: u64,
+    /// Failures since the last success, doubling the backoff each
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    pub last_success_at: Option<String>,
+    /// While backed off, seconds until it is asked again
+    pub retry_in_secs: Option<u64>,
+}
+
+/// Health of the configured peers, in order of preference
+pub struct PeerSync {
+    peers: Vec<(PeerStatus, Option<Instant>)>,
+    base: Duration,
+}
+
+impl PeerSync {
+    /// `base` is the first backoff, doubled per further failure
+    pub fn new(servers: &[String], base: Duration) -> Self {
+        let peers = servers
+            .iter()
+            .map(|server| {
+                let status = PeerStatus {
+                    server: server.clone(),
+                    healthy: true,
+                    successes: 0,
+                    failures: 0,
+                    consecutive_failures: 0,
+                    last_error: None,
+                    last_success_at: None,
+                    retry_in_secs: None,
+                };
+                (status, None)
+            })
+            .collect();
+        Self { peers, base }
+    }
+
+    /// Ask the peers not backed off, preferred first, until one answers:
+    /// that peer and the newer configuration it has, if any. None when no
+    /// peer could be asked or none answered.
+    pub async fn round(&mut self, serial: i32) -> Option<(String, Option<ConfigUpdate>)> {
+        for i in 0..self.peers.len() {
+            if self.peers[i].1.map_or(false, |at| at > Instant::now()) {
+                continue;
+            }
+            let server = self.peers[i].0.server.clone();
+            match ask(&server, serial).await {
+                Ok(cu) => {
+                    self.succeeded(i);
+                    return Some((server, cu));
+                }
+                Err(e) => self.failed(i, e),
+            }
+        }
+        None
+    }
+
+    fn succeeded(&mut self, i: usize) {
+        let (status, retry_at) = &mut self.peers[i];
+        if !status.healthy {
+            log::info!(
+                "Peer sync: {} answers again after {} failure(s)",
+                status.server,
+                status.consecutive_failures
+            );
+        }
+        status.healthy = true;
+        status.successes += 1;
+        status.consecutive_failures = 0;
+        status.last_success_at = Some(chrono::Utc::now().to_rfc3339());
+        *retry_at = None;
+    }
+
+    fn failed(&mut self, i: usize, error: String) {
+        let (status, retry_at) = &mut self.peers[i];
+        status.failures += 1;
+        status.consecutive_failures += 1;
+        let backoff = self
+            .base
+            .saturating_mul(1 << (status.consecutive_failures - 1).min(16))
+            .min(Duration::from_secs(BACKOFF_MAX_SECS));
+        if status.healthy {
+            log::warn!(
+                "Peer sync: {} failed ({}), asking the next peer, retry in {}s",
+                status.server,
+                error,
+                backoff.as_secs()
+            );
+        } else {
+            log::debug!("Peer sync: {} still failing: {}", status.server, error);
+        }
+        status.healthy = false;
+        status.last_error = Some(error);
+        *retry_at = Some(Instant::now() + backoff);
+    }
+
+    pub fn status(&self) -> Vec<PeerStatus> {
+        let now = Instant::now();
+        self.peers
+            .iter()
+            .map(|(status, retry_at)| PeerStatus {
+                retry_in_secs: retry_at
+                    .filter(|at| *at > now)
+                    .map(|at| (at - now).as_secs()),
+                ..status.clone()
+            })
+            .collect()
+    }
+}
+
+/// The peers as of the last round, for `/api/stats`
+pub fn status() -> Vec<PeerStatus> {
+    STATUS.read().map(|x| x.clone()).unwrap_or_default()
+}
+
+/// Syncs every PEER_SYNC_INTERVAL_SECS on a thread of its own; nothing is
+/// started without peers or with the interval at 0
+pub fn spawn_sync_thread(
+    port: i32,
+    rendezvous_servers: Vec<String>,
+) -> Option<std::thread::JoinHandle<()>> {
+    let interval = crate::sync::env_u64("PEER_SYNC_INTERVAL_SECS", SYNC_INTERVAL_SECS);
+    if rendezvous_servers.is_empty() || interval == 0 {
+        return None;
+    }
+    Some(std::thread::spawn(move || {
+        let rt = match tokio::runtime::Runtime::new() {
+            Ok(rt) => rt,
+            Err(e) => {
+                log::warn!("Peer sync: not started: {}", e);
+                return;
+            }
+        };
+        rt.block_on(async {
+            let interval = Duration::from_secs(interval);
+            let mut sync = PeerSync::new(&rendezvous_servers, interval);
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let serial = hbbs::current_serial();
+                if let Some((server, Some(cu))) = sync.round(serial).await {
+                    log::info!(
+                        "Peer sync: serial {} -> {} from {}, rendezvous-servers={:?}",
+                        serial,
+                        cu.serial,
+                        server,
+                        cu.rendezvous_servers
+                    );
+                    if let Err(e) = forward(port, cu).await {
+                        log::warn!("Peer sync: cannot apply the update from {}: {}", server, e);
+                    }
+                }
+                if let Ok(mut lock) = STATUS.write() {
+                    *lock = sync.status();
+                }
+            }
+        });
+    }))
+}
+
+/// A newer configuration from the peer at `server`, None when ours is current
+async fn ask(server: &str, serial: i32) -> Result<Option<ConfigUpdate>, String> {
+    let target = if server.contains(':') {
+        server.to_owned()
+    } else {
+        format!("{}:{}", server, RENDEZVOUS_PORT)
+    };
+    let mut stream = FramedStream::new(&target, None, SYNC_TIMEOUT_MS)
+        .await
+        .map_err(|e| format!("no connection: {}", e))?;
+    let mut msg_out = RendezvousMessage::new();
+    msg_out.set_test_nat_request(TestNatRequest {
+        serial,
+        ..Default::default()
+    });
+    stream
+        .send(&msg_out)
+        .await
+        .map_err(|e| format!("send failed: {}", e))?;
+    match stream.next_timeout(SYNC_TIMEOUT_MS).await {
+        Some(Ok(bytes)) => match RendezvousMessage::parse_from_bytes(&bytes) {
+            Ok(RendezvousMessage {
+                union: Some(rendezvous_message::Union::TestNatResponse(res)),
+                ..
+            }) => Ok(res.cu.into_option().filter(|cu| cu.serial > serial)),
+            _ => Err("not a NAT test response".to_owned()),
+        },
+        _ => Err("no NAT test response".to_owned()),
+    }
+}
+
+/// Hand a configuration to the rendezvous server on `port`, which only takes it
+/// from a loopback address
+async fn forward(port: i32, cu: ConfigUpdate) -> ResultType<()> {
+    let mut socket = FramedSocket::new("127.0.0.1:0").await?;
+    let mut msg_out = RendezvousMessage::new();
+    msg_out.set_configure_update(cu);
+    let server: SocketAddr = format!("127.0.0.1:{}", port).parse()?;
+    socket.send(&msg_out, server).await?;
+    Ok(())
+}
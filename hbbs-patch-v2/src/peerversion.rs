@@ -0,0 +1,96 @@
+// Optimistic concurrency for peer changes through the API
+// peer.version is bumped by every change to a peer: its note, ban, id and
+// attributes (tags included) from the API, and update_pk and client id changes
+// on the registration path. Peer responses carry it as an ETag. A mutating
+// request sends it back as If-Match (required with --api-require-if-match);
+// its change is only made while the version is still that one, in the same
+// transaction as the bump, so of two admins editing from the same version the
+// second gets 412 with the current version instead of overwriting the first.
+// The registration path bumps the version without any check.
+
+use axum::http::{header, HeaderMap, StatusCode};
+use sqlx::{
+    query::Query,
+    sqlite::{Sqlite, SqliteArguments, SqliteConnection, SqlitePool},
+    Row,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bump {
+    /// The version after the change
+    Done(i64),
+    /// The version is no longer the expected one, but this
+    Conflict(i64),
+    NoSuchPeer,
+}
+
+/// The version an If-Match header asks for: `"3"`, `W/"3"` or a bare `3`.
+/// None for `*` and without the header, which is refused with 428 when
+/// `required`; 400 for anything else.
+pub fn if_match(headers: &HeaderMap, required: bool) -> Result<Option<i64>, StatusCode> {
+    let value = match headers.get(header::IF_MATCH) {
+        Some(value) => value.to_str().map_err(|_| StatusCode::BAD_REQUEST)?.trim(),
+        None if required => return Err(StatusCode::PRECONDITION_REQUIRED),
+        None => return Ok(None),
+    };
+    if value == "*" {
+        return Ok(None);
+    }
+    let tag = value.strip_prefix("W/").unwrap_or(value);
+    let tag = tag
+        .strip_prefix('"')
+        .and_then(|x| x.strip_suffix('"'))
+        .unwrap_or(tag);
+    tag.parse().map(Some).map_err(|_| StatusCode::BAD_REQUEST)
+}
+
+pub fn etag(version: i64) -> String {
+    format!("\"{}\"", version)
+}
+
+/// Bump the version of a peer that is not deleted, unless it is no longer
+/// `expected`; inside the caller's transaction
+pub async fn bump(
+    conn: &mut SqliteConnection,
+    id: &str,
+    expected: Option<i64>,
+) -> Result<Bump, sqlx::Error> {
+    let bumped = sqlx::query(
+        "UPDATE peer SET version = version + 1
+         WHERE id = ? AND is_deleted = 0 AND (? IS NULL OR version = ?)
+         RETURNING version",
+    )
+    .bind(id)
+    .bind(expected)
+    .bind(expected)
+    .fetch_optional(&mut *conn)
+    .await?;
+    if let Some(row) = bumped {
+        return Ok(Bump::Done(row.get("version")));
+    }
+    let current = sqlx::query("SELECT version FROM peer WHERE id = ? AND is_deleted = 0")
+        .bind(id)
+        .fetch_optional(&mut *conn)
+        .await?;
+    Ok(match current {
+        Some(row) => Bump::Conflict(row.get("version")),
+        None => Bump::NoSuchPeer,
+    })
+}
+
+/// Run `change` on peer `id` in one transaction with its version bump; it is
+/// not run at all on a conflict or without the peer
+pub async fn change<'q>(
+    pool: &SqlitePool,
+    id: &str,
+    expected: Option<i64>,
+    change: Query<'q, Sqlite, SqliteArguments<'q>>,
+) -> Result<Bump, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let bumped = bump(&mut tx, id, expected).await?;
+    if let Bump::Done(_) = bumped {
+        change.execute(&mut *tx).await?;
+        tx.commit().await?;
+    }
+    Ok(bumped)
+}
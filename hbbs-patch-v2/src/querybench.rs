@@ -0,0 +1,136 @@
+// Hot query benchmark: `hbbs querybench [OPS]`
+// Seeds a throwaway database like dbbench, then times the queries run for
+// nearly every packet as they were before against the current Database:
+// - ban check: a new connection and statement per call, as the rusqlite form
+//   had, vs is_device_banned on the reader pool with the statement cached
+// - peer lookup: the statement prepared again per call vs kept in the
+//   connection's cache (what each pool checkout used to cost without it)
+// - status writes: one autocommit UPDATE per id vs batch_set_offline's single
+//   transaction, per id
+// Prints p50/p99 per operation and how much faster the current form is on average.
+
+use crate::dbbench::{bench_id, seed};
+use hbb_common::{bail, log, tokio, ResultType};
+use sqlx::{sqlite::SqliteConnectOptions, Connection};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+const DEFAULT_OPS: usize = 2_000;
+const PEERS: usize = 10_000;
+const STATUS_BATCH: usize = 100;
+
+pub fn run() -> ResultType<()> {
+    let ops = match std::env::args().nth(2) {
+        Some(n) => n.parse::<usize>()?,
+        None => DEFAULT_OPS,
+    };
+    if ops < STATUS_BATCH {
+        bail!("need at least {} operations", STATUS_BATCH);
+    }
+    let dir = std::env::temp_dir().join(format!("hbbs-querybench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let db = dir.join("db_v2.sqlite3").to_string_lossy().to_string();
+    let res = tokio::runtime::Runtime::new()?.block_on(bench(&db, ops));
+    std::fs::remove_dir_all(&dir).ok();
+    res
+}
+
+async fn bench(url: &str, ops: usize) -> ResultType<()> {
+    let db = hbbs::Database::new(url).await?;
+    seed(url, PEERS).await?;
+    log::info!(
+        "querybench: seeded {} peers, {} operations each",
+        PEERS,
+        ops
+    );
+    let id = |i: usize| bench_id(i * 7919 % PEERS);
+
+    let opt = SqliteConnectOptions::from_str(url)?.read_only(true);
+    let mut before = Vec::with_capacity(ops);
+    for i in 0..ops {
+        let id = id(i);
+        let started = Instant::now();
+        let mut conn = sqlx::SqliteConnection::connect_with(&opt).await?;
+        sqlx::query("SELECT is_banned FROM peer WHERE id = ?")
+            .persistent(false)
+            .bind(&id)
+            .fetch_optional(&mut conn)
+            .await?;
+        conn.close().await?;
+        before.push(started.elapsed());
+    }
+    let mut after = Vec::with_capacity(ops);
+    for i in 0..ops {
+        let id = id(i);
+        let started = Instant::now();
+        db.is_device_banned(&id).await?;
+        after.push(started.elapsed());
+    }
+    compare("ban check", &mut before, &mut after);
+
+    let mut conn = sqlx::SqliteConnection::connect_with(&opt).await?;
+    let mut before = Vec::with_capacity(ops);
+    let mut after = Vec::with_capacity(ops);
+    for (persistent, samples) in [(false, &mut before), (true, &mut after)] {
+        for i in 0..ops {
+            let id = id(i);
+            let started = Instant::now();
+            sqlx::query("select guid, id, uuid, pk, user, status, info from peer where id = ?")
+                .persistent(persistent)
+                .bind(&id)
+                .fetch_optional(&mut conn)
+                .await?;
+            samples.push(started.elapsed());
+        }
+    }
+    conn.close().await?;
+    compare("peer lookup", &mut before, &mut after);
+
+    let ids: Vec<String> = (0..ops).map(id).collect();
+    let mut conn =
+        sqlx::SqliteConnection::connect_with(&SqliteConnectOptions::from_str(url)?).await?;
+    let mut before = Vec::with_capacity(ops / STATUS_BATCH);
+    for chunk in ids.chunks_exact(STATUS_BATCH) {
+        let started = Instant::now();
+        for id in chunk {
+            sqlx::query("UPDATE peer SET status = 0 WHERE id = ?")
+                .bind(id)
+                .execute(&mut conn)
+                .await?;
+        }
+        before.push(started.elapsed() / STATUS_BATCH as u32);
+    }
+    conn.close().await?;
+    let mut after = Vec::with_capacity(ops / STATUS_BATCH);
+    for chunk in ids.chunks_exact(STATUS_BATCH) {
+        let started = Instant::now();
+        db.batch_set_offline(chunk).await?;
+        after.push(started.elapsed() / STATUS_BATCH as u32);
+    }
+    compare("status write", &mut before, &mut after);
+    Ok(())
+}
+
+fn compare(op: &str, before: &mut [Duration], after: &mut [Duration]) {
+    let mean = |x: &[Duration]| x.iter().sum::<Duration>() / x.len().max(1) as u32;
+    report(op, "before", before);
+    report(op, "after", after);
+    log::info!(
+        "querybench: {}: {:.1}x faster on average",
+        op,
+        mean(before).as_secs_f64() / mean(after).as_secs_f64().max(1e-9)
+    );
+}
+
+fn report(op: &str, phase: &str, samples: &mut [Duration]) {
+    samples.sort();
+    let at = |q: f64| samples[((samples.len() - 1) as f64 * q) as usize];
+    log::info!(
+        "querybench: {} {}: p50 {:?}, p99 {:?}, max {:?}",
+        op,
+        phase,
+        at(0.5),
+        at(0.99),
+        samples[samples.len() - 1]
+    );
+}
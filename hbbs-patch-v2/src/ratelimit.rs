@@ -0,0 +1,331 @@
+// Request rate limit of the HTTP API: `--api-rate-limit` and `--api-rate-burst`
+// One token bucket per valid API key, holding up to the burst and refilled at
+// the limit per second; a request takes a token or is answered 429 with
+// Retry-After. A bearer token the identity provider already vouched for takes
+// from the bucket of its subject, wherever it comes from. Other requests (wrong
+// or no key, a token not checked yet) share the bucket of their source IP, so
+// guessing keys is limited too. The limiter runs before authentication and the
+// handlers, which keeps a misbehaving integration away from the database pools
+// the rendezvous side shares. The health checks are exempt. Buckets refilled to
+// the brim are dropped every SWEEP_SECS; at most MAX_IP_BUCKETS source IPs are
+// tracked, a new one beyond that replaces the one idle for the longest.
+
+use crate::http_api::ApiState;
+use axum::{
+    extract::{ConnectInfo, Extension, MatchedPath},
+    http::{header::RETRY_AFTER, HeaderMap, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hbb_common::{log, tokio};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Requests per second and key (`--api-rate-limit`), 0 for no limit
+pub const RATE: u32 = 20;
+/// Requests a key may make at once (`--api-rate-burst`)
+pub const BURST: u32 = 40;
+const SWEEP_SECS: u64 = 60;
+pub(crate) const MAX_IP_BUCKETS: usize = 10_000;
+/// Routes answered without taking a token
+const EXEMPT: [&str; 2] = ["/api/health", "/api/v1/health"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    pub rate: u32,
+    pub burst: u32,
+}
+
+impl Limit {
+    /// None when `rate` is 0; empty values are the defaults
+    pub fn from_args(rate: &str, burst: &str) -> Result<Option<Self>, String> {
+        let parse = |flag: &str, value: &str, default: u32| match value {
+            "" => Ok(default),
+            value => value
+                .parse::<u32>()
+                .map_err(|_| format!("--{}={:?} is not a number", flag, value)),
+        };
+        let rate = parse("api-rate-limit", rate, RATE)?;
+        let burst = parse("api-rate-burst", burst, BURST)?;
+        if rate == 0 {
+            return Ok(None);
+        }
+        if burst == 0 {
+            return Err("--api-rate-burst must be at least 1".to_owned());
+        }
+        Ok(Some(Self { rate, burst }))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Caller {
+    /// The sha256 of the key, so the map holds no key
+    Key([u8; 32]),
+    /// `sub` of a verified bearer token
+    Subject(String),
+    Ip(IpAddr),
+}
+
+impl Caller {
+    /// Whose bucket a request takes from
+    pub(crate) fn of(headers: &HeaderMap, ip: IpAddr, state: &ApiState) -> Self {
+        let caller = match crate::oidc::bearer(headers) {
+            // a token goes by its IP until its first introspection is cached
+            Some(token) => crate::oidc::subject(token).map(Caller::Subject),
+            None => headers
+                .get("X-API-Key")
+                .and_then(|x| x.to_str().ok())
+                .filter(|key| state.api_key.scope_of(key).is_some())
+                .map(|key| Caller::Key(sodiumoxide::crypto::hash::sha256::hash(key.as_bytes()).0)),
+        };
+        caller.unwrap_or(Caller::Ip(ip))
+    }
+}
+
+impl std::fmt::Display for Caller {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            // the first 12 digits of the key's fingerprint
+            Caller::Key(hash) => {
+                f.write_str("key ")?;
+                hash[..6].iter().try_for_each(|b| write!(f, "{:02x}", b))
+            }
+            Caller::Subject(subject) => write!(f, "subject {}", subject),
+            Caller::Ip(ip) => write!(f, "ip {}", ip),
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    at: Instant,
+    /// Refused since the last request let through, logged once
+    limited: bool,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: Limit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate as f64).min(limit.burst as f64);
+        self.at = now;
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref LIMIT: RwLock<Option<Limit>> = Default::default();
+    static ref BUCKETS: Mutex<HashMap<Caller, Bucket>> = Default::default();
+}
+
+static LIMITED: AtomicU64 = AtomicU64::new(0);
+
+/// Limit requests to `limit` from now on (None lets all through); buckets
+/// start over full
+pub fn configure(limit: Option<Limit>) {
+    if let Ok(mut buckets) = BUCKETS.lock() {
+        buckets.clear();
+    }
+    if let Ok(mut current) = LIMIT.write() {
+        *current = limit;
+    }
+}
+
+pub fn configured() -> Option<Limit> {
+    LIMIT.read().ok().and_then(|x| *x)
+}
+
+/// Take a token for `caller`; how long until the next one when there is none,
+/// and whether this is the first refusal since a request was let through
+pub(crate) fn take(limit: Limit, caller: Caller, now: Instant) -> Result<(), (Duration, bool)> {
+    let mut buckets = match BUCKETS.lock() {
+        Ok(buckets) => buckets,
+        Err(e) => e.into_inner(),
+    };
+    if matches!(caller, Caller::Ip(_))
+        && !buckets.contains_key(&caller)
+        && ip_buckets(&buckets) >= MAX_IP_BUCKETS
+    {
+        evict_ip(&mut buckets, limit, now);
+    }
+    let bucket = buckets.entry(caller).or_insert(Bucket {
+        tokens: limit.burst as f64,
+        at: now,
+        limited: false,
+    });
+    bucket.refill(limit, now);
+    if bucket.tokens >= 1.0 {
+        bucket.tokens -= 1.0;
+        bucket.limited = false;
+        return Ok(());
+    }
+    let first = !bucket.limited;
+    bucket.limited = true;
+    let wait = Duration::from_secs_f64((1.0 - bucket.tokens) / limit.rate as f64);
+    Err((wait, first))
+}
+
+fn ip_buckets(buckets: &HashMap<Caller, Bucket>) -> usize {
+    buckets
+        .keys()
+        .filter(|caller| matches!(caller, Caller::Ip(_)))
+        .count()
+}
+
+/// Make room for a new source IP: drop the IPs whose buckets refilled to the
+/// burst, or else the one idle for the longest. A refill would move every
+/// bucket's time to `now`, so the buckets are only read here.
+fn evict_ip(buckets: &mut HashMap<Caller, Bucket>, limit: Limit, now: Instant) {
+    let full = |bucket: &Bucket| {
+        let elapsed = now.saturating_duration_since(bucket.at).as_secs_f64();
+        bucket.tokens + elapsed * limit.rate as f64 >= limit.burst as f64
+    };
+    buckets.retain(|caller, bucket| !matches!(caller, Caller::Ip(_)) || !full(bucket));
+    if ip_buckets(buckets) < MAX_IP_BUCKETS {
+        return;
+    }
+    let idle = buckets
+        .iter()
+        .filter(|(caller, _)| matches!(caller, Caller::Ip(_)))
+        .min_by_key(|(_, bucket)| bucket.at)
+        .map(|(caller, _)| caller.clone());
+    if let Some(idle) = idle {
+        buckets.remove(&idle);
+    }
+}
+
+fn sweep_locked(buckets: &mut HashMap<Caller, Bucket>, limit: Limit, now: Instant) {
+    buckets.retain(|_, bucket| {
+        bucket.refill(limit, now);
+        bucket.tokens < limit.burst as f64
+    });
+}
+
+/// Drop the buckets that refilled to the burst, as a new one would start;
+/// returns how many are left
+pub fn sweep() -> usize {
+    let mut buckets = match BUCKETS.lock() {
+        Ok(buckets) => buckets,
+        Err(e) => e.into_inner(),
+    };
+    match configured() {
+        Some(limit) => sweep_locked(&mut buckets, limit, Instant::now()),
+        None => buckets.clear(),
+    }
+    buckets.len()
+}
+
+/// Sweep the buckets every SWEEP_SECS
+pub fn start() {
+    hbbs::supervise("api_rate_sweep", || async {
+        loop {
+            tokio::time::sleep(Duration::from_secs(SWEEP_SECS)).await;
+            sweep();
+        }
+    });
+}
+
+/// Route layer refusing requests over the limit with 429
+pub async fn limit<B>(
+    matched: MatchedPath,
+    ConnectInfo(caller): ConnectInfo<SocketAddr>,
+    Extension(state): Extension<Arc<ApiState>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let limit = match configured() {
+        Some(limit) if !EXEMPT.contains(&matched.as_str()) => limit,
+        _ => return next.run(req).await,
+    };
+    let bucket = Caller::of(req.headers(), caller.ip(), &state);
+    let (wait, first) = match take(limit, bucket.clone(), Instant::now()) {
+        Ok(()) => return next.run(req).await,
+        Err(refused) => refused,
+    };
+    LIMITED.fetch_add(1, Ordering::Relaxed);
+    if first {
+        log::warn!(
+            "API: Rate limit ({}/s, burst {}) hit by {}",
+            limit.rate,
+            limit.burst,
+            bucket
+        );
+    }
+    let secs = wait.as_secs_f64().ceil().max(1.0) as u64;
+    let retry_after = [(RETRY_AFTER, secs.to_string())];
+    (StatusCode::TOO_MANY_REQUESTS, retry_after).into_response()
+}
+
+/// Source IPs and keys with a bucket, for /api/stats/memory
+pub fn memory() -> Option<(usize, usize)> {
+    let buckets = BUCKETS.try_lock().ok()?.len();
+    Some((buckets, hbbs::map_entry_bytes::<Caller, Bucket>(0)))
+}
+
+/// Prometheus lines for the refused requests, appended to /metrics
+pub fn render_metrics() -> String {
+    let mut out = String::new();
+    out.push_str("# HELP hbbs_api_rate_limited_total Requests refused by the rate limit\n");
+    out.push_str("# TYPE hbbs_api_rate_limited_total counter\n");
+    out.push_str(&format!(
+        "hbbs_api_rate_limited_total {}\n",
+        LIMITED.load(Ordering::Relaxed)
+    ));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: Limit = Limit { rate: 2, burst: 3 };
+
+    #[test]
+    fn limits_from_args() {
+        let defaults = Limit {
+            rate: RATE,
+            burst: BURST,
+        };
+        assert_eq!(Limit::from_args("0", ""), Ok(None));
+        assert_eq!(Limit::from_args("", ""), Ok(Some(defaults)));
+        assert!(Limit::from_args("5", "0").is_err());
+        assert!(Limit::from_args("fast", "").is_err());
+    }
+
+    #[test]
+    fn bucket_refills_at_the_rate_up_to_the_burst() {
+        let t0 = Instant::now();
+        let mut bucket = Bucket {
+            tokens: 0.0,
+            at: t0,
+            limited: false,
+        };
+        bucket.refill(LIMIT, t0 + Duration::from_millis(500));
+        assert_eq!(bucket.tokens, 1.0);
+        bucket.refill(LIMIT, t0 + Duration::from_secs(10));
+        assert_eq!(bucket.tokens, LIMIT.burst as f64);
+    }
+
+    #[test]
+    fn take_refuses_past_the_burst_until_refilled() {
+        // a caller of its own, the buckets are shared by every test
+        let caller = || Caller::Subject("ratelimit-unit-test".to_owned());
+        let t0 = Instant::now();
+        for _ in 0..LIMIT.burst {
+            assert_eq!(take(LIMIT, caller(), t0), Ok(()));
+        }
+        assert_eq!(
+            take(LIMIT, caller(), t0),
+            Err((Duration::from_millis(500), true))
+        );
+        assert_eq!(
+            take(LIMIT, caller(), t0),
+            Err((Duration::from_millis(500), false))
+        );
+        assert_eq!(
+            take(LIMIT, caller(), t0 + Duration::from_millis(500)),
+            Ok(())
+        );
+    }
+}
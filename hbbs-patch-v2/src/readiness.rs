@@ -0,0 +1,87 @@
+// Startup readiness
+// Follows the udp self-test from the rendezvous side. Once it passed (or is off
+// with TEST_HBBS=no) systemd is told READY=1 through $NOTIFY_SOCKET, so a
+// Type=notify unit only counts as started when clients can register. When it
+// failed, `/api/health` reports down with the reason for DOWN_GRACE_SECS before
+// the process ends with ExitCode::SelfTest.
+
+use hbb_common::{log, tokio};
+use hbbs::Readiness;
+use std::time::Duration;
+
+const DOWN_GRACE_SECS: u64 = 3;
+
+pub fn spawn_watch_thread() -> std::thread::JoinHandle<()> {
+    let mut rx = hbbs::readiness_watch();
+    std::thread::spawn(move || {
+        let rt = match tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+        {
+            Ok(rt) => rt,
+            Err(e) => {
+                log::error!("Readiness: cannot follow the self-test: {}", e);
+                crate::crash::exit(crate::crash::ExitCode::Failure);
+            }
+        };
+        rt.block_on(async {
+            let mut announced = false;
+            loop {
+                let state = rx.borrow_and_update().clone();
+                match state {
+                    Readiness::Starting => {}
+                    Readiness::Ready if !announced => {
+                        announced = true;
+                        match notify_systemd("READY=1") {
+                            Ok(true) => log::info!("Ready, told systemd"),
+                            Ok(false) => log::info!("Ready"),
+                            Err(e) => log::warn!("Ready, but cannot tell systemd: {}", e),
+                        }
+                    }
+                    Readiness::Ready => {}
+                    Readiness::Down(reason) => {
+                        log::error!(
+                            "Self-test failed, exiting in {}s: {}",
+                            DOWN_GRACE_SECS,
+                            reason
+                        );
+                        tokio::time::sleep(Duration::from_secs(DOWN_GRACE_SECS)).await;
+                        crate::crash::exit(crate::crash::ExitCode::SelfTest);
+                    }
+                }
+                if rx.changed().await.is_err() {
+                    return;
+                }
+            }
+        });
+    })
+}
+
+/// Sends a state to the service manager; Ok(false) when not run under one
+/// with notify support
+#[cfg(unix)]
+pub fn notify_systemd(state: &str) -> std::io::Result<bool> {
+    use std::os::unix::net::UnixDatagram;
+    let path = match std::env::var("NOTIFY_SOCKET") {
+        Ok(path) if !path.is_empty() => path,
+        _ => return Ok(false),
+    };
+    let socket = UnixDatagram::unbound()?;
+    match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), &path)?;
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(not(unix))]
+pub fn notify_systemd(_state: &str) -> std::io::Result<bool> {
+    Ok(false)
+}
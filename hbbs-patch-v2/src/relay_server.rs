@@ -450,7 +450,7 @@ async fn make_pair_(stream: impl StreamTrait, addr: SocketAddr, key: &str, limit
                                 // This protects banned devices from receiving connections
                                 if !target_id.is_empty() {
                                     if let Ok(Some(Some(banned))) = conn
-                                        .prepare("SELECT is_banned FROM peer WHERE id = ? AND is_deleted = 0")
+                                        .prepare("SELECT is_banned = 1 AND (banned_until IS NULL OR banned_until > CAST(strftime('%s', 'now') AS INTEGER)) FROM peer WHERE id = ? AND is_deleted = 0")
                                         .and_then(|mut stmt| stmt.query_row([&target_id], |row| row.get::<_, Option<i32>>(0)).optional()) {
                                         if banned == 1 {
                                             log::warn!("HBBR Relay BLOCKED - target device {} is BANNED", target_id);
@@ -7,11 +7,11 @@ use hbb_common::{
     config,
     futures::future::join_all,
     futures_util::{
-        sink::SinkExt,
-        stream::{SplitSink, StreamExt},
+        sink::{Sink as FuturesSink, SinkExt},
+        stream::{SplitSink, Stream, StreamExt},
     },
     log,
-    protobuf::{Message as _, MessageField},
+    protobuf::{Message as _, MessageField, MessageFull as _},
     rendezvous_proto::{
         register_pk_response::Result::{TOO_FREQUENT, UUID_MISMATCH},
         *,
@@ -20,10 +20,10 @@ use hbb_common::{
     timeout,
     tokio::{
         self,
-        io::{AsyncReadExt, AsyncWriteExt},
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
         net::{TcpListener, TcpStream},
-        sync::{mpsc, Mutex},
-        time::{interval, Duration},
+        sync::{mpsc, oneshot, watch, Mutex},
+        time::{interval, Duration, Interval},
     },
     tokio_util::codec::Framed,
     try_into_v4,
@@ -31,25 +31,52 @@ use hbb_common::{
     AddrMangle, ResultType,
 };
 use ipnetwork::Ipv4Network;
+use serde_derive::Serialize;
 use sodiumoxide::crypto::sign;
 use std::{
+    borrow::Cow,
     collections::HashMap,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
-    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    hash::{Hash, Hasher},
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicI32, AtomicI64, AtomicU64, AtomicU8, AtomicUsize, Ordering},
     sync::Arc,
+    task::{Context, Poll},
     time::Instant,
 };
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 enum Data {
     Msg(Box<RendezvousMessage>, SocketAddr),
+    // re-queued after a transient udp send failure, with the attempt number
+    MsgRetry(Box<RendezvousMessage>, SocketAddr, u32),
     RelayServers0(String),
     RelayServers(RelayServers),
+    // a serial bump from the API: the new serial (None for the next one), who
+    // asked and where to deliver the outcome
+    Serial(
+        Option<i32>,
+        String,
+        oneshot::Sender<Result<SerialBump, String>>,
+    ),
+    // a dry-run punch hole from the API and where to deliver the trace
+    SimulatePunch(PunchSimulation, oneshot::Sender<Result<PunchTrace, String>>),
 }
 
 const REG_TIMEOUT: i32 = 30_000;
-type TcpStreamSink = SplitSink<Framed<TcpStream, BytesCodec>, Bytes>;
-type WsSink = SplitSink<tokio_tungstenite::WebSocketStream<TcpStream>, tungstenite::Message>;
+type TcpStreamSink = Metered<SplitSink<Framed<Conn, BytesCodec>, Bytes>>;
+type WsSink = Metered<SplitSink<tokio_tungstenite::WebSocketStream<Conn>, tungstenite::Message>>;
+/// The bytes of a tcp or websocket connection: the accepted socket, or TLS
+/// over it on the shared port
+pub trait ConnIo: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ConnIo for T {}
+pub type Conn = Box<dyn ConnIo>;
+/// Terminates TLS on the shared port, see set_tls_acceptor
+pub type TlsAcceptFn = Box<
+    dyn Fn(TcpStream) -> Pin<Box<dyn std::future::Future<Output = std::io::Result<Conn>> + Send>>
+        + Send
+        + Sync,
+>;
 enum Sink {
     TcpStream(TcpStreamSink),
     Ws(WsSink),
@@ -60,26 +87,4578 @@ static ROTATION_RELAY_SERVER: AtomicUsize = AtomicUsize::new(0);
 type RelayServers = Vec<String>;
 const CHECK_RELAY_TIMEOUT: u64 = 3_000;
 static ALWAYS_USE_RELAY: AtomicBool = AtomicBool::new(false);
+static RELAY_MODE: AtomicU8 = AtomicU8::new(RelayMode::Rotation as u8);
+static REBIND_POLICY: AtomicU8 = AtomicU8::new(RebindPolicy::Rebind as u8);
+// how long an ip a RegisterPk verified from may be rebound to without asking again
+const VERIFIED_IP_SECS: u64 = 3600;
+const VERIFIED_IPS_MAX: usize = 4;
+// single-port mode: how long an accepted connection may stay silent before we give up sniffing,
+// and how long a TLS handshake may take after that
+const SNIFF_TIMEOUT: u64 = 3_000;
+const SNIFF_LEN: usize = 4;
+
+const RELAY_STATS_MAX_PEERS: usize = 10_000;
+const RECENT_ERRORS_MAX: usize = 500;
+// transient udp send failures (full socket buffer) are retried this many times, backing off from 200us
+const UDP_SEND_RETRIES: u32 = 3;
+const UDP_SEND_BACKOFF_US: u64 = 200;
+// byte-identical queued udp messages to the same address within this window go out
+// once (UDP_DEDUP_WINDOW_MS, 0 disables); the window holds at most UDP_DEDUP_MAX sends
+const UDP_DEDUP_WINDOW_MS: u64 = 100;
+const UDP_DEDUP_MAX: usize = 4096;
+// signed IdPk bytes are reused this long for the same id and pk (SIGN_CACHE_SECS, 0 disables)
+const SIGN_CACHE_SECS: u64 = 30;
+const SIGN_CACHE_MAX: usize = 100_000;
+// payloads above this size are signed on the blocking pool (SIGN_BLOCKING_BYTES)
+const SIGN_BLOCKING_BYTES: u64 = 4096;
+
+// Size ceilings of incoming messages, checked before parsing. Every frame is bounded by
+// its transport (MAX_UDP_MESSAGE_BYTES, MAX_TCP_MESSAGE_BYTES, the latter also for
+// websocket), every message type by its own ceiling (MAX_<TYPE>_BYTES, e.g.
+// MAX_REGISTER_PK_BYTES), DEFAULT_MESSAGE_LIMIT for types not listed. Read at start.
+const MAX_UDP_MESSAGE_BYTES: u64 = 64 * 1024;
+const MAX_TCP_MESSAGE_BYTES: u64 = 64 * 1024;
+const DEFAULT_MESSAGE_LIMIT: u64 = 4 * 1024;
+const MESSAGE_LIMITS: [(&str, u64); 9] = [
+    ("register_peer", 512),
+    ("register_pk", 1024),
+    ("punch_hole_request", 1024),
+    ("test_nat_request", 512),
+    ("punch_hole_sent", 2048),
+    ("local_addr", 2048),
+    ("request_relay", 2048),
+    ("relay_response", 2048),
+    // carries the ids of a whole address book
+    ("online_request", 32 * 1024),
+];
+
+pub use crate::database::{
+    append_id_history, archive_dir, archives_for, check_storage, merge_duplicate_peers,
+    month_bounds, parse_id_history, parse_last_online, register_pool_stats, status_writer_stats,
+    storage_report, AccessRule, Broadcast, Database, IdChangeVia, IdHistoryEntry, PeerMerge,
+    PeerNotice, PoolStats, RelayHealth, SourceBacklog, StatusWriterStats, StorageIssue,
+    StorageProblem, StorageReport, TopWriter, MAX_ATTACHED_ARCHIVES,
+};
+pub use crate::peer::{
+    active_peer_marks, canonical_id, check_uuid_churn, ct_eq, db_url, deprecated_version_peers,
+    deprecated_versions, flush_active_peers, flush_protocol_versions, forget_user_status,
+    heartbeat_interval_secs, malformed_credential_count, offline_pass_allowed, peer_map_watch,
+    peer_timeout_secs, peer_timers, pk_change_policy, pk_fingerprint, protocol_versions,
+    set_deprecated_versions, set_pk_change_policy, uuid_churn_anomalies, uuid_churn_threshold,
+    uuid_churn_window, version_label, warn_deprecated_versions, Clock, DeprecatedVersion,
+    DriftReport, PeerMapHandle, PeerStats, PeerTimers, PkChangePolicy, ProtocolKind,
+    ProtocolVersionCount, SharedClock, SystemClock, TestClock, TimerInputs, UuidChurnEntry,
+};
+
+/// Why a session was steered to the relay instead of a direct punch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RelayReason {
+    AlwaysUseRelay,
+    LanMismatch,
+    AddrFamily,
+    SameNatHairpin,
+    Websocket,
+    PunchTimeout,
+    ClientRequest,
+}
+
+impl RelayReason {
+    pub const ALL: [RelayReason; 7] = [
+        RelayReason::AlwaysUseRelay,
+        RelayReason::LanMismatch,
+        RelayReason::AddrFamily,
+        RelayReason::SameNatHairpin,
+        RelayReason::Websocket,
+        RelayReason::PunchTimeout,
+        RelayReason::ClientRequest,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RelayReason::AlwaysUseRelay => "always_use_relay",
+            RelayReason::LanMismatch => "lan_mismatch",
+            RelayReason::AddrFamily => "addr_family",
+            RelayReason::SameNatHairpin => "same_nat_hairpin",
+            RelayReason::Websocket => "websocket",
+            RelayReason::PunchTimeout => "punch_timeout",
+            RelayReason::ClientRequest => "client_request",
+        }
+    }
+}
+
+/// Where a rendezvous message came in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Ws,
+}
+
+impl Transport {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+            Transport::Ws => "ws",
+        }
+    }
+
+    /// Largest frame accepted on this transport at all
+    pub fn max_frame(&self) -> usize {
+        match self {
+            Transport::Udp => TRANSPORT_LIMITS.0,
+            Transport::Tcp | Transport::Ws => TRANSPORT_LIMITS.1,
+        }
+    }
+}
+
+/// Field number of the message type, read from the leading tag so no parsing
+/// (and no allocation) happens before the size check
+fn union_field(bytes: &[u8]) -> Option<u32> {
+    let mut tag = 0u64;
+    for (i, b) in bytes.iter().take(5).enumerate() {
+        tag |= ((b & 0x7f) as u64) << (7 * i);
+        if b & 0x80 == 0 {
+            return Some((tag >> 3) as u32);
+        }
+    }
+    None
+}
+
+/// Whether an incoming message is within the ceilings of its transport and type;
+/// violators are counted and must be dropped unparsed
+pub fn message_size_ok(bytes: &[u8], transport: Transport) -> bool {
+    let limit = union_field(bytes)
+        .and_then(|x| MESSAGE_SIZE_LIMITS.get(&x).copied())
+        .unwrap_or(*DEFAULT_MESSAGE_SIZE_LIMIT)
+        .min(transport.max_frame());
+    if bytes.len() <= limit {
+        return true;
+    }
+    MESSAGE_REJECTS.inc(transport.as_str());
+    log::debug!(
+        "Dropped {} byte {} message (field {:?}, limit {})",
+        bytes.len(),
+        transport.as_str(),
+        union_field(bytes),
+        limit
+    );
+    false
+}
+
+/// How a relay is picked among the healthy ones (`--relay-mode`, RELAY_MODE)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayMode {
+    /// Round robin per request (the historical behavior)
+    Rotation = 0,
+    /// Same relay for the same initiator and target while it stays healthy
+    Sticky = 1,
+    /// Relay with the fastest connect in the last health check
+    Latency = 2,
+}
+
+impl RelayMode {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "rotation" => Some(Self::Rotation),
+            "sticky" => Some(Self::Sticky),
+            "latency" => Some(Self::Latency),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Rotation => "rotation",
+            Self::Sticky => "sticky",
+            Self::Latency => "latency",
+        }
+    }
+}
+
+pub fn relay_mode() -> RelayMode {
+    match RELAY_MODE.load(Ordering::SeqCst) {
+        1 => RelayMode::Sticky,
+        2 => RelayMode::Latency,
+        _ => RelayMode::Rotation,
+    }
+}
+
+pub fn set_relay_mode(mode: RelayMode) {
+    RELAY_MODE.store(mode as u8, Ordering::SeqCst);
+}
+
+/// What a session to a peer with bound relays gets while all of them are down
+/// (`--relay-binding-fallback`, RELAY_BINDING_FALLBACK)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BindingFallback {
+    /// The shared relays of relay-servers
+    #[default]
+    Shared,
+    /// No relay: the punch hole is refused
+    Fail,
+}
+
+impl BindingFallback {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "shared" => Some(Self::Shared),
+            "fail" => Some(Self::Fail),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Shared => "shared",
+            Self::Fail => "fail",
+        }
+    }
+}
+
+/// Relays reserved for the peers with a tag (`--relay-bindings`, RELAY_BINDINGS),
+/// written `tag=host,host:port;tag2=host`. The first binding naming one of the
+/// target's tags applies; a bound relay is never handed to other peers.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RelayBindings {
+    pub bindings: Vec<(String, RelayServers)>,
+    pub fallback: BindingFallback,
+}
+
+impl RelayBindings {
+    pub fn parse(input: &str, fallback: BindingFallback) -> Result<Self, String> {
+        let mut bindings: Vec<(String, RelayServers)> = Vec::new();
+        for entry in input.split(';').map(str::trim).filter(|x| !x.is_empty()) {
+            let (tag, relays) = match entry.split_once('=') {
+                Some((tag, relays)) => (tag.trim(), relays),
+                None => return Err(format!("{:?} is not tag=relay,relay", entry)),
+            };
+            if tag.is_empty() || tag.contains(',') {
+                return Err(format!("{:?} does not start with one tag", entry));
+            }
+            if bindings.iter().any(|x| x.0 == tag) {
+                return Err(format!("tag {:?} is bound twice", tag));
+            }
+            let (relays, problems) = canonical_relays(relays);
+            if let Some(problem) = problems.first() {
+                return Err(format!("{}: {}", tag, problem));
+            }
+            if relays.is_empty() {
+                return Err(format!("tag {:?} has no relay", tag));
+            }
+            bindings.push((tag.to_owned(), relays));
+        }
+        Ok(Self { bindings, fallback })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bindings.is_empty()
+    }
+
+    /// Every bound relay, once
+    pub fn relays(&self) -> RelayServers {
+        let mut relays = RelayServers::new();
+        for relay in self.bindings.iter().flat_map(|x| x.1.iter()) {
+            if !relays.contains(relay) {
+                relays.push(relay.clone());
+            }
+        }
+        relays
+    }
+
+    /// The tags `relay` is bound to
+    pub fn tags_of(&self, relay: &str) -> Vec<String> {
+        self.bindings
+            .iter()
+            .filter(|x| x.1.iter().any(|r| r == relay))
+            .map(|x| x.0.clone())
+            .collect()
+    }
+}
+
+/// The relays a session may be steered to, from the target's tags
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RelayPool {
+    /// No binding applies: the relays of relay-servers
+    Shared,
+    /// The usable relays bound to the tag
+    Bound(String, RelayServers),
+    /// Every relay bound to the tag is down, the shared relays stand in
+    Fallback(String),
+    /// Every relay bound to the tag is down and relay-binding-fallback is fail
+    Refused(String),
+}
+
+impl RelayPool {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Shared => "shared",
+            Self::Bound(..) => "bound",
+            Self::Fallback(_) => "fallback",
+            Self::Refused(_) => "none",
+        }
+    }
+}
+
+impl std::fmt::Display for RelayPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Shared => f.write_str("shared"),
+            Self::Bound(tag, _) | Self::Fallback(tag) | Self::Refused(tag) => {
+                write!(f, "{}:{}", self.kind(), tag)
+            }
+        }
+    }
+}
+
+/// Pool for a target with `tags`: its binding's relays as usable_relays orders
+/// them (healthy, else unchecked) unless every one of them is freshly seen down,
+/// then the shared relays or none as the binding fallback says
+pub fn relay_pool(
+    bindings: &RelayBindings,
+    tags: &[String],
+    known: &HashMap<String, RelayHealth>,
+    now: i64,
+    fresh_secs: i64,
+) -> RelayPool {
+    let (tag, relays) = match bindings.bindings.iter().find(|x| tags.contains(&x.0)) {
+        Some(x) => x,
+        None => return RelayPool::Shared,
+    };
+    let down = |x: &String| {
+        known
+            .get(x)
+            .map_or(false, |h| now - h.checked_at <= fresh_secs && !h.healthy)
+    };
+    if !relays.iter().all(down) {
+        return RelayPool::Bound(tag.clone(), usable_relays(relays, known, now, fresh_secs));
+    }
+    match bindings.fallback {
+        BindingFallback::Shared => RelayPool::Fallback(tag.clone()),
+        BindingFallback::Fail => RelayPool::Refused(tag.clone()),
+    }
+}
+
+pub fn relay_bindings() -> Arc<RelayBindings> {
+    RELAY_BINDINGS.read().map(|x| x.clone()).unwrap_or_default()
+}
+
+pub fn set_relay_bindings(bindings: RelayBindings) {
+    if let Ok(mut lock) = RELAY_BINDINGS.write() {
+        *lock = Arc::new(bindings);
+    }
+}
+
+/// What a registration from another address than the stored one may do
+/// (`--rebind-policy`, REBIND_POLICY). A RegisterPeer carries no uuid or pk, so
+/// whatever it is not allowed to move waits for a RegisterPk to verify.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RebindPolicy {
+    /// Any change of port or ip asks for the key before the address moves
+    Strict = 0,
+    /// A new port on the same ip moves the address right away (the historical
+    /// behavior), a new ip asks for the key
+    Rebind = 1,
+    /// Like rebind, and a new ip moves the address right away too if a RegisterPk
+    /// with matching uuid and pk came from it within VERIFIED_IP_SECS
+    RebindAny = 2,
+}
+
+impl RebindPolicy {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "strict" => Some(Self::Strict),
+            "rebind" => Some(Self::Rebind),
+            "rebind-any" => Some(Self::RebindAny),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Strict => "strict",
+            Self::Rebind => "rebind",
+            Self::RebindAny => "rebind-any",
+        }
+    }
+}
+
+pub fn rebind_policy() -> RebindPolicy {
+    match REBIND_POLICY.load(Ordering::SeqCst) {
+        0 => RebindPolicy::Strict,
+        2 => RebindPolicy::RebindAny,
+        _ => RebindPolicy::Rebind,
+    }
+}
+
+pub fn set_rebind_policy(policy: RebindPolicy) {
+    REBIND_POLICY.store(policy as u8, Ordering::SeqCst);
+}
+
+/// What a drain turns away until its deadline (`POST /api/admin/drain`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DrainMode {
+    /// New registrations and key or address changes get TOO_FREQUENT, so clients
+    /// retry; RegisterPeer and RegisterPk of known peers still refresh them
+    Registrations = 1,
+    /// Punch hole and relay requests are refused, no new sessions are brokered
+    Punch = 2,
+    /// Both: maintenance, health answers 503
+    All = 3,
+}
+
+impl DrainMode {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "registrations" => Some(Self::Registrations),
+            "punch" => Some(Self::Punch),
+            "all" => Some(Self::All),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Registrations => "registrations",
+            Self::Punch => "punch",
+            Self::All => "all",
+        }
+    }
+
+    fn covers(self, what: DrainMode) -> bool {
+        self == DrainMode::All || self == what
+    }
+}
+
+/// A drain in effect; kept in memory only, a restart ends it
+#[derive(Debug, Clone, Serialize)]
+pub struct Drain {
+    pub mode: DrainMode,
+    /// Unix seconds
+    pub started_at: i64,
+    /// Unix seconds the drain reverts by itself
+    pub until: i64,
+    pub by: String,
+}
+
+// mode of the drain in effect, 0 without one, so that handlers skip the lock
+static DRAIN_MODE: AtomicU8 = AtomicU8::new(0);
+
+lazy_static::lazy_static! {
+    static ref DRAIN: std::sync::Mutex<Option<Drain>> = Default::default();
+}
+
+/// Start a drain until `until` (unix seconds), replacing the one in effect
+pub fn start_drain(mode: DrainMode, until: i64, by: String) -> Drain {
+    let drain = Drain {
+        mode,
+        started_at: chrono::Utc::now().timestamp(),
+        until,
+        by,
+    };
+    if let Ok(mut lock) = DRAIN.lock() {
+        *lock = Some(drain.clone());
+        DRAIN_MODE.store(mode as u8, Ordering::SeqCst);
+    }
+    log::warn!(
+        "Drain of {} started by {} until {}",
+        mode.as_str(),
+        drain.by,
+        drain.until
+    );
+    drain
+}
+
+/// End the drain in effect before its deadline; the one that was ended
+pub fn end_drain(by: &str) -> Option<Drain> {
+    let ended = DRAIN.lock().ok().and_then(|mut lock| {
+        DRAIN_MODE.store(0, Ordering::SeqCst);
+        lock.take()
+    });
+    if let Some(drain) = &ended {
+        log::warn!("Drain of {} ended by {}", drain.mode.as_str(), by);
+    }
+    ended
+}
+
+/// The drain in effect, reverting it once past its deadline
+pub fn current_drain() -> Option<Drain> {
+    if DRAIN_MODE.load(Ordering::SeqCst) == 0 {
+        return None;
+    }
+    let mut lock = DRAIN.lock().ok()?;
+    match lock.as_ref() {
+        Some(drain) if drain.until <= chrono::Utc::now().timestamp() => {
+            log::warn!(
+                "Drain of {} reached its deadline, back to normal",
+                drain.mode.as_str()
+            );
+            DRAIN_MODE.store(0, Ordering::SeqCst);
+            *lock = None;
+            None
+        }
+        drain => drain.cloned(),
+    }
+}
+
+fn draining(what: DrainMode) -> bool {
+    current_drain().map_or(false, |drain| drain.mode.covers(what))
+}
+
+/// Longest per-peer trace (POST /api/peers/:id/trace)
+pub const MAX_TRACE_SECS: i64 = 3600;
+/// Messages a capturing trace keeps, the oldest going first
+pub const TRACE_CAPTURE_MAX: usize = 1000;
+
+/// One message to or from a traced peer, decoded
+#[derive(Debug, Clone, Serialize)]
+pub struct TracedMessage {
+    /// Unix milliseconds
+    pub at_ms: i64,
+    /// "in" or "out"
+    pub direction: &'static str,
+    pub transport: &'static str,
+    pub addr: String,
+    /// Field name of the message type, e.g. register_pk
+    pub message: String,
+    /// Key fields, e.g. id, serial, nat type, relay server
+    pub fields: String,
+}
+
+/// A per-peer trace; kept in memory only, a restart ends it
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerTrace {
+    pub id: String,
+    /// Unix seconds
+    pub started_at: i64,
+    /// Unix seconds the trace ends by itself
+    pub until: i64,
+    pub by: String,
+    /// Addresses traced for the peer; it joins the ones it registers from
+    pub addrs: Vec<String>,
+    /// Whether messages are kept for GET /api/peers/:id/trace
+    pub capture: bool,
+    /// Messages traced, and those the capture no longer holds
+    pub traced: u64,
+    pub dropped: u64,
+    /// The captured messages, oldest first; only when reading a capture
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub messages: Option<Vec<TracedMessage>>,
+}
+
+struct ActiveTrace {
+    trace: PeerTrace,
+    captured: std::collections::VecDeque<TracedMessage>,
+}
+
+#[derive(Default)]
+struct Traces {
+    by_id: HashMap<String, std::sync::Mutex<ActiveTrace>>,
+    by_addr: HashMap<SocketAddr, String>,
+}
+
+// traces running, 0 lets every message skip the lock
+static TRACES_ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static::lazy_static! {
+    static ref TRACES: std::sync::RwLock<Traces> = Default::default();
+}
+
+/// Trace every message to or from `id` (and `addr`, its address when known)
+/// until `until` (unix seconds), replacing a trace of it in effect; with
+/// `capture` the last TRACE_CAPTURE_MAX of them are kept too
+pub fn start_peer_trace(
+    id: &str,
+    addr: Option<SocketAddr>,
+    until: i64,
+    capture: bool,
+    by: String,
+) -> PeerTrace {
+    let addr = addr.map(try_into_v4);
+    let trace = PeerTrace {
+        id: id.to_owned(),
+        started_at: chrono::Utc::now().timestamp(),
+        until,
+        by,
+        addrs: addr.iter().map(|x| x.to_string()).collect(),
+        capture,
+        traced: 0,
+        dropped: 0,
+        messages: None,
+    };
+    if let Ok(mut traces) = TRACES.write() {
+        traces.by_addr.retain(|_, traced| traced != id);
+        if let Some(addr) = addr {
+            traces.by_addr.insert(addr, id.to_owned());
+        }
+        let active = ActiveTrace {
+            trace: trace.clone(),
+            captured: Default::default(),
+        };
+        traces
+            .by_id
+            .insert(id.to_owned(), std::sync::Mutex::new(active));
+        TRACES_ACTIVE.store(traces.by_id.len(), Ordering::SeqCst);
+    }
+    log::warn!(
+        "Trace of {} started by {} until {}{}",
+        id,
+        trace.by,
+        until,
+        if capture { " with capture" } else { "" }
+    );
+    trace
+}
+
+/// The trace of `id` in effect, with its captured messages
+pub fn peer_trace(id: &str) -> Option<PeerTrace> {
+    expire_traces();
+    let traces = TRACES.read().ok()?;
+    let active = traces.by_id.get(id)?.lock().ok()?;
+    let mut trace = active.trace.clone();
+    if trace.capture {
+        trace.messages = Some(active.captured.iter().cloned().collect());
+    }
+    Some(trace)
+}
+
+/// Drop the traces past their deadline
+fn expire_traces() {
+    if TRACES_ACTIVE.load(Ordering::SeqCst) == 0 {
+        return;
+    }
+    let now = chrono::Utc::now().timestamp();
+    let mut traces = match TRACES.write() {
+        Ok(traces) => traces,
+        Err(_) => return,
+    };
+    let ended: Vec<String> = traces
+        .by_id
+        .iter()
+        .filter(|(_, x)| x.lock().map_or(true, |x| x.trace.until <= now))
+        .map(|(id, _)| id.clone())
+        .collect();
+    for id in &ended {
+        traces.by_id.remove(id);
+        log::warn!("Trace of {} reached its deadline", id);
+    }
+    traces.by_addr.retain(|_, id| !ended.contains(id));
+    TRACES_ACTIVE.store(traces.by_id.len(), Ordering::SeqCst);
+}
+
+/// Log (and capture) `msg` if it goes to or comes from a traced peer, by its
+/// address or an id it names; one atomic load while no trace is running
+pub fn trace_message(
+    direction: &'static str,
+    transport: Transport,
+    addr: SocketAddr,
+    msg: &RendezvousMessage,
+) {
+    if TRACES_ACTIVE.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    let addr = try_into_v4(addr);
+    let (own_id, other_id) = message_ids(msg);
+    let now = chrono::Utc::now();
+    let (id, learned, expired) = {
+        let traces = match TRACES.read() {
+            Ok(traces) => traces,
+            Err(_) => return,
+        };
+        let (id, learned) = match traces.by_addr.get(&addr) {
+            Some(id) => (id.as_str(), false),
+            None => match [own_id.as_deref(), other_id.as_deref()]
+                .into_iter()
+                .flatten()
+                .find(|x| traces.by_id.contains_key(*x))
+            {
+                // a peer's own id from a new address: the address it now uses
+                Some(id) => (id, direction == "in" && Some(id) == own_id.as_deref()),
+                None => return,
+            },
+        };
+        let mut active = match traces.by_id.get(id).map(|x| x.lock()) {
+            Some(Ok(active)) => active,
+            _ => return,
+        };
+        let expired = active.trace.until <= now.timestamp();
+        if !expired {
+            let (message, fields) = describe_message(msg);
+            log::info!(
+                "Trace {}: {} {} {} {} {}",
+                id,
+                direction,
+                transport.as_str(),
+                addr,
+                message,
+                fields
+            );
+            active.trace.traced += 1;
+            if active.trace.capture {
+                if active.captured.len() >= TRACE_CAPTURE_MAX {
+                    active.captured.pop_front();
+                    active.trace.dropped += 1;
+                }
+                active.captured.push_back(TracedMessage {
+                    at_ms: now.timestamp_millis(),
+                    direction,
+                    transport: transport.as_str(),
+                    addr: addr.to_string(),
+                    message,
+                    fields,
+                });
+            }
+            if learned {
+                active.trace.addrs.push(addr.to_string());
+            }
+        }
+        (id.to_owned(), learned && !expired, expired)
+    };
+    if expired {
+        expire_traces();
+    } else if learned {
+        if let Ok(mut traces) = TRACES.write() {
+            traces.by_addr.insert(addr, id);
+        }
+    }
+}
+
+/// The canonical id a message registers, and the one it is about or sent by
+fn message_ids(msg: &RendezvousMessage) -> (Option<Cow<'_, str>>, Option<Cow<'_, str>>) {
+    use rendezvous_message::Union;
+    let (own, other) = match &msg.union {
+        Some(Union::RegisterPeer(x)) => (Some(&x.id), None),
+        Some(Union::RegisterPk(x)) => (Some(&x.id), None),
+        Some(Union::PunchHoleRequest(x)) => (None, Some(&x.id)),
+        Some(Union::PunchHoleSent(x)) => (None, Some(&x.id)),
+        Some(Union::LocalAddr(x)) => (None, Some(&x.id)),
+        Some(Union::RequestRelay(x)) => (None, Some(&x.id)),
+        Some(Union::OnlineRequest(x)) => (None, Some(&x.id)),
+        _ => (None, None),
+    };
+    let canonical = |x: &String| match x.is_empty() {
+        true => None,
+        false => Some(canonical_id(x)),
+    };
+    (own.and_then(canonical), other.and_then(canonical))
+}
+
+/// The field name of a message's type and its key fields, for traces
+fn describe_message(msg: &RendezvousMessage) -> (String, String) {
+    use rendezvous_message::Union;
+    let message = RendezvousMessage::descriptor()
+        .fields()
+        .find(|x| x.has_field(msg))
+        .map_or_else(|| "unknown".to_owned(), |x| x.name().to_owned());
+    let addr = |x: &[u8]| match x.is_empty() {
+        true => String::new(),
+        false => AddrMangle::decode(x).to_string(),
+    };
+    let fields = match &msg.union {
+        Some(Union::RegisterPeer(x)) => format!("id={} serial={}", x.id, x.serial),
+        Some(Union::RegisterPk(x)) => format!(
+            "id={} old_id={} uuid_len={} pk={}",
+            x.id,
+            x.old_id,
+            x.uuid.len(),
+            pk_fingerprint(&x.pk)
+        ),
+        Some(Union::RegisterPkResponse(x)) => format!("result={:?}", x.result),
+        Some(Union::RegisterPeerResponse(x)) => format!("request_pk={}", x.request_pk),
+        Some(Union::PunchHoleRequest(x)) => format!(
+            "id={} nat_type={:?} version={}",
+            x.id, x.nat_type, x.version
+        ),
+        Some(Union::PunchHoleSent(x)) => format!(
+            "id={} socket_addr={} relay_server={} version={}",
+            x.id,
+            addr(&x.socket_addr),
+            x.relay_server,
+            x.version
+        ),
+        Some(Union::PunchHoleResponse(x)) => format!(
+            "failure={:?} socket_addr={} relay_server={}",
+            x.failure,
+            addr(&x.socket_addr),
+            x.relay_server
+        ),
+        Some(Union::LocalAddr(x)) => format!(
+            "id={} socket_addr={} local_addr={} relay_server={}",
+            x.id,
+            addr(&x.socket_addr),
+            addr(&x.local_addr),
+            x.relay_server
+        ),
+        Some(Union::RequestRelay(x)) => format!(
+            "id={} uuid={} relay_server={}",
+            x.id, x.uuid, x.relay_server
+        ),
+        Some(Union::RelayResponse(x)) => format!(
+            "id={} uuid={} relay_server={} refuse_reason={}",
+            x.id(),
+            x.uuid,
+            x.relay_server,
+            x.refuse_reason
+        ),
+        Some(Union::OnlineRequest(x)) => format!("id={} peers={}", x.id, x.peers.len()),
+        Some(Union::TestNatRequest(x)) => format!("serial={}", x.serial),
+        Some(Union::TestNatResponse(x)) => format!("port={}", x.port),
+        Some(Union::ConfigureUpdate(x)) => format!("serial={}", x.serial),
+        _ => String::new(),
+    };
+    (message, fields)
+}
+
+/// Seconds a stop signal keeps the listeners open as a full drain, so the
+/// orchestrator stops routing to the server before it goes away
+const DEFAULT_SHUTDOWN_DRAIN_SECS: u64 = 5;
+
+// set once SIGTERM or SIGINT arrived
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/// A stop signal arrived and the server is on its way out; `/healthz` fails
+pub fn stopping() -> bool {
+    STOPPING.load(Ordering::SeqCst)
+}
+
+/// SIGTERM and SIGINT with handlers of our own: as PID 1 in a container the
+/// default dispositions do not end the process
+#[cfg(unix)]
+struct StopSignals {
+    terminate: tokio::signal::unix::Signal,
+    interrupt: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+impl StopSignals {
+    fn install() -> ResultType<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+        Ok(Self {
+            terminate: signal(SignalKind::terminate())?,
+            interrupt: signal(SignalKind::interrupt())?,
+        })
+    }
+
+    async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.terminate.recv() => "SIGTERM",
+            _ = self.interrupt.recv() => "SIGINT",
+        }
+    }
+}
+
+#[cfg(not(unix))]
+struct StopSignals;
+
+#[cfg(not(unix))]
+impl StopSignals {
+    fn install() -> ResultType<Self> {
+        Ok(Self)
+    }
+
+    async fn recv(&mut self) -> &'static str {
+        if tokio::signal::ctrl_c().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+        "Ctrl-C"
+    }
+}
+
+/// Returns once the server should stop: after a stop signal and the
+/// SHUTDOWN_DRAIN_SECS that follow it as a full drain, or right away on a
+/// second signal during the drain
+async fn shutdown_signal() -> ResultType<()> {
+    let mut signals = StopSignals::install()?;
+    let signal = signals.recv().await;
+    STOPPING.store(true, Ordering::SeqCst);
+    let secs = std::env::var("SHUTDOWN_DRAIN_SECS")
+        .ok()
+        .and_then(|x| x.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_SHUTDOWN_DRAIN_SECS);
+    if secs == 0 {
+        log::info!("{}, shutting down", signal);
+        return Ok(());
+    }
+    start_drain(
+        DrainMode::All,
+        chrono::Utc::now().timestamp() + secs as i64,
+        signal.to_owned(),
+    );
+    log::info!("{}, draining for {}s before shutting down", signal, secs);
+    tokio::select! {
+        _ = tokio::time::sleep(Duration::from_secs(secs)) => log::info!("Drained, shutting down"),
+        again = signals.recv() => log::warn!("{} during the drain, shutting down now", again),
+    }
+    Ok(())
+}
+
+/// How the source of a registration differs from the stored address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddrChange {
+    None,
+    Port,
+    Ip,
+}
+
+impl AddrChange {
+    /// `stored` with port 0 is a peer loaded from the database, known by its ip only
+    pub fn of(stored: SocketAddr, stored_ip: &str, from: SocketAddr) -> Self {
+        let ip_changed = if stored.port() != 0 {
+            from.ip() != stored.ip()
+        } else {
+            from.ip().to_string() != stored_ip
+        };
+        if ip_changed && !from.ip().is_loopback() {
+            AddrChange::Ip
+        } else if stored.port() != 0 && stored != from {
+            AddrChange::Port
+        } else {
+            AddrChange::None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RebindDecision {
+    /// Same address, or the first one seen for a peer loaded from the database
+    Keep,
+    /// Move the stored address to the source now
+    Rebind,
+    /// Keep the stored address and ask for the key (request_pk)
+    Reverify,
+}
+
+/// What a RegisterPeer from a changed address does under `policy`;
+/// `ip_verified` tells whether a RegisterPk verified the new ip lately
+pub fn rebind_decision(
+    policy: RebindPolicy,
+    change: AddrChange,
+    ip_verified: bool,
+) -> RebindDecision {
+    match (change, policy) {
+        (AddrChange::None, _) => RebindDecision::Keep,
+        (AddrChange::Port, RebindPolicy::Strict) => RebindDecision::Reverify,
+        (AddrChange::Port, _) => RebindDecision::Rebind,
+        (AddrChange::Ip, RebindPolicy::RebindAny) if ip_verified => RebindDecision::Rebind,
+        (AddrChange::Ip, _) => RebindDecision::Reverify,
+    }
+}
+
+/// Remember an ip a RegisterPk with matching uuid and pk came from, for rebind-any
+fn verified_ip(peer: &mut crate::peer::Peer, ip: IpAddr, now: Instant) {
+    peer.verified_ips
+        .retain(|(x, tm)| *x != ip && now.duration_since(*tm).as_secs() < VERIFIED_IP_SECS);
+    if peer.verified_ips.len() >= VERIFIED_IPS_MAX {
+        peer.verified_ips.remove(0);
+    }
+    peer.verified_ips.push((ip, now));
+}
+
+fn count_rebind(change: AddrChange, decision: RebindDecision) {
+    REBINDS.inc(match (change, decision) {
+        (AddrChange::Port, RebindDecision::Rebind) => "port_rebind",
+        (AddrChange::Ip, RebindDecision::Rebind) => "ip_rebind",
+        (AddrChange::Port, RebindDecision::Reverify) => "port_reverify",
+        (AddrChange::Ip, RebindDecision::Reverify) => "ip_reverify",
+        _ => return,
+    });
+}
+
+/// Sticky relay choice: the relay with the highest hash of (initiator, target, relay).
+/// A pair keeps its relay while that one is healthy; when it drops out the pair
+/// moves to its next highest, and pairs on other relays stay where they are.
+pub fn sticky_relay<'a>(relays: &'a [String], initiator: &str, target: &str) -> Option<&'a String> {
+    relays.iter().max_by_key(|relay| {
+        let mut h = std::collections::hash_map::DefaultHasher::new();
+        (initiator, target, relay.as_str()).hash(&mut h);
+        h.finish()
+    })
+}
+
+/// Whether `ip` is a client on IPv6 alone, not an IPv4 client reaching the
+/// dual-stack listener through a v4-mapped address
+pub fn ipv6_only_client(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().is_none(),
+        IpAddr::V4(_) => false,
+    }
+}
+
+/// The relays a session with an IPv6-only side can reach: hostnames, which DNS64
+/// synthesizes an address for behind NAT64, and IPv6 literals. None when every
+/// relay is an IPv4 literal.
+pub fn ipv6_relays(relays: &[String]) -> Option<RelayServers> {
+    let usable: RelayServers = relays
+        .iter()
+        .filter(|x| !matches!(x.parse::<SocketAddr>(), Ok(SocketAddr::V4(_))))
+        .cloned()
+        .collect();
+    (!usable.is_empty()).then_some(usable)
+}
+
+/// The relays of `--relay-servers`/RELAY_SERVERS as canonical `host:port`
+/// entries, RELAY_PORT added where missing, and a problem for each rejected
+/// entry. Hostnames are not resolved here: one that does not resolve is kept
+/// and shows up as down in the next relay check.
+pub fn canonical_relays(input: &str) -> (RelayServers, Vec<String>) {
+    let mut relays = RelayServers::new();
+    let mut problems = Vec::new();
+    for entry in input.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+        match canonical_relay(entry) {
+            Ok(relay) if relays.contains(&relay) => {
+                problems.push(format!("{:?}: duplicate of {}", entry, relay))
+            }
+            Ok(relay) => relays.push(relay),
+            Err(problem) => problems.push(format!("{:?}: {}", entry, problem)),
+        }
+    }
+    (relays, problems)
+}
+
+fn canonical_relay(entry: &str) -> Result<String, String> {
+    let default_port = config::RELAY_PORT as u16;
+    if let Some((scheme, _)) = entry.split_once("://") {
+        return Err(format!(
+            "remove the {}:// prefix, expected host or host:port",
+            scheme
+        ));
+    }
+    if entry.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err("contains whitespace".to_owned());
+    }
+    let bare_ip = entry
+        .strip_prefix('[')
+        .and_then(|x| x.strip_suffix(']'))
+        .unwrap_or(entry)
+        .parse::<IpAddr>();
+    if let Ok(ip) = bare_ip {
+        return Ok(SocketAddr::new(ip, default_port).to_string());
+    }
+    let (host, port) = match entry.rsplit_once(':') {
+        Some((host, port)) => match port.parse::<u16>() {
+            Ok(port) if port > 0 => (host, port),
+            _ => return Err(format!("{:?} is not a port number (1-65535)", port)),
+        },
+        None => (entry, default_port),
+    };
+    let ip = host
+        .strip_prefix('[')
+        .and_then(|x| x.strip_suffix(']'))
+        .unwrap_or(host)
+        .parse::<IpAddr>();
+    if let Ok(ip) = ip {
+        return Ok(SocketAddr::new(ip, port).to_string());
+    }
+    let label = |x: &str| {
+        !x.is_empty()
+            && x.len() <= 63
+            && !x.starts_with('-')
+            && !x.ends_with('-')
+            && x.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    // a fully qualified name's trailing dot names the same host
+    let host = host.strip_suffix('.').unwrap_or(host);
+    if host.len() > 253 || !host.split('.').all(label) {
+        return Err(format!("{:?} is not a hostname or IP address", host));
+    }
+    Ok(format!("{}:{}", host.to_lowercase(), port))
+}
+
+/// Entries accepted in relay-servers and in rendezvous-servers (MAX_RELAY_SERVERS);
+/// a longer list is a config error
+const MAX_RELAY_SERVERS: u64 = 64;
+/// Relay checks in flight at once (RELAY_CHECK_CONCURRENCY)
+const RELAY_CHECK_CONCURRENCY: u64 = 8;
+/// Relays a session's relay is picked from, the fastest usable ones (RELAY_ADVERTISE_MAX)
+const RELAY_ADVERTISE_MAX: u64 = 5;
+
+pub fn max_relay_servers() -> usize {
+    env_u64("MAX_RELAY_SERVERS", MAX_RELAY_SERVERS).max(1) as _
+}
+
+pub fn relay_check_concurrency() -> usize {
+    env_u64("RELAY_CHECK_CONCURRENCY", RELAY_CHECK_CONCURRENCY).max(1) as _
+}
+
+pub fn relay_advertise_max() -> usize {
+    env_u64("RELAY_ADVERTISE_MAX", RELAY_ADVERTISE_MAX).max(1) as _
+}
+
+/// At most `k` of `relays`, the lowest last measured latency first and those
+/// never measured after them, kept in their configured order so that sticky
+/// and rotation picks do not move with every latency change
+pub fn advertised_relays(
+    relays: &[String],
+    latency: &HashMap<String, Duration>,
+    k: usize,
+) -> RelayServers {
+    if relays.len() <= k {
+        return relays.to_vec();
+    }
+    let mut ranked: Vec<(usize, Option<Duration>)> = relays
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (i, latency.get(x).copied()))
+        .collect();
+    ranked.sort_by_key(|(i, t)| (t.is_none(), *t, *i));
+    let mut picked: Vec<usize> = ranked.into_iter().take(k).map(|(i, _)| i).collect();
+    picked.sort_unstable();
+    picked.into_iter().map(|i| relays[i].clone()).collect()
+}
+
+/// Probe `hosts` with `probe`, at most relay_check_concurrency() at once, their
+/// starts spread evenly over `spread` rather than all at the same instant.
+/// Returns each host with its latency, None when down, in the order given.
+pub async fn probe_relays<F, Fut>(
+    hosts: &[String],
+    spread: Duration,
+    probe: F,
+) -> Vec<(String, Option<Duration>)>
+where
+    F: Fn(String) -> Fut,
+    Fut: std::future::Future<Output = Option<Duration>> + Send + 'static,
+{
+    let permits = Arc::new(tokio::sync::Semaphore::new(relay_check_concurrency()));
+    let gap = spread / hosts.len().max(1) as u32;
+    let mut futs = Vec::new();
+    for (i, host) in hosts.iter().enumerate() {
+        let permits = permits.clone();
+        let fut = probe(host.clone());
+        let host = host.clone();
+        futs.push(tokio::spawn(async move {
+            tokio::time::sleep(gap * i as u32).await;
+            let _permit = permits.acquire_owned().await.ok()?;
+            Some((host, fut.await))
+        }));
+    }
+    join_all(futs)
+        .await
+        .into_iter()
+        .filter_map(|x| x.ok().flatten())
+        .collect()
+}
+
+/// How long a relay check result is trusted, including one persisted before a
+/// restart (RELAY_HEALTH_FRESH_SECS)
+const RELAY_HEALTH_FRESH_SECS: u64 = 600;
+
+fn relay_health_fresh_secs() -> i64 {
+    env_u64("RELAY_HEALTH_FRESH_SECS", RELAY_HEALTH_FRESH_SECS) as _
+}
+
+/// Relay health as last checked, per host. `live` stays false while it only holds
+/// the snapshot loaded from the database at startup.
+#[derive(Default)]
+struct RelayState {
+    live: bool,
+    relays: HashMap<String, RelayHealth>,
+    saved_at: i64,
+}
+
+/// Relays to steer sessions to from what is known about them: those healthy in a
+/// fresh result, else those without one (unknown, checked by the next sweep), else
+/// all of them. A relay freshly seen down is never picked while another one is usable.
+pub fn usable_relays(
+    configured: &[String],
+    known: &HashMap<String, RelayHealth>,
+    now: i64,
+    fresh_secs: i64,
+) -> RelayServers {
+    let fresh = |x: &String| known.get(x).filter(|h| now - h.checked_at <= fresh_secs);
+    let healthy: RelayServers = configured
+        .iter()
+        .filter(|x| fresh(x).map_or(false, |h| h.healthy))
+        .cloned()
+        .collect();
+    if !healthy.is_empty() {
+        return healthy;
+    }
+    let unknown: RelayServers = configured
+        .iter()
+        .filter(|x| fresh(x).is_none())
+        .cloned()
+        .collect();
+    if !unknown.is_empty() {
+        return unknown;
+    }
+    configured.to_vec()
+}
+
+/// Seed the relay state with the snapshot persisted by an earlier run
+fn seed_relay_health(snapshot: Vec<RelayHealth>) {
+    let now = chrono::Utc::now().timestamp();
+    let fresh_secs = relay_health_fresh_secs();
+    if let Ok(mut lock) = RELAY_LATENCY.write() {
+        for x in snapshot.iter() {
+            if let (true, Some(ms)) = (now - x.checked_at <= fresh_secs, x.latency_ms) {
+                lock.insert(x.host.clone(), Duration::from_millis(ms));
+            }
+        }
+    }
+    if let Ok(mut lock) = RELAY_STATE.write() {
+        lock.saved_at = snapshot
+            .iter()
+            .map(|x| x.checked_at)
+            .max()
+            .unwrap_or_default();
+        lock.relays = snapshot.into_iter().map(|x| (x.host.clone(), x)).collect();
+        lock.live = false;
+    }
+}
+
+/// Fold the results of a relay check into the relay state. Returns the snapshot to
+/// persist when a relay changed health, or when the stored one is half way to stale.
+fn record_relay_check(
+    results: &[(String, Option<Duration>)],
+    now: i64,
+) -> Option<Vec<RelayHealth>> {
+    let mut state = RELAY_STATE.write().ok()?;
+    let mut changed = !state.live;
+    for (host, latency) in results {
+        let prev = state.relays.get(host).cloned();
+        changed |= prev
+            .as_ref()
+            .map_or(true, |x| x.healthy != latency.is_some());
+        let next = RelayHealth {
+            host: host.clone(),
+            healthy: latency.is_some(),
+            latency_ms: latency
+                .map(|t| t.as_millis() as u64)
+                .or_else(|| prev.as_ref().and_then(|x| x.latency_ms)),
+            failures: match (latency, prev) {
+                (Some(_), _) => 0,
+                (None, prev) => prev.map_or(0, |x| x.failures).saturating_add(1),
+            },
+            checked_at: now,
+        };
+        state.relays.insert(host.clone(), next);
+    }
+    state.live = true;
+    if !changed && now - state.saved_at < relay_health_fresh_secs() / 2 {
+        return None;
+    }
+    state.saved_at = now;
+    Some(
+        results
+            .iter()
+            .filter_map(|(host, _)| state.relays.get(host).cloned())
+            .collect(),
+    )
+}
+
+/// One configured relay as reported by `GET /api/relay-servers`
+#[derive(Debug, Clone, Serialize)]
+pub struct RelayStatus {
+    pub host: String,
+    /// healthy, down, or unknown (never checked, or the last result is stale)
+    pub state: &'static str,
+    pub latency_ms: Option<u64>,
+    pub failures: u32,
+    pub checked_at: Option<i64>,
+    /// Whether sessions are currently steered to it
+    pub in_use: bool,
+    /// In relay-servers, handed to any peer
+    pub shared: bool,
+    /// Tags of relay-bindings it is reserved for
+    pub tags: Vec<String>,
+}
+
+/// One binding of relay-bindings as reported by `GET /api/relay-servers`
+#[derive(Debug, Clone, Serialize)]
+pub struct RelayBindingStatus {
+    pub tag: String,
+    pub relays: RelayServers,
+    /// What a session to a peer with the tag gets now: bound, fallback (the
+    /// shared relays) or none
+    pub pool: &'static str,
+    /// The relays such a session is steered to
+    pub in_use: RelayServers,
+}
+
+/// Whether the relay state comes from a check by this run (true) or only from the
+/// snapshot seeded at startup (false), and the state of each configured relay
+pub fn relay_servers_status() -> (bool, Vec<RelayStatus>) {
+    let (configured, mut in_use) = RELAY_HEALTH.read().map(|x| x.clone()).unwrap_or_default();
+    let bindings = relay_bindings();
+    for binding in relay_bindings_status() {
+        if binding.pool == "bound" {
+            in_use.extend(binding.in_use);
+        }
+    }
+    let state = match RELAY_STATE.read() {
+        Ok(state) => state,
+        Err(_) => return (false, Vec::new()),
+    };
+    let now = chrono::Utc::now().timestamp();
+    let fresh_secs = relay_health_fresh_secs();
+    let mut hosts = configured.clone();
+    hosts.extend(
+        bindings
+            .relays()
+            .into_iter()
+            .filter(|x| !configured.contains(x)),
+    );
+    let relays = hosts
+        .iter()
+        .map(|host| {
+            let known = state.relays.get(host);
+            RelayStatus {
+                host: host.clone(),
+                state: match known {
+                    Some(x) if now - x.checked_at <= fresh_secs && x.healthy => "healthy",
+                    Some(x) if now - x.checked_at <= fresh_secs => "down",
+                    _ => "unknown",
+                },
+                latency_ms: known.and_then(|x| x.latency_ms),
+                failures: known.map_or(0, |x| x.failures),
+                checked_at: known.map(|x| x.checked_at),
+                in_use: in_use.contains(host),
+                shared: configured.contains(host),
+                tags: bindings.tags_of(host),
+            }
+        })
+        .collect();
+    (state.live, relays)
+}
+
+/// relay_pool against the relay state as last checked
+pub fn current_relay_pool(tags: &[String]) -> RelayPool {
+    let bindings = relay_bindings();
+    if bindings.is_empty() {
+        return RelayPool::Shared;
+    }
+    match RELAY_STATE.read() {
+        Ok(state) => relay_pool(
+            &bindings,
+            tags,
+            &state.relays,
+            chrono::Utc::now().timestamp(),
+            relay_health_fresh_secs(),
+        ),
+        Err(_) => RelayPool::Shared,
+    }
+}
+
+/// Each binding with what a session to a peer with its tag gets now
+pub fn relay_bindings_status() -> Vec<RelayBindingStatus> {
+    let shared = RELAY_HEALTH.read().map(|x| x.1.clone()).unwrap_or_default();
+    relay_bindings()
+        .bindings
+        .iter()
+        .map(|(tag, relays)| {
+            let pool = current_relay_pool(&[tag.clone()]);
+            RelayBindingStatus {
+                tag: tag.clone(),
+                relays: relays.clone(),
+                pool: pool.kind(),
+                in_use: match pool {
+                    RelayPool::Bound(_, relays) => relays,
+                    RelayPool::Fallback(_) => shared.clone(),
+                    _ => RelayServers::new(),
+                },
+            }
+        })
+        .collect()
+}
+
+/// Pool of a relay a client asked for by name: the first tag it is bound to, or shared
+fn relay_pool_of(relay: &str) -> String {
+    match relay_bindings().tags_of(relay).first() {
+        Some(tag) => format!("bound:{}", tag),
+        None => "shared".to_owned(),
+    }
+}
+
+/// Relay decisions seen for one target peer.
+#[derive(Clone, Debug, Default)]
+pub struct PeerRelayStats {
+    pub last_reason: Option<RelayReason>,
+    pub last_from: String,
+    pub last_relay: String,
+    pub last_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub counts: HashMap<RelayReason, usize>,
+}
+
+lazy_static::lazy_static! {
+    static ref RELAY_REASONS: HashMap<RelayReason, AtomicUsize> =
+        RelayReason::ALL.iter().map(|r| (*r, AtomicUsize::new(0))).collect();
+    static ref PEER_RELAY_STATS: Mutex<HashMap<String, PeerRelayStats>> = Default::default();
+    // (initiator ip, target) -> when the punch hole was forwarded
+    static ref PUNCHES_FORWARDED: std::sync::Mutex<HashMap<(IpAddr, String), Instant>> =
+        Default::default();
+}
+
+/// A relay request following a forwarded punch hole within this many seconds
+/// (PUNCH_TIMEOUT_WINDOW_SECS) means the punch did not get through
+const PUNCH_TIMEOUT_WINDOW_SECS: u64 = 30;
+
+/// Remember a punch hole forwarded from `from` to `target`
+fn punch_forwarded(from: SocketAddr, target: &str) {
+    let window = Duration::from_secs(env_u64(
+        "PUNCH_TIMEOUT_WINDOW_SECS",
+        PUNCH_TIMEOUT_WINDOW_SECS,
+    ));
+    let now = Instant::now();
+    if let Ok(mut lock) = PUNCHES_FORWARDED.lock() {
+        if lock.len() >= RELAY_STATS_MAX_PEERS {
+            lock.retain(|_, at| now.duration_since(*at) < window);
+        }
+        if lock.len() < RELAY_STATS_MAX_PEERS {
+            lock.insert((try_into_v4(from).ip(), target.to_owned()), now);
+        }
+    }
+}
+
+/// Whether a punch hole from `from` to `target` was forwarded within the
+/// window; the entry is used up
+fn punch_timed_out(from: SocketAddr, target: &str) -> bool {
+    let window = Duration::from_secs(env_u64(
+        "PUNCH_TIMEOUT_WINDOW_SECS",
+        PUNCH_TIMEOUT_WINDOW_SECS,
+    ));
+    let key = (try_into_v4(from).ip(), target.to_owned());
+    match PUNCHES_FORWARDED.lock() {
+        Ok(mut lock) => lock.remove(&key).map_or(false, |at| at.elapsed() < window),
+        Err(_) => false,
+    }
+}
+
+async fn record_relay_reason(
+    reason: RelayReason,
+    id: &str,
+    from: SocketAddr,
+    relay: &str,
+    pool: &str,
+) {
+    if let Some(n) = RELAY_REASONS.get(&reason) {
+        n.fetch_add(1, Ordering::SeqCst);
+    }
+    RELAY_DECISIONS.inc(reason.as_str());
+    log::info!(
+        "Relay {:?} -> {} reason={} relay={} pool={}",
+        from,
+        id,
+        reason.as_str(),
+        relay,
+        pool
+    );
+    let mut lock = PEER_RELAY_STATS.lock().await;
+    if lock.len() >= RELAY_STATS_MAX_PEERS && !lock.contains_key(id) {
+        let day_ago = chrono::Utc::now() - chrono::Duration::seconds(DAY_SECONDS as _);
+        lock.retain(|_, v| v.last_at.map(|t| t > day_ago).unwrap_or(false));
+        if lock.len() >= RELAY_STATS_MAX_PEERS {
+            return;
+        }
+    }
+    let stats = lock.entry(id.to_owned()).or_default();
+    stats.last_reason = Some(reason);
+    stats.last_from = from.to_string();
+    stats.last_relay = relay.to_owned();
+    stats.last_at = Some(chrono::Utc::now());
+    *stats.counts.entry(reason).or_default() += 1;
+}
+
+/// Count a brokered punch hole attempt and log both sides, allowed or refused,
+/// with the relays its relay was picked from (`-` when none was).
+/// The initiator is `?` when neither its connection nor its address identify it.
+fn punch_hole_attempt(
+    result: &'static str,
+    initiator: Option<&str>,
+    target: &str,
+    from: SocketAddr,
+    relays: &[String],
+) {
+    PUNCH_HOLES.inc(result);
+    log::info!(
+        "Punch hole {} -> {} from {} result={} relays={}",
+        initiator.unwrap_or("?"),
+        target,
+        from,
+        result,
+        if relays.is_empty() {
+            "-".to_owned()
+        } else {
+            relays.join(",")
+        }
+    );
+}
+
+/// "Try again shortly" answers per punch hole target within one freshness window
+/// (PUNCH_DB_RETRIES)
+const PUNCH_DB_RETRIES: u64 = 3;
+
+lazy_static::lazy_static! {
+    // target -> (first "try again" answer, answers since)
+    static ref PUNCH_DB_RETRY: std::sync::Mutex<HashMap<String, (Instant, u64)>> =
+        Default::default();
+}
+
+/// Whether a punch hole target that has not registered here (evicted from memory,
+/// or heartbeating to another instance on the same database) was online in the
+/// database recently enough, within PUNCH_DB_FRESH_SECS (default the peer
+/// timeout), for the initiator to be told to try again instead of offline. The
+/// answers per target are capped within the window, so a peer that really went
+/// away turns offline after a few tries. Without `count` the answer is not
+/// counted against that cap.
+fn punch_retry_fresh(
+    id: &str,
+    row: Option<&crate::database::PeerStatusRow>,
+    clock: &dyn Clock,
+    count: bool,
+) -> bool {
+    let window = env_u64("PUNCH_DB_FRESH_SECS", peer_timeout_secs());
+    let fresh = row.map_or(false, |row| {
+        let age = row
+            .last_online
+            .as_deref()
+            .and_then(parse_last_online)
+            .map(|at| (chrono::Utc::now().naive_utc() - at).num_seconds());
+        row.status == Some(1) && age.map_or(false, |age| age <= window as i64)
+    });
+    if !fresh {
+        return false;
+    }
+    let mut lock = match PUNCH_DB_RETRY.lock() {
+        Ok(lock) => lock,
+        Err(_) => return false,
+    };
+    let now = clock.now_instant();
+    lock.retain(|_, (since, _)| now.duration_since(*since).as_secs() < window);
+    let limit = env_u64("PUNCH_DB_RETRIES", PUNCH_DB_RETRIES);
+    if !count {
+        return lock.get(id).map_or(0, |(_, answers)| *answers) < limit;
+    }
+    let (_, answers) = lock.entry(id.to_owned()).or_insert((now, 0));
+    *answers += 1;
+    *answers <= limit
+}
+
+/// Queued udp messages skipped as repeats since start
+pub fn udp_coalesced_count() -> usize {
+    UDP_QUEUED_SENDS
+        .values
+        .iter()
+        .find(|(v, _)| *v == "coalesced")
+        .map(|(_, n)| n.load(Ordering::Relaxed))
+        .unwrap_or_default()
+}
+
+/// Aggregate count of relay decisions per reason since start.
+pub fn relay_reason_counts() -> Vec<(RelayReason, usize)> {
+    RelayReason::ALL
+        .iter()
+        .map(|r| {
+            (
+                *r,
+                RELAY_REASONS
+                    .get(r)
+                    .map(|n| n.load(Ordering::SeqCst))
+                    .unwrap_or_default(),
+            )
+        })
+        .collect()
+}
+
+pub async fn peer_relay_stats(id: &str) -> Option<PeerRelayStats> {
+    PEER_RELAY_STATS.lock().await.get(id).cloned()
+}
+
+/// Why the server closes a peer's live rendezvous connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DisconnectReason {
+    IdChanged,
+    Banned,
+    Maintenance,
+    /// Over TCP_CONN_MAX_BYTES
+    ByteLimit,
+    /// Soft-deleted through the API
+    Deleted,
+    /// Its user was disabled through the API
+    UserDisabled,
+}
+
+impl DisconnectReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DisconnectReason::IdChanged => "id_changed",
+            DisconnectReason::Banned => "banned",
+            DisconnectReason::Maintenance => "maintenance",
+            DisconnectReason::ByteLimit => "byte_limit",
+            DisconnectReason::Deleted => "deleted",
+            DisconnectReason::UserDisabled => "user_disabled",
+        }
+    }
+
+    /// Websocket close code (private-use range)
+    pub fn code(&self) -> u16 {
+        match self {
+            DisconnectReason::IdChanged => 4001,
+            DisconnectReason::Banned => 4002,
+            DisconnectReason::Maintenance => 4003,
+            DisconnectReason::ByteLimit => 4004,
+            DisconnectReason::Deleted => 4005,
+            DisconnectReason::UserDisabled => 4006,
+        }
+    }
+}
+
+type LiveConnCommand = (DisconnectReason, String);
+
+lazy_static::lazy_static! {
+    // peers registered over a websocket/tcp connection that is still open
+    static ref LIVE_CONNS: std::sync::Mutex<HashMap<String, mpsc::UnboundedSender<LiveConnCommand>>> =
+        Default::default();
+}
+
+/// Close the live websocket/tcp rendezvous connection of `id`, telling the client why.
+/// Returns false when the peer has none (udp-only peers), which callers can ignore.
+pub fn disconnect_peer(id: &str, reason: DisconnectReason, detail: &str) -> bool {
+    let sent = LIVE_CONNS
+        .lock()
+        .ok()
+        .and_then(|lock| {
+            lock.get(id)
+                .map(|tx| tx.send((reason, detail.to_owned())).is_ok())
+        })
+        .unwrap_or(false);
+    if sent {
+        log::info!(
+            "Disconnecting live connection of {} ({})",
+            id,
+            reason.as_str()
+        );
+    }
+    sent
+}
+
+/// Bytes through a TCP/websocket rendezvous connection, shared by its read loop
+/// and whichever task holds its sink; also added to the totals kept until restart.
+/// Frame payloads are counted, without length prefixes and websocket headers.
+#[derive(Debug, Default)]
+pub struct ConnMeter {
+    read: AtomicU64,
+    written: AtomicU64,
+}
+
+static NET_READ: AtomicU64 = AtomicU64::new(0);
+static NET_WRITTEN: AtomicU64 = AtomicU64::new(0);
+static NET_LIMIT_CLOSES: AtomicU64 = AtomicU64::new(0);
+static NEXT_METERED_CONN: AtomicU64 = AtomicU64::new(0);
+
+/// Bytes after which a TCP/websocket connection is closed (TCP_CONN_MAX_BYTES), 0 for no limit
+const TCP_CONN_MAX_BYTES: u64 = 0;
+/// Seconds between samples of the udp listener's kernel drop counter (UDP_DROPS_SAMPLE_SECS)
+const UDP_DROPS_SAMPLE_SECS: u64 = 15;
+/// SO_RCVBUF reads back doubled on Linux, the kernel's bookkeeping overhead included
+const RCVBUF_READBACK_FACTOR: usize = if cfg!(target_os = "linux") { 2 } else { 1 };
+
+impl ConnMeter {
+    pub fn read(&self) -> u64 {
+        self.read.load(Ordering::Relaxed)
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written.load(Ordering::Relaxed)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.read() + self.written()
+    }
+
+    fn add_read(&self, n: usize) {
+        self.read.fetch_add(n as u64, Ordering::Relaxed);
+        NET_READ.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    fn add_written(&self, n: usize) {
+        self.written.fetch_add(n as u64, Ordering::Relaxed);
+        NET_WRITTEN.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+/// Size of a frame going through a Metered sink or stream
+pub trait WireLen {
+    fn wire_len(&self) -> usize;
+}
+
+impl WireLen for Bytes {
+    fn wire_len(&self) -> usize {
+        self.len()
+    }
+}
+
+impl WireLen for BytesMut {
+    fn wire_len(&self) -> usize {
+        self.len()
+    }
+}
+
+impl WireLen for tungstenite::Message {
+    fn wire_len(&self) -> usize {
+        self.len()
+    }
+}
+
+/// A sink or stream counting the frames through it into a ConnMeter
+pub struct Metered<S> {
+    inner: S,
+    meter: Arc<ConnMeter>,
+}
+
+impl<S> Metered<S> {
+    pub fn new(inner: S, meter: Arc<ConnMeter>) -> Self {
+        Self { inner, meter }
+    }
+}
+
+impl<S: FuturesSink<I> + Unpin, I: WireLen> FuturesSink<I> for Metered<S> {
+    type Error = S::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: I) -> Result<(), S::Error> {
+        let n = item.wire_len();
+        Pin::new(&mut self.inner).start_send(item)?;
+        self.meter.add_written(n);
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+impl<S: Stream<Item = Result<T, E>> + Unpin, T: WireLen, E> Stream for Metered<S> {
+    type Item = Result<T, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let res = Pin::new(&mut self.inner).poll_next(cx);
+        if let Poll::Ready(Some(Ok(item))) = &res {
+            self.meter.add_read(item.wire_len());
+        }
+        res
+    }
+}
+
+struct MeteredConn {
+    addr: SocketAddr,
+    peer: Option<String>,
+    since: Instant,
+    meter: Arc<ConnMeter>,
+}
+
+lazy_static::lazy_static! {
+    // open TCP/websocket connections, for the top consumers in network_stats
+    static ref METERED_CONNS: std::sync::Mutex<HashMap<u64, MeteredConn>> = Default::default();
+    // receive buffer and kernel counters of the current udp listener
+    static ref UDP_BUFFER: std::sync::Mutex<UdpBuffer> = Default::default();
+}
+
+/// An open connection's entry in METERED_CONNS, removed when dropped
+struct ConnTrack(u64);
+
+impl ConnTrack {
+    fn new(addr: SocketAddr) -> (Self, Arc<ConnMeter>) {
+        let n = NEXT_METERED_CONN.fetch_add(1, Ordering::Relaxed);
+        let meter = Arc::new(ConnMeter::default());
+        if let Ok(mut lock) = METERED_CONNS.lock() {
+            lock.insert(
+                n,
+                MeteredConn {
+                    addr: try_into_v4(addr),
+                    peer: None,
+                    since: Instant::now(),
+                    meter: meter.clone(),
+                },
+            );
+        }
+        (Self(n), meter)
+    }
+
+    fn has_peer(&self) -> bool {
+        METERED_CONNS.lock().map_or(false, |lock| {
+            lock.get(&self.0).map_or(false, |x| x.peer.is_some())
+        })
+    }
+
+    fn set_peer(&self, id: &str) {
+        if let Ok(mut lock) = METERED_CONNS.lock() {
+            if let Some(x) = lock.get_mut(&self.0) {
+                if x.peer.as_deref() != Some(id) {
+                    x.peer = Some(id.to_owned());
+                }
+            }
+        }
+    }
+}
+
+impl Drop for ConnTrack {
+    fn drop(&mut self) {
+        if let Ok(mut lock) = METERED_CONNS.lock() {
+            lock.remove(&self.0);
+        }
+    }
+}
+
+/// Whether the connection has gone over TCP_CONN_MAX_BYTES; logged, as it is closed then
+fn over_byte_limit(meter: &ConnMeter, addr: SocketAddr, peer: &Option<String>) -> bool {
+    let limit = env_u64("TCP_CONN_MAX_BYTES", TCP_CONN_MAX_BYTES);
+    if limit == 0 || meter.total() <= limit {
+        return false;
+    }
+    NET_LIMIT_CLOSES.fetch_add(1, Ordering::Relaxed);
+    log::warn!(
+        "Closing tcp connection from {} ({}): {} bytes read, {} written, over the limit of {}",
+        addr,
+        peer.as_deref().unwrap_or("no peer id"),
+        meter.read(),
+        meter.written(),
+        limit
+    );
+    true
+}
+
+/// An open connection by the bytes it carried
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnUsage {
+    /// The peer id when known, else the address
+    pub who: String,
+    pub peer: Option<String>,
+    pub addr: String,
+    pub read_bytes: u64,
+    pub written_bytes: u64,
+    pub connected_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NetworkStats {
+    /// Totals over all TCP/websocket connections since start
+    pub read_bytes: u64,
+    pub written_bytes: u64,
+    pub open_connections: usize,
+    /// TCP_CONN_MAX_BYTES, 0 for no limit
+    pub conn_byte_limit: u64,
+    pub closed_over_limit: u64,
+    /// The open connections with the most bytes, most first
+    pub top: Vec<ConnUsage>,
+    /// The udp listener's receive buffer and kernel drops
+    pub udp: UdpBuffer,
+}
+
+/// Receive buffer of a udp socket, as requested with --rmem and as granted
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UdpBuffer {
+    /// --rmem, 0 for the system default
+    pub requested_bytes: usize,
+    /// SO_RCVBUF read back after binding (twice the requested size on Linux
+    /// when granted in full)
+    pub effective_bytes: Option<usize>,
+    /// The kernel granted less than requested (net.core.rmem_max on Linux)
+    pub clamped: bool,
+    /// Datagrams the kernel dropped on this socket, from /proc/net/udp (Linux only)
+    pub drops: Option<u64>,
+    /// Bytes waiting in the socket's receive queue
+    pub queued_bytes: Option<u64>,
+    #[serde(skip)]
+    inode: Option<u64>,
+}
+
+pub fn network_stats(top: usize) -> NetworkStats {
+    let mut conns: Vec<ConnUsage> = match METERED_CONNS.lock() {
+        Ok(lock) => lock
+            .values()
+            .map(|x| ConnUsage {
+                who: x.peer.clone().unwrap_or_else(|| x.addr.to_string()),
+                peer: x.peer.clone(),
+                addr: x.addr.to_string(),
+                read_bytes: x.meter.read(),
+                written_bytes: x.meter.written(),
+                connected_secs: x.since.elapsed().as_secs(),
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    let open_connections = conns.len();
+    conns.sort_by_key(|x| std::cmp::Reverse(x.read_bytes + x.written_bytes));
+    conns.truncate(top);
+    NetworkStats {
+        read_bytes: NET_READ.load(Ordering::Relaxed),
+        written_bytes: NET_WRITTEN.load(Ordering::Relaxed),
+        open_connections,
+        conn_byte_limit: env_u64("TCP_CONN_MAX_BYTES", TCP_CONN_MAX_BYTES),
+        closed_over_limit: NET_LIMIT_CLOSES.load(Ordering::Relaxed),
+        top: conns,
+        udp: {
+            sample_udp_drops();
+            UDP_BUFFER.lock().map(|x| x.clone()).unwrap_or_default()
+        },
+    }
+}
+
+#[cfg(unix)]
+fn udp_fd(socket: &FramedSocket) -> Option<std::os::unix::io::RawFd> {
+    use std::os::unix::io::AsRawFd;
+    match socket {
+        FramedSocket::Direct(framed) => Some(framed.get_ref().as_raw_fd()),
+        _ => None,
+    }
+}
+
+/// SO_RCVBUF and inode of a bound udp socket
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)]
+fn udp_socket_info(socket: &FramedSocket) -> (Option<usize>, Option<u64>) {
+    let fd = match udp_fd(socket) {
+        Some(fd) => fd,
+        None => return (None, None),
+    };
+    let mut size: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let rcvbuf = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_RCVBUF,
+            &mut size as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    } == 0;
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    let inode = unsafe { libc::fstat(fd, &mut stat) } == 0;
+    (
+        rcvbuf.then_some(size as usize),
+        inode.then_some(stat.st_ino as u64),
+    )
+}
+
+#[cfg(not(unix))]
+fn udp_socket_info(_: &FramedSocket) -> (Option<usize>, Option<u64>) {
+    (None, None)
+}
+
+/// Kernel drops and queued bytes of the udp socket with `inode`
+#[cfg(target_os = "linux")]
+fn kernel_udp_counters(inode: u64) -> Option<(u64, u64)> {
+    for table in ["/proc/net/udp6", "/proc/net/udp"] {
+        let table = match std::fs::read_to_string(table) {
+            Ok(table) => table,
+            Err(_) => continue,
+        };
+        // sl local rem st tx_queue:rx_queue tr:when retrnsmt uid timeout inode ref pointer drops
+        for fields in table
+            .lines()
+            .skip(1)
+            .map(|x| x.split_whitespace().collect::<Vec<_>>())
+        {
+            if fields.len() < 13 || fields[9].parse::<u64>().ok() != Some(inode) {
+                continue;
+            }
+            let (_, rx) = fields[4].split_once(':')?;
+            return Some((fields[12].parse().ok()?, u64::from_str_radix(rx, 16).ok()?));
+        }
+    }
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+fn kernel_udp_counters(_: u64) -> Option<(u64, u64)> {
+    None
+}
+
+/// Read back the receive buffer of a bound udp socket and its kernel counters
+pub fn inspect_udp_socket(socket: &FramedSocket, requested: usize) -> UdpBuffer {
+    let (effective, inode) = udp_socket_info(socket);
+    let counters = inode.and_then(kernel_udp_counters);
+    UdpBuffer {
+        requested_bytes: requested,
+        effective_bytes: effective,
+        clamped: matches!(effective, Some(x) if x < requested * RCVBUF_READBACK_FACTOR),
+        drops: counters.map(|x| x.0),
+        queued_bytes: counters.map(|x| x.1),
+        inode,
+    }
+}
+
+/// Record the buffer of a new udp listener, warning when --rmem was clamped
+fn note_udp_buffer(socket: &FramedSocket, requested: usize) {
+    let buffer = inspect_udp_socket(socket, requested);
+    match buffer.effective_bytes {
+        Some(effective) if buffer.clamped => log::warn!(
+            "UDP receive buffer clamped by the kernel: --rmem={} requested, {} granted \
+             ({} usable). Bursts beyond it are dropped before hbbs sees them; raise the \
+             limit with `sysctl -w net.core.rmem_max={}`",
+            requested,
+            effective,
+            effective / RCVBUF_READBACK_FACTOR,
+            requested
+        ),
+        Some(effective) => log::info!("UDP receive buffer: {} bytes", effective),
+        None => {}
+    }
+    if let Ok(mut lock) = UDP_BUFFER.lock() {
+        *lock = buffer;
+    }
+}
+
+/// Count the drops of the udp listener since the last sample; a new listener
+/// starts again from its own counter
+fn sample_udp_drops() -> u64 {
+    let mut lock = match UDP_BUFFER.lock() {
+        Ok(lock) => lock,
+        Err(_) => return 0,
+    };
+    let (drops, queued) = match lock.inode.and_then(kernel_udp_counters) {
+        Some(counters) => counters,
+        None => return 0,
+    };
+    let new = drops.saturating_sub(lock.drops.unwrap_or(0));
+    lock.drops = Some(drops);
+    lock.queued_bytes = Some(queued);
+    UDP_KERNEL_DROPS.add("rendezvous", new as usize);
+    new
+}
+
+async fn udp_drop_loop() {
+    let every = env_u64("UDP_DROPS_SAMPLE_SECS", UDP_DROPS_SAMPLE_SECS).max(1);
+    let mut interval = tokio::time::interval(Duration::from_secs(every));
+    loop {
+        interval.tick().await;
+        let dropped = sample_udp_drops();
+        if dropped > 0 {
+            log::warn!(
+                "The kernel dropped {} udp datagrams in the last {}s, receive buffer {:?} bytes; \
+                 see --rmem",
+                dropped,
+                every,
+                UDP_BUFFER.lock().ok().and_then(|x| x.effective_bytes)
+            );
+        }
+    }
+}
+
+/// Entries and approximate bytes per entry of an in-memory structure, None while
+/// its lock is busy (the last count is reported then)
+pub type MemorySourceFn = Box<dyn Fn() -> Option<(usize, usize)> + Send + Sync>;
+
+lazy_static::lazy_static! {
+    static ref MEMORY_SOURCES: std::sync::RwLock<Vec<(&'static str, MemorySourceFn)>> =
+        Default::default();
+    static ref MEMORY_LAST: std::sync::Mutex<HashMap<&'static str, usize>> = Default::default();
+    static ref MEMORY_OVER_CAP: std::sync::Mutex<std::collections::HashSet<&'static str>> =
+        Default::default();
+    static ref TCP_PUNCH_SHARE: std::sync::Mutex<Option<Arc<Mutex<HashMap<SocketAddr, Sink>>>>> =
+        Default::default();
+}
+
+/// Register a structure kept outside this crate (replacing an earlier one of the
+/// same name)
+pub fn register_memory_source(name: &'static str, source: MemorySourceFn) {
+    if let Ok(mut sources) = MEMORY_SOURCES.write() {
+        sources.retain(|(x, _)| *x != name);
+        sources.push((name, source));
+    }
+}
+
+/// Bytes of one hash map entry of these types owning `heap` more bytes, with the
+/// control byte and the 7/8 load factor of the table
+pub fn map_entry_bytes<K, V>(heap: usize) -> usize {
+    (std::mem::size_of::<(K, V)>() + 1) * 8 / 7 + heap
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MemoryUsage {
+    pub name: &'static str,
+    pub entries: usize,
+    /// entries times a static per-entry estimate, nothing is traversed
+    pub approx_bytes: usize,
+    /// From MEMORY_SOFT_CAPS, in entries
+    pub soft_cap: Option<usize>,
+    pub over_cap: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MemoryStats {
+    /// Resident set size of the process, None where it cannot be read
+    pub rss_bytes: Option<u64>,
+    /// Sum of approx_bytes; the rest of rss is code, buffers and allocator slack
+    pub accounted_bytes: usize,
+    pub structures: Vec<MemoryUsage>,
+}
+
+/// MEMORY_SOFT_CAPS, e.g. `peer_map=200000,last_packet=100000` (entries)
+fn memory_soft_caps() -> HashMap<String, usize> {
+    std::env::var("MEMORY_SOFT_CAPS")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|x| {
+            let (name, cap) = x.split_once('=')?;
+            Some((name.trim().to_owned(), cap.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+pub fn process_rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kb = status.lines().find_map(|x| x.strip_prefix("VmRSS:"))?;
+    let kb: u64 = kb.trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(kb * 1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn process_rss_bytes() -> Option<u64> {
+    None
+}
+
+/// Counts and approximate sizes of the long-lived maps, rings and queues
+pub fn memory_stats() -> MemoryStats {
+    let tcp_punch = TCP_PUNCH_SHARE.lock().ok().and_then(|x| x.clone());
+    let mut sources = crate::peer::memory_sources();
+    sources.extend([
+        (
+            "tcp_punch",
+            match &tcp_punch {
+                Some(x) => x.try_lock().ok().map(|x| x.len()),
+                None => Some(0),
+            },
+            // a sink with its framing write buffer
+            map_entry_bytes::<SocketAddr, Sink>(8 * 1024),
+        ),
+        (
+            "live_conns",
+            LIVE_CONNS.lock().ok().map(|x| x.len()),
+            map_entry_bytes::<String, mpsc::UnboundedSender<LiveConnCommand>>(16 + 64),
+        ),
+        (
+            "metered_conns",
+            METERED_CONNS.lock().ok().map(|x| x.len()),
+            map_entry_bytes::<u64, MeteredConn>(32),
+        ),
+        (
+            "peer_relay_stats",
+            PEER_RELAY_STATS.try_lock().ok().map(|x| x.len()),
+            map_entry_bytes::<String, PeerRelayStats>(16 + 64),
+        ),
+        (
+            "signed_id_pk",
+            SIGNED_ID_PK.lock().ok().map(|x| x.len()),
+            map_entry_bytes::<String, (Bytes, Instant, Bytes)>(16 + 32 + 100),
+        ),
+        (
+            "notice_ids",
+            NOTICE_IDS.read().ok().map(|x| x.len()),
+            map_entry_bytes::<String, ()>(16),
+        ),
+        (
+            "recent_errors",
+            RECENT_ERRORS.lock().ok().map(|x| x.len()),
+            std::mem::size_of::<RecentError>() + 16 + 40 + 64,
+        ),
+    ]);
+    if let Ok(registered) = MEMORY_SOURCES.read() {
+        for (name, source) in registered.iter() {
+            let (entries, each) = match source() {
+                Some((entries, each)) => (Some(entries), each),
+                None => (None, 0),
+            };
+            sources.push((name, entries, each));
+        }
+    }
+    let caps = memory_soft_caps();
+    let mut last = MEMORY_LAST.lock().ok();
+    let structures: Vec<MemoryUsage> = sources
+        .into_iter()
+        .map(|(name, entries, each)| {
+            let entries = match (entries, last.as_mut()) {
+                (Some(n), Some(last)) => *last.entry(name).or_default().insert(n),
+                (Some(n), None) => n,
+                (None, last) => last.and_then(|x| x.get(name).copied()).unwrap_or_default(),
+            };
+            let soft_cap = caps.get(name).copied();
+            MemoryUsage {
+                name,
+                entries,
+                approx_bytes: entries * each,
+                soft_cap,
+                over_cap: soft_cap.map_or(false, |cap| entries > cap),
+            }
+        })
+        .collect();
+    MemoryStats {
+        rss_bytes: process_rss_bytes(),
+        accounted_bytes: structures.iter().map(|x| x.approx_bytes).sum(),
+        structures,
+    }
+}
+
+/// Warn once when a structure goes over its soft cap, and when it is back under
+fn check_memory_caps() {
+    let stats = memory_stats();
+    let mut over = match MEMORY_OVER_CAP.lock() {
+        Ok(x) => x,
+        Err(_) => return,
+    };
+    for x in stats.structures {
+        match (x.over_cap, over.contains(x.name)) {
+            (true, false) => {
+                log::warn!(
+                    "Memory: {} holds {} entries (~{} KiB), over its soft cap of {}",
+                    x.name,
+                    x.entries,
+                    x.approx_bytes / 1024,
+                    x.soft_cap.unwrap_or_default()
+                );
+                over.insert(x.name);
+            }
+            (false, true) => {
+                log::info!(
+                    "Memory: {} is back under its soft cap ({} entries)",
+                    x.name,
+                    x.entries
+                );
+                over.remove(x.name);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Unknown field of RegisterPeerResponse carrying a PeerNotice as JSON, once per
+/// notice; stock clients skip it, ours update their config from it
+pub const NOTICE_FIELD: u32 = 100;
+
+static NOTICE_GEN: AtomicU64 = AtomicU64::new(0);
+
+lazy_static::lazy_static! {
+    // ids with notices to deliver or acknowledge; registrations of everyone else
+    // never touch the database for them
+    static ref NOTICE_IDS: std::sync::RwLock<std::collections::HashSet<String>> =
+        Default::default();
+}
+
+/// After queueing a notice: have registrations of these ids look for it
+pub fn notice_queued(ids: &[&str]) {
+    NOTICE_GEN.fetch_add(1, Ordering::SeqCst);
+    if let Ok(mut lock) = NOTICE_IDS.write() {
+        lock.extend(ids.iter().map(|x| x.to_string()));
+    }
+}
+
+fn has_notices(id: &str) -> bool {
+    NOTICE_IDS.read().map_or(false, |lock| lock.contains(id))
+}
+
+// serial of the configuration handed to clients, raised by a loopback ConfigureUpdate
+static SERIAL: AtomicI32 = AtomicI32::new(0);
+
+/// The configuration serial in use, for asking peer servers for a newer one
+pub fn current_serial() -> i32 {
+    SERIAL.load(Ordering::Relaxed)
+}
+
+/// Stragglers listed by serial_adoption, the rest are only counted
+const SERIAL_STRAGGLERS: usize = 100;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SerialBump {
+    pub old: i32,
+    pub new: i32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SerialStraggler {
+    pub id: String,
+    pub serial: i32,
+    pub online: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SerialAdoption {
+    pub serial: i32,
+    /// Peers that registered since start, by the serial of their last registration
+    pub peers_by_serial: std::collections::BTreeMap<i32, usize>,
+    /// Peers still on an older serial, online ones first, at most SERIAL_STRAGGLERS
+    pub stragglers: Vec<SerialStraggler>,
+    pub stragglers_total: usize,
+}
+
+/// How far `serial` has spread among peers, from (id, serial, online) of each
+pub fn serial_adoption(serial: i32, peers: Vec<(String, i32, bool)>) -> SerialAdoption {
+    let mut peers_by_serial = std::collections::BTreeMap::new();
+    let mut stragglers = Vec::new();
+    for (id, peer_serial, online) in peers {
+        *peers_by_serial.entry(peer_serial).or_insert(0) += 1;
+        if peer_serial < serial {
+            stragglers.push(SerialStraggler {
+                id,
+                serial: peer_serial,
+                online,
+            });
+        }
+    }
+    stragglers.sort_by(|a, b| b.online.cmp(&a.online).then_with(|| a.id.cmp(&b.id)));
+    let stragglers_total = stragglers.len();
+    stragglers.truncate(SERIAL_STRAGGLERS);
+    SerialAdoption {
+        serial,
+        peers_by_serial,
+        stragglers,
+        stragglers_total,
+    }
+}
+
+/// Raise the serial pushed to clients to `serial`, or by one; `by` goes to the
+/// audit log. Returns None when the rendezvous server is not running.
+pub async fn request_serial(serial: Option<i32>, by: String) -> Option<Result<SerialBump, String>> {
+    let (tx, rx) = oneshot::channel();
+    let sent = match SERIAL_REQUESTS.lock() {
+        Ok(lock) => lock
+            .as_ref()
+            .map(|x| x.send(Data::Serial(serial, by, tx)).is_ok()),
+        Err(_) => None,
+    };
+    if sent != Some(true) {
+        return None;
+    }
+    rx.await.ok()
+}
+
+/// A punch hole request to run through the decision without sending anything
+#[derive(Debug, Clone)]
+pub struct PunchSimulation {
+    pub from_id: String,
+    pub to_id: String,
+    /// where the initiator asks from; None for where it last registered
+    pub from_addr: Option<SocketAddr>,
+    pub transport: Transport,
+}
+
+/// One check of the punch hole decision and how it came out
+#[derive(Debug, Clone, Serialize)]
+pub struct PunchStep {
+    pub step: &'static str,
+    pub outcome: String,
+}
+
+/// What a real punch hole request would have been answered with
+#[derive(Debug, Clone, Serialize)]
+pub struct PunchTrace {
+    pub from_id: String,
+    pub to_id: String,
+    pub from_addr: String,
+    pub transport: &'static str,
+    /// as counted in hbbs_punch_hole_attempts_total
+    pub result: &'static str,
+    /// punch_hole or fetch_local_addr to the target, punch_hole_response to the initiator
+    pub response: &'static str,
+    /// the target's address the message would go to
+    pub sent_to: Option<String>,
+    pub failure: Option<String>,
+    pub relay_server: Option<String>,
+    pub relay_reason: Option<&'static str>,
+    /// the relays the relay server was picked from, at most RELAY_ADVERTISE_MAX
+    pub relay_candidates: Vec<String>,
+    pub steps: Vec<PunchStep>,
+}
+
+/// Outcome of decide_punch_hole; the real request applies the side effects
+struct PunchDecision {
+    target: String,
+    result: &'static str,
+    msg: RendezvousMessage,
+    to: Option<SocketAddr>,
+    // what record_error gets: reason, id and detail
+    error: Option<(ErrorReason, String, String)>,
+    // detail of an access_denied audit entry
+    audit: Option<String>,
+    relay: Option<(RelayReason, String)>,
+    // the fastest relays of the pool the relay was picked from
+    relay_candidates: RelayServers,
+    // relays the target's tags allow
+    pool: RelayPool,
+    steps: Vec<PunchStep>,
+}
+
+/// Run a punch hole request through the same checks as a real one, against the
+/// live state, without sending, counting or logging it. Returns None when the
+/// rendezvous server is not running.
+pub async fn simulate_punch(sim: PunchSimulation) -> Option<Result<PunchTrace, String>> {
+    let (tx, rx) = oneshot::channel();
+    let sent = match SERIAL_REQUESTS.lock() {
+        Ok(lock) => lock
+            .as_ref()
+            .map(|x| x.send(Data::SimulatePunch(sim, tx)).is_ok()),
+        Err(_) => None,
+    };
+    if sent != Some(true) {
+        return None;
+    }
+    rx.await.ok()
+}
+
+/// Kind of the PeerNotice a broadcast goes out as; seq is 0, clients do not
+/// acknowledge it
+pub const BROADCAST_NOTICE: &str = "broadcast";
+
+static BROADCAST_GEN: AtomicU64 = AtomicU64::new(0);
+// latest expiry among the cached broadcasts; i64::MAX until they are loaded, so
+// registrations after it skip them without a lock
+static BROADCASTS_UNTIL: AtomicI64 = AtomicI64::new(i64::MAX);
+
+lazy_static::lazy_static! {
+    static ref BROADCASTS: std::sync::RwLock<Option<Arc<Vec<ActiveBroadcast>>>> =
+        Default::default();
+}
+
+/// A broadcast not expired yet, with its target expanded to ids
+struct ActiveBroadcast {
+    broadcast: Broadcast,
+    /// None for all peers
+    ids: Option<std::collections::HashSet<String>>,
+    delivered: std::sync::Mutex<std::collections::HashSet<String>>,
+}
+
+/// After a new broadcast or a change of a peer's tags: reload the broadcasts on
+/// the next registration
+pub fn invalidate_broadcasts() {
+    BROADCAST_GEN.fetch_add(1, Ordering::SeqCst);
+    BROADCASTS_UNTIL.store(i64::MAX, Ordering::SeqCst);
+    if let Ok(mut lock) = BROADCASTS.write() {
+        *lock = None;
+    }
+}
+
+async fn active_broadcasts(db: &Database) -> ResultType<Arc<Vec<ActiveBroadcast>>> {
+    if let Some(active) = BROADCASTS.read().ok().and_then(|x| x.clone()) {
+        return Ok(active);
+    }
+    let generation = BROADCAST_GEN.load(Ordering::SeqCst);
+    let loaded = db.active_broadcasts(chrono::Utc::now().timestamp()).await?;
+    let tags = if loaded.iter().any(|(x, _)| x.target == "tag") {
+        AccessPolicy::new(Vec::new(), db.tagged_peers().await?).tags
+    } else {
+        HashMap::new()
+    };
+    let active: Vec<ActiveBroadcast> = loaded
+        .into_iter()
+        .map(|(broadcast, delivered)| ActiveBroadcast {
+            ids: match broadcast.target.as_str() {
+                "all" => None,
+                "tag" => Some(
+                    tags.get(&broadcast.target_value)
+                        .cloned()
+                        .unwrap_or_default(),
+                ),
+                _ => Some(
+                    broadcast
+                        .target_value
+                        .split(',')
+                        .map(str::to_owned)
+                        .collect(),
+                ),
+            },
+            delivered: std::sync::Mutex::new(delivered.into_iter().collect()),
+            broadcast,
+        })
+        .collect();
+    let active = Arc::new(active);
+    if let Ok(mut lock) = BROADCASTS.write() {
+        // a broadcast added while loading: leave the cache empty for the next one
+        if BROADCAST_GEN.load(Ordering::SeqCst) == generation {
+            let until = active.iter().map(|x| x.broadcast.expires_at).max();
+            BROADCASTS_UNTIL.store(until.unwrap_or_default(), Ordering::SeqCst);
+            *lock = Some(active.clone());
+        }
+    }
+    Ok(active)
+}
+
+/// Attribute listing a peer's tags, comma separated, for `tag:` access patterns
+pub const TAGS_ATTRIBUTE: &str = "tags";
+
+static ACCESS_GEN: AtomicU64 = AtomicU64::new(0);
+
+lazy_static::lazy_static! {
+    // loaded on the first punch hole after a change of the rules or of a peer's tags
+    static ref ACCESS_POLICY: std::sync::RwLock<Option<Arc<AccessPolicy>>> = Default::default();
+    // id -> tags, for the relay bindings; dropped with the access policy
+    static ref PEER_TAGS: std::sync::RwLock<Option<Arc<HashMap<String, Vec<String>>>>> =
+        Default::default();
+}
+
+/// How the access rules decided a controller -> target pair
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessDecision {
+    /// There are no rules, everything is allowed
+    Open,
+    /// Allowed by the rule with this id
+    Allow(i64),
+    /// Denied by the rule with this id
+    Deny(i64),
+    /// There are rules but none matches: denied
+    Unmatched,
+}
+
+impl AccessDecision {
+    pub fn allowed(&self) -> bool {
+        matches!(self, AccessDecision::Open | AccessDecision::Allow(_))
+    }
+}
+
+impl std::fmt::Display for AccessDecision {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AccessDecision::Open => f.write_str("no rules"),
+            AccessDecision::Allow(id) => write!(f, "allowed by rule {}", id),
+            AccessDecision::Deny(id) => write!(f, "denied by rule {}", id),
+            AccessDecision::Unmatched => f.write_str("no matching rule"),
+        }
+    }
+}
+
+/// The access rules with the tags they refer to expanded to ids
+#[derive(Debug, Default)]
+pub struct AccessPolicy {
+    rules: Vec<AccessRule>,
+    tags: HashMap<String, std::collections::HashSet<String>>,
+}
+
+impl AccessPolicy {
+    /// `tagged` is (id, tags attribute) per peer
+    pub fn new(mut rules: Vec<AccessRule>, tagged: Vec<(String, String)>) -> Self {
+        rules.sort_by_key(|x| (std::cmp::Reverse(x.priority), x.action != "deny", x.id));
+        let mut tags: HashMap<String, std::collections::HashSet<String>> = HashMap::new();
+        for (id, value) in tagged {
+            for tag in value.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+                tags.entry(tag.to_owned()).or_default().insert(id.clone());
+            }
+        }
+        Self { rules, tags }
+    }
+
+    /// Whether the rules name a tag, so the tags of peers need loading
+    pub fn uses_tags(rules: &[AccessRule]) -> bool {
+        rules
+            .iter()
+            .any(|x| x.controller.starts_with("tag:") || x.target.starts_with("tag:"))
+    }
+
+    fn matches(&self, pattern: &str, id: Option<&str>) -> bool {
+        match (pattern, id) {
+            ("*", _) => true,
+            (_, None) => false,
+            (pattern, Some(id)) => match pattern.strip_prefix("tag:") {
+                Some(tag) => self.tags.get(tag).map_or(false, |ids| ids.contains(id)),
+                None => pattern == id,
+            },
+        }
+    }
+
+    /// The first rule by priority matching both sides decides; an initiator
+    /// unknown to the server only matches `*`
+    pub fn check(&self, controller: Option<&str>, target: &str) -> AccessDecision {
+        if self.rules.is_empty() {
+            return AccessDecision::Open;
+        }
+        match self.rules.iter().find(|x| {
+            self.matches(&x.controller, controller) && self.matches(&x.target, Some(target))
+        }) {
+            Some(rule) if rule.action == "deny" => AccessDecision::Deny(rule.id),
+            Some(rule) => AccessDecision::Allow(rule.id),
+            None => AccessDecision::Unmatched,
+        }
+    }
+}
+
+/// After a change of the access rules or of a peer's tags: reload them, and the
+/// tags the relay bindings go by, on the next punch hole
+pub fn invalidate_access_rules() {
+    ACCESS_GEN.fetch_add(1, Ordering::SeqCst);
+    if let Ok(mut lock) = ACCESS_POLICY.write() {
+        *lock = None;
+    }
+    if let Ok(mut lock) = PEER_TAGS.write() {
+        *lock = None;
+    }
+}
+
+async fn access_policy(db: &Database) -> ResultType<Arc<AccessPolicy>> {
+    if let Some(policy) = ACCESS_POLICY.read().ok().and_then(|x| x.clone()) {
+        return Ok(policy);
+    }
+    let generation = ACCESS_GEN.load(Ordering::SeqCst);
+    let rules = db.access_rules().await?;
+    let tagged = if AccessPolicy::uses_tags(&rules) {
+        db.tagged_peers().await?
+    } else {
+        Vec::new()
+    };
+    let policy = Arc::new(AccessPolicy::new(rules, tagged));
+    if let Ok(mut lock) = ACCESS_POLICY.write() {
+        // rules changed while loading: leave the cache empty for the next one
+        if ACCESS_GEN.load(Ordering::SeqCst) == generation {
+            *lock = Some(policy.clone());
+        }
+    }
+    Ok(policy)
+}
+
+/// Tags of the peer `id`, all peers' tags loaded at once on the first call after
+/// a change
+pub(crate) async fn peer_tags(db: &Database, id: &str) -> ResultType<Vec<String>> {
+    if let Some(tags) = PEER_TAGS.read().ok().and_then(|x| x.clone()) {
+        return Ok(tags.get(id).cloned().unwrap_or_default());
+    }
+    let generation = ACCESS_GEN.load(Ordering::SeqCst);
+    let tags: HashMap<String, Vec<String>> = db
+        .tagged_peers()
+        .await?
+        .into_iter()
+        .map(|(id, value)| {
+            let tags = value
+                .split(',')
+                .map(str::trim)
+                .filter(|x| !x.is_empty())
+                .map(str::to_owned)
+                .collect();
+            (id, tags)
+        })
+        .collect();
+    let found = tags.get(id).cloned().unwrap_or_default();
+    if let Ok(mut lock) = PEER_TAGS.write() {
+        if ACCESS_GEN.load(Ordering::SeqCst) == generation {
+            *lock = Some(Arc::new(tags));
+        }
+    }
+    Ok(found)
+}
+
+/// Why a registration or punch hole request was answered with an error result.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorReason {
+    InvalidId,
+    MalformedCredentials,
+    Banned,
+    UuidMismatch,
+    PkMismatch,
+    IdNotFound,
+    IdTaken,
+    TooFrequent,
+    Database,
+    PkChangePending,
+    PkChangeRejected,
+    InitiatorBanned,
+    AccessDenied,
+    Quarantined,
+    UserDisabled,
+}
+
+impl ErrorReason {
+    pub const ALL: [ErrorReason; 15] = [
+        ErrorReason::InvalidId,
+        ErrorReason::MalformedCredentials,
+        ErrorReason::Banned,
+        ErrorReason::UuidMismatch,
+        ErrorReason::PkMismatch,
+        ErrorReason::IdNotFound,
+        ErrorReason::IdTaken,
+        ErrorReason::TooFrequent,
+        ErrorReason::Database,
+        ErrorReason::PkChangePending,
+        ErrorReason::PkChangeRejected,
+        ErrorReason::InitiatorBanned,
+        ErrorReason::AccessDenied,
+        ErrorReason::Quarantined,
+        ErrorReason::UserDisabled,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorReason::InvalidId => "invalid_id",
+            ErrorReason::MalformedCredentials => "malformed_credentials",
+            ErrorReason::Banned => "banned",
+            ErrorReason::UuidMismatch => "uuid_mismatch",
+            ErrorReason::PkMismatch => "pk_mismatch",
+            ErrorReason::IdNotFound => "id_not_found",
+            ErrorReason::IdTaken => "id_taken",
+            ErrorReason::TooFrequent => "too_frequent",
+            ErrorReason::Database => "database",
+            ErrorReason::PkChangePending => "pk_change_pending",
+            ErrorReason::PkChangeRejected => "pk_change_rejected",
+            ErrorReason::InitiatorBanned => "initiator_banned",
+            ErrorReason::AccessDenied => "access_denied",
+            ErrorReason::Quarantined => "quarantined",
+            ErrorReason::UserDisabled => "user_disabled",
+        }
+    }
+}
+
+/// One error response sent to a client. Never carries pk/uuid contents.
+#[derive(Clone, Debug)]
+pub struct RecentError {
+    pub at: chrono::DateTime<chrono::Utc>,
+    pub id: String,
+    pub ip: String,
+    pub reason: ErrorReason,
+    pub detail: String,
+}
+
+lazy_static::lazy_static! {
+    static ref RECENT_ERRORS: std::sync::Mutex<std::collections::VecDeque<RecentError>> =
+        Default::default();
+}
+
+/// Remember an error response for `GET /api/debug/recent-errors` and count it for /metrics
+pub(crate) fn record_error(
+    reason: ErrorReason,
+    id: &str,
+    from: SocketAddr,
+    detail: impl Into<String>,
+) {
+    ERROR_RESPONSES.inc(reason.as_str());
+    let error = RecentError {
+        at: chrono::Utc::now(),
+        id: id.to_owned(),
+        ip: try_into_v4(from).ip().to_string(),
+        reason,
+        detail: detail.into(),
+    };
+    emit_event(EventKind::Rejection {
+        id: error.id.clone(),
+        ip: error.ip.clone(),
+        reason: reason.as_str(),
+        detail: error.detail.clone(),
+    });
+    if let Ok(mut lock) = RECENT_ERRORS.lock() {
+        if lock.len() >= RECENT_ERRORS_MAX {
+            lock.pop_front();
+        }
+        lock.push_back(error);
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref BAN_REJECTIONS: std::sync::Mutex<HashMap<String, u64>> = Default::default();
+}
+
+/// A RegisterPk of `id` refused because the device is banned
+pub(crate) fn count_ban_rejection(id: &str) {
+    if let Ok(mut lock) = BAN_REJECTIONS.lock() {
+        *lock.entry(id.to_owned()).or_default() += 1;
+    }
+}
+
+/// Registrations of `id` refused for its ban since the server started or the
+/// ban was last set or lifted through the API
+pub fn ban_rejections(id: &str) -> u64 {
+    BAN_REJECTIONS
+        .lock()
+        .ok()
+        .and_then(|lock| lock.get(id).copied())
+        .unwrap_or_default()
+}
+
+/// Count the refused registrations of `id` from zero again
+pub fn reset_ban_rejections(id: &str) {
+    if let Ok(mut lock) = BAN_REJECTIONS.lock() {
+        lock.remove(id);
+    }
+}
+
+/// The last error responses, newest first
+pub fn recent_errors() -> Vec<RecentError> {
+    RECENT_ERRORS
+        .lock()
+        .map(|x| x.iter().rev().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// Version of the Event schema; fields are only ever added within a version
+pub const EVENT_SCHEMA: u32 = 1;
+/// Events held for each subscriber that falls behind (EVENT_QUEUE); older ones
+/// are dropped and reported to it as lagged
+const EVENT_QUEUE: u64 = 10_000;
+
+pub fn event_queue() -> u64 {
+    env_u64("EVENT_QUEUE", EVENT_QUEUE).max(1)
+}
+
+/// One server event as subscribers receive it and as the event log writes it,
+/// one JSON object per line:
+/// `{"v":1,"seq":7,"at":"2026-02-06T14:00:27.113Z","event":"offline","id":"42","reason":"timeout"}`
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    /// EVENT_SCHEMA
+    pub v: u32,
+    /// Increasing by one from 1 at each start, in the order events happened; a gap
+    /// in what a subscriber got means it dropped events
+    pub seq: u64,
+    /// RFC3339 UTC with milliseconds
+    pub at: String,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    /// Reasons as in the status-event log (register, id_change, transport_ws, ...)
+    Online { id: String, reason: &'static str },
+    Offline { id: String, reason: &'static str },
+    /// A RegisterPk answered, with its result (ok, uuid_mismatch, too_frequent, ...)
+    Registration {
+        id: String,
+        ip: String,
+        result: &'static str,
+    },
+    /// An error response to a client, reasons as in /api/debug/recent-errors
+    Rejection {
+        id: String,
+        ip: String,
+        reason: &'static str,
+        detail: String,
+    },
+    Ban { id: String, actor: String },
+    Rename {
+        old_id: String,
+        new_id: String,
+        via: IdChangeVia,
+        actor: String,
+    },
+    /// An audit log entry: admin actions and the server's own decisions on them
+    Audit {
+        actor: String,
+        action: &'static str,
+        peer_id: String,
+        detail: String,
+    },
+    /// Online transitions a sink collapsed during a surge; never on the stream itself
+    OnlineBulk(BulkEvents),
+    OfflineBulk(BulkEvents),
+}
+
+/// Summary of the events of one kind a sink left out within one surge window
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BulkEvents {
+    /// events left out, each counted once
+    pub count: u64,
+    /// the first few ids left out
+    pub sample_ids: Vec<String>,
+    /// count per reason
+    pub reasons: std::collections::BTreeMap<&'static str, u64>,
+    pub window_secs: u64,
+    /// seq of the first event left out; the summary's own seq is the last one's
+    pub first_seq: u64,
+}
+
+lazy_static::lazy_static! {
+    // the last seq with the sender, locked together so that seq order is send order
+    static ref EVENTS: std::sync::Mutex<(u64, tokio::sync::broadcast::Sender<Event>)> = {
+        let queue = event_queue() as usize;
+        std::sync::Mutex::new((0, tokio::sync::broadcast::channel(queue).0))
+    };
+}
+
+/// Events from now on; a subscriber more than EVENT_QUEUE events behind loses the
+/// oldest ones and is told how many
+pub fn subscribe_events() -> tokio::sync::broadcast::Receiver<Event> {
+    match EVENTS.lock() {
+        Ok(lock) => lock.1.subscribe(),
+        Err(e) => e.into_inner().1.subscribe(),
+    }
+}
+
+/// Events from now on with the seq of the last one before them, so that a
+/// subscriber coming back can tell how many it missed
+pub fn subscribe_events_since() -> (u64, tokio::sync::broadcast::Receiver<Event>) {
+    match EVENTS.lock() {
+        Ok(lock) => (lock.0, lock.1.subscribe()),
+        Err(e) => {
+            let lock = e.into_inner();
+            (lock.0, lock.1.subscribe())
+        }
+    }
+}
+
+/// Publish an event; never blocks, and costs only its seq without subscribers
+pub fn emit_event(kind: EventKind) {
+    if let Ok(mut lock) = EVENTS.lock() {
+        // numbered either way: gaps after a reconnect are events missed meanwhile
+        lock.0 += 1;
+        if lock.1.receiver_count() == 0 {
+            return;
+        }
+        let event = Event {
+            v: EVENT_SCHEMA,
+            seq: lock.0,
+            at: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            kind,
+        };
+        lock.1.send(event).ok();
+    }
+}
+
+// Bucket upper bounds in milliseconds for latency histograms
+const HISTOGRAM_BUCKETS_MS: [u64; 8] = [1, 5, 10, 25, 50, 100, 250, 1000];
+
+/// Fixed-bucket latency histogram, cheap enough to observe on the hot path.
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicUsize; HISTOGRAM_BUCKETS_MS.len()],
+    count: AtomicUsize,
+    sum_us: AtomicUsize,
+}
+
+impl Histogram {
+    pub fn observe(&self, elapsed: Duration) {
+        // against the bounds in seconds as exposed: 1.5ms is not within le="0.001"
+        let secs = elapsed.as_secs_f64();
+        for (i, le) in HISTOGRAM_BUCKETS_MS.iter().enumerate() {
+            if secs <= *le as f64 / 1000. {
+                self.buckets[i].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_us
+            .fetch_add(elapsed.as_micros() as usize, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_us.load(Ordering::Relaxed) as _)
+    }
+
+    /// (upper bound in ms, observations at or below it) per bucket
+    pub fn buckets(&self) -> Vec<(u64, usize)> {
+        HISTOGRAM_BUCKETS_MS
+            .iter()
+            .zip(self.buckets.iter())
+            .map(|(le, n)| (*le, n.load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+/// Counter family whose label values are fixed up front. Values outside the set
+/// are refused (and logged once) so label cardinality can never grow at runtime.
+pub struct LabeledCounter {
+    name: &'static str,
+    label: &'static str,
+    values: Vec<(&'static str, AtomicUsize)>,
+    refused: AtomicBool,
+}
+
+impl LabeledCounter {
+    fn new(name: &'static str, label: &'static str, values: &[&'static str]) -> Self {
+        Self {
+            name,
+            label,
+            values: values.iter().map(|v| (*v, AtomicUsize::new(0))).collect(),
+            refused: AtomicBool::new(false),
+        }
+    }
+
+    pub fn inc(&self, value: &str) {
+        self.add(value, 1);
+    }
+
+    pub fn add(&self, value: &str, count: usize) {
+        match self.values.iter().find(|(v, _)| *v == value) {
+            Some((_, n)) => {
+                n.fetch_add(count, Ordering::Relaxed);
+            }
+            None => {
+                if !self.refused.swap(true, Ordering::SeqCst) {
+                    log::warn!(
+                        "metrics: refusing unexpected {}=\"{}\" for {}",
+                        self.label,
+                        value,
+                        self.name
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Prometheus text exposition builder. Label values are checked against the
+/// allowed set of their family so only bounded series are ever emitted.
+#[derive(Default)]
+pub struct MetricsText {
+    out: String,
+    refused: Vec<&'static str>,
+}
+
+impl MetricsText {
+    pub fn gauge(&mut self, name: &str, help: &str, value: f64) {
+        use std::fmt::Write as _;
+        let _ = writeln!(
+            self.out,
+            "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}"
+        );
+    }
+
+    pub fn labeled_gauge(
+        &mut self,
+        name: &'static str,
+        help: &str,
+        label: &str,
+        allowed: &[String],
+        values: &[(String, f64)],
+    ) {
+        use std::fmt::Write as _;
+        let _ = writeln!(self.out, "# HELP {name} {help}\n# TYPE {name} gauge");
+        for (v, n) in values {
+            if !allowed.contains(v) {
+                self.refuse(name, label, v);
+                continue;
+            }
+            let _ = writeln!(self.out, "{name}{{{label}=\"{}\"}} {n}", escape_label(v));
+        }
+    }
+
+    pub fn counter(&mut self, c: &LabeledCounter, help: &str) {
+        use std::fmt::Write as _;
+        let name = c.name;
+        let _ = writeln!(self.out, "# HELP {name} {help}\n# TYPE {name} counter");
+        for (v, n) in c.values.iter() {
+            let _ = writeln!(
+                self.out,
+                "{name}{{{}=\"{v}\"}} {}",
+                c.label,
+                n.load(Ordering::Relaxed)
+            );
+        }
+    }
+
+    pub fn histograms(
+        &mut self,
+        name: &'static str,
+        help: &str,
+        label: &str,
+        hs: &[(&'static str, &Histogram)],
+    ) {
+        use std::fmt::Write as _;
+        let _ = writeln!(self.out, "# HELP {name} {help}\n# TYPE {name} histogram");
+        for (v, h) in hs {
+            for (i, le) in HISTOGRAM_BUCKETS_MS.iter().enumerate() {
+                let _ = writeln!(
+                    self.out,
+                    "{name}_bucket{{{label}=\"{v}\",le=\"{}\"}} {}",
+                    *le as f64 / 1000.,
+                    h.buckets[i].load(Ordering::Relaxed)
+                );
+            }
+            let count = h.count.load(Ordering::Relaxed);
+            let _ = writeln!(
+                self.out,
+                "{name}_bucket{{{label}=\"{v}\",le=\"+Inf\"}} {count}"
+            );
+            let _ = writeln!(
+                self.out,
+                "{name}_sum{{{label}=\"{v}\"}} {}",
+                h.sum_us.load(Ordering::Relaxed) as f64 / 1_000_000.
+            );
+            let _ = writeln!(self.out, "{name}_count{{{label}=\"{v}\"}} {count}");
+        }
+    }
+
+    fn refuse(&mut self, name: &'static str, label: &str, value: &str) {
+        if !self.refused.contains(&name) {
+            self.refused.push(name);
+            log::warn!("metrics: refusing unexpected {label}=\"{value}\" for {name}");
+        }
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+fn escape_label(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+lazy_static::lazy_static! {
+    static ref REGISTRATIONS: LabeledCounter = LabeledCounter::new(
+        "hbbs_registrations_total",
+        "result",
+        &["ok", "uuid_mismatch", "too_frequent", "server_error", "not_support", "other"],
+    );
+    static ref PUNCH_HOLES: LabeledCounter = LabeledCounter::new(
+        "hbbs_punch_hole_requests_total",
+        "result",
+        &[
+            "ok",
+            "offline",
+            "id_not_exist",
+            "license_mismatch",
+            "initiator_banned",
+            "access_denied",
+            "draining",
+            "retry_fresh",
+            "quarantined",
+            "user_disabled",
+        ],
+    );
+    static ref RELAY_DECISIONS: LabeledCounter = LabeledCounter::new(
+        "hbbs_relay_decisions_total",
+        "reason",
+        &RelayReason::ALL.map(|r| r.as_str()),
+    );
+    static ref UDP_SEND_FAILURES: LabeledCounter = LabeledCounter::new(
+        "hbbs_udp_send_failures_total",
+        "outcome",
+        &["retried", "recovered", "dropped", "unreachable", "fatal"],
+    );
+    static ref UDP_QUEUED_SENDS: LabeledCounter = LabeledCounter::new(
+        "hbbs_udp_queued_sends_total",
+        "outcome",
+        &["sent", "coalesced"],
+    );
+    static ref ERROR_RESPONSES: LabeledCounter = LabeledCounter::new(
+        "hbbs_error_responses_total",
+        "reason",
+        &ErrorReason::ALL.map(|r| r.as_str()),
+    );
+    static ref SWEEP_OUTCOMES: LabeledCounter = LabeledCounter::new(
+        "hbbs_offline_sweep_total",
+        "outcome",
+        &["offline", "evicted_only", "confirmed", "skipped"],
+    );
+    static ref LEGACY_TIMESTAMPS: LabeledCounter = LabeledCounter::new(
+        "hbbs_legacy_timestamps_total",
+        "source",
+        &["read", "normalized"],
+    );
+    static ref STATUS_WRITES: LabeledCounter = LabeledCounter::new(
+        "hbbs_status_writes_total",
+        "outcome",
+        &["written", "coalesced", "failed"],
+    );
+    // (when the io loop last handled its relay check timer, how late that tick was)
+    static ref IO_LOOP_TICK: std::sync::Mutex<Option<(Instant, Duration)>> = Default::default();
+    static ref KEY_CHANGES: LabeledCounter = LabeledCounter::new(
+        "hbbs_key_changes_total",
+        "outcome",
+        &["auto", "pending", "rejected", "applied", "revoked"],
+    );
+    static ref IPV6_CLIENTS: LabeledCounter = LabeledCounter::new(
+        "hbbs_ipv6_only_clients_total",
+        "event",
+        &["registered", "relay_preferred", "relay_v4_only"],
+    );
+    static ref REBINDS: LabeledCounter = LabeledCounter::new(
+        "hbbs_addr_rebinds_total",
+        "decision",
+        &["port_rebind", "ip_rebind", "port_reverify", "ip_reverify", "reverified"],
+    );
+    static ref SIGN_CACHE: LabeledCounter = LabeledCounter::new(
+        "hbbs_sign_cache_total",
+        "outcome",
+        &["hit", "miss"],
+    );
+    static ref UDP_KERNEL_DROPS: LabeledCounter = LabeledCounter::new(
+        "hbbs_udp_kernel_drops_total",
+        "socket",
+        &["rendezvous"],
+    );
+    static ref SNIFF_FAILURES: LabeledCounter = LabeledCounter::new(
+        "hbbs_single_port_failures_total",
+        "reason",
+        &["silent", "closed", "tls_unconfigured", "tls_handshake"],
+    );
+    // set by the binary from --tls-cert and --tls-key
+    static ref TLS_ACCEPTOR: std::sync::RwLock<Option<Arc<TlsAcceptFn>>> = Default::default();
+    static ref MESSAGE_REJECTS: LabeledCounter = LabeledCounter::new(
+        "hbbs_rejected_messages_total",
+        "transport",
+        &[Transport::Udp, Transport::Tcp, Transport::Ws].map(|t| t.as_str()),
+    );
+    // union field number -> ceiling
+    static ref MESSAGE_SIZE_LIMITS: HashMap<u32, usize> = MESSAGE_LIMITS
+        .iter()
+        .filter_map(|(name, default)| {
+            let field = RendezvousMessage::descriptor().field_by_name(name)?;
+            let env = format!("MAX_{}_BYTES", name.to_uppercase());
+            Some((field.proto().number() as u32, env_u64(&env, *default) as usize))
+        })
+        .collect();
+    static ref DEFAULT_MESSAGE_SIZE_LIMIT: usize =
+        env_u64("MAX_MESSAGE_BYTES", DEFAULT_MESSAGE_LIMIT) as usize;
+    static ref TRANSPORT_LIMITS: (usize, usize) = (
+        env_u64("MAX_UDP_MESSAGE_BYTES", MAX_UDP_MESSAGE_BYTES) as usize,
+        env_u64("MAX_TCP_MESSAGE_BYTES", MAX_TCP_MESSAGE_BYTES) as usize,
+    );
+    static ref SIGN_INLINE: Histogram = Default::default();
+    static ref SIGN_BLOCKING: Histogram = Default::default();
+    // id -> (pk, when signed, signed IdPk)
+    static ref SIGNED_ID_PK: std::sync::Mutex<HashMap<String, (Bytes, Instant, Bytes)>> =
+        Default::default();
+    static ref PEER_STATS_SNAPSHOT: std::sync::RwLock<PeerStats> = Default::default();
+    static ref RELAY_HEALTH: std::sync::RwLock<(RelayServers, RelayServers)> = Default::default();
+    // connect time of each relay in its last successful health check
+    static ref RELAY_LATENCY: std::sync::RwLock<HashMap<String, Duration>> = Default::default();
+    static ref RELAY_STATE: std::sync::RwLock<RelayState> = Default::default();
+    static ref RELAY_BINDINGS: std::sync::RwLock<Arc<RelayBindings>> = Default::default();
+    static ref CURRENT_CONFIG: std::sync::RwLock<Option<ServerConfig>> = Default::default();
+    static ref STATS_TIMING: Histogram = Default::default();
+    static ref VERIFY_TIMING: Histogram = Default::default();
+    static ref LAST_DRIFT: std::sync::RwLock<Option<DriftReport>> = Default::default();
+    static ref VERIFY_REQUESTS: std::sync::Mutex<Option<mpsc::UnboundedSender<VerifyRequest>>> =
+        Default::default();
+    static ref RELOAD_REQUESTS: std::sync::Mutex<Option<mpsc::UnboundedSender<ReloadRequest>>> =
+        Default::default();
+    // the io loop's queue, for serial bumps and punch simulations from the API
+    static ref SERIAL_REQUESTS: std::sync::Mutex<Option<Sender>> = Default::default();
+}
+
+const STATS_INTERVAL_SECS: u64 = 60;
+const VERIFY_INTERVAL_SECS: u64 = 3600;
+const BANNER_MAX_CHARS: usize = 100;
+const BANNER_COLORS: [&str; 8] = [
+    "red", "orange", "yellow", "green", "blue", "purple", "gray", "black",
+];
+const VERIFY_SAMPLE: usize = 200;
+
+/// Full-table flag and where to deliver the report
+type VerifyRequest = (bool, oneshot::Sender<DriftReport>);
+
+/// Who asked for the reload and where to deliver the diff
+type ReloadRequest = (&'static str, oneshot::Sender<Result<ReloadReport, String>>);
+
+/// Effective server settings, as compared by a config reload. Values are kept
+/// as configured (strings) so the diff shows exactly what the operator wrote.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    port: String,
+    single_port: bool,
+    db_url: String,
+    key: String,
+    rendezvous_servers: String,
+    software_url: String,
+    external_check_url: String,
+    mask: String,
+    local_ip: String,
+    relay_servers: String,
+    relay_mode: RelayMode,
+    relay_bindings: String,
+    relay_binding_fallback: BindingFallback,
+    pk_change_policy: PkChangePolicy,
+    rebind_policy: RebindPolicy,
+    always_use_relay: bool,
+    peer_timeout_secs: u64,
+    uuid_churn_threshold: u64,
+    deprecated_versions: String,
+    banner: Banner,
+}
+
+/// Operator-supplied identity of the instance (banner-* settings), stamped on
+/// /api/health, /api/stats and the startup log
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Banner {
+    pub name: String,
+    /// e.g. staging, prod-eu1
+    pub environment: String,
+    /// Who to contact about this instance
+    pub contact: String,
+    /// Color hint for UIs: a name such as red or #rrggbb
+    pub color: String,
+}
+
+impl Banner {
+    pub fn is_empty(&self) -> bool {
+        self.name.is_empty() && self.environment.is_empty() && self.contact.is_empty()
+    }
+
+    /// The banner-* values of effective settings
+    pub fn from_fields(fields: &[ConfigField]) -> Banner {
+        let value = |name: &str| {
+            fields
+                .iter()
+                .find(|x| x.field == name)
+                .map(|x| x.value.clone())
+                .unwrap_or_default()
+        };
+        Banner {
+            name: value("banner-name"),
+            environment: value("banner-environment"),
+            contact: value("banner-contact"),
+            color: value("banner-color"),
+        }
+    }
+
+    /// `[environment] name (contact)` for log lines
+    pub fn line(&self) -> String {
+        let mut parts = Vec::new();
+        if !self.environment.is_empty() {
+            parts.push(format!("[{}]", self.environment));
+        }
+        if !self.name.is_empty() {
+            parts.push(self.name.clone());
+        }
+        if !self.contact.is_empty() {
+            parts.push(format!("({})", self.contact));
+        }
+        parts.join(" ")
+    }
+}
+
+/// The running server's banner, None when unset or before the server started
+pub fn server_banner() -> Option<Banner> {
+    let lock = CURRENT_CONFIG.read().ok()?;
+    let banner = &lock.as_ref()?.banner;
+    (!banner.is_empty()).then(|| banner.clone())
+}
+
+/// Why a banner value may not be shown on every response, None when it may.
+/// Banners end up in screenshots and tickets, so anything that looks like a
+/// credential is refused.
+fn banner_problem(value: &str) -> Option<&'static str> {
+    const CREDENTIAL_WORDS: [&str; 8] = [
+        "password", "passwd", "secret", "token", "apikey", "api_key", "api-key", "bearer",
+    ];
+    let lower = value.to_lowercase();
+    if value.chars().count() > BANNER_MAX_CHARS {
+        Some("is longer than 100 characters")
+    } else if value.chars().any(|c| c.is_control()) {
+        Some("contains control characters")
+    } else if CREDENTIAL_WORDS.iter().any(|x| lower.contains(x)) || lower.contains("-----begin") {
+        Some("looks like it holds a credential")
+    } else if value.split_whitespace().any(|word| {
+        word.split_once("://").map_or(false, |(_, rest)| {
+            rest.split('/').next().unwrap_or_default().contains('@')
+        })
+    }) {
+        Some("holds a URL with credentials")
+    } else if value.split_whitespace().any(|word| {
+        word.len() >= 24
+            && word
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "+/=_-".contains(c))
+            && word.chars().any(|c| c.is_ascii_digit())
+            && word.chars().any(|c| c.is_ascii_alphabetic())
+    }) {
+        Some("holds a long random-looking string (a key or token?)")
+    } else {
+        None
+    }
+}
+
+/// Whether a changed setting took effect or waits for a restart
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeStatus {
+    AppliedLive,
+    RequiresRestart,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigChange {
+    pub field: &'static str,
+    pub old: String,
+    pub new: String,
+    pub status: ChangeStatus,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReloadReport {
+    pub config_file: String,
+    pub changes: Vec<ConfigChange>,
+}
+
+impl ServerConfig {
+    /// (field, display value, hot-appliable); key material is shown as a fingerprint
+    fn fields(&self) -> Vec<(&'static str, String, bool)> {
+        let key = match self.key.as_str() {
+            "" | "-" | "_" => self.key.clone(),
+            key => format!("fingerprint:{}", pk_fingerprint(key.as_bytes())),
+        };
+        vec![
+            ("port", self.port.clone(), false),
+            ("single-port", self.single_port.to_string(), false),
+            ("db-url", self.db_url.clone(), false),
+            ("key", key, false),
+            ("rendezvous-servers", self.rendezvous_servers.clone(), false),
+            ("software-url", self.software_url.clone(), false),
+            ("external-check-url", self.external_check_url.clone(), false),
+            ("mask", self.mask.clone(), false),
+            ("local-ip", self.local_ip.clone(), false),
+            ("relay-servers", self.relay_servers.clone(), true),
+            ("relay-mode", self.relay_mode.as_str().to_owned(), true),
+            ("relay-bindings", self.relay_bindings.clone(), true),
+            (
+                "relay-binding-fallback",
+                self.relay_binding_fallback.as_str().to_owned(),
+                true,
+            ),
+            (
+                "pk-change-policy",
+                self.pk_change_policy.as_str().to_owned(),
+                true,
+            ),
+            (
+                "rebind-policy",
+                self.rebind_policy.as_str().to_owned(),
+                true,
+            ),
+            ("always-use-relay", self.always_use_relay.to_string(), true),
+            (
+                "peer-timeout-secs",
+                self.peer_timeout_secs.to_string(),
+                true,
+            ),
+            (
+                "uuid-churn-threshold",
+                self.uuid_churn_threshold.to_string(),
+                true,
+            ),
+            (
+                "deprecated-versions",
+                self.deprecated_versions.clone(),
+                true,
+            ),
+            ("banner-name", self.banner.name.clone(), true),
+            ("banner-environment", self.banner.environment.clone(), true),
+            ("banner-contact", self.banner.contact.clone(), true),
+            ("banner-color", self.banner.color.clone(), true),
+        ]
+    }
+
+    /// Settings from the command line and environment, which at startup also
+    /// hold the config file's values. Invalid values are reported and replaced
+    /// by their defaults.
+    fn from_args(port: &str, key: &str) -> (ServerConfig, Vec<ConfigError>) {
+        let mut errors = Vec::new();
+        let mut checked = |field: &'static str, value: String| {
+            if let Err(problem) = check_setting(field, &value) {
+                errors.push(ConfigError {
+                    location: "command line/environment".to_owned(),
+                    field: field.to_owned(),
+                    problem,
+                });
+            }
+            value
+        };
+        let env = |name: &str| std::env::var(name).unwrap_or_default();
+        let port = checked("port", port.to_owned());
+        let external_check_url = checked("external-check-url", get_arg("external-check-url"));
+        let mask = checked("mask", get_arg("mask"));
+        let local_ip = checked("local-ip", get_arg("local-ip"));
+        let relay_mode = checked("relay-mode", get_arg_or("relay-mode", "rotation".to_owned()));
+        let relay_servers = checked("relay-servers", get_arg("relay-servers"));
+        let rendezvous_servers = checked("rendezvous-servers", get_arg("rendezvous-servers"));
+        let relay_bindings = checked("relay-bindings", get_arg("relay-bindings"));
+        let relay_binding_fallback = checked(
+            "relay-binding-fallback",
+            get_arg_or("relay-binding-fallback", "shared".to_owned()),
+        );
+        let pk_change_policy = checked(
+            "pk-change-policy",
+            get_arg_or("pk-change-policy", "auto".to_owned()),
+        );
+        let rebind_policy = checked(
+            "rebind-policy",
+            get_arg_or("rebind-policy", "rebind".to_owned()),
+        );
+        checked("peer-timeout-secs", env("PEER_TIMEOUT_SECS"));
+        checked("uuid-churn-threshold", env("UUID_CHURN_THRESHOLD"));
+        let deprecated_versions = checked("deprecated-versions", get_arg("deprecated-versions"));
+        let banner = Banner {
+            name: checked("banner-name", env("BANNER_NAME")),
+            environment: checked("banner-environment", env("BANNER_ENVIRONMENT")),
+            contact: checked("banner-contact", env("BANNER_CONTACT")),
+            color: checked("banner-color", env("BANNER_COLOR")),
+        };
+        let config = ServerConfig {
+            port,
+            single_port: get_flag("single-port"),
+            db_url: std::env::var("DB_URL").unwrap_or_else(|_| "db_v2.sqlite3".to_owned()),
+            key: key.to_owned(),
+            rendezvous_servers,
+            software_url: get_arg("software-url"),
+            external_check_url,
+            mask,
+            local_ip,
+            relay_servers,
+            relay_mode: RelayMode::parse(&relay_mode).unwrap_or(RelayMode::Rotation),
+            relay_bindings,
+            relay_binding_fallback: BindingFallback::parse(&relay_binding_fallback)
+                .unwrap_or_default(),
+            pk_change_policy: PkChangePolicy::parse(&pk_change_policy)
+                .unwrap_or(PkChangePolicy::Auto),
+            rebind_policy: RebindPolicy::parse(&rebind_policy).unwrap_or(RebindPolicy::Rebind),
+            always_use_relay: env("ALWAYS_USE_RELAY").to_uppercase() == "Y",
+            peer_timeout_secs: peer_timeout_secs(),
+            uuid_churn_threshold: uuid_churn_threshold() as u64,
+            deprecated_versions,
+            banner,
+        };
+        (config, errors)
+    }
+
+    fn effective(&self) -> Vec<ConfigField> {
+        self.fields()
+            .into_iter()
+            .map(|(field, value, live)| ConfigField { field, value, live })
+            .collect()
+    }
+
+    /// This config with the `key = value` lines of a config file laid over it.
+    /// Keys may be written as options (`relay-servers`) or env vars (`RELAY_SERVERS`).
+    /// Every bad line is reported, not just the first; unknown keys only warn
+    /// unless `strict`.
+    fn overlay(
+        &self,
+        file: &str,
+        text: &str,
+        strict: bool,
+    ) -> Result<ServerConfig, Vec<ConfigError>> {
+        let mut next = self.clone();
+        let mut errors = Vec::new();
+        let flag = |v: &str| matches!(v.to_uppercase().as_str(), "Y" | "YES" | "TRUE" | "1");
+        let accepted: Vec<&str> = self.fields().iter().map(|x| x.0).collect();
+        for (n, setting) in config_lines(text) {
+            let error = |field: &str, problem: String| ConfigError {
+                location: format!("{}:{}", file, n),
+                field: field.to_owned(),
+                problem,
+            };
+            let (k, v) = match setting {
+                Some(x) => x,
+                None => {
+                    errors.push(error("", "expected key = value".to_owned()));
+                    continue;
+                }
+            };
+            if !accepted.contains(&k.as_str()) {
+                let e = error(
+                    &k,
+                    format!("unknown setting, accepted: {}", accepted.join(", ")),
+                );
+                if strict {
+                    errors.push(e);
+                } else {
+                    log::warn!("config: ignoring {}", e);
+                }
+                continue;
+            }
+            if let Err(problem) = check_setting(&k, &v) {
+                errors.push(error(&k, problem));
+                continue;
+            }
+            match k.as_str() {
+                "port" => next.port = v,
+                "single-port" => next.single_port = flag(&v),
+                "db-url" => next.db_url = v,
+                "key" => next.key = v,
+                "rendezvous-servers" => next.rendezvous_servers = v,
+                "software-url" => next.software_url = v,
+                "external-check-url" => next.external_check_url = v,
+                "mask" => next.mask = v,
+                "local-ip" => next.local_ip = v,
+                "relay-servers" => next.relay_servers = v,
+                "relay-mode" => next.relay_mode = RelayMode::parse(&v).unwrap_or(next.relay_mode),
+                "relay-bindings" => next.relay_bindings = v,
+                "relay-binding-fallback" => {
+                    next.relay_binding_fallback =
+                        BindingFallback::parse(&v).unwrap_or(next.relay_binding_fallback)
+                }
+                "pk-change-policy" => {
+                    next.pk_change_policy =
+                        PkChangePolicy::parse(&v).unwrap_or(next.pk_change_policy)
+                }
+                "rebind-policy" => {
+                    next.rebind_policy = RebindPolicy::parse(&v).unwrap_or(next.rebind_policy)
+                }
+                "always-use-relay" => next.always_use_relay = flag(&v),
+                "peer-timeout-secs" => next.peer_timeout_secs = v.parse().unwrap_or_default(),
+                "uuid-churn-threshold" => {
+                    next.uuid_churn_threshold = v.parse().unwrap_or_default()
+                }
+                "deprecated-versions" => next.deprecated_versions = v,
+                "banner-name" => next.banner.name = v,
+                "banner-environment" => next.banner.environment = v,
+                "banner-contact" => next.banner.contact = v,
+                "banner-color" => next.banner.color = v,
+                _ => {}
+            }
+        }
+        if errors.is_empty() {
+            Ok(next)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Field-by-field changes from `self` to `next`
+    fn diff(&self, next: &ServerConfig) -> Vec<ConfigChange> {
+        self.fields()
+            .into_iter()
+            .zip(next.fields())
+            .filter(|((_, old, _), (_, new, _))| old != new)
+            .map(|((field, old, hot), (_, new, _))| ConfigChange {
+                field,
+                old,
+                new,
+                status: if hot {
+                    ChangeStatus::AppliedLive
+                } else {
+                    ChangeStatus::RequiresRestart
+                },
+            })
+            .collect()
+    }
+
+    /// Take over the hot-appliable settings of `next`, the rest keeps running as is
+    fn apply_live(&mut self, next: &ServerConfig, tx: &Sender) {
+        // checked on load; set first, the shared relays leave out the bound ones
+        set_relay_bindings(
+            RelayBindings::parse(&next.relay_bindings, next.relay_binding_fallback)
+                .unwrap_or_default(),
+        );
+        if self.relay_servers != next.relay_servers || self.relay_bindings != next.relay_bindings {
+            allow_err!(tx.send(Data::RelayServers0(next.relay_servers.clone())));
+        }
+        set_pk_change_policy(next.pk_change_policy);
+        set_rebind_policy(next.rebind_policy);
+        set_relay_mode(next.relay_mode);
+        ALWAYS_USE_RELAY.store(next.always_use_relay, Ordering::SeqCst);
+        set_deprecated_versions(&next.deprecated_versions);
+        // both are read from the environment on every use
+        std::env::set_var("PEER_TIMEOUT_SECS", next.peer_timeout_secs.to_string());
+        std::env::set_var(
+            "UUID_CHURN_THRESHOLD",
+            next.uuid_churn_threshold.to_string(),
+        );
+        self.relay_servers = next.relay_servers.clone();
+        self.relay_mode = next.relay_mode;
+        self.relay_bindings = next.relay_bindings.clone();
+        self.relay_binding_fallback = next.relay_binding_fallback;
+        self.pk_change_policy = next.pk_change_policy;
+        self.rebind_policy = next.rebind_policy;
+        self.always_use_relay = next.always_use_relay;
+        self.peer_timeout_secs = next.peer_timeout_secs;
+        self.uuid_churn_threshold = next.uuid_churn_threshold;
+        self.deprecated_versions = next.deprecated_versions.clone();
+        if self.banner != next.banner {
+            log::info!("Banner: {}", next.banner.line());
+        }
+        self.banner = next.banner.clone();
+    }
+}
+
+/// A setting that failed validation, with where it was set ("file:line" or the
+/// command line/environment) and what is accepted instead
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConfigError {
+    pub location: String,
+    pub field: String,
+    pub problem: String,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.field.is_empty() {
+            write!(f, "{}: {}", self.location, self.problem)
+        } else {
+            write!(f, "{}: {}: {}", self.location, self.field, self.problem)
+        }
+    }
+}
+
+/// Value check for one setting; Err names the accepted values
+fn check_setting(field: &str, value: &str) -> Result<(), String> {
+    let number = |min: u64| match value.parse::<u64>() {
+        Ok(n) if n >= min => Ok(()),
+        _ => Err(format!("{:?} is not a whole number of at least {}", value, min)),
+    };
+    match field {
+        "port" => match value.parse::<u16>() {
+            Ok(n) if n >= 3 => Ok(()),
+            _ => Err(format!("{:?} is not a port number (3-65535)", value)),
+        },
+        "single-port" | "always-use-relay" => {
+            match value.to_uppercase().as_str() {
+                "" | "Y" | "YES" | "TRUE" | "1" | "N" | "NO" | "FALSE" | "0" => Ok(()),
+                _ => Err(format!("{:?} is not one of yes, no, true, false, 1, 0", value)),
+            }
+        }
+        "external-check-url" if !value.is_empty() && !value.starts_with("http://") => Err(format!(
+            "{:?} is not an http:// URL (https is not supported)",
+            value
+        )),
+        "mask" if !value.is_empty() => value
+            .parse::<Ipv4Network>()
+            .map(|_| ())
+            .map_err(|_| format!("{:?} is not an IPv4 network such as 192.168.0.0/16", value)),
+        "local-ip" if !value.is_empty() => value
+            .parse::<IpAddr>()
+            .map(|_| ())
+            .map_err(|_| format!("{:?} is not an IP address", value)),
+        "relay-mode" => RelayMode::parse(value)
+            .map(|_| ())
+            .ok_or_else(|| format!("{:?} is not one of rotation, sticky, latency", value)),
+        "relay-servers" | "rendezvous-servers" => {
+            let n = value.split(',').filter(|x| !x.trim().is_empty()).count();
+            if n > max_relay_servers() {
+                Err(format!(
+                    "{} servers listed, at most {} are supported (MAX_RELAY_SERVERS)",
+                    n,
+                    max_relay_servers()
+                ))
+            } else {
+                Ok(())
+            }
+        }
+        "relay-bindings" => RelayBindings::parse(value, BindingFallback::Shared).map(|_| ()),
+        "relay-binding-fallback" => BindingFallback::parse(value)
+            .map(|_| ())
+            .ok_or_else(|| format!("{:?} is not one of shared, fail", value)),
+        "pk-change-policy" => PkChangePolicy::parse(value)
+            .map(|_| ())
+            .ok_or_else(|| format!("{:?} is not one of auto, approve, reject", value)),
+        "rebind-policy" => RebindPolicy::parse(value)
+            .map(|_| ())
+            .ok_or_else(|| format!("{:?} is not one of strict, rebind, rebind-any", value)),
+        // unset numbers from the environment keep their defaults
+        "peer-timeout-secs" if !value.is_empty() => number(1),
+        "uuid-churn-threshold" if !value.is_empty() => number(0),
+        "deprecated-versions" => match value
+            .split(',')
+            .map(|x| x.trim())
+            .find(|x| !x.is_empty() && version_label(x) == "invalid")
+        {
+            Some(x) => Err(format!(
+                "{:?} is not a version such as 1.1.9, a prefix such as 1.1 or none",
+                x
+            )),
+            None => Ok(()),
+        },
+        "banner-color" if !value.is_empty() => {
+            let hex = value.strip_prefix('#').map_or(false, |x| {
+                [3, 6].contains(&x.len()) && x.chars().all(|c| c.is_ascii_hexdigit())
+            });
+            if hex || BANNER_COLORS.contains(&value.to_lowercase().as_str()) {
+                Ok(())
+            } else {
+                Err(format!(
+                    "{:?} is not #rgb, #rrggbb or one of {}",
+                    value,
+                    BANNER_COLORS.join(", ")
+                ))
+            }
+        }
+        "banner-name" | "banner-environment" | "banner-contact" => match banner_problem(value) {
+            Some(problem) => Err(format!(
+                "the banner value {}; it is shown to every API client",
+                problem
+            )),
+            None => Ok(()),
+        },
+        _ => Ok(()),
+    }
+}
+
+/// (line number, setting) for each meaningful line of a config file, the setting
+/// being None when the line is not `key = value`
+fn config_lines(text: &str) -> impl Iterator<Item = (usize, Option<(String, String)>)> + '_ {
+    text.lines()
+        .enumerate()
+        .map(|(n, line)| (n + 1, line.trim()))
+        .filter(|(_, line)| {
+            !(line.is_empty()
+                || line.starts_with('#')
+                || line.starts_with(';')
+                || line.starts_with('['))
+        })
+        .map(|(n, line)| {
+            let setting = line.split_once('=').map(|(k, v)| {
+                (
+                    k.trim().to_lowercase().replace('_', "-"),
+                    v.trim().trim_matches('"').to_owned(),
+                )
+            });
+            (n, setting)
+        })
+}
+
+/// Validate the startup settings together with `text`, the config file `file`,
+/// collecting every problem. Settings the file sets are reported at their line
+/// only. On success returns the effective settings, the key as a fingerprint.
+pub fn check_config_text(
+    port: &str,
+    key: &str,
+    file: &str,
+    text: &str,
+    strict: bool,
+) -> Result<Vec<ConfigField>, Vec<ConfigError>> {
+    let in_file: Vec<String> = config_lines(text)
+        .filter_map(|(_, setting)| setting.map(|(k, _)| k))
+        .collect();
+    let (base, mut errors) = ServerConfig::from_args(port, key);
+    errors.retain(|e| !in_file.contains(&e.field));
+    match base.overlay(file, text, strict) {
+        Ok(config) => {
+            // bad entries are only logged, unless none are left with --strict-config
+            let (relays, problems) = canonical_relays(&config.relay_servers);
+            if strict && relays.is_empty() && !problems.is_empty() {
+                let location = config_lines(text)
+                    .filter(|(_, setting)| {
+                        setting
+                            .as_ref()
+                            .map_or(false, |(k, _)| k == "relay-servers")
+                    })
+                    .last()
+                    .map_or("command line/environment".to_owned(), |(n, _)| {
+                        format!("{}:{}", file, n)
+                    });
+                errors.push(ConfigError {
+                    location,
+                    field: "relay-servers".to_owned(),
+                    problem: format!("no valid relay server: {}", problems.join("; ")),
+                });
+            }
+            if errors.is_empty() {
+                Ok(config.effective())
+            } else {
+                Err(errors)
+            }
+        }
+        Err(e) => {
+            errors.extend(e);
+            Err(errors)
+        }
+    }
+}
+
+/// check_config_text for the config file given with `--config` (or HBBS_CONFIG)
+pub fn check_config(port: &str, key: &str, strict: bool) -> Result<Vec<ConfigField>, Vec<ConfigError>> {
+    let path = config_file();
+    if path.is_empty() {
+        return check_config_text(port, key, "", "", strict);
+    }
+    match std::fs::read_to_string(&path) {
+        Ok(text) => check_config_text(port, key, &path, &text, strict),
+        Err(e) => Err(vec![ConfigError {
+            location: path,
+            field: "".to_owned(),
+            problem: format!("cannot read the config file: {}", e),
+        }]),
+    }
+}
+
+/// A setting as currently in effect
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigField {
+    pub field: &'static str,
+    pub value: String,
+    /// whether a reload changes it without a restart
+    pub live: bool,
+}
+
+/// The running server's settings, None when the rendezvous server is not running
+pub fn server_config() -> Option<Vec<ConfigField>> {
+    let lock = CURRENT_CONFIG.read().ok()?;
+    Some(lock.as_ref()?.effective())
+}
+
+fn publish_config(config: &ServerConfig) {
+    if let Ok(mut lock) = CURRENT_CONFIG.write() {
+        *lock = Some(config.clone());
+    }
+}
+
+/// The file reloads read: `--config`, or HBBS_CONFIG when started without one
+fn config_file() -> String {
+    let path = get_arg("config");
+    if path.is_empty() {
+        std::env::var("HBBS_CONFIG").unwrap_or_default()
+    } else {
+        path
+    }
+}
+
+/// Re-read the config file now and apply what can be applied live.
+/// Returns None when the rendezvous server is not running.
+pub async fn request_reload(actor: &'static str) -> Option<Result<ReloadReport, String>> {
+    let (tx, rx) = oneshot::channel();
+    let sent = match RELOAD_REQUESTS.lock() {
+        Ok(lock) => lock.as_ref().map(|x| x.send((actor, tx)).is_ok()),
+        Err(_) => None,
+    };
+    if sent != Some(true) {
+        return None;
+    }
+    rx.await.ok()
+}
+
+/// Owns the running config; reloads on SIGHUP and on API requests
+async fn reload_loop(
+    mut config: ServerConfig,
+    tx: Sender,
+    db: crate::database::Database,
+    mut rx: mpsc::UnboundedReceiver<ReloadRequest>,
+) {
+    #[cfg(unix)]
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(s) => Some(s),
+        Err(e) => {
+            log::warn!("Cannot listen for SIGHUP, reload only via the API: {}", e);
+            None
+        }
+    };
+    loop {
+        #[cfg(unix)]
+        let (actor, reply) = tokio::select! {
+            Some((actor, reply)) = rx.recv() => (actor, Some(reply)),
+            Some(_) = async {
+                match hangup.as_mut() {
+                    Some(s) => s.recv().await,
+                    None => std::future::pending().await,
+                }
+            } => ("sighup", None),
+            else => return,
+        };
+        #[cfg(not(unix))]
+        let (actor, reply) = match rx.recv().await {
+            Some((actor, reply)) => (actor, Some(reply)),
+            None => return,
+        };
+
+        let res = reload(&mut config, &tx, &db, actor).await;
+        if let Err(e) = &res {
+            log::error!("Config reload ({}) failed, nothing changed: {}", actor, e);
+        }
+        if let Some(reply) = reply {
+            reply.send(res).ok();
+        }
+    }
+}
+
+async fn reload(
+    config: &mut ServerConfig,
+    tx: &Sender,
+    db: &crate::database::Database,
+    actor: &'static str,
+) -> Result<ReloadReport, String> {
+    let path = config_file();
+    if path.is_empty() {
+        return Err("no config file, start with --config=FILE".to_owned());
+    }
+    let text = std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+    let next = config
+        .overlay(&path, &text, get_flag("strict-config"))
+        .map_err(|errors| {
+            errors
+                .iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<_>>()
+                .join("; ")
+        })?;
+    let changes = config.diff(&next);
+    config.apply_live(&next, tx);
+    publish_config(config);
+    for c in &changes {
+        log::info!(
+            "Config reload ({}): {} {:?} -> {:?} [{}]",
+            actor,
+            c.field,
+            c.old,
+            c.new,
+            if c.status == ChangeStatus::AppliedLive {
+                "applied live"
+            } else {
+                "requires restart"
+            }
+        );
+    }
+    if changes.is_empty() {
+        log::info!("Config reload ({}): no changes in {}", actor, path);
+    }
+    db.audit(
+        actor,
+        "config_reload",
+        "",
+        serde_json::to_string(&changes).unwrap_or_default(),
+    )
+    .await;
+    Ok(ReloadReport {
+        config_file: path,
+        changes,
+    })
+}
+
+/// Count peers handled by the offline sweep (or skipped ticks) by outcome
+pub(crate) fn count_sweep(outcome: &str, n: usize) {
+    SWEEP_OUTCOMES.add(outcome, n);
+}
+
+pub(crate) fn count_legacy_timestamps(source: &str, n: usize) {
+    LEGACY_TIMESTAMPS.add(source, n);
+}
+
+pub(crate) fn count_status_writes(outcome: &str, n: usize) {
+    STATUS_WRITES.add(outcome, n);
+}
+
+fn note_io_loop_tick(scheduled: Instant) {
+    if let Ok(mut lock) = IO_LOOP_TICK.lock() {
+        *lock = Some((Instant::now(), scheduled.elapsed()));
+    }
+}
+
+/// How far the io loop is behind: the lateness of its last relay check tick, or
+/// how overdue the next one is when the loop has not come around since
+pub(crate) fn io_loop_lag() -> Duration {
+    match IO_LOOP_TICK.lock().ok().and_then(|lock| *lock) {
+        Some((handled, late)) => late.max(
+            handled
+                .elapsed()
+                .saturating_sub(Duration::from_millis(CHECK_RELAY_TIMEOUT)),
+        ),
+        None => Duration::ZERO,
+    }
+}
+
+/// Count a pk change for a known uuid by how the policy handled it
+pub(crate) fn count_key_change(outcome: &str) {
+    KEY_CHANGES.inc(outcome);
+}
+
+fn count_registration(res: register_pk_response::Result) -> &'static str {
+    use register_pk_response::Result::*;
+    let result = match res {
+        OK => "ok",
+        UUID_MISMATCH => "uuid_mismatch",
+        TOO_FREQUENT => "too_frequent",
+        SERVER_ERROR => "server_error",
+        NOT_SUPPORT => "not_support",
+        _ => "other",
+    };
+    REGISTRATIONS.inc(result);
+    result
+}
+
+/// RegisterPk responses since the start: (all of them, those answered OK)
+pub fn registration_totals() -> (usize, usize) {
+    let count = |(_, n): &(&str, AtomicUsize)| n.load(Ordering::Relaxed);
+    let values = &REGISTRATIONS.values;
+    (
+        values.iter().map(count).sum(),
+        values.iter().filter(|(v, _)| *v == "ok").map(count).sum(),
+    )
+}
+
+/// Latest peer health counters, refreshed by the stats timer of the io loop
+pub fn peer_stats() -> PeerStats {
+    PEER_STATS_SNAPSHOT
+        .read()
+        .map(|x| x.clone())
+        .unwrap_or_default()
+}
+
+/// Drift counts of the last memory/database consistency pass
+pub fn last_drift_report() -> Option<DriftReport> {
+    LAST_DRIFT.read().ok().and_then(|x| x.clone())
+}
+
+/// Run a consistency pass now, on the rendezvous server's runtime.
+/// Returns None when the rendezvous server is not running.
+pub async fn request_verify(full: bool) -> Option<DriftReport> {
+    let (tx, rx) = oneshot::channel();
+    let sent = match VERIFY_REQUESTS.lock() {
+        Ok(lock) => lock.as_ref().map(|x| x.send((full, tx)).is_ok()),
+        Err(_) => None,
+    };
+    if sent != Some(true) {
+        return None;
+    }
+    rx.await.ok()
+}
+
+/// Hourly sampled consistency check, plus the on-demand passes requested by the API
+async fn consistency_loop(pm: PeerMap, mut rx: mpsc::UnboundedReceiver<VerifyRequest>) {
+    let mut timer = periodic("VERIFY_INTERVAL_SECS", VERIFY_INTERVAL_SECS);
+    // skip the immediate tick, everything was just reset offline on startup
+    tick_opt(&mut timer).await;
+    loop {
+        let (full, reply) = tokio::select! {
+            _ = tick_opt(&mut timer) => (false, None),
+            Some((full, tx)) = rx.recv() => (full, Some(tx)),
+        };
+        let started = Instant::now();
+        let report = pm
+            .verify_consistency(if full { None } else { Some(VERIFY_SAMPLE) })
+            .await;
+        VERIFY_TIMING.observe(started.elapsed());
+        if let Ok(mut lock) = LAST_DRIFT.write() {
+            *lock = Some(report.clone());
+        }
+        if let Some(tx) = reply {
+            tx.send(report).ok();
+        }
+    }
+}
+
+/// Sign `payload`, on the blocking pool when it is large so the io loop's thread
+/// stays free. The caller awaits the result before sending, so responses to a
+/// peer keep their order either way.
+async fn sign_payload(payload: Vec<u8>, sk: &sign::SecretKey) -> Bytes {
+    let started = Instant::now();
+    if payload.len() as u64 > env_u64("SIGN_BLOCKING_BYTES", SIGN_BLOCKING_BYTES) {
+        let sk = sk.clone();
+        match tokio::task::spawn_blocking(move || sign::sign(&payload, &sk)).await {
+            Ok(signed) => {
+                SIGN_BLOCKING.observe(started.elapsed());
+                signed.into()
+            }
+            Err(e) => {
+                log::error!("Signing task failed: {}", e);
+                Bytes::new()
+            }
+        }
+    } else {
+        let signed = sign::sign(&payload, sk);
+        SIGN_INLINE.observe(started.elapsed());
+        signed.into()
+    }
+}
+
+/// Signed IdPk of a peer. Ed25519 signatures are deterministic, so the bytes for
+/// an unchanged id and pk are reused for SIGN_CACHE_SECS instead of being signed
+/// again for every punch hole to a busy peer.
+pub async fn signed_id_pk(sk: &sign::SecretKey, id: String, pk: Bytes) -> Bytes {
+    let ttl = env_u64("SIGN_CACHE_SECS", SIGN_CACHE_SECS);
+    if ttl > 0 {
+        if let Ok(cache) = SIGNED_ID_PK.lock() {
+            if let Some((cached_pk, at, signed)) = cache.get(&id) {
+                if *cached_pk == pk && at.elapsed().as_secs() < ttl {
+                    SIGN_CACHE.inc("hit");
+                    return signed.clone();
+                }
+            }
+        }
+    }
+    let payload = hbb_common::message_proto::IdPk {
+        id: id.clone(),
+        pk: pk.clone(),
+        ..Default::default()
+    }
+    .write_to_bytes()
+    .unwrap_or_default();
+    let signed = sign_payload(payload, sk).await;
+    if ttl > 0 && !signed.is_empty() {
+        SIGN_CACHE.inc("miss");
+        if let Ok(mut cache) = SIGNED_ID_PK.lock() {
+            if cache.len() >= SIGN_CACHE_MAX {
+                cache.retain(|_, (_, at, _)| at.elapsed().as_secs() < ttl);
+                if cache.len() >= SIGN_CACHE_MAX {
+                    cache.clear();
+                }
+            }
+            cache.insert(id, (pk, Instant::now(), signed.clone()));
+        }
+    }
+    signed
+}
+
+/// Prometheus exposition of the rendezvous server's metrics
+pub fn render_metrics() -> String {
+    let mut m = MetricsText::default();
+    let stats = peer_stats();
+    m.gauge("hbbs_peers_total", "Peers held in memory", stats.total as _);
+    m.gauge(
+        "hbbs_peers_online",
+        "Peers with a heartbeat within the timeout",
+        (stats.healthy + stats.degraded + stats.critical) as _,
+    );
+    m.gauge(
+        "hbbs_peers_degraded",
+        "Online peers missing some heartbeats",
+        stats.degraded as _,
+    );
+    m.gauge(
+        "hbbs_peers_critical",
+        "Online peers missing many heartbeats",
+        stats.critical as _,
+    );
+    let transports = [Transport::Udp, Transport::Tcp, Transport::Ws].map(|x| x.as_str().to_owned());
+    let values: Vec<(String, f64)> = transports
+        .iter()
+        .map(|x| {
+            let n = stats.transports.get(x.as_str()).copied().unwrap_or(0);
+            (x.clone(), n as f64)
+        })
+        .collect();
+    m.labeled_gauge(
+        "hbbs_peers_online_by_transport",
+        "Online peers by the transport they last registered over",
+        "transport",
+        &transports,
+        &values,
+    );
+    let (configured, healthy) = RELAY_HEALTH.read().map(|x| x.clone()).unwrap_or_default();
+    let values: Vec<(String, f64)> = configured
+        .iter()
+        .map(|x| (x.clone(), if healthy.contains(x) { 1. } else { 0. }))
+        .collect();
+    m.labeled_gauge(
+        "hbbs_relay_up",
+        "Whether the configured relay server passed its last check",
+        "relay",
+        &configured,
+        &values,
+    );
+    m.counter(&REGISTRATIONS, "RegisterPk responses by result");
+    m.counter(&PUNCH_HOLES, "Punch hole requests by result");
+    m.counter(&RELAY_DECISIONS, "Sessions steered to relay by reason");
+    m.counter(
+        &UDP_SEND_FAILURES,
+        "Failed udp sends of queued messages by outcome",
+    );
+    m.counter(
+        &UDP_QUEUED_SENDS,
+        "Queued udp messages sent, or skipped as a repeat within the dedup window",
+    );
+    m.counter(&ERROR_RESPONSES, "Registration error responses by reason");
+    m.counter(
+        &KEY_CHANGES,
+        "Public key changes of known devices by outcome",
+    );
+    m.counter(
+        &REBINDS,
+        "Registrations from a changed address by rebind decision",
+    );
+    m.counter(
+        &IPV6_CLIENTS,
+        "IPv6-only client registrations, and their sessions by the relays left for them",
+    );
+    m.counter(&SWEEP_OUTCOMES, "Offline sweep decisions by outcome");
+    m.counter(
+        &LEGACY_TIMESTAMPS,
+        "last_online values in a format this version does not write, read or rewritten at startup",
+    );
+    m.counter(
+        &STATUS_WRITES,
+        "Queued status writes by outcome, coalesced ones replaced a pending write",
+    );
+    let writer = crate::database::status_writer_stats();
+    m.gauge(
+        "hbbs_status_writes_pending",
+        "Status writes left for the next flush",
+        writer.pending as _,
+    );
+    let sources: Vec<String> = writer
+        .hot_sources
+        .iter()
+        .map(|x| x.source.clone())
+        .collect();
+    let backlog: Vec<(String, f64)> = writer
+        .hot_sources
+        .iter()
+        .map(|x| (x.source.clone(), x.pending as f64))
+        .collect();
+    m.labeled_gauge(
+        "hbbs_status_writes_backlog",
+        "Status writes left for the next flush by source, for the sources with the most",
+        "source",
+        &sources,
+        &backlog,
+    );
+    m.counter(&SIGN_CACHE, "Signed IdPk lookups by cache outcome");
+    m.counter(
+        &MESSAGE_REJECTS,
+        "Incoming messages dropped unparsed for exceeding their size ceiling",
+    );
+    m.counter(
+        &SNIFF_FAILURES,
+        "Connections to the shared port (--single-port) closed without being served, by reason",
+    );
+    sample_udp_drops();
+    m.counter(
+        &UDP_KERNEL_DROPS,
+        "Datagrams the kernel dropped on the udp listener, sampled every UDP_DROPS_SAMPLE_SECS",
+    );
+    let udp = UDP_BUFFER.lock().map(|x| x.clone()).unwrap_or_default();
+    let kinds = ["requested".to_owned(), "effective".to_owned()];
+    let mut values = vec![(kinds[0].clone(), udp.requested_bytes as f64)];
+    if let Some(effective) = udp.effective_bytes {
+        values.push((kinds[1].clone(), effective as f64));
+    }
+    m.labeled_gauge(
+        "hbbs_udp_receive_buffer_bytes",
+        "Receive buffer of the udp listener, --rmem and SO_RCVBUF as read back",
+        "kind",
+        &kinds,
+        &values,
+    );
+    m.gauge(
+        "hbbs_udp_receive_buffer_clamped",
+        "Whether the kernel granted less receive buffer than --rmem",
+        if udp.clamped { 1. } else { 0. },
+    );
+    m.gauge(
+        "hbbs_io_loop_lag_seconds",
+        "How far the io loop is behind its own timer",
+        io_loop_lag().as_secs_f64(),
+    );
+    let pools = crate::database::pool_stats();
+    let names: Vec<String> = pools.iter().map(|(name, _)| name.to_string()).collect();
+    for (name, help, value) in [
+        (
+            "hbbs_db_pool_connections",
+            "Open connections of the database pool",
+            (|p: &PoolStats| p.size) as fn(&PoolStats) -> usize,
+        ),
+        (
+            "hbbs_db_pool_idle",
+            "Idle connections of the database pool",
+            |p| p.idle,
+        ),
+        (
+            "hbbs_db_pool_max",
+            "Connection limit of the database pool",
+            |p| p.max_size,
+        ),
+    ] {
+        let values: Vec<(String, f64)> = pools
+            .iter()
+            .map(|(pool, stats)| (pool.to_string(), value(stats) as f64))
+            .collect();
+        m.labeled_gauge(name, help, "pool", &names, &values);
+    }
+    let memory = memory_stats();
+    if let Some(rss) = memory.rss_bytes {
+        m.gauge(
+            "hbbs_process_resident_bytes",
+            "Resident set size of the process",
+            rss as _,
+        );
+    }
+    if let Some(storage) = crate::database::last_storage_report() {
+        if let Some(free) = storage.free_bytes {
+            m.gauge(
+                "hbbs_storage_free_bytes",
+                "Free bytes on the database's filesystem for the service user",
+                free as _,
+            );
+        }
+        m.gauge(
+            "hbbs_storage_low_space",
+            "Whether free space is below STORAGE_LOW_SPACE_MB",
+            if storage.low_space { 1. } else { 0. },
+        );
+    }
+    let names: Vec<String> = memory
+        .structures
+        .iter()
+        .map(|x| x.name.to_owned())
+        .collect();
+    for (name, help, value) in [
+        (
+            "hbbs_memory_entries",
+            "Entries held by an in-memory structure",
+            (|x: &MemoryUsage| x.entries as f64) as fn(&MemoryUsage) -> f64,
+        ),
+        (
+            "hbbs_memory_approx_bytes",
+            "Approximate bytes held by an in-memory structure",
+            |x| x.approx_bytes as f64,
+        ),
+        (
+            "hbbs_memory_soft_cap_exceeded",
+            "Whether an in-memory structure is over its MEMORY_SOFT_CAPS entry",
+            |x| if x.over_cap { 1. } else { 0. },
+        ),
+    ] {
+        let values: Vec<(String, f64)> = memory
+            .structures
+            .iter()
+            .map(|x| (x.name.to_owned(), value(x)))
+            .collect();
+        m.labeled_gauge(name, help, "structure", &names, &values);
+    }
+    m.histograms(
+        "hbbs_db_operation_seconds",
+        "Database operation latency",
+        "operation",
+        &crate::database::db_histograms(),
+    );
+    m.histograms(
+        "hbbs_sign_seconds",
+        "Ed25519 signing time by where it ran",
+        "mode",
+        &[("inline", &*SIGN_INLINE), ("blocking", &*SIGN_BLOCKING)],
+    );
+    m.histograms(
+        "hbbs_periodic_job_seconds",
+        "Busy time of the periodic peer jobs",
+        "job",
+        &[
+            ("sweep_tick", &*SWEEP_TICK),
+            ("sweep_pass", &*SWEEP_PASS),
+            ("stats", &*STATS_TIMING),
+            ("verify", &*VERIFY_TIMING),
+        ],
+    );
+    m.finish()
+}
+
+/// What the first bytes of a connection on the shared port look like.
+#[derive(Debug, PartialEq)]
+enum Sniffed {
+    Ws,
+    Tcp,
+    Tls,
+}
+
+#[derive(Clone)]
+struct Inner {
+    serial: i32,
+    version: String,
+    software_url: String,
+    mask: Option<Ipv4Network>,
+    local_ip: String,
+    sk: Option<sign::SecretKey>,
+}
+
+#[derive(Clone)]
+pub struct RendezvousServer {
+    tcp_punch: Arc<Mutex<HashMap<SocketAddr, Sink>>>,
+    pm: PeerMap,
+    tx: Sender,
+    relay_servers: Arc<RelayServers>,
+    relay_servers0: Arc<RelayServers>,
+    rendezvous_servers: Arc<Vec<String>>,
+    inner: Arc<Inner>,
+}
+
+/// Queued udp sends of the last window, to skip byte-identical repeats to the same
+/// address (a peer retrying faster than it reads its answers). Distinct messages,
+/// such as the steps of a punch sequence, always go out.
+pub struct SendDedup {
+    window: Duration,
+    max: usize,
+    order: std::collections::VecDeque<(Instant, u64)>,
+    sent: HashMap<u64, (SocketAddr, Vec<u8>)>,
+}
+
+impl SendDedup {
+    pub fn new(window: Duration, max: usize) -> Self {
+        Self {
+            window,
+            max,
+            order: Default::default(),
+            sent: HashMap::new(),
+        }
+    }
+
+    /// Whether `bytes` to `addr` should be sent at `now`; false only for an exact
+    /// repeat of a send within the window
+    pub fn admit(&mut self, addr: SocketAddr, bytes: &[u8], now: Instant) -> bool {
+        if self.window.is_zero() || self.max == 0 {
+            return true;
+        }
+        while let Some((at, key)) = self.order.front().copied() {
+            if now.duration_since(at) < self.window && self.order.len() < self.max {
+                break;
+            }
+            self.order.pop_front();
+            self.sent.remove(&key);
+        }
+        let mut h = std::collections::hash_map::DefaultHasher::new();
+        (addr, bytes).hash(&mut h);
+        let key = h.finish();
+        if let Some((a, b)) = self.sent.get(&key) {
+            if *a == addr && b == bytes {
+                return false;
+            }
+        }
+        self.sent.insert(key, (addr, bytes.to_vec()));
+        self.order.push_back((now, key));
+        true
+    }
+
+    /// Sends currently remembered, at most `max`
+    pub fn tracked(&self) -> usize {
+        self.order.len()
+    }
+}
+
+/// What becomes of a queued udp send
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendOutcome {
+    Sent,
+    /// Sent on a retry
+    Recovered,
+    /// The socket buffer is momentarily full: try again after this long
+    Retry(Duration),
+    /// Still full after UDP_SEND_RETRIES retries
+    Dropped,
+    /// Refused for this destination only (no route, wrong family, firewall)
+    Unreachable,
+    /// The socket itself is broken, it is recreated
+    SocketFailed,
+}
 
-#[derive(Clone)]
-struct Inner {
-    serial: i32,
-    version: String,
-    software_url: String,
-    mask: Option<Ipv4Network>,
-    local_ip: String,
-    sk: Option<sign::SecretKey>,
+impl SendOutcome {
+    /// The hbbs_udp_send_failures_total outcome, None for a plain send
+    fn label(&self) -> Option<&'static str> {
+        match self {
+            SendOutcome::Sent => None,
+            SendOutcome::Recovered => Some("recovered"),
+            SendOutcome::Retry(_) => Some("retried"),
+            SendOutcome::Dropped => Some("dropped"),
+            SendOutcome::Unreachable => Some("unreachable"),
+            SendOutcome::SocketFailed => Some("fatal"),
+        }
+    }
 }
 
-#[derive(Clone)]
-pub struct RendezvousServer {
-    tcp_punch: Arc<Mutex<HashMap<SocketAddr, Sink>>>,
-    pm: PeerMap,
-    tx: Sender,
-    relay_servers: Arc<RelayServers>,
-    relay_servers0: Arc<RelayServers>,
-    rendezvous_servers: Arc<Vec<String>>,
-    inner: Arc<Inner>,
+/// Sort the result of the `attempt`th udp send (0 for the first): only a
+/// broken socket fails the loop, anything tied to the destination drops the
+/// message, a full buffer is retried with backoff
+pub fn udp_send_outcome(res: &ResultType<()>, attempt: u32) -> SendOutcome {
+    #[cfg(target_os = "linux")]
+    const ERRNO: (i32, i32, i32) = (105, 9, 88); // ENOBUFS, EBADF, ENOTSOCK
+    #[cfg(target_os = "macos")]
+    const ERRNO: (i32, i32, i32) = (55, 9, 38);
+    #[cfg(windows)]
+    const ERRNO: (i32, i32, i32) = (10055, 10009, 10038); // WSAENOBUFS, WSAEBADF, WSAENOTSOCK
+    #[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+    const ERRNO: (i32, i32, i32) = (-1, -1, -1);
+    let (enobufs, ebadf, enotsock) = ERRNO;
+    let err = match res {
+        Ok(()) if attempt > 0 => return SendOutcome::Recovered,
+        Ok(()) => return SendOutcome::Sent,
+        Err(err) => err,
+    };
+    let io = match err.downcast_ref::<std::io::Error>() {
+        Some(io) => io,
+        None => return SendOutcome::Unreachable,
+    };
+    let code = io.raw_os_error();
+    if code == Some(ebadf) || code == Some(enotsock) {
+        SendOutcome::SocketFailed
+    } else if io.kind() != std::io::ErrorKind::WouldBlock && code != Some(enobufs) {
+        SendOutcome::Unreachable
+    } else if attempt >= UDP_SEND_RETRIES {
+        SendOutcome::Dropped
+    } else {
+        SendOutcome::Retry(Duration::from_micros(UDP_SEND_BACKOFF_US << attempt))
+    }
 }
 
 enum LoopFailure {
@@ -90,24 +4669,94 @@ enum LoopFailure {
 }
 
 impl RendezvousServer {
+    pub fn start(port: i32, serial: i32, key: &str, rmem: usize) -> ResultType<()> {
+        Self::start_with_clock(port, serial, key, rmem, Arc::new(SystemClock))
+    }
+
+    /// `start` with the peer timing on `clock`, for tests that let timeouts pass
+    /// without sleeping
     #[tokio::main(flavor = "multi_thread")]
-    pub async fn start(port: i32, serial: i32, key: &str, rmem: usize) -> ResultType<()> {
+    pub async fn start_with_clock(
+        port: i32,
+        serial: i32,
+        key: &str,
+        rmem: usize,
+        clock: SharedClock,
+    ) -> ResultType<()> {
+        let raw_key = key.to_owned();
         let (key, sk) = Self::get_server_sk(key);
         let nat_port = port - 1;
         let ws_port = port + 2;
-        let pm = PeerMap::new().await?;
+        let pm = PeerMap::new(clock).await?;
+        // withdrawn from the API when start returns or unwinds
+        let _share = share_peer_map(&pm);
         log::info!("serial={}", serial);
+        SERIAL.store(serial, Ordering::Relaxed);
         let rendezvous_servers = get_servers(&get_arg("rendezvous-servers"), "rendezvous-servers");
         log::info!("Listening on tcp/udp :{}", port);
         log::info!("Listening on tcp :{}, extra port for NAT test", nat_port);
         log::info!("Listening on websocket :{}", ws_port);
         let mut socket = create_udp_listener(port, rmem).await?;
+        supervise("udp_drops", udp_drop_loop);
         let (tx, mut rx) = mpsc::unbounded_channel::<Data>();
         let software_url = get_arg("software-url");
         let version = hbb_common::get_version_from_url(&software_url);
         if !version.is_empty() {
             log::info!("software_url: {}, version: {}", software_url, version);
         }
+        let policy = get_arg_or("pk-change-policy", "auto".to_owned());
+        match PkChangePolicy::parse(&policy) {
+            Some(policy) => set_pk_change_policy(policy),
+            None => bail!(
+                "Invalid pk-change-policy {}, expected auto, approve or reject",
+                policy
+            ),
+        }
+        log::info!("pk-change-policy={}", pk_change_policy().as_str());
+        let policy = get_arg_or("rebind-policy", "rebind".to_owned());
+        match RebindPolicy::parse(&policy) {
+            Some(policy) => set_rebind_policy(policy),
+            None => bail!(
+                "Invalid rebind-policy {}, expected strict, rebind or rebind-any",
+                policy
+            ),
+        }
+        log::info!("rebind-policy={}", rebind_policy().as_str());
+        let deprecated = get_arg("deprecated-versions");
+        set_deprecated_versions(&deprecated);
+        if !deprecated.is_empty() {
+            log::info!("deprecated-versions={}", deprecated);
+        }
+        let mode = get_arg_or("relay-mode", "rotation".to_owned());
+        match RelayMode::parse(&mode) {
+            Some(mode) => set_relay_mode(mode),
+            None => bail!(
+                "Invalid relay-mode {}, expected rotation, sticky or latency",
+                mode
+            ),
+        }
+        log::info!("relay-mode={}", relay_mode().as_str());
+        let fallback = get_arg_or("relay-binding-fallback", "shared".to_owned());
+        let fallback = match BindingFallback::parse(&fallback) {
+            Some(fallback) => fallback,
+            None => bail!(
+                "Invalid relay-binding-fallback {}, expected shared or fail",
+                fallback
+            ),
+        };
+        match RelayBindings::parse(&get_arg("relay-bindings"), fallback) {
+            Ok(bindings) => {
+                if !bindings.is_empty() {
+                    log::info!(
+                        "relay-bindings: {:?}, relay-binding-fallback={}",
+                        bindings.bindings,
+                        fallback.as_str()
+                    );
+                }
+                set_relay_bindings(bindings);
+            }
+            Err(e) => bail!("Invalid relay-bindings: {}", e),
+        }
         let mask = get_arg("mask").parse().ok();
         let local_ip = if mask.is_none() {
             "".to_owned()
@@ -119,8 +4768,12 @@ impl RendezvousServer {
                     .unwrap_or_default(),
             )
         };
+        let tcp_punch: Arc<Mutex<HashMap<SocketAddr, Sink>>> = Default::default();
+        if let Ok(mut share) = TCP_PUNCH_SHARE.lock() {
+            *share = Some(tcp_punch.clone());
+        }
         let mut rs = Self {
-            tcp_punch: Arc::new(Mutex::new(HashMap::new())),
+            tcp_punch,
             pm,
             tx: tx.clone(),
             relay_servers: Default::default(),
@@ -135,13 +4788,49 @@ impl RendezvousServer {
                 local_ip,
             }),
         };
+        let (verify_tx, verify_rx) = mpsc::unbounded_channel();
+        if let Ok(mut lock) = VERIFY_REQUESTS.lock() {
+            *lock = Some(verify_tx);
+        }
+        tokio::spawn(consistency_loop(rs.pm.clone(), verify_rx));
+        let config = ServerConfig {
+            software_url: rs.inner.software_url.clone(),
+            local_ip: rs.inner.local_ip.clone(),
+            relay_mode: relay_mode(),
+            pk_change_policy: pk_change_policy(),
+            rebind_policy: rebind_policy(),
+            ..ServerConfig::from_args(&port.to_string(), &raw_key).0
+        };
+        let (reload_tx, reload_rx) = mpsc::unbounded_channel();
+        if let Ok(mut lock) = RELOAD_REQUESTS.lock() {
+            *lock = Some(reload_tx);
+        }
+        if let Ok(mut lock) = SERIAL_REQUESTS.lock() {
+            *lock = Some(tx.clone());
+        }
+        publish_config(&config);
+        tokio::spawn(reload_loop(config, tx.clone(), rs.pm.db.clone(), reload_rx));
         log::info!("mask: {:?}", rs.inner.mask);
         log::info!("local-ip: {:?}", rs.inner.local_ip);
         std::env::set_var("PORT_FOR_API", port.to_string());
+        match rs.pm.db.notice_ids().await {
+            Ok(ids) => notice_queued(&ids.iter().map(|x| x.as_str()).collect::<Vec<_>>()),
+            Err(e) => log::warn!("Failed to load peer notices: {}", e),
+        }
+        match rs.pm.db.relay_health().await {
+            Ok(snapshot) => seed_relay_health(snapshot),
+            Err(e) => log::warn!("Failed to load relay health: {}", e),
+        }
         rs.parse_relay_servers(&get_arg("relay-servers"));
+        let single_port = get_flag("single-port");
         let mut listener = create_tcp_listener(port).await?;
         let mut listener2 = create_tcp_listener(nat_port).await?;
-        let mut listener3 = create_tcp_listener(ws_port).await?;
+        let mut listener3 = if single_port {
+            log::info!("single-port: websocket and tcp share :{}", port);
+            None
+        } else {
+            Some(create_tcp_listener(ws_port).await?)
+        };
         let test_addr = std::env::var("TEST_HBBS").unwrap_or_default();
         if std::env::var("ALWAYS_USE_RELAY")
             .unwrap_or_default()
@@ -159,27 +4848,30 @@ impl RendezvousServer {
             }
         );
         if test_addr.to_lowercase() != "no" {
-            let test_addr = if test_addr.is_empty() {
+            let mut test_addr = if test_addr.is_empty() {
                 listener.local_addr()?
             } else {
                 test_addr.parse()?
             };
             tokio::spawn(async move {
-                if let Err(err) = test_hbbs(test_addr).await {
-                    if test_addr.is_ipv6() && test_addr.ip().is_unspecified() {
-                        let mut test_addr = test_addr;
-                        test_addr.set_ip(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
-                        if let Err(err) = test_hbbs(test_addr).await {
-                            log::error!("Failed to run hbbs test with {test_addr}: {err}");
-                            std::process::exit(1);
-                        }
-                    } else {
-                        log::error!("Failed to run hbbs test with {test_addr}: {err}");
-                        std::process::exit(1);
-                    }
+                let timeout = Duration::from_secs(SELF_TEST_TIMEOUT_SECS);
+                let mut res = test_hbbs(test_addr, timeout, &READINESS).await;
+                if res.is_err() && test_addr.is_ipv6() && test_addr.ip().is_unspecified() {
+                    test_addr.set_ip(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+                    res = test_hbbs(test_addr, timeout, &READINESS).await;
+                }
+                if let Err(err) = res {
+                    log::error!("Failed to run hbbs test with {test_addr}: {err}");
+                    // the binary exits on this, once health has shown it for a moment
+                    READINESS.send_replace(Readiness::Down(format!(
+                        "udp self-test with {}: {}",
+                        test_addr, err
+                    )));
                 }
             });
-        };
+        } else {
+            READINESS.send_replace(Readiness::Ready);
+        }
         let main_task = async move {
             loop {
                 log::info!("Start");
@@ -191,6 +4883,7 @@ impl RendezvousServer {
                         &mut listener3,
                         &mut socket,
                         &key,
+                        single_port,
                     )
                     .await
                 {
@@ -208,15 +4901,14 @@ impl RendezvousServer {
                     }
                     LoopFailure::Listener3 => {
                         drop(listener3);
-                        listener3 = create_tcp_listener(ws_port).await?;
+                        listener3 = Some(create_tcp_listener(ws_port).await?);
                     }
                 }
             }
         };
-        let listen_signal = listen_signal();
         tokio::select!(
             res = main_task => res,
-            res = listen_signal => res,
+            res = shutdown_signal() => res,
         )
     }
 
@@ -225,27 +4917,84 @@ impl RendezvousServer {
         rx: &mut Receiver,
         listener: &mut TcpListener,
         listener2: &mut TcpListener,
-        listener3: &mut TcpListener,
+        listener3: &mut Option<TcpListener>,
         socket: &mut FramedSocket,
         key: &str,
+        single_port: bool,
     ) -> LoopFailure {
         let mut timer_check_relay = interval(Duration::from_millis(CHECK_RELAY_TIMEOUT));
+        let mut timer_stats = periodic("STATS_INTERVAL_SECS", STATS_INTERVAL_SECS);
+        let mut dedup = SendDedup::new(
+            Duration::from_millis(env_u64("UDP_DEDUP_WINDOW_MS", UDP_DEDUP_WINDOW_MS)),
+            UDP_DEDUP_MAX,
+        );
         loop {
             tokio::select! {
-                _ = timer_check_relay.tick() => {
-                    if self.relay_servers0.len() > 1 {
+                _ = tick_opt(&mut timer_stats) => {
+                    let pm = self.pm.clone();
+                    tokio::spawn(async move {
+                        let started = Instant::now();
+                        let stats = pm.get_stats().await;
+                        STATS_TIMING.observe(started.elapsed());
+                        log::info!(
+                            "Peer Statistics: Total={}, Healthy={}, Degraded={}, Critical={}",
+                            stats.total, stats.healthy, stats.degraded, stats.critical
+                        );
+                        if let Ok(mut lock) = PEER_STATS_SNAPSHOT.write() {
+                            *lock = stats;
+                        }
+                        check_memory_caps();
+                    });
+                }
+                scheduled = timer_check_relay.tick() => {
+                    note_io_loop_tick(scheduled.into_std());
+                    let bound = relay_bindings().relays();
+                    if (self.relay_servers0.len() > 1 || !bound.is_empty())
+                        && !RELAY_CHECK_RUNNING.swap(true, Ordering::SeqCst)
+                    {
                         let rs = self.relay_servers0.clone();
                         let tx = self.tx.clone();
+                        let db = self.pm.db.clone();
                         tokio::spawn(async move {
-                            check_relay_servers(rs, tx).await;
+                            check_relay_servers(rs, bound, tx, db).await;
+                            RELAY_CHECK_RUNNING.store(false, Ordering::SeqCst);
                         });
                     }
                 }
                 Some(data) = rx.recv() => {
                     match data {
-                        Data::Msg(msg, addr) => { allow_err!(socket.send(msg.as_ref(), addr).await); }
+                        Data::Msg(msg, addr) => {
+                            let fresh = match msg.write_to_bytes() {
+                                Ok(bytes) => dedup.admit(addr, &bytes, Instant::now()),
+                                Err(_) => true,
+                            };
+                            if !fresh {
+                                UDP_QUEUED_SENDS.inc("coalesced");
+                            } else {
+                                UDP_QUEUED_SENDS.inc("sent");
+                                if !self.send_queued(socket, msg, addr, 0).await {
+                                    return LoopFailure::UdpSocket;
+                                }
+                            }
+                        }
+                        Data::MsgRetry(msg, addr, attempt) => {
+                            if !self.send_queued(socket, msg, addr, attempt).await {
+                                return LoopFailure::UdpSocket;
+                            }
+                        }
                         Data::RelayServers0(rs) => { self.parse_relay_servers(&rs); }
-                        Data::RelayServers(rs) => { self.relay_servers = Arc::new(rs); }
+                        Data::RelayServers(rs) => {
+                            if let Ok(mut lock) = RELAY_HEALTH.write() {
+                                lock.1 = rs.clone();
+                            }
+                            self.relay_servers = Arc::new(rs);
+                        }
+                        Data::Serial(serial, by, reply) => {
+                            reply.send(self.bump_serial(serial, by).await).ok();
+                        }
+                        Data::SimulatePunch(sim, reply) => {
+                            reply.send(self.simulate_punch(sim, key).await).ok();
+                        }
                     }
                 }
                 res = socket.next() => {
@@ -277,7 +5026,7 @@ impl RendezvousServer {
                         }
                     }
                 }
-                res = listener3.accept() => {
+                res = accept_opt(listener3) => {
                     match res {
                         Ok((stream, addr))  => {
                             stream.set_nodelay(true).ok();
@@ -293,7 +5042,11 @@ impl RendezvousServer {
                     match res {
                         Ok((stream, addr)) => {
                             stream.set_nodelay(true).ok();
-                            self.handle_listener(stream, addr, key, false).await;
+                            if single_port {
+                                self.handle_single_port(stream, addr, key).await;
+                            } else {
+                                self.handle_listener(stream, addr, key, false).await;
+                            }
                         }
                        Err(err) => {
                            log::error!("listener.accept failed: {}", err);
@@ -305,6 +5058,56 @@ impl RendezvousServer {
         }
     }
 
+    /// Send a queued udp message. A transient failure is handed to a retry task that
+    /// re-queues it after a short sleep, so the io loop never waits; returns false on
+    /// a fatal failure, which recreates the socket.
+    async fn send_queued(
+        &self,
+        socket: &mut FramedSocket,
+        msg: Box<RendezvousMessage>,
+        addr: SocketAddr,
+        attempt: u32,
+    ) -> bool {
+        if attempt == 0 {
+            trace_message("out", Transport::Udp, addr, &msg);
+        }
+        let res = socket.send(msg.as_ref(), addr).await;
+        let outcome = udp_send_outcome(&res, attempt);
+        if let Some(label) = outcome.label() {
+            UDP_SEND_FAILURES.inc(label);
+        }
+        let err = match res {
+            Ok(()) => return true,
+            Err(err) => err,
+        };
+        match outcome {
+            SendOutcome::Sent | SendOutcome::Recovered => {}
+            SendOutcome::Retry(backoff) => {
+                log::debug!("udp send to {} failed ({}), retrying", addr, err);
+                let tx = self.tx.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(backoff).await;
+                    tx.send(Data::MsgRetry(msg, addr, attempt + 1)).ok();
+                });
+            }
+            SendOutcome::Dropped => {
+                log::warn!(
+                    "udp send to {} dropped after {} retries: {}",
+                    addr,
+                    attempt,
+                    err
+                );
+            }
+            // a client can name any address, that is not the socket's fault
+            SendOutcome::Unreachable => log::warn!("udp send to {} failed: {}", addr, err),
+            SendOutcome::SocketFailed => {
+                log::error!("udp send to {} failed: {}", addr, err);
+                return false;
+            }
+        }
+        true
+    }
+
     #[inline]
     async fn handle_udp(
         &mut self,
@@ -313,13 +5116,21 @@ impl RendezvousServer {
         socket: &mut FramedSocket,
         key: &str,
     ) -> ResultType<()> {
+        note_udp_packet(addr, &*self.pm.clock);
+        if !message_size_ok(bytes, Transport::Udp) {
+            return Ok(());
+        }
         if let Ok(msg_in) = RendezvousMessage::parse_from_bytes(bytes) {
+            trace_message("in", Transport::Udp, addr, &msg_in);
             match msg_in.union {
-                Some(rendezvous_message::Union::RegisterPeer(rp)) => {
+                Some(rendezvous_message::Union::RegisterPeer(mut rp)) => {
                     // B registered
+                    canonicalize(&mut rp.id);
                     if !rp.id.is_empty() {
                         log::trace!("New peer registered: {:?} {:?}", &rp.id, &addr);
-                        self.update_addr(rp.id, addr, socket).await?;
+                        self.update_addr(rp.id.clone(), addr, socket).await?;
+                        self.pm.note_serial(&rp.id, rp.serial).await;
+                        self.pm.note_transport(&rp.id, Transport::Udp).await;
                         if self.inner.serial > rp.serial {
                             let mut msg_out = RendezvousMessage::new();
                             msg_out.set_configure_update(ConfigUpdate {
@@ -327,16 +5138,20 @@ impl RendezvousServer {
                                 rendezvous_servers: (*self.rendezvous_servers).clone(),
                                 ..Default::default()
                             });
-                            socket.send(&msg_out, addr).await?;
+                            send_udp(socket, &msg_out, addr).await?;
                         }
                     }
                 }
                 Some(rendezvous_message::Union::RegisterPk(rk)) => {
                     if rk.uuid.is_empty() || rk.pk.is_empty() {
+                        // not answered, as before, but logged and counted
+                        check_credentials(&rk.id, &rk.uuid, &rk.pk);
                         return Ok(());
                     }
-                    let id = rk.id;
-                    let old_id = rk.old_id;
+                    let mut id = rk.id;
+                    let mut old_id = rk.old_id;
+                    canonicalize(&mut id);
+                    canonicalize(&mut old_id);
                     let ip = addr.ip().to_string();
 
                     // =========================================================
@@ -348,24 +5163,47 @@ impl RendezvousServer {
                         if id.len() < 6 || id.len() > 16 {
                             // TODO: Use INVALID_ID_FORMAT when proto supports it
                             log::warn!("Invalid ID format for change: {}", id);
+                            record_error(
+                                ErrorReason::InvalidId,
+                                &old_id,
+                                addr,
+                                format!("new id length {}", id.len()),
+                            );
                             return send_rk_res(socket, addr, UUID_MISMATCH).await;
                         }
                         if !id.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
                             log::warn!("Invalid ID characters for change: {}", id);
+                            record_error(
+                                ErrorReason::InvalidId,
+                                &old_id,
+                                addr,
+                                "new id has invalid characters",
+                            );
                             return send_rk_res(socket, addr, UUID_MISMATCH).await;
                         }
                         if !self.check_ip_blocker(&ip, &old_id).await {
+                            record_error(ErrorReason::TooFrequent, &old_id, addr, "ip blocker");
                             return send_rk_res(socket, addr, TOO_FREQUENT).await;
                         }
+                        if draining(DrainMode::Registrations) {
+                            record_error(ErrorReason::TooFrequent, &old_id, addr, "draining");
+                            return send_rk_res(socket, addr, TOO_FREQUENT).await;
+                        }
+                        let new_id = id.clone();
                         let result = self.pm.change_id(
                             old_id, id, addr, rk.uuid, rk.pk, ip
                         ).await;
+                        emit_event(EventKind::Registration {
+                            id: new_id,
+                            ip: try_into_v4(addr).ip().to_string(),
+                            result: count_registration(result),
+                        });
                         let mut msg_out = RendezvousMessage::new();
                         msg_out.set_register_pk_response(RegisterPkResponse {
                             result: result.into(),
                             ..Default::default()
                         });
-                        socket.send(&msg_out, addr).await?;
+                        send_udp(socket, &msg_out, addr).await?;
                         return Ok(());
                     }
 
@@ -373,8 +5211,18 @@ impl RendezvousServer {
                     // Normal registration flow
                     // =========================================================
                     if id.len() < 6 {
+                        record_error(
+                            ErrorReason::InvalidId,
+                            &id,
+                            addr,
+                            format!("id length {}", id.len()),
+                        );
                         return send_rk_res(socket, addr, UUID_MISMATCH).await;
                     } else if !self.check_ip_blocker(&ip, &id).await {
+                        record_error(ErrorReason::TooFrequent, &id, addr, "ip blocker");
+                        return send_rk_res(socket, addr, TOO_FREQUENT).await;
+                    } else if !check_uuid_churn(&ip, &id, &rk.uuid, &*self.pm.clock).await {
+                        record_error(ErrorReason::TooFrequent, &id, addr, "uuid churn");
                         return send_rk_res(socket, addr, TOO_FREQUENT).await;
                     }
                     let peer = self.pm.get_or(&id).await;
@@ -383,8 +5231,8 @@ impl RendezvousServer {
                         if peer.uuid.is_empty() {
                             (true, false)
                         } else {
-                            if peer.uuid == rk.uuid {
-                                if peer.info.ip != ip && peer.pk != rk.pk {
+                            if ct_eq(&peer.uuid, &rk.uuid) {
+                                if peer.info.ip != ip && !ct_eq(&peer.pk, &rk.pk) {
                                     log::warn!(
                                         "Peer {} ip/pk mismatch: {}/{:?} vs {}/{:?}",
                                         id,
@@ -394,6 +5242,12 @@ impl RendezvousServer {
                                         peer.pk,
                                     );
                                     drop(peer);
+                                    record_error(
+                                        ErrorReason::PkMismatch,
+                                        &id,
+                                        addr,
+                                        "pk changed together with ip",
+                                    );
                                     return send_rk_res(socket, addr, UUID_MISMATCH).await;
                                 }
                             } else {
@@ -404,29 +5258,44 @@ impl RendezvousServer {
                                     peer.uuid
                                 );
                                 drop(peer);
+                                record_error(
+                                    ErrorReason::UuidMismatch,
+                                    &id,
+                                    addr,
+                                    "uuid differs from the registered one",
+                                );
                                 return send_rk_res(socket, addr, UUID_MISMATCH).await;
                             }
                             let ip_changed = peer.info.ip != ip;
-                            (
-                                peer.uuid != rk.uuid || peer.pk != rk.pk || ip_changed,
-                                ip_changed,
-                            )
+                            (!ct_eq(&peer.pk, &rk.pk) || ip_changed, ip_changed)
                         }
                     };
+                    // a known peer with the same key and ip only refreshes
+                    if changed && draining(DrainMode::Registrations) {
+                        record_error(ErrorReason::TooFrequent, &id, addr, "draining");
+                        return send_rk_res(socket, addr, TOO_FREQUENT).await;
+                    }
+                    let now = self.pm.clock.now_instant();
                     let mut req_pk = peer.read().await.reg_pk;
-                    if req_pk.1.elapsed().as_secs() > 6 {
+                    if now.duration_since(req_pk.1).as_secs() > 6 {
                         req_pk.0 = 0;
                     } else if req_pk.0 > 2 {
+                        record_error(
+                            ErrorReason::TooFrequent,
+                            &id,
+                            addr,
+                            "more than 2 RegisterPk within 6s",
+                        );
                         return send_rk_res(socket, addr, TOO_FREQUENT).await;
                     }
                     req_pk.0 += 1;
-                    req_pk.1 = Instant::now();
+                    req_pk.1 = now;
                     peer.write().await.reg_pk = req_pk;
                     if ip_changed {
                         let mut lock = IP_CHANGES.lock().await;
                         if let Some((tm, ips)) = lock.get_mut(&id) {
-                            if tm.elapsed().as_secs() > IP_CHANGE_DUR {
-                                *tm = Instant::now();
+                            if now.duration_since(*tm).as_secs() > IP_CHANGE_DUR {
+                                *tm = now;
                                 ips.clear();
                                 ips.insert(ip.clone(), 1);
                             } else if let Some(v) = ips.get_mut(&ip) {
@@ -435,47 +5304,111 @@ impl RendezvousServer {
                                 ips.insert(ip.clone(), 1);
                             }
                         } else {
-                            lock.insert(
-                                id.clone(),
-                                (Instant::now(), HashMap::from([(ip.clone(), 1)])),
-                            );
+                            lock.insert(id.clone(), (now, HashMap::from([(ip.clone(), 1)])));
                         }
                     }
-                    if changed {
-                        self.pm.update_pk(id.clone(), peer, addr, rk.uuid, rk.pk, ip).await;
+                    let moved = {
+                        let stored = peer.read().await.socket_addr;
+                        stored.port() != 0 && stored != addr
+                    };
+                    let res = if changed {
+                        self.pm
+                            .update_pk(id.clone(), peer.clone(), addr, rk.uuid, rk.pk, ip)
+                            .await
                     } else {
+                        // uuid and pk match: the address a RegisterPeer was not
+                        // allowed to move to is verified now
+                        if moved {
+                            let mut w = peer.write().await;
+                            self.pm.index_addr(&id, w.socket_addr, addr).await;
+                            w.socket_addr = addr;
+                            w.last_reg_time = now;
+                        }
                         self.pm.touch_peer(&id).await;
+                        register_pk_response::Result::OK
+                    };
+                    if res == register_pk_response::Result::OK {
+                        if moved {
+                            REBINDS.inc("reverified");
+                        }
+                        if ipv6_only_client(addr.ip()) {
+                            IPV6_CLIENTS.inc("registered");
+                        }
+                        verified_ip(&mut *peer.write().await, addr.ip(), now);
+                        self.pm.note_transport(&id, Transport::Udp).await;
                     }
+                    emit_event(EventKind::Registration {
+                        id,
+                        ip: try_into_v4(addr).ip().to_string(),
+                        result: count_registration(res),
+                    });
                     let mut msg_out = RendezvousMessage::new();
                     msg_out.set_register_pk_response(RegisterPkResponse {
-                        result: register_pk_response::Result::OK.into(),
+                        result: res.into(),
                         ..Default::default()
                     });
-                    socket.send(&msg_out, addr).await?
+                    send_udp(socket, &msg_out, addr).await?
                 }
                 Some(rendezvous_message::Union::PunchHoleRequest(ph)) => {
-                    if self.pm.is_in_memory(&ph.id).await {
-                        self.handle_udp_punch_hole_request(addr, ph, key).await?;
+                    let initiator = self.pm.get_id_by_addr(addr).await;
+                    self.pm
+                        .note_version(
+                            ProtocolKind::PunchRequest,
+                            Transport::Udp,
+                            initiator.as_deref(),
+                            &ph.version,
+                        )
+                        .await;
+                    if initiator.is_none() && self.pm.is_in_memory(&canonical_id(&ph.id)).await {
+                        self.handle_udp_punch_hole_request(addr, ph, key, None)
+                            .await?;
                     } else {
-                        // not in memory, fetch from db with spawn in case blocking me
+                        // not in memory, or the initiator's ban to check: fetch from db
+                        // with spawn in case blocking me
                         let mut me = self.clone();
                         let key = key.to_owned();
                         tokio::spawn(async move {
-                            allow_err!(me.handle_udp_punch_hole_request(addr, ph, &key).await);
+                            allow_err!(
+                                me.handle_udp_punch_hole_request(addr, ph, &key, initiator)
+                                    .await
+                            );
                         });
                     }
                 }
                 Some(rendezvous_message::Union::PunchHoleSent(phs)) => {
+                    self.pm
+                        .note_version(
+                            ProtocolKind::PunchReply,
+                            Transport::Udp,
+                            Some(&canonical_id(&phs.id)),
+                            &phs.version,
+                        )
+                        .await;
                     self.handle_hole_sent(phs, addr, Some(socket)).await?;
                 }
                 Some(rendezvous_message::Union::LocalAddr(la)) => {
+                    self.pm
+                        .note_version(
+                            ProtocolKind::PunchReply,
+                            Transport::Udp,
+                            Some(&canonical_id(&la.id)),
+                            &la.version,
+                        )
+                        .await;
                     self.handle_local_addr(la, addr, Some(socket)).await?;
                 }
                 Some(rendezvous_message::Union::ConfigureUpdate(mut cu)) => {
                     if try_into_v4(addr).ip().is_loopback() && cu.serial > self.inner.serial {
-                        let mut inner: Inner = (*self.inner).clone();
-                        inner.serial = cu.serial;
-                        self.inner = Arc::new(inner);
+                        let detail = serde_json::json!({
+                            "old": self.inner.serial,
+                            "new": cu.serial,
+                            "by": "loopback ConfigureUpdate",
+                        });
+                        self.set_serial(cu.serial);
+                        self.pm
+                            .db
+                            .audit("loopback", "serial_bump", "", detail.to_string())
+                            .await;
                         self.rendezvous_servers = Arc::new(
                             cu.rendezvous_servers
                                 .drain(..)
@@ -483,6 +5416,7 @@ impl RendezvousServer {
                                     !x.is_empty()
                                         && test_if_valid_server(x, "rendezvous-server").is_ok()
                                 })
+                                .take(max_relay_servers())
                                 .collect(),
                         );
                         log::info!(
@@ -499,7 +5433,7 @@ impl RendezvousServer {
                             url: self.inner.software_url.clone(),
                             ..Default::default()
                         });
-                        socket.send(&msg_out, addr).await?;
+                        send_udp(socket, &msg_out, addr).await?;
                     }
                 }
                 _ => {}
@@ -516,15 +5450,70 @@ impl RendezvousServer {
         addr: SocketAddr,
         key: &str,
         ws: bool,
+        conn_peer: &mut Option<String>,
     ) -> bool {
+        let transport = if ws { Transport::Ws } else { Transport::Tcp };
+        if !message_size_ok(bytes, transport) {
+            return false;
+        }
         if let Ok(msg_in) = RendezvousMessage::parse_from_bytes(bytes) {
+            trace_message("in", transport, addr, &msg_in);
             match msg_in.union {
+                Some(rendezvous_message::Union::RegisterPeer(mut rp)) => {
+                    // Peers that keep a rendezvous connection open use it as their liveness
+                    // signal, so closing it takes them offline right away (see listener loop)
+                    canonicalize(&mut rp.id);
+                    if rp.id.is_empty() {
+                        return false;
+                    }
+                    let registered_from = match self.pm.get(&rp.id).await {
+                        Some(peer) => peer.read().await.socket_addr,
+                        None => return false,
+                    };
+                    // RegisterPeer carries no credentials: the connection only speaks for
+                    // the peer from the address it registered from, or after a RegisterPk
+                    // with its uuid and key (below). Anyone else could take it offline by
+                    // closing the connection, or punch in its name.
+                    if conn_peer.as_deref() != Some(rp.id.as_str())
+                        && try_into_v4(registered_from).ip() != try_into_v4(addr).ip()
+                    {
+                        log::warn!(
+                            "{} registration as {} from {} ignored: registered from {}",
+                            transport.as_str(),
+                            rp.id,
+                            addr,
+                            registered_from
+                        );
+                        return true;
+                    }
+                    self.pm.touch_peer(&rp.id).await;
+                    self.pm.note_serial(&rp.id, rp.serial).await;
+                    self.pm.note_transport(&rp.id, transport).await;
+                    *conn_peer = Some(rp.id);
+                    return true;
+                }
                 Some(rendezvous_message::Union::PunchHoleRequest(ph)) => {
                     // there maybe several attempt, so sink can be none
                     if let Some(sink) = sink.take() {
                         self.tcp_punch.lock().await.insert(try_into_v4(addr), sink);
                     }
-                    allow_err!(self.handle_tcp_punch_hole_request(addr, ph, key, ws).await);
+                    // the id this connection registered as, else whoever last registered from addr
+                    let initiator = match conn_peer.clone() {
+                        Some(id) => Some(id),
+                        None => self.pm.get_id_by_addr(addr).await,
+                    };
+                    self.pm
+                        .note_version(
+                            ProtocolKind::PunchRequest,
+                            transport,
+                            initiator.as_deref(),
+                            &ph.version,
+                        )
+                        .await;
+                    allow_err!(
+                        self.handle_tcp_punch_hole_request(addr, ph, key, ws, initiator)
+                            .await
+                    );
                     return true;
                 }
                 Some(rendezvous_message::Union::RequestRelay(mut rf)) => {
@@ -532,11 +5521,36 @@ impl RendezvousServer {
                     if let Some(sink) = sink.take() {
                         self.tcp_punch.lock().await.insert(try_into_v4(addr), sink);
                     }
-                    if let Some(peer) = self.pm.get_in_memory(&rf.id).await {
+                    if draining(DrainMode::Punch) {
+                        log::info!("Relay request to {} from {} refused: draining", rf.id, addr);
+                        let mut msg_out = RendezvousMessage::new();
+                        msg_out.set_relay_response(RelayResponse {
+                            uuid: rf.uuid,
+                            refuse_reason: "Server is draining, try again shortly".to_owned(),
+                            ..Default::default()
+                        });
+                        self.send_to_tcp(msg_out, addr).await;
+                        return true;
+                    }
+                    // forwarded as sent, only the lookup is canonical
+                    if let Some(peer) = self.pm.get_in_memory(&canonical_id(&rf.id)).await {
+                        let peer_addr = peer.read().await.socket_addr;
+                        let reason = if ws {
+                            RelayReason::Websocket
+                        } else if try_into_v4(addr).is_ipv4() != try_into_v4(peer_addr).is_ipv4() {
+                            RelayReason::AddrFamily
+                        } else if try_into_v4(addr).ip() == try_into_v4(peer_addr).ip() {
+                            RelayReason::SameNatHairpin
+                        } else if punch_timed_out(addr, &canonical_id(&rf.id)) {
+                            RelayReason::PunchTimeout
+                        } else {
+                            RelayReason::ClientRequest
+                        };
+                        let pool = relay_pool_of(&rf.relay_server);
+                        record_relay_reason(reason, &rf.id, addr, &rf.relay_server, &pool).await;
                         let mut msg_out = RendezvousMessage::new();
                         rf.socket_addr = AddrMangle::encode(addr).into();
                         msg_out.set_request_relay(rf);
-                        let peer_addr = peer.read().await.socket_addr;
                         self.tx.send(Data::Msg(msg_out.into(), peer_addr)).ok();
                     }
                     return true;
@@ -555,16 +5569,33 @@ impl RendezvousServer {
                             // https://github.com/rustdesk/rustdesk-server/issues/24
                             rr.relay_server = self.inner.local_ip.clone();
                         } else if rr.relay_server == self.inner.local_ip {
-                            rr.relay_server = self.get_relay_server(addr.ip(), addr_b.ip());
+                            let target = rr.id().to_owned();
+                            rr.relay_server = self.get_relay_server(addr_b.ip(), &target).await;
                         }
                     }
                     msg_out.set_relay_response(rr);
                     allow_err!(self.send_to_tcp_sync(msg_out, addr_b).await);
                 }
                 Some(rendezvous_message::Union::PunchHoleSent(phs)) => {
+                    self.pm
+                        .note_version(
+                            ProtocolKind::PunchReply,
+                            transport,
+                            Some(&canonical_id(&phs.id)),
+                            &phs.version,
+                        )
+                        .await;
                     allow_err!(self.handle_hole_sent(phs, addr, None).await);
                 }
                 Some(rendezvous_message::Union::LocalAddr(la)) => {
+                    self.pm
+                        .note_version(
+                            ProtocolKind::PunchReply,
+                            transport,
+                            Some(&canonical_id(&la.id)),
+                            &la.version,
+                        )
+                        .await;
                     allow_err!(self.handle_local_addr(la, addr, None).await);
                 }
                 Some(rendezvous_message::Union::TestNatRequest(tar)) => {
@@ -580,16 +5611,34 @@ impl RendezvousServer {
                         res.cu = MessageField::from_option(Some(cu));
                     }
                     msg_out.set_test_nat_response(res);
-                    Self::send_to_sink(sink, msg_out).await;
+                    Self::send_to_sink(sink, msg_out, addr).await;
                 }
-                Some(rendezvous_message::Union::RegisterPk(_)) => {
-                    let res = register_pk_response::Result::NOT_SUPPORT;
+                Some(rendezvous_message::Union::RegisterPk(mut rk)) => {
+                    // keys are registered over udp; here matching credentials only bind
+                    // the connection to its peer, from any address
+                    canonicalize(&mut rk.id);
+                    let proven = match self.pm.get(&rk.id).await {
+                        Some(peer) => {
+                            let peer = peer.read().await;
+                            !peer.uuid.is_empty()
+                                && ct_eq(&peer.uuid, &rk.uuid)
+                                && ct_eq(&peer.pk, &rk.pk)
+                        }
+                        None => false,
+                    };
+                    let res = if proven {
+                        *conn_peer = Some(rk.id);
+                        register_pk_response::Result::OK
+                    } else {
+                        register_pk_response::Result::NOT_SUPPORT
+                    };
                     let mut msg_out = RendezvousMessage::new();
                     msg_out.set_register_pk_response(RegisterPkResponse {
                         result: res.into(),
                         ..Default::default()
                     });
-                    Self::send_to_sink(sink, msg_out).await;
+                    Self::send_to_sink(sink, msg_out, addr).await;
+                    return proven;
                 }
                 _ => {}
             }
@@ -598,26 +5647,51 @@ impl RendezvousServer {
     }
 
     #[inline]
+    fn set_serial(&mut self, serial: i32) {
+        let mut inner: Inner = (*self.inner).clone();
+        inner.serial = serial;
+        self.inner = Arc::new(inner);
+        SERIAL.store(serial, Ordering::Relaxed);
+    }
+
+    /// The API's serial bump: above the current one, pushed to every peer that
+    /// registers with an older serial from now on
+    async fn bump_serial(&mut self, serial: Option<i32>, by: String) -> Result<SerialBump, String> {
+        let old = self.inner.serial;
+        let new = match serial {
+            Some(new) if new > old => new,
+            Some(new) => return Err(format!("Serial {} is not above the current {}", new, old)),
+            None => old
+                .checked_add(1)
+                .ok_or_else(|| format!("Serial {} cannot be raised further", old))?,
+        };
+        self.set_serial(new);
+        log::info!("Serial bumped {} -> {} by {}", old, new, by);
+        let detail = serde_json::json!({ "old": old, "new": new, "by": by });
+        self.pm
+            .db
+            .audit("api", "serial_bump", "", detail.to_string())
+            .await;
+        Ok(SerialBump { old, new })
+    }
+
     async fn update_addr(
         &mut self,
         id: String,
         socket_addr: SocketAddr,
         socket: &mut FramedSocket,
     ) -> ResultType<()> {
+        let now = self.pm.clock.now_instant();
         let (request_pk, ip_change) = if let Some(old) = self.pm.get_in_memory(&id).await {
             let mut old = old.write().await;
-            let ip = socket_addr.ip();
-            let ip_change = if old.socket_addr.port() != 0 {
-                ip != old.socket_addr.ip()
-            } else {
-                ip.to_string() != old.info.ip
-            } && !ip.is_loopback();
-            let request_pk = old.pk.is_empty() || ip_change;
-            if !request_pk {
-                old.socket_addr = socket_addr;
-                old.last_reg_time = Instant::now();
-            }
-            let ip_change = if ip_change && old.reg_pk.0 <= 2 {
+            let change = AddrChange::of(old.socket_addr, &old.info.ip, socket_addr);
+            let ip_verified = change == AddrChange::Ip
+                && old.verified_ips.iter().any(|(ip, tm)| {
+                    *ip == socket_addr.ip() && now.duration_since(*tm).as_secs() < VERIFIED_IP_SECS
+                });
+            let decision = rebind_decision(rebind_policy(), change, ip_verified);
+            count_rebind(change, decision);
+            let ip_change = if change == AddrChange::Ip && old.reg_pk.0 <= 2 {
                 Some(if old.socket_addr.port() == 0 {
                     old.info.ip.clone()
                 } else {
@@ -626,6 +5700,12 @@ impl RendezvousServer {
             } else {
                 None
             };
+            let request_pk = old.pk.is_empty() || decision == RebindDecision::Reverify;
+            if !request_pk {
+                self.pm.index_addr(&id, old.socket_addr, socket_addr).await;
+                old.socket_addr = socket_addr;
+                old.last_reg_time = now;
+            }
             (request_pk, ip_change)
         } else {
             (true, None)
@@ -635,12 +5715,102 @@ impl RendezvousServer {
         }
         // Update database status for this peer
         self.pm.touch_peer(&id).await;
-        let mut msg_out = RendezvousMessage::new();
-        msg_out.set_register_peer_response(RegisterPeerResponse {
+        let mut res = RegisterPeerResponse {
             request_pk,
             ..Default::default()
-        });
-        socket.send(&msg_out, socket_addr).await
+        };
+        if has_notices(&id) {
+            self.attach_notices(&id, &mut res).await;
+        }
+        if chrono::Utc::now().timestamp() < BROADCASTS_UNTIL.load(Ordering::SeqCst) {
+            self.attach_broadcasts(&id, &mut res).await;
+        }
+        let mut msg_out = RendezvousMessage::new();
+        msg_out.set_register_peer_response(res);
+        send_udp(socket, &msg_out, socket_addr).await
+    }
+
+    /// Hand the pending notices of `id` to its registration response, after
+    /// acknowledging those handed out before
+    async fn attach_notices(&self, id: &str, res: &mut RegisterPeerResponse) {
+        let generation = NOTICE_GEN.load(Ordering::SeqCst);
+        let (notices, waiting) = match self.pm.db.take_notices(id).await {
+            Ok(x) => x,
+            Err(e) => {
+                log::warn!("Failed to take notices of {}: {}", id, e);
+                return;
+            }
+        };
+        for x in notices {
+            log::info!("Delivering {} notice {} to {}", x.kind, x.seq, id);
+            let json = serde_json::to_vec(&x).unwrap_or_default();
+            res.mut_unknown_fields()
+                .add_length_delimited(NOTICE_FIELD, json);
+        }
+        if waiting {
+            return;
+        }
+        if let Ok(mut lock) = NOTICE_IDS.write() {
+            // a notice queued meanwhile keeps the id
+            if NOTICE_GEN.load(Ordering::SeqCst) == generation {
+                lock.remove(id);
+            }
+        }
+    }
+
+    /// Hand the broadcasts `id` has not seen yet to its registration response
+    async fn attach_broadcasts(&self, id: &str, res: &mut RegisterPeerResponse) {
+        let active = match active_broadcasts(&self.pm.db).await {
+            Ok(x) => x,
+            Err(e) => {
+                log::warn!("Failed to load broadcasts: {}", e);
+                return;
+            }
+        };
+        let now = chrono::Utc::now().timestamp();
+        for x in active.iter() {
+            if x.broadcast.expires_at <= now
+                || x.ids.as_ref().map_or(false, |ids| !ids.contains(id))
+            {
+                continue;
+            }
+            let first = match x.delivered.lock() {
+                Ok(mut delivered) => delivered.insert(id.to_owned()),
+                Err(_) => false,
+            };
+            if !first {
+                continue;
+            }
+            if let Err(e) = self
+                .pm
+                .db
+                .record_broadcast_delivery(x.broadcast.id, id)
+                .await
+            {
+                log::warn!(
+                    "Failed to record broadcast {} to {}: {}",
+                    x.broadcast.id,
+                    id,
+                    e
+                );
+            }
+            let notice = PeerNotice {
+                seq: 0,
+                peer_id: id.to_owned(),
+                ack_id: id.to_owned(),
+                kind: BROADCAST_NOTICE.to_owned(),
+                detail: serde_json::json!({
+                    "broadcast_id": x.broadcast.id,
+                    "message": x.broadcast.message,
+                    "expires_at": x.broadcast.expires_at,
+                }),
+                created_at: x.broadcast.created_at,
+                delivered_at: Some(now),
+            };
+            let json = serde_json::to_vec(&notice).unwrap_or_default();
+            res.mut_unknown_fields()
+                .add_length_delimited(NOTICE_FIELD, json);
+        }
     }
 
     #[inline]
@@ -670,7 +5840,7 @@ impl RendezvousServer {
         }
         msg_out.set_punch_hole_response(p);
         if let Some(socket) = socket {
-            socket.send(&msg_out, addr_a).await?;
+            send_udp(socket, &msg_out, addr_a).await?;
         } else {
             self.send_to_tcp(msg_out, addr_a).await;
         }
@@ -702,7 +5872,7 @@ impl RendezvousServer {
         p.set_is_local(true);
         msg_out.set_punch_hole_response(p);
         if let Some(socket) = socket {
-            socket.send(&msg_out, addr_a).await?;
+            send_udp(socket, &msg_out, addr_a).await?;
         } else {
             self.send_to_tcp(msg_out, addr_a).await;
         }
@@ -716,102 +5886,508 @@ impl RendezvousServer {
         ph: PunchHoleRequest,
         key: &str,
         ws: bool,
+        initiator: Option<String>,
     ) -> ResultType<(RendezvousMessage, Option<SocketAddr>)> {
-        let mut ph = ph;
-        if !key.is_empty() && ph.licence_key != key {
-            let mut msg_out = RendezvousMessage::new();
-            msg_out.set_punch_hole_response(PunchHoleResponse {
-                failure: punch_hole_response::Failure::LICENSE_MISMATCH.into(),
-                ..Default::default()
-            });
-            return Ok((msg_out, None));
+        let from = initiator.as_deref();
+        let decision = self.decide_punch_hole(addr, ph, key, ws, from, false).await;
+        let target = &decision.target;
+        let relays = &decision.relay_candidates;
+        punch_hole_attempt(decision.result, from, target, addr, relays);
+        if let Some((reason, id, detail)) = decision.error {
+            record_error(reason, &id, addr, detail);
+        }
+        if let Some(detail) = decision.audit {
+            self.pm
+                .db
+                .audit("server", "access_denied", target, detail)
+                .await;
+        }
+        if let Some((reason, relay_server)) = &decision.relay {
+            let pool = decision.pool.to_string();
+            record_relay_reason(*reason, target, addr, relay_server, &pool).await;
+        } else if decision.result == "ok" {
+            punch_forwarded(addr, target);
+        }
+        Ok((decision.msg, decision.to))
+    }
+
+    /// What the server answers a punch hole request from `addr` with, and why,
+    /// without sending anything or counting it. `dry_run` also leaves the relay
+    /// rotation and the "try again" answers of the database fallback as they are.
+    async fn decide_punch_hole(
+        &self,
+        addr: SocketAddr,
+        mut ph: PunchHoleRequest,
+        key: &str,
+        ws: bool,
+        from: Option<&str>,
+        dry_run: bool,
+    ) -> PunchDecision {
+        canonicalize(&mut ph.id);
+        let mut steps = Vec::new();
+        let mut step = |step: &'static str, outcome: String| {
+            steps.push(PunchStep { step, outcome });
+        };
+        let refuse = |result: &'static str, response: PunchHoleResponse| {
+            let mut msg = RendezvousMessage::new();
+            msg.set_punch_hole_response(response);
+            (result, msg)
+        };
+        let mut decision = PunchDecision {
+            target: ph.id.clone(),
+            result: "ok",
+            msg: RendezvousMessage::new(),
+            to: None,
+            error: None,
+            audit: None,
+            relay: None,
+            relay_candidates: RelayServers::new(),
+            pool: RelayPool::Shared,
+            steps: Vec::new(),
+        };
+
+        let refused = 'checks: {
+            if !key.is_empty() && ph.licence_key != key {
+                step("licence_key", "mismatch".to_owned());
+                break 'checks Some(refuse(
+                    "license_mismatch",
+                    PunchHoleResponse {
+                        failure: punch_hole_response::Failure::LICENSE_MISMATCH.into(),
+                        ..Default::default()
+                    },
+                ));
+            }
+            step(
+                "licence_key",
+                if key.is_empty() {
+                    "no key required"
+                } else {
+                    "matches"
+                }
+                .to_owned(),
+            );
+            if draining(DrainMode::Punch) {
+                step("drain", "draining punch holes".to_owned());
+                break 'checks Some(refuse(
+                    "draining",
+                    PunchHoleResponse {
+                        other_failure: "Server is draining, try again shortly".to_owned(),
+                        ..Default::default()
+                    },
+                ));
+            }
+            step("drain", "not draining".to_owned());
+            // a banned device may not control others either
+            match from {
+                Some(from) => match self.pm.db.is_device_banned(from).await {
+                    Ok(true) => {
+                        step("initiator_ban", format!("{} is banned", from));
+                        decision.error = Some((
+                            ErrorReason::InitiatorBanned,
+                            from.to_owned(),
+                            format!("initiator is banned, punch hole to {} refused", ph.id),
+                        ));
+                        break 'checks Some(refuse(
+                            "initiator_banned",
+                            PunchHoleResponse {
+                                other_failure: "Your device is banned".to_owned(),
+                                ..Default::default()
+                            },
+                        ));
+                    }
+                    Ok(false) => step("initiator_ban", format!("{} is not banned", from)),
+                    Err(e) => {
+                        log::error!(
+                            "Failed to check ban status for initiator {}: {}. Allowing (fail-open)",
+                            from,
+                            e
+                        );
+                        step("initiator_ban", format!("check failed, allowed: {}", e));
+                    }
+                },
+                None => step("initiator_ban", "initiator unknown, not checked".to_owned()),
+            }
+            // a quarantined peer stays registered, but may neither control nor be controlled
+            for (id, initiator) in from
+                .map(|x| (x, true))
+                .into_iter()
+                .chain([(&*ph.id, false)])
+            {
+                match self.pm.db.is_quarantined(id).await {
+                    Ok(true) => {
+                        step("quarantine", format!("{} is quarantined", id));
+                        decision.error = Some((
+                            ErrorReason::Quarantined,
+                            from.unwrap_or_default().to_owned(),
+                            format!("{} is quarantined, punch hole to {} refused", id, ph.id),
+                        ));
+                        break 'checks Some(refuse(
+                            "quarantined",
+                            PunchHoleResponse {
+                                other_failure: if initiator {
+                                    "Your device is quarantined"
+                                } else {
+                                    "Peer is quarantined"
+                                }
+                                .to_owned(),
+                                ..Default::default()
+                            },
+                        ));
+                    }
+                    Ok(false) => step("quarantine", format!("{} is not quarantined", id)),
+                    Err(e) => {
+                        log::error!(
+                            "Failed to check quarantine of {}: {}. Allowing (fail-open)",
+                            id,
+                            e
+                        );
+                        step(
+                            "quarantine",
+                            format!("check of {} failed, allowed: {}", id, e),
+                        );
+                    }
+                }
+            }
+            // the peers of a disabled user may neither control nor be controlled
+            for (id, initiator) in from
+                .map(|x| (x, true))
+                .into_iter()
+                .chain([(&*ph.id, false)])
+            {
+                match self.pm.disabled_user(id).await {
+                    Some(user) => {
+                        step("user", format!("user {} of {} is disabled", user, id));
+                        decision.error = Some((
+                            ErrorReason::UserDisabled,
+                            from.unwrap_or_default().to_owned(),
+                            format!(
+                                "user {} of {} is disabled, punch hole to {} refused",
+                                user, id, ph.id
+                            ),
+                        ));
+                        break 'checks Some(refuse(
+                            "user_disabled",
+                            PunchHoleResponse {
+                                other_failure: if initiator {
+                                    "Your account is disabled"
+                                } else {
+                                    "Peer's account is disabled"
+                                }
+                                .to_owned(),
+                                ..Default::default()
+                            },
+                        ));
+                    }
+                    None => step("user", format!("{} has no disabled user", id)),
+                }
+            }
+            match access_policy(&self.pm.db).await {
+                Ok(policy) => {
+                    let access = policy.check(from, &ph.id);
+                    step("access_rules", access.to_string());
+                    if !access.allowed() {
+                        let detail = format!("{} -> {} {}", from.unwrap_or("?"), ph.id, access);
+                        decision.error = Some((
+                            ErrorReason::AccessDenied,
+                            from.unwrap_or_default().to_owned(),
+                            detail.clone(),
+                        ));
+                        decision.audit = Some(detail);
+                        break 'checks Some(refuse(
+                            "access_denied",
+                            PunchHoleResponse {
+                                other_failure: "Access denied by server policy".to_owned(),
+                                ..Default::default()
+                            },
+                        ));
+                    }
+                }
+                Err(e) => {
+                    log::error!("Failed to load access rules: {}. Allowing (fail-open)", e);
+                    step("access_rules", format!("loading failed, allowed: {}", e));
+                }
+            }
+            decision.pool = self.relay_pool_for(&ph.id).await;
+            let outcome = match &decision.pool {
+                RelayPool::Shared => "no binding for the target's tags".to_owned(),
+                RelayPool::Bound(tag, relays) => format!("{:?} bound to {}", relays, tag),
+                RelayPool::Fallback(tag) => {
+                    format!(
+                        "every relay bound to {} is down, using the shared relays",
+                        tag
+                    )
+                }
+                RelayPool::Refused(tag) => {
+                    step(
+                        "relay_binding",
+                        format!(
+                            "every relay bound to {} is down and relay-binding-fallback is fail",
+                            tag
+                        ),
+                    );
+                    break 'checks Some(refuse(
+                        "relay_unavailable",
+                        PunchHoleResponse {
+                            other_failure: "No relay available for this device, try again shortly"
+                                .to_owned(),
+                            ..Default::default()
+                        },
+                    ));
+                }
+            };
+            step("relay_binding", outcome);
+            None
+        };
+        if let Some((result, msg)) = refused {
+            decision.result = result;
+            decision.msg = msg;
+            decision.steps = steps;
+            return decision;
         }
-        let id = ph.id;
+        let id = ph.id.clone();
         // punch hole request from A, relay to B,
         // check if in same intranet first,
         // fetch local addrs if in same intranet.
         // because punch hole won't work if in the same intranet,
         // all routers will drop such self-connections.
-        if let Some(peer) = self.pm.get(&id).await {
-            let (elapsed, peer_addr) = {
-                let r = peer.read().await;
-                (r.last_reg_time.elapsed().as_millis() as i32, r.socket_addr)
-            };
-            if elapsed >= REG_TIMEOUT {
-                let mut msg_out = RendezvousMessage::new();
-                msg_out.set_punch_hole_response(PunchHoleResponse {
-                    failure: punch_hole_response::Failure::OFFLINE.into(),
-                    ..Default::default()
-                });
-                return Ok((msg_out, None));
-            }
-            let mut msg_out = RendezvousMessage::new();
-            let peer_is_lan = self.is_lan(peer_addr);
-            let is_lan = self.is_lan(addr);
-            let mut relay_server = self.get_relay_server(addr.ip(), peer_addr.ip());
-            if ALWAYS_USE_RELAY.load(Ordering::SeqCst) || (peer_is_lan ^ is_lan) {
-                if peer_is_lan {
-                    // https://github.com/rustdesk/rustdesk-server/issues/24
-                    relay_server = self.inner.local_ip.clone()
-                }
-                ph.nat_type = NatType::SYMMETRIC.into(); // will force relay
-            }
-            let same_intranet: bool = !ws
-                && (peer_is_lan && is_lan || {
-                    match (peer_addr, addr) {
-                        (SocketAddr::V4(a), SocketAddr::V4(b)) => a.ip() == b.ip(),
-                        (SocketAddr::V6(a), SocketAddr::V6(b)) => a.ip() == b.ip(),
-                        _ => false,
+        let peer = if dry_run {
+            self.pm.peek(&id).await
+        } else {
+            self.pm.get(&id).await
+        };
+        let (result, msg) = match peer {
+            None => {
+                step("target", format!("{} is not registered", id));
+                refuse(
+                    "id_not_exist",
+                    PunchHoleResponse {
+                        failure: punch_hole_response::Failure::ID_NOT_EXIST.into(),
+                        ..Default::default()
+                    },
+                )
+            }
+            Some(peer) => {
+                let (elapsed, peer_addr) = {
+                    let r = peer.read().await;
+                    (
+                        self.pm.clock.elapsed(r.last_reg_time).as_millis() as i32,
+                        r.socket_addr,
+                    )
+                };
+                if elapsed >= REG_TIMEOUT {
+                    // no address: loaded from the database, not registered here since.
+                    // There is nowhere to ask it to register again; its next heartbeat
+                    // to this instance brings it back
+                    let retry = peer_addr.port() == 0 && {
+                        let row = self.pm.db.peer_status_row(&id).await.ok().flatten();
+                        punch_retry_fresh(&id, row.as_ref(), &*self.pm.clock, !dry_run)
+                    };
+                    if retry {
+                        step(
+                            "target",
+                            format!("{} is not in memory but online in the database", id),
+                        );
+                        refuse(
+                            "retry_fresh",
+                            PunchHoleResponse {
+                                other_failure: "Peer is reconnecting, try again shortly".to_owned(),
+                                ..Default::default()
+                            },
+                        )
+                    } else {
+                        step(
+                            "target",
+                            if peer_addr.port() == 0 {
+                                format!("{} is offline, not registered since the start", id)
+                            } else {
+                                format!("{} is offline, last registered {}ms ago", id, elapsed)
+                            },
+                        );
+                        refuse(
+                            "offline",
+                            PunchHoleResponse {
+                                failure: punch_hole_response::Failure::OFFLINE.into(),
+                                ..Default::default()
+                            },
+                        )
+                    }
+                } else {
+                    step(
+                        "target",
+                        format!(
+                            "{} is online at {}, registered {}ms ago",
+                            id, peer_addr, elapsed
+                        ),
+                    );
+                    let mut msg_out = RendezvousMessage::new();
+                    let peer_is_lan = self.is_lan(peer_addr);
+                    let is_lan = self.is_lan(addr);
+                    let ipv6 = ipv6_only_client(addr.ip()) || ipv6_only_client(peer_addr.ip());
+                    let (mut relay_server, candidates) =
+                        self.pick_relay_server(addr.ip(), &id, &decision.pool, ipv6, !dry_run);
+                    let always_use_relay = ALWAYS_USE_RELAY.load(Ordering::SeqCst);
+                    if always_use_relay || (peer_is_lan ^ is_lan) {
+                        if peer_is_lan {
+                            // https://github.com/rustdesk/rustdesk-server/issues/24
+                            relay_server = self.inner.local_ip.clone()
+                        }
+                        decision.relay = Some((
+                            if always_use_relay {
+                                RelayReason::AlwaysUseRelay
+                            } else {
+                                RelayReason::LanMismatch
+                            },
+                            relay_server.clone(),
+                        ));
+                        ph.nat_type = NatType::SYMMETRIC.into(); // will force relay
+                    }
+                    let same_intranet: bool = !ws
+                        && (peer_is_lan && is_lan || {
+                            match (peer_addr, addr) {
+                                (SocketAddr::V4(a), SocketAddr::V4(b)) => a.ip() == b.ip(),
+                                (SocketAddr::V6(a), SocketAddr::V6(b)) => a.ip() == b.ip(),
+                                _ => false,
+                            }
+                        });
+                    step(
+                        "lan",
+                        format!(
+                            "initiator lan={}, target lan={}, same intranet={}",
+                            is_lan, peer_is_lan, same_intranet
+                        ),
+                    );
+                    step(
+                        "relay",
+                        match &decision.relay {
+                            Some((reason, _)) => {
+                                format!("forced to {:?} ({})", relay_server, reason.as_str())
+                            }
+                            None => format!(
+                                "{:?} if the punch fails ({} mode, {} pool)",
+                                relay_server,
+                                relay_mode().as_str(),
+                                decision.pool
+                            ),
+                        },
+                    );
+                    step("relay_candidates", format!("picked from {:?}", candidates));
+                    decision.relay_candidates = candidates;
+                    let socket_addr = AddrMangle::encode(addr).into();
+                    if same_intranet {
+                        log::debug!(
+                            "Fetch local addr {:?} {:?} request from {:?} relay={}",
+                            id,
+                            peer_addr,
+                            addr,
+                            relay_server
+                        );
+                        msg_out.set_fetch_local_addr(FetchLocalAddr {
+                            socket_addr,
+                            relay_server,
+                            ..Default::default()
+                        });
+                    } else {
+                        log::debug!(
+                            "Punch hole {:?} {:?} request from {:?} relay={}",
+                            id,
+                            peer_addr,
+                            addr,
+                            relay_server
+                        );
+                        msg_out.set_punch_hole(PunchHole {
+                            socket_addr,
+                            nat_type: ph.nat_type,
+                            relay_server,
+                            ..Default::default()
+                        });
                     }
-                });
-            let socket_addr = AddrMangle::encode(addr).into();
-            if same_intranet {
-                log::debug!(
-                    "Fetch local addr {:?} {:?} request from {:?}",
-                    id,
-                    peer_addr,
-                    addr
-                );
-                msg_out.set_fetch_local_addr(FetchLocalAddr {
-                    socket_addr,
-                    relay_server,
-                    ..Default::default()
-                });
-            } else {
-                log::debug!(
-                    "Punch hole {:?} {:?} request from {:?}",
-                    id,
-                    peer_addr,
-                    addr
-                );
-                msg_out.set_punch_hole(PunchHole {
-                    socket_addr,
-                    nat_type: ph.nat_type,
-                    relay_server,
-                    ..Default::default()
-                });
+                    decision.to = Some(peer_addr);
+                    ("ok", msg_out)
+                }
             }
-            Ok((msg_out, Some(peer_addr)))
-        } else {
-            let mut msg_out = RendezvousMessage::new();
-            msg_out.set_punch_hole_response(PunchHoleResponse {
-                failure: punch_hole_response::Failure::ID_NOT_EXIST.into(),
-                ..Default::default()
-            });
-            Ok((msg_out, None))
-        }
+        };
+        decision.result = result;
+        decision.msg = msg;
+        decision.steps = steps;
+        decision
+    }
+
+    /// Run the punch hole decision for `sim` against the current state; the
+    /// initiator's address defaults to where it last registered
+    async fn simulate_punch(&self, sim: PunchSimulation, key: &str) -> Result<PunchTrace, String> {
+        let from_id = canonical_id(&sim.from_id).into_owned();
+        let addr = match sim.from_addr {
+            Some(addr) => addr,
+            None => {
+                let addr = match self.pm.get_in_memory(&from_id).await {
+                    Some(peer) => peer.read().await.socket_addr,
+                    None => SocketAddr::from(([0, 0, 0, 0], 0)),
+                };
+                if addr.port() == 0 {
+                    return Err(format!(
+                        "{} has no known address here, pass from_addr",
+                        from_id
+                    ));
+                }
+                addr
+            }
+        };
+        let ph = PunchHoleRequest {
+            id: sim.to_id,
+            // a client configured with the server's key
+            licence_key: key.to_owned(),
+            ..Default::default()
+        };
+        let ws = sim.transport == Transport::Ws;
+        let from = (!from_id.is_empty()).then_some(from_id.as_str());
+        let decision = self.decide_punch_hole(addr, ph, key, ws, from, true).await;
+        let (response, relay_server) = match &decision.msg.union {
+            Some(rendezvous_message::Union::PunchHole(x)) => {
+                ("punch_hole", Some(x.relay_server.clone()))
+            }
+            Some(rendezvous_message::Union::FetchLocalAddr(x)) => {
+                ("fetch_local_addr", Some(x.relay_server.clone()))
+            }
+            _ => ("punch_hole_response", None),
+        };
+        let failure = match &decision.msg.union {
+            Some(rendezvous_message::Union::PunchHoleResponse(x)) => match x.failure.enum_value() {
+                Ok(failure) if x.other_failure.is_empty() => Some(format!("{:?}", failure)),
+                _ => Some(x.other_failure.clone()),
+            },
+            _ => None,
+        };
+        Ok(PunchTrace {
+            from_id,
+            to_id: decision.target,
+            from_addr: addr.to_string(),
+            transport: sim.transport.as_str(),
+            result: decision.result,
+            response,
+            sent_to: decision.to.map(|x| x.to_string()),
+            failure,
+            relay_server,
+            relay_reason: decision.relay.map(|(reason, _)| reason.as_str()),
+            relay_candidates: decision.relay_candidates,
+            steps: decision.steps,
+        })
     }
 
     #[inline]
     async fn handle_online_request(
         &mut self,
         stream: &mut FramedStream,
+        addr: SocketAddr,
         peers: Vec<String>,
     ) -> ResultType<()> {
         let mut states = BytesMut::zeroed((peers.len() + 7) / 8);
         for (i, peer_id) in peers.iter().enumerate() {
-            if let Some(peer) = self.pm.get_in_memory(peer_id).await {
-                let elapsed = peer.read().await.last_reg_time.elapsed().as_millis() as i32;
+            if let Some(peer) = self.pm.get_in_memory(&canonical_id(peer_id)).await {
+                let elapsed = self
+                    .pm
+                    .clock
+                    .elapsed(peer.read().await.last_reg_time)
+                    .as_millis() as i32;
                 // bytes index from left to right
                 let states_idx = i / 8;
                 let bit_idx = 7 - i % 8;
@@ -826,6 +6402,7 @@ impl RendezvousServer {
             states: states.into(),
             ..Default::default()
         });
+        trace_message("out", Transport::Tcp, addr, &msg_out);
         stream.send(&msg_out).await?;
 
         Ok(())
@@ -835,13 +6412,18 @@ impl RendezvousServer {
     async fn send_to_tcp(&mut self, msg: RendezvousMessage, addr: SocketAddr) {
         let mut tcp = self.tcp_punch.lock().await.remove(&try_into_v4(addr));
         tokio::spawn(async move {
-            Self::send_to_sink(&mut tcp, msg).await;
+            Self::send_to_sink(&mut tcp, msg, addr).await;
         });
     }
 
     #[inline]
-    async fn send_to_sink(sink: &mut Option<Sink>, msg: RendezvousMessage) {
+    async fn send_to_sink(sink: &mut Option<Sink>, msg: RendezvousMessage, addr: SocketAddr) {
         if let Some(sink) = sink.as_mut() {
+            let transport = match sink {
+                Sink::TcpStream(_) => Transport::Tcp,
+                Sink::Ws(_) => Transport::Ws,
+            };
+            trace_message("out", transport, addr, &msg);
             if let Ok(bytes) = msg.write_to_bytes() {
                 match sink {
                     Sink::TcpStream(s) => {
@@ -862,7 +6444,7 @@ impl RendezvousServer {
         addr: SocketAddr,
     ) -> ResultType<()> {
         let mut sink = self.tcp_punch.lock().await.remove(&try_into_v4(addr));
-        Self::send_to_sink(&mut sink, msg).await;
+        Self::send_to_sink(&mut sink, msg, addr).await;
         Ok(())
     }
 
@@ -873,8 +6455,11 @@ impl RendezvousServer {
         ph: PunchHoleRequest,
         key: &str,
         ws: bool,
+        initiator: Option<String>,
     ) -> ResultType<()> {
-        let (msg, to_addr) = self.handle_punch_hole_request(addr, ph, key, ws).await?;
+        let (msg, to_addr) = self
+            .handle_punch_hole_request(addr, ph, key, ws, initiator)
+            .await?;
         if let Some(addr) = to_addr {
             self.tx.send(Data::Msg(msg.into(), addr))?;
         } else {
@@ -889,8 +6474,11 @@ impl RendezvousServer {
         addr: SocketAddr,
         ph: PunchHoleRequest,
         key: &str,
+        initiator: Option<String>,
     ) -> ResultType<()> {
-        let (msg, to_addr) = self.handle_punch_hole_request(addr, ph, key, false).await?;
+        let (msg, to_addr) = self
+            .handle_punch_hole_request(addr, ph, key, false, initiator)
+            .await?;
         self.tx.send(Data::Msg(
             msg.into(),
             match to_addr {
@@ -903,12 +6491,12 @@ impl RendezvousServer {
 
     async fn check_ip_blocker(&self, ip: &str, id: &str) -> bool {
         let mut lock = IP_BLOCKER.lock().await;
-        let now = Instant::now();
+        let now = self.pm.clock.now_instant();
         if let Some(old) = lock.get_mut(ip) {
             let counter = &mut old.0;
-            if counter.1.elapsed().as_secs() > IP_BLOCK_DUR {
+            if now.duration_since(counter.1).as_secs() > IP_BLOCK_DUR {
                 counter.0 = 0;
-            } else if counter.0 > 30 {
+            } else if counter.0 > IP_BLOCK_MAX_REGS {
                 return false;
             }
             counter.0 += 1;
@@ -916,7 +6504,7 @@ impl RendezvousServer {
 
             let counter = &mut old.1;
             let is_new = counter.0.get(id).is_none();
-            if counter.1.elapsed().as_secs() > DAY_SECONDS {
+            if now.duration_since(counter.1).as_secs() > DAY_SECONDS {
                 counter.0.clear();
             } else if counter.0.len() > 300 {
                 return !is_new;
@@ -932,19 +6520,145 @@ impl RendezvousServer {
     }
 
     fn parse_relay_servers(&mut self, relay_servers: &str) {
-        let rs = get_servers(relay_servers, "relay-servers");
+        let (mut rs, problems) = canonical_relays(relay_servers);
+        for problem in &problems {
+            log::error!("relay-servers: ignoring {}", problem);
+        }
+        let bound = relay_bindings().relays();
+        if rs.iter().any(|x| bound.contains(x)) {
+            rs.retain(|x| !bound.contains(x));
+            log::warn!("relay-servers: leaving out the relays reserved by relay-bindings");
+        }
+        // refused at startup and on reload, but the console command is not checked
+        if rs.len() > max_relay_servers() {
+            log::error!(
+                "relay-servers: {} relays, using the first {} (MAX_RELAY_SERVERS)",
+                rs.len(),
+                max_relay_servers()
+            );
+            rs.truncate(max_relay_servers());
+        }
+        if rs.is_empty() && !problems.is_empty() {
+            log::error!("relay-servers: no valid relay left, sessions that need one will fail");
+        }
+        let usable = match RELAY_STATE.read() {
+            Ok(state) => usable_relays(
+                &rs,
+                &state.relays,
+                chrono::Utc::now().timestamp(),
+                relay_health_fresh_secs(),
+            ),
+            Err(_) => rs.clone(),
+        };
+        if usable.len() < rs.len() {
+            log::info!(
+                "relay-servers: using {:?} until the next check, the others were last seen down or not checked",
+                usable
+            );
+        }
+        if let Ok(mut lock) = RELAY_HEALTH.write() {
+            *lock = (rs.clone(), usable.clone());
+        }
         self.relay_servers0 = Arc::new(rs);
-        self.relay_servers = self.relay_servers0.clone();
+        self.relay_servers = Arc::new(usable);
+    }
+
+    /// Relay for a session from `initiator` to the peer `target`. Punch hole requests
+    /// do not carry the initiator's id, so sticky mode keys on its address.
+    async fn get_relay_server(&self, initiator: IpAddr, target: &str) -> String {
+        let pool = self.relay_pool_for(target).await;
+        let ipv6 = ipv6_only_client(initiator);
+        let (relay, _) = self.pick_relay_server(initiator, target, &pool, ipv6, true);
+        relay
+    }
+
+    /// Relays the tags of `target` allow; no lookup without relay bindings. A
+    /// failed lookup uses the shared relays (fail-open).
+    async fn relay_pool_for(&self, target: &str) -> RelayPool {
+        if relay_bindings().is_empty() {
+            return RelayPool::Shared;
+        }
+        match peer_tags(&self.pm.db, target).await {
+            Ok(tags) => current_relay_pool(&tags),
+            Err(e) => {
+                log::error!(
+                    "Failed to load the tags of {}: {}. Using the shared relays",
+                    target,
+                    e
+                );
+                RelayPool::Shared
+            }
+        }
     }
 
-    fn get_relay_server(&self, _pa: IpAddr, _pb: IpAddr) -> String {
-        if self.relay_servers.is_empty() {
-            return "".to_owned();
-        } else if self.relay_servers.len() == 1 {
-            return self.relay_servers[0].clone();
+    /// `get_relay_server` from `pool`, but with `advance` false the rotation is only
+    /// looked at, so a dry run answers with the relay the next real session would get.
+    /// With `ipv6` (a side of the session is IPv6-only) IPv4 literals are left out
+    /// while the pool has other relays. The relay is picked among the
+    /// relay_advertise_max() fastest of the pool, returned with it.
+    fn pick_relay_server(
+        &self,
+        initiator: IpAddr,
+        target: &str,
+        pool: &RelayPool,
+        ipv6: bool,
+        advance: bool,
+    ) -> (String, RelayServers) {
+        let relays = match pool {
+            RelayPool::Bound(_, relays) => relays,
+            RelayPool::Shared | RelayPool::Fallback(_) => &*self.relay_servers,
+            RelayPool::Refused(_) => return ("".to_owned(), RelayServers::new()),
+        };
+        let reachable;
+        let relays = if !ipv6 || relays.is_empty() {
+            relays
+        } else {
+            match ipv6_relays(relays) {
+                Some(usable) => {
+                    if advance && usable.len() < relays.len() {
+                        IPV6_CLIENTS.inc("relay_preferred");
+                    }
+                    reachable = usable;
+                    &reachable
+                }
+                None => {
+                    if advance {
+                        IPV6_CLIENTS.inc("relay_v4_only");
+                    }
+                    relays
+                }
+            }
+        };
+        if relays.is_empty() {
+            return ("".to_owned(), RelayServers::new());
+        } else if relays.len() == 1 {
+            return (relays[0].clone(), relays.to_vec());
         }
-        let i = ROTATION_RELAY_SERVER.fetch_add(1, Ordering::SeqCst) % self.relay_servers.len();
-        self.relay_servers[i].clone()
+        let latency = RELAY_LATENCY.read().map(|x| x.clone()).unwrap_or_default();
+        let relays = advertised_relays(relays, &latency, relay_advertise_max());
+        match relay_mode() {
+            RelayMode::Sticky => {
+                if let Some(x) = sticky_relay(&relays, &initiator.to_string(), target) {
+                    return (x.clone(), relays);
+                }
+            }
+            RelayMode::Latency => {
+                if let Some(x) = relays
+                    .iter()
+                    .filter_map(|x| latency.get(x).map(|t| (x, t)))
+                    .min_by_key(|(_, t)| **t)
+                {
+                    return (x.0.clone(), relays);
+                }
+            }
+            RelayMode::Rotation => {}
+        }
+        let i = if advance {
+            ROTATION_RELAY_SERVER.fetch_add(1, Ordering::SeqCst)
+        } else {
+            ROTATION_RELAY_SERVER.load(Ordering::SeqCst)
+        } % relays.len();
+        (relays[i].clone(), relays)
     }
 
     async fn check_cmd(&self, cmd: &str) -> String {
@@ -1080,10 +6794,11 @@ impl RendezvousServer {
                     if let Ok(a) = rs.parse::<IpAddr>() {
                         if let Some(rs) = fds.next() {
                             if let Ok(b) = rs.parse::<IpAddr>() {
-                                res = format!("{:?}", self.get_relay_server(a, b));
+                                res =
+                                    format!("{:?}", self.get_relay_server(a, &b.to_string()).await);
                             }
                         } else {
-                            res = format!("{:?}", self.get_relay_server(a, a));
+                            res = format!("{:?}", self.get_relay_server(a, &a.to_string()).await);
                         }
                     }
                 }
@@ -1112,7 +6827,11 @@ impl RendezvousServer {
         tokio::spawn(async move {
             let mut stream = stream;
             if let Some(Ok(bytes)) = stream.next_timeout(30_000).await {
+                if !message_size_ok(&bytes, Transport::Tcp) {
+                    return;
+                }
                 if let Ok(msg_in) = RendezvousMessage::parse_from_bytes(&bytes) {
+                    trace_message("in", Transport::Tcp, addr, &msg_in);
                     match msg_in.union {
                         Some(rendezvous_message::Union::TestNatRequest(_)) => {
                             let mut msg_out = RendezvousMessage::new();
@@ -1120,10 +6839,12 @@ impl RendezvousServer {
                                 port: addr.port() as _,
                                 ..Default::default()
                             });
+                            trace_message("out", Transport::Tcp, addr, &msg_out);
                             stream.send(&msg_out).await.ok();
                         }
                         Some(rendezvous_message::Union::OnlineRequest(or)) => {
-                            allow_err!(rs.handle_online_request(&mut stream, or.peers).await);
+                            let res = rs.handle_online_request(&mut stream, addr, or.peers).await;
+                            allow_err!(res);
                         }
                         _ => {}
                     }
@@ -1137,6 +6858,51 @@ impl RendezvousServer {
         let mut rs = self.clone();
         let key = key.to_owned();
         tokio::spawn(async move {
+            let stream: Conn = Box::new(stream);
+            allow_err!(rs.handle_listener_inner(stream, addr, &key, ws).await);
+        });
+    }
+
+    async fn handle_single_port(&self, stream: TcpStream, addr: SocketAddr, key: &str) {
+        let mut rs = self.clone();
+        let key = key.to_owned();
+        tokio::spawn(async move {
+            let (stream, ws) = match sniff(&stream).await {
+                Ok(Sniffed::Ws) => (Box::new(stream) as Conn, true),
+                Ok(Sniffed::Tcp) => (Box::new(stream) as Conn, false),
+                Ok(Sniffed::Tls) => {
+                    let accept = match tls_acceptor() {
+                        Some(accept) => accept,
+                        None => {
+                            SNIFF_FAILURES.inc("tls_unconfigured");
+                            log::warn!(
+                                "TLS handshake from {:?} on the single port without --tls-cert",
+                                addr
+                            );
+                            return;
+                        }
+                    };
+                    // wss:// is the only protocol spoken over TLS
+                    match timeout(SNIFF_TIMEOUT, (*accept)(stream)).await {
+                        Ok(Ok(tls)) => (tls, true),
+                        Ok(Err(err)) => {
+                            SNIFF_FAILURES.inc("tls_handshake");
+                            log::debug!("TLS handshake from {:?} failed: {}", addr, err);
+                            return;
+                        }
+                        Err(_) => {
+                            SNIFF_FAILURES.inc("tls_handshake");
+                            log::debug!("TLS handshake from {:?} timed out", addr);
+                            return;
+                        }
+                    }
+                }
+                Err(reason) => {
+                    SNIFF_FAILURES.inc(reason);
+                    log::debug!("Failed to sniff protocol of {:?}: {}", addr, reason);
+                    return;
+                }
+            };
             allow_err!(rs.handle_listener_inner(stream, addr, &key, ws).await);
         });
     }
@@ -1144,12 +6910,15 @@ impl RendezvousServer {
     #[inline]
     async fn handle_listener_inner(
         &mut self,
-        stream: TcpStream,
+        stream: Conn,
         mut addr: SocketAddr,
         key: &str,
         ws: bool,
     ) -> ResultType<()> {
         let mut sink;
+        let mut conn_peer = None;
+        let mut closed_by = None;
+        let (cmd_tx, mut cmd_rx) = mpsc::unbounded_channel::<LiveConnCommand>();
         if ws {
             use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
             let callback = |req: &Request, response: Response| {
@@ -1167,51 +6936,175 @@ impl RendezvousServer {
                 }
                 Ok(response)
             };
-            let ws_stream = tokio_tungstenite::accept_hdr_async(stream, callback).await?;
-            let (a, mut b) = ws_stream.split();
-            sink = Some(Sink::Ws(a));
-            while let Ok(Some(Ok(msg))) = timeout(30_000, b.next()).await {
-                if let tungstenite::Message::Binary(bytes) = msg {
-                    if !self.handle_tcp(&bytes, &mut sink, addr, key, ws).await {
+            let max = Some(Transport::Ws.max_frame());
+            let config = tokio_tungstenite::tungstenite::protocol::WebSocketConfig {
+                max_message_size: max,
+                max_frame_size: max,
+                ..Default::default()
+            };
+            let ws_stream =
+                tokio_tungstenite::accept_hdr_async_with_config(stream, callback, Some(config))
+                    .await?;
+            let (a, b) = ws_stream.split();
+            let (track, meter) = ConnTrack::new(addr);
+            sink = Some(Sink::Ws(Metered::new(a, meter.clone())));
+            let mut b = Metered::new(b, meter.clone());
+            loop {
+                let res = tokio::select! {
+                    res = timeout(30_000, b.next()) => res,
+                    Some((reason, detail)) = cmd_rx.recv() => {
+                        Self::close_sink(&mut sink, reason, &detail).await;
+                        closed_by = Some(OfflineReason::ServerDisconnect);
+                        break;
+                    }
+                };
+                if over_byte_limit(&meter, addr, &conn_peer) {
+                    Self::close_sink(&mut sink, DisconnectReason::ByteLimit, "").await;
+                    closed_by = Some(OfflineReason::ServerDisconnect);
+                    break;
+                }
+                match res {
+                    Ok(Some(Ok(tungstenite::Message::Binary(bytes)))) => {
+                        if !self
+                            .handle_tcp(&bytes, &mut sink, addr, key, ws, &mut conn_peer)
+                            .await
+                        {
+                            break;
+                        }
+                        register_live_conn(&conn_peer, &cmd_tx);
+                        self.name_conn(&track, &conn_peer, addr).await;
+                    }
+                    Ok(Some(Ok(tungstenite::Message::Close(_)))) | Ok(None) => {
+                        closed_by = Some(OfflineReason::CleanShutdown);
+                        break;
+                    }
+                    Ok(Some(Ok(_))) => {}
+                    Ok(Some(Err(_))) => {
+                        closed_by = Some(OfflineReason::ConnectionLost);
+                        break;
+                    }
+                    Err(_) => {
+                        closed_by = Some(OfflineReason::Timeout);
                         break;
                     }
                 }
             }
         } else {
-            let (a, mut b) = Framed::new(stream, BytesCodec::new()).split();
-            sink = Some(Sink::TcpStream(a));
-            while let Ok(Some(Ok(bytes))) = timeout(30_000, b.next()).await {
-                if !self.handle_tcp(&bytes, &mut sink, addr, key, ws).await {
+            let mut codec = BytesCodec::new();
+            codec.set_max_packet_length(Transport::Tcp.max_frame());
+            let (a, b) = Framed::new(stream, codec).split();
+            let (track, meter) = ConnTrack::new(addr);
+            sink = Some(Sink::TcpStream(Metered::new(a, meter.clone())));
+            let mut b = Metered::new(b, meter.clone());
+            loop {
+                let res = tokio::select! {
+                    res = timeout(30_000, b.next()) => res,
+                    Some((reason, detail)) = cmd_rx.recv() => {
+                        Self::close_sink(&mut sink, reason, &detail).await;
+                        closed_by = Some(OfflineReason::ServerDisconnect);
+                        break;
+                    }
+                };
+                if over_byte_limit(&meter, addr, &conn_peer) {
+                    Self::close_sink(&mut sink, DisconnectReason::ByteLimit, "").await;
+                    closed_by = Some(OfflineReason::ServerDisconnect);
                     break;
                 }
+                match res {
+                    Ok(Some(Ok(bytes))) => {
+                        if !self
+                            .handle_tcp(&bytes, &mut sink, addr, key, ws, &mut conn_peer)
+                            .await
+                        {
+                            break;
+                        }
+                        register_live_conn(&conn_peer, &cmd_tx);
+                        self.name_conn(&track, &conn_peer, addr).await;
+                    }
+                    Ok(None) => {
+                        closed_by = Some(OfflineReason::CleanShutdown);
+                        break;
+                    }
+                    Ok(Some(Err(_))) => {
+                        closed_by = Some(OfflineReason::ConnectionLost);
+                        break;
+                    }
+                    Err(_) => {
+                        closed_by = Some(OfflineReason::Timeout);
+                        break;
+                    }
+                }
             }
         }
         if sink.is_none() {
             self.tcp_punch.lock().await.remove(&try_into_v4(addr));
         }
+        if let Some(id) = conn_peer.as_ref() {
+            if let Ok(mut lock) = LIVE_CONNS.lock() {
+                if lock.get(id).map_or(false, |x| x.same_channel(&cmd_tx)) {
+                    lock.remove(id);
+                }
+            }
+        }
+        // Only the client side ending the connection (or us disconnecting the peer) says
+        // anything about the peer; otherwise the heartbeat timeout still applies
+        if let (Some(id), Some(reason)) = (conn_peer, closed_by) {
+            self.pm.mark_offline(&id, reason).await;
+        }
         log::debug!("Tcp connection from {:?} closed", addr);
         Ok(())
     }
 
+    /// Name a metered connection after its peer: the id it registered as, else
+    /// whoever last registered from its address
+    async fn name_conn(&self, track: &ConnTrack, conn_peer: &Option<String>, addr: SocketAddr) {
+        match conn_peer {
+            Some(id) => track.set_peer(id),
+            None if !track.has_peer() => {
+                if let Some(id) = self.pm.get_id_by_addr(addr).await {
+                    track.set_peer(&id);
+                }
+            }
+            None => {}
+        }
+    }
+
+    /// Close a live peer connection on the server's initiative. Websocket peers get a
+    /// close frame carrying the reason; sends are bounded so a dead sink can't stall us.
+    async fn close_sink(sink: &mut Option<Sink>, reason: DisconnectReason, detail: &str) {
+        let text = if detail.is_empty() {
+            reason.as_str().to_owned()
+        } else {
+            format!("{}:{}", reason.as_str(), detail)
+        };
+        match sink.as_mut() {
+            Some(Sink::Ws(ws)) => {
+                use tokio_tungstenite::tungstenite::protocol::{
+                    frame::coding::CloseCode, CloseFrame,
+                };
+                let frame = CloseFrame {
+                    code: CloseCode::Library(reason.code()),
+                    reason: text.into(),
+                };
+                allow_err!(timeout(1_000, ws.send(tungstenite::Message::Close(Some(frame)))).await);
+            }
+            Some(Sink::TcpStream(tcp)) => {
+                allow_err!(timeout(1_000, tcp.close()).await);
+            }
+            None => {}
+        }
+    }
+
     #[inline]
     async fn get_pk(&mut self, version: &str, id: String) -> Bytes {
         if version.is_empty() || self.inner.sk.is_none() {
             Bytes::new()
         } else {
-            match self.pm.get(&id).await {
+            // signed for the id as the client sent it, which is what it verifies
+            match self.pm.get(&canonical_id(&id)).await {
                 Some(peer) => {
                     let pk = peer.read().await.pk.clone();
-                    sign::sign(
-                        &hbb_common::message_proto::IdPk {
-                            id,
-                            pk,
-                            ..Default::default()
-                        }
-                        .write_to_bytes()
-                        .unwrap_or_default(),
-                        self.inner.sk.as_ref().unwrap(),
-                    )
-                    .into()
+                    signed_id_pk(self.inner.sk.as_ref().unwrap(), id, pk).await
                 }
                 _ => Bytes::new(),
             }
@@ -1243,6 +7136,9 @@ impl RendezvousServer {
         if !key.is_empty() {
             log::info!("Key: {}", key);
         }
+        if let (Some(sk), Ok(mut lock)) = (&out_sk, SERVER_PK.write()) {
+            *lock = Some(base64::encode(&sk.0[(sign::SECRETKEYBYTES / 2)..]));
+        }
         (key, out_sk)
     }
 
@@ -1265,35 +7161,246 @@ impl RendezvousServer {
     }
 }
 
-async fn check_relay_servers(rs0: Arc<RelayServers>, tx: Sender) {
-    let mut futs = Vec::new();
-    let rs = Arc::new(Mutex::new(Vec::new()));
-    for x in rs0.iter() {
-        let mut host = x.to_owned();
+lazy_static::lazy_static! {
+    static ref SERVER_PK: std::sync::RwLock<Option<String>> = Default::default();
+}
+
+/// The public half of the server's signing key once the rendezvous server
+/// loaded or generated it, as clients are configured with it
+pub fn server_public_key() -> Option<String> {
+    SERVER_PK.read().ok()?.clone()
+}
+
+// set while a relay check runs; a long list can take longer than the interval
+static RELAY_CHECK_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Check the shared relays `rs0` and the `bound` ones; the healthy shared ones
+/// become the relays in use
+async fn check_relay_servers(
+    rs0: Arc<RelayServers>,
+    bound: RelayServers,
+    tx: Sender,
+    db: Database,
+) {
+    let hosts: RelayServers = rs0
+        .iter()
+        .chain(bound.iter().filter(|x| !rs0.contains(x)))
+        .cloned()
+        .collect();
+    // spread over the check interval, the next tick is skipped while this one runs
+    let spread = Duration::from_millis(CHECK_RELAY_TIMEOUT);
+    let results = probe_relays(&hosts, spread, |x| async move {
+        let mut host = x;
         if !host.contains(':') {
             host = format!("{}:{}", host, config::RELAY_PORT);
         }
-        let rs = rs.clone();
-        let x = x.clone();
-        futs.push(tokio::spawn(async move {
-            if FramedStream::new(&host, None, CHECK_RELAY_TIMEOUT)
-                .await
-                .is_ok()
-            {
-                rs.lock().await.push(x);
+        let started = Instant::now();
+        let ok = FramedStream::new(&host, None, CHECK_RELAY_TIMEOUT)
+            .await
+            .is_ok();
+        if ok {
+            Some(started.elapsed())
+        } else {
+            None
+        }
+    })
+    .await;
+    log::debug!("check_relay_servers");
+    if let Ok(mut lock) = RELAY_LATENCY.write() {
+        for (x, latency) in results.iter() {
+            if let Some(latency) = latency {
+                lock.insert(x.clone(), *latency);
             }
-        }));
+        }
     }
-    join_all(futs).await;
-    log::debug!("check_relay_servers");
-    let rs = std::mem::take(&mut *rs.lock().await);
+    if let Some(snapshot) = record_relay_check(&results, chrono::Utc::now().timestamp()) {
+        if let Err(e) = db.save_relay_health(&snapshot).await {
+            log::warn!("Failed to save relay health: {}", e);
+        }
+    }
+    let rs: RelayServers = results
+        .into_iter()
+        .filter(|x| x.1.is_some() && rs0.contains(&x.0))
+        .map(|x| x.0)
+        .collect();
     if !rs.is_empty() {
         tx.send(Data::RelayServers(rs)).ok();
     }
 }
 
+/// Restarts of one task within SUPERVISOR_WINDOW_SECS after which it is left dead
+const SUPERVISOR_MAX_RESTARTS: usize = 5;
+const SUPERVISOR_WINDOW_SECS: u64 = 3600;
+/// Wait before the first restart, doubled for each further one in the window
+const SUPERVISOR_BACKOFF_MS: u64 = 500;
+const SUPERVISOR_MAX_BACKOFF_MS: u64 = 60_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskState {
+    Running,
+    /// Panicked, waiting out its backoff
+    Restarting,
+    /// Returned on its own (e.g. disabled by its setting)
+    Finished,
+    /// Panicked too often within the hour, not restarted again
+    Dead,
+}
+
+/// A background loop owned by the supervisor, for /api/health
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskStatus {
+    pub name: &'static str,
+    pub state: TaskState,
+    /// Restarts since the server started
+    pub restarts: u64,
+    pub last_panic: Option<String>,
+    pub last_panic_at: Option<String>,
+}
+
+lazy_static::lazy_static! {
+    static ref TASKS: std::sync::Mutex<Vec<TaskStatus>> = Default::default();
+}
+
+tokio::task_local! {
+    static SUPERVISED_TASK: &'static str;
+}
+
+/// Name of the supervised task running on this thread right now, so the panic
+/// hook can leave a panic in it to the supervisor instead of ending the process
+pub fn supervised_task() -> Option<&'static str> {
+    SUPERVISED_TASK.try_with(|name| *name).ok()
+}
+
+fn update_task(name: &'static str, update: impl FnOnce(&mut TaskStatus)) {
+    if let Ok(mut tasks) = TASKS.lock() {
+        let i = match tasks.iter().position(|x| x.name == name) {
+            Some(i) => i,
+            None => {
+                tasks.push(TaskStatus {
+                    name,
+                    state: TaskState::Running,
+                    restarts: 0,
+                    last_panic: None,
+                    last_panic_at: None,
+                });
+                tasks.len() - 1
+            }
+        };
+        update(&mut tasks[i]);
+    }
+}
+
+/// Run the future `factory` makes as the background task `name`. A panic is
+/// logged and the task made afresh after a backoff (SUPERVISOR_BACKOFF_MS, doubled
+/// per restart), at most SUPERVISOR_MAX_RESTARTS times within an hour.
+pub fn supervise<F, Fut>(name: &'static str, factory: F)
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: std::future::Future<Output = ()> + Send + 'static,
+{
+    update_task(name, |x| x.state = TaskState::Running);
+    tokio::spawn(async move {
+        let window = Duration::from_secs(SUPERVISOR_WINDOW_SECS);
+        let mut recent: std::collections::VecDeque<Instant> = Default::default();
+        loop {
+            let res = tokio::spawn(SUPERVISED_TASK.scope(name, factory())).await;
+            let message = match res {
+                Ok(()) => {
+                    log::info!("Task {} finished", name);
+                    update_task(name, |x| x.state = TaskState::Finished);
+                    return;
+                }
+                Err(e) if e.is_panic() => {
+                    let payload = e.into_panic();
+                    match payload.downcast_ref::<&str>() {
+                        Some(s) => s.to_string(),
+                        None => payload
+                            .downcast_ref::<String>()
+                            .cloned()
+                            .unwrap_or_else(|| "Box<dyn Any>".to_owned()),
+                    }
+                }
+                Err(e) => e.to_string(),
+            };
+            let now = Instant::now();
+            while recent
+                .front()
+                .map_or(false, |x| now.duration_since(*x) >= window)
+            {
+                recent.pop_front();
+            }
+            let at = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+            if recent.len() >= SUPERVISOR_MAX_RESTARTS {
+                log::error!(
+                    "Task {} panicked: {}; {} restarts within the hour, giving up",
+                    name,
+                    message,
+                    recent.len()
+                );
+                update_task(name, |x| {
+                    x.state = TaskState::Dead;
+                    x.last_panic = Some(message);
+                    x.last_panic_at = Some(at);
+                });
+                return;
+            }
+            let backoff = Duration::from_millis(
+                (SUPERVISOR_BACKOFF_MS << recent.len()).min(SUPERVISOR_MAX_BACKOFF_MS),
+            );
+            recent.push_back(now);
+            log::error!(
+                "Task {} panicked: {}; restarting in {:?}",
+                name,
+                message,
+                backoff
+            );
+            update_task(name, |x| {
+                x.state = TaskState::Restarting;
+                x.restarts += 1;
+                x.last_panic = Some(message);
+                x.last_panic_at = Some(at);
+            });
+            tokio::time::sleep(backoff).await;
+            update_task(name, |x| x.state = TaskState::Running);
+        }
+    });
+}
+
+/// The supervised background tasks in the order they were started
+pub fn task_statuses() -> Vec<TaskStatus> {
+    TASKS.lock().map(|x| x.clone()).unwrap_or_default()
+}
+
+/// Whether the server can be announced: Starting until the udp self-test gets
+/// its first answer (at once with TEST_HBBS=no), Down with the reason once the
+/// test failed; the process ends right after that
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Readiness {
+    Starting,
+    Ready,
+    Down(String),
+}
+
+/// Without an answer for this long the udp self-test fails
+pub const SELF_TEST_TIMEOUT_SECS: u64 = 12;
+
+lazy_static::lazy_static! {
+    static ref READINESS: watch::Sender<Readiness> = watch::channel(Readiness::Starting).0;
+}
+
+/// The self-test result, for `/api/health` and the service manager
+pub fn readiness_watch() -> watch::Receiver<Readiness> {
+    READINESS.subscribe()
+}
+
 // temp solution to solve udp socket failure
-async fn test_hbbs(addr: SocketAddr) -> ResultType<()> {
+/// Marks `readiness` Ready on the first answer; returns only on failure
+pub async fn test_hbbs(
+    addr: SocketAddr,
+    timeout: Duration,
+    readiness: &watch::Sender<Readiness>,
+) -> ResultType<()> {
     let mut addr = addr;
     if addr.ip().is_unspecified() {
         addr.set_ip(if addr.is_ipv4() {
@@ -1315,7 +7422,7 @@ async fn test_hbbs(addr: SocketAddr) -> ResultType<()> {
     loop {
         tokio::select! {
           _ = timer.tick() => {
-              if last_time_recv.elapsed().as_secs() > 12 {
+              if last_time_recv.elapsed() > timeout {
                   bail!("Timeout of test_hbbs");
               }
               socket.send(&msg_out, addr).await?;
@@ -1324,6 +7431,13 @@ async fn test_hbbs(addr: SocketAddr) -> ResultType<()> {
               if let Ok(msg_in) = RendezvousMessage::parse_from_bytes(&bytes) {
                  log::trace!("Recv {:?} of test_hbbs", msg_in);
                  last_time_recv = Instant::now();
+                 readiness.send_if_modified(|x| {
+                     let starting = *x == Readiness::Starting;
+                     if starting {
+                         *x = Readiness::Ready;
+                     }
+                     starting
+                 });
               }
           }
         }
@@ -1336,29 +7450,253 @@ async fn send_rk_res(
     addr: SocketAddr,
     res: register_pk_response::Result,
 ) -> ResultType<()> {
+    count_registration(res);
     let mut msg_out = RendezvousMessage::new();
     msg_out.set_register_pk_response(RegisterPkResponse {
         result: res.into(),
         ..Default::default()
     });
-    socket.send(&msg_out, addr).await
+    send_udp(socket, &msg_out, addr).await
+}
+
+/// Send over udp, traced when to a traced peer
+async fn send_udp(
+    socket: &mut FramedSocket,
+    msg: &RendezvousMessage,
+    addr: SocketAddr,
+) -> ResultType<()> {
+    trace_message("out", Transport::Udp, addr, msg);
+    socket.send(msg, addr).await
 }
 
 async fn create_udp_listener(port: i32, rmem: usize) -> ResultType<FramedSocket> {
     let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port as _);
     if let Ok(s) = FramedSocket::new_reuse(&addr, true, rmem).await {
         log::debug!("listen on udp {:?}", s.local_addr());
+        note_udp_buffer(&s, rmem);
         return Ok(s);
     }
     let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port as _);
     let s = FramedSocket::new_reuse(&addr, true, rmem).await?;
     log::debug!("listen on udp {:?}", s.local_addr());
+    note_udp_buffer(&s, rmem);
     Ok(s)
 }
 
+/// Terminate TLS on the shared port with `accept` from now on; a TLS handshake
+/// there is refused without one
+pub fn set_tls_acceptor(accept: Option<TlsAcceptFn>) {
+    if let Ok(mut lock) = TLS_ACCEPTOR.write() {
+        *lock = accept.map(Arc::new);
+    }
+}
+
+fn tls_acceptor() -> Option<Arc<TlsAcceptFn>> {
+    TLS_ACCEPTOR.read().ok().and_then(|x| x.clone())
+}
+
+async fn accept_opt(
+    listener: &mut Option<TcpListener>,
+) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Peek at the first bytes of a connection on the shared port without consuming them.
+/// A websocket client opens with an HTTP request line, TLS with a handshake record,
+/// anything else is taken to be the framed RustDesk protocol. Fails with the
+/// SNIFF_FAILURES reason.
+async fn sniff(stream: &TcpStream) -> Result<Sniffed, &'static str> {
+    let mut buf = [0u8; SNIFF_LEN];
+    let started = Instant::now();
+    loop {
+        let left = SNIFF_TIMEOUT.saturating_sub(started.elapsed().as_millis() as u64);
+        let n = match timeout(left, stream.peek(&mut buf)).await {
+            Ok(Ok(n)) => n,
+            Ok(Err(_)) => return Err("closed"),
+            Err(_) => return Err("silent"),
+        };
+        if n == 0 {
+            return Err("closed");
+        }
+        if n >= SNIFF_LEN {
+            break;
+        }
+        // peek returns immediately with what has arrived so far
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    Ok(match &buf {
+        b"GET " => Sniffed::Ws,
+        [0x16, 0x03, ..] => Sniffed::Tls,
+        _ => Sniffed::Tcp,
+    })
+}
+
+/// Make a connection that registered a peer reachable through `disconnect_peer`
+fn register_live_conn(conn_peer: &Option<String>, tx: &mpsc::UnboundedSender<LiveConnCommand>) {
+    if let Some(id) = conn_peer {
+        if let Ok(mut lock) = LIVE_CONNS.lock() {
+            if !lock.get(id).map_or(false, |x| x.same_channel(tx)) {
+                lock.insert(id.clone(), tx.clone());
+            }
+        }
+    }
+}
+
+/// Interval for a periodic job, configurable in seconds through `env`; 0 disables the job
+fn periodic(env: &str, default: u64) -> Option<Interval> {
+    match env_u64(env, default) {
+        0 => {
+            log::info!("{}=0, job disabled", env);
+            None
+        }
+        secs => Some(interval(Duration::from_secs(secs))),
+    }
+}
+
+/// Tick of a job created by `periodic`, never resolves for a disabled job
+async fn tick_opt(timer: &mut Option<Interval>) {
+    match timer {
+        Some(timer) => {
+            timer.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Boolean switches carry no value so `init_args` does not keep them;
+/// read them from the command line, falling back to the usual env var (`Y`).
+pub fn get_flag(name: &str) -> bool {
+    let arg = format!("--{name}");
+    std::env::args().any(|x| x == arg)
+        || std::env::var(name.to_uppercase().replace('-', "_"))
+            .unwrap_or_default()
+            .to_uppercase()
+            == "Y"
+}
+
 #[inline]
 async fn create_tcp_listener(port: i32) -> ResultType<TcpListener> {
     let s = listen_any(port as _).await?;
     log::debug!("listen on tcp {:?}", s.local_addr());
     Ok(s)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAIRS: usize = 20_000;
+
+    fn relays() -> Vec<String> {
+        (1..=4)
+            .map(|i| format!("relay{}.example:21117", i))
+            .collect()
+    }
+
+    fn pair(i: usize) -> (String, String) {
+        (
+            format!("10.{}.{}.{}", i >> 16, (i >> 8) & 255, i & 255),
+            format!("PEER{}", i * 31),
+        )
+    }
+
+    #[test]
+    fn sticky_relays_spread_evenly_and_stay_put() {
+        let relays = relays();
+        let mut counts = HashMap::new();
+        for i in 0..PAIRS {
+            let (initiator, target) = pair(i);
+            let relay = sticky_relay(&relays, &initiator, &target);
+            assert_eq!(relay, sticky_relay(&relays, &initiator, &target));
+            *counts
+                .entry(relay.cloned().unwrap_or_default())
+                .or_insert(0usize) += 1;
+        }
+        let fair = PAIRS / relays.len();
+        for relay in &relays {
+            let n = counts.get(relay).copied().unwrap_or_default();
+            assert!(
+                n >= fair * 9 / 10 && n <= fair * 11 / 10,
+                "{} got {} of {} pairs",
+                relay,
+                n,
+                PAIRS
+            );
+        }
+    }
+
+    #[test]
+    fn only_pairs_of_a_dropped_relay_move() {
+        let relays = relays();
+        let healthy = relays[1..].to_vec();
+        for i in 0..PAIRS {
+            let (initiator, target) = pair(i);
+            let before = sticky_relay(&relays, &initiator, &target);
+            let after = sticky_relay(&healthy, &initiator, &target);
+            if before != Some(&relays[0]) {
+                assert_eq!(before, after);
+            }
+            assert_eq!(after, sticky_relay(&healthy, &initiator, &target));
+        }
+        assert!(sticky_relay(&[], "10.0.0.1", "PEER").is_none());
+    }
+
+    #[test]
+    fn histogram_buckets_by_seconds_cumulatively() {
+        let h = Histogram::default();
+        h.observe(Duration::from_micros(1_500));
+        assert_eq!(h.buckets()[..2], [(1, 0), (5, 1)]);
+        h.observe(Duration::from_millis(1));
+        h.observe(Duration::from_secs(2));
+        let buckets = h.buckets();
+        assert_eq!(buckets[0], (1, 1));
+        assert_eq!(buckets[buckets.len() - 1], (1000, 2));
+        assert_eq!(h.count(), 3);
+        assert_eq!(h.sum(), Duration::from_micros(2_002_500));
+    }
+
+    #[test]
+    fn send_dedup_skips_only_byte_identical_repeats() {
+        let a: SocketAddr = "10.0.0.1:21116".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:21116".parse().unwrap();
+        let t0 = Instant::now();
+        let ms = |n: u64| t0 + Duration::from_millis(n);
+        let mut dedup = SendDedup::new(Duration::from_millis(100), 3);
+        let sends: [(&str, SocketAddr, &[u8], u64, bool); 6] = [
+            ("first send", a, b"ok", 0, true),
+            ("repeat", a, b"ok", 50, false),
+            ("same bytes, other address", b, b"ok", 50, true),
+            ("next step of a sequence", a, b"punch", 60, true),
+            ("repeat after the window", a, b"ok", 150, true),
+            ("repeat of a later send", a, b"ok", 160, false),
+        ];
+        for (name, addr, bytes, at, expected) in sends {
+            assert_eq!(dedup.admit(addr, bytes, ms(at)), expected, "{}", name);
+        }
+        for n in 0..10u8 {
+            dedup.admit(a, &[n], ms(170));
+        }
+        assert!(dedup.tracked() <= 3);
+    }
+
+    #[test]
+    fn send_dedup_with_a_zero_window_admits_all() {
+        let a: SocketAddr = "10.0.0.1:21116".parse().unwrap();
+        let t0 = Instant::now();
+        let mut dedup = SendDedup::new(Duration::ZERO, 3);
+        assert!(dedup.admit(a, b"ok", t0));
+        assert!(dedup.admit(a, b"ok", t0));
+    }
+
+    #[test]
+    fn relay_modes_parse_back() {
+        for mode in [RelayMode::Rotation, RelayMode::Sticky, RelayMode::Latency] {
+            assert_eq!(RelayMode::parse(mode.as_str()), Some(mode));
+        }
+        assert_eq!(RelayMode::parse("Sticky"), Some(RelayMode::Sticky));
+        assert_eq!(RelayMode::parse("random"), None);
+    }
+}
@@ -112,7 +112,7 @@ impl RendezvousServer {
         let (key, sk) = Self::get_server_sk(key);
         let nat_port = port - 1;
         let ws_port = port + 2;
-        let pm = PeerMap::new().await?;
+        let pm = PeerMap::new(Arc::new(SystemClock)).await?;
         
         log::info!("Configuration:");
         log::info!("  Serial: {}", serial);
@@ -273,17 +273,10 @@ impl RendezvousServer {
         key: &str,
     ) -> LoopFailure {
         let mut timer_check_relay = interval(Duration::from_millis(CHECK_RELAY_TIMEOUT));
-        
-        // Get heartbeat interval from environment or use default
-        let heartbeat_secs = std::env::var("HEARTBEAT_INTERVAL_SECS")
-            .ok()
-            .and_then(|s| s.parse::<u64>().ok())
-            .unwrap_or(HEARTBEAT_INTERVAL_DEFAULT);
-        
-        let mut timer_check_peers = interval(Duration::from_secs(heartbeat_secs));
+        // Offline marking is done by PeerMap's own sweep (PEER_SWEEP_INTERVAL_SECS)
         let mut timer_stats = interval(Duration::from_secs(60)); // Log stats every minute
         
-        log::info!("IO loop started (heartbeat interval: {}s)", heartbeat_secs);
+        log::info!("IO loop started");
         
         loop {
             tokio::select! {
@@ -296,13 +289,6 @@ impl RendezvousServer {
                         });
                     }
                 }
-                _ = timer_check_peers.tick() => {
-                    log::debug!("Running peer health check...");
-                    let pm = self.pm.clone();
-                    tokio::spawn(async move {
-                        pm.check_online_peers().await;
-                    });
-                }
                 _ = timer_stats.tick() => {
                     // Log statistics periodically
                     let pm = self.pm.clone();
@@ -0,0 +1,83 @@
+// Signing benchmark: `hbbs signbench [REQUESTS]`
+// Replays punch-hole style IdPk lookups, most of them for a small set of busy
+// peers, and times the response signing as it was before (a fresh ed25519
+// sign per request) against `signed_id_pk` with its short-lived cache.
+
+use hbb_common::{
+    bytes::Bytes, log, message_proto::IdPk, protobuf::Message as _, tokio, ResultType,
+};
+use sodiumoxide::crypto::sign;
+use std::time::{Duration, Instant};
+
+const DEFAULT_REQUESTS: usize = 100_000;
+const PEERS: usize = 5_000;
+const HOT_PEERS: usize = 50;
+
+pub fn run() -> ResultType<()> {
+    let requests = match std::env::args().nth(2) {
+        Some(n) => n.parse::<usize>()?,
+        None => DEFAULT_REQUESTS,
+    };
+    tokio::runtime::Runtime::new()?.block_on(bench(requests.max(1)));
+    Ok(())
+}
+
+async fn bench(requests: usize) {
+    let (_, sk) = sign::gen_keypair();
+    let peers: Vec<(String, Bytes)> = (0..PEERS)
+        .map(|i| {
+            let (pk, _) = sign::gen_keypair();
+            (format!("SIGNBENCH{:06}", i), Bytes::from(pk.0.to_vec()))
+        })
+        .collect();
+    // four of five requests go to one of the busy peers
+    let pick = |i: usize| {
+        if i % 5 == 0 {
+            &peers[i * 7919 % PEERS]
+        } else {
+            &peers[i % HOT_PEERS]
+        }
+    };
+
+    let mut uncached = Vec::with_capacity(requests);
+    for i in 0..requests {
+        let (id, pk) = pick(i);
+        let started = Instant::now();
+        let payload = IdPk {
+            id: id.clone(),
+            pk: pk.clone(),
+            ..Default::default()
+        }
+        .write_to_bytes()
+        .unwrap_or_default();
+        let signed: Bytes = sign::sign(&payload, &sk).into();
+        uncached.push(started.elapsed());
+        drop(signed);
+    }
+
+    let mut cached = Vec::with_capacity(requests);
+    for i in 0..requests {
+        let (id, pk) = pick(i);
+        let started = Instant::now();
+        let signed = hbbs::signed_id_pk(&sk, id.clone(), pk.clone()).await;
+        cached.push(started.elapsed());
+        drop(signed);
+    }
+
+    report("sign per request", &mut uncached);
+    report("signed_id_pk", &mut cached);
+}
+
+fn report(what: &str, samples: &mut [Duration]) {
+    samples.sort();
+    let at = |q: f64| samples[((samples.len() - 1) as f64 * q) as usize];
+    let total: Duration = samples.iter().sum();
+    log::info!(
+        "signbench: {}: p50 {:?}, p99 {:?}, max {:?}, total {:?}",
+        what,
+        at(0.5),
+        at(0.99),
+        samples[samples.len() - 1],
+        total
+    );
+}
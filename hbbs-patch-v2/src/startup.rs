@@ -0,0 +1,141 @@
+// Machine-readable startup summary: `--startup-summary=json`
+// Provisioning tools read the ports, key and paths from one JSON document on
+// stdout instead of from log wording. With the flag the logger duplicates to
+// stderr rather than stdout, and once the rendezvous server is ready the
+// document is printed as a single line, once per process. The API key itself
+// is never part of it, only the file holding it.
+
+use hbb_common::{log, tokio};
+use hbbs::Readiness;
+use serde::Serialize;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub const FLAG: &str = "startup-summary";
+
+#[derive(Serialize, Debug)]
+pub struct StartupSummary {
+    pub version: &'static str,
+    /// The working directory, where the key pair and a relative database live
+    pub data_dir: String,
+    pub db_path: String,
+    pub ports: Ports,
+    /// As clients are configured with it, None without a key pair
+    pub public_key: Option<String>,
+    /// None with `--no-api`
+    pub api_key_file: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Ports {
+    pub udp: u16,
+    pub tcp: u16,
+    pub nat: u16,
+    /// The main port with `--single-port`
+    pub ws: u16,
+    pub api: Option<u16>,
+}
+
+/// Whether the command line asks for the JSON summary; read before the argument
+/// parser runs, as the logger is set up first
+pub fn json_requested() -> bool {
+    let args: Vec<String> = std::env::args().collect();
+    let flag = format!("--{}", FLAG);
+    args.iter()
+        .enumerate()
+        .any(|(i, x)| match x.strip_prefix(&flag) {
+            Some("=json") => true,
+            Some("") => args.get(i + 1).map(String::as_str) == Some("json"),
+            _ => false,
+        })
+}
+
+/// Check the value of `--startup-summary`; only json is known
+pub fn check_format(format: &str) -> Result<(), String> {
+    match format {
+        "" | "json" => Ok(()),
+        other => Err(format!(
+            "--{}={:?} is not supported, expected json",
+            FLAG, other
+        )),
+    }
+}
+
+pub fn summary(port: u16, single_port: bool, api_port: Option<u16>) -> StartupSummary {
+    let data_dir = std::env::current_dir().unwrap_or_default();
+    StartupSummary {
+        version: env!("CARGO_PKG_VERSION"),
+        data_dir: data_dir.to_string_lossy().to_string(),
+        db_path: resolve(&data_dir, &hbbs::db_url()),
+        ports: Ports {
+            udp: port,
+            tcp: port,
+            nat: port - 1,
+            ws: if single_port { port } else { port + 2 },
+            api: api_port,
+        },
+        public_key: hbbs::server_public_key(),
+        api_key_file: api_port.map(|_| resolve(&data_dir, &crate::http_api::get_api_key_path())),
+    }
+}
+
+fn resolve(dir: &Path, path: &str) -> String {
+    let path = path.strip_prefix("sqlite://").unwrap_or(path);
+    let path = PathBuf::from(path);
+    let path = if path.is_absolute() {
+        path
+    } else {
+        dir.join(path)
+    };
+    // `./db_v2.sqlite3` without the `.` component
+    path.components()
+        .filter(|x| !matches!(x, std::path::Component::CurDir))
+        .collect::<PathBuf>()
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Print the summary once the rendezvous server is ready; nothing when the
+/// self-test fails, the process ends then
+pub fn spawn_thread(
+    port: u16,
+    single_port: bool,
+    api_port: Option<u16>,
+) -> std::thread::JoinHandle<()> {
+    let mut rx = hbbs::readiness_watch();
+    std::thread::spawn(move || {
+        let rt = match tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+        {
+            Ok(rt) => rt,
+            Err(e) => {
+                log::error!("Startup summary: cannot follow the readiness: {}", e);
+                return;
+            }
+        };
+        let ready = rt.block_on(async {
+            while *rx.borrow_and_update() != Readiness::Ready {
+                if rx.changed().await.is_err() {
+                    return false;
+                }
+            }
+            true
+        });
+        if !ready {
+            return;
+        }
+        let summary = summary(port, single_port, api_port);
+        let line = match serde_json::to_string(&summary) {
+            Ok(line) => line,
+            Err(e) => {
+                log::error!("Startup summary: {}", e);
+                return;
+            }
+        };
+        let mut stdout = std::io::stdout().lock();
+        if let Err(e) = writeln!(stdout, "{}", line).and_then(|_| stdout.flush()) {
+            log::error!("Startup summary: cannot write to stdout: {}", e);
+        }
+    })
+}
@@ -0,0 +1,311 @@
+// Resumable full-table sync for external inventory systems
+// `start` materializes the peer table into sync_snapshot under a random token,
+// one pre-serialized NDJSON line per peer numbered by `seq`, so every chunk of
+// a token returns exactly the same bytes no matter how the live table changes.
+// Tokens live in memory; snapshot rows are dropped on release or expiry.
+
+use hbb_common::log;
+use sqlx::{sqlite::SqlitePool, Row};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const TOKEN_TTL_SECS: u64 = 15 * 60;
+const TOMBSTONE_SECS: u64 = 3600; // expired tokens answer 410 for this long, then 404
+const MAX_TOKENS: u64 = 4; // SYNC_MAX_TOKENS
+const CHUNK_SIZE: u64 = 1000; // SYNC_CHUNK_SIZE
+const MAX_CHUNK_SIZE: u64 = 50_000;
+const JANITOR_INTERVAL_SECS: u64 = 60;
+
+/// One peer as a JSON record: same online window as the peer listing
+/// (ONLINE_TIMEOUT_SECS), previous_ids flattened to bare ids whether stored as
+/// strings or history entries, custom attributes as an object
+macro_rules! peer_record {
+    () => {
+        "json_object(
+        'id', id,
+        'note', note,
+        'online', json(CASE WHEN julianday(last_online) >= julianday('now', '-60 seconds')
+            THEN 'true' ELSE 'false' END),
+        'last_online', last_online,
+        'created_at', created_at,
+        'previous_ids', json(CASE WHEN json_valid(previous_ids) AND previous_ids LIKE '[%' THEN (
+            SELECT json_group_array(CASE WHEN type = 'text' THEN value ELSE json_extract(value, '$.id') END)
+            FROM json_each(peer.previous_ids)
+        ) ELSE '[]' END),
+        'banned', json(CASE WHEN is_banned = 1 AND (banned_until IS NULL
+            OR banned_until > CAST(strftime('%s', 'now') AS INTEGER)) THEN 'true' ELSE 'false' END),
+        'attributes', json((
+            SELECT json_group_object(key, value) FROM peer_attributes a WHERE a.guid = peer.guid
+        ))
+    )"
+    };
+}
+
+const SNAPSHOT_SQL: &str = concat!(
+    "INSERT INTO sync_snapshot (token, seq, line)
+    SELECT ?, row_number() OVER (ORDER BY id) - 1, ",
+    peer_record!(),
+    " FROM peer WHERE is_deleted = 0"
+);
+
+/// The peers as sync records, for the export job
+pub const EXPORT_SQL: &str = concat!(
+    "SELECT ",
+    peer_record!(),
+    " FROM peer WHERE is_deleted = 0 ORDER BY id"
+);
+
+struct SyncToken {
+    created: Instant,
+    rows: u64,
+    chunk_size: u64,
+    expired: bool,
+}
+
+lazy_static::lazy_static! {
+    static ref TOKENS: Mutex<HashMap<String, SyncToken>> = Default::default();
+}
+
+static STARTED: AtomicU64 = AtomicU64::new(0);
+static REJECTED: AtomicU64 = AtomicU64::new(0);
+static EXPIRED: AtomicU64 = AtomicU64::new(0);
+static RELEASED: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, serde::Serialize)]
+pub struct SyncStart {
+    pub token: String,
+    pub rows: u64,
+    pub chunk_size: u64,
+    pub chunks: u64,
+    pub expires_at: String,
+}
+
+#[derive(Debug)]
+pub enum SyncError {
+    /// Too many concurrent tokens
+    Busy,
+    UnknownToken,
+    Expired,
+    NoSuchChunk,
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for SyncError {
+    fn from(e: sqlx::Error) -> Self {
+        SyncError::Database(e)
+    }
+}
+
+pub(crate) fn env_u64(name: &str, default: u64) -> u64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(default)
+}
+
+fn new_token() -> String {
+    use hbb_common::rand::Rng;
+    const CHARSET: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+    let mut rng = hbb_common::rand::thread_rng();
+    (0..32)
+        .map(|_| CHARSET[rng.gen_range(0..CHARSET.len())] as char)
+        .collect()
+}
+
+/// Create the snapshot table, drop rows of tokens from a previous run and start the janitor
+pub async fn init(pool: SqlitePool) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS sync_snapshot (
+            token VARCHAR(32) NOT NULL,
+            seq INTEGER NOT NULL,
+            line TEXT NOT NULL,
+            PRIMARY KEY (token, seq)
+        ) WITHOUT ROWID",
+    )
+    .execute(&pool)
+    .await?;
+    sqlx::query("DELETE FROM sync_snapshot")
+        .execute(&pool)
+        .await?;
+    hbb_common::tokio::spawn(async move {
+        let mut interval =
+            hbb_common::tokio::time::interval(Duration::from_secs(JANITOR_INTERVAL_SECS));
+        loop {
+            interval.tick().await;
+            expire_tokens(&pool).await;
+        }
+    });
+    Ok(())
+}
+
+/// Mark tokens past their lifetime expired and free their snapshot rows
+async fn expire_tokens(pool: &SqlitePool) {
+    let expired: Vec<String> = match TOKENS.lock() {
+        Ok(mut tokens) => {
+            tokens.retain(|_, t| {
+                !(t.expired && t.created.elapsed().as_secs() > TOKEN_TTL_SECS + TOMBSTONE_SECS)
+            });
+            tokens
+                .iter_mut()
+                .filter(|(_, t)| !t.expired && t.created.elapsed().as_secs() > TOKEN_TTL_SECS)
+                .map(|(token, t)| {
+                    t.expired = true;
+                    token.clone()
+                })
+                .collect()
+        }
+        Err(_) => return,
+    };
+    for token in expired {
+        EXPIRED.fetch_add(1, Ordering::Relaxed);
+        drop_snapshot(pool, &token).await;
+    }
+}
+
+async fn drop_snapshot(pool: &SqlitePool, token: &str) {
+    if let Err(e) = sqlx::query("DELETE FROM sync_snapshot WHERE token = ?")
+        .bind(token)
+        .execute(pool)
+        .await
+    {
+        log::warn!("API: Failed to drop sync snapshot {}: {}", token, e);
+    }
+}
+
+pub async fn start(pool: &SqlitePool) -> Result<SyncStart, SyncError> {
+    expire_tokens(pool).await;
+    let token = new_token();
+    let chunk_size = env_u64("SYNC_CHUNK_SIZE", CHUNK_SIZE).clamp(1, MAX_CHUNK_SIZE);
+    {
+        let mut tokens = TOKENS.lock().map_err(|_| SyncError::Busy)?;
+        if tokens.values().filter(|t| !t.expired).count() as u64
+            >= env_u64("SYNC_MAX_TOKENS", MAX_TOKENS)
+        {
+            REJECTED.fetch_add(1, Ordering::Relaxed);
+            return Err(SyncError::Busy);
+        }
+        // reserve the slot while the snapshot is built
+        tokens.insert(
+            token.clone(),
+            SyncToken {
+                created: Instant::now(),
+                rows: 0,
+                chunk_size,
+                expired: false,
+            },
+        );
+    }
+
+    let rows = match sqlx::query(SNAPSHOT_SQL).bind(&token).execute(pool).await {
+        Ok(res) => res.rows_affected(),
+        Err(e) => {
+            if let Ok(mut tokens) = TOKENS.lock() {
+                tokens.remove(&token);
+            }
+            drop_snapshot(pool, &token).await;
+            return Err(e.into());
+        }
+    };
+    if let Ok(mut tokens) = TOKENS.lock() {
+        if let Some(t) = tokens.get_mut(&token) {
+            t.rows = rows;
+        }
+    }
+    STARTED.fetch_add(1, Ordering::Relaxed);
+    log::info!("API: Sync snapshot {} with {} peers", token, rows);
+    Ok(SyncStart {
+        token,
+        rows,
+        chunk_size,
+        chunks: (rows + chunk_size - 1) / chunk_size,
+        expires_at: (chrono::Utc::now() + chrono::Duration::seconds(TOKEN_TTL_SECS as i64))
+            .to_rfc3339(),
+    })
+}
+
+/// Chunk `n` of a snapshot as NDJSON, one peer record per line, read through `read_pool`
+pub async fn chunk(
+    pool: &SqlitePool,
+    read_pool: &SqlitePool,
+    token: &str,
+    n: u64,
+) -> Result<String, SyncError> {
+    expire_tokens(pool).await;
+    let (rows, chunk_size) = {
+        let tokens = TOKENS.lock().map_err(|_| SyncError::UnknownToken)?;
+        match tokens.get(token) {
+            None => return Err(SyncError::UnknownToken),
+            Some(t) if t.expired => return Err(SyncError::Expired),
+            Some(t) => (t.rows, t.chunk_size),
+        }
+    };
+    if n.saturating_mul(chunk_size) >= rows.max(1) {
+        return Err(SyncError::NoSuchChunk);
+    }
+    let lines = sqlx::query(
+        "SELECT line FROM sync_snapshot WHERE token = ? AND seq >= ? AND seq < ? ORDER BY seq",
+    )
+    .bind(token)
+    .bind((n * chunk_size) as i64)
+    .bind(((n + 1) * chunk_size) as i64)
+    .fetch_all(read_pool)
+    .await?;
+    let mut body = String::new();
+    for row in &lines {
+        body.push_str(row.get::<&str, _>(0));
+        body.push('\n');
+    }
+    Ok(body)
+}
+
+/// Release a token before it expires
+pub async fn release(pool: &SqlitePool, token: &str) -> Result<(), SyncError> {
+    {
+        let mut tokens = TOKENS.lock().map_err(|_| SyncError::UnknownToken)?;
+        match tokens.get(token) {
+            None => return Err(SyncError::UnknownToken),
+            Some(t) if t.expired => return Err(SyncError::Expired),
+            Some(_) => {}
+        }
+        tokens.remove(token);
+    }
+    RELEASED.fetch_add(1, Ordering::Relaxed);
+    drop_snapshot(pool, token).await;
+    Ok(())
+}
+
+/// Tokens held and approximate bytes per token, for `/api/stats/memory` (the
+/// snapshots themselves live in the database)
+pub fn memory() -> Option<(usize, usize)> {
+    let tokens = TOKENS.try_lock().ok()?.len();
+    Some((tokens, hbbs::map_entry_bytes::<String, SyncToken>(64)))
+}
+
+/// Prometheus lines for the sync tokens, appended to /metrics
+pub fn render_metrics() -> String {
+    let active = TOKENS
+        .lock()
+        .map(|t| t.values().filter(|t| !t.expired).count())
+        .unwrap_or(0);
+    let mut out = String::new();
+    out.push_str("# HELP hbbs_sync_tokens_active Sync snapshot tokens currently held\n");
+    out.push_str("# TYPE hbbs_sync_tokens_active gauge\n");
+    out.push_str(&format!("hbbs_sync_tokens_active {}\n", active));
+    out.push_str("# HELP hbbs_sync_tokens_total Sync snapshot tokens by outcome\n");
+    out.push_str("# TYPE hbbs_sync_tokens_total counter\n");
+    for (outcome, counter) in [
+        ("started", &STARTED),
+        ("rejected", &REJECTED),
+        ("expired", &EXPIRED),
+        ("released", &RELEASED),
+    ] {
+        out.push_str(&format!(
+            "hbbs_sync_tokens_total{{outcome=\"{}\"}} {}\n",
+            outcome,
+            counter.load(Ordering::Relaxed)
+        ));
+    }
+    out
+}
@@ -0,0 +1,37 @@
+// TLS on the shared port: `--tls-cert` and `--tls-key`
+// With `--single-port`, a connection opening with a TLS handshake is decrypted
+// here and handed to the websocket handler, so wss:// clients reach hbbs on 443
+// without a proxy in front. The certificate (chain) and its PKCS#8 key are PEM
+// files read once at startup. Without them a TLS handshake on the shared port
+// is refused and counted in hbbs_single_port_failures_total.
+
+use hbb_common::tokio::net::TcpStream;
+use tokio_native_tls::native_tls;
+
+/// Check the pair of files and install the acceptor; false when neither is set
+pub fn configure(cert: &str, key: &str, single_port: bool) -> Result<bool, String> {
+    match (cert.is_empty(), key.is_empty()) {
+        (true, true) => return Ok(false),
+        (false, true) => return Err("--tls-cert needs --tls-key".to_owned()),
+        (true, false) => return Err("--tls-key needs --tls-cert".to_owned()),
+        (false, false) => {}
+    }
+    if !single_port {
+        return Err("--tls-cert is only used with --single-port".to_owned());
+    }
+    let read = |path: &str| std::fs::read(path).map_err(|e| format!("{}: {}", path, e));
+    let identity = native_tls::Identity::from_pkcs8(&read(cert)?, &read(key)?)
+        .map_err(|e| format!("{} and {}: {}", cert, key, e))?;
+    let acceptor = native_tls::TlsAcceptor::new(identity).map_err(|e| e.to_string())?;
+    let acceptor = tokio_native_tls::TlsAcceptor::from(acceptor);
+    hbbs::set_tls_acceptor(Some(Box::new(move |stream: TcpStream| {
+        let acceptor = acceptor.clone();
+        Box::pin(async move {
+            match acceptor.accept(stream).await {
+                Ok(tls) => Ok(Box::new(tls) as hbbs::Conn),
+                Err(e) => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+            }
+        })
+    })));
+    Ok(true)
+}
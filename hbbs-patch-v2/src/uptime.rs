@@ -0,0 +1,459 @@
+// Uptime SLO report over the status-event log
+// Replays peer_event transitions against the server_run table: time outside any
+// server run is "unknown" (the server could not observe the peer), every server
+// start resets peers to offline, and time before a peer's first event is unknown
+// too (the peer did not exist yet). Events are streamed peer by peer in
+// (peer_id, at) order so memory stays bounded by the number of server runs.
+// Events older than the hot window live in monthly archive files, attached for
+// the months the window covers; a peer's state before the oldest attached month
+// is unknown. With a tag, only peers whose `tags` attribute carries it are
+// replayed and the aggregate covers that group; the group's ids are the one
+// thing held in full.
+
+use hbb_common::futures_util::TryStreamExt;
+use serde::Serialize;
+use sqlx::{
+    sqlite::{SqliteConnection, SqlitePool},
+    Row,
+};
+use std::collections::HashSet;
+
+/// The current run's alive_until is extended every minute by the server;
+/// a run touched within this slack is treated as still running up to now
+const RUN_TOUCH_SLACK_SECS: i64 = 120;
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct UptimeRow {
+    pub id: String,
+    pub online_secs: i64,
+    pub offline_secs: i64,
+    pub unknown_secs: i64,
+    /// online / (online + offline); None when there is no observed time
+    pub availability: Option<f64>,
+    pub outages: u64,
+    pub longest_outage_secs: i64,
+}
+
+impl UptimeRow {
+    fn finish(mut self) -> Self {
+        let observed = self.online_secs + self.offline_secs;
+        if observed > 0 {
+            self.availability = Some(self.online_secs as f64 / observed as f64);
+        }
+        self
+    }
+
+    fn add(&mut self, other: &UptimeRow) {
+        self.online_secs += other.online_secs;
+        self.offline_secs += other.offline_secs;
+        self.unknown_secs += other.unknown_secs;
+        self.outages += other.outages;
+        self.longest_outage_secs = self.longest_outage_secs.max(other.longest_outage_secs);
+    }
+}
+
+#[derive(Debug)]
+pub enum ReportError {
+    Database(sqlx::Error),
+    /// The window covers more archived months than one report may attach
+    TooManyArchives(Vec<String>),
+}
+
+impl std::fmt::Display for ReportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReportError::Database(e) => write!(f, "Database error: {}", e),
+            ReportError::TooManyArchives(months) => write!(
+                f,
+                "The range covers {} archived months ({}), at most {} per report",
+                months.len(),
+                months.join(", "),
+                hbbs::MAX_ATTACHED_ARCHIVES
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ReportError {}
+
+impl From<sqlx::Error> for ReportError {
+    fn from(e: sqlx::Error) -> Self {
+        ReportError::Database(e)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct UptimeReport {
+    pub from: i64,
+    pub to: i64,
+    pub peers: Vec<UptimeRow>,
+    pub aggregate: UptimeRow,
+}
+
+/// Interval arithmetic for one peer. Feed `event`s in time order, then `finish`.
+/// Runs are (started_at, alive_until) sorted by start and must not overlap.
+pub struct PeerTimeline<'a> {
+    runs: &'a [(i64, i64)],
+    from: i64,
+    to: i64,
+    cursor: i64,
+    run: usize,
+    entered: Option<usize>,
+    // None until the peer's first event
+    state: Option<bool>,
+    // offline seconds of the outage in progress; adjacent offline periods are merged
+    // into one outage even across unknown time
+    outage: Option<i64>,
+    row: UptimeRow,
+}
+
+impl<'a> PeerTimeline<'a> {
+    pub fn new(id: String, runs: &'a [(i64, i64)], from: i64, to: i64, first_at: i64) -> Self {
+        Self {
+            runs,
+            from,
+            to,
+            cursor: first_at.min(from),
+            run: 0,
+            entered: None,
+            state: None,
+            outage: None,
+            row: UptimeRow {
+                id,
+                ..Default::default()
+            },
+        }
+    }
+
+    pub fn event(&mut self, at: i64, online: bool) {
+        self.advance(at);
+        self.state = Some(online);
+    }
+
+    pub fn finish(mut self) -> UptimeRow {
+        self.advance(self.to);
+        self.row.finish()
+    }
+
+    // skip finished runs; entering a new run resets a tracked peer to offline,
+    // like set_all_offline does on every server start
+    fn sync_run(&mut self) {
+        while self.run < self.runs.len() && self.runs[self.run].1 <= self.cursor {
+            self.run += 1;
+        }
+        if let Some(&(start, _)) = self.runs.get(self.run) {
+            if start <= self.cursor && self.entered != Some(self.run) {
+                self.entered = Some(self.run);
+                if self.state.is_some() {
+                    self.state = Some(false);
+                }
+            }
+        }
+    }
+
+    fn advance(&mut self, until: i64) {
+        let until = until.min(self.to);
+        loop {
+            self.sync_run();
+            if self.cursor >= until {
+                break;
+            }
+            let (end, state) = match self.runs.get(self.run) {
+                Some(&(start, end)) if start <= self.cursor => (end.min(until), self.state),
+                Some(&(start, _)) => (start.min(until), None),
+                None => (until, None),
+            };
+            self.account(self.cursor, end, state);
+            self.cursor = end;
+        }
+    }
+
+    fn account(&mut self, start: i64, end: i64, state: Option<bool>) {
+        let secs = end.min(self.to) - start.max(self.from);
+        if secs <= 0 {
+            return;
+        }
+        match state {
+            Some(true) => {
+                self.row.online_secs += secs;
+                self.outage = None;
+            }
+            Some(false) => {
+                self.row.offline_secs += secs;
+                let outage = match self.outage {
+                    Some(outage) => outage + secs,
+                    None => {
+                        self.row.outages += 1;
+                        secs
+                    }
+                };
+                self.outage = Some(outage);
+                self.row.longest_outage_secs = self.row.longest_outage_secs.max(outage);
+            }
+            None => self.row.unknown_secs += secs,
+        }
+    }
+}
+
+/// Server runs overlapping [from, to], with a still-running last run extended to `now`
+pub fn effective_runs(mut runs: Vec<(i64, i64)>, now: i64) -> Vec<(i64, i64)> {
+    if let Some(last) = runs.last_mut() {
+        if now - last.1 <= RUN_TOUCH_SLACK_SECS {
+            last.1 = last.1.max(now);
+        }
+    }
+    // a crashed run can only end where the next one starts
+    for i in 1..runs.len() {
+        let next_start = runs[i].0;
+        if runs[i - 1].1 > next_start {
+            runs[i - 1].1 = next_start;
+        }
+    }
+    runs
+}
+
+/// Ids of the peers whose `tags` attribute carries `tag`
+async fn tagged(pool: &SqlitePool, tag: &str) -> Result<HashSet<String>, sqlx::Error> {
+    Ok(sqlx::query(
+        "SELECT p.id, a.value FROM peer_attributes a JOIN peer p ON p.guid = a.guid
+         WHERE a.key = ? AND p.is_deleted = 0",
+    )
+    .bind(hbbs::TAGS_ATTRIBUTE)
+    .fetch_all(pool)
+    .await?
+    .iter()
+    .filter(|row| {
+        row.get::<String, _>("value")
+            .split(',')
+            .any(|x| x.trim() == tag)
+    })
+    .map(|row| row.get("id"))
+    .collect())
+}
+
+pub async fn uptime_report(
+    pool: &SqlitePool,
+    from: i64,
+    to: i64,
+    tag: Option<&str>,
+) -> Result<UptimeReport, ReportError> {
+    let now = chrono::Utc::now().timestamp();
+    let to = to.min(now);
+    let group = match tag {
+        Some(tag) => Some((tag, tagged(pool, tag).await?)),
+        None => None,
+    };
+
+    let runs: Vec<(i64, i64)> = sqlx::query(
+        "SELECT started_at, alive_until FROM server_run
+         WHERE alive_until >= ? AND started_at <= ? ORDER BY started_at",
+    )
+    .bind(from - RUN_TOUCH_SLACK_SECS)
+    .bind(to)
+    .fetch_all(pool)
+    .await?
+    .iter()
+    .map(|r| (r.get::<i64, _>(0), r.get::<i64, _>(1)))
+    .collect();
+    let runs = effective_runs(runs, now);
+
+    let mut conn = pool.acquire().await?;
+    let main_file: String =
+        sqlx::query("SELECT file FROM pragma_database_list WHERE name = 'main'")
+            .fetch_one(&mut *conn)
+            .await?
+            .get(0);
+    let archives = hbbs::archives_for(&hbbs::archive_dir(&main_file), from, to);
+    if archives.len() > hbbs::MAX_ATTACHED_ARCHIVES {
+        return Err(ReportError::TooManyArchives(
+            archives.into_iter().map(|(month, _)| month).collect(),
+        ));
+    }
+    let mut attached = 0;
+    let mut res = Ok(());
+    for (_, path) in &archives {
+        res = sqlx::query(&format!("ATTACH DATABASE ? AS archive{}", attached))
+            .bind(path.to_string_lossy().to_string())
+            .execute(&mut *conn)
+            .await
+            .map(|_| ());
+        if res.is_err() {
+            break;
+        }
+        attached += 1;
+    }
+    let report = match res {
+        Ok(()) => replay(&mut conn, &runs, from, to, attached, group).await,
+        Err(e) => Err(e),
+    };
+    // the connection goes back to the pool, without the archives
+    for i in 0..attached {
+        let _ = sqlx::query(&format!("DETACH DATABASE archive{}", i))
+            .execute(&mut *conn)
+            .await;
+    }
+    Ok(report?)
+}
+
+async fn replay(
+    conn: &mut SqliteConnection,
+    runs: &[(i64, i64)],
+    from: i64,
+    to: i64,
+    archives: usize,
+    group: Option<(&str, HashSet<String>)>,
+) -> Result<UptimeReport, sqlx::Error> {
+    let events_table = std::iter::once("main".to_owned())
+        .chain((0..archives).map(|i| format!("archive{}", i)))
+        .map(|db| format!("SELECT peer_id, online, at FROM {}.peer_event", db))
+        .collect::<Vec<_>>()
+        .join(" UNION ALL ");
+    // the last transition before the window gives each peer's initial state
+    let sql = format!(
+        "WITH events AS ({})
+         SELECT peer_id, online, at FROM events WHERE at > ? AND at <= ?
+         UNION ALL
+         SELECT peer_id, online, max(at) FROM events WHERE at <= ? GROUP BY peer_id
+         ORDER BY 1, 3",
+        events_table
+    );
+    let mut events = sqlx::query(&sql).bind(from).bind(to).bind(from).fetch(conn);
+
+    let mut peers = Vec::new();
+    let mut aggregate = UptimeRow {
+        id: group
+            .as_ref()
+            .map_or("*".to_string(), |(tag, _)| format!("tag:{}", tag)),
+        ..Default::default()
+    };
+    let mut current: Option<PeerTimeline> = None;
+    while let Some(row) = events.try_next().await? {
+        let id: String = row.get(0);
+        if group.as_ref().map_or(false, |(_, ids)| !ids.contains(&id)) {
+            continue;
+        }
+        let online: i64 = row.get(1);
+        let at: i64 = row.get(2);
+        if current.as_ref().map_or(true, |t| t.row.id != id) {
+            if let Some(done) = current.take() {
+                let done = done.finish();
+                aggregate.add(&done);
+                peers.push(done);
+            }
+            current = Some(PeerTimeline::new(id, runs, from, to, at));
+        }
+        if let Some(timeline) = current.as_mut() {
+            timeline.event(at, online != 0);
+        }
+    }
+    if let Some(done) = current.take() {
+        let done = done.finish();
+        aggregate.add(&done);
+        peers.push(done);
+    }
+
+    Ok(UptimeReport {
+        from,
+        to,
+        peers,
+        aggregate: aggregate.finish(),
+    })
+}
+
+/// The report window from optional bounds (RFC3339 or unix seconds), the last
+/// 30 days by default
+pub fn window(from: Option<&str>, to: Option<&str>) -> Result<(i64, i64), String> {
+    let parse = |value: Option<&str>, default: i64| match value {
+        Some(s) => parse_time(s).ok_or_else(|| format!("Invalid time: {}", s)),
+        None => Ok(default),
+    };
+    let to = parse(to, chrono::Utc::now().timestamp())?;
+    let from = parse(from, to - 30 * 86400)?;
+    if from >= to {
+        return Err("from must be before to".to_string());
+    }
+    Ok((from, to))
+}
+
+/// Accepts RFC3339 or unix seconds
+pub fn parse_time(s: &str) -> Option<i64> {
+    s.parse::<i64>().ok().or_else(|| {
+        chrono::DateTime::parse_from_rfc3339(s)
+            .ok()
+            .map(|t| t.timestamp())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RUN: [(i64, i64); 1] = [(0, 1000)];
+    const RESTART: [(i64, i64); 2] = [(0, 400), (500, 1000)];
+
+    /// (online, offline, unknown, outages, longest outage) for one synthetic timeline
+    fn timeline(
+        runs: &[(i64, i64)],
+        from: i64,
+        to: i64,
+        events: &[(i64, bool)],
+    ) -> (i64, i64, i64, u64, i64) {
+        let mut t = PeerTimeline::new(String::new(), runs, from, to, events[0].0);
+        for &(at, online) in events {
+            t.event(at, online);
+        }
+        let row = t.finish();
+        (
+            row.online_secs,
+            row.offline_secs,
+            row.unknown_secs,
+            row.outages,
+            row.longest_outage_secs,
+        )
+    }
+
+    #[test]
+    fn time_before_the_first_event_is_unknown() {
+        assert_eq!(timeline(&RUN, 0, 1000, &[(400, true)]), (600, 0, 400, 0, 0));
+    }
+
+    #[test]
+    fn open_outage_counts_up_to_the_window_end() {
+        assert_eq!(
+            timeline(&RUN, 0, 1000, &[(0, true), (700, false)]),
+            (700, 300, 0, 1, 300)
+        );
+    }
+
+    #[test]
+    fn state_before_the_window_is_clipped() {
+        assert_eq!(
+            timeline(&[(0, 2000)], 500, 1500, &[(100, false), (800, true)]),
+            (700, 300, 0, 1, 300)
+        );
+    }
+
+    #[test]
+    fn restart_resets_to_offline_until_registered() {
+        assert_eq!(
+            timeline(&RESTART, 0, 1000, &[(0, true), (520, true)]),
+            (880, 20, 100, 1, 20)
+        );
+    }
+
+    #[test]
+    fn outage_across_an_unknown_gap_is_one_outage() {
+        assert_eq!(
+            timeline(&RESTART, 0, 1000, &[(0, true), (300, false), (600, true)]),
+            (700, 200, 100, 1, 200)
+        );
+    }
+
+    #[test]
+    fn repeated_offline_events_merge() {
+        assert_eq!(
+            timeline(&RUN, 0, 1000, &[(0, false), (100, false), (200, true)]),
+            (800, 200, 0, 1, 200)
+        );
+    }
+}
@@ -0,0 +1,98 @@
+// Users for `/api/users` and `/api/peers/:id/user`
+// A peer is assigned to at most one user through peer.user, which holds the name
+// as bytes. Disabling a user refuses the registrations and punch holes of all its
+// peers (checked by the rendezvous side through its USER_STATUS cache, from which
+// the user is dropped on every change made here). A user exists once a peer is
+// assigned to it or it was disabled or enabled; the row in users only carries the
+// switch.
+
+use serde::Serialize;
+use sqlx::{
+    sqlite::{SqlitePool, SqliteRow},
+    Row,
+};
+
+pub const MAX_NAME_BYTES: usize = 100;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct User {
+    pub name: String,
+    pub disabled: bool,
+    /// Unix time the switch was last flipped, None when it never was
+    pub changed_at: Option<i64>,
+    /// Peers assigned to the user, soft-deleted ones left out
+    pub peers: i64,
+}
+
+/// 1-MAX_NAME_BYTES bytes, without whitespace at either end or control characters
+pub fn check_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_NAME_BYTES || name.trim() != name {
+        return Err(format!(
+            "User name {:?} must be 1-{} bytes without surrounding whitespace",
+            name, MAX_NAME_BYTES
+        ));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(format!(
+            "User name {:?} cannot contain control characters",
+            name
+        ));
+    }
+    Ok(())
+}
+
+const SELECT: &str = "SELECT n.name, coalesce(u.disabled, 0) AS disabled, u.changed_at,
+        (SELECT count(*) FROM peer p
+         WHERE p.user = CAST(n.name AS BLOB) AND p.is_deleted = 0) AS peers
+    FROM (SELECT name FROM users
+          UNION SELECT CAST(user AS TEXT) FROM peer WHERE user IS NOT NULL AND is_deleted = 0) n
+    LEFT JOIN users u ON u.name = n.name";
+
+/// All users by name
+pub async fn list(pool: &SqlitePool) -> Result<Vec<User>, sqlx::Error> {
+    let rows = sqlx::query(&format!("{} ORDER BY n.name", SELECT))
+        .fetch_all(pool)
+        .await?;
+    Ok(rows.iter().map(from_row).collect())
+}
+
+/// Flip the switch of a user checked with check_name, creating its row; returns
+/// the user and the ids of its peers
+pub async fn set_disabled(
+    pool: &SqlitePool,
+    name: &str,
+    disabled: bool,
+) -> Result<(User, Vec<String>), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO users (name, disabled, changed_at) VALUES (?, ?, ?)
+         ON CONFLICT (name) DO UPDATE SET disabled = excluded.disabled,
+             changed_at = excluded.changed_at",
+    )
+    .bind(name)
+    .bind(disabled as i64)
+    .bind(chrono::Utc::now().timestamp())
+    .execute(pool)
+    .await?;
+    hbbs::forget_user_status(name);
+    let row = sqlx::query(&format!("{} WHERE n.name = ?", SELECT))
+        .bind(name)
+        .fetch_one(pool)
+        .await?;
+    let ids = sqlx::query("SELECT id FROM peer WHERE user = ? AND is_deleted = 0 ORDER BY id")
+        .bind(name.as_bytes())
+        .fetch_all(pool)
+        .await?
+        .iter()
+        .map(|row| row.get("id"))
+        .collect();
+    Ok((from_row(&row), ids))
+}
+
+fn from_row(row: &SqliteRow) -> User {
+    User {
+        name: row.get("name"),
+        disabled: row.get::<i64, _>("disabled") == 1,
+        changed_at: row.get("changed_at"),
+        peers: row.get("peers"),
+    }
+}
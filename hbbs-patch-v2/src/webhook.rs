@@ -0,0 +1,621 @@
+// Webhooks for `/api/webhooks`: server events POSTed to an http(s) URL
+// A dispatcher task subscribes to the server's event stream (the payloads of
+// --event-log and /api/events) and hands each event to every webhook whose
+// filters match: `events` lists event types (online, offline, registration,
+// rejection, ban, rename, audit) and `tags` peer tags, each empty for all. The
+// tags are those of the event's peer (its `tags` attribute) from the rendezvous
+// side's cache at dispatch time, so a retagged peer's next event is routed by
+// its new tags; an event without a peer, or one while no rendezvous server
+// runs, only goes to webhooks without a tag filter. The body is the event's
+// JSON, or the webhook's template with {{id}}, {{note}} and {{event}} replaced,
+// a missing field by empty text; `slack` wraps it as {"text": ...} for
+// Slack-compatible incoming webhooks. Deliveries go out one at a time in event
+// order and are not retried; each webhook counts its deliveries and failures
+// with the last status. A dispatcher more than EVENT_QUEUE events behind loses
+// the oldest ones, counted in hbbs_webhook_deliveries_total{outcome="dropped"}.
+
+use hbb_common::{log, tokio::sync::broadcast::error::RecvError, ResultType};
+use hbbs::{Event, EventKind};
+use serde::Serialize;
+use sqlx::{
+    sqlite::{SqlitePool, SqliteRow},
+    Row,
+};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+pub const MAX_URL_BYTES: usize = 2048;
+pub const MAX_TEMPLATE_CHARS: usize = 2000;
+pub const MAX_FILTERS: usize = 50;
+/// Event types a webhook can filter on, as in the events' `event` field
+pub const EVENT_TYPES: [&str; 7] = [
+    "online",
+    "offline",
+    "registration",
+    "rejection",
+    "ban",
+    "rename",
+    "audit",
+];
+/// Fields a template can name as {{field}}
+pub const TEMPLATE_FIELDS: [&str; 3] = ["id", "note", "event"];
+/// The text of a Slack webhook without a template
+pub const SLACK_TEMPLATE: &str = "{{event}} {{id}}";
+
+static DELIVERED: AtomicU64 = AtomicU64::new(0);
+static FAILED: AtomicU64 = AtomicU64::new(0);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+static HOOKS_GEN: AtomicU64 = AtomicU64::new(0);
+
+const COLUMNS: &str = "id, url, tags, events, template, slack, created_at, delivered, failed,
+                       last_status, last_attempt_at";
+
+lazy_static::lazy_static! {
+    // loaded on the first event after a change
+    static ref HOOKS: RwLock<Option<Arc<Vec<Webhook>>>> = Default::default();
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Webhook {
+    pub id: i64,
+    pub url: String,
+    /// Peer tags, any of which routes an event here; empty: every peer
+    pub tags: Vec<String>,
+    /// Event types routed here; empty: every type
+    pub events: Vec<String>,
+    /// Empty: the event's JSON
+    pub template: String,
+    pub slack: bool,
+    pub created_at: i64,
+    /// Deliveries the endpoint answered with a 2xx status
+    pub delivered: i64,
+    pub failed: i64,
+    /// The last delivery's status code, or why it failed
+    pub last_status: Option<String>,
+    pub last_attempt_at: Option<i64>,
+}
+
+impl Webhook {
+    /// Whether an event of type `event` about a peer with `tags` goes here
+    pub fn wants(&self, event: &str, tags: &[String]) -> bool {
+        (self.events.is_empty() || self.events.iter().any(|x| x == event))
+            && (self.tags.is_empty() || self.tags.iter().any(|x| tags.contains(x)))
+    }
+}
+
+/// An event's type as in its `event` field, and the id of the peer it is about
+pub fn describe(kind: &EventKind) -> (&'static str, Option<&str>) {
+    match kind {
+        EventKind::Online { id, .. } => ("online", Some(id.as_str())),
+        EventKind::Offline { id, .. } => ("offline", Some(id.as_str())),
+        EventKind::Registration { id, .. } => ("registration", Some(id.as_str())),
+        EventKind::Rejection { id, .. } => ("rejection", Some(id.as_str())),
+        EventKind::Ban { id, .. } => ("ban", Some(id.as_str())),
+        EventKind::Rename { new_id, .. } => ("rename", Some(new_id.as_str())),
+        EventKind::Audit { peer_id, .. } => {
+            ("audit", Some(peer_id.as_str()).filter(|x| !x.is_empty()))
+        }
+        EventKind::OnlineBulk(_) => ("online_bulk", None),
+        EventKind::OfflineBulk(_) => ("offline_bulk", None),
+    }
+}
+
+/// `template` with each {{field}} named in `fields` replaced by its value, or
+/// by empty text for a field without one; other braces are left as they are
+pub fn render(template: &str, fields: &[(&str, Option<&str>)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let field = after.find("}}").and_then(|end| {
+            fields
+                .iter()
+                .find(|(name, _)| *name == &after[..end])
+                .map(|&(_, value)| (end, value))
+        });
+        match field {
+            Some((end, value)) => {
+                out.push_str(value.unwrap_or_default());
+                rest = &after[end + 2..];
+            }
+            None => {
+                out.push_str("{{");
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// The content type and body `hook` gets for `event`, `note` being the note of
+/// the event's peer
+pub fn body(hook: &Webhook, event: &Event, note: Option<&str>) -> (&'static str, String) {
+    let (name, id) = describe(&event.kind);
+    let fields = [("id", id), ("note", note), ("event", Some(name))];
+    match (hook.slack, hook.template.as_str()) {
+        (false, "") => (
+            "application/json",
+            serde_json::to_string(event).unwrap_or_default(),
+        ),
+        (false, template) => ("text/plain; charset=utf-8", render(template, &fields)),
+        (true, template) => {
+            let template = if template.is_empty() {
+                SLACK_TEMPLATE
+            } else {
+                template
+            };
+            let text = render(template, &fields);
+            (
+                "application/json",
+                serde_json::json!({ "text": text }).to_string(),
+            )
+        }
+    }
+}
+
+/// An http:// or https:// URL with a host, without whitespace
+pub fn check_url(url: &str) -> Result<(), String> {
+    let rest = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))
+        .ok_or_else(|| format!("URL {:?} is not an http:// or https:// URL", url))?;
+    if rest.is_empty() || rest.starts_with('/') || url.len() > MAX_URL_BYTES {
+        return Err(format!(
+            "URL {:?} must name a host and be at most {} bytes",
+            url, MAX_URL_BYTES
+        ));
+    }
+    if url.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err(format!(
+            "URL {:?} cannot contain whitespace or control characters",
+            url
+        ));
+    }
+    Ok(())
+}
+
+/// Plain tag names and known event types, at most MAX_FILTERS of each
+pub fn check_filters(tags: &[String], events: &[String]) -> Result<(), String> {
+    if tags.len() > MAX_FILTERS || events.len() > MAX_FILTERS {
+        return Err(format!(
+            "tags and events take at most {} entries each",
+            MAX_FILTERS
+        ));
+    }
+    for tag in tags {
+        crate::access::check_pattern(tag)?;
+        if tag == "*" || tag.starts_with("tag:") {
+            return Err(format!("Tag {:?} must be a plain tag name", tag));
+        }
+    }
+    if let Some(event) = events.iter().find(|x| !EVENT_TYPES.contains(&x.as_str())) {
+        return Err(format!(
+            "Event {:?} is not one of {}",
+            event,
+            EVENT_TYPES.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// At most MAX_TEMPLATE_CHARS characters naming only TEMPLATE_FIELDS
+pub fn check_template(template: &str) -> Result<(), String> {
+    let chars = template.chars().count();
+    if chars > MAX_TEMPLATE_CHARS {
+        return Err(format!(
+            "Template must be at most {} characters, got {}",
+            MAX_TEMPLATE_CHARS, chars
+        ));
+    }
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        rest = &rest[start + 2..];
+        if let Some(end) = rest.find("}}") {
+            let name = &rest[..end];
+            if !TEMPLATE_FIELDS.contains(&name) {
+                return Err(format!(
+                    "Template field {{{{{}}}}} is not one of {}",
+                    name,
+                    TEMPLATE_FIELDS.join(", ")
+                ));
+            }
+            rest = &rest[end + 2..];
+        }
+    }
+    Ok(())
+}
+
+pub async fn init(pool: &SqlitePool) -> ResultType<()> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS webhooks (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            url TEXT NOT NULL,
+            tags TEXT NOT NULL DEFAULT '',
+            events TEXT NOT NULL DEFAULT '',
+            template TEXT NOT NULL DEFAULT '',
+            slack TINYINT NOT NULL DEFAULT 0,
+            created_at INTEGER NOT NULL,
+            delivered INTEGER NOT NULL DEFAULT 0,
+            failed INTEGER NOT NULL DEFAULT 0,
+            last_status TEXT,
+            last_attempt_at INTEGER
+        )",
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// All webhooks with their delivery stats
+pub async fn list(pool: &SqlitePool) -> Result<Vec<Webhook>, sqlx::Error> {
+    let rows = sqlx::query(&format!("SELECT {} FROM webhooks ORDER BY id", COLUMNS))
+        .fetch_all(pool)
+        .await?;
+    Ok(rows.iter().map(from_row).collect())
+}
+
+/// Add a webhook checked with check_url, check_filters and check_template
+pub async fn add(
+    pool: &SqlitePool,
+    url: &str,
+    tags: &[String],
+    events: &[String],
+    template: &str,
+    slack: bool,
+) -> Result<Webhook, sqlx::Error> {
+    let row = sqlx::query(&format!(
+        "INSERT INTO webhooks (url, tags, events, template, slack, created_at)
+         VALUES (?, ?, ?, ?, ?, ?) RETURNING {}",
+        COLUMNS
+    ))
+    .bind(url)
+    .bind(tags.join(","))
+    .bind(events.join(","))
+    .bind(template)
+    .bind(slack)
+    .bind(chrono::Utc::now().timestamp())
+    .fetch_one(pool)
+    .await?;
+    invalidate();
+    Ok(from_row(&row))
+}
+
+/// Remove a webhook; Ok(None) when there is none with this id
+pub async fn remove(pool: &SqlitePool, id: i64) -> Result<Option<Webhook>, sqlx::Error> {
+    let row = sqlx::query(&format!(
+        "DELETE FROM webhooks WHERE id = ? RETURNING {}",
+        COLUMNS
+    ))
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+    invalidate();
+    Ok(row.as_ref().map(from_row))
+}
+
+fn split(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .filter(|x| !x.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+fn from_row(row: &SqliteRow) -> Webhook {
+    Webhook {
+        id: row.get("id"),
+        url: row.get("url"),
+        tags: split(row.get::<&str, _>("tags")),
+        events: split(row.get::<&str, _>("events")),
+        template: row.get("template"),
+        slack: row.get("slack"),
+        created_at: row.get("created_at"),
+        delivered: row.get("delivered"),
+        failed: row.get("failed"),
+        last_status: row.get("last_status"),
+        last_attempt_at: row.get("last_attempt_at"),
+    }
+}
+
+fn invalidate() {
+    HOOKS_GEN.fetch_add(1, Ordering::SeqCst);
+    if let Ok(mut lock) = HOOKS.write() {
+        *lock = None;
+    }
+}
+
+async fn hooks(pool: &SqlitePool) -> Result<Arc<Vec<Webhook>>, sqlx::Error> {
+    if let Some(hooks) = HOOKS.read().ok().and_then(|x| x.clone()) {
+        return Ok(hooks);
+    }
+    let generation = HOOKS_GEN.load(Ordering::SeqCst);
+    let hooks = Arc::new(list(pool).await?);
+    if let Ok(mut lock) = HOOKS.write() {
+        // changed while loading: leave the cache empty for the next event
+        if HOOKS_GEN.load(Ordering::SeqCst) == generation {
+            *lock = Some(hooks.clone());
+        }
+    }
+    Ok(hooks)
+}
+
+pub fn start(pool: SqlitePool, read_pool: SqlitePool) {
+    hbbs::supervise("webhooks", move || run(pool.clone(), read_pool.clone()));
+}
+
+async fn run(pool: SqlitePool, read_pool: SqlitePool) {
+    let mut rx = hbbs::subscribe_events();
+    loop {
+        match rx.recv().await {
+            Ok(event) => dispatch(&pool, &read_pool, &event).await,
+            Err(RecvError::Lagged(n)) => {
+                DROPPED.fetch_add(n, Ordering::Relaxed);
+                log::warn!("Webhooks: fell behind, {} events dropped", n);
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+/// Tags of the peer `id`; none while no rendezvous server runs
+async fn peer_tags(id: &str) -> Vec<String> {
+    let pm = hbbs::peer_map_watch().borrow().clone();
+    match pm {
+        Some(pm) => pm.tags(id).await.unwrap_or_else(|e| {
+            log::warn!("Webhooks: tags of {} unavailable: {}", id, e);
+            Vec::new()
+        }),
+        None => Vec::new(),
+    }
+}
+
+/// Hand `event` to the webhooks that want it, by the tags its peer has now
+pub async fn dispatch(pool: &SqlitePool, read_pool: &SqlitePool, event: &Event) {
+    let hooks = match hooks(read_pool).await {
+        Ok(hooks) => hooks,
+        Err(e) => {
+            log::warn!("Webhooks: failed to load: {}", e);
+            return;
+        }
+    };
+    if hooks.is_empty() {
+        return;
+    }
+    let tags = match describe(&event.kind).1 {
+        Some(id) if hooks.iter().any(|x| !x.tags.is_empty()) => peer_tags(id).await,
+        _ => Vec::new(),
+    };
+    send(pool, read_pool, &hooks, event, &tags).await;
+}
+
+/// POST `event` to each of `hooks` that wants it given the peer's `tags`, and
+/// record the outcomes
+pub async fn send(
+    pool: &SqlitePool,
+    read_pool: &SqlitePool,
+    hooks: &[Webhook],
+    event: &Event,
+    tags: &[String],
+) {
+    let (name, id) = describe(&event.kind);
+    // read once, and only for a template that shows it
+    let mut note: Option<Option<String>> = None;
+    for hook in hooks.iter().filter(|x| x.wants(name, tags)) {
+        if note.is_none() && hook.template.contains("{{note}}") {
+            note = Some(match id {
+                Some(id) => peer_note(read_pool, id).await,
+                None => None,
+            });
+        }
+        let (content_type, body) = body(hook, event, note.as_ref().and_then(|x| x.as_deref()));
+        let headers = format!("Content-Type: {}\r\n", content_type);
+        let outcome = match crate::oidc::post(&hook.url, &headers, &body).await {
+            Ok((status, _)) if status.starts_with('2') => Ok(status),
+            Ok((status, _)) => Err(format!("status {}", status)),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = &outcome {
+            log::warn!("Webhooks: delivery to webhook {} failed: {}", hook.id, e);
+        }
+        if let Err(e) = record(pool, hook.id, &outcome).await {
+            log::warn!("Webhooks: failed to record a delivery: {}", e);
+        }
+    }
+}
+
+async fn peer_note(pool: &SqlitePool, id: &str) -> Option<String> {
+    sqlx::query("SELECT note FROM peer WHERE id = ? AND is_deleted = 0")
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+        .ok()
+        .flatten()
+        .and_then(|row| row.get("note"))
+}
+
+async fn record(
+    pool: &SqlitePool,
+    id: i64,
+    outcome: &Result<String, String>,
+) -> Result<(), sqlx::Error> {
+    let (delivered, status) = match outcome {
+        Ok(status) => (true, status),
+        Err(e) => (false, e),
+    };
+    let counter = if delivered { &DELIVERED } else { &FAILED };
+    counter.fetch_add(1, Ordering::Relaxed);
+    sqlx::query(
+        "UPDATE webhooks SET delivered = delivered + ?, failed = failed + ?,
+         last_status = ?, last_attempt_at = ? WHERE id = ?",
+    )
+    .bind(delivered as i64)
+    .bind(!delivered as i64)
+    .bind(status.as_str())
+    .bind(chrono::Utc::now().timestamp())
+    .bind(id)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub fn render_metrics() -> String {
+    let mut out = String::new();
+    out.push_str("# HELP hbbs_webhook_deliveries_total Webhook deliveries by outcome\n");
+    out.push_str("# TYPE hbbs_webhook_deliveries_total counter\n");
+    for (outcome, n) in [
+        ("delivered", &DELIVERED),
+        ("failed", &FAILED),
+        ("dropped", &DROPPED),
+    ] {
+        out.push_str(&format!(
+            "hbbs_webhook_deliveries_total{{outcome=\"{}\"}} {}\n",
+            outcome,
+            n.load(Ordering::Relaxed)
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hook(tags: &[&str], events: &[&str]) -> Webhook {
+        Webhook {
+            id: 1,
+            url: "http://127.0.0.1/hook".to_owned(),
+            tags: tags.iter().map(|x| x.to_string()).collect(),
+            events: events.iter().map(|x| x.to_string()).collect(),
+            template: String::new(),
+            slack: false,
+            created_at: 0,
+            delivered: 0,
+            failed: 0,
+            last_status: None,
+            last_attempt_at: None,
+        }
+    }
+
+    fn event(kind: EventKind) -> Event {
+        Event {
+            v: 1,
+            seq: 7,
+            at: "2026-02-06T14:00:27.113Z".to_owned(),
+            kind,
+        }
+    }
+
+    fn offline(id: &str) -> Event {
+        event(EventKind::Offline {
+            id: id.to_owned(),
+            reason: "timeout",
+        })
+    }
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn tag_filter_needs_one_of_the_peer_tags() {
+        let office = hook(&["office", "lab"], &[]);
+        assert!(office.wants("offline", &tags(&["lab"])));
+        assert!(office.wants("online", &tags(&["kiosk", "office"])));
+        assert!(!office.wants("offline", &tags(&["kiosk"])));
+        // an event without a peer has no tags
+        assert!(!office.wants("offline", &[]));
+        assert!(hook(&[], &[]).wants("offline", &[]));
+    }
+
+    #[test]
+    fn event_filter_combines_with_the_tag_filter() {
+        let offline_lab = hook(&["lab"], &["offline", "ban"]);
+        assert!(offline_lab.wants("offline", &tags(&["lab"])));
+        assert!(offline_lab.wants("ban", &tags(&["lab"])));
+        assert!(!offline_lab.wants("online", &tags(&["lab"])));
+        assert!(!offline_lab.wants("offline", &tags(&["office"])));
+        assert!(hook(&[], &["online"]).wants("online", &tags(&["office"])));
+    }
+
+    #[test]
+    fn templates_render_missing_fields_empty() {
+        let fields = [
+            ("id", Some("123456789")),
+            ("note", None),
+            ("event", Some("offline")),
+        ];
+        assert_eq!(
+            render("{{id}} went {{event}} ({{note}})", &fields),
+            "123456789 went offline ()"
+        );
+        // a value is not rendered again, unknown or unclosed braces stay
+        let fields = [("id", Some("1")), ("note", Some("{{id}}")), ("event", None)];
+        assert_eq!(
+            render("{{note}} {{other}} {{id", &fields),
+            "{{id}} {{other}} {{id"
+        );
+        assert_eq!(render("", &fields), "");
+    }
+
+    #[test]
+    fn bodies_by_template_and_slack() {
+        let mut hook = hook(&[], &[]);
+        let (content_type, body) = body(&hook, &offline("123456789"), Some("front desk"));
+        assert_eq!(content_type, "application/json");
+        assert_eq!(
+            body,
+            r#"{"v":1,"seq":7,"at":"2026-02-06T14:00:27.113Z","event":"offline","id":"123456789","reason":"timeout"}"#
+        );
+
+        hook.template = "{{id}}: {{note}}".to_owned();
+        assert_eq!(
+            super::body(&hook, &offline("123456789"), Some("front desk")),
+            (
+                "text/plain; charset=utf-8",
+                "123456789: front desk".to_owned()
+            )
+        );
+
+        hook.slack = true;
+        assert_eq!(
+            super::body(&hook, &offline("123456789"), None).1,
+            r#"{"text":"123456789: "}"#
+        );
+        hook.template.clear();
+        assert_eq!(
+            super::body(&hook, &offline("123456789"), None).1,
+            r#"{"text":"offline 123456789"}"#
+        );
+        // an audit entry about no peer
+        let audit = event(EventKind::Audit {
+            actor: "api".to_owned(),
+            action: "reload",
+            peer_id: String::new(),
+            detail: String::new(),
+        });
+        assert_eq!(super::body(&hook, &audit, None).1, r#"{"text":"audit "}"#);
+    }
+
+    #[test]
+    fn checks() {
+        assert!(check_url("https://hooks.slack.com/services/T0/B0/X").is_ok());
+        assert!(check_url("http://10.0.0.5:8080").is_ok());
+        assert!(check_url("ftp://example.com").is_err());
+        assert!(check_url("https:///path").is_err());
+        assert!(check_url("https://example.com/a b").is_err());
+
+        assert!(check_filters(&tags(&["office"]), &tags(&["online", "audit"])).is_ok());
+        assert!(check_filters(&tags(&["tag:office"]), &[]).is_err());
+        assert!(check_filters(&tags(&["*"]), &[]).is_err());
+        assert!(check_filters(&[], &tags(&["online_bulk"])).is_err());
+
+        assert!(check_template("{{id}} is {{event}}: {{note}}").is_ok());
+        assert!(check_template("{ \"id\": \"{{id}}\" }").is_ok());
+        assert_eq!(
+            check_template("{{id}} {{ip}}"),
+            Err("Template field {{ip}} is not one of id, note, event".to_owned())
+        );
+    }
+}
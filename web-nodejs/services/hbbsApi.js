@@ -28,16 +28,29 @@ async function getHealth() {
     }
 }
 
+// Largest page GET /peers serves; it answers 100 peers without a limit
+const PEERS_PAGE_LIMIT = 1000;
+
 /**
- * Get online peers from HBBS API
+ * Get online peers from HBBS API, walking every page of the list
  */
 async function getOnlinePeers() {
     try {
-        const { data } = await apiClient.get('/peers');
-        if (data.success && Array.isArray(data.data)) {
-            return data.data.filter(p => p.online);
+        const online = [];
+        let offset = 0;
+        while (true) {
+            const { data } = await apiClient.get('/peers', {
+                params: { limit: PEERS_PAGE_LIMIT, offset }
+            });
+            if (!data.success || !Array.isArray(data.data)) {
+                return [];
+            }
+            online.push(...data.data.filter(p => p.online));
+            offset += data.data.length;
+            if (data.data.length === 0 || offset >= (data.total || 0)) {
+                return online;
+            }
         }
-        return [];
     } catch (err) {
         console.warn('HBBS API unavailable:', err.message);
         return [];
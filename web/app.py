@@ -589,11 +589,21 @@ def get_stats():
             if HBBS_API_KEY:
                 headers['X-API-Key'] = HBBS_API_KEY
             
-            response = requests.get(f'{HBBS_API_URL}/peers', headers=headers, timeout=2)
-            if response.status_code == 200:
+            # the list is paged; walk it until the last page
+            offset = 0
+            while True:
+                response = requests.get(f'{HBBS_API_URL}/peers', headers=headers,
+                                        params={'limit': 1000, 'offset': offset}, timeout=2)
+                if response.status_code != 200:
+                    break
                 api_data = response.json()
-                if api_data.get('success') and api_data.get('data'):
-                    online_count = sum(1 for peer in api_data['data'] if peer.get('online'))
+                peers = api_data.get('data') or []
+                if not api_data.get('success') or not peers:
+                    break
+                online_count += sum(1 for peer in peers if peer.get('online'))
+                offset += len(peers)
+                if offset >= api_data.get('total', 0):
+                    break
         except Exception as e:
             print(f"Warning: Could not connect to HBBS API for stats: {e}")
         